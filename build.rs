@@ -0,0 +1,23 @@
+use std::env;
+use std::process::Command;
+
+/// Embed the git revision the server is built from as `VORTEX_GIT_REVISION`, for
+/// `ServerInfo`. Builds outside a checkout can set the variable themselves
+fn main() {
+    println!("cargo:rerun-if-env-changed=VORTEX_GIT_REVISION");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let revision = env::var("VORTEX_GIT_REVISION").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(&["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let revision = String::from_utf8(output.stdout).ok()?;
+        Some(revision.trim().to_string())
+    });
+    if let Some(revision) = revision.filter(|revision| !revision.is_empty()) {
+        println!("cargo:rustc-env=VORTEX_GIT_REVISION={}", revision);
+    }
+}
@@ -0,0 +1,125 @@
+use mediasoup::prelude::*;
+use mediasoup::rtp_parameters::RtpCapabilitiesFinalized;
+use tokio::sync::Mutex;
+use warp::http::header;
+use warp::{filters::BoxedFilter, reply::Reply};
+use warp::{Filter, Rejection};
+
+use super::ApiError;
+use crate::rtc::{get_worker_pool, media_codecs};
+use crate::state::room::Room;
+use crate::util::config::CONFIG;
+
+lazy_static! {
+    /// Capabilities of a router with the configured codecs, filled on the first request
+    static ref DEFAULT_CAPABILITIES: Mutex<Option<RtpCapabilitiesFinalized>> = Mutex::new(None);
+}
+
+/// Router RTP capabilities, for clients to load their device before joining a room.
+/// Served without a management token, like the room's HLS playlists and under the same
+/// tenant prefix
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    let default = warp::path("capabilities")
+        .and(warp::path::end())
+        .and_then(default_capabilities);
+    let room = super::path_tenant()
+        .and(warp::path("rooms"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("capabilities"))
+        .and(warp::path::end())
+        .and_then(room_capabilities);
+
+    warp::get().and(default.or(room)).boxed()
+}
+
+/// Those of a router offering `rtc.codecs`, which rooms without codecs of their own use.
+/// The router is only created to read them and closed again, no room is created
+async fn default_capabilities() -> Result<impl Reply, Rejection> {
+    let mut capabilities = DEFAULT_CAPABILITIES.lock().await;
+    if capabilities.is_none() {
+        let mut options = RouterOptions::default();
+        options.media_codecs = media_codecs(&CONFIG.rtc.codecs);
+        let router = get_worker_pool()
+            .get_worker()
+            .create_router(options)
+            .await
+            .map_err(|_| warp::reject::custom(ApiError::InternalServerError))?;
+        *capabilities = Some(router.rtp_capabilities().clone());
+    }
+
+    // The configured codecs only change with a restart
+    Ok(cacheable(
+        capabilities.as_ref().unwrap(),
+        "public, max-age=3600",
+    ))
+}
+
+async fn room_capabilities(tenant: String, room_id: String) -> Result<impl Reply, Rejection> {
+    let room = Room::get(&tenant, &room_id)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let router = room.router().ok_or_else(warp::reject::not_found)?;
+
+    // A room closed and created again under the same ID may offer other codecs
+    Ok(cacheable(router.rtp_capabilities(), "public, max-age=60"))
+}
+
+fn cacheable(capabilities: &RtpCapabilitiesFinalized, cache_control: &str) -> impl Reply {
+    warp::reply::with_header(
+        warp::reply::json(capabilities),
+        header::CACHE_CONTROL,
+        cache_control.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::config::CodecConfig;
+    use crate::util::testing;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn capabilities_need_no_room() {
+        testing::init();
+        let request = |path: &str| warp::test::request().method("GET").path(path);
+
+        let response = request("/capabilities").reply(&route()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+        let capabilities: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(capabilities["codecs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|codec| codec["mimeType"] == "audio/opus"));
+
+        let response = request("/rooms/missing/capabilities").reply(&route()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rooms_offer_their_own_codecs() {
+        let settings = RoomSettings {
+            codecs: Some(vec![CodecConfig::Opus { channels: 2 }]),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+
+        let path = format!("/rooms/{}/capabilities", room.id());
+        let response = warp::test::request()
+            .method("GET")
+            .path(&path)
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let capabilities: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let codecs = capabilities["codecs"].as_array().unwrap();
+        assert!(codecs.iter().all(|codec| codec["kind"] == "audio"));
+        room.delete().await;
+    }
+}
@@ -7,16 +7,64 @@ use warp::http::StatusCode;
 use warp::reject::Reject;
 use warp::{Rejection, Reply};
 
+use crate::rtc::broadcast::BroadcastError;
+use crate::rtc::hls::HlsError;
+use crate::rtc::playback::PlaybackError;
+use crate::rtc::probe::ProbeError;
+use crate::rtc::recording::RecordingError;
+use crate::state::room::MigrationError;
+
 #[derive(Debug, IntoStaticStr)]
 pub enum ApiError {
     Unauthorized,
     InternalServerError,
+    InvalidBody(String),
+    /// Why the room's codecs were rejected
+    InvalidCodecs(String),
+    /// Name of a room template that isn't configured
+    TemplateNotFound(String),
 
     RoomNotFound(String),
     RoomAlreadyExists(String),
+    /// Room ID of the default tenant starting with another tenant's, see `Room::scoped_id`
+    InvalidRoomId(String),
+    RoomFull(String),
 
     UserNotFound(String),
     UserAlreadyExists(String),
+    JoinTokenNotFound(String),
+    BanNotFound(String),
+    ProducerNotFound(String),
+
+    RecordingUnavailable,
+    AlreadyRecording,
+    NotRecording,
+    RecordingNotAllowed,
+
+    BroadcastUnavailable,
+    AlreadyBroadcasting,
+    NotBroadcasting,
+    NothingToBroadcast,
+
+    HlsUnavailable,
+
+    PlaybackUnavailable,
+    AlreadyPlaying,
+    AudioFileNotFound(String),
+    /// Size of the uploaded audio in bytes
+    AudioTooLarge(usize),
+
+    ProbeUnavailable,
+    TooManyProbes,
+    /// The client's address has `probe.max_probes_per_ip` running
+    TooManyProbesFromAddress,
+    ProbeNotFound(String),
+    ProbeTimedOut,
+    ProbeFailed,
+
+    WorkerNotFound(String),
+    /// Why the room can't be migrated, see `MigrationError`
+    MigrationRefused(String),
 }
 
 impl ApiError {
@@ -24,9 +72,44 @@ impl ApiError {
         match self {
             ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
             ApiError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::InvalidBody(_)
+            | ApiError::InvalidCodecs(_)
+            | ApiError::TemplateNotFound(_)
+            | ApiError::InvalidRoomId(_) => StatusCode::BAD_REQUEST,
 
-            ApiError::RoomNotFound(_) | ApiError::UserNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::RoomNotFound(_)
+            | ApiError::UserNotFound(_)
+            | ApiError::JoinTokenNotFound(_)
+            | ApiError::BanNotFound(_)
+            | ApiError::ProducerNotFound(_) => StatusCode::NOT_FOUND,
             ApiError::RoomAlreadyExists(_) | ApiError::UserAlreadyExists(_) => StatusCode::CONFLICT,
+            ApiError::RoomFull(_) => StatusCode::FORBIDDEN,
+
+            ApiError::RecordingUnavailable => StatusCode::NOT_IMPLEMENTED,
+            ApiError::RecordingNotAllowed => StatusCode::FORBIDDEN,
+            ApiError::AlreadyRecording | ApiError::NotRecording => StatusCode::CONFLICT,
+
+            ApiError::BroadcastUnavailable => StatusCode::NOT_IMPLEMENTED,
+            ApiError::AlreadyBroadcasting
+            | ApiError::NotBroadcasting
+            | ApiError::NothingToBroadcast => StatusCode::CONFLICT,
+
+            ApiError::HlsUnavailable => StatusCode::NOT_IMPLEMENTED,
+
+            ApiError::PlaybackUnavailable => StatusCode::NOT_IMPLEMENTED,
+            ApiError::AlreadyPlaying => StatusCode::CONFLICT,
+            ApiError::AudioFileNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::AudioTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+
+            ApiError::ProbeUnavailable => StatusCode::NOT_IMPLEMENTED,
+            ApiError::TooManyProbes => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TooManyProbesFromAddress => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ProbeNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::ProbeTimedOut => StatusCode::REQUEST_TIMEOUT,
+            ApiError::ProbeFailed => StatusCode::BAD_REQUEST,
+
+            ApiError::WorkerNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MigrationRefused(_) => StatusCode::CONFLICT,
         }
     }
 }
@@ -38,12 +121,148 @@ impl Display for ApiError {
         match self {
             ApiError::Unauthorized => write!(f, "Invalid management token"),
             ApiError::InternalServerError => write!(f, "Internal Server Error"),
+            ApiError::InvalidBody(err) => write!(f, "Invalid request body: {}", err),
+            ApiError::InvalidCodecs(err) => write!(f, "Invalid room codecs: {}", err),
+            ApiError::TemplateNotFound(name) => write!(f, "No room template named {}", name),
 
             ApiError::RoomNotFound(id) => write!(f, "Room with ID {} not found", id),
             ApiError::RoomAlreadyExists(id) => write!(f, "Room with ID {} already exists", id),
+            ApiError::InvalidRoomId(id) => {
+                write!(f, "Room ID {} starts with the ID of another tenant", id)
+            }
+            ApiError::RoomFull(id) => write!(f, "Room with ID {} is full", id),
 
             ApiError::UserNotFound(id) => write!(f, "User with ID {} not found", id),
             ApiError::UserAlreadyExists(id) => write!(f, "User with ID {} already exists", id),
+            ApiError::JoinTokenNotFound(id) => {
+                write!(f, "Join token {} not found or already used", id)
+            }
+            ApiError::BanNotFound(id) => write!(f, "User with ID {} is not banned", id),
+            ApiError::ProducerNotFound(id) => write!(f, "Producer with ID {} not found", id),
+
+            ApiError::RecordingUnavailable => write!(f, "Recording is not enabled"),
+            ApiError::AlreadyRecording => write!(f, "Room is already being recorded"),
+            ApiError::NotRecording => write!(f, "Room is not being recorded"),
+            ApiError::RecordingNotAllowed => write!(f, "Recording is not allowed in this room"),
+
+            ApiError::BroadcastUnavailable => write!(f, "Broadcasting is not enabled"),
+            ApiError::AlreadyBroadcasting => write!(f, "Room is already being broadcast"),
+            ApiError::NotBroadcasting => write!(f, "Room is not being broadcast"),
+            ApiError::NothingToBroadcast => {
+                write!(f, "None of the selected users are producing media")
+            }
+
+            ApiError::HlsUnavailable => write!(f, "HLS is not enabled"),
+
+            ApiError::PlaybackUnavailable => write!(f, "Playback is not enabled"),
+            ApiError::AlreadyPlaying => write!(f, "Room is already playing audio"),
+            ApiError::AudioFileNotFound(file) => write!(f, "No audio file named {}", file),
+            ApiError::AudioTooLarge(size) => {
+                write!(f, "Audio of {} bytes is above the upload limit", size)
+            }
+
+            ApiError::ProbeUnavailable => write!(f, "Probing is not enabled"),
+            ApiError::TooManyProbes => write!(f, "Too many probes are running, try again later"),
+            ApiError::TooManyProbesFromAddress => {
+                write!(f, "Too many probes are running from this address")
+            }
+            ApiError::ProbeNotFound(id) => {
+                write!(
+                    f,
+                    "Probe with ID {} not found, expired or already connected",
+                    id
+                )
+            }
+            ApiError::ProbeTimedOut => write!(f, "Probe was not connected in time"),
+            ApiError::ProbeFailed => write!(f, "Probe failed to connect"),
+
+            ApiError::WorkerNotFound(id) => write!(f, "No live worker with ID {}", id),
+            ApiError::MigrationRefused(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<RecordingError> for ApiError {
+    fn from(err: RecordingError) -> Self {
+        match err {
+            RecordingError::Unavailable => ApiError::RecordingUnavailable,
+            RecordingError::AlreadyRecording => ApiError::AlreadyRecording,
+            RecordingError::NotRecording => ApiError::NotRecording,
+            RecordingError::NotAllowed => ApiError::RecordingNotAllowed,
+            RecordingError::ConsentNotRequested => ApiError::InvalidBody(err.to_string()),
+            RecordingError::Egress(_) => {
+                error!("{}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<BroadcastError> for ApiError {
+    fn from(err: BroadcastError) -> Self {
+        match err {
+            BroadcastError::Unavailable => ApiError::BroadcastUnavailable,
+            BroadcastError::AlreadyBroadcasting => ApiError::AlreadyBroadcasting,
+            BroadcastError::NotBroadcasting => ApiError::NotBroadcasting,
+            BroadcastError::InvalidUrl(_) => ApiError::InvalidBody(err.to_string()),
+            BroadcastError::NothingToBroadcast => ApiError::NothingToBroadcast,
+            BroadcastError::Egress(_) => {
+                error!("{}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<PlaybackError> for ApiError {
+    fn from(err: PlaybackError) -> Self {
+        match err {
+            PlaybackError::Unavailable => ApiError::PlaybackUnavailable,
+            PlaybackError::AlreadyPlaying => ApiError::AlreadyPlaying,
+            PlaybackError::InvalidFile(file) => ApiError::AudioFileNotFound(file),
+            PlaybackError::TooLarge(size) => ApiError::AudioTooLarge(size),
+            PlaybackError::Ingress(_) => {
+                error!("{}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<ProbeError> for ApiError {
+    fn from(err: ProbeError) -> Self {
+        match err {
+            ProbeError::Unavailable => ApiError::ProbeUnavailable,
+            ProbeError::Full => ApiError::TooManyProbes,
+            ProbeError::AddressFull => ApiError::TooManyProbesFromAddress,
+            ProbeError::NotFound(id) => ApiError::ProbeNotFound(id),
+            ProbeError::TimedOut => ApiError::ProbeTimedOut,
+            ProbeError::Failed => ApiError::ProbeFailed,
+            ProbeError::Transport(_) => {
+                error!("{}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<HlsError> for ApiError {
+    fn from(err: HlsError) -> Self {
+        match err {
+            HlsError::Unavailable => ApiError::HlsUnavailable,
+            HlsError::Egress(_) => {
+                error!("{}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<MigrationError> for ApiError {
+    fn from(err: MigrationError) -> Self {
+        match err {
+            MigrationError::WorkerNotFound(id) => ApiError::WorkerNotFound(id),
+            _ => ApiError::MigrationRefused(err.to_string()),
         }
     }
 }
@@ -70,6 +289,18 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
             ApiError::InternalServerError => (),
             _ => message = Some(api_error.to_string()),
         }
+    } else if let Some(too_large) = err.find::<warp::reject::PayloadTooLarge>() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        error = "PayloadTooLarge";
+        message = Some(too_large.to_string());
+    } else if let Some(required) = err.find::<warp::reject::LengthRequired>() {
+        code = StatusCode::LENGTH_REQUIRED;
+        error = "LengthRequired";
+        message = Some(required.to_string());
+    } else if let Some(invalid) = err.find::<warp::reject::InvalidQuery>() {
+        code = StatusCode::BAD_REQUEST;
+        error = "InvalidQuery";
+        message = Some(invalid.to_string());
     } else {
         code = StatusCode::INTERNAL_SERVER_ERROR;
         error = "InternalServerError";
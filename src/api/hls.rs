@@ -0,0 +1,72 @@
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::{filters::BoxedFilter, reply::Reply};
+use warp::{Filter, Rejection};
+
+use crate::rtc::hls;
+use crate::state::room::Room;
+
+/// Playlists and segments of rooms packaged as HLS. Served without a management token,
+/// viewers only need the room ID, like the WebRTC participants they listen to. Rooms of
+/// tenants other than the default one are under `/tenants/{tenant}`
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    warp::get()
+        .and(super::path_tenant())
+        .and(warp::path("rooms"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("hls"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(serve)
+        .boxed()
+}
+
+async fn serve(tenant: String, room_id: String, name: String) -> Result<Response<Body>, Rejection> {
+    let content_type = hls::content_type(&name).ok_or_else(warp::reject::not_found)?;
+    let room = Room::get(&tenant, &room_id)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let directory = room
+        .hls_directory()
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let contents = tokio::fs::read(directory.join(&name))
+        .await
+        .map_err(|_| warp::reject::not_found())?;
+
+    // The playlist changes with every segment, segments never do
+    let cache_control = match name.as_str() {
+        hls::PLAYLIST => "no-cache",
+        _ => "public, max-age=3600",
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, cache_control)
+        .body(Body::from(contents))
+        .map_err(|_| warp::reject::not_found())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn rooms_without_hls_have_no_playlist() {
+        let room = testing::room(RoomSettings::default()).await;
+        let request = |path: String| warp::test::request().method("GET").path(&path);
+
+        let playlist = format!("/rooms/{}/hls/index.m3u8", room.id());
+        let response = request(playlist).reply(&route()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = request("/rooms/missing/hls/index.m3u8".to_string())
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        room.delete().await;
+    }
+}
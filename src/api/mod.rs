@@ -1,38 +1,169 @@
-use warp::{filters::BoxedFilter, reply::Reply};
+use subtle::ConstantTimeEq;
+use warp::hyper::body::Bytes;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::Reply};
 use warp::{Filter, Rejection};
 
-use crate::util::variables;
+use crate::rtc::self_test;
+use crate::util::config::{CONFIG, DEFAULT_TENANT};
+use crate::util::metrics;
+use crate::util::telemetry;
 
 pub mod error;
 pub use error::ApiError;
 
+pub mod capabilities;
+pub mod hls;
+pub mod probe;
 pub mod room;
 pub mod user;
 
-fn authorize() -> impl Filter<Extract = ((),), Error = Rejection> + Copy {
-    warp::header::optional("Authorization").and_then(|authorization: Option<String>| async move {
-        match authorization {
-            Some(authorization) => {
-                if authorization == *variables::MANAGE_TOKEN {
-                    return Ok(());
-                }
+/// Tenant of the management token an `Authorization` header value, bare or with a
+/// `Bearer` scheme, carries. `None` if it matches none of the configured tokens
+fn token_tenant(authorization: &str) -> Option<&'static str> {
+    let token = authorization
+        .strip_prefix("Bearer ")
+        .unwrap_or(authorization)
+        .trim();
+    if token.is_empty() {
+        return None;
+    }
 
-                Err(warp::reject::custom(ApiError::Unauthorized))
+    // Check every key so the time taken doesn't reveal which one matched, or whose
+    CONFIG
+        .api
+        .tenant_tokens()
+        .fold(None, |matched, (tenant, key)| {
+            match bool::from(key.as_bytes().ct_eq(token.as_bytes())) {
+                true => Some(tenant),
+                false => matched,
             }
+        })
+}
+
+/// Tenant the request's management token grants access to, rejecting requests without
+/// a valid one
+pub fn tenant() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Copy {
+    warp::header::optional("Authorization").and_then(|authorization: Option<String>| async move {
+        match authorization.as_deref().and_then(token_tenant) {
+            Some(tenant) => Ok(tenant),
             None => Err(warp::reject::custom(ApiError::Unauthorized)),
         }
     })
 }
 
+/// Tenant named by the `tenants/{tenant}` prefix of a route served without a management
+/// token, the default tenant if the path doesn't start with one
+pub fn path_tenant() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    warp::path("tenants")
+        .and(warp::path::param::<String>())
+        .or(warp::any().map(|| DEFAULT_TENANT.to_string()))
+        .unify()
+}
+
+/// Body of a request, refused with 413 past `signaling.max_message_size` like those of
+/// the long-poll routes. Requests sent without a body have no length and get an empty one
+pub fn body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    let limit = CONFIG.signaling.max_message_size as u64;
+    let sized = warp::body::content_length_limit(limit).and(warp::body::bytes());
+    let empty = warp::header::optional::<String>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and_then(
+            |length: Option<String>, encoding: Option<String>| async move {
+                match (length, encoding) {
+                    (None, None) => Ok(Bytes::new()),
+                    _ => Err(warp::reject()),
+                }
+            },
+        );
+    sized.or(empty).unify()
+}
+
+fn authorize() -> impl Filter<Extract = ((),), Error = Rejection> + Copy {
+    tenant().map(|_| ())
+}
+
 pub fn route() -> BoxedFilter<(impl Reply,)> {
     let room_routes = warp::path("room").and(room::route());
+    let list_routes = warp::path("rooms").and(room::list_route());
+    let template_routes = warp::path("templates").and(room::template_route());
     let user_routes = warp::path("room").and(user::route());
+    let presence_routes = warp::path("room").and(user::presence_route());
+    let join_token_routes = warp::path("room").and(user::join_token_route());
+    let ban_routes = warp::path("room").and(user::ban_route());
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(tenant())
+        .map(metrics::gather);
 
-    let routes = room_routes.or(user_routes);
+    // Same checks as `--self-test`, 503 if any failed
+    let self_test_route = warp::path("self-test")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(tenant())
+        .and_then(|_: &'static str| async move {
+            let report = self_test::run().await;
+            let status = match report.passed {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&report), status))
+        });
+
+    let routes = room_routes
+        .or(list_routes)
+        .or(template_routes)
+        .or(user_routes)
+        .or(presence_routes)
+        .or(join_token_routes)
+        .or(ban_routes)
+        .or(metrics_route)
+        .or(self_test_route);
 
     authorize()
         .untuple_one()
         .and(routes)
         .recover(error::handle_rejection)
+        .with(warp::trace(telemetry::api_span))
         .boxed()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[test]
+    fn token_forms() {
+        testing::init();
+        let tenant = Some(DEFAULT_TENANT);
+        assert_eq!(token_tenant("test-token"), tenant);
+        assert_eq!(token_tenant("Bearer test-token"), tenant);
+        assert_eq!(token_tenant(" test-token "), tenant);
+
+        assert_eq!(token_tenant(""), None);
+        assert_eq!(token_tenant("Bearer "), None);
+        assert_eq!(token_tenant("Bearer wrong-token"), None);
+        assert_eq!(token_tenant("Basic dGVzdC10b2tlbg=="), None);
+        assert_eq!(token_tenant("test-token-and-more"), None);
+    }
+
+    #[tokio::test]
+    async fn routes_require_a_token() {
+        testing::init();
+        let status = |authorization: Option<&'static str>| async move {
+            let mut request = warp::test::request().method("GET").path("/room");
+            if let Some(authorization) = authorization {
+                request = request.header("Authorization", authorization);
+            }
+            request.reply(&route()).await.status()
+        };
+
+        assert_eq!(status(Some("Bearer test-token")).await, 200);
+        assert_eq!(status(Some("test-token")).await, 200);
+        assert_eq!(status(None).await, 401);
+        assert_eq!(status(Some("Bearer wrong-token")).await, 401);
+        assert_eq!(status(Some("Bearer")).await, 401);
+    }
+}
@@ -0,0 +1,95 @@
+use std::net::IpAddr;
+
+use warp::hyper::body::Bytes;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::Reply};
+use warp::{Filter, Rejection};
+
+use mediasoup::prelude::DtlsParameters;
+use serde::Deserialize;
+
+use super::ApiError;
+use crate::rtc::probe;
+use crate::util::client_ip;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ConnectProbe {
+    dtls_parameters: DtlsParameters,
+}
+
+/// Connectivity probes served at `/probe` without a management token, see `rtc::probe`.
+/// Each client address gets `probe.max_probes_per_ip` of them at once. Errors are replied
+/// here so they aren't taken for a missing token by the management API
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    let start = warp::path::end()
+        .and(warp::post())
+        .and(client_ip::filter())
+        .and_then(|ip: Option<IpAddr>| async move {
+            let probes =
+                probe::probes().map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            let init_data = probes
+                .start(ip)
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::json(&init_data),
+                StatusCode::CREATED,
+            ))
+        });
+
+    // Replies once connected, or when the probe fails or expires
+    let connect = warp::path::param::<String>()
+        .and(warp::path("connect"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(super::body())
+        .and_then(|id: String, body: Bytes| async move {
+            let request: ConnectProbe = serde_json::from_slice(&body)
+                .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?;
+            let probes =
+                probe::probes().map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            let result = probes
+                .connect(&id, request.dtls_parameters)
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::json(&result))
+        });
+
+    warp::path("probe")
+        .and(start.or(connect).recover(super::error::handle_rejection))
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::CONFIG;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn probing_must_be_enabled() {
+        testing::init();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/probe")
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/probe/missing/connect")
+            .body("{}")
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/probe/missing/connect")
+            .body("x".repeat(CONFIG.signaling.max_message_size + 1))
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
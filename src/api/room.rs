@@ -1,62 +1,311 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
 
+use warp::hyper::body::Bytes;
 use warp::{filters::BoxedFilter, http::StatusCode, reply::Reply};
 use warp::{Filter, Rejection};
 
 use crate::api::ApiError;
-use crate::state::room::{Room, ROOMS};
+use crate::rtc::broadcast::StartBroadcast;
+use crate::rtc::dump;
+use crate::rtc::playback::PlaybackSource;
+use crate::state::room::{
+    Ban, OccupancyCounts, Room, RoomExport, RoomMetadata, RoomSettings, RoomSettingsUpdate, ROOMS,
+};
+use crate::util::config::CONFIG;
 
 #[derive(Serialize)]
 struct RoomReply {
     #[serde(rename = "videoAllowed")]
     video_allowed: bool,
     users: Vec<()>,
+    occupancy: OccupancyReply,
+    bans: Vec<Ban>,
+    #[serde(flatten)]
+    metadata: RoomMetadata,
 }
 
-pub fn room_filter() -> impl Filter<Extract = (Arc<Room>,), Error = Rejection> + Copy {
-    warp::path::param::<String>().and_then(|id: String| async move {
-        match Room::get(&id).await {
-            Some(room) => Ok(room),
-            None => Err(warp::reject::custom(ApiError::RoomNotFound(id))),
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OccupancyReply {
+    #[serde(flatten)]
+    counts: OccupancyCounts,
+    capacity_used: usize,
+    capacity: Option<usize>,
+}
+
+/// Page of `GET /rooms`, rooms are ordered by creation
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+struct ListQuery {
+    offset: usize,
+    /// At most `MAX_PAGE_SIZE`
+    limit: usize,
+    /// Leave out rooms with fewer visible users, for dashboards of active calls
+    min_users: usize,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        ListQuery {
+            offset: 0,
+            limit: 50,
+            min_users: 0,
         }
-    })
+    }
 }
 
-pub fn route() -> BoxedFilter<(impl Reply,)> {
-    let get_rooms = warp::path::end().and(warp::get()).and_then(|| async move {
-        let map = ROOMS.read().await;
-        let rooms: Vec<&String> = map.keys().collect();
-        Ok::<warp::reply::Json, Infallible>(warp::reply::json(&rooms))
+const MAX_PAGE_SIZE: usize = 200;
+
+/// Header carrying the number of rooms matching the filter, across all pages
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListedRoom {
+    id: String,
+    /// Connected users shown to other participants
+    user_count: usize,
+    #[serde(flatten)]
+    metadata: RoomMetadata,
+}
+
+/// Configured room template, as rooms created from it without overrides get it
+#[derive(Serialize)]
+struct ListedTemplate {
+    name: String,
+    settings: RoomSettings,
+}
+
+/// Settings of a room creation body, those of the configured template it names under
+/// `template` with the body's other fields replacing the template's
+fn creation_settings(body: &[u8]) -> Result<RoomSettings, ApiError> {
+    // Settings are optional, an empty body creates a room with the defaults
+    if body.is_empty() {
+        return Ok(RoomSettings::default());
+    }
+
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(body).map_err(|err| ApiError::InvalidBody(err.to_string()))?;
+    let template = match fields.remove("template") {
+        None => None,
+        Some(serde_json::Value::String(name)) => match CONFIG.rooms.templates.get(&name) {
+            Some(template) => Some(template),
+            None => return Err(ApiError::TemplateNotFound(name)),
+        },
+        Some(_) => {
+            return Err(ApiError::InvalidBody(
+                "template must be the name of a room template".to_string(),
+            ))
+        }
+    };
+
+    let settings = match template {
+        Some(template) => RoomSettings::from_template(template, fields),
+        None => serde_json::from_value(serde_json::Value::Object(fields)),
+    };
+    settings.map_err(|err| ApiError::InvalidBody(err.to_string()))
+}
+
+/// Room templates of the configuration by name, served at `/templates`
+pub fn template_route() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::get())
+        .and(super::tenant())
+        .and_then(|_| async move {
+            let mut templates = Vec::new();
+            for (name, template) in &CONFIG.rooms.templates {
+                // Checked as the configuration was loaded
+                let settings = RoomSettings::from_template(template, serde_json::Map::new())
+                    .map_err(|_| warp::reject::custom(ApiError::InternalServerError))?;
+                templates.push(ListedTemplate {
+                    name: name.clone(),
+                    settings,
+                });
+            }
+            templates.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok::<_, Rejection>(warp::reply::json(&templates))
+        })
+        .boxed()
+}
+
+/// Play request naming a file of the playback directory, sent as JSON. Any other
+/// body is the audio itself
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlayFile {
+    file: String,
+}
+
+/// Worker to migrate a room to, the one hosting the fewest rooms when absent
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+struct MigrateRoom {
+    worker_id: Option<String>,
+}
+
+/// Join token an imported user redeems on this instance
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportedUser {
+    token: String,
+    token_id: String,
+}
+
+/// Instance the room was imported into and the tokens it issued, by user ID
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HandOff {
+    url: String,
+    tokens: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct HandOffReply {
+    /// Connected users told to move, users without a token stay
+    moved: Vec<String>,
+}
+
+/// The tenant's room, those of other tenants aren't found so their IDs can't be probed
+pub async fn find_room(tenant: &'static str, id: String) -> Result<Arc<Room>, Rejection> {
+    match Room::get(tenant, &id).await {
+        Some(room) => Ok(room),
+        None => Err(warp::reject::custom(ApiError::RoomNotFound(id))),
+    }
+}
+
+pub fn room_filter() -> impl Filter<Extract = (Arc<Room>,), Error = Rejection> + Copy {
+    super::tenant()
+        .and(warp::path::param::<String>())
+        .and_then(find_room)
+}
+
+/// Rooms of the tenant, copied so the room map is only locked while they're picked out
+async fn tenant_rooms(tenant: &str) -> Vec<Arc<Room>> {
+    ROOMS
+        .read()
+        .await
+        .values()
+        .filter(|room| room.tenant() == tenant)
+        .cloned()
+        .collect()
+}
+
+/// Rooms of the tenant hosted on this node a page at a time, served at `/rooms`
+pub fn list_route() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::get())
+        .and(super::tenant())
+        .and(warp::query::<ListQuery>())
+        .and_then(list_rooms)
+        .boxed()
+}
+
+async fn list_rooms(tenant: &'static str, query: ListQuery) -> Result<impl Reply, Infallible> {
+    let rooms = tenant_rooms(tenant).await;
+    let mut rooms: Vec<(Arc<Room>, usize)> = rooms
+        .into_iter()
+        .map(|room| {
+            let user_count = room.occupancy().counts().visible;
+            (room, user_count)
+        })
+        .filter(|(_, user_count)| *user_count >= query.min_users)
+        .collect();
+    rooms.sort_by(|(a, _), (b, _)| {
+        a.created_at()
+            .cmp(&b.created_at())
+            .then_with(|| a.id().cmp(b.id()))
     });
 
-    let get_room = room_filter()
-        .and(warp::path::end())
+    let total = rooms.len();
+    let mut page = Vec::new();
+    let limit = query.limit.min(MAX_PAGE_SIZE);
+    for (room, user_count) in rooms.into_iter().skip(query.offset).take(limit) {
+        page.push(ListedRoom {
+            id: room.id().to_string(),
+            user_count,
+            metadata: room.metadata().await,
+        });
+    }
+
+    Ok(warp::reply::with_header(
+        warp::reply::json(&page),
+        TOTAL_COUNT_HEADER,
+        total.to_string(),
+    ))
+}
+
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    let get_rooms = warp::path::end()
         .and(warp::get())
-        .map(|_room: Arc<Room>| {
-            warp::reply::json(&RoomReply {
-                video_allowed: false,
+        .and(super::tenant())
+        .and_then(|tenant| async move {
+            let rooms = tenant_rooms(tenant).await;
+            let rooms: Vec<&str> = rooms.iter().map(|room| room.id()).collect();
+            Ok::<warp::reply::Json, Infallible>(warp::reply::json(&rooms))
+        });
+
+    // Match the method before looking up the room, so a request meant for
+    // another route isn't rejected with RoomNotFound
+    let get_room = warp::get()
+        .and(room_filter())
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            let counts = room.occupancy().counts();
+            let metadata = room.metadata().await;
+            Ok::<_, Rejection>(warp::reply::json(&RoomReply {
+                video_allowed: metadata.video_allowed(),
                 users: Vec::new(),
-            })
+                occupancy: OccupancyReply {
+                    counts,
+                    capacity_used: counts.capacity_used(),
+                    capacity: room.max_users(),
+                },
+                bans: room.bans(),
+                metadata,
+            }))
         });
 
-    let create_room = warp::path::param::<String>()
+    let create_room = super::tenant()
+        .and(warp::path::param::<String>())
         .and(warp::path::end())
         .and(warp::post())
-        .and_then(|id: String| async move {
-            match Room::new(id).await {
+        .and(super::body())
+        .and_then(|tenant: &'static str, id: String, body: Bytes| async move {
+            let settings = creation_settings(&body).map_err(warp::reject::custom)?;
+
+            // Replies with the settings the room got, templates filled in
+            match Room::new(tenant, id, settings.clone()).await {
                 Ok(_) => Ok(warp::reply::with_status(
-                    warp::reply::reply(),
+                    warp::reply::json(&settings),
                     StatusCode::CREATED,
                 )),
                 Err(err) => Err(warp::reject::custom(err)),
             }
         });
 
-    let delete_room = room_filter()
+    let update_room = warp::patch()
+        .and(room_filter())
+        .and(warp::path::end())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, body: Bytes| async move {
+            let update: RoomSettingsUpdate = serde_json::from_slice(&body)
+                .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?;
+
+            room.update_settings(update)
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::reply(),
+                StatusCode::NO_CONTENT,
+            ))
+        });
+
+    let delete_room = warp::delete()
+        .and(room_filter())
         .and(warp::path::end())
-        .and(warp::delete())
         .and_then(|room: Arc<Room>| async move {
             room.delete().await;
             Ok::<_, Infallible>(warp::reply::with_status(
@@ -65,9 +314,655 @@ pub fn route() -> BoxedFilter<(impl Reply,)> {
             ))
         });
 
+    // Bitrates are sampled at most every couple of seconds, however often this is polled
+    let get_stats = warp::get()
+        .and(room_filter())
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            Ok::<_, Rejection>(warp::reply::json(&room.stats().await))
+        });
+
+    // mediasoup's dumps, for debugging a room without attaching to the worker
+    let dump_router = warp::get()
+        .and(room_filter())
+        .and(warp::path("dump"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            let router = room.router().ok_or_else(|| {
+                warp::reject::custom(ApiError::RoomNotFound(room.id().to_string()))
+            })?;
+            let dump = dump::router(&router)
+                .await
+                .map_err(|_| warp::reject::custom(ApiError::InternalServerError))?;
+            Ok::<_, Rejection>(warp::reply::json(&dump))
+        });
+
+    let dump_producer = warp::get()
+        .and(room_filter())
+        .and(warp::path("dump"))
+        .and(warp::path("producer"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>, producer_id: String| async move {
+            let producer = room
+                .producer(&producer_id)
+                .await
+                .ok_or_else(|| warp::reject::custom(ApiError::ProducerNotFound(producer_id)))?;
+            let dump = dump::producer(&producer)
+                .await
+                .map_err(|_| warp::reject::custom(ApiError::InternalServerError))?;
+            Ok::<_, Rejection>(warp::reply::json(&dump))
+        });
+
+    // Returns once the recording is running, or stopped with its files closed
+    let start_recording = warp::post()
+        .and(room_filter())
+        .and(warp::path("recording"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            room.start_recording()
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::reply(),
+                StatusCode::NO_CONTENT,
+            ))
+        });
+
+    let stop_recording = warp::delete()
+        .and(room_filter())
+        .and(warp::path("recording"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            room.stop_recording()
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::reply(),
+                StatusCode::NO_CONTENT,
+            ))
+        });
+
+    // Returns once FFmpeg is started, progress is reported to the room's participants
+    let start_broadcast = warp::post()
+        .and(room_filter())
+        .and(warp::path("broadcast"))
+        .and(warp::path::end())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, body: Bytes| async move {
+            let request: StartBroadcast = serde_json::from_slice(&body)
+                .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?;
+
+            room.start_broadcast(request)
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::reply(),
+                StatusCode::ACCEPTED,
+            ))
+        });
+
+    // Returns once FFmpeg is started, the audio is announced as the system user producing
+    let play = warp::post()
+        .and(room_filter())
+        .and(warp::path("play"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(super::body())
+        .and_then(
+            |room: Arc<Room>, content_type: Option<String>, body: Bytes| async move {
+                let json = content_type.map_or(false, |content_type| {
+                    content_type.starts_with("application/json")
+                });
+                let source = match json {
+                    true => {
+                        let request: PlayFile = serde_json::from_slice(&body).map_err(|err| {
+                            warp::reject::custom(ApiError::InvalidBody(err.to_string()))
+                        })?;
+                        PlaybackSource::File(request.file)
+                    }
+                    false if body.is_empty() => {
+                        let message = "expected audio or a file to play".to_string();
+                        return Err(warp::reject::custom(ApiError::InvalidBody(message)));
+                    }
+                    false => PlaybackSource::Bytes(body.to_vec()),
+                };
+
+                room.play(source)
+                    .await
+                    .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+                Ok::<_, Rejection>(warp::reply::with_status(
+                    warp::reply::reply(),
+                    StatusCode::ACCEPTED,
+                ))
+            },
+        );
+
+    // Returns once connections were told to move, with how each step went. A failed
+    // step leaves the room where it was and is answered with a 502
+    let migrate = warp::post()
+        .and(room_filter())
+        .and(warp::path("migrate"))
+        .and(warp::path::end())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, body: Bytes| async move {
+            let request: MigrateRoom = match body.is_empty() {
+                true => MigrateRoom::default(),
+                false => serde_json::from_slice(&body)
+                    .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?,
+            };
+
+            let migration = room
+                .migrate(request.worker_id.as_deref())
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            let status = match migration.aborted {
+                true => StatusCode::BAD_GATEWAY,
+                false => StatusCode::OK,
+            };
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::json(&migration),
+                status,
+            ))
+        });
+
+    // Signaling state only, for `import` on the instance taking over the room
+    let export_room = warp::get()
+        .and(room_filter())
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            Ok::<_, Rejection>(warp::reply::json(&room.export().await))
+        });
+
+    // Replies with a join token for each user, those the old instance issued aren't
+    // valid here
+    let import_room = super::tenant()
+        .and(warp::path::param::<String>())
+        .and(warp::path("import"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(super::body())
+        .and_then(|tenant: &'static str, id: String, body: Bytes| async move {
+            let export: RoomExport = serde_json::from_slice(&body)
+                .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?;
+
+            let (_, tokens) = Room::import(tenant, id, export)
+                .await
+                .map_err(warp::reject::custom)?;
+            let users: HashMap<String, ImportedUser> = tokens
+                .into_iter()
+                .map(|(id, join_token)| {
+                    let user = ImportedUser {
+                        token: join_token.token,
+                        token_id: join_token.id,
+                    };
+                    (id, user)
+                })
+                .collect();
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::json(&users),
+                StatusCode::CREATED,
+            ))
+        });
+
+    // Tells connected clients to reconnect to the instance the room was imported into
+    let hand_off = warp::post()
+        .and(room_filter())
+        .and(warp::path("handoff"))
+        .and(warp::path::end())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, body: Bytes| async move {
+            let request: HandOff = serde_json::from_slice(&body)
+                .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?;
+
+            let moved = room.hand_off(&request.url, &request.tokens);
+            Ok::<_, Rejection>(warp::reply::json(&HandOffReply { moved }))
+        });
+
+    let stop_broadcast = warp::delete()
+        .and(room_filter())
+        .and(warp::path("broadcast"))
+        .and(warp::path::end())
+        .and_then(|room: Arc<Room>| async move {
+            room.stop_broadcast()
+                .await
+                .map_err(|err| warp::reject::custom(ApiError::from(err)))?;
+            Ok::<_, Rejection>(warp::reply::with_status(
+                warp::reply::reply(),
+                StatusCode::NO_CONTENT,
+            ))
+        });
+
     get_rooms
         .or(get_room)
         .or(create_room)
+        .or(update_room)
         .or(delete_room)
+        .or(get_stats)
+        .or(dump_router)
+        .or(dump_producer)
+        .or(start_recording)
+        .or(stop_recording)
+        .or(start_broadcast)
+        .or(stop_broadcast)
+        .or(play)
+        .or(migrate)
+        .or(export_room)
+        .or(import_room)
+        .or(hand_off)
         .boxed()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::error::handle_rejection;
+    use crate::rtc::playback::SYSTEM_USER_ID;
+    use crate::state::user::{Role, UserOptions};
+    use crate::util::config::DEFAULT_TENANT;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn room_stats() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = route().recover(handle_rejection);
+        let request = |path: String| testing::request().method("GET").path(&path);
+
+        let response = request(format!("/{}/stats", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(stats["userCount"], 0);
+        assert_eq!(stats["users"], serde_json::json!({}));
+        assert_eq!(stats["bitrates"]["incoming"], 0);
+        assert_eq!(
+            stats["retransmissions"],
+            serde_json::json!({ "nacks": 0, "plis": 0, "retransmittedBytes": 0 })
+        );
+        assert!(stats["workerId"].is_string());
+        assert!(stats.get("throttle").is_none());
+
+        let response = request("/missing/stats".to_string()).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        room.delete().await;
+
+        // Rooms with a bitrate budget show how far they're throttled
+        let settings = RoomSettings {
+            bitrate_budget: Some(2_000_000),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let response = request(format!("/{}/stats", room.id()))
+            .reply(&routes)
+            .await;
+        let stats: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            stats["throttle"],
+            serde_json::json!({ "budget": 2_000_000, "level": 0 })
+        );
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn router_dumps() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = route().recover(handle_rejection);
+        let request = |path: String| testing::request().method("GET").path(&path);
+
+        let response = request(format!("/{}/dump", room.id())).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let dump: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(dump["truncated"], false);
+        assert!(dump["dump"]["id"].is_string());
+
+        let response = request(format!("/{}/dump/producer/missing", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_migrated_between_workers() {
+        let room = testing::room(RoomSettings::default()).await;
+        let from = room.worker_id();
+        let routes = route().recover(handle_rejection);
+        let path = format!("/{}/migrate", room.id());
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .json(&serde_json::json!({ "workerId": "nowhere" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let migration: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(migration["from"], from.to_string());
+        assert_eq!(migration["to"], room.worker_id().to_string());
+        assert_eq!(migration["aborted"], false);
+        assert_eq!(migration["steps"][0]["step"], "createRouter");
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn exported_rooms_are_imported_intact() {
+        let room = testing::room(RoomSettings::default()).await;
+        room.ban("mallory", Some("spam".to_string()));
+        room.set_metadata("topic".to_string(), serde_json::json!("standup"))
+            .unwrap();
+        let options = UserOptions {
+            role: Role::Moderator,
+            ..UserOptions::default()
+        };
+        room.users()
+            .create("alice".to_string(), options)
+            .await
+            .unwrap();
+        let routes = route().recover(handle_rejection);
+
+        let response = testing::request()
+            .path(&format!("/{}/export", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let export: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(export["users"][0]["id"], "alice");
+
+        let id = format!("{}-imported", room.id());
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/import", id))
+            .body(response.body().clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let tokens: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+        let imported = Room::get(DEFAULT_TENANT, &id).await.unwrap();
+        assert!(imported.banned("mallory"));
+        assert_eq!(imported.metadata().await.values["topic"], "standup");
+        let users = imported.users();
+        let token = tokens["alice"]["token"].as_str().unwrap();
+        let registration = users.register(token, 1).await.unwrap();
+        assert_eq!(registration.user.read().await.role(), Role::Moderator);
+        drop(registration);
+        drop(users);
+
+        // Nobody connected on the old instance, so nobody is told to move
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/handoff", room.id()))
+            .json(&serde_json::json!({ "url": "wss://next", "tokens": { "alice": token } }))
+            .reply(&routes)
+            .await;
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reply["moved"], serde_json::json!([]));
+        imported.delete().await;
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn recording_requires_configuration() {
+        let room = testing::room(RoomSettings::default()).await;
+        let request = |method: &'static str| {
+            testing::request()
+                .method(method)
+                .path(&format!("/{}/recording", room.id()))
+        };
+
+        let routes = route().recover(handle_rejection);
+        let response = request("POST").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        let response = request("DELETE").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(!room.recording().await);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn broadcast_requires_configuration() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = route().recover(handle_rejection);
+        let path = format!("/{}/broadcast", room.id());
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .body(r#"{ "url": "rtmp://ingest.example.com/live/key" }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .body(r#"{ "users": [] }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = testing::request()
+            .method("DELETE")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(!room.broadcasting().await);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn playback_requires_configuration() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = route().recover(handle_rejection);
+        let path = format!("/{}/play", room.id());
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .header("content-type", "application/json")
+            .body(r#"{ "file": "chime.ogg" }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let response = testing::request()
+            .method("POST")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(room.playback_producer().await.is_none());
+
+        // Nobody can pass for the audio the server plays
+        let result = room
+            .users()
+            .create(SYSTEM_USER_ID.to_string(), UserOptions::default())
+            .await;
+        assert!(matches!(result, Err(ApiError::UserAlreadyExists(_))));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn hls_requires_configuration() {
+        testing::init();
+        let routes = route().recover(handle_rejection);
+        let id = format!("test-{}", rand::random::<u64>());
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}", id))
+            .body(r#"{ "hls": {} }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert!(Room::get(DEFAULT_TENANT, &id).await.is_none());
+
+        let room = testing::room(RoomSettings::default()).await;
+        let response = testing::request()
+            .method("PATCH")
+            .path(&format!("/{}", room.id()))
+            .body(r#"{ "hls": { "videoUser": "host" } }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert!(room.hls_directory().await.is_none());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn creation_replies_with_the_settings() {
+        testing::init();
+        let routes = route().recover(handle_rejection);
+        let create = |id: &str, body: &'static str| {
+            testing::request()
+                .method("POST")
+                .path(&format!("/{}", id))
+                .body(body)
+        };
+
+        let id = format!("test-{}", rand::random::<u64>());
+        let response = create(&id, r#"{ "template": "missing" }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = create(&id, r#"{ "template": 1 }"#).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(Room::get(DEFAULT_TENANT, &id).await.is_none());
+
+        let response = create(&id, r#"{ "maxUsers": 3 }"#).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let settings: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(settings["maxUsers"], 3);
+        assert_eq!(settings["recordingAllowed"], true);
+        Room::get(DEFAULT_TENANT, &id).await.unwrap().delete().await;
+
+        let templates = template_route().recover(handle_rejection);
+        let response = testing::request()
+            .method("GET")
+            .path("/")
+            .reply(&templates)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"[]");
+    }
+
+    #[tokio::test]
+    async fn rooms_choose_their_codecs() {
+        testing::init();
+        let routes = route().recover(handle_rejection);
+        let create = |id: String, body: &'static str| {
+            testing::request()
+                .method("POST")
+                .path(&format!("/{}", id))
+                .body(body)
+        };
+
+        let id = format!("test-{}", rand::random::<u64>());
+        let response = create(id.clone(), r#"{ "codecs": [{ "codec": "vp8" }] }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(Room::get(DEFAULT_TENANT, &id).await.is_none());
+
+        let body = r#"{ "codecs": [{ "codec": "opus", "channels": 2 }, { "codec": "h264" }] }"#;
+        let response = create(id.clone(), body).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let room = Room::get(DEFAULT_TENANT, &id).await.unwrap();
+        let router = room.router().unwrap();
+        let codecs = &router.rtp_capabilities().codecs;
+        let mime_types: Vec<String> = codecs
+            .iter()
+            .map(|codec| serde_json::to_value(codec).unwrap()["mimeType"].to_string())
+            .collect();
+        assert!(mime_types
+            .iter()
+            .any(|mime_type| mime_type.contains("H264")));
+        assert!(!mime_types.iter().any(|mime_type| mime_type.contains("VP8")));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn rooms_are_listed_a_page_at_a_time() {
+        let first = testing::room(RoomSettings::default()).await;
+        let second = testing::room(RoomSettings::default()).await;
+        second
+            .users()
+            .join("alice".to_string(), UserOptions::default(), 1)
+            .await
+            .unwrap();
+
+        let routes = list_route().recover(handle_rejection);
+        let request = |query: &str| {
+            testing::request()
+                .method("GET")
+                .path(&format!("/?{}", query))
+        };
+
+        // Other tests create rooms in parallel, so only the relative order is checked
+        let response = request("limit=200").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rooms: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+        let total: usize = response.headers()[TOTAL_COUNT_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(total >= 2);
+        let position = |id: &str| rooms.iter().position(|room| room["id"] == id).unwrap();
+        assert!(position(first.id()) < position(second.id()));
+        assert_eq!(rooms[position(second.id())]["userCount"], 1);
+        assert!(rooms[position(first.id())]["settings"].is_object());
+
+        let response = request("minUsers=1&limit=200").reply(&routes).await;
+        let rooms: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+        assert!(rooms.iter().any(|room| room["id"] == second.id()));
+        assert!(!rooms.iter().any(|room| room["id"] == first.id()));
+
+        let response = request("offset=1&limit=1").reply(&routes).await;
+        let rooms: Vec<serde_json::Value> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(rooms.len(), 1);
+
+        let response = request("limit=many").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        first.delete().await;
+        second.delete().await;
+    }
+
+    #[tokio::test]
+    async fn room_reply_carries_metadata() {
+        let room = testing::room(RoomSettings::default()).await;
+        let response = testing::request()
+            .method("PATCH")
+            .path(&format!("/{}", room.id()))
+            .body(r#"{ "maxIncomingBitrate": 300000, "persistent": true }"#)
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = testing::request()
+            .method("GET")
+            .path(&format!("/{}", room.id()))
+            .reply(&route())
+            .await;
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reply["videoAllowed"], true);
+        assert_eq!(reply["settings"]["maxIncomingBitrate"], 300000);
+        assert_eq!(reply["settings"]["persistent"], true);
+        assert_eq!(
+            reply["createdAt"],
+            room.created_at()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        );
+        room.delete().await;
+    }
+}
@@ -1,28 +1,122 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-use warp::Filter;
+use warp::hyper::body::Bytes;
 use warp::{filters::BoxedFilter, http::StatusCode, reply::Reply};
+use warp::{Filter, Rejection};
 
 use crate::api::ApiError;
-use crate::state::room::Room;
+use crate::integrations::audit::{self, AuditAction, AuditRecord};
+use crate::rtc::quality::QualityLevel;
+use crate::rtc::stats::ConnectionStats;
+use crate::state::room::{users::MAX_KICK_REASON, Room, TalkStats};
+use crate::state::user::{
+    metadata_fits, ConnectionState, Permission, QualityChange, UserInfo, UserOptions,
+    MAX_METADATA_SIZE,
+};
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct CreateUserReply {
     token: String,
+    /// Revokes the token while it's unused, see `DELETE /room/{id}/join-tokens/{token_id}`
+    token_id: String,
+}
+
+/// User of `GET /room/{id}/users`, hidden and pending users included
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PresentUser {
+    id: String,
+    /// What participants are shown, role, connection state and producers included
+    info: UserInfo,
+    permissions: Vec<Permission>,
+    hidden: bool,
+    /// Created with a join token that wasn't redeemed yet
+    pending: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct KickBody {
+    /// Shown to the kicked user
+    reason: Option<String>,
+}
+
+impl KickBody {
+    /// Optional, an empty body gives no reason
+    fn parse(body: &[u8]) -> Result<Self, ApiError> {
+        let body: KickBody = match body.is_empty() {
+            true => KickBody::default(),
+            false => serde_json::from_slice(body)
+                .map_err(|err| ApiError::InvalidBody(err.to_string()))?,
+        };
+        if let Some(reason) = &body.reason {
+            if reason.len() > MAX_KICK_REASON {
+                let message = format!("reason is longer than {} bytes", MAX_KICK_REASON);
+                return Err(ApiError::InvalidBody(message));
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Reply of `DELETE /room/{id}/user/{user_id}`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RemovedUser {
+    /// State of the user's connection when it was removed, absent if the user never
+    /// redeemed its join token
+    connection_state: Option<ConnectionState>,
+}
+
+/// Reply of `GET /room/{id}/user/{user_id}/stats`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserStatsReply {
+    connection_state: ConnectionState,
+    /// Seconds the user's current connection has held its session, absent if the user
+    /// never redeemed its join token
+    connected_secs: Option<u64>,
+    quality: Option<QualityLevel>,
+    /// Latest changes of the sampled quality, oldest first
+    quality_history: Vec<QualityChange>,
+    /// Sampled from the worker on request, absent if the connection has no transports
+    /// or didn't answer in time
+    media: Option<ConnectionStats>,
+    /// How the user spoke in the call going on, absent until it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    talk: Option<TalkStats>,
 }
 
 pub fn route() -> BoxedFilter<(impl Reply,)> {
-    let root = super::room::room_filter().and(warp::path("user"));
+    let root = super::tenant()
+        .and(warp::path::param::<String>())
+        .and(warp::path("user"))
+        .and_then(super::room::find_room);
 
     let create_user = root
+        .clone()
         .and(warp::path::param::<String>())
         .and(warp::path::end())
         .and(warp::post())
-        .and_then(|room: Arc<Room>, id: String| async move {
+        .and(super::body())
+        .and_then(|room: Arc<Room>, id: String, body: Bytes| async move {
+            let options: UserOptions = match body.is_empty() {
+                true => UserOptions::default(),
+                false => serde_json::from_slice(&body)
+                    .map_err(|err| warp::reject::custom(ApiError::InvalidBody(err.to_string())))?,
+            };
+            if !metadata_fits(&options.metadata) {
+                let message = format!("metadata is larger than {} bytes", MAX_METADATA_SIZE);
+                return Err(warp::reject::custom(ApiError::InvalidBody(message)));
+            }
+
+            // Unlike a JWT, which takes over the session, a connected user is kicked
+            // and the new token registers a fresh user
             let users = room.users();
-            let user_lock = match users.new(id.clone()).await {
-                Ok(user) => user,
+            let join_token = match users.create(id.clone(), options.clone()).await {
+                Ok(join_token) => join_token,
                 Err(ApiError::UserAlreadyExists(_)) => {
                     debug!(
                         "User {} in room {} already exists, kicking them",
@@ -30,19 +124,519 @@ pub fn route() -> BoxedFilter<(impl Reply,)> {
                         room.id()
                     );
                     users.remove(&id).await.ok();
-                    users.new(id).await?
+                    users.create(id, options).await?
                 }
                 Err(err) => return Err(warp::reject::custom(err)),
             };
 
-            let user = user_lock.read().await;
             Ok(warp::reply::with_status(
                 warp::reply::json(&CreateUserReply {
-                    token: user.token().unwrap().to_string(),
+                    token: join_token.token,
+                    token_id: join_token.id,
                 }),
                 StatusCode::CREATED,
             ))
         });
 
-    create_user.boxed()
+    let kick_user = root
+        .clone()
+        .and(warp::path::param::<String>())
+        .and(warp::path("kick"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, id: String, body: Bytes| async move {
+            let body = KickBody::parse(&body)?;
+            room.users()
+                .kick(&id, body.reason, None)
+                .await
+                .map_err(|_| warp::reject::custom(ApiError::UserNotFound(id)))?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    // For the backend to eject a user whatever the call thinks, e.g. when its account
+    // was suspended. Its connection is closed with the reason like a kick
+    let remove_user = root
+        .clone()
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(super::body())
+        .and_then(|room: Arc<Room>, id: String, body: Bytes| async move {
+            let body = KickBody::parse(&body)?;
+            let users = room.users();
+            let connection_state = match users.get(&id).await {
+                Some(user) => {
+                    let user = user.read().await;
+                    Some(user.connection_state()).filter(|_| user.registered())
+                }
+                None => return Err(warp::reject::custom(ApiError::UserNotFound(id))),
+            };
+
+            users
+                .kick(&id, body.reason, None)
+                .await
+                .map_err(|_| warp::reject::custom(ApiError::UserNotFound(id)))?;
+            Ok::<_, Rejection>(warp::reply::json(&RemovedUser { connection_state }))
+        });
+
+    // For support looking into a user's call without asking it for client diagnostics
+    let user_stats = root
+        .clone()
+        .and(warp::path::param::<String>())
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(|room: Arc<Room>, id: String| async move {
+            let user = match room.users().get(&id).await {
+                Some(user) => user,
+                None => return Err(warp::reject::custom(ApiError::UserNotFound(id))),
+            };
+            let user = user.read().await;
+            let connection_id = user.connection_id();
+            let mut reply = UserStatsReply {
+                connection_state: user.connection_state(),
+                connected_secs: user.connected_for().map(|duration| duration.as_secs()),
+                quality: user.quality(),
+                quality_history: user.quality_history().copied().collect(),
+                media: None,
+                talk: room.usage().talk_stats_of(&id),
+            };
+            drop(user);
+
+            if let Some(connection_id) = connection_id {
+                reply.media = room.connection_stats(connection_id).await;
+            }
+            Ok::<_, Rejection>(warp::reply::json(&reply))
+        });
+
+    // Users abusing `RelayBroadcast` are muted rather than kicked, they may not even
+    // have joined yet
+    let relay_mute = root
+        .and(warp::path::param::<String>())
+        .and(warp::path("relay-mute"))
+        .and(warp::path::end())
+        .and(
+            warp::put()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .map(|room: Arc<Room>, id: String, muted: bool| {
+            room.set_relay_muted(&id, muted);
+            let action = match muted {
+                true => AuditAction::RelayMuted,
+                false => AuditAction::RelayUnmuted,
+            };
+            audit::record(AuditRecord::new(room.scoped_id(), action).target(&id));
+            StatusCode::NO_CONTENT
+        });
+
+    create_user
+        .or(kick_user)
+        .or(remove_user)
+        .or(user_stats)
+        .or(relay_mute)
+        .boxed()
+}
+
+/// Everyone in the room, for showing who's in a call without connecting to it. Users of
+/// the room connected to other instances aren't listed
+pub fn presence_route() -> BoxedFilter<(impl Reply,)> {
+    super::tenant()
+        .and(warp::path::param::<String>())
+        .and(warp::path("users"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(super::room::find_room)
+        .and_then(|room: Arc<Room>| async move {
+            let users = room.users();
+            let guard = users.guard().await;
+            let mut present = Vec::new();
+            for user in guard.iter() {
+                let user = user.read().await;
+                present.push(PresentUser {
+                    id: user.id().to_string(),
+                    info: user.into_info(),
+                    permissions: user.permissions().to_vec(),
+                    hidden: user.hidden(),
+                    pending: !user.registered(),
+                });
+            }
+            drop(guard);
+
+            present.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok::<_, Rejection>(warp::reply::json(&present))
+        })
+        .boxed()
+}
+
+/// Tokens of users who were never meant to get in after all, e.g. banned before connecting
+pub fn join_token_route() -> BoxedFilter<(impl Reply,)> {
+    super::room::room_filter()
+        .and(warp::path("join-tokens"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and_then(|room: Arc<Room>, token_id: String| async move {
+            room.users().revoke(&token_id).await?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        })
+        .boxed()
+}
+
+/// Bans are made by moderators over the WebSocket, and lifted here
+pub fn ban_route() -> BoxedFilter<(impl Reply,)> {
+    super::room::room_filter()
+        .and(warp::path("bans"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and_then(|room: Arc<Room>, user_id: String| async move {
+            if !room.unban(&user_id) {
+                return Err(warp::reject::custom(ApiError::BanNotFound(user_id)));
+            }
+            audit::record(
+                AuditRecord::new(room.scoped_id(), AuditAction::Unbanned).target(&user_id),
+            );
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::{
+        subscriber::{SubscriberMessage, SubscriberOptions},
+        token::TokenError,
+        RoomEvent, RoomSettings,
+    };
+    use crate::util::config::CONFIG;
+    use crate::util::testing;
+    use crate::ws::{error::WSCloseType, types::SignalingTransport};
+
+    #[tokio::test]
+    async fn creating_a_connected_user_kicks_them() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "alice", options).unwrap();
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserJoined(id, _)) => assert_eq!(id, "alice"),
+            _ => panic!("Expected the join event"),
+        }
+
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/user/alice", room.id()))
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // The connection sees itself leave and closes with Kicked
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserLeft(id, kicked)) => {
+                assert_eq!((id.as_str(), kicked), ("alice", false))
+            }
+            _ => panic!("Expected the connected user to be kicked"),
+        }
+        let user = users.get("alice").await.unwrap();
+        assert!(!user.read().await.registered());
+        drop(user);
+
+        let counts = room.occupancy().counts();
+        assert_eq!((counts.visible, counts.pending), (0, 1));
+        drop((subscriber, users));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn user_stats_are_gathered_by_its_connection() {
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "alice", options).unwrap();
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        let user = users.get("alice").await.unwrap();
+        user.write().await.set_quality(QualityLevel::Bad);
+        drop(user);
+
+        // Stands in for the connection, which gathers them from its transports
+        let connection = tokio::spawn(async move {
+            loop {
+                if let SubscriberMessage::Stats(reply) = subscriber.recv().await {
+                    reply.send(ConnectionStats::default()).ok();
+                    return subscriber;
+                }
+            }
+        });
+
+        let routes = route().recover(crate::api::error::handle_rejection);
+        let response = testing::request()
+            .path(&format!("/{}/user/alice/stats", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(stats["connectionState"], "connected");
+        assert_eq!(stats["connectedSecs"], 0);
+        assert_eq!(stats["quality"], "bad");
+        assert_eq!(stats["qualityHistory"][0]["level"], "bad");
+        assert_eq!(stats["media"]["transports"], serde_json::json!([]));
+
+        let response = testing::request()
+            .path(&format!("/{}/user/bob/stats", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        drop((connection.await.unwrap(), users));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = route().recover(crate::api::error::handle_rejection);
+        let metadata = "x".repeat(CONFIG.signaling.max_message_size);
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/user/alice", room.id()))
+            .json(&serde_json::json!({ "metadata": metadata }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(room.users().get("alice").await.is_none());
+
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/user/alice", room.id()))
+            .json(&serde_json::json!({ "role": "speaker" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn kicks_carry_their_reason() {
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "alice", options).unwrap();
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+
+        let routes = route().recover(crate::api::error::handle_rejection);
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/user/alice/kick", room.id()))
+            .body(r#"{ "reason": "Spamming the chat" }"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(users.get("alice").await.is_none());
+
+        // The close comes ahead of the leave, so the connection knows why
+        match subscriber.recv().await {
+            SubscriberMessage::Close(WSCloseType::Kicked(reason)) => {
+                assert_eq!(reason.as_deref(), Some("Spamming the chat"))
+            }
+            _ => panic!("Expected the connection to be closed"),
+        }
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserLeft(id, kicked)) => {
+                assert_eq!((id.as_str(), kicked), ("alice", true))
+            }
+            _ => panic!("Expected the others to see the kick"),
+        }
+
+        let response = testing::request()
+            .method("POST")
+            .path(&format!("/{}/user/alice/kick", room.id()))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        drop((subscriber, users));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn bans_are_listed_until_lifted() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("mallory".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        room.ban("mallory", Some("Trolling".to_string()));
+        assert!(matches!(
+            users.register(&token, 1).await,
+            Err(TokenError::Banned)
+        ));
+
+        let routes = ban_route()
+            .or(crate::api::room::route())
+            .recover(crate::api::error::handle_rejection);
+        let response = testing::request()
+            .path(&format!("/{}", room.id()))
+            .reply(&routes)
+            .await;
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            reply["bans"],
+            serde_json::json!([{ "userId": "mallory", "reason": "Trolling" }])
+        );
+
+        let path = format!("/{}/bans/mallory", room.id());
+        let unban = || {
+            testing::request()
+                .method("DELETE")
+                .path(&path)
+                .reply(&routes)
+        };
+        assert_eq!(unban().await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(unban().await.status(), StatusCode::NOT_FOUND);
+        assert!(users.register(&token, 1).await.is_ok());
+        drop(users);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn removed_users_report_their_connection_state() {
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "alice", options).unwrap();
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        users
+            .create("bob".to_string(), UserOptions::default())
+            .await
+            .unwrap();
+
+        let routes = route().recover(crate::api::error::handle_rejection);
+        let remove = |id: &str, body: &'static str| {
+            testing::request()
+                .method("DELETE")
+                .path(&format!("/{}/user/{}", room.id(), id))
+                .body(body)
+                .reply(&routes)
+        };
+        let response = remove("alice", r#"{ "reason": "Subscription ended" }"#).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reply["connectionState"], "connected");
+        match subscriber.recv().await {
+            SubscriberMessage::Close(WSCloseType::Kicked(reason)) => {
+                assert_eq!(reason.as_deref(), Some("Subscription ended"))
+            }
+            _ => panic!("Expected the connection to be closed"),
+        }
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserLeft(id, kicked)) => {
+                assert_eq!((id.as_str(), kicked), ("alice", true))
+            }
+            _ => panic!("Expected the others to see the kick"),
+        }
+
+        // Pending users never had a connection
+        let response = remove("bob", "").await;
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reply["connectionState"], serde_json::Value::Null);
+        assert!(users.get("bob").await.is_none());
+
+        assert_eq!(remove("bob", "").await.status(), StatusCode::NOT_FOUND);
+        drop((subscriber, users));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_cant_join() {
+        let room = testing::room(RoomSettings::default()).await;
+        let routes = join_token_route()
+            .or(route())
+            .recover(crate::api::error::handle_rejection);
+        let request = |method: &str, path: String| {
+            testing::request().method(method).path(&path).reply(&routes)
+        };
+
+        let response = request("POST", format!("/{}/user/bob", room.id())).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let token = reply["token"].as_str().unwrap();
+        let token_id = reply["tokenId"].as_str().unwrap();
+
+        let path = format!("/{}/join-tokens/{}", room.id(), token_id);
+        let response = request("DELETE", path.clone()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(room.users().get("bob").await.is_none());
+        assert!(room.users().register(token, 1).await.is_err());
+
+        let response = request("DELETE", path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn presence_flags_pending_users() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        let options = UserOptions {
+            permissions: vec![Permission::Moderator],
+            ..UserOptions::default()
+        };
+        users.create("bob".to_string(), options).await.unwrap();
+        drop(users);
+
+        let response = testing::request()
+            .method("GET")
+            .path(&format!("/{}/users", room.id()))
+            .reply(&presence_route())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let present: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(present[0]["id"], "alice");
+        assert_eq!(present[0]["pending"], false);
+        assert_eq!(present[0]["info"]["connection_state"], "connected");
+        assert_eq!(present[1]["id"], "bob");
+        assert_eq!(present[1]["pending"], true);
+        assert_eq!(present[1]["permissions"], serde_json::json!(["moderator"]));
+        room.delete().await;
+    }
 }
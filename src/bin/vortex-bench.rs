@@ -0,0 +1,716 @@
+//! Load test of a Vortex deployment. Opens connections across rooms created over the
+//! management API, goes through the `Authenticate` and `InitializeTransports` handshake,
+//! runs a mix of commands and reports how the server held up. Commands are built from
+//! the server's own types, so a client drifting from the protocol fails to compile.
+//!
+//! With `--mock` a server is started in the process with mock media, loading the
+//! signaling path alone. It's configured like the bundled binary, from the environment
+//! and the config file.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Display;
+use std::num::{NonZeroU32, NonZeroU8};
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use hyper::{body, header::AUTHORIZATION, Body, Client, Method, Request, StatusCode};
+use mediasoup::prelude::*;
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use vortex::rtc::types::{InitializationInput, InitializationInputMode};
+use vortex::state::user::{ProduceType, UserInfo};
+use vortex::util::config::CONFIG;
+use vortex::util::variables::HTTP_HOST;
+use vortex::ws::types::{
+    sequenced_text, SequencedEvent, WSCommand, WSCommandType, WSEvent, PROTOCOL_VERSION,
+};
+use vortex::ServerBuilder;
+
+const USAGE: &str = "Usage: vortex-bench [options]
+
+  --url <url>              Signaling endpoint [ws://127.0.0.1:8080/ws]
+  --api <url>              Management API [http://127.0.0.1:8080]
+  --manage-token <token>   Management token, or MANAGE_TOKEN
+  --rooms <count>          Rooms the connections are spread across [1]
+  --connections <count>    Connections opened [10]
+  --commands <count>       Commands each connection sends after the handshake [20]
+  --interval-ms <ms>       Pause between a connection's commands [100]
+  --mix <weights>          Commands picked at random by weight
+                           [roominfo=6,produce=1,consume=2,relay=1]
+  --mock                   Start a server in this process with mock media and load it
+  --fanout <users>         Rather than load a server, time serializing the userJoined events
+                           of a room filling up to this many users for everyone in it, once
+                           per connection and once per event with the text shared";
+
+/// Longest a reply is waited for before the connection counts as stuck
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest the server started with `--mock` takes to come up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    RoomInfo,
+    /// Start the audio producer, or stop it if the connection has one
+    Produce,
+    /// Consume the audio of another user, `RoomInfo` if nobody else produces any
+    Consume,
+    Relay,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "roominfo" => Ok(Action::RoomInfo),
+            "produce" => Ok(Action::Produce),
+            "consume" => Ok(Action::Consume),
+            "relay" => Ok(Action::Relay),
+            _ => Err(format!("unknown command {}", name)),
+        }
+    }
+}
+
+struct Options {
+    url: String,
+    api: String,
+    manage_token: String,
+    rooms: usize,
+    connections: usize,
+    commands: usize,
+    interval: Duration,
+    mix: Vec<(Action, u32)>,
+    mock: bool,
+    fanout: Option<usize>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            url: "ws://127.0.0.1:8080/ws".to_string(),
+            api: "http://127.0.0.1:8080".to_string(),
+            manage_token: env::var("MANAGE_TOKEN").unwrap_or_default(),
+            rooms: 1,
+            connections: 10,
+            commands: 20,
+            interval: Duration::from_millis(100),
+            mix: parse_mix("roominfo=6,produce=1,consume=2,relay=1")?,
+            mock: false,
+            fanout: None,
+        };
+
+        while let Some(arg) = args.next() {
+            if arg == "--mock" {
+                options.mock = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+            match arg.as_str() {
+                "--url" => options.url = value,
+                "--api" => options.api = value.trim_end_matches('/').to_string(),
+                "--manage-token" => options.manage_token = value,
+                "--rooms" => options.rooms = number(&arg, &value)?,
+                "--connections" => options.connections = number(&arg, &value)?,
+                "--commands" => options.commands = number(&arg, &value)?,
+                "--interval-ms" => options.interval = Duration::from_millis(number(&arg, &value)?),
+                "--mix" => options.mix = parse_mix(&value)?,
+                "--fanout" => options.fanout = Some(number(&arg, &value)?),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+
+        if options.rooms == 0 || options.connections == 0 {
+            return Err("--rooms and --connections must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+
+    fn pick(&self) -> Action {
+        let total: u32 = self.mix.iter().map(|(_, weight)| weight).sum();
+        let mut roll = rand::thread_rng().gen_range(0..total);
+        for (action, weight) in &self.mix {
+            if roll < *weight {
+                return *action;
+            }
+            roll -= weight;
+        }
+        unreachable!("Rolled past the total weight")
+    }
+}
+
+fn number<T: FromStr>(arg: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err| format!("{} {}: {}", arg, value, err))
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<(Action, u32)>, String> {
+    let mix = mix
+        .split(',')
+        .map(|entry| {
+            let (name, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not command=weight", entry))?;
+            Ok((name.trim().parse()?, number("--mix", weight.trim())?))
+        })
+        .collect::<Result<Vec<(Action, u32)>, String>>()?;
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err("--mix needs a command with a weight above 0".to_string());
+    }
+    Ok(mix)
+}
+
+/// What a connection saw, merged into the report once they're all done
+#[derive(Default)]
+struct ConnectionReport {
+    /// Authenticated and initialized its transports
+    connected: bool,
+    latencies: HashMap<&'static str, Vec<Duration>>,
+    errors: HashMap<&'static str, usize>,
+    /// Close code the server sent, or how the connection ended without one
+    closed: Option<String>,
+}
+
+impl ConnectionReport {
+    fn close(mut self, closed: Closed) -> Self {
+        self.closed = Some(match closed {
+            Closed::Code(code) => code.to_string(),
+            Closed::Dropped => "dropped".to_string(),
+            Closed::TimedOut => "timed out".to_string(),
+            Closed::Refused(reason) => format!("refused ({})", reason),
+        });
+        self
+    }
+}
+
+/// How a connection ended before the bench was done with it
+enum Closed {
+    Code(u16),
+    Dropped,
+    TimedOut,
+    Refused(String),
+}
+
+/// A connection's view of the room, enough to pick what to consume
+#[derive(Default)]
+struct Session {
+    user_id: String,
+    next_id: u64,
+    producing: bool,
+    /// Other users producing audio
+    audio_producers: HashSet<String>,
+    consumed: HashSet<String>,
+}
+
+impl Session {
+    fn observe(&mut self, event: &Value) {
+        let data = &event["data"];
+        let id = match data["id"].as_str() {
+            Some(id) if id != self.user_id => id.to_string(),
+            _ => return,
+        };
+        match (event["type"].as_str(), data["type"].as_str()) {
+            (Some("userStartProduce"), Some("audio")) => {
+                self.audio_producers.insert(id);
+            }
+            (Some("userStopProduce"), Some("audio")) | (Some("userLeft"), _) => {
+                self.audio_producers.remove(&id);
+                self.consumed.remove(&id);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Send a command and wait for its reply, timing it. Events received in the meantime
+/// are passed to the session
+async fn request<W, R>(
+    (sink, stream): (&mut W, &mut R),
+    session: &mut Session,
+    report: &mut ConnectionReport,
+    command_type: WSCommandType,
+) -> Result<Value, Closed>
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    session.next_id += 1;
+    let id = session.next_id.to_string();
+    let name: &'static str = (&command_type).into();
+    let command = WSCommand::new(id.clone(), command_type);
+    let frame = serde_json::to_string(&command).expect("Commands serialize");
+
+    let sent = Instant::now();
+    sink.send(Message::Text(frame))
+        .await
+        .map_err(|_| Closed::Dropped)?;
+    let deadline = tokio::time::Instant::from_std(sent + REPLY_TIMEOUT);
+    loop {
+        let message = match tokio::time::timeout_at(deadline, stream.next()).await {
+            Err(_) => return Err(Closed::TimedOut),
+            Ok(None) | Ok(Some(Err(_))) => return Err(Closed::Dropped),
+            Ok(Some(Ok(message))) => message,
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(frame) => {
+                return Err(
+                    frame.map_or(Closed::Dropped, |frame| Closed::Code(u16::from(frame.code)))
+                )
+            }
+            _ => continue,
+        };
+        let value: Value = match serde_json::from_str(&text) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        if value["id"].as_str() != Some(id.as_str()) {
+            session.observe(&value);
+            continue;
+        }
+
+        report
+            .latencies
+            .entry(name)
+            .or_default()
+            .push(sent.elapsed());
+        if value.get("error").is_some() {
+            *report.errors.entry(name).or_default() += 1;
+        }
+        return Ok(value);
+    }
+}
+
+/// Opus at the payload type a browser would pick, enough for the server to take it
+fn audio_parameters() -> RtpParameters {
+    RtpParameters {
+        codecs: vec![RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type: 111,
+            clock_rate: NonZeroU32::new(48000).unwrap(),
+            channels: NonZeroU8::new(2).unwrap(),
+            parameters: RtpCodecParametersParameters::default(),
+            rtcp_feedback: Vec::new(),
+        }],
+        encodings: vec![RtpEncodingParameters {
+            ssrc: Some(rand::random()),
+            ..RtpEncodingParameters::default()
+        }],
+        ..RtpParameters::default()
+    }
+}
+
+async fn run_connection(
+    options: Arc<Options>,
+    room_id: String,
+    user_id: String,
+    token: String,
+) -> ConnectionReport {
+    let mut report = ConnectionReport::default();
+    let socket = match tokio_tungstenite::connect_async(options.url.as_str()).await {
+        Ok((socket, _)) => socket,
+        Err(err) => return report.close(Closed::Refused(err.to_string())),
+    };
+    let (mut sink, mut stream) = socket.split();
+    let mut session = Session {
+        user_id,
+        ..Session::default()
+    };
+
+    let authenticate = WSCommandType::Authenticate {
+        room_id,
+        tenant: None,
+        token,
+        scores: false,
+        debug: false,
+        version: PROTOCOL_VERSION,
+        capabilities: None,
+        subscriptions: None,
+    };
+    let reply = match request(
+        (&mut sink, &mut stream),
+        &mut session,
+        &mut report,
+        authenticate,
+    )
+    .await
+    {
+        Ok(reply) => reply,
+        Err(closed) => return report.close(closed),
+    };
+    let data = &reply["data"];
+    if let Some(users) = data["users"].as_object() {
+        for (id, info) in users {
+            if id != &session.user_id && info["audio"].as_bool() == Some(true) {
+                session.audio_producers.insert(id.clone());
+            }
+        }
+    }
+    let rtp_capabilities: RtpCapabilities =
+        match serde_json::from_value(data["rtpCapabilities"].clone()) {
+            Ok(rtp_capabilities) => rtp_capabilities,
+            Err(_) => return report,
+        };
+
+    let init_data =
+        InitializationInput::new(rtp_capabilities, InitializationInputMode::SplitWebRtc);
+    let initialize = WSCommandType::InitializeTransports { init_data };
+    match request(
+        (&mut sink, &mut stream),
+        &mut session,
+        &mut report,
+        initialize,
+    )
+    .await
+    {
+        Ok(reply) if reply.get("error").is_none() => report.connected = true,
+        Ok(_) => return report,
+        Err(closed) => return report.close(closed),
+    }
+
+    for _ in 0..options.commands {
+        tokio::time::sleep(options.interval).await;
+        let consumable = session
+            .audio_producers
+            .difference(&session.consumed)
+            .next()
+            .cloned();
+        let command_type = match (options.pick(), consumable) {
+            (Action::Produce, _) if session.producing => WSCommandType::StopProduce {
+                produce_type: ProduceType::Audio,
+            },
+            (Action::Produce, _) => WSCommandType::StartProduce {
+                produce_type: ProduceType::Audio,
+                rtp_parameters: audio_parameters(),
+                opus: None,
+                paused: false,
+            },
+            (Action::Consume, Some(user_id)) => {
+                session.consumed.insert(user_id.clone());
+                WSCommandType::StartConsume {
+                    produce_type: ProduceType::Audio,
+                    user_id,
+                }
+            }
+            (Action::Relay, _) => WSCommandType::RelayBroadcast {
+                payload: json!({ "bench": session.next_id }),
+            },
+            (Action::RoomInfo, _) | (Action::Consume, None) => WSCommandType::RoomInfo(None),
+        };
+        let produce = matches!(
+            command_type,
+            WSCommandType::StartProduce { .. } | WSCommandType::StopProduce { .. }
+        );
+
+        match request(
+            (&mut sink, &mut stream),
+            &mut session,
+            &mut report,
+            command_type,
+        )
+        .await
+        {
+            Ok(reply) if produce && reply.get("error").is_none() => {
+                session.producing = !session.producing
+            }
+            Ok(_) => (),
+            Err(closed) => return report.close(closed),
+        }
+    }
+
+    sink.send(Message::Close(None)).await.ok();
+    // Drain until the server closes too, so its close code is the one reported
+    while let Ok(Some(Ok(message))) = tokio::time::timeout(REPLY_TIMEOUT, stream.next()).await {
+        if let Message::Close(frame) = message {
+            let code = frame.map_or(1005, |frame| u16::from(frame.code));
+            return report.close(Closed::Code(code));
+        }
+    }
+    report.close(Closed::Dropped)
+}
+
+/// Management API calls setting up the rooms and their users
+struct Api {
+    client: Client<hyper::client::HttpConnector>,
+    base: String,
+    token: String,
+}
+
+impl Api {
+    async fn call(&self, method: Method, path: &str) -> Result<(StatusCode, Value), String> {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, path))
+            .header(AUTHORIZATION, format!("Bearer {}", self.token))
+            .body(Body::empty())
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body())
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok((
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        ))
+    }
+
+    /// Rooms left over from an earlier run are reused
+    async fn create_room(&self, room_id: &str) -> Result<(), String> {
+        match self
+            .call(Method::POST, &format!("/room/{}", room_id))
+            .await?
+        {
+            (status, _) if status.is_success() || status == StatusCode::CONFLICT => Ok(()),
+            (status, body) => Err(format!("creating room {}: {} {}", room_id, status, body)),
+        }
+    }
+
+    async fn create_user(&self, room_id: &str, user_id: &str) -> Result<String, String> {
+        let path = format!("/room/{}/user/{}", room_id, user_id);
+        match self.call(Method::POST, &path).await? {
+            (status, body) if status.is_success() => body["token"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("no token for user {}", user_id)),
+            (status, body) => Err(format!("creating user {}: {} {}", user_id, status, body)),
+        }
+    }
+}
+
+/// Start the server with mock media and point the bench at it once it's up
+async fn start_mock_server(options: &mut Options) -> Result<(), String> {
+    tokio::spawn(ServerBuilder::new().mock_media().run());
+
+    let port = HTTP_HOST.port();
+    options.url = format!("ws://127.0.0.1:{}/ws", port);
+    options.api = format!("http://127.0.0.1:{}", port);
+    if options.manage_token.is_empty() {
+        options.manage_token = CONFIG
+            .api
+            .manage_tokens
+            .first()
+            .cloned()
+            .unwrap_or_default();
+    }
+
+    let client = Client::new();
+    let started = Instant::now();
+    while started.elapsed() < STARTUP_TIMEOUT {
+        let uri = options.api.parse().map_err(|err| format!("{}", err))?;
+        if client.get(uri).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err("the mock server didn't start in time".to_string())
+}
+
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+    sorted[index]
+}
+
+fn print_report(options: &Options, reports: Vec<ConnectionReport>, elapsed: Duration) {
+    let connected = reports.iter().filter(|report| report.connected).count();
+    println!(
+        "{} connections across {} rooms in {:.1}s",
+        options.connections,
+        options.rooms,
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "Connected: {}/{} ({:.1}%)",
+        connected,
+        reports.len(),
+        connected as f64 * 100.0 / reports.len() as f64
+    );
+
+    let mut latencies: HashMap<&'static str, Vec<Duration>> = HashMap::new();
+    let mut errors: HashMap<&'static str, usize> = HashMap::new();
+    let mut closes: HashMap<String, usize> = HashMap::new();
+    for report in reports {
+        for (name, samples) in report.latencies {
+            latencies.entry(name).or_default().extend(samples);
+        }
+        for (name, count) in report.errors {
+            *errors.entry(name).or_default() += count;
+        }
+        if let Some(closed) = report.closed {
+            *closes.entry(closed).or_default() += 1;
+        }
+    }
+
+    println!();
+    println!(
+        "{:<22} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        "Reply latency", "count", "errors", "p50", "p90", "p99", "max"
+    );
+    let mut names: Vec<_> = latencies.keys().copied().collect();
+    names.sort_unstable();
+    for name in names {
+        let samples = latencies.get_mut(name).unwrap();
+        samples.sort_unstable();
+        let millis = |duration: Duration| format!("{:.1}ms", duration.as_secs_f64() * 1000.0);
+        println!(
+            "{:<22} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}",
+            name,
+            samples.len(),
+            errors.get(name).copied().unwrap_or(0),
+            millis(percentile(samples, 0.5)),
+            millis(percentile(samples, 0.9)),
+            millis(percentile(samples, 0.99)),
+            millis(*samples.last().unwrap()),
+        );
+    }
+
+    println!();
+    println!("Closes:");
+    let mut closes: Vec<_> = closes.into_iter().collect();
+    closes.sort();
+    for (closed, count) in closes {
+        println!("  {:<20} {}", closed, count);
+    }
+}
+
+async fn bench(mut options: Options) -> Result<(), String> {
+    if options.mock {
+        start_mock_server(&mut options).await?;
+    }
+    let api = Api {
+        client: Client::new(),
+        base: options.api.clone(),
+        token: options.manage_token.clone(),
+    };
+
+    let room_ids: Vec<String> = (0..options.rooms)
+        .map(|room| format!("bench-{}", room))
+        .collect();
+    for room_id in &room_ids {
+        api.create_room(room_id).await?;
+    }
+    let mut users = Vec::with_capacity(options.connections);
+    for connection in 0..options.connections {
+        let room_id = room_ids[connection % room_ids.len()].clone();
+        let user_id = format!("bench-user-{}", connection);
+        let token = api.create_user(&room_id, &user_id).await?;
+        users.push((room_id, user_id, token));
+    }
+
+    let options = Arc::new(options);
+    let started = Instant::now();
+    let connections = users.into_iter().map(|(room_id, user_id, token)| {
+        tokio::spawn(run_connection(options.clone(), room_id, user_id, token))
+    });
+    let reports = future::join_all(connections)
+        .await
+        .into_iter()
+        .map(|report| report.unwrap_or_default())
+        .collect();
+    print_report(&options, reports, started.elapsed());
+
+    for room_id in &room_ids {
+        api.call(Method::DELETE, &format!("/room/{}", room_id))
+            .await
+            .ok();
+    }
+    Ok(())
+}
+
+/// Time what a room filling up to `size` users costs to tell everyone about, each join
+/// sent to the users already there. Serialized for each connection as rooms used to, and
+/// once for each join with the text shared as they are now
+fn fanout(size: usize) {
+    let info: UserInfo = serde_json::from_value(json!({
+        "audio": true,
+        "metadata": { "title": "Attendee", "team": "Support", "locale": "en-GB" },
+    }))
+    .expect("User info is valid");
+    let joined = |user: usize| WSEvent::UserJoined {
+        id: format!("user-{}", user),
+        info: info.clone(),
+    };
+
+    let started = Instant::now();
+    let mut bytes = 0;
+    for user in 1..size {
+        for seq in 0..user as u64 {
+            let event = SequencedEvent {
+                seq,
+                room_id: None,
+                event: joined(user),
+            };
+            bytes += serde_json::to_string(&event)
+                .expect("Events serialize")
+                .len();
+        }
+    }
+    let per_connection = started.elapsed();
+
+    let started = Instant::now();
+    let mut shared_bytes = 0;
+    for user in 1..size {
+        let mut shared: Option<String> = None;
+        for seq in 0..user as u64 {
+            let event = joined(user);
+            let text = shared
+                .get_or_insert_with(|| serde_json::to_string(&event).expect("Events serialize"));
+            shared_bytes += sequenced_text(seq, None, text).len();
+        }
+    }
+    let shared = started.elapsed();
+    assert_eq!(
+        bytes, shared_bytes,
+        "Shared text differs from serialized events"
+    );
+
+    let events = size * size.saturating_sub(1) / 2;
+    println!("{} events sent filling a room of {} users", events, size);
+    println!("  serialized per connection  {:>10.1?}", per_connection);
+    println!("  shared across connections  {:>10.1?}", shared);
+    println!(
+        "  {:.1}x less time spent serializing",
+        per_connection.as_secs_f64() / shared.as_secs_f64().max(f64::EPSILON)
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match Options::parse(args.into_iter()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Some(size) = options.fanout {
+        fanout(size);
+        return;
+    }
+    if let Err(err) = bench(options).await {
+        eprintln!("vortex-bench: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,119 @@
+//! Liveness and readiness probe for load balancers and orchestrators
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{filters::BoxedFilter, reply::Reply};
+use warp::{Filter, Rejection};
+
+use crate::rtc::worker::{WorkerStatus, WORKER_POOL};
+use crate::state::room::ROOMS;
+use crate::util::config::CONFIG;
+use crate::util::metrics::ROOM_SUBSCRIBERS;
+
+lazy_static! {
+    static ref STARTED_AT: Instant = Instant::now();
+}
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+/// Seconds of uptime until which a worker is considered out of ports or memory
+static EXHAUSTED_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    /// False while shutting down, when a worker died or shortly after one ran out of
+    /// ports or memory, answered with a 503
+    ready: bool,
+    /// A worker ran out of ports or memory within the last `rtc.exhausted_unready_secs`
+    exhausted: bool,
+    uptime_secs: u64,
+    workers: Vec<WorkerStatus>,
+    rooms: usize,
+    /// Signaling connections, over WebSocket or long polling
+    connections: i64,
+}
+
+/// Start counting uptime from now rather than from the first probe
+pub fn mark_started() {
+    lazy_static::initialize(&STARTED_AT);
+}
+
+/// Report the node as not ready from now on, so traffic drains before it exits
+pub fn begin_drain() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+/// Report the node as not ready for `rtc.exhausted_unready_secs`, as a worker ran out of
+/// ports or memory
+pub fn mark_exhausted() {
+    let until = STARTED_AT.elapsed().as_secs() + CONFIG.rtc.exhausted_unready_secs;
+    EXHAUSTED_UNTIL.fetch_max(until, Ordering::Relaxed);
+}
+
+fn exhausted() -> bool {
+    STARTED_AT.elapsed().as_secs() < EXHAUSTED_UNTIL.load(Ordering::Relaxed)
+}
+
+/// Only the room map is read, never a room's own locks, so a stuck room can't hold up the probe
+pub async fn get_health() -> Health {
+    let workers = WORKER_POOL
+        .get()
+        .map(|pool| pool.status())
+        .unwrap_or_default();
+    let workers_alive = !workers.is_empty() && workers.iter().all(|worker| worker.alive);
+    let exhausted = exhausted();
+
+    Health {
+        ready: workers_alive && !exhausted && !DRAINING.load(Ordering::Relaxed),
+        exhausted,
+        uptime_secs: STARTED_AT.elapsed().as_secs(),
+        workers,
+        rooms: ROOMS.read().await.len(),
+        connections: ROOM_SUBSCRIBERS.get(),
+    }
+}
+
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(|| async {
+            let health = get_health().await;
+            let status = match health.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Ok::<_, Rejection>(warp::reply::with_status(warp::reply::json(&health), status))
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn reports_workers_and_rooms() {
+        // Draining is process wide, so it isn't exercised here
+        let room = testing::room(RoomSettings::default()).await;
+        let response = warp::test::request()
+            .method("GET")
+            .path("/health")
+            .reply(&route())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let health: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(health["ready"], true);
+        assert_eq!(health["workers"].as_array().unwrap().len(), 1);
+        assert_eq!(health["workers"][0]["alive"], true);
+        assert!(health["rooms"].as_u64().unwrap() >= 1);
+        assert!(health["uptimeSecs"].is_u64());
+        room.delete().await;
+    }
+}
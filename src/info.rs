@@ -1,4 +1,8 @@
+use crate::state::room::RoomSettings;
+use crate::util::config::CONFIG;
 use crate::util::variables;
+use crate::ws::types::{Capability, MAX_BATCH_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use schemars::JsonSchema;
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -15,7 +19,7 @@ pub struct Features {
 
 pub fn get_info() -> Info {
     let features = Features {
-        rtp: !*variables::DISABLE_RTP,
+        rtp: !CONFIG.rtc.disable_rtp,
     };
 
     Info {
@@ -24,3 +28,84 @@ pub fn get_info() -> Info {
         ws: &variables::WS_URL,
     }
 }
+
+/// What a client is talking to, replied to `ServerInfo` and served on `GET /version`
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub version: &'static str,
+    /// Git revision the server was built from, absent when built outside a checkout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<&'static str>,
+    pub protocol_versions: ProtocolVersions,
+    pub features: ServerFeatures,
+    pub limits: ServerLimits,
+}
+
+/// Range of `version` accepted by `Authenticate`
+#[derive(Serialize, JsonSchema)]
+pub struct ProtocolVersions {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Optional parts of the server and whether this instance has them enabled
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerFeatures {
+    pub rtp: bool,
+    pub recording: bool,
+    pub broadcast: bool,
+    pub hls: bool,
+    pub playback: bool,
+    pub turn: bool,
+    pub simulcast: bool,
+    /// Transports are created without SCTP, so there are no data channels
+    pub data_channels: bool,
+    /// Optional parts of the protocol clients may declare, see `Capability`
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerLimits {
+    /// Bytes of a frame from the client
+    pub max_message_size: usize,
+    /// Bytes of a single command's data
+    pub max_command_size: usize,
+    pub max_batch_size: usize,
+    /// Users a room created without `maxUsers` takes, unlimited if absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+}
+
+pub fn server_info() -> ServerInfo {
+    let features = ServerFeatures {
+        rtp: !CONFIG.rtc.disable_rtp,
+        recording: CONFIG.recording.is_some(),
+        broadcast: CONFIG.broadcast.is_some(),
+        hls: CONFIG.hls.is_some(),
+        playback: CONFIG.playback.is_some(),
+        turn: CONFIG.turn.is_some(),
+        simulcast: true,
+        data_channels: false,
+        capabilities: Capability::ALL.to_vec(),
+    };
+    let limits = ServerLimits {
+        max_message_size: CONFIG.signaling.max_message_size,
+        max_command_size: CONFIG.signaling.max_command_size,
+        max_batch_size: MAX_BATCH_SIZE,
+        max_users: RoomSettings::default().max_users,
+    };
+
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        revision: option_env!("VORTEX_GIT_REVISION"),
+        protocol_versions: ProtocolVersions {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        },
+        features,
+        limits,
+    }
+}
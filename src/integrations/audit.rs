@@ -0,0 +1,250 @@
+//! Durable record of who joined and left each room and of every moderation action, for
+//! deployments that have to account for them. Records are queued and written by a task
+//! of their own, a slow sink never holds up a connection
+
+use std::fmt::{self, Display};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+use tokio::sync::Mutex;
+
+use crate::util::metrics::AUDIT_RECORDS;
+
+pub static AUDIT: OnceCell<Audit> = OnceCell::new();
+
+/// Records waiting for the sink before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Joined,
+    Left,
+    Kicked,
+    Banned,
+    Unbanned,
+    RelayMuted,
+    RelayUnmuted,
+    MutedAll,
+    Promoted,
+    Demoted,
+    OwnerChanged,
+    RoomOpened,
+    Admitted,
+    Reported,
+    MaxIncomingBitrateSet,
+    SettingsUpdated,
+    MetadataUpdated,
+    RecordingStarted,
+    RecordingStopped,
+    RecordingConsentGranted,
+    RecordingConsentDeclined,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub room_id: String,
+    pub action: AuditAction,
+    /// Moderator who took the action, none when it's the target's own doing or the
+    /// management API's
+    pub actor: Option<String>,
+    /// User the action was taken on, none for actions on the whole room
+    pub target: Option<String>,
+    pub reason: Option<String>,
+    /// What else there is to know about the action, such as the settings changed
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub details: serde_json::Value,
+}
+
+impl AuditRecord {
+    pub fn new(room_id: &str, action: AuditAction) -> Self {
+        AuditRecord {
+            timestamp: Utc::now(),
+            room_id: room_id.to_string(),
+            action,
+            actor: None,
+            target: None,
+            reason: None,
+            details: serde_json::Value::Null,
+        }
+    }
+
+    pub fn actor(mut self, actor: Option<&str>) -> Self {
+        self.actor = actor.map(str::to_string);
+        self
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn reason(mut self, reason: Option<&str>) -> Self {
+        self.reason = reason.map(str::to_string);
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditError(pub String);
+
+impl Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Audit sink failed: {}", self.0)
+    }
+}
+
+/// Where audit records are written, one at a time in the order they were made. Register
+/// an implementation with `ServerBuilder::audit_sink` to send them elsewhere than a file
+/// or stdout
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn write(&self, record: &AuditRecord) -> Result<(), AuditError>;
+}
+
+fn json_line(record: &AuditRecord) -> Result<Vec<u8>, AuditError> {
+    let mut line = serde_json::to_vec(record).map_err(|err| AuditError(err.to_string()))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Records appended to a file as JSON lines
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileSink {
+    async fn write(&self, record: &AuditRecord) -> Result<(), AuditError> {
+        let line = json_line(record)?;
+        let mut file = self.file.lock().await;
+        file.write_all(&line)
+            .await
+            .map_err(|err| AuditError(err.to_string()))?;
+        file.flush()
+            .await
+            .map_err(|err| AuditError(err.to_string()))
+    }
+}
+
+/// Records printed as JSON lines, for deployments that collect the output of the process
+pub struct StdoutSink;
+
+#[async_trait]
+impl AuditSink for StdoutSink {
+    async fn write(&self, record: &AuditRecord) -> Result<(), AuditError> {
+        let line = json_line(record)?;
+        io::stdout()
+            .lock()
+            .write_all(&line)
+            .map_err(|err| AuditError(err.to_string()))
+    }
+}
+
+/// Queue to the configured sink, only present when one is
+pub struct Audit {
+    queue: Sender<AuditRecord>,
+}
+
+impl Audit {
+    pub fn start(sink: Arc<dyn AuditSink>) -> Self {
+        let (queue, mut receiver) = mpsc::channel::<AuditRecord>(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                match sink.write(&record).await {
+                    Ok(()) => AUDIT_RECORDS.with_label_values(&["written"]).inc(),
+                    Err(err) => {
+                        warn!("{}", err);
+                        AUDIT_RECORDS.with_label_values(&["failed"]).inc();
+                    }
+                }
+            }
+        });
+
+        Audit { queue }
+    }
+
+    /// Never waits, records made while the sink fell too far behind are dropped
+    fn queue(&self, record: AuditRecord) {
+        match self.queue.try_send(record) {
+            Ok(()) => (),
+            Err(TrySendError::Full(record)) => {
+                warn!(
+                    "Audit queue full, dropping {:?} record of room {}",
+                    record.action, record.room_id
+                );
+                AUDIT_RECORDS.with_label_values(&["dropped"]).inc();
+            }
+            Err(TrySendError::Closed(_)) => (),
+        }
+    }
+}
+
+/// Queue a record for the audit sink, if one is configured
+pub fn record(record: AuditRecord) {
+    if let Some(audit) = AUDIT.get() {
+        audit.queue(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn file_sink_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("vortex-{}.jsonl", rand::random::<u64>()));
+        let sink = FileSink::open(&path).unwrap();
+        let kicked = AuditRecord::new("room", AuditAction::Kicked)
+            .actor(Some("host"))
+            .target("guest")
+            .reason(Some("Spamming"));
+        sink.write(&kicked).await.unwrap();
+        let settings = AuditRecord::new("room", AuditAction::SettingsUpdated)
+            .actor(Some("host"))
+            .details(json!({ "locked": true }));
+        sink.write(&settings).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["action"], "kicked");
+        assert_eq!(lines[0]["actor"], "host");
+        assert_eq!(lines[0]["target"], "guest");
+        assert_eq!(lines[0]["reason"], "Spamming");
+        assert!(lines[0].get("details").is_none());
+        assert_eq!(lines[1]["roomId"], "room");
+        assert_eq!(lines[1]["target"], serde_json::Value::Null);
+        assert_eq!(lines[1]["details"]["locked"], true);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
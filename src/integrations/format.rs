@@ -0,0 +1,417 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use rand::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, RwLock};
+
+use crate::state::room::{CallSummary, RoomEvent};
+use crate::util::variables::WS_URL;
+
+/// Event mirrored to the outbound integration sinks (webhooks, Redis)
+#[derive(Serialize, Clone, Debug)]
+pub struct IntegrationEvent {
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    pub room_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+impl IntegrationEvent {
+    pub fn new(event_type: &'static str, room_id: &str, data: serde_json::Value) -> Self {
+        IntegrationEvent {
+            event_type,
+            room_id: room_id.to_string(),
+            timestamp: Utc::now(),
+            data,
+        }
+    }
+
+    pub fn from_room_event(room_id: &str, event: &RoomEvent) -> Self {
+        let (event_type, data) = match event {
+            RoomEvent::UserJoined(id, _) => ("user.joined", json!({ "id": id })),
+            RoomEvent::UserLeft(id, _) => ("user.left", json!({ "id": id })),
+            RoomEvent::UserStartProduce(id, produce_type, paused) => (
+                "user.produce.started",
+                json!({ "id": id, "type": produce_type, "paused": paused }),
+            ),
+            RoomEvent::UserProducerPauseChanged(id, produce_type, paused) => (
+                "user.produce.paused",
+                json!({ "id": id, "type": produce_type, "paused": paused }),
+            ),
+            RoomEvent::UserStopProduce(id, produce_type) => (
+                "user.produce.stopped",
+                json!({ "id": id, "type": produce_type }),
+            ),
+            RoomEvent::UserProducerReplaced(id, produce_type) => (
+                "user.produce.replaced",
+                json!({ "id": id, "type": produce_type }),
+            ),
+            RoomEvent::ProducerAudience(id, produce_type, count) => (
+                "user.produce.audience",
+                json!({ "id": id, "type": produce_type, "consumerCount": count }),
+            ),
+            RoomEvent::UserInfoUpdated(id) => ("user.updated", json!({ "id": id })),
+            RoomEvent::Relay(id, payload) => {
+                ("user.relayed", json!({ "id": id, "payload": payload }))
+            }
+            // Passed between connections only and never published, the data stays out
+            RoomEvent::RelayBlob(_) => ("user.blob.relayed", json!({})),
+            RoomEvent::UserRoleChanged(id, role) => {
+                ("user.role.changed", json!({ "id": id, "role": role }))
+            }
+            RoomEvent::UserConnectionStateChanged(id, state) => (
+                "user.connection.changed",
+                json!({ "id": id, "state": state }),
+            ),
+            RoomEvent::OwnerChanged(owner) => ("room.owner.changed", json!({ "owner": owner })),
+            RoomEvent::UserWaiting(id) => ("user.waiting", json!({ "id": id })),
+            RoomEvent::UserAdmitted(id) => ("user.admitted", json!({ "id": id })),
+            RoomEvent::RoomOpened => ("room.opened", json!({})),
+            RoomEvent::UserReported(report) => ("user.reported", json!({ "report": report })),
+            RoomEvent::QualityAlert(alert) => ("room.quality.alert", json!({ "alert": alert })),
+            RoomEvent::UserIdle(id) => ("user.idle", json!({ "id": id })),
+            RoomEvent::UserActive(id) => ("user.active", json!({ "id": id })),
+            RoomEvent::UserQualityChanged(id, level) => {
+                ("user.quality.changed", json!({ "id": id, "level": level }))
+            }
+            RoomEvent::ActiveSpeakers(speakers) => {
+                ("room.speakers", json!({ "speakers": speakers }))
+            }
+            RoomEvent::Positions(positions) => ("room.positions", json!({ "users": positions })),
+            RoomEvent::RecordingStateChanged(true) => ("room.recording.started", json!({})),
+            RoomEvent::RecordingStateChanged(false) => ("room.recording.stopped", json!({})),
+            RoomEvent::RecordingConsentRequested(users) => (
+                "room.recording.consent.requested",
+                json!({ "users": users }),
+            ),
+            RoomEvent::RoomSettingsChanged(settings) => {
+                ("room.settings.updated", json!({ "settings": settings }))
+            }
+            RoomEvent::RoomMetadataChanged(key, value) => (
+                "room.metadata.updated",
+                json!({ "key": key, "value": value }),
+            ),
+            RoomEvent::RoomThrottled(level) => ("room.throttled", json!({ "level": level })),
+            RoomEvent::BroadcastStateChanged(state) => {
+                ("room.broadcast.updated", json!({ "state": state }))
+            }
+            // The token stays with the client it was meant for
+            RoomEvent::MigrateTo(url, _) => ("room.migrating", json!({ "url": url })),
+            RoomEvent::CallSummary(summary) => ("room.call.ended", json!({ "summary": summary })),
+            RoomEvent::RoomDelete(summary) => ("room.deleted", json!({ "summary": summary })),
+        };
+
+        IntegrationEvent::new(event_type, room_id, data)
+    }
+
+    pub fn from_lifecycle(room_id: &str, event: &LifecycleEvent) -> Self {
+        let (event_type, data) = match event {
+            LifecycleEvent::RoomCreated => ("room.created", json!({})),
+            LifecycleEvent::FirstUserJoined(id) => ("room.started", json!({ "id": id })),
+            LifecycleEvent::LastUserLeft(id) => ("room.emptied", json!({ "id": id })),
+            LifecycleEvent::CallEnded(summary) => {
+                ("room.call.ended", json!({ "summary": summary }))
+            }
+            LifecycleEvent::UserKicked { id, reason, by } => (
+                "user.kicked",
+                json!({ "id": id, "reason": reason, "by": by }),
+            ),
+        };
+
+        IntegrationEvent::new(event_type, room_id, data)
+    }
+}
+
+/// Changes in a room's life that only integrations are told about, participants see
+/// them as the room events they're made of
+#[derive(Clone, Debug)]
+pub enum LifecycleEvent {
+    RoomCreated,
+    /// ID of the first visible user to join, the call started
+    FirstUserJoined(String),
+    /// ID of the last visible user to leave, the room is empty
+    LastUserLeft(String),
+    /// How the call went, once the room emptied or was deleted
+    CallEnded(CallSummary),
+    UserKicked {
+        id: String,
+        reason: Option<String>,
+        /// Moderator who kicked the user, none for the management API
+        by: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Serialization(String),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Serialization(err) => write!(f, "Failed to serialize payload: {}", err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for FormatError {
+    fn from(err: serde_json::Error) -> FormatError {
+        FormatError::Serialization(err.to_string())
+    }
+}
+
+/// Turns an integration event into the bytes sent by a sink
+pub trait PayloadFormatter: Send + Sync {
+    /// MIME type of the formatted payload, sent as `Content-Type` where the sink supports it
+    fn content_type(&self) -> &'static str;
+    fn format(&self, event: &IntegrationEvent) -> Result<Vec<u8>, FormatError>;
+}
+
+/// Plain JSON serialization of the event
+pub struct JsonFormatter;
+
+impl PayloadFormatter for JsonFormatter {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn format(&self, event: &IntegrationEvent) -> Result<Vec<u8>, FormatError> {
+        Ok(serde_json::to_vec(event)?)
+    }
+}
+
+/// CloudEvents 1.0 structured-mode JSON envelope
+pub struct CloudEventsFormatter {
+    source: String,
+}
+
+#[derive(Serialize)]
+struct CloudEvent<'a> {
+    specversion: &'static str,
+    id: String,
+    source: &'a str,
+    #[serde(rename = "type")]
+    event_type: String,
+    subject: &'a str,
+    time: String,
+    datacontenttype: &'static str,
+    data: &'a serde_json::Value,
+}
+
+impl CloudEventsFormatter {
+    pub fn new(source: String) -> Self {
+        CloudEventsFormatter { source }
+    }
+}
+
+impl PayloadFormatter for CloudEventsFormatter {
+    fn content_type(&self) -> &'static str {
+        "application/cloudevents+json"
+    }
+
+    fn format(&self, event: &IntegrationEvent) -> Result<Vec<u8>, FormatError> {
+        let mut id_bytes = [0u8; 16];
+        thread_rng().fill_bytes(&mut id_bytes);
+        let id = id_bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let cloud_event = CloudEvent {
+            specversion: "1.0",
+            id,
+            source: &self.source,
+            event_type: format!("chat.revolt.vortex.{}", event.event_type),
+            subject: &event.room_id,
+            time: event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            datacontenttype: "application/json",
+            data: &event.data,
+        };
+
+        Ok(serde_json::to_vec(&cloud_event)?)
+    }
+}
+
+#[cfg(feature = "protobuf")]
+pub mod protobuf {
+    use prost::Message;
+
+    use super::{FormatError, IntegrationEvent, PayloadFormatter};
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct EventEnvelope {
+        #[prost(string, tag = "1")]
+        pub event_type: String,
+        #[prost(string, tag = "2")]
+        pub room_id: String,
+        #[prost(int64, tag = "3")]
+        pub timestamp_ms: i64,
+        /// JSON-encoded event data
+        #[prost(bytes = "vec", tag = "4")]
+        pub data: Vec<u8>,
+    }
+
+    /// Protobuf `EventEnvelope`, more compact than JSON for high-volume sinks
+    pub struct ProtobufFormatter;
+
+    impl PayloadFormatter for ProtobufFormatter {
+        fn content_type(&self) -> &'static str {
+            "application/x-protobuf"
+        }
+
+        fn format(&self, event: &IntegrationEvent) -> Result<Vec<u8>, FormatError> {
+            let envelope = EventEnvelope {
+                event_type: event.event_type.to_string(),
+                room_id: event.room_id.clone(),
+                timestamp_ms: event.timestamp.timestamp_millis(),
+                data: serde_json::to_vec(&event.data)?,
+            };
+
+            Ok(envelope.encode_to_vec())
+        }
+    }
+}
+
+lazy_static! {
+    static ref FORMATTERS: RwLock<HashMap<String, Arc<dyn PayloadFormatter>>> = {
+        let mut formatters: HashMap<String, Arc<dyn PayloadFormatter>> = HashMap::new();
+        formatters.insert("json".to_string(), Arc::new(JsonFormatter));
+        formatters.insert(
+            "cloudevents".to_string(),
+            Arc::new(CloudEventsFormatter::new(WS_URL.clone())),
+        );
+        #[cfg(feature = "protobuf")]
+        formatters.insert(
+            "protobuf".to_string(),
+            Arc::new(protobuf::ProtobufFormatter),
+        );
+        RwLock::new(formatters)
+    };
+}
+
+pub fn register(name: String, formatter: Arc<dyn PayloadFormatter>) {
+    FORMATTERS.write().unwrap().insert(name, formatter);
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn PayloadFormatter>> {
+    FORMATTERS.read().unwrap().get(name).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::rtc::broadcast::BroadcastState;
+    use crate::state::room::RoomSummary;
+    use crate::state::user::{ProduceType, UserInfo};
+
+    fn events() -> Vec<IntegrationEvent> {
+        let summary = RoomSummary {
+            duration_secs: 120,
+            peak_users: 3,
+            total_unique_users: 4,
+            recording_available: false,
+        };
+
+        vec![
+            RoomEvent::UserJoined("alice".to_string(), UserInfo::default()),
+            RoomEvent::UserStartProduce("alice".to_string(), ProduceType::Audio, true),
+            RoomEvent::RecordingStateChanged(true),
+            RoomEvent::BroadcastStateChanged(BroadcastState::Live),
+            RoomEvent::RoomDelete(summary),
+        ]
+        .iter()
+        .map(|event| IntegrationEvent::from_room_event("room", event))
+        .chain(
+            [
+                LifecycleEvent::RoomCreated,
+                LifecycleEvent::FirstUserJoined("alice".to_string()),
+                LifecycleEvent::UserKicked {
+                    id: "bob".to_string(),
+                    reason: Some("spam".to_string()),
+                    by: None,
+                },
+            ]
+            .iter()
+            .map(|event| IntegrationEvent::from_lifecycle("room", event)),
+        )
+        .collect()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for event in events() {
+            let payload = JsonFormatter.format(&event).unwrap();
+            let value: Value = serde_json::from_slice(&payload).unwrap();
+
+            assert_eq!(value["type"], event.event_type);
+            assert_eq!(value["room_id"], event.room_id);
+            assert_eq!(value["data"], event.data);
+            let timestamp: DateTime<Utc> =
+                serde_json::from_value(value["timestamp"].clone()).unwrap();
+            assert_eq!(timestamp, event.timestamp);
+        }
+    }
+
+    #[test]
+    fn room_deleted_carries_summary() {
+        let deleted = events()
+            .into_iter()
+            .find(|event| event.event_type == "room.deleted")
+            .unwrap();
+        let payload = JsonFormatter.format(&deleted).unwrap();
+        let value: Value = serde_json::from_slice(&payload).unwrap();
+
+        assert_eq!(value["type"], "room.deleted");
+        assert_eq!(
+            value["data"]["summary"],
+            serde_json::json!({
+                "durationSecs": 120,
+                "peakUsers": 3,
+                "totalUniqueUsers": 4,
+                "recordingAvailable": false,
+            })
+        );
+    }
+
+    #[test]
+    fn cloudevents_round_trip() {
+        let formatter = CloudEventsFormatter::new("wss://vortex.example.com".to_string());
+        for event in events() {
+            let payload = formatter.format(&event).unwrap();
+            let value: Value = serde_json::from_slice(&payload).unwrap();
+
+            assert_eq!(value["specversion"], "1.0");
+            assert_eq!(value["source"], "wss://vortex.example.com");
+            assert_eq!(
+                value["type"],
+                format!("chat.revolt.vortex.{}", event.event_type)
+            );
+            assert_eq!(value["subject"], event.room_id);
+            assert_eq!(value["datacontenttype"], "application/json");
+            assert_eq!(value["data"], event.data);
+            assert_eq!(value["id"].as_str().unwrap().len(), 32);
+
+            let time = DateTime::parse_from_rfc3339(value["time"].as_str().unwrap()).unwrap();
+            assert_eq!(time.timestamp_millis(), event.timestamp.timestamp_millis());
+        }
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trip() {
+        use prost::Message;
+
+        for event in events() {
+            let payload = protobuf::ProtobufFormatter.format(&event).unwrap();
+            let envelope = protobuf::EventEnvelope::decode(payload.as_slice()).unwrap();
+
+            assert_eq!(envelope.event_type, event.event_type);
+            assert_eq!(envelope.room_id, event.room_id);
+            assert_eq!(envelope.timestamp_ms, event.timestamp.timestamp_millis());
+            let data: Value = serde_json::from_slice(&envelope.data).unwrap();
+            assert_eq!(data, event.data);
+        }
+    }
+}
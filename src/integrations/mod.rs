@@ -0,0 +1,6 @@
+pub mod audit;
+pub mod format;
+pub mod redis;
+pub mod webhook;
+
+pub use format::{IntegrationEvent, PayloadFormatter};
@@ -0,0 +1,334 @@
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::{error::RecvError, Sender};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use super::format::{self, IntegrationEvent};
+use crate::state::room::{subscriber::BroadcastEvent, Room, RoomEvent, ROOMS};
+use crate::state::user::UserInfo;
+use crate::util::config::CONFIG;
+
+pub static REDIS: OnceCell<Redis> = OnceCell::new();
+
+/// Redis backend, only present when `REDIS_URI` is configured
+pub fn get_redis() -> Option<&'static Redis> {
+    REDIS.get()
+}
+
+lazy_static! {
+    /// Distinguishes events published by this instance from the ones relayed by its peers
+    static ref INSTANCE_ID: String = {
+        let mut bytes = [0u8; 12];
+        thread_rng().fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE)
+    };
+}
+
+const EVENTS_CHANNEL: &str = "vortex:events";
+
+fn room_channel(room_id: &str) -> String {
+    format!("vortex:room:{}", room_id)
+}
+
+/// Users of a room connected to one instance
+fn users_key(room_id: &str, instance: &str) -> String {
+    format!("vortex:room:{}:users:{}", room_id, instance)
+}
+
+/// Instances that had users in a room
+fn instances_key(room_id: &str) -> String {
+    format!("vortex:room:{}:instances", room_id)
+}
+
+/// Lifetime of an instance's entries, refreshed every `HEARTBEAT_INTERVAL` while it
+/// runs so the users of a crashed instance disappear on their own
+const ENTRY_TTL: usize = 30;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+struct RelayedEvent {
+    origin: String,
+    event: RoomEvent,
+}
+
+enum Operation {
+    Publish(String, Vec<u8>),
+    /// Room ID, user ID and serialized user info
+    SetUser(String, String, String),
+    /// Room ID and user ID
+    RemoveUser(String, String),
+    DeleteRoom(String),
+    /// Refresh the TTL of this instance's entries in these rooms
+    Heartbeat(Vec<String>),
+}
+
+pub struct Redis {
+    client: Client,
+    connection: MultiplexedConnection,
+    queue: UnboundedSender<Operation>,
+}
+
+impl Redis {
+    pub async fn connect(uri: &str) -> RedisResult<Self> {
+        let client = Client::open(uri)?;
+        let connection = client.get_multiplexed_tokio_connection().await?;
+
+        // Writes go through a single task so they reach Redis in the order they were issued
+        let (queue, mut receiver) = mpsc::unbounded_channel();
+        let mut writer = connection.clone();
+        tokio::spawn(async move {
+            while let Some(operation) = receiver.recv().await {
+                let result: RedisResult<()> = match operation {
+                    Operation::Publish(channel, payload) => writer.publish(channel, payload).await,
+                    Operation::SetUser(room_id, id, info) => {
+                        let key = users_key(&room_id, &INSTANCE_ID);
+                        let instances = instances_key(&room_id);
+                        redis::pipe()
+                            .atomic()
+                            .hset(&key, id, info)
+                            .ignore()
+                            .expire(&key, ENTRY_TTL)
+                            .ignore()
+                            .sadd(&instances, &*INSTANCE_ID)
+                            .ignore()
+                            .expire(&instances, ENTRY_TTL)
+                            .ignore()
+                            .query_async(&mut writer)
+                            .await
+                    }
+                    Operation::RemoveUser(room_id, id) => {
+                        writer.hdel(users_key(&room_id, &INSTANCE_ID), id).await
+                    }
+                    Operation::DeleteRoom(room_id) => {
+                        redis::pipe()
+                            .atomic()
+                            .del(users_key(&room_id, &INSTANCE_ID))
+                            .ignore()
+                            .srem(instances_key(&room_id), &*INSTANCE_ID)
+                            .ignore()
+                            .query_async(&mut writer)
+                            .await
+                    }
+                    Operation::Heartbeat(room_ids) => {
+                        let mut pipe = redis::pipe();
+                        for room_id in room_ids {
+                            pipe.expire(users_key(&room_id, &INSTANCE_ID), ENTRY_TTL)
+                                .ignore()
+                                .expire(instances_key(&room_id), ENTRY_TTL)
+                                .ignore();
+                        }
+                        pipe.query_async(&mut writer).await
+                    }
+                };
+
+                if let Err(err) = result {
+                    warn!("Redis write failed: {}", err);
+                }
+            }
+        });
+
+        let heartbeat = queue.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let room_ids: Vec<String> = ROOMS
+                    .read()
+                    .await
+                    .values()
+                    .map(|room| room.scoped_id().to_string())
+                    .collect();
+                if room_ids.is_empty() {
+                    continue;
+                }
+
+                if heartbeat.send(Operation::Heartbeat(room_ids)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        info!("Connected to Redis, instance ID {}", *INSTANCE_ID);
+        Ok(Redis {
+            client,
+            connection,
+            queue,
+        })
+    }
+
+    /// Publish a locally originated room event to the other instances and
+    /// mirror it on the integration events channel
+    pub fn publish(&self, room_id: &str, event: &RoomEvent) {
+        // Room deletion is local to each instance, only mirror it
+        if !matches!(event, RoomEvent::RoomDelete(_)) {
+            let relayed = RelayedEvent {
+                origin: INSTANCE_ID.clone(),
+                event: event.clone(),
+            };
+
+            match serde_json::to_vec(&relayed) {
+                Ok(payload) => self.write(Operation::Publish(room_channel(room_id), payload)),
+                Err(err) => warn!("Failed to serialize room event for Redis: {}", err),
+            }
+        }
+
+        let event = IntegrationEvent::from_room_event(room_id, event);
+        let formatter =
+            format::get(&CONFIG.integrations.redis_format).expect("Redis payload format missing");
+        match formatter.format(&event) {
+            Ok(payload) => self.write(Operation::Publish(EVENTS_CHANNEL.to_string(), payload)),
+            Err(err) => warn!("Failed to format event for Redis: {}", err),
+        }
+    }
+
+    pub fn set_user(&self, room_id: &str, user_id: &str, info: &UserInfo) {
+        match serde_json::to_string(info) {
+            Ok(info) => self.write(Operation::SetUser(
+                room_id.to_string(),
+                user_id.to_string(),
+                info,
+            )),
+            Err(err) => warn!("Failed to serialize user info for Redis: {}", err),
+        }
+    }
+
+    pub fn remove_user(&self, room_id: &str, user_id: &str) {
+        self.write(Operation::RemoveUser(
+            room_id.to_string(),
+            user_id.to_string(),
+        ));
+    }
+
+    /// Drop this instance's users of a deleted room
+    pub fn delete_room(&self, room_id: &str) {
+        self.write(Operation::DeleteRoom(room_id.to_string()));
+    }
+
+    /// Users registered in the room across all instances
+    pub async fn users(&self, room_id: &str) -> HashMap<String, UserInfo> {
+        let mut connection = self.connection.clone();
+        let instances: Vec<String> = match connection.smembers(instances_key(room_id)).await {
+            Ok(instances) => instances,
+            Err(err) => {
+                warn!(
+                    "Failed to fetch instances of room {} from Redis: {}",
+                    room_id, err
+                );
+                return HashMap::new();
+            }
+        };
+
+        let mut users = HashMap::new();
+        for instance in instances {
+            let key = users_key(room_id, &instance);
+            let entries: HashMap<String, String> = match connection.hgetall(&key).await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!(
+                        "Failed to fetch users of room {} from Redis: {}",
+                        room_id, err
+                    );
+                    continue;
+                }
+            };
+
+            users.extend(
+                entries.into_iter().filter_map(|(id, info)| {
+                    serde_json::from_str(&info).ok().map(|info| (id, info))
+                }),
+            );
+        }
+
+        users
+    }
+
+    /// Relay events published by other instances into the room's local
+    /// broadcast until the room is deleted
+    pub fn bridge(&self, room_id: String, sender: Sender<BroadcastEvent>) {
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let mut pubsub = match client.get_async_connection().await {
+                Ok(connection) => connection.into_pubsub(),
+                Err(err) => {
+                    error!(
+                        "Failed to open Redis subscription for room {}: {}",
+                        room_id, err
+                    );
+                    return;
+                }
+            };
+
+            if let Err(err) = pubsub.subscribe(room_channel(&room_id)).await {
+                error!(
+                    "Failed to subscribe to Redis channel of room {}: {}",
+                    room_id, err
+                );
+                return;
+            }
+
+            debug!("Bridging Redis events into room {}", room_id);
+            let mut local = sender.subscribe();
+            let mut messages = pubsub.into_on_message();
+            loop {
+                tokio::select! {
+                    message = messages.next() => {
+                        let message = match message {
+                            Some(message) => message,
+                            None => {
+                                warn!("Redis subscription of room {} ended", room_id);
+                                break;
+                            }
+                        };
+
+                        let payload: Vec<u8> = match message.get_payload() {
+                            Ok(payload) => payload,
+                            Err(_) => continue,
+                        };
+
+                        match serde_json::from_slice::<RelayedEvent>(&payload) {
+                            Ok(relayed) if relayed.origin != *INSTANCE_ID => {
+                                if !superseded(&room_id, &relayed.event).await {
+                                    sender.send(relayed.event.into()).ok();
+                                }
+                            }
+                            Ok(_) => (),
+                            Err(err) => warn!("Invalid event on Redis channel of room {}: {}", room_id, err),
+                        }
+                    },
+                    event = local.recv() => {
+                        match event.map(|broadcast| broadcast.event) {
+                            Ok(RoomEvent::RoomDelete(_)) | Err(RecvError::Closed) => break,
+                            _ => (),
+                        }
+                    }
+                }
+            }
+
+            debug!("Stopped bridging Redis events into room {}", room_id);
+        });
+    }
+
+    fn write(&self, operation: Operation) {
+        self.queue.send(operation).ok();
+    }
+}
+
+/// Whether a relayed event is stale here, as with another instance announcing
+/// that a user left while the user is connected to this one
+async fn superseded(room_id: &str, event: &RoomEvent) -> bool {
+    if let RoomEvent::UserLeft(id, _) = event {
+        if let Some(room) = Room::get_scoped(room_id).await {
+            if room.users().get(id).await.is_some() {
+                return true;
+            }
+        }
+    }
+
+    false
+}
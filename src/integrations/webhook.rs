@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::OnceCell;
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+use warp::hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request};
+
+use super::format::{self, IntegrationEvent, LifecycleEvent, PayloadFormatter};
+use crate::state::room::RoomEvent;
+use crate::util::config::CONFIG;
+use crate::util::metrics::WEBHOOK_DELIVERIES;
+
+pub static WEBHOOK: OnceCell<Webhook> = OnceCell::new();
+
+/// Header carrying `sha256=` and the hex HMAC of the body, when a secret is configured
+pub const SIGNATURE_HEADER: &str = "X-Vortex-Signature";
+/// Events waiting for an endpoint before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+/// Attempts at delivering an event, including the first
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Webhook sink, only present when `WEBHOOK_URL` is configured
+pub fn get_webhook() -> Option<&'static Webhook> {
+    WEBHOOK.get()
+}
+
+/// Posts room events to HTTP endpoints in the configured payload format
+pub struct Webhook {
+    queues: Vec<Sender<IntegrationEvent>>,
+}
+
+impl Webhook {
+    pub fn start(urls: Vec<String>, secret: Option<String>) -> Self {
+        let formatter = format::get(&CONFIG.integrations.webhook_format)
+            .expect("Webhook payload format missing");
+
+        // One task per endpoint, so events arrive in the order they happened and a slow
+        // endpoint only holds up its own deliveries
+        let client = Client::new();
+        let queues = urls
+            .into_iter()
+            .map(|url| {
+                let (queue, mut receiver) = mpsc::channel::<IntegrationEvent>(QUEUE_SIZE);
+                let endpoint = Endpoint {
+                    client: client.clone(),
+                    formatter: formatter.clone(),
+                    secret: secret.clone(),
+                    url,
+                };
+                info!("Delivering room events to webhook {}", endpoint.url);
+                tokio::spawn(async move {
+                    while let Some(event) = receiver.recv().await {
+                        endpoint.deliver(&event).await;
+                    }
+                });
+                queue
+            })
+            .collect();
+
+        Webhook { queues }
+    }
+
+    /// Queue a locally originated room event for delivery
+    pub fn publish(&self, room_id: &str, event: &RoomEvent) {
+        self.queue(IntegrationEvent::from_room_event(room_id, event));
+    }
+
+    /// Queue a change in a room's life for delivery
+    pub fn publish_lifecycle(&self, room_id: &str, event: &LifecycleEvent) {
+        self.queue(IntegrationEvent::from_lifecycle(room_id, event));
+    }
+
+    /// Never waits, events for an endpoint that fell too far behind are dropped
+    fn queue(&self, event: IntegrationEvent) {
+        for queue in &self.queues {
+            match queue.try_send(event.clone()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(event)) => {
+                    warn!("Webhook queue full, dropping {} event", event.event_type);
+                    WEBHOOK_DELIVERIES.with_label_values(&["dropped"]).inc();
+                }
+                Err(TrySendError::Closed(_)) => (),
+            }
+        }
+    }
+}
+
+struct Endpoint {
+    client: Client<HttpConnector>,
+    formatter: Arc<dyn PayloadFormatter>,
+    secret: Option<String>,
+    url: String,
+}
+
+impl Endpoint {
+    /// Post the event, retrying with exponential backoff until it's accepted or
+    /// `MAX_ATTEMPTS` ran out
+    async fn deliver(&self, event: &IntegrationEvent) {
+        let payload = match self.formatter.format(event) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!("Failed to format event for webhook: {}", err);
+                return;
+            }
+        };
+        let signature = self.secret.as_deref().map(|secret| sign(secret, &payload));
+
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = Request::post(self.url.as_str())
+                .header(CONTENT_TYPE, self.formatter.content_type());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature.as_str());
+            }
+            let request = match request.body(Body::from(payload.clone())) {
+                Ok(request) => request,
+                Err(err) => {
+                    error!("Invalid webhook request: {}", err);
+                    return;
+                }
+            };
+
+            match self.client.request(request).await {
+                Ok(response) if response.status().is_success() => {
+                    WEBHOOK_DELIVERIES.with_label_values(&["delivered"]).inc();
+                    return;
+                }
+                Ok(response) => warn!(
+                    "Webhook {} rejected {} event with status {} (attempt {} of {})",
+                    self.url,
+                    event.event_type,
+                    response.status(),
+                    attempt,
+                    MAX_ATTEMPTS
+                ),
+                Err(err) => warn!(
+                    "Failed to deliver {} event to webhook {}: {} (attempt {} of {})",
+                    event.event_type, self.url, err, attempt, MAX_ATTEMPTS
+                ),
+            }
+
+            if attempt < MAX_ATTEMPTS {
+                WEBHOOK_DELIVERIES.with_label_values(&["retried"]).inc();
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+
+        error!(
+            "Gave up delivering {} event to webhook {}",
+            event.event_type, self.url
+        );
+        WEBHOOK_DELIVERIES.with_label_values(&["failed"]).inc();
+    }
+}
+
+/// Value of `SIGNATURE_HEADER` for a request body
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_signed() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
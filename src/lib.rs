@@ -0,0 +1,20 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod state;
+pub mod util;
+
+pub mod api;
+pub mod health;
+pub mod info;
+pub mod poll;
+pub mod ws;
+
+pub mod rtc;
+
+pub mod integrations;
+pub mod server;
+
+pub use server::ServerBuilder;
@@ -1,43 +1,48 @@
-#[macro_use]
-extern crate log;
-#[macro_use]
-extern crate lazy_static;
+use std::env;
+use std::process;
 
-use futures::try_join;
-use warp::Filter;
-
-pub mod state;
-pub mod util;
-
-pub mod api;
-pub mod info;
-pub mod ws;
-
-pub mod rtc;
-
-use util::variables::HTTP_HOST;
+use tracing_subscriber::EnvFilter;
+use vortex::rtc::self_test;
+use vortex::rtc::worker::{WorkerPool, WORKER_POOL};
+use vortex::util::config::CONFIG;
+use vortex::util::telemetry;
+use vortex::ws::schema;
+use vortex::ServerBuilder;
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().ok();
-    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "info"));
-
-    info!("Starting Revolt Vortex voice server");
-    util::variables::preflight_checks();
+    // `--write-schema [path]` writes the protocol's JSON Schema instead of serving
+    let mut args = env::args().skip(1);
+    let mode = args.next();
+    if mode.as_deref() == Some("--write-schema") {
+        let path = args
+            .next()
+            .unwrap_or_else(|| schema::SCHEMA_PATH.to_string());
+        schema::write(&path).expect("Failed to write the schema");
+        return;
+    }
 
-    let worker_pool = rtc::worker::WorkerPool::new().await;
-    rtc::worker::WORKER_POOL.set(worker_pool).unwrap();
-
-    let info_route = warp::path::end()
-        .and(warp::get())
-        .map(|| warp::reply::json(&info::get_info()));
-
-    let ws_route = warp::path::end().and(ws::route());
-
-    let route = ws_route.or(info_route).or(api::route());
-
-    let warp_serve = warp::serve(route).run(*HTTP_HOST);
-    let warp_future = tokio::spawn(warp_serve);
-
-    try_join!(warp_future).unwrap();
+    dotenv::dotenv().ok();
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&CONFIG.log_level));
+    telemetry::init(filter);
+
+    // `--self-test` checks the media path and exits non-zero if it's broken, see
+    // `rtc::self_test`
+    if mode.as_deref() == Some("--self-test") {
+        WORKER_POOL.set(WorkerPool::new().await).ok();
+        let report = self_test::run().await;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Failed to serialize the report")
+        );
+        telemetry::shutdown();
+        process::exit(match report.passed {
+            true => 0,
+            false => 1,
+        });
+    }
+
+    ServerBuilder::new().run().await;
+    telemetry::shutdown();
 }
@@ -0,0 +1,426 @@
+use parking_lot::Mutex as SyncMutex;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::{SinkExt, StreamExt};
+
+use warp::hyper::body::Bytes;
+use warp::Filter;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::Reply, ws::Message};
+
+use crate::rtc::RtcState;
+use crate::util::config::CONFIG;
+use crate::ws::{self, error::WSCloseType, types::SignalingTransport, WSSink, WSStream};
+
+const DEFAULT_POLL_TIMEOUT: u64 = 25;
+/// Seconds a poll may wait for frames, `signaling.poll_session_timeout` has to be longer
+pub const MAX_POLL_TIMEOUT: u64 = 30;
+const CHANNEL_CAPACITY: usize = 64;
+
+lazy_static! {
+    static ref SESSIONS: RwLock<HashMap<String, Arc<Session>>> = RwLock::new(HashMap::new());
+}
+
+/// Signaling connection carried over plain HTTP requests, for clients
+/// whose network blocks websocket upgrades
+struct Session {
+    /// Shared by every request, so concurrent sends wait for room in the
+    /// channel just like frames read off a websocket
+    inbound: Mutex<Sender<Result<Message, WSCloseType>>>,
+    outbound: Mutex<Receiver<Message>>,
+    last_active: SyncMutex<Instant>,
+}
+
+impl Session {
+    /// A session along with the other ends of its channels, the connection reads the
+    /// client's frames from the receiver and writes its own to the sender
+    fn new() -> (
+        Arc<Self>,
+        Receiver<Result<Message, WSCloseType>>,
+        Sender<Message>,
+    ) {
+        let (inbound, inbound_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (outbound_sender, outbound) = mpsc::channel(CHANNEL_CAPACITY);
+        let session = Arc::new(Session {
+            inbound: Mutex::new(inbound),
+            outbound: Mutex::new(outbound),
+            last_active: SyncMutex::new(Instant::now()),
+        });
+        (session, inbound_receiver, outbound_sender)
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().elapsed()
+    }
+}
+
+/// Credentials of the `Authenticate` command performed when opening a session
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateSessionBody {
+    room_id: String,
+    token: String,
+    #[serde(default)]
+    scores: bool,
+    /// Protocol version, the oldest supported if left out
+    version: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct SessionReply {
+    session: String,
+    /// Reply to the `Authenticate` command
+    authenticate: Value,
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    timeout: Option<u64>,
+}
+
+/// Open a session and authenticate it, returning the session ID along with the
+/// `Authenticate` reply, or the close frame if authentication failed
+async fn create_session(body: CreateSessionBody) -> Result<SessionReply, Value> {
+    let (session, inbound_receiver, outbound_sender) = Session::new();
+
+    let mut bytes = [0u8; 24];
+    thread_rng().fill_bytes(&mut bytes);
+    let id = base64::encode_config(bytes, base64::URL_SAFE);
+
+    let ws_sink: WSSink = Box::pin(outbound_sender.sink_map_err(WSCloseType::from));
+    let ws_stream: WSStream = Box::pin(inbound_receiver);
+    let session_id = id.clone();
+    tokio::spawn(async move {
+        ws::serve::<RtcState>(ws_sink, ws_stream, SignalingTransport::LongPoll).await;
+        debug!("Long-poll session {} finished", session_id);
+    });
+
+    let mut data = json!({ "roomId": body.room_id, "token": body.token, "scores": body.scores });
+    if let Some(version) = body.version {
+        data["version"] = json!(version);
+    }
+    let authenticate = json!({ "type": "Authenticate", "data": data });
+    session
+        .inbound
+        .lock()
+        .await
+        .send(Ok(Message::text(authenticate.to_string())))
+        .await
+        .map_err(|_| close_json(WSCloseType::ServerError.code(), "Session closed"))?;
+
+    // The first frame is either the reply or the close frame of a failed authentication
+    let timeout = Duration::from_secs(MAX_POLL_TIMEOUT);
+    let message = tokio::time::timeout(timeout, session.outbound.lock().await.next()).await;
+    let reply = match message {
+        Ok(Some(message)) if !message.is_close() => frame_to_json(message),
+        Ok(Some(message)) => return Err(frame_to_json(message).unwrap_or(Value::Null)),
+        Ok(None) | Err(_) => None,
+    }
+    .ok_or_else(|| close_json(WSCloseType::ServerError.code(), "Authentication failed"))?;
+
+    SESSIONS.write().await.insert(id.clone(), session);
+    Ok(SessionReply {
+        session: id,
+        authenticate: reply,
+    })
+}
+
+async fn get_session(id: &str) -> Option<Arc<Session>> {
+    let session = SESSIONS.read().await.get(id).cloned();
+    if let Some(session) = &session {
+        session.touch();
+    }
+
+    session
+}
+
+/// Convert an outgoing frame into its JSON representation, `None` for frames
+/// the client has no use for
+fn frame_to_json(message: Message) -> Option<Value> {
+    if message.is_close() {
+        let (code, reason) = message.close_frame().unwrap_or((1000, ""));
+        return Some(close_json(code, reason));
+    }
+
+    message
+        .to_str()
+        .ok()
+        .and_then(|text| serde_json::from_str(text).ok())
+}
+
+fn close_json(code: u16, reason: &str) -> Value {
+    json!({
+        "type": "Close",
+        "data": { "code": code, "reason": reason }
+    })
+}
+
+/// Wait for at least one outgoing frame, then drain whatever else is queued
+async fn poll_session(id: &str, session: &Session, timeout: Duration) -> Vec<Value> {
+    let mut outbound = session.outbound.lock().await;
+    let mut messages = Vec::new();
+    let mut closed = false;
+
+    match tokio::time::timeout(timeout, outbound.next()).await {
+        Ok(Some(message)) => {
+            closed |= message.is_close();
+            messages.extend(frame_to_json(message));
+            while let Ok(Some(message)) = outbound.try_next() {
+                closed |= message.is_close();
+                messages.extend(frame_to_json(message));
+            }
+        }
+        Ok(None) => closed = true,
+        Err(_) => (),
+    }
+
+    drop(outbound);
+    if closed {
+        SESSIONS.write().await.remove(id);
+    } else {
+        session.touch();
+    }
+
+    messages
+}
+
+pub fn route() -> BoxedFilter<(impl Reply,)> {
+    // Same limit as WebSocket messages, larger bodies are refused with 413
+    let body_limit = CONFIG.signaling.max_message_size as u64;
+    let create = warp::path("session")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and_then(|body: CreateSessionBody| async move {
+            let reply = match create_session(body).await {
+                Ok(reply) => {
+                    warp::reply::with_status(warp::reply::json(&reply), StatusCode::CREATED)
+                }
+                Err(close) => {
+                    warp::reply::with_status(warp::reply::json(&close), StatusCode::UNAUTHORIZED)
+                }
+            };
+
+            Ok::<_, Infallible>(reply)
+        });
+
+    let send = warp::path::param::<String>()
+        .and(warp::path("send"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and_then(|id: String, body: Bytes| async move {
+            let session = match get_session(&id).await {
+                Some(session) => session,
+                None => return Ok::<_, Infallible>(StatusCode::NOT_FOUND),
+            };
+
+            let text = match String::from_utf8(body.to_vec()) {
+                Ok(text) => text,
+                Err(_) => return Ok(StatusCode::BAD_REQUEST),
+            };
+
+            let mut inbound = session.inbound.lock().await;
+            match inbound.send(Ok(Message::text(text))).await {
+                Ok(_) => Ok(StatusCode::NO_CONTENT),
+                Err(_) => Ok(StatusCode::GONE),
+            }
+        });
+
+    let recv = warp::path::param::<String>()
+        .and(warp::path("recv"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<PollQuery>())
+        .and_then(|id: String, query: PollQuery| async move {
+            let session = match get_session(&id).await {
+                Some(session) => session,
+                None => {
+                    return Ok::<_, Infallible>(StatusCode::NOT_FOUND.into_response());
+                }
+            };
+
+            let timeout = query
+                .timeout
+                .unwrap_or(DEFAULT_POLL_TIMEOUT)
+                .min(MAX_POLL_TIMEOUT);
+            let messages = poll_session(&id, &session, Duration::from_secs(timeout)).await;
+            Ok(warp::reply::json(&messages).into_response())
+        });
+
+    create.or(send).or(recv).boxed()
+}
+
+/// Periodically drop sessions whose client stopped polling, which ends
+/// their signaling connection and removes the user from the room
+pub fn start_reaper() {
+    tokio::spawn(async move {
+        let timeout = CONFIG.signaling.poll_session_timeout();
+        let mut interval = tokio::time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            expire_sessions(timeout).await;
+        }
+    });
+}
+
+async fn expire_sessions(timeout: Duration) {
+    SESSIONS.write().await.retain(|id, session| {
+        let alive = session.idle_for() < timeout;
+        if !alive {
+            debug!("Expiring idle long-poll session {}", id);
+        }
+
+        alive
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::state::user::UserOptions;
+    use crate::util::testing;
+
+    /// A session whose connection is played by the test
+    async fn insert(
+        id: &str,
+    ) -> (
+        Arc<Session>,
+        Receiver<Result<Message, WSCloseType>>,
+        Sender<Message>,
+    ) {
+        testing::init();
+        let (session, inbound, outbound) = Session::new();
+        SESSIONS
+            .write()
+            .await
+            .insert(id.to_string(), session.clone());
+        (session, inbound, outbound)
+    }
+
+    fn send(id: &str, text: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/send", id))
+            .body(text.to_string())
+    }
+
+    #[tokio::test]
+    async fn sessions_open_authenticated() {
+        let room = testing::room(RoomSettings::default()).await;
+        let token = room
+            .users()
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        let open = |token: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/session")
+                .json(&json!({ "roomId": room.id(), "token": token }))
+        };
+
+        let response = open("invalid").reply(&route()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let close: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(close["data"]["code"], WSCloseType::Unauthorized.code());
+
+        let response = open(&token).reply(&route()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let reply: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(reply["authenticate"]["type"], "authenticate");
+        assert_eq!(reply["authenticate"]["data"]["userId"], "alice");
+        let id = reply["session"].as_str().unwrap();
+        assert!(SESSIONS.read().await.contains_key(id));
+        SESSIONS.write().await.remove(id);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn frames_keep_their_order() {
+        let (_session, mut inbound, mut outbound) = insert("ordered").await;
+        for text in ["first", "second", "third"].iter() {
+            let response = send("ordered", text).reply(&route()).await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+        for text in ["first", "second", "third"].iter() {
+            let message = inbound.next().await.unwrap().unwrap();
+            assert_eq!(message.to_str(), Ok(*text));
+        }
+
+        for seq in 0..3 {
+            let frame = json!({ "type": "event", "seq": seq }).to_string();
+            outbound.send(Message::text(frame)).await.unwrap();
+        }
+        let response = warp::test::request()
+            .path("/ordered/recv?timeout=1")
+            .reply(&route())
+            .await;
+        let frames: Vec<Value> = serde_json::from_slice(response.body()).unwrap();
+        let seqs: Vec<&Value> = frames.iter().map(|frame| &frame["seq"]).collect();
+        assert_eq!(seqs, vec![&json!(0), &json!(1), &json!(2)]);
+
+        // The close frame is delivered last and ends the session
+        outbound
+            .send(Message::close_with(4001u16, "Invalid token"))
+            .await
+            .unwrap();
+        let response = warp::test::request()
+            .path("/ordered/recv?timeout=1")
+            .reply(&route())
+            .await;
+        let frames: Vec<Value> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(frames, vec![close_json(4001, "Invalid token")]);
+        assert!(!SESSIONS.read().await.contains_key("ordered"));
+    }
+
+    #[tokio::test]
+    async fn sends_wait_for_room() {
+        let (_session, mut inbound, _outbound) = insert("backpressure").await;
+        // The sender has a slot of its own on top of the channel's capacity
+        for _ in 0..=CHANNEL_CAPACITY {
+            let response = send("backpressure", "{}").reply(&route()).await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+
+        let filter = route();
+        let mut blocked =
+            tokio::spawn(async move { send("backpressure", "{}").reply(&filter).await });
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut blocked).await;
+        assert!(waited.is_err(), "Sent past the channel's capacity");
+        inbound.next().await.unwrap().unwrap();
+        assert_eq!(blocked.await.unwrap().status(), StatusCode::NO_CONTENT);
+        SESSIONS.write().await.remove("backpressure");
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        testing::init();
+        let timeout = CONFIG.signaling.poll_session_timeout();
+        let (idle, ..) = insert("idle").await;
+        let (_active, ..) = insert("active").await;
+        *idle.last_active.lock() = Instant::now()
+            .checked_sub(timeout)
+            .expect("Clock started less than the timeout ago");
+
+        expire_sessions(timeout).await;
+        assert!(!SESSIONS.read().await.contains_key("idle"));
+        assert!(SESSIONS.read().await.contains_key("active"));
+        SESSIONS.write().await.remove("active");
+    }
+}
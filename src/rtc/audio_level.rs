@@ -0,0 +1,136 @@
+//! Who is speaking in a room, from an audio level observer on its router watching the
+//! microphone producers of its visible users
+
+use std::collections::HashMap;
+use std::num::NonZeroU16;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use mediasoup::audio_level_observer::{AudioLevelObserver, AudioLevelObserverOptions};
+use mediasoup::prelude::*;
+use mediasoup::rtp_observer::{RtpObserver, RtpObserverAddProducerOptions};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::local::run_unsend;
+
+/// Thresholds accepted from the configuration and room settings, in dBov
+pub const THRESHOLD_DB: RangeInclusive<i8> = -127..=0;
+/// Intervals accepted from the configuration and room settings, in milliseconds
+pub const INTERVAL_MS: RangeInclusive<u16> = 100..=10_000;
+/// Loudest producers reported per interval
+const MAX_SPEAKERS: u16 = 3;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeakerVolume {
+    pub user_id: String,
+    /// Average over the interval in dBov, from `THRESHOLD_DB` up to 0
+    pub volume: i8,
+}
+
+pub struct AudioLevels {
+    observer: AudioLevelObserver,
+    threshold_db: i8,
+    interval_ms: u16,
+    /// Users the observed producers belong to
+    users: Arc<Mutex<HashMap<ProducerId, String>>>,
+}
+
+impl AudioLevels {
+    /// Create an observer on the router, `on_speakers` is called with the loudest users
+    /// every interval and with none once everyone went quiet
+    pub async fn create<F>(
+        router: &Router,
+        threshold_db: i8,
+        interval_ms: u16,
+        on_speakers: F,
+    ) -> Result<Self, ()>
+    where
+        F: Fn(Vec<SpeakerVolume>) + Send + Sync + 'static,
+    {
+        let mut options = AudioLevelObserverOptions::default();
+        options.max_entries = NonZeroU16::new(MAX_SPEAKERS).unwrap();
+        options.threshold = threshold_db;
+        options.interval = interval_ms;
+        let router = router.clone();
+        let observer =
+            run_unsend(move || async move { router.create_audio_level_observer(options).await })
+                .await
+                .map_err(|_| ())?
+                .map_err(|_| ())?;
+
+        let users: Arc<Mutex<HashMap<ProducerId, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        let on_speakers = Arc::new(on_speakers);
+        {
+            let users = users.clone();
+            let on_speakers = on_speakers.clone();
+            observer
+                .on_volumes(move |volumes| {
+                    let speakers = {
+                        let users = users.lock();
+                        volumes
+                            .iter()
+                            .filter_map(|volume| {
+                                Some(SpeakerVolume {
+                                    user_id: users.get(&volume.producer.id())?.clone(),
+                                    volume: volume.volume,
+                                })
+                            })
+                            .collect()
+                    };
+                    on_speakers(speakers);
+                })
+                .detach();
+        }
+        observer
+            .on_silence(move || on_speakers(Vec::new()))
+            .detach();
+
+        Ok(AudioLevels {
+            observer,
+            threshold_db,
+            interval_ms,
+            users,
+        })
+    }
+
+    pub fn threshold_db(&self) -> i8 {
+        self.threshold_db
+    }
+
+    pub fn interval_ms(&self) -> u16 {
+        self.interval_ms
+    }
+
+    /// Observe the user's microphone producer, in place of the one it had before
+    pub async fn add(&self, user_id: &str, producer_id: ProducerId) {
+        {
+            let mut users = self.users.lock();
+            users.retain(|_, id| id != user_id);
+            users.insert(producer_id, user_id.to_string());
+        }
+
+        // Closed producers leave the observer by themselves
+        let observer = self.observer.clone();
+        let options = RtpObserverAddProducerOptions::new(producer_id);
+        let added = run_unsend(move || async move { observer.add_producer(options).await }).await;
+        if !matches!(added, Ok(Ok(()))) {
+            warn!(
+                "Failed to observe the audio level of producer {}",
+                producer_id
+            );
+            self.users.lock().remove(&producer_id);
+        }
+    }
+
+    /// Producers observed along with their users, to carry over to a new observer
+    pub fn producers(&self) -> Vec<(ProducerId, String)> {
+        self.users
+            .lock()
+            .iter()
+            .map(|(producer_id, user_id)| (*producer_id, user_id.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,104 @@
+//! Caps on the bitrate of producers, by kind. They're written into the `maxBitrate` of
+//! the producer's encodings and reported back, for the client to configure its encoder
+//! to stay under them
+
+use mediasoup::prelude::*;
+
+/// Least an encoding may be left with in bits per second, a cap that would leave one
+/// with less can't be met
+pub const MIN_ENCODING_BITRATE: u32 = 30_000;
+
+/// The cap would leave an encoding with less than `MIN_ENCODING_BITRATE`
+#[derive(Debug, PartialEq)]
+pub struct BitrateUnattainable;
+
+/// Clamp the encodings of a new producer so they add up to `cap` at most, returning the
+/// `maxBitrate` each was left with. Simulcast encodings share the cap from the lowest
+/// layer up, those that didn't ask for a bitrate split what the others leave
+pub fn clamp_encodings(
+    rtp_parameters: &mut RtpParameters,
+    cap: u32,
+) -> Result<Vec<u32>, BitrateUnattainable> {
+    let encodings = &mut rtp_parameters.encodings;
+    if encodings.is_empty() {
+        encodings.push(RtpEncodingParameters::default());
+    }
+
+    let mut remaining = cap;
+    for encoding in encodings.iter_mut() {
+        if let Some(bitrate) = encoding.max_bitrate {
+            let bitrate = bitrate.min(remaining);
+            remaining -= bitrate;
+            encoding.max_bitrate = Some(bitrate);
+        }
+    }
+    let unset = encodings
+        .iter()
+        .filter(|encoding| encoding.max_bitrate.is_none())
+        .count() as u32;
+    if unset > 0 {
+        let share = remaining / unset;
+        for encoding in encodings.iter_mut() {
+            encoding.max_bitrate.get_or_insert(share);
+        }
+    }
+
+    let applied: Vec<u32> = encodings
+        .iter()
+        .filter_map(|encoding| encoding.max_bitrate)
+        .collect();
+    if applied
+        .iter()
+        .any(|bitrate| *bitrate < MIN_ENCODING_BITRATE)
+    {
+        return Err(BitrateUnattainable);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(bitrates: &[Option<u32>]) -> RtpParameters {
+        RtpParameters {
+            encodings: bitrates
+                .iter()
+                .map(|bitrate| RtpEncodingParameters {
+                    max_bitrate: *bitrate,
+                    ..RtpEncodingParameters::default()
+                })
+                .collect(),
+            ..RtpParameters::default()
+        }
+    }
+
+    #[test]
+    fn encodings_share_the_cap() {
+        let mut single = parameters(&[Some(4_000_000)]);
+        assert_eq!(clamp_encodings(&mut single, 1_500_000), Ok(vec![1_500_000]));
+        assert_eq!(single.encodings[0].max_bitrate, Some(1_500_000));
+
+        // Lower layers keep what they asked for, the top one gets what's left
+        let mut simulcast = parameters(&[Some(150_000), Some(500_000), Some(2_500_000)]);
+        assert_eq!(
+            clamp_encodings(&mut simulcast, 1_500_000),
+            Ok(vec![150_000, 500_000, 850_000])
+        );
+
+        let mut unset = parameters(&[Some(100_000), None, None]);
+        assert_eq!(
+            clamp_encodings(&mut unset, 1_000_000),
+            Ok(vec![100_000, 450_000, 450_000])
+        );
+        let mut empty = parameters(&[]);
+        assert_eq!(clamp_encodings(&mut empty, 64_000), Ok(vec![64_000]));
+
+        // The top layer would be starved
+        let mut starved = parameters(&[Some(150_000), Some(500_000), Some(2_500_000)]);
+        assert_eq!(
+            clamp_encodings(&mut starved, 660_000),
+            Err(BitrateUnattainable)
+        );
+    }
+}
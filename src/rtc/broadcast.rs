@@ -0,0 +1,354 @@
+//! Live broadcasting of a room to an RTMP or SRT ingest. Selected producers are
+//! consumed onto local plain transports and pushed to the ingest by one FFmpeg process
+
+use std::fmt::{self, Display};
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use mediasoup::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use super::egress::{self, EgressError, EgressTrack};
+use crate::util::config::BroadcastConfig;
+
+/// Progress of a broadcast, reported to the room's participants
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BroadcastState {
+    /// FFmpeg is starting and connecting to the ingest
+    Connecting,
+    /// Media is being pushed to the ingest
+    Live,
+    /// FFmpeg exited on its own, the room carries on without the broadcast
+    Failed,
+    Stopped,
+}
+
+#[derive(Debug)]
+pub enum BroadcastError {
+    /// Broadcasting is not enabled on this node
+    Unavailable,
+    AlreadyBroadcasting,
+    NotBroadcasting,
+    /// The URL's scheme isn't one of the allowed ingest protocols
+    InvalidUrl(String),
+    /// None of the selected users produce anything
+    NothingToBroadcast,
+    Egress(EgressError),
+}
+
+impl Display for BroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BroadcastError::Unavailable => {
+                write!(f, "Broadcasting is not enabled on this server")
+            }
+            BroadcastError::AlreadyBroadcasting => write!(f, "Room is already being broadcast"),
+            BroadcastError::NotBroadcasting => write!(f, "Room is not being broadcast"),
+            BroadcastError::InvalidUrl(url) => write!(f, "Can't broadcast to {}", url),
+            BroadcastError::NothingToBroadcast => {
+                write!(f, "None of the selected users are producing media")
+            }
+            BroadcastError::Egress(err) => write!(f, "Broadcast failed: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for BroadcastError {
+    fn from(err: io::Error) -> Self {
+        BroadcastError::Egress(err.into())
+    }
+}
+
+impl From<EgressError> for BroadcastError {
+    fn from(err: EgressError) -> Self {
+        BroadcastError::Egress(err)
+    }
+}
+
+/// Management request to broadcast a room
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StartBroadcast {
+    /// `rtmp://`, `rtmps://` or `srt://` ingest, including any stream key
+    pub url: String,
+    /// Users heard and seen in the broadcast, every visible user when empty
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Producers sent to the ingest, audio is mixed and a single video is shown
+#[derive(Clone, Debug, PartialEq)]
+pub struct BroadcastSources {
+    pub audio: Vec<ProducerId>,
+    pub video: Option<ProducerId>,
+}
+
+/// A running broadcast, stopped when dropped
+pub struct Broadcast {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Broadcast {
+    /// Consume the sources and start pushing them to `url`, state changes are
+    /// reported through `on_state` until the broadcast ends
+    pub async fn start<F>(
+        router: &Router,
+        url: &str,
+        sources: BroadcastSources,
+        config: &BroadcastConfig,
+        on_state: F,
+    ) -> Result<Self, BroadcastError>
+    where
+        F: Fn(BroadcastState) + Send + 'static,
+    {
+        let format = output_format(url, &config.allowed_schemes)
+            .ok_or_else(|| BroadcastError::InvalidUrl(url.to_string()))?;
+        if sources.audio.is_empty() && sources.video.is_none() {
+            return Err(BroadcastError::NothingToBroadcast);
+        }
+
+        let mut tracks = Vec::new();
+        for producer_id in sources.audio.iter().chain(sources.video.iter()) {
+            tracks.push(EgressTrack::create(router, *producer_id).await?);
+        }
+
+        let sections = tracks
+            .iter()
+            .map(EgressTrack::media_section)
+            .collect::<Result<Vec<String>, EgressError>>()?;
+        let sdp_path =
+            std::env::temp_dir().join(format!("vortex-broadcast-{}.sdp", rand::random::<u64>()));
+        tokio::fs::write(&sdp_path, egress::session_description(&sections)).await?;
+
+        let process = Command::new(&config.ffmpeg)
+            .args(ffmpeg_args(
+                &sdp_path.to_string_lossy(),
+                sources.audio.len(),
+                sources.video.is_some(),
+                format,
+                url,
+            ))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let process = match process {
+            Ok(process) => process,
+            Err(err) => {
+                tokio::fs::remove_file(&sdp_path).await.ok();
+                return Err(err.into());
+            }
+        };
+
+        on_state(BroadcastState::Connecting);
+        let (stop, stopped) = oneshot::channel();
+        let finished = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(supervise(
+            process,
+            tracks,
+            sdp_path,
+            stopped,
+            on_state,
+            finished.clone(),
+        ));
+
+        Ok(Broadcast {
+            stop,
+            task,
+            finished,
+        })
+    }
+
+    /// Whether the broadcast ended, because it failed or was stopped
+    pub fn finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Stop pushing to the ingest, returning once FFmpeg exited
+    pub async fn stop(self) {
+        self.stop.send(()).ok();
+        self.task.await.ok();
+    }
+}
+
+/// Watch FFmpeg until it's asked to stop or exits on its own. A failing pipeline only
+/// ends its own broadcast, the room and its participants aren't affected
+async fn supervise<F>(
+    mut process: Child,
+    tracks: Vec<EgressTrack>,
+    sdp_path: PathBuf,
+    mut stopped: oneshot::Receiver<()>,
+    on_state: F,
+    finished: Arc<AtomicBool>,
+) where
+    F: Fn(BroadcastState),
+{
+    tokio::time::sleep(egress::STARTUP_DELAY).await;
+    for track in tracks.iter() {
+        if let Err(err) = track.start().await {
+            warn!("Failed to start broadcast track: {}", err);
+        }
+    }
+
+    // `-progress` reports on stdout once media is being written to the ingest
+    let mut progress = process
+        .stdout
+        .take()
+        .map(|stdout| BufReader::new(stdout).lines());
+    let mut live = false;
+    let state = loop {
+        let line = async {
+            match progress.as_mut() {
+                Some(lines) => lines.next_line().await.ok().flatten(),
+                None => None,
+            }
+        };
+
+        tokio::select! {
+            // Stopped, or the handle was dropped along with the room
+            _ = &mut stopped => {
+                egress::stop_process(&mut process).await;
+                break BroadcastState::Stopped;
+            }
+            line = line => match line {
+                Some(line) => {
+                    if !live && line.starts_with("progress=") {
+                        live = true;
+                        on_state(BroadcastState::Live);
+                    }
+                }
+                None => {
+                    match process.wait().await {
+                        Ok(status) => warn!("Broadcast FFmpeg exited with {}", status),
+                        Err(err) => warn!("Failed to wait for broadcast FFmpeg: {}", err),
+                    }
+                    break BroadcastState::Failed;
+                }
+            },
+        }
+    };
+
+    drop(tracks);
+    tokio::fs::remove_file(&sdp_path).await.ok();
+    finished.store(true, Ordering::Relaxed);
+    on_state(state);
+}
+
+/// FFmpeg muxer for the ingest a URL points at, if its scheme is allowed
+fn output_format(url: &str, allowed_schemes: &[String]) -> Option<&'static str> {
+    let (scheme, rest) = url.split_once("://")?;
+    if rest.is_empty() || url.chars().any(char::is_whitespace) {
+        return None;
+    }
+
+    if !allowed_schemes.iter().any(|allowed| allowed == scheme) {
+        return None;
+    }
+
+    match scheme {
+        "rtmp" | "rtmps" => Some("flv"),
+        "srt" => Some("mpegts"),
+        _ => None,
+    }
+}
+
+/// Encode for the ingest, RTMP and SRT targets expect H.264 and AAC rather than
+/// what WebRTC produces
+fn ffmpeg_args(sdp: &str, audio: usize, video: bool, format: &str, url: &str) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "-loglevel",
+        "error",
+        "-nostats",
+        "-progress",
+        "pipe:1",
+        "-protocol_whitelist",
+        "file,rtp,udp",
+        "-i",
+        sdp,
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect();
+
+    args.extend(egress::transcode_args(audio, video));
+    args.push("-f".to_string());
+    args.push(format.to_string());
+    args.push(url.to_string());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schemes() -> Vec<String> {
+        vec!["rtmp".to_string(), "rtmps".to_string(), "srt".to_string()]
+    }
+
+    #[test]
+    fn ingest_formats() {
+        let schemes = schemes();
+        assert_eq!(
+            output_format("rtmp://a.rtmp.youtube.com/live2/key", &schemes),
+            Some("flv")
+        );
+        assert_eq!(
+            output_format("srt://ingest.example.com:9000", &schemes),
+            Some("mpegts")
+        );
+        assert_eq!(output_format("file:///etc/passwd", &schemes), None);
+        assert_eq!(output_format("rtmp://", &schemes), None);
+        assert_eq!(output_format("rtmp://host/key -y", &schemes), None);
+        assert_eq!(output_format("rtmp://host/key", &["srt".to_string()]), None);
+    }
+
+    #[test]
+    fn audio_is_mixed() {
+        let args = ffmpeg_args("in.sdp", 3, true, "flv", "rtmp://host/key");
+        let filter = args
+            .iter()
+            .position(|arg| arg == "-filter_complex")
+            .unwrap();
+        assert_eq!(args[filter + 1], "[0:a:0][0:a:1][0:a:2]amix=inputs=3[a]");
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:v:0"]));
+        assert_eq!(args[args.len() - 3..], ["-f", "flv", "rtmp://host/key"]);
+    }
+
+    #[test]
+    fn single_audio_is_mapped() {
+        let args = ffmpeg_args("in.sdp", 1, false, "mpegts", "srt://host:9000");
+        assert!(!args.iter().any(|arg| arg == "-filter_complex"));
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:a:0"]));
+        assert!(!args.iter().any(|arg| arg == "libx264"));
+    }
+
+    #[test]
+    fn states_serialize_lowercase() {
+        let states = [
+            BroadcastState::Connecting,
+            BroadcastState::Live,
+            BroadcastState::Failed,
+            BroadcastState::Stopped,
+        ];
+        let names: Vec<String> = states
+            .iter()
+            .map(|state| serde_json::to_value(state).unwrap())
+            .map(|value| value.as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["connecting", "live", "failed", "stopped"]);
+    }
+}
@@ -0,0 +1,19 @@
+//! Consumers mediasoup closed along with their producer. The room usually tells the
+//! connection first, but a producer may also go away with its transport or the worker
+
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// IDs of consumers whose producer closed
+pub type ClosedReceiver = UnboundedReceiver<String>;
+
+/// Report the consumer's ID once its producer closes. Reports after the connection
+/// closed are dropped
+pub fn watch(consumer: &Consumer, sender: UnboundedSender<String>) {
+    let consumer_id = consumer.id().to_string();
+    consumer
+        .on_producer_close(move || {
+            sender.send(consumer_id).ok();
+        })
+        .detach();
+}
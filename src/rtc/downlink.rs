@@ -0,0 +1,92 @@
+//! Bitrate mediasoup's congestion control estimates is available towards the client on
+//! its receiving transport, forwarded to clients that asked for it so they can lower
+//! their consumers' layers or pause some before their downlink gives out
+
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use mediasoup::transport::{TransportTraceEventData, TransportTraceEventType};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// Most often an estimate is sent, they're traced several times a second
+pub const ESTIMATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Available bitrate in bits per second
+pub type DownlinkReceiver = UnboundedReceiver<u32>;
+
+/// Forward the estimates of the transport media is sent to the client on, for as long as
+/// the transport lives. Estimates sent after the connection closed are dropped
+pub async fn watch(transport: &WebRtcTransport, sender: UnboundedSender<u32>) {
+    let enabled = transport
+        .enable_trace_event(vec![TransportTraceEventType::Bwe])
+        .await;
+    if let Err(err) = enabled {
+        warn!("Failed to trace bandwidth estimates: {}", err);
+        return;
+    }
+
+    transport
+        .on_trace(move |trace| {
+            if let TransportTraceEventData::Bwe { info, .. } = trace {
+                sender.send(info.available_bitrate).ok();
+            }
+        })
+        .detach();
+}
+
+/// Holds back estimates so one is sent at most once per `ESTIMATE_INTERVAL`, only the
+/// latest is kept in between and it's not sent again if it didn't change
+#[derive(Default)]
+pub struct DownlinkThrottle {
+    pending: Option<u32>,
+    last_sent: Option<u32>,
+    last_flush: Option<Instant>,
+}
+
+impl DownlinkThrottle {
+    pub fn offer(&mut self, available_bitrate: u32) {
+        self.pending = Some(available_bitrate).filter(|bitrate| Some(*bitrate) != self.last_sent);
+    }
+
+    /// When the pending estimate may be sent, `None` if there's none
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.pending?;
+        Some(match self.last_flush {
+            Some(last_flush) => last_flush + ESTIMATE_INTERVAL,
+            None => Instant::now(),
+        })
+    }
+
+    pub fn flush(&mut self) -> Option<u32> {
+        let available_bitrate = self.pending.take()?;
+        self.last_flush = Some(Instant::now());
+        self.last_sent = Some(available_bitrate);
+        Some(available_bitrate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_change_is_sent() {
+        let mut throttle = DownlinkThrottle::default();
+        assert!(throttle.next_flush().is_none());
+
+        for bitrate in [2_000_000, 1_200_000, 800_000].iter() {
+            throttle.offer(*bitrate);
+        }
+        assert!(throttle.next_flush().unwrap() <= Instant::now());
+        assert_eq!(throttle.flush(), Some(800_000));
+        assert_eq!(throttle.flush(), None);
+
+        // Unchanged estimates aren't sent again
+        throttle.offer(800_000);
+        assert!(throttle.next_flush().is_none());
+
+        throttle.offer(600_000);
+        let next_flush = throttle.next_flush().unwrap();
+        assert!(next_flush > Instant::now() + ESTIMATE_INTERVAL / 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! mediasoup's view of a room's router, transports, producers and consumers, handed to
+//! moderators and the management API for debugging instead of attaching to the worker
+
+use mediasoup::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::local::run_unsend;
+
+/// Largest dump returned as JSON, bigger ones are cut off and returned as text
+pub const MAX_DUMP_BYTES: usize = 256 * 1024;
+
+/// What to dump, transports are those of the connection asking
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DumpTarget {
+    Router,
+    Transports,
+    Producer { id: String },
+    Consumer { id: String },
+}
+
+/// mediasoup's dump, opaque to the server
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Dump {
+    pub dump: Value,
+    /// The dump was above `MAX_DUMP_BYTES`, so `dump` holds the start of its JSON text
+    pub truncated: bool,
+}
+
+impl Dump {
+    pub fn new<T: Serialize>(dump: &T) -> Result<Dump, ()> {
+        let value = serde_json::to_value(dump).map_err(|_| ())?;
+        Ok(Dump::limit(value, MAX_DUMP_BYTES))
+    }
+
+    fn limit(value: Value, max_bytes: usize) -> Dump {
+        let text = value.to_string();
+        if text.len() <= max_bytes {
+            return Dump {
+                dump: value,
+                truncated: false,
+            };
+        }
+
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        Dump {
+            dump: Value::String(text[..end].to_string()),
+            truncated: true,
+        }
+    }
+}
+
+pub async fn router(router: &Router) -> Result<Dump, ()> {
+    let dump = router.dump().await.map_err(|_| ())?;
+    Dump::new(&dump)
+}
+
+pub async fn producer(producer: &Producer) -> Result<Dump, ()> {
+    let dump = producer.dump().await.map_err(|_| ())?;
+    Dump::new(&dump)
+}
+
+pub async fn consumer(consumer: &Consumer) -> Result<Dump, ()> {
+    let dump = consumer.dump().await.map_err(|_| ())?;
+    Dump::new(&dump)
+}
+
+/// Dumps of the given WebRTC and plain transports, as one array
+pub async fn transports(
+    webrtc: Vec<WebRtcTransport>,
+    plain: Vec<PlainTransport>,
+) -> Result<Dump, ()> {
+    let dumps = run_unsend(move || async move {
+        let mut dumps = Vec::new();
+        for transport in webrtc {
+            let dump = transport.dump().await.map_err(|_| ())?;
+            dumps.push(serde_json::to_value(dump).map_err(|_| ())?);
+        }
+        for transport in plain {
+            let dump = transport.dump().await.map_err(|_| ())?;
+            dumps.push(serde_json::to_value(dump).map_err(|_| ())?);
+        }
+        Ok::<_, ()>(dumps)
+    })
+    .await
+    .map_err(|_| ())??;
+    Dump::new(&dumps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn large_dumps_are_truncated() {
+        let value = json!({ "id": "router", "transportIds": ["a", "b"] });
+        let dump = Dump::limit(value.clone(), 1024);
+        assert_eq!(dump.dump, value);
+        assert!(!dump.truncated);
+
+        let dump = Dump::limit(value.clone(), 10);
+        assert_eq!(
+            dump.dump,
+            Value::String(value.to_string()[..10].to_string())
+        );
+        assert!(dump.truncated);
+
+        // Never cut inside a character
+        let dump = Dump::limit(json!("ééé"), 3);
+        assert_eq!(dump.dump, json!("\"é"));
+    }
+
+    #[test]
+    fn targets_are_tagged() {
+        let target: DumpTarget =
+            serde_json::from_value(json!({ "type": "consumer", "id": "consumer" })).unwrap();
+        assert_eq!(
+            target,
+            DumpTarget::Consumer {
+                id: "consumer".to_string()
+            }
+        );
+        let target: DumpTarget = serde_json::from_value(json!({ "type": "router" })).unwrap();
+        assert_eq!(target, DumpTarget::Router);
+    }
+}
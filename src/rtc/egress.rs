@@ -0,0 +1,372 @@
+//! Media leaving the server over local plain transports, picked up by FFmpeg
+//! for recording and broadcasting
+
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+use mediasoup::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Child;
+
+use super::local::run_unsend;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+/// Time FFmpeg is given to open its sockets before media starts flowing
+pub const STARTUP_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait for FFmpeg to flush its output once asked to quit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum EgressError {
+    Io(String),
+    Rtc(String),
+    /// The producer uses a codec FFmpeg isn't set up for
+    UnsupportedCodec,
+}
+
+impl Display for EgressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressError::Io(err) => write!(f, "{}", err),
+            EgressError::Rtc(err) => write!(f, "{}", err),
+            EgressError::UnsupportedCodec => write!(f, "Producer codec can't be sent to FFmpeg"),
+        }
+    }
+}
+
+impl From<io::Error> for EgressError {
+    fn from(err: io::Error) -> Self {
+        EgressError::Io(err.to_string())
+    }
+}
+
+/// Producer consumed onto a local plain transport, sending RTP to `port` and RTCP
+/// to the port after it. Dropping it closes the consumer and transport
+pub struct EgressTrack {
+    pub consumer: Consumer,
+    pub port: u16,
+    _transport: PlainTransport,
+}
+
+impl EgressTrack {
+    /// Consume a producer, paused until `start` so nothing is lost before FFmpeg listens
+    pub async fn create(router: &Router, producer_id: ProducerId) -> Result<Self, EgressError> {
+        let port = free_port_pair()?;
+        let mut options = PlainTransportOptions::new(TransportListenIp {
+            ip: LOCALHOST,
+            announced_ip: None,
+        });
+        options.rtcp_mux = false;
+        options.comedia = false;
+        let transport = router
+            .create_plain_transport(options)
+            .await
+            .map_err(|err| EgressError::Rtc(err.to_string()))?;
+        transport
+            .connect(PlainTransportRemoteParameters {
+                ip: Some(LOCALHOST),
+                port: Some(port),
+                rtcp_port: Some(port + 1),
+                srtp_parameters: None,
+            })
+            .await
+            .map_err(|err| EgressError::Rtc(err.to_string()))?;
+
+        let mut consumer_options = ConsumerOptions::new(producer_id, egress_capabilities(router));
+        consumer_options.paused = true;
+        let consumer = {
+            let transport = transport.clone();
+            run_unsend(move || async move { transport.consume(consumer_options).await })
+                .await
+                .map_err(|err| EgressError::Rtc(err.to_string()))?
+                .map_err(|err| EgressError::Rtc(err.to_string()))?
+        };
+
+        Ok(EgressTrack {
+            consumer,
+            port,
+            _transport: transport,
+        })
+    }
+
+    /// SDP media section describing the track to FFmpeg
+    pub fn media_section(&self) -> Result<String, EgressError> {
+        media_section(self.consumer.rtp_parameters(), self.port)
+    }
+
+    /// Let media flow, once FFmpeg had time to start listening
+    pub async fn start(&self) -> Result<(), EgressError> {
+        self.consumer
+            .resume()
+            .await
+            .map_err(|err| EgressError::Rtc(err.to_string()))?;
+        if self.consumer.kind() == MediaKind::Video {
+            self.consumer.request_key_frame().await.ok();
+        }
+
+        Ok(())
+    }
+}
+
+/// Ask FFmpeg to finish its output, killing it if it doesn't exit in time
+pub async fn stop_process(process: &mut Child) {
+    if let Some(mut stdin) = process.stdin.take() {
+        stdin.write_all(b"q").await.ok();
+    }
+
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, process.wait()).await {
+        Ok(Ok(status)) if status.success() => (),
+        Ok(Ok(status)) => warn!("FFmpeg exited with {}", status),
+        Ok(Err(err)) => warn!("Failed to wait for FFmpeg: {}", err),
+        Err(_) => {
+            warn!("FFmpeg didn't exit in time, killing it");
+            process.kill().await.ok();
+        }
+    }
+}
+
+/// Map the tracks of an egress session and encode them to AAC and H.264, mixing every
+/// audio track into one stream
+pub fn transcode_args(audio: usize, video: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    match audio {
+        0 => (),
+        1 => args.extend(vec!["-map".to_string(), "0:a:0".to_string()]),
+        _ => {
+            let inputs: String = (0..audio).map(|i| format!("[0:a:{}]", i)).collect();
+            args.push("-filter_complex".to_string());
+            args.push(format!("{}amix=inputs={}[a]", inputs, audio));
+            args.push("-map".to_string());
+            args.push("[a]".to_string());
+        }
+    }
+
+    if audio > 0 {
+        args.extend(
+            vec!["-c:a", "aac", "-b:a", "128k"]
+                .into_iter()
+                .map(str::to_string),
+        );
+    }
+
+    if video {
+        args.extend(
+            vec![
+                "-map",
+                "0:v:0",
+                "-c:v",
+                "libx264",
+                "-preset",
+                "veryfast",
+                "-tune",
+                "zerolatency",
+                "-pix_fmt",
+                "yuv420p",
+                "-g",
+                "60",
+            ]
+            .into_iter()
+            .map(str::to_string),
+        );
+    }
+
+    args
+}
+
+/// IDs are chosen by clients of the API, keep them from escaping output directories
+pub fn path_safe(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Capabilities of the egress side, every codec of the router except retransmission
+pub(super) fn egress_capabilities(router: &Router) -> RtpCapabilities {
+    let codecs = router
+        .rtp_capabilities()
+        .codecs
+        .iter()
+        .filter_map(|codec| match codec.clone() {
+            RtpCodecCapabilityFinalized::Audio {
+                mime_type,
+                preferred_payload_type,
+                clock_rate,
+                channels,
+                parameters,
+                rtcp_feedback,
+            } => Some(RtpCodecCapability::Audio {
+                mime_type,
+                preferred_payload_type: Some(preferred_payload_type),
+                clock_rate,
+                channels,
+                parameters,
+                rtcp_feedback,
+            }),
+            RtpCodecCapabilityFinalized::Video {
+                mime_type: MimeTypeVideo::Rtx,
+                ..
+            } => None,
+            RtpCodecCapabilityFinalized::Video {
+                mime_type,
+                preferred_payload_type,
+                clock_rate,
+                parameters,
+                rtcp_feedback,
+            } => Some(RtpCodecCapability::Video {
+                mime_type,
+                preferred_payload_type: Some(preferred_payload_type),
+                clock_rate,
+                parameters,
+                rtcp_feedback,
+            }),
+        })
+        .collect();
+
+    RtpCapabilities {
+        codecs,
+        header_extensions: Vec::new(),
+    }
+}
+
+/// Session description of one or more tracks, FFmpeg reads it as its input
+pub fn session_description(media_sections: &[String]) -> String {
+    let mut sdp = "v=0\r\n\
+                   o=- 0 0 IN IP4 127.0.0.1\r\n\
+                   s=Vortex egress\r\n\
+                   c=IN IP4 127.0.0.1\r\n\
+                   t=0 0\r\n"
+        .to_string();
+    for section in media_sections {
+        sdp.push_str(section);
+    }
+
+    sdp
+}
+
+fn media_section(parameters: &RtpParameters, port: u16) -> Result<String, EgressError> {
+    let (media, payload_type, rtpmap) = match parameters.codecs.first() {
+        Some(RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type,
+            clock_rate,
+            channels,
+            ..
+        }) => (
+            "audio",
+            *payload_type,
+            format!("opus/{}/{}", clock_rate, channels),
+        ),
+        Some(RtpCodecParameters::Video {
+            mime_type: MimeTypeVideo::Vp8,
+            payload_type,
+            clock_rate,
+            ..
+        }) => ("video", *payload_type, format!("VP8/{}", clock_rate)),
+        _ => return Err(EgressError::UnsupportedCodec),
+    };
+
+    Ok(format!(
+        "m={media} {port} RTP/AVP {payload_type}\r\n\
+         a=rtcp:{rtcp_port}\r\n\
+         a=rtpmap:{payload_type} {rtpmap}\r\n\
+         a=recvonly\r\n",
+        media = media,
+        port = port,
+        rtcp_port = port + 1,
+        payload_type = payload_type,
+        rtpmap = rtpmap,
+    ))
+}
+
+/// Even local UDP port whose successor is free as well, for FFmpeg's RTP and RTCP
+fn free_port_pair() -> io::Result<u16> {
+    for _ in 0..16 {
+        let socket = UdpSocket::bind((LOCALHOST, 0))?;
+        let port = socket.local_addr()?.port() & !1;
+        drop(socket);
+
+        if port == 0 || port == u16::MAX - 1 {
+            continue;
+        }
+
+        let rtp = UdpSocket::bind((LOCALHOST, port));
+        let rtcp = UdpSocket::bind((LOCALHOST, port + 1));
+        if rtp.is_ok() && rtcp.is_ok() {
+            return Ok(port);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "No free port pair for FFmpeg",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::{NonZeroU32, NonZeroU8};
+
+    fn parameters(codec: RtpCodecParameters) -> RtpParameters {
+        RtpParameters {
+            codecs: vec![codec],
+            ..RtpParameters::default()
+        }
+    }
+
+    #[test]
+    fn opus_media_section() {
+        let parameters = parameters(RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type: 100,
+            clock_rate: NonZeroU32::new(48000).unwrap(),
+            channels: NonZeroU8::new(2).unwrap(),
+            parameters: RtpCodecParametersParameters::default(),
+            rtcp_feedback: Vec::new(),
+        });
+
+        let section = media_section(&parameters, 40000).unwrap();
+        assert!(section.starts_with("m=audio 40000 RTP/AVP 100\r\n"));
+        assert!(section.contains("a=rtcp:40001\r\n"));
+        assert!(section.contains("a=rtpmap:100 opus/48000/2\r\n"));
+    }
+
+    #[test]
+    fn vp8_media_section() {
+        let parameters = parameters(RtpCodecParameters::Video {
+            mime_type: MimeTypeVideo::Vp8,
+            payload_type: 101,
+            clock_rate: NonZeroU32::new(90000).unwrap(),
+            parameters: RtpCodecParametersParameters::default(),
+            rtcp_feedback: Vec::new(),
+        });
+
+        let section = media_section(&parameters, 40002).unwrap();
+        assert!(section.starts_with("m=video 40002 RTP/AVP 101\r\n"));
+        assert!(section.contains("a=rtpmap:101 VP8/90000\r\n"));
+    }
+
+    #[test]
+    fn sections_follow_the_session() {
+        let sdp = session_description(&["m=audio 1\r\n".to_string(), "m=video 2\r\n".to_string()]);
+        assert!(sdp.starts_with("v=0\r\n"));
+        assert!(sdp.ends_with("t=0 0\r\nm=audio 1\r\nm=video 2\r\n"));
+    }
+
+    #[test]
+    fn ids_stay_in_the_directory() {
+        assert_eq!(path_safe("user-1_a"), "user-1_a");
+        assert_eq!(path_safe("../../etc/passwd"), "______etc_passwd");
+    }
+
+    #[test]
+    fn port_pairs_start_even() {
+        let port = free_port_pair().unwrap();
+        assert_eq!(port % 2, 0);
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use mediasoup::consumer::{ConsumerTraceEventData, ConsumerTraceEventType};
+use mediasoup::data_structures::TraceEventDirection;
+use mediasoup::prelude::*;
+
+use super::layers;
+use crate::util::metrics::FIRST_VIDEO_FRAME_SECONDS;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Consecutive bandwidth estimate samples within tolerance before it is considered settled
+const STABLE_SAMPLES: usize = 3;
+const STABLE_TOLERANCE: f64 = 0.1;
+
+/// Layers a fast joining consumer starts at
+pub fn initial_layers() -> ConsumerLayers {
+    ConsumerLayers {
+        spatial_layer: 0,
+        temporal_layer: None,
+    }
+}
+
+/// Move a fast joining consumer up to the highest spatial layer under its cap once the
+/// transport's outgoing bandwidth estimate has settled, or `max_delay` elapsed. The
+/// consumer is left alone if the client turned its automatic layers off by then
+pub fn promote_when_stable(
+    transport: WebRtcTransport,
+    consumer: &Consumer,
+    auto_layers: Arc<AtomicBool>,
+    layer_cap: Arc<AtomicU8>,
+    max_delay: Duration,
+) {
+    if layers::spatial_layers(consumer) <= 1 {
+        return;
+    }
+
+    let consumer = consumer.downgrade();
+    tokio::spawn(async move {
+        let deadline = Instant::now() + max_delay;
+        let mut previous: Option<u32> = None;
+        let mut stable = 0;
+        while stable < STABLE_SAMPLES && Instant::now() < deadline {
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+            let transport = transport.clone();
+            let stats = super::run_unsend(move || async move { transport.get_stats().await }).await;
+            let bitrate = match stats {
+                Ok(Ok(stats)) => stats
+                    .first()
+                    .and_then(|stat| stat.available_outgoing_bitrate),
+                _ => return,
+            };
+
+            stable = stable_samples(stable, previous, bitrate);
+            previous = bitrate;
+        }
+
+        if !auto_layers.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(consumer) = consumer.upgrade() {
+            let layers = layers::highest(&consumer, layer_cap.load(Ordering::Relaxed));
+            if let Err(err) = consumer.set_preferred_layers(layers).await {
+                warn!("Failed to promote consumer {}: {}", consumer.id(), err);
+            } else {
+                debug!("Promoted fast join consumer {}", consumer.id());
+            }
+        }
+    });
+}
+
+/// Samples in a row the bandwidth estimate stayed within tolerance, counting `bitrate`.
+/// An estimate missing on either side starts the count over
+fn stable_samples(stable: usize, previous: Option<u32>, bitrate: Option<u32>) -> usize {
+    match (previous, bitrate) {
+        (Some(previous), Some(bitrate)) if previous > 0 => {
+            let change = (bitrate as f64 - previous as f64).abs() / previous as f64;
+            if change <= STABLE_TOLERANCE {
+                stable + 1
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Record the time from `connected_at` until the first keyframe is forwarded
+/// through `consumer`, unless another consumer of the connection got there first
+pub async fn observe_first_frame(
+    consumer: &Consumer,
+    connected_at: Instant,
+    recorded: Arc<AtomicBool>,
+    mode: &'static str,
+) {
+    if recorded.load(Ordering::Acquire) {
+        return;
+    }
+
+    if consumer
+        .enable_trace_event(vec![ConsumerTraceEventType::KeyFrame])
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Keyframe traces are rare, so the handler stays attached after recording
+    consumer
+        .on_trace(move |data| {
+            if let ConsumerTraceEventData::KeyFrame {
+                direction: TraceEventDirection::Out,
+                ..
+            } = data
+            {
+                if !recorded.swap(true, Ordering::AcqRel) {
+                    FIRST_VIDEO_FRAME_SECONDS
+                        .with_label_values(&[mode])
+                        .observe(connected_at.elapsed().as_secs_f64());
+                }
+            }
+        })
+        .detach();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_settle_within_tolerance() {
+        let samples = [None, Some(1_000_000), Some(1_050_000), Some(1_000_000)];
+        let stable = samples
+            .windows(2)
+            .fold(0, |stable, pair| stable_samples(stable, pair[0], pair[1]));
+        assert_eq!(stable, 2);
+
+        // A jump or a missing estimate starts over
+        assert_eq!(stable_samples(2, Some(1_000_000), Some(1_200_000)), 0);
+        assert_eq!(stable_samples(2, Some(1_000_000), None), 0);
+        assert_eq!(stable_samples(2, Some(0), Some(0)), 0);
+        assert_eq!(initial_layers().spatial_layer, 0);
+    }
+}
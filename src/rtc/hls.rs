@@ -0,0 +1,265 @@
+//! HLS packaging for passive audiences. The room's audio and one video are consumed
+//! onto local plain transports and segmented to disk by an FFmpeg process
+
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use mediasoup::prelude::*;
+use tokio::process::{Child, Command};
+
+use super::broadcast::BroadcastSources;
+use super::egress::{self, path_safe, EgressError, EgressTrack};
+use crate::util::config::HlsConfig;
+
+/// Playlist viewers load, segments are listed in it
+pub const PLAYLIST: &str = "index.m3u8";
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_EXTENSION: &str = ".ts";
+
+#[derive(Debug)]
+pub enum HlsError {
+    /// HLS is not enabled on this node
+    Unavailable,
+    Egress(EgressError),
+}
+
+impl Display for HlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HlsError::Unavailable => write!(f, "HLS is not enabled on this server"),
+            HlsError::Egress(err) => write!(f, "HLS packaging failed: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for HlsError {
+    fn from(err: io::Error) -> Self {
+        HlsError::Egress(err.into())
+    }
+}
+
+impl From<EgressError> for HlsError {
+    fn from(err: EgressError) -> Self {
+        HlsError::Egress(err)
+    }
+}
+
+/// Packager writing a room's playlist, FFmpeg is restarted whenever the packaged
+/// producers change and the playlist carries on across restarts
+pub struct HlsPackager {
+    router: Router,
+    config: HlsConfig,
+    directory: PathBuf,
+    sources: Option<BroadcastSources>,
+    pipeline: Option<Pipeline>,
+}
+
+struct Pipeline {
+    _tracks: Vec<EgressTrack>,
+    process: Child,
+}
+
+impl HlsPackager {
+    /// Create the room's output directory, nothing is packaged until `update`
+    pub async fn create(
+        router: Router,
+        room_id: &str,
+        config: &HlsConfig,
+    ) -> Result<Self, HlsError> {
+        let directory = Path::new(&config.directory).join(path_safe(room_id));
+        // Left over by an earlier room with the same ID
+        tokio::fs::remove_dir_all(&directory).await.ok();
+        tokio::fs::create_dir_all(&directory).await?;
+
+        Ok(HlsPackager {
+            router,
+            config: config.clone(),
+            directory,
+            sources: None,
+            pipeline: None,
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Package the given producers, restarting FFmpeg if they changed or it exited
+    pub async fn update(&mut self, sources: BroadcastSources) -> Result<(), HlsError> {
+        let running = match self.pipeline.as_mut() {
+            Some(pipeline) => matches!(pipeline.process.try_wait(), Ok(None)),
+            None => false,
+        };
+        if running && self.sources.as_ref() == Some(&sources) {
+            return Ok(());
+        }
+
+        if let Some(mut pipeline) = self.pipeline.take() {
+            egress::stop_process(&mut pipeline.process).await;
+        }
+
+        self.sources = None;
+        if sources.audio.is_empty() && sources.video.is_none() {
+            return Ok(());
+        }
+
+        self.pipeline = Some(self.start(&sources).await?);
+        self.sources = Some(sources);
+        Ok(())
+    }
+
+    async fn start(&self, sources: &BroadcastSources) -> Result<Pipeline, HlsError> {
+        let mut tracks = Vec::new();
+        for producer_id in sources.audio.iter().chain(sources.video.iter()) {
+            tracks.push(EgressTrack::create(&self.router, *producer_id).await?);
+        }
+
+        let sections = tracks
+            .iter()
+            .map(EgressTrack::media_section)
+            .collect::<Result<Vec<String>, EgressError>>()?;
+        let sdp_path = self.directory.join("input.sdp");
+        tokio::fs::write(&sdp_path, egress::session_description(&sections)).await?;
+
+        let process = Command::new(&self.config.ffmpeg)
+            .args(hls_args(
+                &sdp_path.to_string_lossy(),
+                sources.audio.len(),
+                sources.video.is_some(),
+                &self.config,
+                &self.directory,
+            ))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        tokio::time::sleep(egress::STARTUP_DELAY).await;
+        for track in tracks.iter() {
+            track.start().await?;
+        }
+
+        Ok(Pipeline {
+            _tracks: tracks,
+            process,
+        })
+    }
+
+    /// Stop packaging and delete the playlist along with its segments
+    pub async fn stop(mut self) {
+        if let Some(mut pipeline) = self.pipeline.take() {
+            egress::stop_process(&mut pipeline.process).await;
+        }
+
+        tokio::fs::remove_dir_all(&self.directory).await.ok();
+    }
+}
+
+/// Content type of a file viewers may load from the output directory, `None` for
+/// anything else in it
+pub fn content_type(name: &str) -> Option<&'static str> {
+    if name == PLAYLIST {
+        return Some("application/vnd.apple.mpegurl");
+    }
+
+    let number = name
+        .strip_prefix(SEGMENT_PREFIX)?
+        .strip_suffix(SEGMENT_EXTENSION)?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some("video/mp2t")
+}
+
+/// Segments beyond the playlist size are deleted by FFmpeg, bounding the disk used by
+/// long calls. Restarts append to the playlist behind a discontinuity
+fn hls_args(
+    sdp: &str,
+    audio: usize,
+    video: bool,
+    config: &HlsConfig,
+    directory: &Path,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "-loglevel",
+        "error",
+        "-protocol_whitelist",
+        "file,rtp,udp",
+        "-i",
+        sdp,
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect();
+
+    args.extend(egress::transcode_args(audio, video));
+    args.extend(vec![
+        "-f".to_string(),
+        "hls".to_string(),
+        "-hls_time".to_string(),
+        config.segment_secs.to_string(),
+        "-hls_list_size".to_string(),
+        config.playlist_size.to_string(),
+        "-hls_flags".to_string(),
+        "delete_segments+append_list+discont_start+independent_segments".to_string(),
+        "-hls_segment_filename".to_string(),
+        directory
+            .join(format!("{}%05d{}", SEGMENT_PREFIX, SEGMENT_EXTENSION))
+            .to_string_lossy()
+            .into_owned(),
+        directory.join(PLAYLIST).to_string_lossy().into_owned(),
+    ]);
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_playlist_and_segments_are_served() {
+        assert_eq!(
+            content_type("index.m3u8"),
+            Some("application/vnd.apple.mpegurl")
+        );
+        assert_eq!(content_type("segment-00042.ts"), Some("video/mp2t"));
+        assert_eq!(content_type("input.sdp"), None);
+        assert_eq!(content_type("segment-.ts"), None);
+        assert_eq!(content_type("segment-../index.ts"), None);
+        assert_eq!(content_type("../index.m3u8"), None);
+    }
+
+    #[test]
+    fn retention_is_bounded() {
+        let config = HlsConfig {
+            directory: "/var/lib/vortex/hls".to_string(),
+            ffmpeg: "ffmpeg".to_string(),
+            segment_secs: 2,
+            playlist_size: 10,
+        };
+        let args = hls_args(
+            "input.sdp",
+            2,
+            true,
+            &config,
+            Path::new("/var/lib/vortex/hls/room"),
+        );
+
+        let value = |flag: &str| {
+            let index = args.iter().position(|arg| arg == flag).unwrap();
+            args[index + 1].as_str()
+        };
+        assert_eq!(value("-hls_time"), "2");
+        assert_eq!(value("-hls_list_size"), "10");
+        assert!(value("-hls_flags").contains("delete_segments"));
+        assert_eq!(
+            value("-hls_segment_filename"),
+            "/var/lib/vortex/hls/room/segment-%05d.ts"
+        );
+        assert_eq!(args.last().unwrap(), "/var/lib/vortex/hls/room/index.m3u8");
+    }
+}
@@ -0,0 +1,140 @@
+//! Simulcast and SVC layers forwarded to video consumers. mediasoup adapts them to the
+//! bandwidth estimate, up to the layers the client prefers, unless the client turned
+//! automatic layers off to keep what it chose
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// Priority of consumers with automatic layers off, so the bandwidth estimate is spent
+/// on their layers before any other consumer's. mediasoup has no way of switching the
+/// estimate off for a single consumer
+pub const PINNED_PRIORITY: u8 = 255;
+/// Spatial layer cap of consumers whose room doesn't cap them
+pub const UNCAPPED: u8 = u8::MAX;
+
+/// Layers now forwarded to a consumer, `None` while nothing is forwarded
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayersChange {
+    pub consumer_id: String,
+    pub layers: Option<ConsumerLayers>,
+    /// Whether the layers follow the bandwidth estimate
+    pub auto: bool,
+}
+
+pub type LayersReceiver = UnboundedReceiver<LayersChange>;
+
+/// Scalability mode of an encoding, `None` when it has a single layer
+pub fn scalability_mode(encoding: &RtpEncodingParameters) -> Option<String> {
+    let mode = &encoding.scalability_mode;
+    match (mode.spatial_layers().get(), mode.temporal_layers().get()) {
+        (1, 1) => None,
+        _ => Some(mode.to_string()),
+    }
+}
+
+pub fn spatial_layers(consumer: &Consumer) -> u8 {
+    consumer
+        .rtp_parameters()
+        .encodings
+        .first()
+        .map(|encoding| encoding.scalability_mode.spatial_layers().get())
+        .unwrap_or(1)
+}
+
+fn temporal_layers(consumer: &Consumer) -> u8 {
+    consumer
+        .rtp_parameters()
+        .encodings
+        .first()
+        .map(|encoding| encoding.scalability_mode.temporal_layers().get())
+        .unwrap_or(1)
+}
+
+/// Whether a producer with the given numbers of layers sends `layers`
+pub fn within(layers: &ConsumerLayers, spatial: u8, temporal: u8) -> bool {
+    layers.spatial_layer < spatial
+        && layers
+            .temporal_layer
+            .map_or(true, |temporal_layer| temporal_layer < temporal)
+}
+
+/// Whether the consumer's producer sends `layers`
+pub fn sent(consumer: &Consumer, layers: &ConsumerLayers) -> bool {
+    within(layers, spatial_layers(consumer), temporal_layers(consumer))
+}
+
+/// Best layers the consumer's producer sends up to the spatial layer `cap`, what
+/// automatic layers aim for
+pub fn highest(consumer: &Consumer, cap: u8) -> ConsumerLayers {
+    ConsumerLayers {
+        spatial_layer: (spatial_layers(consumer) - 1).min(cap),
+        temporal_layer: None,
+    }
+}
+
+/// `layers` brought down to the spatial layer `cap`, any temporal layer of it
+pub fn capped(layers: ConsumerLayers, cap: u8) -> ConsumerLayers {
+    if layers.spatial_layer <= cap {
+        return layers;
+    }
+    ConsumerLayers {
+        spatial_layer: cap,
+        temporal_layer: None,
+    }
+}
+
+/// Forward changes of the layers sent to the consumer along with its current mode.
+/// Changes after the connection closed are dropped
+pub fn watch(consumer: &Consumer, auto: Arc<AtomicBool>, sender: UnboundedSender<LayersChange>) {
+    let consumer_id = consumer.id().to_string();
+    consumer
+        .on_layers_change(move |layers| {
+            let change = LayersChange {
+                consumer_id: consumer_id.clone(),
+                layers: *layers,
+                auto: auto.load(Ordering::Relaxed),
+            };
+            sender.send(change).ok();
+        })
+        .detach();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_must_be_sent() {
+        let layers = |spatial_layer, temporal_layer| ConsumerLayers {
+            spatial_layer,
+            temporal_layer,
+        };
+
+        // Three spatial layers of three temporal layers each
+        assert!(within(&layers(0, None), 3, 3));
+        assert!(within(&layers(2, Some(2)), 3, 3));
+        assert!(!within(&layers(3, None), 3, 3));
+        assert!(!within(&layers(1, Some(3)), 3, 3));
+
+        // A single stream only has its base layer
+        assert!(within(&layers(0, Some(0)), 1, 1));
+        assert!(!within(&layers(1, None), 1, 1));
+    }
+
+    #[test]
+    fn layers_are_capped() {
+        let layers = |spatial_layer, temporal_layer| ConsumerLayers {
+            spatial_layer,
+            temporal_layer,
+        };
+
+        assert_eq!(capped(layers(2, Some(2)), 1), layers(1, None));
+        assert_eq!(capped(layers(1, Some(2)), 1), layers(1, Some(2)));
+        assert_eq!(capped(layers(2, Some(1)), UNCAPPED), layers(2, Some(1)));
+    }
+}
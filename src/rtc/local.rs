@@ -0,0 +1,89 @@
+use futures::channel::oneshot;
+use futures::Future;
+use once_cell::sync::Lazy;
+use std::fmt::{self, Display};
+use std::pin::Pin;
+use std::thread;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::LocalSet;
+
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// Thread driving the futures of mediasoup calls that aren't `Send`, all of them
+/// run concurrently on its `LocalSet` rather than occupying a thread each
+static RUNNER: Lazy<UnboundedSender<Job>> = Lazy::new(|| {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+    thread::Builder::new()
+        .name("rtc-local".to_string())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build the local RTC runtime");
+            let local = LocalSet::new();
+            local.block_on(&runtime, async move {
+                while let Some(job) = receiver.recv().await {
+                    tokio::task::spawn_local(job());
+                }
+            });
+        })
+        .expect("Failed to spawn the local RTC thread");
+
+    sender
+});
+
+/// The call never completed, because it panicked or the runner is gone
+#[derive(Debug)]
+pub struct LocalTaskError;
+
+impl Display for LocalTaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Local RTC task did not complete")
+    }
+}
+
+/// Run a future that isn't `Send` on the local RTC thread and wait for its output.
+/// The generic transport methods of mediasoup produce such futures, which can't
+/// be held across an await point in a connection task
+pub(crate) async fn run_unsend<F, Fut, T>(f: F) -> Result<T, LocalTaskError>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = T> + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        Box::pin(async move {
+            sender.send(f().await).ok();
+        })
+    });
+
+    RUNNER.send(job).map_err(|_| LocalTaskError)?;
+    // A panic drops the sender along with the rest of the task
+    receiver.await.map_err(|_| LocalTaskError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[tokio::test]
+    async fn unsend_calls_run_on_the_local_thread() {
+        let output = run_unsend(|| async {
+            let shared = Rc::new(20);
+            tokio::task::yield_now().await;
+            *shared + 1
+        })
+        .await;
+        assert_eq!(output.unwrap(), 21);
+
+        // A call that panics fails on its own, the thread goes on with the others
+        let output = run_unsend::<_, _, ()>(|| async { panic!("Call failed") }).await;
+        assert!(output.is_err());
+        assert_eq!(
+            run_unsend(|| async { "still running" }).await.unwrap(),
+            "still running"
+        );
+    }
+}
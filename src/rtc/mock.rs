@@ -0,0 +1,471 @@
+//! Stand-in for a connection's transports, driving the signaling flow in tests without a
+//! client negotiating ICE and DTLS. Media goes through a direct transport on the room's
+//! router, so producers and consumers are still real to the rest of the room
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use mediasoup::data_structures::TransportProtocol;
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use super::closed::{self, ClosedReceiver};
+use super::downlink::DownlinkReceiver;
+use super::dump::Dump;
+use super::layers::{self, LayersReceiver};
+use super::local::run_unsend;
+use super::opus::OpusOptions;
+use super::reaper::{Activity, ActivityReceiver, ConsumerReaper, PacketCounts};
+use super::score::{self, ScoreReceiver, ScoreUpdate};
+use super::stats::{StatsSource, StatsSubscription};
+use super::transport_state::TransportStateReceiver;
+use super::types::{
+    ConnectTransportData, ConsumerSummary, InitializationInput, InitializationInputMode,
+    TransportInitData,
+};
+use super::{
+    producer_opus, InitError, KeyFrameError, LayersError, MediaStats, ProduceError, RtcSession,
+    SRTP_CRYPTO_SUITE,
+};
+use crate::state::room::settings::{FastJoinSettings, OpusLimits, ProducerLimits};
+use crate::state::user::ProduceType;
+
+thread_local! {
+    /// Added to the media calls of sessions on this thread
+    static LATENCY: Cell<Duration> = Cell::new(Duration::from_secs(0));
+    /// Whether capping the incoming bitrate panics, for sessions on this thread
+    static BITRATE_PANICS: Cell<bool> = Cell::new(false);
+    /// Counting interval and idle time of consumers reaped on this thread, in place of
+    /// `reaping` in the configuration
+    static REAPING: Cell<Option<(Duration, Duration)>> = Cell::new(None);
+    /// How long sessions started on this thread queue resumes for a receiving transport
+    /// that never connects, `None` resumes right away
+    static RESUME_TIMEOUT: Cell<Option<Duration>> = Cell::new(None);
+}
+
+/// Make media calls of the sessions on this thread take `latency` longer, like a busy
+/// worker would
+pub fn set_latency(latency: Duration) {
+    LATENCY.with(|cell| cell.set(latency));
+}
+
+/// Make sessions on this thread panic when their incoming bitrate is capped, like a call
+/// choking on unexpected worker output. The cap is applied from the event loop itself,
+/// outside any command handler
+pub fn set_bitrate_panics(panics: bool) {
+    BITRATE_PANICS.with(|cell| cell.set(panics));
+}
+
+/// Reap the idle consumers of sessions started on this thread with these timings, rather
+/// than the configured ones meant for minutes of silence. `None` goes back to those
+pub fn set_reaping(timings: Option<(Duration, Duration)>) {
+    REAPING.with(|cell| cell.set(timings));
+}
+
+/// Make sessions started on this thread queue consumer resumes like a receiving
+/// transport that doesn't connect, closing them after `timeout`
+pub fn set_resume_timeout(timeout: Option<Duration>) {
+    RESUME_TIMEOUT.with(|cell| cell.set(timeout));
+}
+
+async fn latency() {
+    let latency = LATENCY.with(Cell::get);
+    if latency > Duration::from_secs(0) {
+        tokio::time::sleep(latency).await;
+    }
+}
+
+struct MockConsumer {
+    consumer: Consumer,
+    user_id: String,
+    produce_type: ProduceType,
+    auto_layers: bool,
+    activity: Activity,
+}
+
+pub struct MockSession {
+    rtp_capabilities: RtpCapabilities,
+    /// Initialized with `RecvWebRtc`, producing fails like it would on a real session
+    listener: bool,
+    transport: DirectTransport,
+    consumers: HashMap<String, MockConsumer>,
+    producer_counts: HashMap<ProduceType, usize>,
+    layer_caps: HashMap<ProduceType, u8>,
+    scores: Option<UnboundedSender<ScoreUpdate>>,
+    closed_sender: UnboundedSender<String>,
+    closed_consumers: Option<ClosedReceiver>,
+    stats: StatsSubscription,
+    resume_timeout: Option<Duration>,
+    pending_resumes: Vec<String>,
+    resume_deadline: Option<Instant>,
+}
+
+impl MockSession {
+    fn video_consumer(&mut self, id: &str) -> Result<&mut MockConsumer, LayersError> {
+        let entry = self
+            .consumers
+            .get_mut(id)
+            .ok_or(LayersError::ConsumerNotFound)?;
+        match entry.consumer.kind() {
+            MediaKind::Video => Ok(entry),
+            MediaKind::Audio => Err(LayersError::NotVideo),
+        }
+    }
+}
+
+#[async_trait]
+impl RtcSession for MockSession {
+    async fn initialize(
+        router: &Router,
+        init_data: InitializationInput,
+    ) -> Result<Self, InitError> {
+        let transport = router
+            .create_direct_transport(DirectTransportOptions::default())
+            .await?;
+        let (closed_sender, closed_consumers) = mpsc::unbounded_channel();
+
+        Ok(MockSession {
+            rtp_capabilities: init_data.rtp_capabilities,
+            listener: init_data.mode == InitializationInputMode::RecvWebRtc,
+            transport,
+            consumers: HashMap::new(),
+            producer_counts: HashMap::new(),
+            layer_caps: HashMap::new(),
+            scores: None,
+            closed_sender,
+            closed_consumers: Some(closed_consumers),
+            stats: StatsSubscription::default(),
+            resume_timeout: RESUME_TIMEOUT.with(Cell::get),
+            pending_resumes: Vec::new(),
+            resume_deadline: None,
+        })
+    }
+
+    /// Looks like a plain RTP transport to the client, which has nothing to connect to
+    fn get_init_data(&self) -> TransportInitData {
+        TransportInitData::CombinedRtp {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            protocol: TransportProtocol::Udp,
+            id: self.transport.id(),
+            srtp_crypto_suite: SRTP_CRYPTO_SUITE,
+        }
+    }
+
+    fn media_stats(&self) -> Option<MediaStats> {
+        None
+    }
+
+    fn take_transport_states(&mut self) -> Option<TransportStateReceiver> {
+        None
+    }
+
+    fn take_downlink_estimates(&mut self) -> Option<DownlinkReceiver> {
+        None
+    }
+
+    fn take_layer_changes(&mut self) -> Option<LayersReceiver> {
+        None
+    }
+
+    fn take_closed_consumers(&mut self) -> Option<ClosedReceiver> {
+        self.closed_consumers.take()
+    }
+
+    fn enable_scores(&mut self) -> ScoreReceiver {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.scores = Some(sender);
+        receiver
+    }
+
+    fn stats_subscription(&mut self) -> &mut StatsSubscription {
+        &mut self.stats
+    }
+
+    fn stats_source(&self) -> Option<StatsSource> {
+        None
+    }
+
+    async fn dump_transports(&self) -> Result<Dump, ()> {
+        let transport = self.transport.clone();
+        let dump = run_unsend(move || async move { transport.dump().await })
+            .await
+            .map_err(|_| ())?
+            .map_err(|_| ())?;
+        Dump::new(&dump)
+    }
+
+    /// Any parameters are accepted, as long as they are for the one transport
+    async fn connect_transport(&mut self, connect_data: &ConnectTransportData) -> Result<(), ()> {
+        latency().await;
+        match connect_data.id == self.transport.id() {
+            true => Ok(()),
+            false => Err(()),
+        }
+    }
+
+    async fn set_max_incoming_bitrate(&self, _bitrate: u32) -> Result<(), ()> {
+        if BITRATE_PANICS.with(Cell::get) {
+            panic!("Unexpected output capping the incoming bitrate");
+        }
+        Ok(())
+    }
+
+    fn can_produce(&self, produce_type: ProduceType, limits: &ProducerLimits) -> bool {
+        let count = self
+            .producer_counts
+            .get(&produce_type)
+            .copied()
+            .unwrap_or(0);
+        count < limits.get(produce_type)
+    }
+
+    async fn start_produce(
+        &mut self,
+        produce_type: ProduceType,
+        mut rtp_parameters: RtpParameters,
+        opus: Option<OpusOptions>,
+        opus_limits: &OpusLimits,
+    ) -> Result<(Producer, Option<OpusOptions>), ProduceError> {
+        latency().await;
+        let opus = producer_opus(produce_type, &mut rtp_parameters, opus, opus_limits)?;
+        if self.listener {
+            return Err(ProduceError::NoSendTransport);
+        }
+        let transport = self.transport.clone();
+        let options = ProducerOptions::new(produce_type.into_kind(), rtp_parameters);
+        let producer = run_unsend(move || async move { transport.produce(options).await })
+            .await
+            .map_err(|_| ProduceError::Failed)?
+            .map_err(|_| ProduceError::Failed)?;
+
+        *self.producer_counts.entry(produce_type).or_insert(0) += 1;
+        if let Some(scores) = &self.scores {
+            score::watch_producer(&producer, produce_type, scores.clone());
+        }
+        Ok((producer, opus))
+    }
+
+    fn release_producer(&mut self, produce_type: ProduceType) {
+        if let Some(count) = self.producer_counts.get_mut(&produce_type) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn take_fast_join_slot(&mut self, _settings: &FastJoinSettings, _kind: MediaKind) -> bool {
+        false
+    }
+
+    async fn start_consume(
+        &mut self,
+        router: &Router,
+        producer_id: ProducerId,
+        user_id: &str,
+        produce_type: ProduceType,
+        _fast_join: Option<&FastJoinSettings>,
+    ) -> Result<Consumer, ()> {
+        latency().await;
+        if !router.can_consume(&producer_id, &self.rtp_capabilities) {
+            return Err(());
+        }
+
+        let transport = self.transport.clone();
+        let options = ConsumerOptions::new(producer_id, self.rtp_capabilities.clone());
+        let consumer = run_unsend(move || async move { transport.consume(options).await })
+            .await
+            .map_err(|_| ())?
+            .map_err(|_| ())?;
+
+        let entry = MockConsumer {
+            consumer: consumer.clone(),
+            user_id: user_id.to_string(),
+            produce_type,
+            auto_layers: true,
+            activity: Activity::new(Instant::now()),
+        };
+        self.consumers.insert(consumer.id().to_string(), entry);
+        if let Some(scores) = &self.scores {
+            score::watch_consumer(&consumer, scores.clone());
+        }
+        closed::watch(&consumer, self.closed_sender.clone());
+        Ok(consumer)
+    }
+
+    fn get_consumer(&self, id: &str) -> Option<&Consumer> {
+        self.consumers.get(id).map(|entry| &entry.consumer)
+    }
+
+    fn consumer_auto_layers(&self, id: &str) -> Option<bool> {
+        self.consumers.get(id).map(|entry| entry.auto_layers)
+    }
+
+    async fn set_consumer_layers(
+        &mut self,
+        id: &str,
+        layers: ConsumerLayers,
+    ) -> Result<(), LayersError> {
+        let produce_type = self.video_consumer(id)?.produce_type;
+        let cap = self.layer_caps.get(&produce_type).copied();
+        let layers = layers::capped(layers, cap.unwrap_or(layers::UNCAPPED));
+        self.video_consumer(id)?
+            .consumer
+            .set_preferred_layers(layers)
+            .await
+            .map_err(|_| LayersError::Failed)
+    }
+
+    /// Only remembered, nothing adapts the layers of a direct transport
+    async fn set_consumer_auto_layers(
+        &mut self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<(), LayersError> {
+        self.video_consumer(id)?.auto_layers = enabled;
+        Ok(())
+    }
+
+    /// Only applied to the layers chosen later, a direct transport forwards no others
+    async fn cap_consumer_layers(
+        &mut self,
+        produce_type: ProduceType,
+        max_spatial_layer: Option<u8>,
+    ) {
+        match max_spatial_layer {
+            Some(cap) => self.layer_caps.insert(produce_type, cap),
+            None => self.layer_caps.remove(&produce_type),
+        };
+    }
+
+    async fn request_key_frame(&mut self, id: &str) -> Result<(), KeyFrameError> {
+        latency().await;
+        let entry = self
+            .consumers
+            .get(id)
+            .ok_or(KeyFrameError::ConsumerNotFound)?;
+        if entry.consumer.kind() != MediaKind::Video {
+            return Err(KeyFrameError::NotVideo);
+        }
+
+        entry
+            .consumer
+            .request_key_frame()
+            .await
+            .map_err(|_| KeyFrameError::Failed)
+    }
+
+    async fn set_consumer_priority(&self, id: &str, priority: u8) -> Option<Result<(), ()>> {
+        let consumer = self.get_consumer(id)?;
+        Some(consumer.set_priority(priority).await.map_err(|_| ()))
+    }
+
+    fn remove_consumers_of(
+        &mut self,
+        user_id: &str,
+        produce_type: Option<ProduceType>,
+    ) -> Vec<Consumer> {
+        let ids: Vec<String> = self
+            .consumers
+            .iter()
+            .filter(|(_, entry)| {
+                entry.user_id == user_id
+                    && produce_type.map_or(true, |produce_type| entry.produce_type == produce_type)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| self.consumers.remove(id))
+            .map(|entry| entry.consumer)
+            .collect()
+    }
+
+    fn stop_consume(&mut self, id: &str) -> bool {
+        self.pending_resumes.retain(|pending| pending != id);
+        self.consumers.remove(id).is_some()
+    }
+
+    fn list_consumers(&self) -> Vec<ConsumerSummary> {
+        self.consumers
+            .values()
+            .map(|entry| ConsumerSummary {
+                id: entry.consumer.id().to_string(),
+                producer_id: entry.consumer.producer_id().to_string(),
+                user_id: entry.user_id.clone(),
+                produce_type: entry.produce_type,
+                kind: entry.consumer.kind(),
+                paused: entry.consumer.paused(),
+                producer_paused: entry.consumer.producer_paused(),
+            })
+            .collect()
+    }
+
+    /// The direct transport has no handshake, consumers resume right away unless the
+    /// test set a resume timeout
+    fn defer_resume(&mut self, id: &str) -> bool {
+        let timeout = match self.resume_timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        if self.pending_resumes.is_empty() {
+            self.resume_deadline = Some(Instant::now() + timeout);
+        }
+        if !self.pending_resumes.iter().any(|pending| pending == id) {
+            self.pending_resumes.push(id.to_string());
+        }
+        true
+    }
+
+    async fn transport_connected(&mut self, _transport_id: TransportId) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn resume_deadline(&self) -> Option<Instant> {
+        self.resume_deadline
+    }
+
+    fn expire_resumes(&mut self) -> Vec<Consumer> {
+        match self.resume_deadline {
+            Some(deadline) if deadline <= Instant::now() => (),
+            _ => return Vec::new(),
+        }
+        self.resume_deadline = None;
+        std::mem::take(&mut self.pending_resumes)
+            .into_iter()
+            .filter_map(|id| self.consumers.remove(&id))
+            .map(|entry| entry.consumer)
+            .collect()
+    }
+
+    fn consumer_reaper() -> Option<(ConsumerReaper, ActivityReceiver)> {
+        match REAPING.with(Cell::get) {
+            Some((interval, inactive_after)) => Some(ConsumerReaper::new(interval, inactive_after)),
+            None => ConsumerReaper::from_config(),
+        }
+    }
+
+    fn counted_consumers(&self) -> Vec<Consumer> {
+        self.consumers
+            .values()
+            .map(|entry| entry.consumer.clone())
+            .collect()
+    }
+
+    fn reap_inactive(&mut self, counts: PacketCounts, inactive_after: Duration) -> Vec<Consumer> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+        for (id, packets) in counts {
+            let entry = match self.consumers.get_mut(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let paused = entry.consumer.paused() || entry.consumer.producer_paused();
+            if entry.activity.record(packets, paused, now, inactive_after) {
+                reaped.extend(self.consumers.remove(&id).map(|entry| entry.consumer));
+            }
+        }
+        reaped
+    }
+}
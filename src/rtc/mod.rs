@@ -1,21 +1,71 @@
 use std::collections::HashMap;
 use std::num::{NonZeroU32, NonZeroU8};
+use std::ops::AddAssign;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
-use crate::util::variables::{DISABLE_RTP, RTC_IPS};
+use crate::state::room::retransmissions::RetransmissionCounts;
+use crate::state::room::settings::{FastJoinSettings, OpusLimits, ProducerLimits};
 use crate::state::user::ProduceType;
+use crate::util::config::{CodecConfig, RtcConfig, CONFIG};
 use futures::join;
+use mediasoup::consumer::ConsumerStats;
 use mediasoup::prelude::*;
+use mediasoup::worker::RequestError;
+use parking_lot::Mutex;
+use serde::Serialize;
 
+pub mod audio_level;
+pub mod bitrate;
+pub mod broadcast;
+pub mod closed;
+pub mod downlink;
+pub mod dump;
+pub mod egress;
+pub mod fast_join;
+pub mod hls;
+pub mod layers;
+mod local;
+#[cfg(test)]
+pub mod mock;
+pub mod opus;
+pub mod playback;
+pub mod pool;
+pub mod probe;
+pub mod quality;
+pub mod reaper;
+pub mod recording;
+pub mod score;
+pub mod self_test;
+pub mod session;
+pub mod stats;
+pub mod transport_state;
+pub mod turn;
 pub mod types;
 pub mod worker;
 
+pub use session::RtcSession;
 pub use worker::get_worker_pool;
 
+use local::run_unsend;
+
 pub const SRTP_CRYPTO_SUITE: SrtpCryptoSuite = SrtpCryptoSuite::AesCm128HmacSha180;
 
+use closed::ClosedReceiver;
+use downlink::DownlinkReceiver;
+use layers::{LayersChange, LayersReceiver};
+use opus::{OpusError, OpusOptions};
+use reaper::{Activity, PacketCounts};
+use score::{ScoreReceiver, ScoreUpdate};
+use stats::{StatsSource, StatsSubscription};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use transport_state::TransportStateReceiver;
 use types::{
-    ConnectTransportData, ConnectTransportParams, InitializationInput, InitializationInputMode,
-    TransportInitData, WebRtcTransportInitData,
+    ConnectTransportData, ConnectTransportParams, ConsumerSummary, IcePolicy, InitializationInput,
+    InitializationInputMode, TransportInitData, WebRtcTransportInitData,
 };
 
 pub fn create_opus_codec(channels: u8) -> RtpCodecCapability {
@@ -29,69 +79,395 @@ pub fn create_opus_codec(channels: u8) -> RtpCodecCapability {
     }
 }
 
+pub fn create_vp8_codec() -> RtpCodecCapability {
+    RtpCodecCapability::Video {
+        mime_type: MimeTypeVideo::Vp8,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(90000).unwrap(),
+        parameters: RtpCodecParametersParameters::default(),
+        rtcp_feedback: Vec::new(),
+    }
+}
+
+pub fn create_vp9_codec(profile_id: u8) -> RtpCodecCapability {
+    let mut parameters = RtpCodecParametersParameters::default();
+    parameters.insert("profile-id", u32::from(profile_id));
+
+    RtpCodecCapability::Video {
+        mime_type: MimeTypeVideo::Vp9,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(90000).unwrap(),
+        parameters,
+        rtcp_feedback: Vec::new(),
+    }
+}
+
+pub fn create_av1_codec() -> RtpCodecCapability {
+    RtpCodecCapability::Video {
+        mime_type: MimeTypeVideo::AV1,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(90000).unwrap(),
+        parameters: RtpCodecParametersParameters::default(),
+        rtcp_feedback: Vec::new(),
+    }
+}
+
+pub fn create_h264_codec(profile_level_id: &str, packetization_mode: u8) -> RtpCodecCapability {
+    let mut parameters = RtpCodecParametersParameters::default();
+    parameters.insert("level-asymmetry-allowed", 1_u32);
+    parameters.insert("packetization-mode", u32::from(packetization_mode));
+    parameters.insert("profile-level-id", profile_level_id.to_string());
+
+    RtpCodecCapability::Video {
+        mime_type: MimeTypeVideo::H264,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(90000).unwrap(),
+        parameters,
+        rtcp_feedback: Vec::new(),
+    }
+}
+
+/// Codecs offered by a room's router, which must have been validated
+pub fn media_codecs(codecs: &[CodecConfig]) -> Vec<RtpCodecCapability> {
+    codecs
+        .iter()
+        .map(|codec| match codec {
+            CodecConfig::Opus { channels } => create_opus_codec(*channels),
+            CodecConfig::Vp8 => create_vp8_codec(),
+            CodecConfig::Vp9 { profile_id } => create_vp9_codec(*profile_id),
+            CodecConfig::Av1 => create_av1_codec(),
+            CodecConfig::H264 {
+                profile_level_id,
+                packetization_mode,
+            } => create_h264_codec(profile_level_id, *packetization_mode),
+        })
+        .collect()
+}
+
+/// Least time between keyframes a client may request for one consumer
+const KEY_FRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consumer along with the producer it receives media from
+struct ConsumerEntry {
+    consumer: Consumer,
+    user_id: String,
+    produce_type: ProduceType,
+    last_key_frame_request: Option<Instant>,
+    /// Whether mediasoup picks the layers from the bandwidth estimate
+    auto_layers: Arc<AtomicBool>,
+    /// Priority to restore once automatic layers are turned back on
+    auto_priority: Option<u8>,
+    /// Highest spatial layer the consumer may be forwarded, shared by the consumers of
+    /// the same produce type
+    layer_cap: Arc<AtomicU8>,
+    /// Packets it was last seen sending, see `reap_inactive`
+    activity: Activity,
+}
+
+pub enum ProduceError {
+    /// Opus options that are out of range or given for something other than Opus
+    Opus(OpusError),
+    /// The session was initialized to only receive
+    NoSendTransport,
+    Failed,
+}
+
+pub enum KeyFrameError {
+    ConsumerNotFound,
+    /// Keyframes only exist for video
+    NotVideo,
+    Failed,
+}
+
+/// Why a connection's transports couldn't be created, see `RtcState::initialize`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The worker ran out of ports or memory, another instance may have room
+    Exhausted,
+    /// The worker didn't answer in time or its channel closed, trying again may work
+    Transient,
+    /// Refused by the configuration or a bug, trying again won't help
+    Failed,
+}
+
+impl InitError {
+    /// Label of the failure in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InitError::Exhausted => "exhausted",
+            InitError::Transient => "transient",
+            InitError::Failed => "failed",
+        }
+    }
+}
+
+/// Parts of the worker's error messages telling it ran out of something
+const EXHAUSTION_REASONS: [&str; 4] = [
+    "no more available ports",
+    "bad_alloc",
+    "cannot allocate memory",
+    "too many open files",
+];
+
+impl From<RequestError> for InitError {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::ChannelClosed | RequestError::TimedOut => InitError::Transient,
+            RequestError::Response { reason } => {
+                let reason = reason.to_lowercase();
+                match EXHAUSTION_REASONS
+                    .iter()
+                    .any(|exhausted| reason.contains(exhausted))
+                {
+                    true => InitError::Exhausted,
+                    false => InitError::Failed,
+                }
+            }
+            _ => InitError::Failed,
+        }
+    }
+}
+
+pub enum LayersError {
+    ConsumerNotFound,
+    /// Layers only exist for video
+    NotVideo,
+    /// The producer doesn't send the requested layers
+    Unavailable,
+    Failed,
+}
+
+/// Bitrates of transports in bits per second, incoming being what the clients send
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bitrates {
+    pub incoming: u64,
+    pub outgoing: u64,
+}
+
+impl AddAssign for Bitrates {
+    fn add_assign(&mut self, other: Bitrates) {
+        self.incoming += other.incoming;
+        self.outgoing += other.outgoing;
+    }
+}
+
+/// View of a connection's media, sampled for room stats without going through
+/// the connection itself
+#[derive(Clone)]
+pub struct MediaStats {
+    transport_mode: TransportMode,
+    consumers: Arc<Mutex<Vec<Consumer>>>,
+}
+
+impl MediaStats {
+    pub fn consumers(&self) -> usize {
+        self.consumers.lock().len()
+    }
+
+    /// Ask the worker for the current bitrates of the connection's transports
+    pub async fn bitrates(&self) -> Result<Bitrates, ()> {
+        let transport_mode = self.transport_mode.clone();
+        run_unsend(move || async move { transport_mode.bitrates().await })
+            .await
+            .map_err(|_| ())?
+    }
+
+    /// Ask the worker for the NACK, PLI and retransmission counts of the given producers
+    /// of the user and of the connection's consumers, by producer or consumer ID
+    pub async fn retransmissions(
+        &self,
+        producers: Vec<Producer>,
+    ) -> Result<Vec<(String, RetransmissionCounts)>, ()> {
+        let consumers = self.consumers.lock().clone();
+        run_unsend(move || async move {
+            let mut counts = Vec::with_capacity(producers.len() + consumers.len());
+            for producer in producers {
+                let mut producer_counts = RetransmissionCounts::default();
+                for stream in producer.get_stats().await.map_err(|_| ())? {
+                    producer_counts += RetransmissionCounts::from_stream(
+                        u64::from(stream.nack_count),
+                        u64::from(stream.pli_count),
+                        u64::from(stream.packets_retransmitted),
+                        u64::from(stream.packet_count),
+                        u64::from(stream.byte_count),
+                    );
+                }
+                counts.push((producer.id().to_string(), producer_counts));
+            }
+            // Closed since they were taken, they no longer count
+            for consumer in consumers {
+                let stat = match consumer.get_stats().await {
+                    Ok(ConsumerStats::JustConsumer((stat,)))
+                    | Ok(ConsumerStats::WithProducer((stat, _))) => stat,
+                    Err(_) => continue,
+                };
+                let consumer_counts = RetransmissionCounts::from_stream(
+                    u64::from(stat.nack_count),
+                    u64::from(stat.pli_count),
+                    u64::from(stat.packets_retransmitted),
+                    u64::from(stat.packet_count),
+                    u64::from(stat.byte_count),
+                );
+                counts.push((consumer.id().to_string(), consumer_counts));
+            }
+            Ok(counts)
+        })
+        .await
+        .map_err(|_| ())?
+    }
+}
+
 pub struct RtcState {
     rtp_capabilities: RtpCapabilities,
     transport_mode: TransportMode,
-    consumers: HashMap<String, Consumer>,
+    consumers: HashMap<String, ConsumerEntry>,
+    /// Consumers of `consumers`, shared with `MediaStats`
+    shared_consumers: Arc<Mutex<Vec<Consumer>>>,
+    producer_counts: HashMap<ProduceType, usize>,
+    /// Spatial layer caps of video consumers by produce type, see `cap_consumer_layers`
+    layer_caps: HashMap<ProduceType, Arc<AtomicU8>>,
+    /// Whether audio consumers start paused, see `InitializationInput::pause_consumers`
+    pause_consumers: bool,
+
+    /// When the transport receiving media was connected
+    connected_at: Option<Instant>,
+    /// Whether the receiving transport's DTLS connected. Plain RTP has no handshake
+    recv_connected: bool,
+    /// Consumers resumed before then in the order they were, see `defer_resume`
+    pending_resumes: Vec<String>,
+    /// When those still pending are closed, set while there are any
+    resume_deadline: Option<Instant>,
+    fast_join_consumers: usize,
+    first_frame_recorded: Arc<AtomicBool>,
+
+    /// ICE and DTLS state changes of the WebRTC transports, until taken by the connection
+    transport_states: Option<TransportStateReceiver>,
+    /// Bandwidth estimates of the receiving transport if the client asked for them, until
+    /// taken by the connection
+    downlink_estimates: Option<DownlinkReceiver>,
+    /// Where the scores of new producers and consumers go, if the client asked for them
+    scores: Option<UnboundedSender<ScoreUpdate>>,
+    /// Layer changes of video consumers, the receiver until taken by the connection
+    layer_sender: UnboundedSender<LayersChange>,
+    layer_changes: Option<LayersReceiver>,
+    /// Consumers closed along with their producer, the receiver until taken by the connection
+    closed_sender: UnboundedSender<String>,
+    closed_consumers: Option<ClosedReceiver>,
+    stats: StatsSubscription,
 }
 
 impl RtcState {
-    pub async fn initialize(router: &Router, init_data: InitializationInput) -> Result<Self, ()> {
-        let mut webrtc_options = WebRtcTransportOptions::new(RTC_IPS.clone());
-        webrtc_options.enable_udp = true;
-        webrtc_options.enable_tcp = true;
-        webrtc_options.prefer_udp = true;
+    #[tracing::instrument(name = "rtc.initialize", skip_all, fields(mode = ?init_data.mode))]
+    pub async fn initialize(
+        router: &Router,
+        init_data: InitializationInput,
+    ) -> Result<Self, InitError> {
+        let rtc_config = &CONFIG.rtc;
+        let webrtc_options = webrtc_options(rtc_config, init_data.ice_policy);
+        // Pooled transports are created with the default ICE options
+        let pooled = init_data.ice_policy.is_none();
 
+        // A transport created or claimed here is closed as it's dropped, should the other
+        // one of a pair fail
         let transport_mode = match init_data.mode {
             InitializationInputMode::SplitWebRtc => {
                 let (send, recv) = join!(
-                    router.create_webrtc_transport(webrtc_options.clone()),
-                    router.create_webrtc_transport(webrtc_options)
+                    webrtc_transport(router, webrtc_options.clone(), pooled),
+                    webrtc_transport(router, webrtc_options, pooled)
                 );
-                TransportMode::SplitWebRtc(send.map_err(|_| ())?, recv.map_err(|_| ())?)
+                TransportMode::SplitWebRtc(send?, recv?)
             }
             InitializationInputMode::CombinedWebRtc => {
-                let transport = router.create_webrtc_transport(webrtc_options).await;
-                TransportMode::CombinedWebRtc(transport.map_err(|_| ())?)
+                let transport = webrtc_transport(router, webrtc_options, pooled).await;
+                TransportMode::CombinedWebRtc(transport?)
+            }
+            InitializationInputMode::RecvWebRtc => {
+                let transport = webrtc_transport(router, webrtc_options, pooled).await;
+                TransportMode::RecvWebRtc(transport?)
             }
             InitializationInputMode::CombinedRtp => {
-                // TODO: make it return an error struct instead of ()
-                if *DISABLE_RTP {
-                    return Err(());
+                if rtc_config.disable_rtp {
+                    return Err(InitError::Failed);
                 }
 
-                let mut options = PlainTransportOptions::new(RTC_IPS[0]);
+                let mut options = PlainTransportOptions::new(rtc_config.transport_listen_ips()[0]);
                 options.rtcp_mux = true;
                 options.comedia = true;
                 options.enable_srtp = true;
                 options.srtp_crypto_suite = SRTP_CRYPTO_SUITE;
                 let transport = router.create_plain_transport(options).await;
-                TransportMode::CombinedRtp(transport.map_err(|_| ())?)
+                TransportMode::CombinedRtp(transport?)
+            }
+        };
+
+        let (state_sender, transport_states) = mpsc::unbounded_channel();
+        match &transport_mode {
+            TransportMode::SplitWebRtc(send, recv) => {
+                transport_state::watch(send, state_sender.clone());
+                transport_state::watch(recv, state_sender);
+            }
+            TransportMode::CombinedWebRtc(transport) | TransportMode::RecvWebRtc(transport) => {
+                transport_state::watch(transport, state_sender)
             }
+            TransportMode::CombinedRtp(_) => (),
+        }
+        let downlink_estimates = match (&transport_mode, init_data.downlink_estimates) {
+            (TransportMode::SplitWebRtc(_, recv), true)
+            | (TransportMode::CombinedWebRtc(recv), true)
+            | (TransportMode::RecvWebRtc(recv), true) => {
+                let (downlink_sender, downlink_estimates) = mpsc::unbounded_channel();
+                downlink::watch(recv, downlink_sender).await;
+                Some(downlink_estimates)
+            }
+            _ => None,
         };
+        let (layer_sender, layer_changes) = mpsc::unbounded_channel();
+        let (closed_sender, closed_consumers) = mpsc::unbounded_channel();
+        let recv_connected = matches!(transport_mode, TransportMode::CombinedRtp(_));
 
         Ok(RtcState {
             rtp_capabilities: init_data.rtp_capabilities,
             transport_mode,
             consumers: HashMap::new(),
+            shared_consumers: Arc::new(Mutex::new(Vec::new())),
+            producer_counts: HashMap::new(),
+            layer_caps: HashMap::new(),
+            pause_consumers: init_data.pause_consumers,
+
+            connected_at: None,
+            recv_connected,
+            pending_resumes: Vec::new(),
+            resume_deadline: None,
+            fast_join_consumers: 0,
+            first_frame_recorded: Arc::new(AtomicBool::new(false)),
+
+            transport_states: Some(transport_states),
+            downlink_estimates,
+            scores: None,
+            layer_sender,
+            layer_changes: Some(layer_changes),
+            closed_sender,
+            closed_consumers: Some(closed_consumers),
+            stats: StatsSubscription::default(),
         })
     }
 
     pub fn get_init_data(&self) -> TransportInitData {
         match &self.transport_mode {
             TransportMode::SplitWebRtc(send, recv) => TransportInitData::SplitWebRtc {
-                send_transport: RtcState::get_webrtc_init_data(&send),
-                recv_transport: RtcState::get_webrtc_init_data(&recv),
+                send_transport: RtcState::get_webrtc_init_data(send),
+                recv_transport: RtcState::get_webrtc_init_data(recv),
             },
             TransportMode::CombinedWebRtc(transport) => TransportInitData::CombinedWebRtc {
-                transport: RtcState::get_webrtc_init_data(&transport),
+                transport: RtcState::get_webrtc_init_data(transport),
+            },
+            TransportMode::RecvWebRtc(recv) => TransportInitData::RecvWebRtc {
+                recv_transport: RtcState::get_webrtc_init_data(recv),
             },
             TransportMode::CombinedRtp(transport) => {
                 let tuple = transport.tuple();
-                let ip = RTC_IPS[0];
-                let ip = ip.announced_ip.unwrap_or(ip.ip);
                 TransportInitData::CombinedRtp {
-                    ip,
+                    ip: CONFIG.rtc.announced_ip(),
                     port: tuple.local_port(),
                     protocol: tuple.protocol(),
                     id: transport.id(),
@@ -115,14 +491,84 @@ impl RtcState {
         self.transport_mode.combined()
     }
 
+    /// State changes of the connection's WebRTC transports, only handed out once.
+    /// Plain RTP transports report none
+    pub fn take_transport_states(&mut self) -> Option<TransportStateReceiver> {
+        self.transport_states.take()
+    }
+
+    /// Bandwidth estimates towards the client, only handed out once. `None` unless the
+    /// client asked for them on a WebRTC receiving transport
+    pub fn take_downlink_estimates(&mut self) -> Option<DownlinkReceiver> {
+        self.downlink_estimates.take()
+    }
+
+    /// Layer changes of the connection's video consumers, only handed out once
+    pub fn take_layer_changes(&mut self) -> Option<LayersReceiver> {
+        self.layer_changes.take()
+    }
+
+    /// Consumers of the connection closed along with their producer, only handed out once
+    pub fn take_closed_consumers(&mut self) -> Option<ClosedReceiver> {
+        self.closed_consumers.take()
+    }
+
+    /// Report the scores of producers and consumers created from now on
+    pub fn enable_scores(&mut self) -> ScoreReceiver {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.scores = Some(sender);
+        receiver
+    }
+
+    /// mediasoup's dump of the connection's transports
+    pub async fn dump_transports(&self) -> Result<dump::Dump, ()> {
+        let (webrtc, plain) = match &self.transport_mode {
+            TransportMode::SplitWebRtc(send, recv) => (vec![send.clone(), recv.clone()], vec![]),
+            TransportMode::CombinedWebRtc(transport) | TransportMode::RecvWebRtc(transport) => {
+                (vec![transport.clone()], vec![])
+            }
+            TransportMode::CombinedRtp(transport) => (vec![], vec![transport.clone()]),
+        };
+        dump::transports(webrtc, plain).await
+    }
+
+    /// Whether and when stats are pushed to the client
+    pub fn stats_subscription(&mut self) -> &mut StatsSubscription {
+        &mut self.stats
+    }
+
+    /// What the pushed stats are gathered from, producers belong to the user
+    pub fn stats_source(&self) -> StatsSource {
+        StatsSource {
+            transport_mode: self.transport_mode.clone(),
+            consumers: self
+                .consumers
+                .values()
+                .map(|entry| entry.consumer.clone())
+                .collect(),
+        }
+    }
+
+    pub fn media_stats(&self) -> MediaStats {
+        MediaStats {
+            transport_mode: self.transport_mode.clone(),
+            consumers: self.shared_consumers.clone(),
+        }
+    }
+
+    /// Hand the consumers to `MediaStats` again, after they changed
+    fn share_consumers(&self) {
+        let consumers = self.consumers.values().map(|entry| entry.consumer.clone());
+        *self.shared_consumers.lock() = consumers.collect();
+    }
+
     pub fn get_webrtc_transport_by_id(&self, id: TransportId) -> Option<&WebRtcTransport> {
         match self.transport_mode {
             TransportMode::SplitWebRtc(ref send, ref recv) => Some(send)
                 .filter(|t| t.id() == id)
                 .or_else(|| Some(recv).filter(|t| t.id() == id)),
-            TransportMode::CombinedWebRtc(ref transport) => {
-                Some(transport).filter(|t| t.id() == id)
-            }
+            TransportMode::CombinedWebRtc(ref transport)
+            | TransportMode::RecvWebRtc(ref transport) => Some(transport).filter(|t| t.id() == id),
             _ => None,
         }
     }
@@ -134,13 +580,30 @@ impl RtcState {
         }
     }
 
-    pub async fn connect_transport(&self, connect_data: &ConnectTransportData) -> Result<(), ()> {
+    #[tracing::instrument(
+        name = "rtc.connect_transport",
+        skip_all,
+        fields(transport_id = %connect_data.id)
+    )]
+    pub async fn connect_transport(
+        &mut self,
+        connect_data: &ConnectTransportData,
+    ) -> Result<(), ()> {
+        self.connect_transport_inner(connect_data).await?;
+        if self.connected_at.is_none() && self.transport_mode.recv().id() == connect_data.id {
+            self.connected_at = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    async fn connect_transport_inner(&self, connect_data: &ConnectTransportData) -> Result<(), ()> {
         match self.transport_mode {
-            TransportMode::SplitWebRtc(..) | TransportMode::CombinedWebRtc(..) => {
+            TransportMode::SplitWebRtc(..)
+            | TransportMode::CombinedWebRtc(..)
+            | TransportMode::RecvWebRtc(..) => {
                 if let ConnectTransportParams::WebRtc { dtls_parameters } = &connect_data.params {
-                    let transport = self
-                        .get_webrtc_transport_by_id(connect_data.id)
-                        .ok_or_else(|| ())?;
+                    let transport = self.get_webrtc_transport_by_id(connect_data.id).ok_or(())?;
 
                     transport
                         .connect(WebRtcTransportRemoteParameters {
@@ -156,9 +619,7 @@ impl RtcState {
             }
             TransportMode::CombinedRtp(..) => {
                 if let ConnectTransportParams::Rtp { srtp_parameters } = &connect_data.params {
-                    let transport = self
-                        .get_rtp_transport_by_id(connect_data.id)
-                        .ok_or_else(|| ())?;
+                    let transport = self.get_rtp_transport_by_id(connect_data.id).ok_or(())?;
                     transport
                         .connect(PlainTransportRemoteParameters {
                             ip: None,
@@ -177,16 +638,551 @@ impl RtcState {
         }
     }
 
-    pub async fn start_produce(&self, produce_type: ProduceType, rtp_parameters: RtpParameters) -> Result<Producer, ProduceError> {
-        let transport = self.transport_mode.send();
-        transport.produce(ProducerOptions::new(produce_type.into_kind(), rtp_parameters)).await
+    /// Cap the bitrate the client may send on its sending transport, 0 removes the cap
+    pub async fn set_max_incoming_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+        let transport_mode = self.transport_mode.clone();
+        run_unsend(move || async move {
+            match transport_mode.send() {
+                Some(send) => send.set_max_incoming_bitrate(bitrate).await.map_err(|_| ()),
+                // Nothing comes in on a session without a send transport
+                None => Ok(()),
+            }
+        })
+        .await
+        .map_err(|_| ())?
+    }
+
+    /// Whether another producer of this type stays within the limits
+    pub fn can_produce(&self, produce_type: ProduceType, limits: &ProducerLimits) -> bool {
+        let count = self
+            .producer_counts
+            .get(&produce_type)
+            .copied()
+            .unwrap_or(0);
+        count < limits.get(produce_type)
+    }
+
+    /// Create a producer on the sending transport. Audio may carry Opus options, which
+    /// are clamped to the room's limits and returned as they were applied
+    #[tracing::instrument(name = "rtc.produce", skip_all, fields(produce_type = ?produce_type))]
+    pub async fn start_produce(
+        &mut self,
+        produce_type: ProduceType,
+        mut rtp_parameters: RtpParameters,
+        opus: Option<OpusOptions>,
+        opus_limits: &OpusLimits,
+    ) -> Result<(Producer, Option<OpusOptions>), ProduceError> {
+        let opus = producer_opus(produce_type, &mut rtp_parameters, opus, opus_limits)?;
+        let transport_mode = self.transport_mode.clone();
+        let options = ProducerOptions::new(produce_type.into_kind(), rtp_parameters);
+        let producer = run_unsend(move || async move {
+            let send = transport_mode.send().ok_or(ProduceError::NoSendTransport)?;
+            send.produce(options)
+                .await
+                .map_err(|_| ProduceError::Failed)
+        })
+        .await
+        .map_err(|_| ProduceError::Failed)??;
+
+        *self.producer_counts.entry(produce_type).or_insert(0) += 1;
+        if let Some(scores) = &self.scores {
+            score::watch_producer(&producer, produce_type, scores.clone());
+        }
+        Ok((producer, opus))
+    }
+
+    /// Account for a producer of this connection being closed
+    pub fn release_producer(&mut self, produce_type: ProduceType) {
+        if let Some(count) = self.producer_counts.get_mut(&produce_type) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Whether the next consumer of this kind should start in fast join mode,
+    /// claiming one of the connection's fast join slots if so
+    pub fn take_fast_join_slot(&mut self, settings: &FastJoinSettings, kind: MediaKind) -> bool {
+        if kind != MediaKind::Video
+            || self.connected_at.is_none()
+            || self.fast_join_consumers >= settings.consumers
+        {
+            return false;
+        }
+
+        self.fast_join_consumers += 1;
+        true
+    }
+
+    /// Create a consumer on the receiving transport, paused until the client resumes it.
+    /// Audio starts unpaused if the client initialized with `pause_consumers` off. Fast
+    /// joining video starts unpaused at the lowest layer and is promoted once the
+    /// bandwidth estimate settles
+    #[tracing::instrument(
+        name = "rtc.consume",
+        skip_all,
+        fields(
+            producer_id = %producer_id,
+            user_id = %user_id,
+            produce_type = ?produce_type,
+        )
+    )]
+    pub async fn start_consume(
+        &mut self,
+        router: &Router,
+        producer_id: ProducerId,
+        user_id: &str,
+        produce_type: ProduceType,
+        fast_join: Option<&FastJoinSettings>,
+    ) -> Result<Consumer, ()> {
+        let kind = produce_type.into_kind();
+        if !router.can_consume(&producer_id, &self.rtp_capabilities) {
+            return Err(());
+        }
+
+        let mut options = ConsumerOptions::new(producer_id, self.rtp_capabilities.clone());
+        options.paused = match kind {
+            MediaKind::Audio => self.pause_consumers,
+            MediaKind::Video => fast_join.is_none(),
+        };
+        let layer_cap = self.layer_cap(produce_type);
+        let cap = layer_cap.load(Ordering::Relaxed);
+        if fast_join.is_some() {
+            options.preferred_layers = Some(fast_join::initial_layers());
+        } else if cap != layers::UNCAPPED {
+            // mediasoup lowers preferred layers the producer doesn't send to its highest
+            options.preferred_layers = Some(ConsumerLayers {
+                spatial_layer: cap,
+                temporal_layer: None,
+            });
+        }
+
+        let transport_mode = self.transport_mode.clone();
+        let consumer =
+            run_unsend(move || async move { transport_mode.recv().consume(options).await })
+                .await
+                .map_err(|_| ())?
+                .map_err(|_| ())?;
+
+        let auto_layers = Arc::new(AtomicBool::new(true));
+        if let Some(settings) = fast_join {
+            if let TransportMode::SplitWebRtc(_, transport)
+            | TransportMode::CombinedWebRtc(transport)
+            | TransportMode::RecvWebRtc(transport) = &self.transport_mode
+            {
+                let max_delay = Duration::from_millis(settings.max_promote_delay);
+                let auto_layers = auto_layers.clone();
+                fast_join::promote_when_stable(
+                    transport.clone(),
+                    &consumer,
+                    auto_layers,
+                    layer_cap.clone(),
+                    max_delay,
+                );
+            }
+        }
+
+        if kind == MediaKind::Video {
+            if let Some(connected_at) = self.connected_at {
+                let mode = match fast_join {
+                    Some(_) => "fast_join",
+                    None => "default",
+                };
+
+                let recorded = self.first_frame_recorded.clone();
+                fast_join::observe_first_frame(&consumer, connected_at, recorded, mode).await;
+            }
+        }
+
+        let entry = ConsumerEntry {
+            consumer: consumer.clone(),
+            user_id: user_id.to_string(),
+            produce_type,
+            last_key_frame_request: None,
+            auto_layers: auto_layers.clone(),
+            auto_priority: None,
+            layer_cap,
+            activity: Activity::new(Instant::now()),
+        };
+        self.consumers.insert(consumer.id().to_string(), entry);
+        self.share_consumers();
+        if let Some(scores) = &self.scores {
+            score::watch_consumer(&consumer, scores.clone());
+        }
+        if kind == MediaKind::Video {
+            layers::watch(&consumer, auto_layers, self.layer_sender.clone());
+        }
+        closed::watch(&consumer, self.closed_sender.clone());
+        Ok(consumer)
+    }
+
+    pub fn get_consumer(&self, id: &str) -> Option<&Consumer> {
+        self.consumers.get(id).map(|entry| &entry.consumer)
+    }
+
+    /// Whether the consumer's layers follow the bandwidth estimate, `None` if it doesn't exist
+    pub fn consumer_auto_layers(&self, id: &str) -> Option<bool> {
+        let entry = self.consumers.get(id)?;
+        Some(entry.auto_layers.load(Ordering::Relaxed))
+    }
+
+    fn video_consumer(&mut self, id: &str) -> Result<&mut ConsumerEntry, LayersError> {
+        let entry = self
+            .consumers
+            .get_mut(id)
+            .ok_or(LayersError::ConsumerNotFound)?;
+        match entry.consumer.kind() {
+            MediaKind::Video => Ok(entry),
+            MediaKind::Audio => Err(LayersError::NotVideo),
+        }
+    }
+
+    /// Choose the layers forwarded to a video consumer, brought down to the room's cap.
+    /// With automatic layers on they are the most mediasoup forwards, lower ones are
+    /// still sent when bandwidth runs short
+    pub async fn set_consumer_layers(
+        &mut self,
+        id: &str,
+        layers: ConsumerLayers,
+    ) -> Result<(), LayersError> {
+        let entry = self.video_consumer(id)?;
+        let layers = layers::capped(layers, entry.layer_cap.load(Ordering::Relaxed));
+        if !layers::sent(&entry.consumer, &layers) {
+            return Err(LayersError::Unavailable);
+        }
+
+        entry
+            .consumer
+            .set_preferred_layers(layers)
+            .await
+            .map_err(|_| LayersError::Failed)
+    }
+
+    /// Turn the bandwidth driven layer selection of a video consumer on or off. Turned
+    /// off, the consumer keeps the layers last chosen with `set_consumer_layers` and gets
+    /// the highest priority so it's the last to be downgraded, turned back on its
+    /// priority is restored and it aims for the highest layers again
+    pub async fn set_consumer_auto_layers(
+        &mut self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<(), LayersError> {
+        let entry = self.video_consumer(id)?;
+        if entry.auto_layers.swap(enabled, Ordering::Relaxed) == enabled {
+            return Ok(());
+        }
+
+        let consumer = entry.consumer.clone();
+        let result = if enabled {
+            let priority = entry.auto_priority.take().unwrap_or(1);
+            let layers = layers::highest(&consumer, entry.layer_cap.load(Ordering::Relaxed));
+            let (priority, layers) = join!(
+                consumer.set_priority(priority),
+                consumer.set_preferred_layers(layers)
+            );
+            priority.and(layers)
+        } else {
+            entry.auto_priority = Some(consumer.priority());
+            consumer.set_priority(layers::PINNED_PRIORITY).await
+        };
+        result.map_err(|_| LayersError::Failed)
+    }
+
+    fn layer_cap(&mut self, produce_type: ProduceType) -> Arc<AtomicU8> {
+        self.layer_caps
+            .entry(produce_type)
+            .or_insert_with(|| Arc::new(AtomicU8::new(layers::UNCAPPED)))
+            .clone()
+    }
+
+    /// Cap the spatial layer forwarded to the video consumers of `produce_type`, those
+    /// open and those created later, `None` lifting the cap. Consumers with automatic
+    /// layers aim for the highest layers under the new cap, pinned ones are only lowered
+    pub async fn cap_consumer_layers(
+        &mut self,
+        produce_type: ProduceType,
+        max_spatial_layer: Option<u8>,
+    ) {
+        let cap = max_spatial_layer.unwrap_or(layers::UNCAPPED);
+        if self.layer_cap(produce_type).swap(cap, Ordering::Relaxed) == cap {
+            return;
+        }
+
+        let changes: Vec<_> = self
+            .consumers
+            .values()
+            .filter(|entry| entry.produce_type == produce_type)
+            .filter_map(|entry| {
+                let layers = if entry.auto_layers.load(Ordering::Relaxed) {
+                    layers::highest(&entry.consumer, cap)
+                } else {
+                    layers::capped(entry.consumer.preferred_layers()?, cap)
+                };
+                Some((entry.consumer.clone(), layers))
+            })
+            .collect();
+        for (consumer, layers) in changes {
+            if let Err(err) = consumer.set_preferred_layers(layers).await {
+                warn!(
+                    "Failed to cap the layers of consumer {}: {}",
+                    consumer.id(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Ask the producer of a video consumer for a keyframe. Requests for a consumer coming
+    /// quicker than `KEY_FRAME_REQUEST_INTERVAL` are dropped, one is already on its way
+    pub async fn request_key_frame(&mut self, id: &str) -> Result<(), KeyFrameError> {
+        let entry = self
+            .consumers
+            .get_mut(id)
+            .ok_or(KeyFrameError::ConsumerNotFound)?;
+        if entry.consumer.kind() != MediaKind::Video {
+            return Err(KeyFrameError::NotVideo);
+        }
+
+        let now = Instant::now();
+        if let Some(last) = entry.last_key_frame_request {
+            if now.duration_since(last) < KEY_FRAME_REQUEST_INTERVAL {
+                return Ok(());
+            }
+        }
+
+        entry.last_key_frame_request = Some(now);
+        entry
+            .consumer
+            .request_key_frame()
+            .await
+            .map_err(|_| KeyFrameError::Failed)
+    }
+
+    /// Change how strongly a consumer is protected when downstream bandwidth runs short,
+    /// `None` if the consumer doesn't exist
+    pub async fn set_consumer_priority(&self, id: &str, priority: u8) -> Option<Result<(), ()>> {
+        let consumer = self.get_consumer(id)?;
+        Some(consumer.set_priority(priority).await.map_err(|_| ()))
+    }
+
+    /// Remove the consumers of a user's producers, only those of `produce_type` if given.
+    /// Their producers are already closed, so they are returned for notifying the client
+    pub fn remove_consumers_of(
+        &mut self,
+        user_id: &str,
+        produce_type: Option<ProduceType>,
+    ) -> Vec<Consumer> {
+        let ids: Vec<String> = self
+            .consumers
+            .iter()
+            .filter(|(_, entry)| {
+                entry.user_id == user_id
+                    && produce_type.is_none_or(|produce_type| entry.produce_type == produce_type)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let consumers = ids
+            .iter()
+            .filter_map(|id| self.consumers.remove(id))
+            .map(|entry| entry.consumer)
+            .collect();
+        self.share_consumers();
+        consumers
+    }
+
+    /// Every consumer the connection holds, echo consumers included
+    pub fn list_consumers(&self) -> Vec<ConsumerSummary> {
+        self.consumers
+            .values()
+            .map(|entry| ConsumerSummary {
+                id: entry.consumer.id().to_string(),
+                producer_id: entry.consumer.producer_id().to_string(),
+                user_id: entry.user_id.clone(),
+                produce_type: entry.produce_type,
+                kind: entry.consumer.kind(),
+                paused: entry.consumer.paused(),
+                producer_paused: entry.consumer.producer_paused(),
+            })
+            .collect()
+    }
+
+    /// Close a consumer, returning whether it existed
+    pub fn stop_consume(&mut self, id: &str) -> bool {
+        let existed = self.consumers.remove(id).is_some();
+        self.pending_resumes.retain(|pending| pending != id);
+        self.share_consumers();
+        existed
+    }
+
+    /// Queue resuming a consumer until the receiving transport's DTLS connected, returning
+    /// whether it was queued. Once connected consumers are resumed right away
+    pub fn defer_resume(&mut self, id: &str) -> bool {
+        if self.recv_connected {
+            return false;
+        }
+        if self.pending_resumes.iter().any(|pending| pending == id) {
+            return true;
+        }
+        if self.pending_resumes.is_empty() {
+            let timeout = Duration::from_secs(CONFIG.rtc.resume_timeout_secs);
+            self.resume_deadline = Some(Instant::now() + timeout);
+        }
+        self.pending_resumes.push(id.to_string());
+        true
+    }
+
+    /// Note a transport's DTLS connected. Once it's the receiving one the queued consumers
+    /// are resumed, with a keyframe requested for video, returning the IDs of those resumed.
+    /// Those closed in the meantime or failing to resume are left out
+    pub async fn transport_connected(&mut self, transport_id: TransportId) -> Vec<String> {
+        if self.recv_connected || self.transport_mode.recv().id() != transport_id {
+            return Vec::new();
+        }
+        self.recv_connected = true;
+        self.resume_deadline = None;
+
+        let mut resumed = Vec::new();
+        for id in std::mem::take(&mut self.pending_resumes) {
+            let consumer = match self.consumers.get(&id) {
+                Some(entry) => entry.consumer.clone(),
+                None => continue,
+            };
+            if consumer.resume().await.is_err() {
+                continue;
+            }
+            if consumer.kind() == MediaKind::Video {
+                self.request_key_frame(&id).await.ok();
+            }
+            resumed.push(id);
+        }
+        resumed
+    }
+
+    /// When the queued consumers are closed, `None` while there are none
+    pub fn resume_deadline(&self) -> Option<Instant> {
+        self.resume_deadline
+    }
+
+    /// Close the queued consumers if the receiving transport didn't connect by the
+    /// deadline, returning those closed
+    pub fn expire_resumes(&mut self) -> Vec<Consumer> {
+        match self.resume_deadline {
+            Some(deadline) if deadline <= Instant::now() => (),
+            _ => return Vec::new(),
+        }
+        self.resume_deadline = None;
+        let expired = std::mem::take(&mut self.pending_resumes)
+            .into_iter()
+            .filter_map(|id| self.consumers.remove(&id))
+            .map(|entry| entry.consumer)
+            .collect();
+        self.share_consumers();
+        expired
+    }
+
+    /// Consumers whose packets are counted for `reap_inactive`
+    pub fn counted_consumers(&self) -> Vec<Consumer> {
+        self.consumers
+            .values()
+            .map(|entry| entry.consumer.clone())
+            .collect()
+    }
+
+    /// Record the packets each consumer sent so far and close those that sent none for
+    /// `inactive_after`, unless the client or the producer paused them
+    pub fn reap_inactive(
+        &mut self,
+        counts: PacketCounts,
+        inactive_after: Duration,
+    ) -> Vec<Consumer> {
+        let now = Instant::now();
+        let mut reaped = Vec::new();
+        for (id, packets) in counts {
+            let entry = match self.consumers.get_mut(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let paused = entry.consumer.paused() || entry.consumer.producer_paused();
+            if entry.activity.record(packets, paused, now, inactive_after) {
+                reaped.extend(self.consumers.remove(&id).map(|entry| entry.consumer));
+            }
+        }
+        self.share_consumers();
+        reaped
+    }
+}
+
+/// Options of the client's WebRTC transports, its ICE policy narrowing the protocols the
+/// configuration enables. A policy that would leave none is ignored
+/// An idle transport of the router's pool if it can stand in for one created with
+/// `options`, a new one otherwise
+async fn webrtc_transport(
+    router: &Router,
+    options: WebRtcTransportOptions,
+    pooled: bool,
+) -> Result<WebRtcTransport, InitError> {
+    if pooled {
+        if let Some(transport) = pool::claim(router) {
+            return Ok(transport);
+        }
+    }
+    Ok(router.create_webrtc_transport(options).await?)
+}
+
+fn webrtc_options(rtc_config: &RtcConfig, policy: Option<IcePolicy>) -> WebRtcTransportOptions {
+    let mut options = WebRtcTransportOptions::new(rtc_config.transport_listen_ips());
+    let (udp, tcp, prefer_udp) = match policy {
+        Some(IcePolicy::UdpOnly) => (true, false, true),
+        Some(IcePolicy::PreferUdp) => (true, true, true),
+        Some(IcePolicy::TcpOnly) => (false, true, false),
+        None => (true, true, rtc_config.prefer_udp),
+    };
+    let enable_udp = rtc_config.enable_udp && udp;
+    let enable_tcp = rtc_config.enable_tcp && tcp;
+    if enable_udp || enable_tcp {
+        options.enable_udp = enable_udp;
+        options.enable_tcp = enable_tcp;
+        options.prefer_udp = prefer_udp;
+    } else {
+        options.enable_udp = rtc_config.enable_udp;
+        options.enable_tcp = rtc_config.enable_tcp;
+        options.prefer_udp = rtc_config.prefer_udp;
+    }
+    options
+}
+
+/// Clamp the Opus options of a new producer to the room's limits and apply them to
+/// its parameters, `None` if they are the defaults
+fn producer_opus(
+    produce_type: ProduceType,
+    rtp_parameters: &mut RtpParameters,
+    opus: Option<OpusOptions>,
+    opus_limits: &OpusLimits,
+) -> Result<Option<OpusOptions>, ProduceError> {
+    match produce_type.into_kind() {
+        MediaKind::Audio => {
+            let opus = opus
+                .unwrap_or_default()
+                .effective(opus_limits)
+                .map_err(ProduceError::Opus)?;
+            match opus == OpusOptions::default() {
+                true => Ok(None),
+                false => {
+                    opus.apply(rtp_parameters).map_err(ProduceError::Opus)?;
+                    Ok(Some(opus))
+                }
+            }
+        }
+        MediaKind::Video if opus.is_some() => Err(ProduceError::Opus(OpusError::NotOpus)),
+        MediaKind::Video => Ok(None),
     }
 }
 
+#[derive(Clone)]
 enum TransportMode {
     SplitWebRtc(WebRtcTransport, WebRtcTransport),
     CombinedWebRtc(WebRtcTransport),
     CombinedRtp(PlainTransport),
+    RecvWebRtc(WebRtcTransport),
 }
 
 impl TransportMode {
@@ -195,14 +1191,17 @@ impl TransportMode {
             TransportMode::SplitWebRtc(..) => false,
             TransportMode::CombinedWebRtc(..) => true,
             TransportMode::CombinedRtp(..) => true,
+            TransportMode::RecvWebRtc(..) => false,
         }
     }
 
-    pub fn send(&self) -> &dyn Transport {
+    /// `None` for listeners, which can't produce
+    pub fn send(&self) -> Option<&dyn Transport> {
         match self {
-            TransportMode::SplitWebRtc(ref send, _) => send,
-            TransportMode::CombinedWebRtc(ref transport) => transport,
-            TransportMode::CombinedRtp(ref transport) => transport,
+            TransportMode::SplitWebRtc(ref send, _) => Some(send),
+            TransportMode::CombinedWebRtc(ref transport) => Some(transport),
+            TransportMode::CombinedRtp(ref transport) => Some(transport),
+            TransportMode::RecvWebRtc(..) => None,
         }
     }
 
@@ -211,6 +1210,271 @@ impl TransportMode {
             TransportMode::SplitWebRtc(_, ref recv) => recv,
             TransportMode::CombinedWebRtc(ref transport) => transport,
             TransportMode::CombinedRtp(ref transport) => transport,
+            TransportMode::RecvWebRtc(ref recv) => recv,
+        }
+    }
+
+    async fn bitrates(&self) -> Result<Bitrates, ()> {
+        let mut bitrates = Bitrates::default();
+        let webrtc = match self {
+            TransportMode::SplitWebRtc(send, recv) => vec![send, recv],
+            TransportMode::CombinedWebRtc(transport) | TransportMode::RecvWebRtc(transport) => {
+                vec![transport]
+            }
+            TransportMode::CombinedRtp(transport) => {
+                for stat in transport.get_stats().await.map_err(|_| ())? {
+                    bitrates += Bitrates {
+                        incoming: stat.recv_bitrate.into(),
+                        outgoing: stat.send_bitrate.into(),
+                    };
+                }
+                return Ok(bitrates);
+            }
+        };
+
+        for transport in webrtc {
+            for stat in transport.get_stats().await.map_err(|_| ())? {
+                bitrates += Bitrates {
+                    incoming: stat.recv_bitrate.into(),
+                    outgoing: stat.send_bitrate.into(),
+                };
+            }
+        }
+        Ok(bitrates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::config::ListenIp;
+    use crate::util::testing;
+    use serde_json::{json, Value};
+
+    fn init_data(mode: Option<&str>) -> InitializationInput {
+        let mut init_data = json!({
+            "rtpCapabilities": { "codecs": [], "headerExtensions": [] },
+        });
+        if let Some(mode) = mode {
+            init_data["mode"] = json!(mode);
         }
+        serde_json::from_value(init_data).unwrap()
+    }
+
+    fn connect_data(id: &Value) -> ConnectTransportData {
+        let fingerprint = vec!["AB"; 32].join(":");
+        serde_json::from_value(json!({
+            "id": id,
+            "dtlsParameters": {
+                "role": "client",
+                "fingerprints": [{ "algorithm": "sha-256", "value": fingerprint }],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn init_failures_are_classified() {
+        let response = |reason: &str| RequestError::Response {
+            reason: reason.to_string(),
+        };
+        let exhausted = response("no more available ports [protocol:udp, ip:'10.0.0.1']");
+        assert_eq!(InitError::from(exhausted), InitError::Exhausted);
+        assert_eq!(
+            InitError::from(response("std::bad_alloc")),
+            InitError::Exhausted
+        );
+        assert_eq!(
+            InitError::from(RequestError::TimedOut),
+            InitError::Transient
+        );
+        assert_eq!(
+            InitError::from(response("unknown method 'router.foo'")),
+            InitError::Failed
+        );
+    }
+
+    #[test]
+    fn ice_policies_narrow_the_configured_protocols() {
+        let mut rtc_config = RtcConfig::default();
+        rtc_config.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        let protocols = |rtc_config: &RtcConfig, policy| {
+            let options = webrtc_options(rtc_config, policy);
+            (options.enable_udp, options.enable_tcp, options.prefer_udp)
+        };
+
+        assert_eq!(protocols(&rtc_config, None), (true, true, true));
+        let policy = Some(IcePolicy::UdpOnly);
+        assert_eq!(protocols(&rtc_config, policy), (true, false, true));
+        let policy = Some(IcePolicy::PreferUdp);
+        assert_eq!(protocols(&rtc_config, policy), (true, true, true));
+        let policy = Some(IcePolicy::TcpOnly);
+        assert_eq!(protocols(&rtc_config, policy), (false, true, false));
+
+        // Protocols disabled on the server stay disabled, unless nothing would be left
+        rtc_config.enable_tcp = false;
+        let policy = Some(IcePolicy::PreferUdp);
+        assert_eq!(protocols(&rtc_config, policy), (true, false, true));
+        let policy = Some(IcePolicy::TcpOnly);
+        assert_eq!(protocols(&rtc_config, policy), (true, false, true));
+    }
+
+    #[tokio::test]
+    async fn tcp_only_offers_tcp_candidates() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut init_data = init_data(Some("CombinedWebRtc"));
+        init_data.ice_policy = Some(IcePolicy::TcpOnly);
+        let state = RtcState::initialize(&room.router().unwrap(), init_data)
+            .await
+            .unwrap();
+
+        let reply = serde_json::to_value(state.get_init_data()).unwrap();
+        let candidates = reply["transport"]["iceCandidates"].as_array().unwrap();
+        assert!(!candidates.is_empty());
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate["protocol"] == "tcp"));
+        room.delete().await;
+    }
+
+    #[test]
+    fn split_transports_by_default() {
+        assert_eq!(init_data(None).mode, InitializationInputMode::SplitWebRtc);
+        assert_eq!(
+            init_data(Some("CombinedWebRtc")).mode,
+            InitializationInputMode::CombinedWebRtc
+        );
+    }
+
+    #[tokio::test]
+    async fn split_handshake() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut state = RtcState::initialize(&room.router().unwrap(), init_data(None))
+            .await
+            .unwrap();
+        assert!(!state.combined());
+
+        let reply = serde_json::to_value(state.get_init_data()).unwrap();
+        assert!(reply.get("transport").is_none());
+        let (send, recv) = (&reply["sendTransport"]["id"], &reply["recvTransport"]["id"]);
+        assert_ne!(send, recv);
+
+        state.connect_transport(&connect_data(send)).await.unwrap();
+        assert!(state.connected_at.is_none());
+        state.connect_transport(&connect_data(recv)).await.unwrap();
+        assert!(state.connected_at.is_some());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn resumes_wait_for_the_receiving_transport() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut state = RtcState::initialize(&room.router().unwrap(), init_data(None))
+            .await
+            .unwrap();
+        assert!(state.defer_resume("first"));
+        assert!(state.defer_resume("first"));
+        assert!(state.defer_resume("second"));
+        assert_eq!(state.pending_resumes.len(), 2);
+        assert!(state.resume_deadline().is_some());
+
+        // Not yet expired, and the send transport connecting doesn't count
+        assert!(state.expire_resumes().is_empty());
+        assert_eq!(state.pending_resumes.len(), 2);
+        let send = state.transport_mode.send().unwrap().id();
+        assert!(state.transport_connected(send).await.is_empty());
+        assert!(state.defer_resume("third"));
+
+        // Closed before the transport connected, so there's nothing left to resume
+        let recv = state.transport_mode.recv().id();
+        assert!(state.transport_connected(recv).await.is_empty());
+        assert!(state.pending_resumes.is_empty());
+        assert!(state.resume_deadline().is_none());
+        assert!(!state.defer_resume("fourth"));
+
+        let mut state = RtcState::initialize(&room.router().unwrap(), init_data(None))
+            .await
+            .unwrap();
+        assert!(state.defer_resume("first"));
+        state.resume_deadline = Some(Instant::now());
+        assert!(state.expire_resumes().is_empty());
+        assert!(state.pending_resumes.is_empty());
+        assert!(state.resume_deadline().is_none());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn combined_handshake() {
+        let room = testing::room(RoomSettings::default()).await;
+        let init_data = init_data(Some("CombinedWebRtc"));
+        let mut state = RtcState::initialize(&room.router().unwrap(), init_data)
+            .await
+            .unwrap();
+        assert!(state.combined());
+
+        let reply = serde_json::to_value(state.get_init_data()).unwrap();
+        assert!(reply.get("sendTransport").is_none());
+        assert!(reply.get("recvTransport").is_none());
+
+        let unknown = json!("00000000-0000-4000-8000-000000000000");
+        assert!(state
+            .connect_transport(&connect_data(&unknown))
+            .await
+            .is_err());
+
+        // Connecting the only transport readies both directions
+        let transport = &reply["transport"]["id"];
+        state
+            .connect_transport(&connect_data(transport))
+            .await
+            .unwrap();
+        assert!(state.connected_at.is_some());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn listeners_only_receive() {
+        let room = testing::room(RoomSettings::default()).await;
+        let init_data = init_data(Some("RecvWebRtc"));
+        let mut state = RtcState::initialize(&room.router().unwrap(), init_data)
+            .await
+            .unwrap();
+        assert!(!state.combined());
+
+        let reply = serde_json::to_value(state.get_init_data()).unwrap();
+        assert!(reply.get("sendTransport").is_none());
+        assert!(reply.get("transport").is_none());
+        let recv = &reply["recvTransport"]["id"];
+        state.connect_transport(&connect_data(recv)).await.unwrap();
+        assert!(state.connected_at.is_some());
+
+        // Nothing to cap or produce on
+        assert!(state.set_max_incoming_bitrate(500_000).await.is_ok());
+        let rtp_parameters = serde_json::from_value(json!({
+            "codecs": [{
+                "mimeType": "audio/opus",
+                "payloadType": 100,
+                "clockRate": 48000,
+                "channels": 2,
+                "parameters": {},
+                "rtcpFeedback": [],
+            }],
+            "encodings": [{ "ssrc": 1111 }],
+        }))
+        .unwrap();
+        let result = state
+            .start_produce(
+                ProduceType::Audio,
+                rtp_parameters,
+                None,
+                &room.settings().opus_limits,
+            )
+            .await;
+        assert!(matches!(result, Err(ProduceError::NoSendTransport)));
+        room.delete().await;
     }
 }
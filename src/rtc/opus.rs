@@ -0,0 +1,183 @@
+//! Opus options of audio producers. They're written into the producer's codec
+//! parameters, which consumers inherit, so receivers decode what the sender encodes
+
+use std::ops::RangeInclusive;
+
+use mediasoup::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::state::room::settings::OpusLimits;
+
+/// Average bitrates Opus supports, in bits per second
+pub const BITRATE_RANGE: RangeInclusive<u32> = 6000..=510_000;
+
+/// Options a client asks for when producing audio, absent ones are left to the
+/// browser's defaults
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OpusOptions {
+    /// In-band forward error correction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub useinbandfec: Option<bool>,
+    /// Discontinuous transmission, nothing is sent during silence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usedtx: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stereo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxaveragebitrate: Option<u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OpusError {
+    /// Name of the option outside its allowed range
+    OutOfRange(&'static str),
+    /// The producer isn't sending Opus
+    NotOpus,
+}
+
+impl OpusOptions {
+    /// Validate the options and clamp them to the room's limits, giving the ones the
+    /// producer is created with
+    pub fn effective(self, limits: &OpusLimits) -> Result<OpusOptions, OpusError> {
+        if let Some(bitrate) = self.maxaveragebitrate {
+            if !BITRATE_RANGE.contains(&bitrate) {
+                return Err(OpusError::OutOfRange("maxaveragebitrate"));
+            }
+        }
+
+        let stereo = match self.stereo {
+            Some(true) if !limits.stereo => Some(false),
+            stereo => stereo,
+        };
+        let maxaveragebitrate = match (self.maxaveragebitrate, limits.max_average_bitrate) {
+            (Some(bitrate), Some(max)) => Some(bitrate.min(max)),
+            (None, Some(max)) => Some(max),
+            (bitrate, None) => bitrate,
+        };
+
+        Ok(OpusOptions {
+            stereo,
+            maxaveragebitrate,
+            ..self
+        })
+    }
+
+    /// Write the options into the Opus codec of the producer's parameters
+    pub fn apply(&self, rtp_parameters: &mut RtpParameters) -> Result<(), OpusError> {
+        let parameters = rtp_parameters
+            .codecs
+            .iter_mut()
+            .find_map(|codec| match codec {
+                RtpCodecParameters::Audio {
+                    mime_type: MimeTypeAudio::Opus,
+                    parameters,
+                    ..
+                } => Some(parameters),
+                _ => None,
+            })
+            .ok_or(OpusError::NotOpus)?;
+
+        let flags = [
+            ("useinbandfec", self.useinbandfec),
+            ("usedtx", self.usedtx),
+            ("stereo", self.stereo),
+        ];
+        for (name, flag) in flags.iter() {
+            if let Some(flag) = flag {
+                parameters.insert(*name, u32::from(*flag));
+            }
+        }
+        if let Some(bitrate) = self.maxaveragebitrate {
+            parameters.insert("maxaveragebitrate", bitrate);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::{NonZeroU32, NonZeroU8};
+
+    fn opus_parameters() -> RtpParameters {
+        RtpParameters {
+            codecs: vec![RtpCodecParameters::Audio {
+                mime_type: MimeTypeAudio::Opus,
+                payload_type: 111,
+                clock_rate: NonZeroU32::new(48000).unwrap(),
+                channels: NonZeroU8::new(2).unwrap(),
+                parameters: RtpCodecParametersParameters::default(),
+                rtcp_feedback: Vec::new(),
+            }],
+            ..RtpParameters::default()
+        }
+    }
+
+    #[test]
+    fn options_are_clamped_to_the_room() {
+        let options = OpusOptions {
+            useinbandfec: Some(true),
+            usedtx: None,
+            stereo: Some(true),
+            maxaveragebitrate: Some(128_000),
+        };
+        assert_eq!(options.effective(&OpusLimits::default()), Ok(options));
+
+        let voice_room = OpusLimits {
+            stereo: false,
+            max_average_bitrate: Some(32_000),
+        };
+        assert_eq!(
+            options.effective(&voice_room),
+            Ok(OpusOptions {
+                stereo: Some(false),
+                maxaveragebitrate: Some(32_000),
+                ..options
+            })
+        );
+        assert_eq!(
+            OpusOptions::default().effective(&voice_room),
+            Ok(OpusOptions {
+                maxaveragebitrate: Some(32_000),
+                ..OpusOptions::default()
+            })
+        );
+
+        let options = OpusOptions {
+            maxaveragebitrate: Some(1_000_000),
+            ..OpusOptions::default()
+        };
+        assert_eq!(
+            options.effective(&OpusLimits::default()),
+            Err(OpusError::OutOfRange("maxaveragebitrate"))
+        );
+    }
+
+    #[test]
+    fn options_are_written_into_the_codec() {
+        let mut rtp_parameters = opus_parameters();
+        let options = OpusOptions {
+            useinbandfec: Some(true),
+            usedtx: Some(false),
+            stereo: None,
+            maxaveragebitrate: Some(64_000),
+        };
+        options.apply(&mut rtp_parameters).unwrap();
+
+        let parameters = match &rtp_parameters.codecs[0] {
+            RtpCodecParameters::Audio { parameters, .. } => parameters,
+            _ => unreachable!(),
+        };
+        let number = |value| Some(RtpCodecParametersParametersValue::Number(value));
+        assert_eq!(parameters.get("useinbandfec").cloned(), number(1));
+        assert_eq!(parameters.get("usedtx").cloned(), number(0));
+        assert_eq!(parameters.get("stereo").cloned(), None);
+        assert_eq!(parameters.get("maxaveragebitrate").cloned(), number(64_000));
+
+        let mut video = RtpParameters::default();
+        assert_eq!(options.apply(&mut video), Err(OpusError::NotOpus));
+    }
+}
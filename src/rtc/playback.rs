@@ -0,0 +1,305 @@
+//! Audio the server plays into a room, such as a join chime or an announcement. FFmpeg
+//! encodes the file to Opus and sends it over RTP to a local plain transport, where it's
+//! produced under a reserved user ID so participants consume it like anyone's audio
+
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::{NonZeroU32, NonZeroU8};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use mediasoup::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use super::local::run_unsend;
+use crate::util::config::PlaybackConfig;
+
+/// User ID playback is produced under, refused for real users
+pub const SYSTEM_USER_ID: &str = "system";
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+/// Payload type FFmpeg sends, consumers get whatever the router negotiates with them
+pub(super) const PAYLOAD_TYPE: u8 = 100;
+
+#[derive(Debug)]
+pub enum PlaybackError {
+    /// No playback directory is configured on this node
+    Unavailable,
+    AlreadyPlaying,
+    /// Name of the file that's missing or outside the playback directory
+    InvalidFile(String),
+    /// Size of the uploaded audio in bytes
+    TooLarge(usize),
+    Ingress(String),
+}
+
+impl Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaybackError::Unavailable => write!(f, "Playback is not enabled on this server"),
+            PlaybackError::AlreadyPlaying => write!(f, "Room is already playing audio"),
+            PlaybackError::InvalidFile(file) => write!(f, "No audio file named {}", file),
+            PlaybackError::TooLarge(size) => {
+                write!(f, "Audio of {} bytes is above the upload limit", size)
+            }
+            PlaybackError::Ingress(err) => write!(f, "Playback failed: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for PlaybackError {
+    fn from(err: io::Error) -> Self {
+        PlaybackError::Ingress(err.to_string())
+    }
+}
+
+/// What to play, a file from the playback directory or audio uploaded with the request
+pub enum PlaybackSource {
+    File(String),
+    Bytes(Vec<u8>),
+}
+
+/// Audio being played into a room, stopped when dropped
+pub struct Playback {
+    producer_id: ProducerId,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Playback {
+    /// Produce the source into the router, `on_finished` is called once playback ended,
+    /// whether it played out or was stopped
+    pub async fn start<F>(
+        router: &Router,
+        source: PlaybackSource,
+        config: &PlaybackConfig,
+        on_finished: F,
+    ) -> Result<Self, PlaybackError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let (input, bytes) = match source {
+            PlaybackSource::File(name) => {
+                let path = resolve_file(Path::new(&config.directory), &name)
+                    .ok_or_else(|| PlaybackError::InvalidFile(name.clone()))?;
+                if !tokio::fs::metadata(&path)
+                    .await
+                    .map_or(false, |m| m.is_file())
+                {
+                    return Err(PlaybackError::InvalidFile(name));
+                }
+                (path.to_string_lossy().into_owned(), None)
+            }
+            PlaybackSource::Bytes(bytes) if bytes.len() > config.max_upload_bytes => {
+                return Err(PlaybackError::TooLarge(bytes.len()))
+            }
+            PlaybackSource::Bytes(bytes) => ("pipe:0".to_string(), Some(bytes)),
+        };
+
+        // FFmpeg's address is learnt from the first packet it sends
+        let mut options = PlainTransportOptions::new(TransportListenIp {
+            ip: LOCALHOST,
+            announced_ip: None,
+        });
+        options.comedia = true;
+        let transport = router
+            .create_plain_transport(options)
+            .await
+            .map_err(|err| PlaybackError::Ingress(err.to_string()))?;
+        let port = transport.tuple().local_port();
+
+        let ssrc = rand::random::<u32>();
+        let producer = {
+            let transport = transport.clone();
+            let options = ProducerOptions::new(MediaKind::Audio, rtp_parameters(ssrc));
+            run_unsend(move || async move { transport.produce(options).await })
+                .await
+                .map_err(|err| PlaybackError::Ingress(err.to_string()))?
+                .map_err(|err| PlaybackError::Ingress(err.to_string()))?
+        };
+
+        let stdin = match bytes {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+        let process = Command::new(&config.ffmpeg)
+            .args(ffmpeg_args(&input, ssrc, port))
+            .stdin(stdin)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let producer_id = producer.id();
+        let (stop, stopped) = oneshot::channel();
+        let finished = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn({
+            let finished = finished.clone();
+            async move {
+                supervise(process, bytes, stopped).await;
+                // Closes the producer, which consumers see as the system user stopping
+                drop((producer, transport));
+                finished.store(true, Ordering::Relaxed);
+                on_finished();
+            }
+        });
+
+        Ok(Playback {
+            producer_id,
+            stop,
+            task,
+            finished,
+        })
+    }
+
+    pub fn producer_id(&self) -> ProducerId {
+        self.producer_id
+    }
+
+    /// Whether the audio played out or was stopped
+    pub fn finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Cut the audio short, returning once FFmpeg exited
+    pub async fn stop(self) {
+        self.stop.send(()).ok();
+        self.task.await.ok();
+    }
+}
+
+/// Wait for FFmpeg to play the audio out, feeding it uploaded audio on stdin
+async fn supervise(mut process: Child, bytes: Option<Vec<u8>>, stopped: oneshot::Receiver<()>) {
+    let stdin = process.stdin.take();
+    let played = async {
+        if let (Some(mut stdin), Some(bytes)) = (stdin, bytes) {
+            // Read as fast as it's played, FFmpeg sees the end once stdin closes
+            stdin.write_all(&bytes).await.ok();
+        }
+        process.wait().await
+    };
+
+    let stop = tokio::select! {
+        // Stopped, or the handle was dropped along with the room
+        _ = stopped => true,
+        status = played => {
+            match status {
+                Ok(status) if status.success() => (),
+                Ok(status) => warn!("Playback FFmpeg exited with {}", status),
+                Err(err) => warn!("Failed to wait for playback FFmpeg: {}", err),
+            }
+            false
+        }
+    };
+
+    if stop {
+        process.kill().await.ok();
+    }
+}
+
+/// Path of a file in the playback directory, names can't climb out of it
+fn resolve_file(directory: &Path, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    let plain = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    match plain && name.components().next().is_some() {
+        true => Some(directory.join(name)),
+        false => None,
+    }
+}
+
+/// Opus as FFmpeg sends it, in real time with a fixed SSRC so the producer matches it
+pub(super) fn rtp_parameters(ssrc: u32) -> RtpParameters {
+    RtpParameters {
+        codecs: vec![RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type: PAYLOAD_TYPE,
+            clock_rate: NonZeroU32::new(48000).unwrap(),
+            channels: NonZeroU8::new(2).unwrap(),
+            parameters: RtpCodecParametersParameters::default(),
+            rtcp_feedback: Vec::new(),
+        }],
+        encodings: vec![RtpEncodingParameters {
+            ssrc: Some(ssrc),
+            ..RtpEncodingParameters::default()
+        }],
+        ..RtpParameters::default()
+    }
+}
+
+fn ffmpeg_args(input: &str, ssrc: u32, port: u16) -> Vec<String> {
+    let ssrc = ssrc.to_string();
+    let payload_type = PAYLOAD_TYPE.to_string();
+    // RTCP shares the RTP port, the transport muxes them
+    let url = format!("rtp://{}:{}?rtcpport={}", LOCALHOST, port, port);
+    vec![
+        "-loglevel",
+        "error",
+        "-nostats",
+        "-re",
+        "-i",
+        input,
+        "-map",
+        "0:a:0",
+        "-c:a",
+        "libopus",
+        "-ar",
+        "48000",
+        "-ac",
+        "2",
+        "-b:a",
+        "64k",
+        "-payload_type",
+        &payload_type,
+        "-ssrc",
+        &ssrc,
+        "-f",
+        "rtp",
+        &url,
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_stay_in_the_directory() {
+        let directory = Path::new("/var/lib/vortex/sounds");
+        assert_eq!(
+            resolve_file(directory, "chimes/join.ogg"),
+            Some(directory.join("chimes/join.ogg"))
+        );
+        assert_eq!(resolve_file(directory, "../../etc/passwd"), None);
+        assert_eq!(resolve_file(directory, "/etc/passwd"), None);
+        assert_eq!(resolve_file(directory, ""), None);
+    }
+
+    #[test]
+    fn ffmpeg_sends_what_the_producer_expects() {
+        let args = ffmpeg_args("pipe:0", 1234, 40000);
+        assert!(args.windows(2).any(|pair| pair == ["-i", "pipe:0"]));
+        assert!(args.windows(2).any(|pair| pair == ["-ssrc", "1234"]));
+        assert!(args.windows(2).any(|pair| pair == ["-payload_type", "100"]));
+        assert_eq!(
+            args.last().map(String::as_str),
+            Some("rtp://127.0.0.1:40000?rtcpport=40000")
+        );
+
+        let parameters = rtp_parameters(1234);
+        assert_eq!(parameters.encodings[0].ssrc, Some(1234));
+    }
+}
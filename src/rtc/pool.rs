@@ -0,0 +1,206 @@
+//! WebRTC transports created ahead of time on a room's router, claimed by connections
+//! initializing their transports instead of waiting for the worker. They're created with
+//! the default ICE options, connections asking for a policy of their own get new ones.
+//! A claimed transport is never given back: its client has seen its ICE credentials, so
+//! it's closed along with the connection's media like any other
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use mediasoup::router::RouterId;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use super::webrtc_options;
+use crate::util::config::{TransportPoolConfig, CONFIG};
+use crate::util::metrics::{TRANSPORT_POOL_CLAIMS, TRANSPORT_POOL_EXPIRED, TRANSPORT_POOL_IDLE};
+
+/// How often idle transports are checked for expiry while none are claimed
+const EXPIRY_CHECK: Duration = Duration::from_secs(10);
+/// Pause after the worker failed to create a transport, before trying again
+const REFILL_BACKOFF: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Pools by the router they're on, owned by the room the router belongs to
+    static ref POOLS: Mutex<HashMap<RouterId, Weak<TransportPool>>> = Mutex::new(HashMap::new());
+}
+
+pub struct TransportPool {
+    router_id: RouterId,
+    config: &'static TransportPoolConfig,
+    idle: Mutex<VecDeque<(WebRtcTransport, Instant)>>,
+    /// Wakes the refill task after a claim, or to stop once the pool is dropped
+    wake: Arc<Notify>,
+}
+
+impl TransportPool {
+    /// Keep transports ready on the router if a pool is configured. They're closed once
+    /// the returned pool is dropped
+    pub fn start(router: &Router) -> Option<Arc<TransportPool>> {
+        let config = CONFIG.rtc.transport_pool.as_ref()?;
+        Some(TransportPool::start_with(router, config))
+    }
+
+    fn start_with(router: &Router, config: &'static TransportPoolConfig) -> Arc<TransportPool> {
+        let pool = Arc::new(TransportPool {
+            router_id: router.id(),
+            config,
+            idle: Mutex::new(VecDeque::new()),
+            wake: Arc::new(Notify::new()),
+        });
+        POOLS.lock().insert(router.id(), Arc::downgrade(&pool));
+        tokio::spawn(refill(Arc::downgrade(&pool), router.clone()));
+        pool
+    }
+
+    fn max_idle(&self) -> Duration {
+        Duration::from_secs(self.config.max_idle_secs)
+    }
+
+    fn len(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    fn push(&self, transport: WebRtcTransport) {
+        self.idle.lock().push_back((transport, Instant::now()));
+        TRANSPORT_POOL_IDLE.inc();
+    }
+
+    /// Oldest idle transport that's still fresh. Expired ones are closed on the way
+    fn take(&self) -> Option<WebRtcTransport> {
+        let mut expired = Vec::new();
+        let mut idle = self.idle.lock();
+        let transport = loop {
+            let (transport, created) = match idle.pop_front() {
+                Some(entry) => entry,
+                None => break None,
+            };
+            TRANSPORT_POOL_IDLE.dec();
+            if created.elapsed() < self.max_idle() && !transport.closed() {
+                break Some(transport);
+            }
+            expired.push(transport);
+        };
+        drop(idle);
+        TRANSPORT_POOL_EXPIRED.inc_by(expired.len() as u64);
+        transport
+    }
+
+    /// Close the transports that sat idle too long, the oldest being first
+    fn expire(&self) {
+        let mut expired = Vec::new();
+        let mut idle = self.idle.lock();
+        while let Some((transport, created)) = idle.front() {
+            if created.elapsed() < self.max_idle() && !transport.closed() {
+                break;
+            }
+            expired.extend(idle.pop_front().map(|(transport, _)| transport));
+        }
+        drop(idle);
+        TRANSPORT_POOL_IDLE.sub(expired.len() as i64);
+        TRANSPORT_POOL_EXPIRED.inc_by(expired.len() as u64);
+    }
+}
+
+impl Drop for TransportPool {
+    fn drop(&mut self) {
+        POOLS.lock().remove(&self.router_id);
+        TRANSPORT_POOL_IDLE.sub(self.idle.get_mut().len() as i64);
+        self.wake.notify_one();
+    }
+}
+
+/// Claim an idle transport of the router's pool, `None` if it has no pool or it ran dry
+pub fn claim(router: &Router) -> Option<WebRtcTransport> {
+    let pool = POOLS.lock().get(&router.id())?.upgrade()?;
+    let transport = pool.take();
+    let outcome = match transport {
+        Some(_) => "hit",
+        None => "miss",
+    };
+    TRANSPORT_POOL_CLAIMS.with_label_values(&[outcome]).inc();
+    pool.wake.notify_one();
+    transport
+}
+
+/// Keep the pool topped up and fresh until it's dropped. Only a weak reference is held
+/// while waiting, so the pool and its transports go as soon as their room lets go
+async fn refill(pool: Weak<TransportPool>, router: Router) {
+    loop {
+        let wake = match pool.upgrade() {
+            Some(strong) => {
+                strong.expire();
+                if strong.len() >= strong.config.size {
+                    strong.wake.clone()
+                } else {
+                    drop(strong);
+                    let options = webrtc_options(&CONFIG.rtc, None);
+                    match router.create_webrtc_transport(options).await {
+                        // Closed right away if the pool was dropped in the meantime
+                        Ok(transport) => {
+                            if let Some(strong) = pool.upgrade() {
+                                strong.push(transport);
+                            }
+                        }
+                        Err(err) => {
+                            warn!("Failed to create a pooled transport: {}", err);
+                            tokio::time::sleep(REFILL_BACKOFF).await;
+                        }
+                    }
+                    continue;
+                }
+            }
+            None => return,
+        };
+        tokio::time::timeout(EXPIRY_CHECK, wake.notified())
+            .await
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    async fn filled(pool: &TransportPool, size: usize) {
+        for _ in 0..250 {
+            if pool.len() == size {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Pool wasn't refilled to {}", size);
+    }
+
+    #[tokio::test]
+    async fn claims_are_refilled_and_stale_transports_replaced() {
+        let room = testing::room(RoomSettings::default()).await;
+        let router = room.router().unwrap();
+        let config = Box::leak(Box::new(TransportPoolConfig {
+            size: 2,
+            max_idle_secs: 1,
+        }));
+        let pool = TransportPool::start_with(&router, config);
+        filled(&pool, 2).await;
+
+        let claimed = claim(&router).unwrap();
+        assert!(!claimed.closed());
+        filled(&pool, 2).await;
+
+        // Transports past their idle time aren't handed out, fresh ones replace them
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(claim(&router).is_none());
+        filled(&pool, 2).await;
+        let fresh = claim(&router).unwrap();
+        assert_ne!(fresh.id(), claimed.id());
+
+        // Nothing is claimed once the pool is gone
+        drop(pool);
+        assert!(claim(&router).is_none());
+        room.delete().await;
+    }
+}
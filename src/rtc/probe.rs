@@ -0,0 +1,313 @@
+//! Short-lived WebRTC transports clients connect to before joining a room, to find out
+//! whether media can reach them at all. They live on a router of their own, outside
+//! any room, and are closed once connected or after `probe.timeout_secs`
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use super::stats::SelectedTuple;
+use super::types::WebRtcTransportInitData;
+use super::{get_worker_pool, webrtc_options, RtcState};
+use crate::util::config::{ProbeConfig, CONFIG};
+
+lazy_static! {
+    static ref PROBES: Option<Probes> = CONFIG.probe.as_ref().map(Probes::new);
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    /// Probing isn't configured on this node
+    Unavailable,
+    /// `probe.max_probes` are running
+    Full,
+    /// `probe.max_probes_per_ip` are running from the client's address
+    AddressFull,
+    /// ID of a probe that expired, was already connected or never existed
+    NotFound(String),
+    /// The client didn't connect before the probe expired
+    TimedOut,
+    /// ICE or DTLS failed, as far as the server can tell
+    Failed,
+    Transport(String),
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Unavailable => write!(f, "Probing is not enabled on this server"),
+            ProbeError::Full => write!(f, "Too many probes are running"),
+            ProbeError::AddressFull => write!(f, "Too many probes are running from the address"),
+            ProbeError::NotFound(id) => write!(f, "Probe with ID {} not found", id),
+            ProbeError::TimedOut => write!(f, "Probe was not connected in time"),
+            ProbeError::Failed => write!(f, "Probe transport failed to connect"),
+            ProbeError::Transport(err) => write!(f, "Probe transport error: {}", err),
+        }
+    }
+}
+
+/// How the probe's transport was reached
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+    /// Candidate pair the client connected over
+    pub selected_tuple: Option<SelectedTuple>,
+    /// From the probe's creation until DTLS connected
+    pub connect_ms: u64,
+}
+
+struct Probe {
+    transport: WebRtcTransport,
+    created: Instant,
+    _slot: Slot,
+}
+
+/// Place of a running probe in `probe.max_probes`, and in `probe.max_probes_per_ip` of
+/// its address, given back when dropped
+struct Slot {
+    probes: &'static Probes,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.probes.running.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ip) = self.ip {
+            let mut by_ip = self.probes.by_ip.lock();
+            if let Some(count) = by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+pub struct Probes {
+    config: &'static ProbeConfig,
+    /// Probes created or being connected, the cap applies to both
+    running: AtomicUsize,
+    /// Probes running by client address
+    by_ip: Mutex<HashMap<IpAddr, usize>>,
+    /// Waiting for their client to connect, by transport ID
+    waiting: Mutex<HashMap<String, Probe>>,
+    /// Without codecs, probes carry no media. Replaced if its worker died
+    router: tokio::sync::Mutex<Option<Router>>,
+}
+
+/// Probes of the node, `Unavailable` unless configured
+pub fn probes() -> Result<&'static Probes, ProbeError> {
+    PROBES.as_ref().ok_or(ProbeError::Unavailable)
+}
+
+impl Probes {
+    fn new(config: &'static ProbeConfig) -> Self {
+        Probes {
+            config,
+            running: AtomicUsize::new(0),
+            by_ip: Mutex::new(HashMap::new()),
+            waiting: Mutex::new(HashMap::new()),
+            router: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Clients whose address isn't known only count against `probe.max_probes`
+    fn slot(&'static self, ip: Option<IpAddr>) -> Result<Slot, ProbeError> {
+        if let Some(ip) = ip {
+            let mut by_ip = self.by_ip.lock();
+            let count = by_ip.entry(ip).or_insert(0);
+            if *count >= self.config.max_probes_per_ip {
+                return Err(ProbeError::AddressFull);
+            }
+            *count += 1;
+        }
+        // Both counts are given back by the slot, also when the node turns out to be full
+        let slot = Slot { probes: self, ip };
+        if self.running.fetch_add(1, Ordering::Relaxed) >= self.config.max_probes {
+            return Err(ProbeError::Full);
+        }
+        Ok(slot)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs)
+    }
+
+    async fn router(&self) -> Result<Router, ProbeError> {
+        let mut router = self.router.lock().await;
+        if let Some(router) = router.as_ref().filter(|router| !router.closed()) {
+            return Ok(router.clone());
+        }
+
+        let created = get_worker_pool()
+            .get_worker()
+            .create_router(RouterOptions::default())
+            .await
+            .map_err(|err| ProbeError::Transport(err.to_string()))?;
+        *router = Some(created.clone());
+        Ok(created)
+    }
+
+    /// Create a transport for the client at `ip` to connect, closed after the timeout
+    /// unless connected first
+    pub async fn start(
+        &'static self,
+        ip: Option<IpAddr>,
+    ) -> Result<WebRtcTransportInitData, ProbeError> {
+        let slot = self.slot(ip)?;
+        let router = self.router().await?;
+        let transport = router
+            .create_webrtc_transport(webrtc_options(&CONFIG.rtc, None))
+            .await
+            .map_err(|err| ProbeError::Transport(err.to_string()))?;
+
+        let init_data = RtcState::get_webrtc_init_data(&transport);
+        let id = transport.id().to_string();
+        let created = Instant::now();
+        self.waiting.lock().insert(
+            id.clone(),
+            Probe {
+                transport,
+                created,
+                _slot: slot,
+            },
+        );
+
+        // Dropping the probe closes its transport, unless a connection attempt took it
+        let timeout = self.timeout();
+        tokio::spawn(async move {
+            tokio::time::sleep_until((created + timeout).into()).await;
+            self.waiting.lock().remove(&id);
+        });
+        Ok(init_data)
+    }
+
+    /// Connect the probe with the client's DTLS parameters and wait until DTLS
+    /// connected, within what's left of its timeout. The probe is closed either way
+    pub async fn connect(
+        &self,
+        id: &str,
+        dtls_parameters: DtlsParameters,
+    ) -> Result<ProbeResult, ProbeError> {
+        let probe = self
+            .waiting
+            .lock()
+            .remove(id)
+            .ok_or_else(|| ProbeError::NotFound(id.to_string()))?;
+        let transport = &probe.transport;
+
+        let (sender, mut states) = mpsc::unbounded_channel();
+        let _handler = transport.on_dtls_state_change(move |state| {
+            sender.send(state).ok();
+        });
+        transport
+            .connect(WebRtcTransportRemoteParameters { dtls_parameters })
+            .await
+            .map_err(|err| ProbeError::Transport(err.to_string()))?;
+
+        let deadline = probe.created + self.timeout();
+        let connected = async {
+            loop {
+                match states.recv().await {
+                    Some(DtlsState::Connected) => return Ok(()),
+                    Some(DtlsState::Failed) | Some(DtlsState::Closed) | None => {
+                        return Err(ProbeError::Failed)
+                    }
+                    Some(_) => {}
+                }
+            }
+        };
+        tokio::time::timeout_at(deadline.into(), connected)
+            .await
+            .map_err(|_| ProbeError::TimedOut)??;
+
+        let redact = CONFIG.rtc.redact_remote_addresses;
+        Ok(ProbeResult {
+            selected_tuple: transport
+                .ice_selected_tuple()
+                .map(|tuple| SelectedTuple::new(&tuple, redact)),
+            connect_ms: probe.created.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn probes_are_capped_and_expire() {
+        testing::init();
+        let config = Box::leak(Box::new(ProbeConfig {
+            max_probes: 1,
+            max_probes_per_ip: 1,
+            timeout_secs: 1,
+        }));
+        let probes: &'static Probes = Box::leak(Box::new(Probes::new(config)));
+
+        let init_data = probes.start(None).await.unwrap();
+        assert!(!init_data.ice_candidates.is_empty());
+        assert!(matches!(probes.start(None).await, Err(ProbeError::Full)));
+
+        // Nobody answers on the client's side, the probe expires and frees its slot
+        let dtls_parameters = DtlsParameters {
+            role: DtlsRole::Client,
+            fingerprints: init_data.dtls_parameters.fingerprints.clone(),
+        };
+        let id = init_data.id.to_string();
+        let result = probes.connect(&id, dtls_parameters.clone()).await;
+        assert!(matches!(result, Err(ProbeError::TimedOut)));
+        assert!(matches!(
+            probes.connect(&id, dtls_parameters).await,
+            Err(ProbeError::NotFound(_))
+        ));
+        assert_eq!(probes.running.load(Ordering::Relaxed), 0);
+
+        // Unconnected probes expire all the same
+        probes.start(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(probes.running.load(Ordering::Relaxed), 0);
+        probes.start(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn probes_are_capped_by_address() {
+        testing::init();
+        let config = Box::leak(Box::new(ProbeConfig {
+            max_probes: 3,
+            max_probes_per_ip: 2,
+            timeout_secs: 10,
+        }));
+        let probes: &'static Probes = Box::leak(Box::new(Probes::new(config)));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let slots = vec![probes.slot(Some(client)), probes.slot(Some(client))];
+        assert!(slots.iter().all(Result::is_ok));
+        assert!(matches!(
+            probes.slot(Some(client)),
+            Err(ProbeError::AddressFull)
+        ));
+        let third = probes.slot(Some(other)).unwrap();
+
+        // A full node gives back the address it counted
+        assert!(matches!(probes.slot(Some(other)), Err(ProbeError::Full)));
+        assert_eq!(probes.by_ip.lock().get(&other), Some(&1));
+        assert_eq!(probes.running.load(Ordering::Relaxed), 3);
+
+        drop(slots);
+        drop(third);
+        assert!(probes.by_ip.lock().is_empty());
+        assert_eq!(probes.running.load(Ordering::Relaxed), 0);
+        probes.slot(Some(client)).unwrap();
+    }
+}
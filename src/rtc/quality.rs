@@ -0,0 +1,208 @@
+//! Coarse quality of a connection, sampled from its transport and producer stats so the
+//! room can tell a participant with a bad connection apart from one that went quiet
+
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::local::run_unsend;
+use super::stats::StatsSource;
+use super::TransportMode;
+use crate::util::config::{QualityConfig, CONFIG};
+
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityLevel {
+    Good,
+    Ok,
+    Bad,
+}
+
+/// Worst of what was sampled from a connection at once
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QualitySample {
+    /// Fraction of packets lost, from 0 to 1
+    pub loss: f64,
+    pub rtt_ms: Option<f64>,
+    /// Bits per second sent towards the client
+    pub outgoing_bitrate: u32,
+    /// Bandwidth estimate towards the client, WebRTC transports only
+    pub available_outgoing_bitrate: Option<u32>,
+}
+
+impl QualitySample {
+    pub fn level(&self, thresholds: &QualityConfig) -> QualityLevel {
+        let rtt = self.rtt_ms.unwrap_or(0.0);
+        // Nothing to compare the estimate with while nothing is sent
+        let ratio = match self.available_outgoing_bitrate {
+            Some(available) if self.outgoing_bitrate > 0 => {
+                f64::from(available) / f64::from(self.outgoing_bitrate)
+            }
+            _ => f64::INFINITY,
+        };
+
+        if self.loss >= thresholds.bad_loss
+            || rtt >= thresholds.bad_rtt_ms
+            || ratio < thresholds.bad_bitrate_ratio
+        {
+            QualityLevel::Bad
+        } else if self.loss >= thresholds.ok_loss
+            || rtt >= thresholds.ok_rtt_ms
+            || ratio < thresholds.ok_bitrate_ratio
+        {
+            QualityLevel::Ok
+        } else {
+            QualityLevel::Good
+        }
+    }
+}
+
+pub type QualityReceiver = UnboundedReceiver<QualitySample>;
+
+/// When a connection's quality is sampled next. Samples are taken in a task of their own
+/// and handed out through the receiver
+pub struct QualityMonitor {
+    interval: Duration,
+    next: Instant,
+    /// Set while a sample is being taken, ticks in the meantime are skipped
+    sampling: Arc<AtomicBool>,
+    sender: UnboundedSender<QualitySample>,
+}
+
+impl QualityMonitor {
+    pub fn new(interval: Duration) -> (Self, QualityReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let monitor = QualityMonitor {
+            interval,
+            next: Instant::now() + interval,
+            sampling: Arc::new(AtomicBool::new(false)),
+            sender,
+        };
+        (monitor, receiver)
+    }
+
+    /// As configured, `None` if sampling is turned off
+    pub fn from_config() -> Option<(Self, QualityReceiver)> {
+        Some(QualityMonitor::new(CONFIG.quality.interval()?))
+    }
+
+    pub fn next_tick(&self) -> Instant {
+        self.next
+    }
+
+    /// Schedule the next tick and sample in the background, unless the previous sample
+    /// is still being taken
+    pub fn tick<F>(&mut self, sample: F)
+    where
+        F: Future<Output = Option<QualitySample>> + Send + 'static,
+    {
+        self.next = Instant::now() + self.interval;
+        if self.sampling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let sampling = self.sampling.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            if let Some(sample) = sample.await {
+                sender.send(sample).ok();
+            }
+            sampling.store(false, Ordering::Release);
+        });
+    }
+}
+
+impl StatsSource {
+    /// Ask the worker for the loss and round trip times of the given producers, and the
+    /// bitrates of the connection's transports
+    pub async fn sample_quality(self, producers: Vec<Producer>) -> Result<QualitySample, ()> {
+        let transport_mode = self.transport_mode;
+        run_unsend(move || async move {
+            let mut sample = transport_sample(&transport_mode).await?;
+            for producer in producers {
+                for stream in producer.get_stats().await.map_err(|_| ())? {
+                    sample.loss = sample.loss.max(f64::from(stream.fraction_lost) / 256.0);
+                    if let Some(rtt) = stream.round_trip_time {
+                        let rtt = f64::from(rtt);
+                        sample.rtt_ms = Some(sample.rtt_ms.map_or(rtt, |worst| worst.max(rtt)));
+                    }
+                }
+            }
+            Ok(sample)
+        })
+        .await
+        .map_err(|_| ())?
+    }
+}
+
+/// Only the transport sending to the client has a bandwidth estimate worth comparing
+async fn transport_sample(transport_mode: &TransportMode) -> Result<QualitySample, ()> {
+    let mut sample = QualitySample::default();
+    let transport = match transport_mode {
+        TransportMode::SplitWebRtc(_, recv) => recv,
+        TransportMode::CombinedWebRtc(transport) | TransportMode::RecvWebRtc(transport) => {
+            transport
+        }
+        TransportMode::CombinedRtp(_) => return Ok(sample),
+    };
+
+    for stat in transport.get_stats().await.map_err(|_| ())? {
+        sample.outgoing_bitrate += stat.send_bitrate;
+        if let Some(available) = stat.available_outgoing_bitrate {
+            sample.available_outgoing_bitrate =
+                Some(sample.available_outgoing_bitrate.unwrap_or(0) + available);
+        }
+    }
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_map_to_levels() {
+        let thresholds = QualityConfig::default();
+        let good = QualitySample {
+            loss: 0.0,
+            rtt_ms: Some(40.0),
+            outgoing_bitrate: 500_000,
+            available_outgoing_bitrate: Some(1_000_000),
+        };
+        assert_eq!(good.level(&thresholds), QualityLevel::Good);
+
+        let lossy = QualitySample {
+            loss: 0.05,
+            ..good.clone()
+        };
+        assert_eq!(lossy.level(&thresholds), QualityLevel::Ok);
+
+        let slow = QualitySample {
+            rtt_ms: Some(800.0),
+            ..good.clone()
+        };
+        assert_eq!(slow.level(&thresholds), QualityLevel::Bad);
+
+        let congested = QualitySample {
+            available_outgoing_bitrate: Some(200_000),
+            ..good.clone()
+        };
+        assert_eq!(congested.level(&thresholds), QualityLevel::Bad);
+
+        // Nothing sent yet, so the estimate says nothing
+        let idle = QualitySample {
+            outgoing_bitrate: 0,
+            available_outgoing_bitrate: Some(0),
+            ..good
+        };
+        assert_eq!(idle.level(&thresholds), QualityLevel::Good);
+    }
+}
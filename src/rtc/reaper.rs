@@ -0,0 +1,161 @@
+//! Consumers a client stopped using without closing them, such as when it missed a
+//! `ConsumerClosed` event. Every interval the packets each consumer sent so far are
+//! counted, those that sent none for `reaping.inactive_after` seconds are closed.
+//! Consumers paused by the client or by their producer are never reaped
+
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use mediasoup::consumer::ConsumerStats;
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::local::run_unsend;
+use crate::util::config::CONFIG;
+
+/// Packets sent so far by each consumer, by consumer ID
+pub type PacketCounts = Vec<(String, u64)>;
+pub type ActivityReceiver = UnboundedReceiver<PacketCounts>;
+
+/// Last time a consumer was seen sending, or excused from sending
+#[derive(Clone, Copy, Debug)]
+pub struct Activity {
+    packets: u64,
+    since: Instant,
+}
+
+impl Activity {
+    pub fn new(now: Instant) -> Self {
+        Activity {
+            packets: 0,
+            since: now,
+        }
+    }
+
+    /// Note the consumer's packet count, `true` once it's been idle for `inactive_after`.
+    /// A paused consumer is idle on purpose and starts over
+    pub fn record(
+        &mut self,
+        packets: u64,
+        paused: bool,
+        now: Instant,
+        inactive_after: Duration,
+    ) -> bool {
+        if paused || packets > self.packets {
+            self.packets = packets;
+            self.since = now;
+            return false;
+        }
+        now.duration_since(self.since) >= inactive_after
+    }
+}
+
+/// When a connection's consumers are counted next. Counts are taken in a task of their
+/// own and handed out through the receiver, like quality samples
+pub struct ConsumerReaper {
+    interval: Duration,
+    inactive_after: Duration,
+    next: Instant,
+    /// Set while counts are being taken, ticks in the meantime are skipped
+    counting: Arc<AtomicBool>,
+    sender: UnboundedSender<PacketCounts>,
+}
+
+impl ConsumerReaper {
+    pub fn new(interval: Duration, inactive_after: Duration) -> (Self, ActivityReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reaper = ConsumerReaper {
+            interval,
+            inactive_after,
+            next: Instant::now() + interval,
+            counting: Arc::new(AtomicBool::new(false)),
+            sender,
+        };
+        (reaper, receiver)
+    }
+
+    /// As configured, `None` if reaping is turned off
+    pub fn from_config() -> Option<(Self, ActivityReceiver)> {
+        let reaping = &CONFIG.reaping;
+        Some(ConsumerReaper::new(
+            reaping.interval()?,
+            reaping.inactive_after(),
+        ))
+    }
+
+    pub fn inactive_after(&self) -> Duration {
+        self.inactive_after
+    }
+
+    pub fn next_tick(&self) -> Instant {
+        self.next
+    }
+
+    /// Schedule the next tick and count in the background, unless the previous counts
+    /// are still being taken
+    pub fn tick<F>(&mut self, counts: F)
+    where
+        F: Future<Output = Option<PacketCounts>> + Send + 'static,
+    {
+        self.next = Instant::now() + self.interval;
+        if self.counting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let counting = self.counting.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            if let Some(counts) = counts.await {
+                sender.send(counts).ok();
+            }
+            counting.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Ask the worker how many packets each consumer sent so far. Consumers closed in the
+/// meantime are left out
+pub async fn packet_counts(consumers: Vec<Consumer>) -> Option<PacketCounts> {
+    run_unsend(move || async move {
+        let mut counts = Vec::with_capacity(consumers.len());
+        for consumer in consumers {
+            let stat = match consumer.get_stats().await {
+                Ok(ConsumerStats::JustConsumer((stat,)))
+                | Ok(ConsumerStats::WithProducer((stat, _))) => stat,
+                Err(_) => continue,
+            };
+            counts.push((consumer.id().to_string(), stat.packet_count));
+        }
+        counts
+    })
+    .await
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_idle_unpaused_consumers_are_reaped() {
+        let start = Instant::now();
+        let inactive_after = Duration::from_secs(300);
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut activity = Activity::new(start);
+
+        assert!(!activity.record(40, false, at(60), inactive_after));
+        assert!(!activity.record(40, false, at(300), inactive_after));
+        assert!(activity.record(40, false, at(360), inactive_after));
+
+        // Packets flowing again, or a pause, start the count over
+        assert!(!activity.record(41, false, at(420), inactive_after));
+        assert!(!activity.record(41, true, at(700), inactive_after));
+        assert!(!activity.record(41, true, at(1500), inactive_after));
+        assert!(!activity.record(41, false, at(1700), inactive_after));
+        assert!(activity.record(41, false, at(1800), inactive_after));
+    }
+}
@@ -0,0 +1,404 @@
+//! Server-side recording. Each producer of a room is consumed onto a local plain
+//! transport and written to its own file by an FFmpeg child process. Rooms requiring
+//! consent only have the producers of users who granted it recorded, and a manifest
+//! written as the recording stops lists the tracks and everyone's answer
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use chrono::{DateTime, Utc};
+use mediasoup::prelude::*;
+use serde::Serialize;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot};
+
+use super::egress::{self, path_safe, EgressError, EgressTrack};
+use crate::state::user::ProduceType;
+use crate::util::config::RecordingConfig;
+
+#[derive(Debug)]
+pub enum RecordingError {
+    /// No recording directory is configured on this node
+    Unavailable,
+    AlreadyRecording,
+    NotRecording,
+    /// Recording was turned off in the room's settings
+    NotAllowed,
+    /// The user wasn't asked for its consent to being recorded
+    ConsentNotRequested,
+    Egress(EgressError),
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Unavailable => write!(f, "Recording is not enabled on this server"),
+            RecordingError::AlreadyRecording => write!(f, "Room is already being recorded"),
+            RecordingError::NotRecording => write!(f, "Room is not being recorded"),
+            RecordingError::NotAllowed => write!(f, "Recording is not allowed in this room"),
+            RecordingError::ConsentNotRequested => {
+                write!(f, "Consent to being recorded wasn't requested")
+            }
+            RecordingError::Egress(err) => write!(f, "Recording failed: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> Self {
+        RecordingError::Egress(err.into())
+    }
+}
+
+impl From<EgressError> for RecordingError {
+    fn from(err: EgressError) -> Self {
+        RecordingError::Egress(err)
+    }
+}
+
+/// Producer to write to disk
+pub struct TrackSource {
+    pub user_id: String,
+    pub produce_type: ProduceType,
+    pub producer_id: ProducerId,
+}
+
+/// A participant's answer to being recorded, those who didn't answer yet aren't
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Consent {
+    Pending,
+    Granted,
+    Declined,
+}
+
+/// What a recording holds, written to `manifest.json` in its directory as it stops
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest<'a> {
+    room_id: &'a str,
+    started_at: DateTime<Utc>,
+    stopped_at: DateTime<Utc>,
+    /// Files by the user and type of producer they're of
+    tracks: &'a BTreeMap<String, ManifestTrack>,
+    /// Answer of everyone asked, absent when the room doesn't require consent. Users
+    /// who declined or never answered have no tracks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consent: Option<BTreeMap<&'a str, Consent>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestTrack {
+    user_id: String,
+    produce_type: ProduceType,
+}
+
+enum RecorderCommand {
+    Add(TrackSource),
+    ProducerClosed(ProducerId),
+    Stop(oneshot::Sender<()>),
+}
+
+/// A running recording of a room, producers added to it are recorded until they
+/// close or the recording is stopped
+pub struct Recording {
+    directory: PathBuf,
+    queue: mpsc::UnboundedSender<RecorderCommand>,
+    room_id: String,
+    started_at: DateTime<Utc>,
+    /// Tracks by file name, for the manifest
+    tracks: BTreeMap<String, ManifestTrack>,
+    /// Everyone asked for consent, `None` unless the room requires it
+    consents: Option<HashMap<String, Consent>>,
+}
+
+impl Recording {
+    /// Create the directory of a new recording and start the task handling its tracks
+    pub fn start(
+        router: Router,
+        room_id: &str,
+        config: &RecordingConfig,
+        require_consent: bool,
+    ) -> Result<Self, RecordingError> {
+        let started_at = Utc::now();
+        let directory = Path::new(&config.directory)
+            .join(path_safe(room_id))
+            .join(started_at.format("%Y%m%dT%H%M%SZ").to_string());
+        std::fs::create_dir_all(&directory)?;
+
+        let (queue, receiver) = mpsc::unbounded_channel();
+        let recorder = Recorder {
+            router,
+            directory: directory.clone(),
+            ffmpeg: config.ffmpeg.clone(),
+            queue: queue.clone(),
+            tracks: HashMap::new(),
+        };
+        tokio::spawn(recorder.run(receiver));
+
+        info!("Recording room {} to {}", room_id, directory.display());
+        Ok(Recording {
+            directory,
+            queue,
+            room_id: room_id.to_string(),
+            started_at,
+            tracks: BTreeMap::new(),
+            consents: match require_consent {
+                true => Some(HashMap::new()),
+                false => None,
+            },
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Start recording a producer, picked up by the recording task in order. Held back
+    /// unless its user consented, where the room requires it
+    pub fn add(&mut self, source: TrackSource) {
+        if !self.consented(&source.user_id) {
+            return;
+        }
+        let track = ManifestTrack {
+            user_id: source.user_id.clone(),
+            produce_type: source.produce_type,
+        };
+        self.tracks
+            .insert(format!("{}.webm", track_name(&source)), track);
+        self.queue.send(RecorderCommand::Add(source)).ok();
+    }
+
+    /// Stop recording a producer, its file is kept
+    pub fn remove(&self, producer_id: ProducerId) {
+        self.queue
+            .send(RecorderCommand::ProducerClosed(producer_id))
+            .ok();
+    }
+
+    fn consented(&self, user_id: &str) -> bool {
+        match &self.consents {
+            Some(consents) => consents.get(user_id) == Some(&Consent::Granted),
+            None => true,
+        }
+    }
+
+    /// Whether the user has yet to answer if it consents to being recorded, asking it
+    /// if it never was. Always `false` where the room doesn't require consent
+    pub fn ask(&mut self, user_id: &str) -> bool {
+        match &mut self.consents {
+            Some(consents) => {
+                let consent = consents
+                    .entry(user_id.to_string())
+                    .or_insert(Consent::Pending);
+                *consent == Consent::Pending
+            }
+            None => false,
+        }
+    }
+
+    /// Record the user's answer to being recorded. It may change its mind, the caller
+    /// adds or removes its producers
+    pub fn answer(&mut self, user_id: &str, granted: bool) -> Result<(), RecordingError> {
+        let consent = self
+            .consents
+            .as_mut()
+            .and_then(|consents| consents.get_mut(user_id))
+            .ok_or(RecordingError::ConsentNotRequested)?;
+        *consent = match granted {
+            true => Consent::Granted,
+            false => Consent::Declined,
+        };
+        Ok(())
+    }
+
+    fn manifest(&self, stopped_at: DateTime<Utc>) -> Manifest<'_> {
+        Manifest {
+            room_id: &self.room_id,
+            started_at: self.started_at,
+            stopped_at,
+            tracks: &self.tracks,
+            consent: self.consents.as_ref().map(|consents| {
+                consents
+                    .iter()
+                    .map(|(user_id, consent)| (user_id.as_str(), *consent))
+                    .collect()
+            }),
+        }
+    }
+
+    /// Stop every track, waiting until their files are flushed and closed, then write
+    /// the manifest
+    pub async fn stop(self) {
+        let (sender, receiver) = oneshot::channel();
+        if self.queue.send(RecorderCommand::Stop(sender)).is_ok() {
+            receiver.await.ok();
+        }
+
+        let manifest = match serde_json::to_vec_pretty(&self.manifest(Utc::now())) {
+            Ok(manifest) => manifest,
+            Err(_) => return,
+        };
+        let path = self.directory.join("manifest.json");
+        if let Err(err) = tokio::fs::write(&path, manifest).await {
+            warn!(
+                "Failed to write recording manifest {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Files of a track are named after its user and type of producer
+fn track_name(source: &TrackSource) -> String {
+    format!("{}-{}", path_safe(&source.user_id), source.produce_type)
+}
+
+/// Producer being written to disk
+struct Track {
+    egress: EgressTrack,
+    process: Child,
+}
+
+struct Recorder {
+    router: Router,
+    directory: PathBuf,
+    ffmpeg: String,
+    queue: mpsc::UnboundedSender<RecorderCommand>,
+    tracks: HashMap<ProducerId, Track>,
+}
+
+impl Recorder {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<RecorderCommand>) {
+        while let Some(command) = commands.recv().await {
+            match command {
+                RecorderCommand::Add(source) => {
+                    if self.tracks.contains_key(&source.producer_id) {
+                        continue;
+                    }
+
+                    let producer_id = source.producer_id;
+                    match self.start_track(&source).await {
+                        Ok(track) => {
+                            self.tracks.insert(producer_id, track);
+                        }
+                        Err(err) => warn!(
+                            "Failed to record {} of user {}: {}",
+                            source.produce_type, source.user_id, err
+                        ),
+                    }
+                }
+                RecorderCommand::ProducerClosed(producer_id) => {
+                    if let Some(track) = self.tracks.remove(&producer_id) {
+                        stop_track(track).await;
+                    }
+                }
+                RecorderCommand::Stop(done) => {
+                    let tracks = self.tracks.drain().map(|(_, track)| stop_track(track));
+                    futures::future::join_all(tracks).await;
+                    done.send(()).ok();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn start_track(&self, source: &TrackSource) -> Result<Track, RecordingError> {
+        let egress = EgressTrack::create(&self.router, source.producer_id).await?;
+        let sdp = egress::session_description(&[egress.media_section()?]);
+        let name = track_name(source);
+        let sdp_path = self.directory.join(format!("{}.sdp", name));
+        tokio::fs::write(&sdp_path, sdp).await?;
+
+        let process = Command::new(&self.ffmpeg)
+            .args(&["-loglevel", "error", "-protocol_whitelist", "file,rtp,udp"])
+            .arg("-i")
+            .arg(&sdp_path)
+            .args(&["-map", "0", "-c", "copy", "-f", "webm", "-y"])
+            .arg(self.directory.join(format!("{}.webm", name)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let queue = self.queue.clone();
+        let producer_id = source.producer_id;
+        egress
+            .consumer
+            .on_producer_close(move || {
+                queue
+                    .send(RecorderCommand::ProducerClosed(producer_id))
+                    .ok();
+            })
+            .detach();
+
+        tokio::time::sleep(egress::STARTUP_DELAY).await;
+        egress.start().await?;
+
+        debug!(
+            "Recording {} of user {}",
+            source.produce_type, source.user_id
+        );
+        Ok(Track { egress, process })
+    }
+}
+
+/// Ask FFmpeg to finish the file, the track's consumer closes once it's done
+async fn stop_track(mut track: Track) {
+    track.egress.consumer.pause().await.ok();
+    egress::stop_process(&mut track.process).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn manifests_list_everyone_asked_for_consent() {
+        let room = testing::room(RoomSettings::default()).await;
+        let config = RecordingConfig {
+            directory: std::env::temp_dir()
+                .join(format!("vortex-{}", rand::random::<u64>()))
+                .to_string_lossy()
+                .into_owned(),
+            ffmpeg: "ffmpeg".to_string(),
+        };
+        let mut recording =
+            Recording::start(room.router().unwrap(), "room", &config, true).unwrap();
+
+        assert!(matches!(
+            recording.answer("alice", true),
+            Err(RecordingError::ConsentNotRequested)
+        ));
+        assert!(recording.ask("alice"));
+        assert!(recording.ask("bob"));
+        // Still waiting when asked again, as after reconnecting
+        assert!(recording.ask("alice"));
+        recording.answer("alice", true).unwrap();
+        recording.answer("bob", false).unwrap();
+        assert!(!recording.ask("alice"));
+        assert!(!recording.ask("bob"));
+        assert!(recording.ask("carol"));
+
+        let directory = recording.directory().to_path_buf();
+        recording.stop().await;
+        let manifest = std::fs::read(directory.join("manifest.json")).unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest["roomId"], "room");
+        assert_eq!(manifest["tracks"], serde_json::json!({}));
+        assert_eq!(
+            manifest["consent"],
+            serde_json::json!({ "alice": "granted", "bob": "declined", "carol": "pending" })
+        );
+        std::fs::remove_dir_all(&config.directory).ok();
+        room.delete().await;
+    }
+}
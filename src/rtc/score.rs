@@ -0,0 +1,136 @@
+//! Scores mediasoup computes for the RTP streams of a connection's producers and
+//! consumers, forwarded to clients that asked for them to show connection quality
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::state::user::ProduceType;
+
+/// Most often the score of a single producer or consumer is sent
+pub const SCORE_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ScoreSource {
+    Producer(ProduceType),
+    /// Consumer ID
+    Consumer(String),
+}
+
+/// Score from 0 to 10, 10 being a perfectly healthy stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreUpdate {
+    pub source: ScoreSource,
+    pub score: u8,
+}
+
+pub type ScoreReceiver = UnboundedReceiver<ScoreUpdate>;
+
+/// Forward the producer's score, that of its best stream when simulcasting.
+/// Scores sent after the connection closed are dropped
+pub fn watch_producer(
+    producer: &Producer,
+    produce_type: ProduceType,
+    sender: UnboundedSender<ScoreUpdate>,
+) {
+    producer
+        .on_score(move |scores| {
+            if let Some(score) = scores.iter().map(|score| score.score).max() {
+                let source = ScoreSource::Producer(produce_type);
+                sender.send(ScoreUpdate { source, score }).ok();
+            }
+        })
+        .detach();
+}
+
+pub fn watch_consumer(consumer: &Consumer, sender: UnboundedSender<ScoreUpdate>) {
+    let source = ScoreSource::Consumer(consumer.id().to_string());
+    consumer
+        .on_score(move |score| {
+            let update = ScoreUpdate {
+                source: source.clone(),
+                score: score.score,
+            };
+            sender.send(update).ok();
+        })
+        .detach();
+}
+
+/// Holds back scores so each source is sent at most once per `SCORE_INTERVAL`,
+/// only its latest score is kept in between
+#[derive(Default)]
+pub struct ScoreThrottle {
+    pending: HashMap<ScoreSource, u8>,
+    last_flush: Option<Instant>,
+}
+
+impl ScoreThrottle {
+    pub fn offer(&mut self, update: ScoreUpdate) {
+        self.pending.insert(update.source, update.score);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When pending scores may be sent
+    pub fn next_flush(&self) -> Instant {
+        match self.last_flush {
+            Some(last_flush) => last_flush + SCORE_INTERVAL,
+            None => Instant::now(),
+        }
+    }
+
+    pub fn flush(&mut self) -> Vec<ScoreUpdate> {
+        self.last_flush = Some(Instant::now());
+        self.pending
+            .drain()
+            .map(|(source, score)| ScoreUpdate { source, score })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_score_is_sent() {
+        let mut throttle = ScoreThrottle::default();
+        assert!(throttle.next_flush() <= Instant::now());
+
+        let producer = ScoreSource::Producer(ProduceType::Audio);
+        let consumer = ScoreSource::Consumer("consumer".to_string());
+        for score in [10, 7, 4].iter() {
+            throttle.offer(ScoreUpdate {
+                source: producer.clone(),
+                score: *score,
+            });
+        }
+        throttle.offer(ScoreUpdate {
+            source: consumer.clone(),
+            score: 9,
+        });
+
+        let mut sent = throttle.flush();
+        sent.sort_by_key(|update| update.score);
+        assert_eq!(
+            sent,
+            vec![
+                ScoreUpdate {
+                    source: producer,
+                    score: 4
+                },
+                ScoreUpdate {
+                    source: consumer,
+                    score: 9
+                },
+            ]
+        );
+
+        assert!(throttle.is_empty());
+        assert!(throttle.next_flush() > Instant::now() + SCORE_INTERVAL / 2);
+    }
+}
@@ -0,0 +1,707 @@
+//! End to end check of the node's media path, run with `--self-test` before users are
+//! pointed at a new deployment or through the management API. On a router of its own,
+//! outside any room, it checks that:
+//!
+//! - a worker can be given a router
+//! - the WebRTC transport's UDP candidates answer ICE connectivity checks at the
+//!   addresses clients are given, announced IP and port range included
+//! - two WebRTC transports complete a DTLS handshake with each other, one of them in
+//!   the client's role. Both are ICE lite, so the check acts as the controlling agent
+//!   of each and relays what they send between them
+//! - Opus frames produced over one local plain transport are consumed on another
+//! - the worker's stats account for what went through
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac, NewMac};
+use mediasoup::data_structures::TransportProtocol;
+use mediasoup::prelude::*;
+use serde::Serialize;
+use sha1::Sha1;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use super::egress::egress_capabilities;
+use super::local::run_unsend;
+use super::playback::{rtp_parameters, PAYLOAD_TYPE};
+use super::{create_opus_codec, get_worker_pool, webrtc_options};
+use crate::util::config::CONFIG;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_FINGERPRINT_XOR: u32 = 0x5354_554e;
+/// Our side of the ICE username, the server only checks its own
+const LOCAL_UFRAG: &str = "selftest";
+/// Priority of a host candidate, as RFC 8445 computes it
+const LOCAL_PRIORITY: u32 = (126 << 24) | (65535 << 8) | 255;
+/// Binding requests sent to each candidate before it counts as unreachable
+const STUN_ATTEMPTS: u32 = 5;
+const STUN_RETRY: Duration = Duration::from_millis(300);
+/// How long both transports have to complete the DTLS handshake once ICE connected
+const DTLS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Frames sent through the loopback, a second of audio
+const RTP_PACKETS: u16 = 50;
+const RTP_INTERVAL: Duration = Duration::from_millis(20);
+/// How long the last packets are waited for once all were sent
+const RTP_GRACE: Duration = Duration::from_secs(1);
+/// Of the frames the check produces
+const SSRC: u32 = 0x5e1f_7e57;
+/// 20ms of Opus silence, the worker forwards payloads without decoding them
+const OPUS_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Worker,
+    Ice,
+    Dtls,
+    Rtp,
+    Stats,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// Couldn't be run, because an earlier check failed or it doesn't apply here
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckResult {
+    pub check: Check,
+    pub status: CheckStatus,
+    /// Why the check failed or was skipped
+    pub reason: Option<String>,
+}
+
+impl CheckResult {
+    fn passed(check: Check) -> Self {
+        CheckResult {
+            check,
+            status: CheckStatus::Passed,
+            reason: None,
+        }
+    }
+
+    fn failed(check: Check, reason: String) -> Self {
+        CheckResult {
+            check,
+            status: CheckStatus::Failed,
+            reason: Some(reason),
+        }
+    }
+
+    fn skipped(check: Check, reason: &str) -> Self {
+        CheckResult {
+            check,
+            status: CheckStatus::Skipped,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    fn from_result(check: Check, result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => CheckResult::passed(check),
+            Err(reason) => CheckResult::failed(check, reason),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestReport {
+    /// Whether no check failed
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+    pub duration_ms: u64,
+}
+
+/// Run every check, tearing down what they created before returning
+pub async fn run() -> SelfTestReport {
+    let started = Instant::now();
+    let mut checks = Vec::new();
+
+    let mut options = RouterOptions::default();
+    options.media_codecs = vec![create_opus_codec(2)];
+    match get_worker_pool().get_worker().create_router(options).await {
+        Ok(router) => {
+            checks.push(CheckResult::passed(Check::Worker));
+            checks.push(check_ice(&router).await);
+            checks.push(CheckResult::from_result(
+                Check::Dtls,
+                dtls_handshake(&router).await,
+            ));
+            checks.extend(check_media(&router).await);
+        }
+        Err(err) => {
+            checks.push(CheckResult::failed(Check::Worker, err.to_string()));
+            for check in [Check::Ice, Check::Dtls, Check::Rtp, Check::Stats].iter() {
+                checks.push(CheckResult::skipped(*check, "No router to test on"));
+            }
+        }
+    }
+
+    SelfTestReport {
+        passed: checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Failed),
+        checks,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Send an ICE connectivity check to each UDP candidate of a WebRTC transport, as a
+/// client would. TCP candidates are left out
+async fn check_ice(router: &Router) -> CheckResult {
+    let transport = match router
+        .create_webrtc_transport(webrtc_options(&CONFIG.rtc, None))
+        .await
+    {
+        Ok(transport) => transport,
+        Err(err) => {
+            return CheckResult::failed(Check::Ice, format!("ICE failed, no transport: {}", err))
+        }
+    };
+    let password = transport.ice_parameters().password.clone();
+    let username = format!(
+        "{}:{}",
+        transport.ice_parameters().username_fragment,
+        LOCAL_UFRAG
+    );
+
+    let candidates: Vec<_> = transport
+        .ice_candidates()
+        .iter()
+        .filter(|candidate| candidate.protocol == TransportProtocol::Udp)
+        .map(|candidate| SocketAddr::new(candidate.ip, candidate.port))
+        .collect();
+    if candidates.is_empty() {
+        return CheckResult::skipped(Check::Ice, "UDP is disabled, TCP candidates aren't checked");
+    }
+
+    for candidate in candidates {
+        let local = match candidate {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let result = match UdpSocket::bind(local).await {
+            Ok(socket) => binding(&socket, candidate, &username, &password).await,
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = result {
+            let reason = format!("ICE failed for candidate {}: {}", candidate, err);
+            return CheckResult::failed(Check::Ice, reason);
+        }
+    }
+    CheckResult::passed(Check::Ice)
+}
+
+/// Exchange a STUN binding request and its success response with a candidate. The pair
+/// is nominated, the transport sends from then on to the socket's address
+async fn binding(
+    socket: &UdpSocket,
+    candidate: SocketAddr,
+    username: &str,
+    password: &str,
+) -> Result<(), String> {
+    let transaction_id = rand::random::<[u8; 12]>();
+    let request = binding_request(transaction_id, username, password);
+    let mut buffer = [0u8; 1500];
+    for _ in 0..STUN_ATTEMPTS {
+        socket
+            .send_to(&request, candidate)
+            .await
+            .map_err(|err| err.to_string())?;
+        let deadline = tokio::time::Instant::now() + STUN_RETRY;
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+        {
+            let (length, _) = received.map_err(|err| err.to_string())?;
+            match binding_response(&buffer[..length], &transaction_id) {
+                Some(true) => return Ok(()),
+                Some(false) => return Err("the connectivity check was refused".to_string()),
+                None => {}
+            }
+        }
+    }
+    Err("no answer to connectivity checks".to_string())
+}
+
+/// Binding request as an ICE controlling agent nominating the pair, signed with the
+/// server's ICE password
+fn binding_request(transaction_id: [u8; 12], username: &str, password: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(128);
+    message.extend_from_slice(&0x0001u16.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(&transaction_id);
+
+    stun_attribute(&mut message, 0x0006, username.as_bytes());
+    stun_attribute(&mut message, 0x0024, &LOCAL_PRIORITY.to_be_bytes());
+    stun_attribute(&mut message, 0x802a, &rand::random::<u64>().to_be_bytes());
+    stun_attribute(&mut message, 0x0025, &[]);
+
+    // Each covers the message before it, with a length that already counts it
+    set_stun_length(&mut message, 24);
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(password.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(&message);
+    stun_attribute(&mut message, 0x0008, &mac.finalize().into_bytes());
+
+    set_stun_length(&mut message, 8);
+    let fingerprint = crc32(&message) ^ STUN_FINGERPRINT_XOR;
+    stun_attribute(&mut message, 0x8028, &fingerprint.to_be_bytes());
+    message
+}
+
+fn stun_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    let padding = (4 - value.len() % 4) % 4;
+    message.extend(std::iter::repeat(0).take(padding));
+}
+
+/// Length of the attributes so far and of those still to be added
+fn set_stun_length(message: &mut [u8], pending: usize) {
+    let length = (message.len() - 20 + pending) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// Whether a packet answers the request successfully, `None` if it's no answer to it
+fn binding_response(packet: &[u8], transaction_id: &[u8; 12]) -> Option<bool> {
+    if packet.len() < 20
+        || packet[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || packet[8..20] != transaction_id[..]
+    {
+        return None;
+    }
+    match u16::from_be_bytes([packet[0], packet[1]]) {
+        0x0101 => Some(true),
+        0x0111 => Some(false),
+        _ => None,
+    }
+}
+
+/// CRC-32 of the STUN fingerprint, the one of ISO HDLC
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+/// Connect two WebRTC transports listening on localhost to each other, and wait for
+/// both to have completed the DTLS handshake
+async fn dtls_handshake(router: &Router) -> Result<(), String> {
+    let client = localhost_transport(router).await?;
+    let server = localhost_transport(router).await?;
+    let client_socket = UdpSocket::bind((LOCALHOST, 0))
+        .await
+        .map_err(|err| err.to_string())?;
+    let server_socket = UdpSocket::bind((LOCALHOST, 0))
+        .await
+        .map_err(|err| err.to_string())?;
+    let client_candidate = nominate(&client, &client_socket).await?;
+    let server_candidate = nominate(&server, &server_socket).await?;
+
+    let (client_sender, mut client_states) = mpsc::unbounded_channel();
+    let _client_handler = client.on_dtls_state_change(move |state| {
+        client_sender.send(state).ok();
+    });
+    let (server_sender, mut server_states) = mpsc::unbounded_channel();
+    let _server_handler = server.on_dtls_state_change(move |state| {
+        server_sender.send(state).ok();
+    });
+
+    // The role each is given is the other's
+    let server_dtls = DtlsParameters {
+        role: DtlsRole::Client,
+        fingerprints: client.dtls_parameters().fingerprints.clone(),
+    };
+    server
+        .connect(WebRtcTransportRemoteParameters {
+            dtls_parameters: server_dtls,
+        })
+        .await
+        .map_err(|err| format!("DTLS failed, connecting the server's side: {}", err))?;
+    let client_dtls = DtlsParameters {
+        role: DtlsRole::Server,
+        fingerprints: server.dtls_parameters().fingerprints.clone(),
+    };
+    client
+        .connect(WebRtcTransportRemoteParameters {
+            dtls_parameters: client_dtls,
+        })
+        .await
+        .map_err(|err| format!("DTLS failed, connecting the client's side: {}", err))?;
+
+    let handshake = async {
+        tokio::try_join!(
+            dtls_connected(&mut client_states, "client"),
+            dtls_connected(&mut server_states, "server"),
+        )
+    };
+    let relayed = relay(
+        (&client_socket, client_candidate),
+        (&server_socket, server_candidate),
+    );
+    tokio::select! {
+        result = tokio::time::timeout(DTLS_TIMEOUT, handshake) => match result {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(format!(
+                "DTLS failed, the handshake didn't complete within {}s",
+                DTLS_TIMEOUT.as_secs()
+            )),
+        },
+        err = relayed => Err(format!("DTLS failed, relaying the handshake: {}", err)),
+    }
+}
+
+/// Transport of the DTLS check, on localhost whatever the configured IPs, which the ICE
+/// check covers
+async fn localhost_transport(router: &Router) -> Result<WebRtcTransport, String> {
+    let listen_ip = TransportListenIp {
+        ip: LOCALHOST,
+        announced_ip: None,
+    };
+    let mut options = WebRtcTransportOptions::new(TransportListenIps::new(listen_ip));
+    options.enable_udp = true;
+    options.enable_tcp = false;
+    router
+        .create_webrtc_transport(options)
+        .await
+        .map_err(|err| format!("DTLS failed, no transport: {}", err))
+}
+
+/// Nominate the transport's UDP candidate from the socket, returning the candidate
+async fn nominate(transport: &WebRtcTransport, socket: &UdpSocket) -> Result<SocketAddr, String> {
+    let candidate = transport
+        .ice_candidates()
+        .iter()
+        .find(|candidate| candidate.protocol == TransportProtocol::Udp)
+        .map(|candidate| SocketAddr::new(candidate.ip, candidate.port))
+        .ok_or_else(|| "DTLS failed, the transport has no UDP candidate".to_string())?;
+    let parameters = transport.ice_parameters();
+    let username = format!("{}:{}", parameters.username_fragment, LOCAL_UFRAG);
+    binding(socket, candidate, &username, &parameters.password)
+        .await
+        .map_err(|err| format!("DTLS failed, ICE didn't connect: {}", err))?;
+    Ok(candidate)
+}
+
+/// Until one of the transports connected, or its handshake failed
+async fn dtls_connected(
+    states: &mut mpsc::UnboundedReceiver<DtlsState>,
+    side: &str,
+) -> Result<(), String> {
+    loop {
+        match states.recv().await {
+            Some(DtlsState::Connected) => return Ok(()),
+            Some(DtlsState::Failed) | Some(DtlsState::Closed) | None => {
+                return Err(format!("DTLS failed on the {}'s side", side))
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// Forward what each transport sends to its socket on to the other transport, until
+/// either socket fails
+async fn relay(
+    (a, a_candidate): (&UdpSocket, SocketAddr),
+    (b, b_candidate): (&UdpSocket, SocketAddr),
+) -> String {
+    let mut a_buffer = [0u8; 2048];
+    let mut b_buffer = [0u8; 2048];
+    loop {
+        let result = tokio::select! {
+            received = a.recv_from(&mut a_buffer) => match received {
+                Ok((length, _)) => b.send_to(&a_buffer[..length], b_candidate).await,
+                Err(err) => Err(err),
+            },
+            received = b.recv_from(&mut b_buffer) => match received {
+                Ok((length, _)) => a.send_to(&b_buffer[..length], a_candidate).await,
+                Err(err) => Err(err),
+            },
+        };
+        if let Err(err) = result {
+            return err.to_string();
+        }
+    }
+}
+
+/// Producer and consumer of the media check, closed with their transports when dropped
+struct Loopback {
+    producer: Producer,
+    consumer: Consumer,
+    /// Connected to the transport produced into
+    sender: UdpSocket,
+    /// Where the consumer's transport sends
+    receiver: UdpSocket,
+    _transports: (PlainTransport, PlainTransport),
+}
+
+/// The RTP check, and the stats one after it if media got through
+async fn check_media(router: &Router) -> Vec<CheckResult> {
+    let loopback = match Loopback::create(router).await {
+        Ok(loopback) => loopback,
+        Err(err) => {
+            return vec![
+                CheckResult::failed(Check::Rtp, err),
+                CheckResult::skipped(Check::Stats, "No media was sent"),
+            ]
+        }
+    };
+
+    let received = match loopback.send().await {
+        Ok(received) => received,
+        Err(err) => {
+            return vec![
+                CheckResult::failed(Check::Rtp, err),
+                CheckResult::skipped(Check::Stats, "No media got through"),
+            ]
+        }
+    };
+    vec![
+        CheckResult::passed(Check::Rtp),
+        CheckResult::from_result(Check::Stats, loopback.check_stats(received).await),
+    ]
+}
+
+impl Loopback {
+    async fn create(router: &Router) -> Result<Self, String> {
+        let sender = UdpSocket::bind((LOCALHOST, 0))
+            .await
+            .map_err(|err| err.to_string())?;
+        let receiver = UdpSocket::bind((LOCALHOST, 0))
+            .await
+            .map_err(|err| err.to_string())?;
+        let listen_ip = TransportListenIp {
+            ip: LOCALHOST,
+            announced_ip: None,
+        };
+
+        // The sender's address is learnt from its first packet
+        let mut options = PlainTransportOptions::new(listen_ip);
+        options.comedia = true;
+        let ingress = router
+            .create_plain_transport(options)
+            .await
+            .map_err(|err| format!("No RTP, no transport to produce on: {}", err))?;
+        sender
+            .connect((LOCALHOST, ingress.tuple().local_port()))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let egress = router
+            .create_plain_transport(PlainTransportOptions::new(listen_ip))
+            .await
+            .map_err(|err| format!("No RTP, no transport to consume from: {}", err))?;
+        let port = receiver.local_addr().map_err(|err| err.to_string())?.port();
+        egress
+            .connect(PlainTransportRemoteParameters {
+                ip: Some(LOCALHOST),
+                port: Some(port),
+                rtcp_port: None,
+                srtp_parameters: None,
+            })
+            .await
+            .map_err(|err| format!("No RTP, the consumer's transport failed: {}", err))?;
+
+        let producer = {
+            let transport = ingress.clone();
+            let options = ProducerOptions::new(MediaKind::Audio, rtp_parameters(SSRC));
+            run_unsend(move || async move { transport.produce(options).await })
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| format!("No RTP, producing failed: {}", err))?
+        };
+        let consumer = {
+            let transport = egress.clone();
+            let options = ConsumerOptions::new(producer.id(), egress_capabilities(router));
+            run_unsend(move || async move { transport.consume(options).await })
+                .await
+                .map_err(|err| err.to_string())?
+                .map_err(|err| format!("No RTP, consuming failed: {}", err))?
+        };
+
+        Ok(Loopback {
+            producer,
+            consumer,
+            sender,
+            receiver,
+            _transports: (ingress, egress),
+        })
+    }
+
+    /// Send the frames and count those the consumer forwarded, failing if fewer than
+    /// half of them came back
+    async fn send(&self) -> Result<u16, String> {
+        let ssrc = self
+            .consumer
+            .rtp_parameters()
+            .encodings
+            .first()
+            .and_then(|encoding| encoding.ssrc);
+
+        let send = async {
+            let mut interval = tokio::time::interval(RTP_INTERVAL);
+            for sequence in 0..RTP_PACKETS {
+                interval.tick().await;
+                let timestamp = u32::from(sequence) * 960;
+                self.sender
+                    .send(&rtp_packet(sequence, timestamp, SSRC))
+                    .await
+                    .map_err(|err| format!("No RTP, sending failed: {}", err))?;
+            }
+            Ok::<_, String>(())
+        };
+        let receive =
+            async {
+                let deadline = tokio::time::Instant::now() + RTP_INTERVAL * RTP_PACKETS.into();
+                let deadline = deadline + RTP_GRACE;
+                let mut received = 0;
+                let mut buffer = [0u8; 1500];
+                while received < RTP_PACKETS {
+                    let length =
+                        match tokio::time::timeout_at(deadline, self.receiver.recv(&mut buffer))
+                            .await
+                        {
+                            Ok(Ok(length)) => length,
+                            Ok(Err(_)) | Err(_) => break,
+                        };
+                    if ssrc.is_some() && rtp_ssrc(&buffer[..length]) == ssrc {
+                        received += 1;
+                    }
+                }
+                received
+            };
+
+        let (sent, received) = tokio::join!(send, receive);
+        sent?;
+        match received {
+            0 => Err("No RTP came out of the consumer".to_string()),
+            received if received < RTP_PACKETS / 2 => Err(format!(
+                "No RTP for most of the audio, {} of {} packets came out of the consumer",
+                received, RTP_PACKETS
+            )),
+            received => Ok(received),
+        }
+    }
+
+    /// Whether the worker counted the packets going in and out of the router
+    async fn check_stats(&self, received: u16) -> Result<(), String> {
+        let producer = self.producer.clone();
+        let consumer = self.consumer.clone();
+        let (produced, consumed) = run_unsend(move || async move {
+            let produced: u64 = producer
+                .get_stats()
+                .await
+                .map_err(|err| err.to_string())?
+                .iter()
+                .map(|stream| u64::from(stream.packet_count))
+                .sum();
+            let consumed = match consumer.get_stats().await {
+                Ok(ConsumerStats::JustConsumer((stat,)))
+                | Ok(ConsumerStats::WithProducer((stat, _))) => u64::from(stat.packet_count),
+                Err(err) => return Err(err.to_string()),
+            };
+            Ok((produced, consumed))
+        })
+        .await
+        .map_err(|err| err.to_string())??;
+
+        if produced == 0 {
+            return Err("The producer counted no packets".to_string());
+        }
+        if consumed < u64::from(received) {
+            return Err(format!(
+                "The consumer counted {} packets, {} were received from it",
+                consumed, received
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn rtp_packet(sequence: u16, timestamp: u32, ssrc: u32) -> Vec<u8> {
+    let mut packet = vec![0x80, PAYLOAD_TYPE];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&OPUS_FRAME);
+    packet
+}
+
+/// SSRC of an RTP packet, `None` for RTCP and anything else
+fn rtp_ssrc(packet: &[u8]) -> Option<u32> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 || (192..=223).contains(&packet[1]) {
+        return None;
+    }
+    Some(u32::from_be_bytes([
+        packet[8], packet[9], packet[10], packet[11],
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[test]
+    fn binding_requests_are_signed_and_fingerprinted() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let transaction_id = [7; 12];
+        let request = binding_request(transaction_id, "server:selftest", "password");
+        let length = u16::from_be_bytes([request[2], request[3]]) as usize;
+        assert_eq!(length, request.len() - 20);
+        assert_eq!(length % 4, 0);
+
+        let (signed, fingerprint) = request.split_at(request.len() - 8);
+        assert_eq!(fingerprint[..4], [0x80, 0x28, 0, 4]);
+        assert_eq!(
+            fingerprint[4..],
+            (crc32(signed) ^ STUN_FINGERPRINT_XOR).to_be_bytes()
+        );
+
+        let mut response = request.clone();
+        response[0..2].copy_from_slice(&0x0101u16.to_be_bytes());
+        assert_eq!(binding_response(&response, &transaction_id), Some(true));
+        assert_eq!(binding_response(&response, &[8; 12]), None);
+        assert_eq!(binding_response(&request, &transaction_id), None);
+    }
+
+    #[tokio::test]
+    async fn media_flows_on_this_host() {
+        testing::init();
+        let report = run().await;
+        let failed: Vec<_> = report
+            .checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Failed)
+            .collect();
+        assert!(report.passed, "{:?}", failed);
+        assert_eq!(report.checks.len(), 5);
+        let dtls = report
+            .checks
+            .iter()
+            .find(|check| check.check == Check::Dtls)
+            .unwrap();
+        assert_eq!(dtls.status, CheckStatus::Passed);
+    }
+}
@@ -0,0 +1,276 @@
+//! What the signaling connection needs from the media side, so its flow can run against
+//! something other than WebRTC transports on a worker
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use mediasoup::prelude::*;
+
+use super::closed::ClosedReceiver;
+use super::downlink::DownlinkReceiver;
+use super::dump::Dump;
+use super::layers::LayersReceiver;
+use super::opus::OpusOptions;
+use super::reaper::{ActivityReceiver, ConsumerReaper, PacketCounts};
+use super::score::ScoreReceiver;
+use super::stats::{StatsSource, StatsSubscription};
+use super::transport_state::TransportStateReceiver;
+use super::types::{ConnectTransportData, ConsumerSummary, InitializationInput, TransportInitData};
+use super::{InitError, KeyFrameError, LayersError, MediaStats, ProduceError, RtcState};
+use crate::state::room::settings::{FastJoinSettings, OpusLimits, ProducerLimits};
+use crate::state::user::ProduceType;
+
+/// Transports, producers and consumers of a single connection
+#[async_trait]
+pub trait RtcSession: Send + Sync + Sized + 'static {
+    async fn initialize(router: &Router, init_data: InitializationInput)
+        -> Result<Self, InitError>;
+    fn get_init_data(&self) -> TransportInitData;
+    /// Sampled for room stats, `None` if there are no transports to sample
+    fn media_stats(&self) -> Option<MediaStats>;
+
+    fn take_transport_states(&mut self) -> Option<TransportStateReceiver>;
+    fn take_downlink_estimates(&mut self) -> Option<DownlinkReceiver>;
+    fn take_layer_changes(&mut self) -> Option<LayersReceiver>;
+    fn take_closed_consumers(&mut self) -> Option<ClosedReceiver>;
+    fn enable_scores(&mut self) -> ScoreReceiver;
+    fn stats_subscription(&mut self) -> &mut StatsSubscription;
+    /// What pushed stats are gathered from, `None` if there are none to gather
+    fn stats_source(&self) -> Option<StatsSource>;
+    async fn dump_transports(&self) -> Result<Dump, ()>;
+
+    async fn connect_transport(&mut self, connect_data: &ConnectTransportData) -> Result<(), ()>;
+    async fn set_max_incoming_bitrate(&self, bitrate: u32) -> Result<(), ()>;
+
+    fn can_produce(&self, produce_type: ProduceType, limits: &ProducerLimits) -> bool;
+    async fn start_produce(
+        &mut self,
+        produce_type: ProduceType,
+        rtp_parameters: RtpParameters,
+        opus: Option<OpusOptions>,
+        opus_limits: &OpusLimits,
+    ) -> Result<(Producer, Option<OpusOptions>), ProduceError>;
+    fn release_producer(&mut self, produce_type: ProduceType);
+
+    fn take_fast_join_slot(&mut self, settings: &FastJoinSettings, kind: MediaKind) -> bool;
+    async fn start_consume(
+        &mut self,
+        router: &Router,
+        producer_id: ProducerId,
+        user_id: &str,
+        produce_type: ProduceType,
+        fast_join: Option<&FastJoinSettings>,
+    ) -> Result<Consumer, ()>;
+    fn get_consumer(&self, id: &str) -> Option<&Consumer>;
+    fn consumer_auto_layers(&self, id: &str) -> Option<bool>;
+    async fn set_consumer_layers(
+        &mut self,
+        id: &str,
+        layers: ConsumerLayers,
+    ) -> Result<(), LayersError>;
+    async fn set_consumer_auto_layers(
+        &mut self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<(), LayersError>;
+    async fn cap_consumer_layers(
+        &mut self,
+        produce_type: ProduceType,
+        max_spatial_layer: Option<u8>,
+    );
+    async fn request_key_frame(&mut self, id: &str) -> Result<(), KeyFrameError>;
+    async fn set_consumer_priority(&self, id: &str, priority: u8) -> Option<Result<(), ()>>;
+    fn remove_consumers_of(
+        &mut self,
+        user_id: &str,
+        produce_type: Option<ProduceType>,
+    ) -> Vec<Consumer>;
+    fn stop_consume(&mut self, id: &str) -> bool;
+    fn list_consumers(&self) -> Vec<ConsumerSummary>;
+
+    /// Whether resuming the consumer waits for the receiving transport to connect
+    fn defer_resume(&mut self, id: &str) -> bool;
+    /// IDs of the consumers resumed as the transport connected
+    async fn transport_connected(&mut self, transport_id: TransportId) -> Vec<String>;
+    fn resume_deadline(&self) -> Option<Instant>;
+    fn expire_resumes(&mut self) -> Vec<Consumer>;
+
+    /// Counting of the consumers' packets, `None` if idle consumers aren't reaped
+    fn consumer_reaper() -> Option<(ConsumerReaper, ActivityReceiver)> {
+        ConsumerReaper::from_config()
+    }
+    fn counted_consumers(&self) -> Vec<Consumer>;
+    /// Consumers closed after sending nothing for `inactive_after`
+    fn reap_inactive(&mut self, counts: PacketCounts, inactive_after: Duration) -> Vec<Consumer>;
+}
+
+#[async_trait]
+impl RtcSession for RtcState {
+    async fn initialize(
+        router: &Router,
+        init_data: InitializationInput,
+    ) -> Result<Self, InitError> {
+        RtcState::initialize(router, init_data).await
+    }
+
+    fn get_init_data(&self) -> TransportInitData {
+        RtcState::get_init_data(self)
+    }
+
+    fn media_stats(&self) -> Option<MediaStats> {
+        Some(RtcState::media_stats(self))
+    }
+
+    fn take_transport_states(&mut self) -> Option<TransportStateReceiver> {
+        RtcState::take_transport_states(self)
+    }
+
+    fn take_downlink_estimates(&mut self) -> Option<DownlinkReceiver> {
+        RtcState::take_downlink_estimates(self)
+    }
+
+    fn take_layer_changes(&mut self) -> Option<LayersReceiver> {
+        RtcState::take_layer_changes(self)
+    }
+
+    fn take_closed_consumers(&mut self) -> Option<ClosedReceiver> {
+        RtcState::take_closed_consumers(self)
+    }
+
+    fn enable_scores(&mut self) -> ScoreReceiver {
+        RtcState::enable_scores(self)
+    }
+
+    fn stats_subscription(&mut self) -> &mut StatsSubscription {
+        RtcState::stats_subscription(self)
+    }
+
+    fn stats_source(&self) -> Option<StatsSource> {
+        Some(RtcState::stats_source(self))
+    }
+
+    async fn dump_transports(&self) -> Result<Dump, ()> {
+        RtcState::dump_transports(self).await
+    }
+
+    async fn connect_transport(&mut self, connect_data: &ConnectTransportData) -> Result<(), ()> {
+        RtcState::connect_transport(self, connect_data).await
+    }
+
+    async fn set_max_incoming_bitrate(&self, bitrate: u32) -> Result<(), ()> {
+        RtcState::set_max_incoming_bitrate(self, bitrate).await
+    }
+
+    fn can_produce(&self, produce_type: ProduceType, limits: &ProducerLimits) -> bool {
+        RtcState::can_produce(self, produce_type, limits)
+    }
+
+    async fn start_produce(
+        &mut self,
+        produce_type: ProduceType,
+        rtp_parameters: RtpParameters,
+        opus: Option<OpusOptions>,
+        opus_limits: &OpusLimits,
+    ) -> Result<(Producer, Option<OpusOptions>), ProduceError> {
+        RtcState::start_produce(self, produce_type, rtp_parameters, opus, opus_limits).await
+    }
+
+    fn release_producer(&mut self, produce_type: ProduceType) {
+        RtcState::release_producer(self, produce_type)
+    }
+
+    fn take_fast_join_slot(&mut self, settings: &FastJoinSettings, kind: MediaKind) -> bool {
+        RtcState::take_fast_join_slot(self, settings, kind)
+    }
+
+    async fn start_consume(
+        &mut self,
+        router: &Router,
+        producer_id: ProducerId,
+        user_id: &str,
+        produce_type: ProduceType,
+        fast_join: Option<&FastJoinSettings>,
+    ) -> Result<Consumer, ()> {
+        RtcState::start_consume(self, router, producer_id, user_id, produce_type, fast_join).await
+    }
+
+    fn get_consumer(&self, id: &str) -> Option<&Consumer> {
+        RtcState::get_consumer(self, id)
+    }
+
+    fn consumer_auto_layers(&self, id: &str) -> Option<bool> {
+        RtcState::consumer_auto_layers(self, id)
+    }
+
+    async fn set_consumer_layers(
+        &mut self,
+        id: &str,
+        layers: ConsumerLayers,
+    ) -> Result<(), LayersError> {
+        RtcState::set_consumer_layers(self, id, layers).await
+    }
+
+    async fn set_consumer_auto_layers(
+        &mut self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<(), LayersError> {
+        RtcState::set_consumer_auto_layers(self, id, enabled).await
+    }
+
+    async fn cap_consumer_layers(
+        &mut self,
+        produce_type: ProduceType,
+        max_spatial_layer: Option<u8>,
+    ) {
+        RtcState::cap_consumer_layers(self, produce_type, max_spatial_layer).await
+    }
+
+    async fn request_key_frame(&mut self, id: &str) -> Result<(), KeyFrameError> {
+        RtcState::request_key_frame(self, id).await
+    }
+
+    async fn set_consumer_priority(&self, id: &str, priority: u8) -> Option<Result<(), ()>> {
+        RtcState::set_consumer_priority(self, id, priority).await
+    }
+
+    fn remove_consumers_of(
+        &mut self,
+        user_id: &str,
+        produce_type: Option<ProduceType>,
+    ) -> Vec<Consumer> {
+        RtcState::remove_consumers_of(self, user_id, produce_type)
+    }
+
+    fn stop_consume(&mut self, id: &str) -> bool {
+        RtcState::stop_consume(self, id)
+    }
+
+    fn list_consumers(&self) -> Vec<ConsumerSummary> {
+        RtcState::list_consumers(self)
+    }
+
+    fn defer_resume(&mut self, id: &str) -> bool {
+        RtcState::defer_resume(self, id)
+    }
+
+    async fn transport_connected(&mut self, transport_id: TransportId) -> Vec<String> {
+        RtcState::transport_connected(self, transport_id).await
+    }
+
+    fn resume_deadline(&self) -> Option<Instant> {
+        RtcState::resume_deadline(self)
+    }
+
+    fn expire_resumes(&mut self) -> Vec<Consumer> {
+        RtcState::expire_resumes(self)
+    }
+
+    fn counted_consumers(&self) -> Vec<Consumer> {
+        RtcState::counted_consumers(self)
+    }
+
+    fn reap_inactive(&mut self, counts: PacketCounts, inactive_after: Duration) -> Vec<Consumer> {
+        RtcState::reap_inactive(self, counts, inactive_after)
+    }
+}
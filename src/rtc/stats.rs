@@ -0,0 +1,417 @@
+//! Stats of a connection's transports, producers and consumers, pushed to clients that
+//! subscribed to them instead of polling. They're gathered in a task of their own, so a
+//! worker slow to dump them doesn't hold up the connection's commands
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use mediasoup::consumer::ConsumerStats as MediasoupConsumerStats;
+use mediasoup::data_structures::{TransportProtocol, TransportTuple};
+use mediasoup::prelude::*;
+use mediasoup::webrtc_transport::{IceRole, IceState};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::local::run_unsend;
+use super::TransportMode;
+use crate::state::user::ProduceType;
+use crate::util::config::CONFIG;
+
+/// Shortest interval stats are pushed at, asking for less is raised to it
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransportStats {
+    pub id: String,
+    /// Bits per second received from the client
+    pub incoming_bitrate: u32,
+    pub outgoing_bitrate: u32,
+    /// Bandwidth estimate towards the client, WebRTC transports only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_outgoing_bitrate: Option<u32>,
+    /// WebRTC transports only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ice: Option<IceStats>,
+}
+
+/// How a WebRTC transport reaches its client, the first thing to look at when media
+/// only flows one way
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IceStats {
+    #[schemars(with = "String")]
+    pub role: IceRole,
+    #[schemars(with = "String")]
+    pub state: IceState,
+    /// Candidate pair media flows over, none until ICE connected
+    pub selected_tuple: Option<SelectedTuple>,
+    /// Over the selected pair, mediasoup doesn't count the others
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedTuple {
+    #[schemars(with = "String")]
+    pub protocol: TransportProtocol,
+    pub local_ip: IpAddr,
+    pub local_port: u16,
+    /// Address of the client, or of the TURN server relaying it. Only its network when
+    /// `rtc.redact_remote_addresses` is set, see `redact_ip`
+    pub remote_ip: Option<String>,
+    /// Left out along with the host part of the address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_port: Option<u16>,
+}
+
+impl SelectedTuple {
+    pub fn new(tuple: &TransportTuple, redact: bool) -> Self {
+        let remote_ip = tuple.remote_ip().map(|ip| match redact {
+            true => redact_ip(ip),
+            false => ip.to_string(),
+        });
+        SelectedTuple {
+            protocol: tuple.protocol(),
+            local_ip: tuple.local_ip(),
+            local_port: tuple.local_port(),
+            remote_ip,
+            remote_port: tuple.remote_port().filter(|_| !redact),
+        }
+    }
+}
+
+/// Network of an address in CIDR notation, its /24 for IPv4 and /48 for IPv6, enough
+/// to tell providers and relays apart without pointing at the user
+pub fn redact_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProducerStats {
+    #[serde(rename = "type")]
+    pub produce_type: ProduceType,
+    pub id: String,
+    /// Summed over the streams of a simulcast producer
+    pub bitrate: u32,
+    /// Score of the best stream, from 0 to 10
+    pub score: u8,
+    /// Consumers of the producer across the room
+    pub consumer_count: usize,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumerStats {
+    pub id: String,
+    pub bitrate: u32,
+    pub score: u8,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub transports: Vec<TransportStats>,
+    pub producers: Vec<ProducerStats>,
+    pub consumers: Vec<ConsumerStats>,
+}
+
+pub type StatsReceiver = UnboundedReceiver<ConnectionStats>;
+
+/// Interval a client asked for in milliseconds, within what the server pushes at
+pub fn clamp_interval(interval_ms: u64) -> Duration {
+    Duration::from_millis(interval_ms).clamp(MIN_INTERVAL, MAX_INTERVAL)
+}
+
+/// When a connection's stats are due next, if it subscribed to them
+pub struct StatsSubscription {
+    interval: Option<Duration>,
+    next: Instant,
+    /// Set while stats are being gathered, ticks in the meantime are skipped
+    gathering: Arc<AtomicBool>,
+    sender: UnboundedSender<ConnectionStats>,
+    receiver: Option<StatsReceiver>,
+}
+
+impl Default for StatsSubscription {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        StatsSubscription {
+            interval: None,
+            next: Instant::now(),
+            gathering: Arc::new(AtomicBool::new(false)),
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl StatsSubscription {
+    /// Push stats every `interval`, starting right away
+    pub fn subscribe(&mut self, interval: Duration) {
+        self.interval = Some(interval);
+        self.next = Instant::now();
+    }
+
+    pub fn unsubscribe(&mut self) {
+        self.interval = None;
+    }
+
+    pub fn subscribed(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    pub fn next_tick(&self) -> Option<Instant> {
+        self.interval.map(|_| self.next)
+    }
+
+    /// Gathered stats, only handed out once
+    pub fn take_receiver(&mut self) -> Option<StatsReceiver> {
+        self.receiver.take()
+    }
+
+    /// Schedule the next tick and gather in the background, unless the previous
+    /// gathering is still running
+    pub fn tick<F>(&mut self, gather: F)
+    where
+        F: Future<Output = Option<ConnectionStats>> + Send + 'static,
+    {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        self.next = Instant::now() + interval;
+        if self.gathering.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let gathering = self.gathering.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            if let Some(stats) = gather.await {
+                sender.send(stats).ok();
+            }
+            gathering.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Handles to what's sampled, cheap to take from the connection on every tick
+pub struct StatsSource {
+    pub(super) transport_mode: TransportMode,
+    pub(super) consumers: Vec<Consumer>,
+}
+
+impl StatsSource {
+    /// Ask the worker for the stats of the connection's transports, the given producers
+    /// of its user along with how many consume them, and its consumers
+    pub async fn gather(
+        self,
+        producers: Vec<(ProduceType, Producer, usize)>,
+    ) -> Result<ConnectionStats, ()> {
+        let StatsSource {
+            transport_mode,
+            consumers,
+        } = self;
+        run_unsend(move || async move {
+            let mut stats = ConnectionStats {
+                transports: transport_stats(&transport_mode).await?,
+                ..ConnectionStats::default()
+            };
+
+            for (produce_type, producer, consumer_count) in producers {
+                let streams = producer.get_stats().await.map_err(|_| ())?;
+                stats.producers.push(ProducerStats {
+                    produce_type,
+                    id: producer.id().to_string(),
+                    bitrate: streams.iter().map(|stream| stream.bitrate).sum(),
+                    score: streams.iter().map(|stream| stream.score).max().unwrap_or(0),
+                    consumer_count,
+                });
+            }
+
+            for consumer in consumers {
+                let stat = match consumer.get_stats().await.map_err(|_| ())? {
+                    MediasoupConsumerStats::JustConsumer((stat,))
+                    | MediasoupConsumerStats::WithProducer((stat, _)) => stat,
+                };
+                stats.consumers.push(ConsumerStats {
+                    id: consumer.id().to_string(),
+                    bitrate: stat.bitrate,
+                    score: stat.score,
+                });
+            }
+
+            Ok(stats)
+        })
+        .await
+        .map_err(|_| ())?
+    }
+}
+
+async fn transport_stats(transport_mode: &TransportMode) -> Result<Vec<TransportStats>, ()> {
+    let webrtc = match transport_mode {
+        TransportMode::SplitWebRtc(send, recv) => vec![send, recv],
+        TransportMode::CombinedWebRtc(transport) | TransportMode::RecvWebRtc(transport) => {
+            vec![transport]
+        }
+        TransportMode::CombinedRtp(transport) => {
+            let stats = transport.get_stats().await.map_err(|_| ())?;
+            return Ok(stats
+                .into_iter()
+                .map(|stat| TransportStats {
+                    id: transport.id().to_string(),
+                    incoming_bitrate: stat.recv_bitrate,
+                    outgoing_bitrate: stat.send_bitrate,
+                    available_outgoing_bitrate: None,
+                    ice: None,
+                })
+                .collect());
+        }
+    };
+
+    let redact = CONFIG.rtc.redact_remote_addresses;
+    let mut stats = Vec::new();
+    for transport in webrtc {
+        for stat in transport.get_stats().await.map_err(|_| ())? {
+            let ice = IceStats {
+                role: stat.ice_role,
+                state: stat.ice_state,
+                selected_tuple: stat
+                    .ice_selected_tuple
+                    .as_ref()
+                    .map(|tuple| SelectedTuple::new(tuple, redact)),
+                bytes_received: stat.bytes_received as u64,
+                bytes_sent: stat.bytes_sent as u64,
+            };
+            stats.push(TransportStats {
+                id: transport.id().to_string(),
+                incoming_bitrate: stat.recv_bitrate,
+                outgoing_bitrate: stat.send_bitrate,
+                available_outgoing_bitrate: stat.available_outgoing_bitrate,
+                ice: Some(ice),
+            });
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_addresses_are_redacted_to_their_network() {
+        let cases = [
+            ("203.0.113.57", "203.0.113.0/24"),
+            ("10.1.2.3", "10.1.2.0/24"),
+            ("2001:db8:85a3:8d3:1319:8a2e:370:7348", "2001:db8:85a3::/48"),
+            ("::1", "::/48"),
+        ];
+        for (ip, redacted) in cases.iter() {
+            assert_eq!(redact_ip(ip.parse().unwrap()), *redacted);
+        }
+    }
+
+    #[test]
+    fn transport_stats_carry_the_selected_tuple() {
+        let tuple = TransportTuple::WithRemote {
+            local_ip: "192.0.2.10".parse().unwrap(),
+            local_port: 10000,
+            remote_ip: "203.0.113.57".parse().unwrap(),
+            remote_port: 54321,
+            protocol: TransportProtocol::Udp,
+        };
+        let stats = |redact| TransportStats {
+            id: "transport".to_string(),
+            incoming_bitrate: 32_000,
+            outgoing_bitrate: 64_000,
+            available_outgoing_bitrate: None,
+            ice: Some(IceStats {
+                role: IceRole::Controlled,
+                state: IceState::Completed,
+                selected_tuple: Some(SelectedTuple::new(&tuple, redact)),
+                bytes_received: 4000,
+                bytes_sent: 8000,
+            }),
+        };
+
+        assert_eq!(
+            serde_json::to_value(stats(false)).unwrap(),
+            serde_json::json!({
+                "id": "transport",
+                "incomingBitrate": 32_000,
+                "outgoingBitrate": 64_000,
+                "ice": {
+                    "role": "controlled",
+                    "state": "completed",
+                    "selectedTuple": {
+                        "protocol": "udp",
+                        "localIp": "192.0.2.10",
+                        "localPort": 10000,
+                        "remoteIp": "203.0.113.57",
+                        "remotePort": 54321,
+                    },
+                    "bytesReceived": 4000,
+                    "bytesSent": 8000,
+                },
+            })
+        );
+
+        let redacted = serde_json::to_value(stats(true)).unwrap();
+        let selected = &redacted["ice"]["selectedTuple"];
+        assert_eq!(selected["remoteIp"], "203.0.113.0/24");
+        assert!(selected.get("remotePort").is_none());
+        assert_eq!(selected["localPort"], 10000);
+    }
+
+    #[test]
+    fn intervals_are_clamped() {
+        assert_eq!(clamp_interval(0), MIN_INTERVAL);
+        assert_eq!(clamp_interval(2500), Duration::from_millis(2500));
+        assert_eq!(clamp_interval(u64::MAX), MAX_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn ticks_wait_for_the_previous_gathering() {
+        let mut subscription = StatsSubscription::default();
+        let mut receiver = subscription.take_receiver().unwrap();
+        assert_eq!(subscription.next_tick(), None);
+
+        subscription.subscribe(MIN_INTERVAL);
+        assert!(subscription.next_tick().unwrap() <= Instant::now());
+
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        subscription.tick(async move {
+            released.await.ok();
+            Some(ConnectionStats::default())
+        });
+        // Still gathering, so this one is skipped
+        subscription.tick(async { None });
+        assert!(subscription.next_tick().unwrap() > Instant::now());
+
+        release.send(()).unwrap();
+        assert_eq!(receiver.recv().await, Some(ConnectionStats::default()));
+
+        subscription.unsubscribe();
+        assert!(!subscription.subscribed());
+        assert_eq!(subscription.next_tick(), None);
+    }
+}
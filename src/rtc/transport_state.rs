@@ -0,0 +1,107 @@
+//! ICE and DTLS state of a connection's WebRTC transports, reported so clients
+//! notice a dead transport instead of just losing media
+
+use std::sync::Arc;
+
+use mediasoup::prelude::*;
+use parking_lot::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// State of a transport after one of its ICE or DTLS states changed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransportStateChange {
+    pub transport_id: TransportId,
+    pub ice_state: IceState,
+    pub dtls_state: DtlsState,
+}
+
+pub type TransportStateReceiver = UnboundedReceiver<TransportStateChange>;
+
+/// Last known states of a transport, each change is reported along with the other state
+struct Reporter {
+    transport_id: TransportId,
+    states: Mutex<(IceState, DtlsState)>,
+    sender: UnboundedSender<TransportStateChange>,
+}
+
+impl Reporter {
+    fn ice(&self, ice_state: IceState) {
+        let mut states = self.states.lock();
+        states.0 = ice_state;
+        self.report(*states);
+    }
+
+    fn dtls(&self, dtls_state: DtlsState) {
+        let mut states = self.states.lock();
+        states.1 = dtls_state;
+        self.report(*states);
+    }
+
+    /// Changes after the connection closed its receiver are dropped
+    fn report(&self, (ice_state, dtls_state): (IceState, DtlsState)) {
+        let change = TransportStateChange {
+            transport_id: self.transport_id,
+            ice_state,
+            dtls_state,
+        };
+        self.sender.send(change).ok();
+    }
+}
+
+/// Report the transport's state changes to `sender` for as long as the transport
+/// lives, which may outlast the connection
+pub fn watch(transport: &WebRtcTransport, sender: UnboundedSender<TransportStateChange>) {
+    let reporter = Arc::new(Reporter {
+        transport_id: transport.id(),
+        states: Mutex::new((transport.ice_state(), transport.dtls_state())),
+        sender,
+    });
+
+    transport
+        .on_ice_state_change({
+            let reporter = reporter.clone();
+            move |ice_state| reporter.ice(ice_state)
+        })
+        .detach();
+    transport
+        .on_dtls_state_change(move |dtls_state| reporter.dtls(dtls_state))
+        .detach();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn reporter() -> (Reporter, TransportStateReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let transport_id = serde_json::from_str(r#""00000000-0000-4000-8000-000000000000""#);
+        let reporter = Reporter {
+            transport_id: transport_id.unwrap(),
+            states: Mutex::new((IceState::New, DtlsState::New)),
+            sender,
+        };
+        (reporter, receiver)
+    }
+
+    #[test]
+    fn changes_carry_both_states() {
+        let (reporter, mut receiver) = reporter();
+        reporter.ice(IceState::Connected);
+        reporter.dtls(DtlsState::Failed);
+
+        let states = |change: TransportStateChange| (change.ice_state, change.dtls_state);
+        let first = receiver.try_recv().unwrap();
+        assert_eq!(states(first), (IceState::Connected, DtlsState::New));
+        let second = receiver.try_recv().unwrap();
+        assert_eq!(states(second), (IceState::Connected, DtlsState::Failed));
+    }
+
+    #[test]
+    fn changes_after_close_are_dropped() {
+        let (reporter, receiver) = reporter();
+        drop(receiver);
+        reporter.ice(IceState::Disconnected);
+        reporter.dtls(DtlsState::Closed);
+    }
+}
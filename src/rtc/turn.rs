@@ -0,0 +1,65 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::types::IceServer;
+use crate::util::config::{TurnConfig, CONFIG};
+
+/// Generate time-limited TURN credentials for a user, `None` if no TURN relay is configured
+pub fn ice_servers(user_id: &str) -> Option<Vec<IceServer>> {
+    let turn = CONFIG.turn.as_ref()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the UNIX epoch")
+        .as_secs();
+
+    Some(vec![ice_server_at(turn, user_id, now)])
+}
+
+/// Credentials of the relay as of `now`, in UNIX seconds
+fn ice_server_at(turn: &TurnConfig, user_id: &str, now: u64) -> IceServer {
+    // coturn REST API: username is `expiry:user`, credential is base64(HMAC-SHA1(secret, username))
+    let username = format!("{}:{}", now + turn.ttl, user_id);
+    let mut mac = Hmac::<Sha1>::new_from_slice(turn.secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    let credential = base64::encode(mac.finalize().into_bytes());
+
+    IceServer {
+        urls: turn.urls.clone(),
+        username,
+        credential,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_follow_the_coturn_rest_api() {
+        let turn = TurnConfig {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            secret: "turn-secret".to_string(),
+            ttl: 600,
+        };
+        let server = ice_server_at(&turn, "alice", 400);
+        assert_eq!(server.urls, turn.urls);
+        assert_eq!(server.username, "1000:alice");
+        assert_eq!(server.credential, "k6ZaLXo+b1Vwml6IBp9prt8RtUA=");
+
+        // Credentials are tied to the user and the secret
+        assert_ne!(
+            ice_server_at(&turn, "bob", 400).credential,
+            server.credential
+        );
+        let other = TurnConfig {
+            secret: "another-secret".to_string(),
+            ..turn.clone()
+        };
+        assert_ne!(
+            ice_server_at(&other, "alice", 400).credential,
+            server.credential
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
@@ -6,23 +7,74 @@ use mediasoup::prelude::*;
 use mediasoup::sctp_parameters::SctpParameters;
 use mediasoup::srtp_parameters::SrtpParameters;
 
-#[derive(Serialize, Deserialize)]
+use crate::state::user::ProduceType;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct InitializationInput {
+    #[schemars(with = "serde_json::Value")]
     pub(super) rtp_capabilities: RtpCapabilities,
-    #[serde(flatten)]
+    /// Separate send and receive transports unless the client asks otherwise
+    #[serde(default)]
     pub(super) mode: InitializationInputMode,
+    /// ICE candidates the client wants offered, all the server allows if not given
+    #[serde(default)]
+    pub(super) ice_policy: Option<IcePolicy>,
+    /// Start audio consumers paused like video ones, until the client resumes them with
+    /// `ResumeConsumer` once its receiver is ready. Clients that expect audio to play right
+    /// away turn it off
+    #[serde(default = "default_pause_consumers")]
+    pub(super) pause_consumers: bool,
+    /// Send `DownlinkEstimate` events with the bitrate available towards the client
+    #[serde(default)]
+    pub(super) downlink_estimates: bool,
+}
+
+impl InitializationInput {
+    /// Input as a client sends it, with the defaults of the optional fields
+    pub fn new(rtp_capabilities: RtpCapabilities, mode: InitializationInputMode) -> Self {
+        InitializationInput {
+            rtp_capabilities,
+            mode,
+            ice_policy: None,
+            pause_consumers: default_pause_consumers(),
+            downlink_estimates: false,
+        }
+    }
+}
+
+fn default_pause_consumers() -> bool {
+    true
+}
+
+/// Protocols of the ICE candidates offered on WebRTC transports, for clients on networks
+/// that block UDP. Protocols disabled in the configuration stay disabled
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub enum IcePolicy {
+    UdpOnly,
+    /// UDP and TCP candidates, with UDP ones ranked higher
+    PreferUdp,
+    TcpOnly,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "mode")]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
 pub enum InitializationInputMode {
     SplitWebRtc,
+    /// One WebRTC transport carrying both directions, halving the ICE and DTLS
+    /// handshakes and ports used by the client
     CombinedWebRtc,
     CombinedRtp,
+    /// Only a receive transport, for listeners that never produce
+    RecvWebRtc,
+}
+
+impl Default for InitializationInputMode {
+    fn default() -> Self {
+        InitializationInputMode::SplitWebRtc
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 #[serde(untagged)]
 #[serde(rename_all = "camelCase")]
 pub enum TransportInitData {
@@ -35,38 +87,82 @@ pub enum TransportInitData {
         transport: WebRtcTransportInitData,
     },
     #[serde(rename_all = "camelCase")]
+    RecvWebRtc {
+        recv_transport: WebRtcTransportInitData,
+    },
+    #[serde(rename_all = "camelCase")]
     CombinedRtp {
         ip: IpAddr,
         port: u16,
+        #[schemars(with = "String")]
         protocol: TransportProtocol,
+        #[schemars(with = "String")]
         id: TransportId,
+        #[schemars(with = "String")]
         srtp_crypto_suite: SrtpCryptoSuite,
     },
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct WebRtcTransportInitData {
+    #[schemars(with = "String")]
     pub id: TransportId,
+    #[schemars(with = "serde_json::Value")]
     pub ice_parameters: IceParameters,
+    #[schemars(with = "Vec<serde_json::Value>")]
     pub ice_candidates: Vec<IceCandidate>,
+    #[schemars(with = "serde_json::Value")]
     pub dtls_parameters: DtlsParameters,
+    #[schemars(with = "Option<serde_json::Value>")]
     pub sctp_parameters: Option<SctpParameters>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ConnectTransportData {
+    #[schemars(with = "String")]
     pub id: TransportId,
     #[serde(flatten)]
     pub params: ConnectTransportParams,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 #[serde(rename_all = "camelCase")]
 pub enum ConnectTransportParams {
     #[serde(rename_all = "camelCase")]
-    WebRtc { dtls_parameters: DtlsParameters },
+    WebRtc {
+        #[schemars(with = "serde_json::Value")]
+        dtls_parameters: DtlsParameters,
+    },
     #[serde(rename_all = "camelCase")]
-    Rtp { srtp_parameters: SrtpParameters },
+    Rtp {
+        #[schemars(with = "serde_json::Value")]
+        srtp_parameters: SrtpParameters,
+    },
+}
+
+/// Entry of the `RTCIceServer` list handed to clients
+#[derive(Serialize, JsonSchema)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+/// Consumer the connection holds, as listed by `ListConsumers`
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumerSummary {
+    pub id: String,
+    pub producer_id: String,
+    /// User producing what is consumed
+    pub user_id: String,
+    #[serde(rename = "type")]
+    pub produce_type: ProduceType,
+    #[schemars(with = "String")]
+    pub kind: MediaKind,
+    pub paused: bool,
+    /// Paused by its producer rather than by the client
+    pub producer_paused: bool,
 }
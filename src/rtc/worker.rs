@@ -1,38 +1,189 @@
-use mediasoup::{worker::Worker, worker::WorkerSettings, worker_manager::WorkerManager};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use mediasoup::{
+    worker::{Worker, WorkerId, WorkerSettings},
+    worker_manager::WorkerManager,
+};
 use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
-use crate::util::variables::{RTC_MAX_PORT, RTC_MIN_PORT};
+use crate::state::room;
+use crate::util::config::CONFIG;
 
 pub static WORKER_POOL: OnceCell<WorkerPool> = OnceCell::new();
 
+/// Attempts at starting a worker to replace one that died, including the first
+const REPLACE_ATTEMPTS: u32 = 3;
+const REPLACE_BACKOFF: Duration = Duration::from_secs(1);
+
 pub fn get_worker_pool() -> &'static WorkerPool {
     WORKER_POOL
         .get()
         .expect("Mediasoup worker pool not initialized")
 }
 
-// Single threaded for now
-#[derive(Debug)]
 pub struct WorkerPool {
-    //manager: WorkerManager,
-    worker: Worker,
+    manager: WorkerManager,
+    slots: Vec<WorkerSlot>,
+    /// Slot the next room goes to, see `get_worker`
+    next: AtomicUsize,
+    deaths: UnboundedSender<WorkerId>,
+    /// Until taken by `supervise`
+    dead_workers: Mutex<Option<UnboundedReceiver<WorkerId>>>,
+}
+
+struct WorkerSlot {
+    /// Replaced by `supervise` when it dies
+    worker: RwLock<Worker>,
+    /// Set once the current worker process exits, until it's replaced
+    dead: Arc<AtomicBool>,
+}
+
+/// Whether a worker process is still running, reported by the health check
+#[derive(Serialize, Debug)]
+pub struct WorkerStatus {
+    pub id: WorkerId,
+    pub alive: bool,
 }
 
 impl WorkerPool {
     pub async fn new() -> Self {
+        WorkerPool::with_workers(CONFIG.rtc.workers).await
+    }
+
+    pub async fn with_workers(count: usize) -> Self {
         let manager = WorkerManager::new();
-        let mut settings = WorkerSettings::default();
-        settings.rtc_ports_range = (*RTC_MIN_PORT)..=(*RTC_MAX_PORT);
+        let (deaths, dead_workers) = mpsc::unbounded_channel();
+        let mut slots = Vec::with_capacity(count);
+        for _ in 0..count {
+            let dead = Arc::new(AtomicBool::new(false));
+            let worker = create_worker(&manager, &dead, &deaths).await.unwrap();
+            slots.push(WorkerSlot {
+                worker: RwLock::new(worker),
+                dead,
+            });
+        }
 
-        let worker = manager.create_worker(settings).await.unwrap();
-        debug!("Initialized worker pool");
+        debug!("Initialized worker pool of {} workers", count);
         WorkerPool {
-            //manager,
-            worker,
+            manager,
+            slots,
+            next: AtomicUsize::new(0),
+            deaths,
+            dead_workers: Mutex::new(Some(dead_workers)),
         }
     }
 
-    pub fn get_worker(&self) -> &Worker {
-        &self.worker
+    /// Worker for a new router, taking turns between those still alive
+    pub fn get_worker(&self) -> Worker {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.slots.len();
+        let slot = (0..count)
+            .map(|offset| &self.slots[(start + offset) % count])
+            .find(|slot| !slot.dead.load(Ordering::Relaxed))
+            .unwrap_or(&self.slots[start % count]);
+        slot.worker.read().clone()
+    }
+
+    /// Worker with the given ID, if it's in the pool and alive
+    pub fn worker(&self, id: WorkerId) -> Option<Worker> {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.dead.load(Ordering::Relaxed))
+            .map(|slot| slot.worker.read().clone())
+            .find(|worker| worker.id() == id)
+    }
+
+    /// Workers still alive, in the order rooms are spread over them
+    pub fn workers(&self) -> Vec<Worker> {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.dead.load(Ordering::Relaxed))
+            .map(|slot| slot.worker.read().clone())
+            .collect()
+    }
+
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        self.slots
+            .iter()
+            .map(|slot| WorkerStatus {
+                id: slot.worker.read().id(),
+                alive: !slot.dead.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Start a worker in place of one that died, `false` if none would start
+    async fn replace(&self, dead: WorkerId) -> bool {
+        let slot = match self
+            .slots
+            .iter()
+            .find(|slot| slot.worker.read().id() == dead)
+        {
+            Some(slot) => slot,
+            None => return false,
+        };
+        for attempt in 1..=REPLACE_ATTEMPTS {
+            match create_worker(&self.manager, &slot.dead, &self.deaths).await {
+                Ok(worker) => {
+                    info!("Replaced dead worker {} with {}", dead, worker.id());
+                    *slot.worker.write() = worker;
+                    slot.dead.store(false, Ordering::Relaxed);
+                    return true;
+                }
+                Err(err) => error!(
+                    "Failed to start a worker in place of {}: {} (attempt {} of {})",
+                    dead, err, attempt, REPLACE_ATTEMPTS
+                ),
+            }
+            tokio::time::sleep(REPLACE_BACKOFF).await;
+        }
+        false
+    }
+}
+
+async fn create_worker(
+    manager: &WorkerManager,
+    dead: &Arc<AtomicBool>,
+    deaths: &UnboundedSender<WorkerId>,
+) -> std::io::Result<Worker> {
+    let mut settings = WorkerSettings::default();
+    settings.rtc_ports_range = CONFIG.rtc.min_port..=CONFIG.rtc.max_port;
+
+    let worker = manager.create_worker(settings).await?;
+    let id = worker.id();
+    worker
+        .on_dead({
+            let dead = dead.clone();
+            let deaths = deaths.clone();
+            move |result| {
+                error!("Mediasoup worker {} died: {:?}", id, result);
+                dead.store(true, Ordering::Relaxed);
+                deaths.send(id).ok();
+            }
+        })
+        .detach();
+    Ok(worker)
+}
+
+/// Replace workers as they die and move their rooms to the new one, or close the rooms if
+/// no worker would start. Runs for as long as the pool
+pub async fn supervise() {
+    let pool = get_worker_pool();
+    let mut dead_workers = match pool.dead_workers.lock().take() {
+        Some(dead_workers) => dead_workers,
+        None => return,
+    };
+
+    while let Some(dead) = dead_workers.recv().await {
+        // Rooms fail to move and are closed if there's no worker to move them to
+        pool.replace(dead).await;
+        room::restart_media(dead).await;
     }
 }
@@ -0,0 +1,229 @@
+use std::backtrace::Backtrace;
+use std::panic;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future;
+use tokio::signal::unix::{signal, SignalKind};
+use warp::{Filter, Reply};
+
+use crate::integrations::audit::{Audit, AuditSink, FileSink, StdoutSink, AUDIT};
+use crate::integrations::format::{self, PayloadFormatter};
+use crate::integrations::redis::{Redis, REDIS};
+use crate::integrations::webhook::{Webhook, WEBHOOK};
+use crate::state::store::{self, FileStore, Persistence, RoomStore, STORE};
+use crate::util::config::{AuditSinkKind, AuthBackend, CONFIG};
+use crate::util::variables::{self, HTTP_HOST};
+use crate::util::{listener, load, tls};
+use crate::ws::auth::{Authenticator, HttpAuthenticator, AUTHENTICATOR};
+use crate::{api, health, info, poll, rtc, ws};
+
+/// Entry point for running Vortex, either from the bundled binary or embedded in another one
+#[derive(Default)]
+pub struct ServerBuilder {
+    formatters: Vec<(String, Arc<dyn PayloadFormatter>)>,
+    room_store: Option<Arc<dyn RoomStore>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    mock_media: bool,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder::default()
+    }
+
+    /// Register a custom payload formatter, selectable by name in the integration sink configuration
+    pub fn formatter<F: PayloadFormatter + 'static>(mut self, name: &str, formatter: F) -> Self {
+        self.formatters
+            .push((name.to_string(), Arc::new(formatter)));
+        self
+    }
+
+    /// Keep rooms across restarts in this store, in place of the file set by `persistence.path`
+    pub fn room_store<S: RoomStore + 'static>(mut self, store: S) -> Self {
+        self.room_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Write audit records to this sink, in place of the one set by `audit.sink`
+    pub fn audit_sink<S: AuditSink + 'static>(mut self, sink: S) -> Self {
+        self.audit_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Check the tokens clients authenticate with using this authenticator, in place of the
+    /// one set by `auth.backend`
+    pub fn authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Serve signaling with mock transports in place of WebRTC ones, so connections can
+    /// produce and consume without negotiating ICE and DTLS. For load testing only
+    pub fn mock_media(mut self) -> Self {
+        self.mock_media = true;
+        self
+    }
+
+    pub async fn run(self) {
+        info!("Starting Revolt Vortex voice server");
+        health::mark_started();
+        panic::set_hook(Box::new(|info| {
+            error!("{}\n{}", info, Backtrace::force_capture());
+        }));
+
+        for (name, formatter) in self.formatters {
+            format::register(name, formatter);
+        }
+
+        variables::preflight_checks();
+
+        if let Some(uri) = &*variables::REDIS_URI {
+            let redis = Redis::connect(uri)
+                .await
+                .expect("Failed to connect to Redis");
+            REDIS.set(redis).ok();
+        }
+
+        if !variables::WEBHOOK_URLS.is_empty() {
+            let webhook = Webhook::start(
+                variables::WEBHOOK_URLS.clone(),
+                variables::WEBHOOK_SECRET.clone(),
+            );
+            WEBHOOK.set(webhook).ok();
+        }
+
+        let audit_sink = self.audit_sink.or_else(|| {
+            let audit = CONFIG.audit.as_ref()?;
+            Some(match audit.sink {
+                AuditSinkKind::File => {
+                    Arc::new(FileSink::open(&audit.path).expect("Failed to open the audit log"))
+                        as Arc<dyn AuditSink>
+                }
+                AuditSinkKind::Stdout => Arc::new(StdoutSink),
+            })
+        });
+        if let Some(audit_sink) = audit_sink {
+            AUDIT.set(Audit::start(audit_sink)).ok();
+        }
+
+        let authenticator = self.authenticator.or_else(|| {
+            let auth = CONFIG.auth.as_ref()?;
+            match auth.backend {
+                AuthBackend::Local => None,
+                AuthBackend::Http => {
+                    Some(Arc::new(HttpAuthenticator::new(auth)) as Arc<dyn Authenticator>)
+                }
+            }
+        });
+        if let Some(authenticator) = authenticator {
+            AUTHENTICATOR.set(authenticator).ok();
+        }
+
+        let worker_pool = rtc::worker::WorkerPool::new().await;
+        rtc::worker::WORKER_POOL.set(worker_pool).ok();
+        tokio::spawn(rtc::worker::supervise());
+
+        // Ahead of serving, rooms and tokens must be back before clients reconnect
+        let room_store = self.room_store.or_else(|| {
+            let persistence = CONFIG.persistence.as_ref()?;
+            Some(Arc::new(FileStore::new(&persistence.path)) as Arc<dyn RoomStore>)
+        });
+        if let Some(room_store) = room_store {
+            let (persistence, rooms) = Persistence::start(room_store)
+                .await
+                .expect("Failed to load the room store");
+            STORE.set(persistence).ok();
+            store::restore(rooms).await;
+        }
+
+        let info_route = warp::path::end()
+            .and(warp::get())
+            .map(|| warp::reply::json(&info::get_info()));
+        let version_route = warp::path("version")
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(|| warp::reply::json(&info::server_info()));
+        let schema_route = warp::path("schema")
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(|| {
+                warp::reply::with_header(
+                    ws::schema::SCHEMA.as_str(),
+                    "content-type",
+                    "application/json",
+                )
+            });
+
+        let session_route = match self.mock_media {
+            true => {
+                warn!("Serving signaling with mock media, no client can send or receive any");
+                ws::mock_route().map(Reply::into_response).boxed()
+            }
+            false => ws::route().map(Reply::into_response).boxed(),
+        };
+        // At the root for clients that predate the versioned path
+        let ws_route = warp::path("ws")
+            .and(warp::path::end())
+            .and(session_route.clone())
+            .or(warp::path::end().and(session_route));
+        let poll_route = warp::path("poll").and(poll::route());
+        let hls_route = api::hls::route();
+        let capabilities_route = api::capabilities::route();
+        let probe_route = api::probe::route();
+        let health_route = health::route();
+        poll::start_reaper();
+        load::start_monitor();
+
+        // Ahead of the management API, which turns any unmatched request into an error
+        let route = ws_route
+            .or(info_route)
+            .or(version_route)
+            .or(schema_route)
+            .or(poll_route)
+            .or(hls_route)
+            .or(capabilities_route)
+            .or(probe_route)
+            .or(health_route)
+            .or(api::route());
+
+        let route = route.map(Reply::into_response).boxed();
+        let mut listeners = Vec::new();
+        if let Some(config) = &CONFIG.unix_socket {
+            listeners.push(tokio::spawn(listener::serve_unix(route.clone(), config)));
+        }
+        if CONFIG
+            .unix_socket
+            .as_ref()
+            .map_or(true, |config| config.tcp)
+        {
+            listeners.push(match &CONFIG.tls {
+                Some(config) => tokio::spawn(tls::serve(route, *HTTP_HOST, config)),
+                None => tokio::spawn(warp::serve(route).run(*HTTP_HOST)),
+            });
+        }
+
+        tokio::select! {
+            (result, _, _) = future::select_all(listeners) => result.unwrap(),
+            _ = shutdown_signal() => {
+                let drain = CONFIG.shutdown.drain_secs;
+                info!("Shutting down, draining traffic for {}s", drain);
+                health::begin_drain();
+                tokio::time::sleep(Duration::from_secs(drain)).await;
+            }
+        }
+
+        if let Some(config) = &CONFIG.unix_socket {
+            listener::remove_unix(config);
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
@@ -1,2 +1,3 @@
 pub mod room;
+pub mod store;
 pub mod user;
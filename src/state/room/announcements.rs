@@ -0,0 +1,182 @@
+use mediasoup::producer::ProducerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::state::user::ProduceType;
+
+/// How long after a producer was announced started or stopped further changes are held
+/// back, clients with flaky devices toggle them several times a second
+pub const PRODUCE_COALESCE: Duration = Duration::from_millis(300);
+
+/// When a change of a producer is announced
+#[derive(Debug, PartialEq)]
+pub enum Announce {
+    Now,
+    /// Once the window is over, as the state the producer is in by then
+    After(Duration),
+    /// Along with an earlier change that's already held back
+    Held,
+}
+
+/// What to announce once the changes held back settled
+#[derive(Debug, Default, PartialEq)]
+pub struct Settled {
+    /// The producer last announced is gone
+    pub stopped: bool,
+    /// Another one was started since
+    pub started: bool,
+}
+
+struct Announced<P> {
+    producer: Option<P>,
+    at: Instant,
+    held: bool,
+}
+
+/// Producers last announced to a room by user and type, so that only the state they
+/// settle in is announced of changes in quick succession
+pub struct Announcements<P = ProducerId> {
+    latest: HashMap<(String, ProduceType), Announced<P>>,
+}
+
+impl<P> Default for Announcements<P> {
+    fn default() -> Self {
+        Announcements {
+            latest: HashMap::new(),
+        }
+    }
+}
+
+impl<P: PartialEq> Announcements<P> {
+    /// The user's producer changed to `producer`. The first change in a while goes out
+    /// right away, those following it within the window are held back until it's over
+    pub fn change(
+        &mut self,
+        user_id: &str,
+        produce_type: ProduceType,
+        producer: Option<P>,
+        now: Instant,
+    ) -> Announce {
+        let key = (user_id.to_string(), produce_type);
+        if let Some(announced) = self.latest.get_mut(&key) {
+            if announced.held {
+                return Announce::Held;
+            }
+            let until = announced.at + PRODUCE_COALESCE;
+            if now < until {
+                announced.held = true;
+                return Announce::After(until - now);
+            }
+        }
+
+        let announced = Announced {
+            producer,
+            at: now,
+            held: false,
+        };
+        self.latest.insert(key, announced);
+        Announce::Now
+    }
+
+    /// The changes held back are over with the user's producer being `producer`.
+    /// Nothing is announced if it's the one last announced
+    pub fn settle(
+        &mut self,
+        user_id: &str,
+        produce_type: ProduceType,
+        producer: Option<P>,
+        now: Instant,
+    ) -> Settled {
+        let key = (user_id.to_string(), produce_type);
+        let announced = match self.latest.get_mut(&key) {
+            Some(announced) => announced,
+            None => return Settled::default(),
+        };
+        announced.held = false;
+        if announced.producer == producer {
+            return Settled::default();
+        }
+
+        let settled = Settled {
+            stopped: announced.producer.is_some(),
+            started: producer.is_some(),
+        };
+        announced.producer = producer;
+        announced.at = now;
+        settled
+    }
+
+    pub fn remove(&mut self, user_id: &str) {
+        self.latest.retain(|(id, _), _| id != user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flapping_producers_settle_in_their_final_state() {
+        let mut announcements = Announcements::<u32>::default();
+        let start = Instant::now();
+        let audio = ProduceType::Audio;
+        assert_eq!(
+            announcements.change("alice", audio, Some(1), start),
+            Announce::Now
+        );
+
+        // Stopped and started again right after, only the new producer is announced
+        let soon = start + Duration::from_millis(100);
+        assert_eq!(
+            announcements.change("alice", audio, None, soon),
+            Announce::After(Duration::from_millis(200))
+        );
+        assert_eq!(
+            announcements.change("alice", audio, Some(2), soon),
+            Announce::Held
+        );
+        // Other types and users aren't held back along with it
+        assert_eq!(
+            announcements.change("alice", ProduceType::Video, Some(3), soon),
+            Announce::Now
+        );
+        assert_eq!(
+            announcements.change("bob", audio, Some(4), soon),
+            Announce::Now
+        );
+        let settled = start + PRODUCE_COALESCE;
+        assert_eq!(
+            announcements.settle("alice", audio, Some(2), settled),
+            Settled {
+                stopped: true,
+                started: true,
+            }
+        );
+
+        // Back where it was announced, there's nothing to tell
+        let later = settled + Duration::from_millis(100);
+        assert!(matches!(
+            announcements.change("alice", audio, None, later),
+            Announce::After(_)
+        ));
+        announcements.change("alice", audio, Some(2), later);
+        let settled = settled + PRODUCE_COALESCE;
+        assert_eq!(
+            announcements.settle("alice", audio, Some(2), settled),
+            Settled::default()
+        );
+
+        // A quiet producer is announced right away again
+        let quiet = settled + PRODUCE_COALESCE;
+        assert_eq!(
+            announcements.change("alice", audio, None, quiet),
+            Announce::Now
+        );
+
+        announcements.remove("alice");
+        assert_eq!(
+            announcements.settle("alice", audio, None, quiet),
+            Settled::default()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use mediasoup::producer::ProducerId;
+
+use crate::state::user::ProduceType;
+
+/// Consumers of each producer across the room, for its user to know how many people
+/// receive its media
+#[derive(Default)]
+pub struct Audience {
+    producers: HashMap<ProducerId, Listeners>,
+}
+
+struct Listeners {
+    user_id: String,
+    produce_type: ProduceType,
+    count: usize,
+    /// Count the producing user was last told about
+    announced: usize,
+}
+
+/// Count of consumers a producing user is to be told about
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudienceChange {
+    pub user_id: String,
+    pub produce_type: ProduceType,
+    pub count: usize,
+}
+
+impl Audience {
+    pub fn count(&self, producer_id: ProducerId) -> usize {
+        self.producers
+            .get(&producer_id)
+            .map_or(0, |listeners| listeners.count)
+    }
+
+    /// Count a consumer created of the user's producer
+    pub fn add(
+        &mut self,
+        producer_id: ProducerId,
+        user_id: &str,
+        produce_type: ProduceType,
+        delta: usize,
+    ) -> Option<AudienceChange> {
+        let listeners = self
+            .producers
+            .entry(producer_id)
+            .or_insert_with(|| Listeners {
+                user_id: user_id.to_string(),
+                produce_type,
+                count: 0,
+                announced: 0,
+            });
+        listeners.count += 1;
+        listeners.announce(delta)
+    }
+
+    /// Forget a consumer of the producer that closed, whatever closed it
+    pub fn remove(&mut self, producer_id: ProducerId, delta: usize) -> Option<AudienceChange> {
+        let listeners = self.producers.get_mut(&producer_id)?;
+        listeners.count = listeners.count.saturating_sub(1);
+        let change = listeners.announce(delta);
+        if listeners.count == 0 {
+            self.producers.remove(&producer_id);
+        }
+        change
+    }
+}
+
+impl Listeners {
+    /// The count if it moved `delta` or more since it was last announced, or dropped to
+    /// nobody. A `delta` of 0 announces nothing
+    fn announce(&mut self, delta: usize) -> Option<AudienceChange> {
+        let moved = if self.count > self.announced {
+            self.count - self.announced
+        } else {
+            self.announced - self.count
+        };
+        let emptied = self.count == 0 && self.announced > 0;
+        if delta == 0 || (moved < delta && !emptied) {
+            return None;
+        }
+
+        self.announced = self.count;
+        Some(AudienceChange {
+            user_id: self.user_id.clone(),
+            produce_type: self.produce_type,
+            count: self.count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer_id(id: &str) -> ProducerId {
+        serde_json::from_value(serde_json::json!(id)).unwrap()
+    }
+
+    #[test]
+    fn changes_are_announced_past_the_delta() {
+        let screen = producer_id("5f1ec5a4-6b8e-4a8e-9d07-8a2b2cf5a001");
+        let mut audience = Audience::default();
+        let counts: Vec<Option<usize>> = (0..5)
+            .map(|_| audience.add(screen, "alice", ProduceType::Screen, 2))
+            .map(|change| change.map(|change| change.count))
+            .collect();
+        assert_eq!(counts, [None, Some(2), None, Some(4), None]);
+        assert_eq!(audience.count(screen), 5);
+
+        assert_eq!(audience.remove(screen, 2), None);
+        assert_eq!(audience.remove(screen, 2), None);
+        let change = audience.remove(screen, 2).unwrap();
+        assert_eq!(
+            change,
+            AudienceChange {
+                user_id: "alice".to_string(),
+                produce_type: ProduceType::Screen,
+                count: 2,
+            }
+        );
+
+        // Losing everyone is told however small the change
+        assert_eq!(audience.remove(screen, 2), None);
+        assert_eq!(
+            audience.remove(screen, 2).map(|change| change.count),
+            Some(0)
+        );
+        assert_eq!(audience.count(screen), 0);
+        assert_eq!(audience.remove(screen, 2), None);
+
+        // Counted all the same when nothing is announced
+        assert_eq!(audience.add(screen, "alice", ProduceType::Screen, 0), None);
+        assert_eq!(audience.count(screen), 1);
+    }
+}
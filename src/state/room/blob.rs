@@ -0,0 +1,446 @@
+//! Small files participants pass each other in chunks, such as an image dropped into the
+//! call. Chunks go on to the recipients' connections on this node as they come, the
+//! server only counts their bytes and never holds a whole blob. A transfer ends with the
+//! connection sending it, its recipients are then told it failed
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::future;
+use serde::{Deserialize, Serialize};
+
+use super::subscriber::SubscriberSignal;
+use super::{Room, RoomEvent};
+use crate::util::config::CONFIG;
+use crate::util::ulid;
+
+/// How long a chunk waits for room in a recipient's control channel before the recipient
+/// is dropped from the transfer. Senders are held back meanwhile, so a slow recipient
+/// slows the transfer down rather than having chunks pile up. Recipients are waited for
+/// at once, so this is as long as a chunk can take however many there are
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Part of a transfer passed on to each of its recipients, see `RoomEvent::RelayBlob`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BlobSignal {
+    Started {
+        transfer_id: String,
+        from: String,
+        size: usize,
+        mime: String,
+    },
+    /// Base64 data as the sender sent it
+    Chunk {
+        transfer_id: String,
+        seq: u64,
+        data: String,
+    },
+    Ended {
+        transfer_id: String,
+    },
+    /// The sender's connection ended, the blob went past or ended short of its size, or
+    /// the recipient didn't keep up. What was received of it is to be discarded
+    Failed {
+        transfer_id: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BlobError {
+    /// Declared size in bytes, above `signaling.max_blob_size`
+    TooLarge(usize),
+    /// ID of a transfer that doesn't exist or another connection sends
+    NotFound(String),
+    /// The user is sending `signaling.max_blob_transfers` blobs already
+    TooMany,
+    /// Sequence number the chunk should have had
+    OutOfOrder(u64),
+    /// The chunk would take the blob past its declared size
+    Overflow,
+    /// Bytes received when the blob was ended short of its declared size
+    Incomplete(usize),
+    /// Every recipient left or stopped keeping up
+    Undeliverable,
+}
+
+struct Transfer {
+    from: String,
+    /// Connection sending the blob
+    connection_id: u64,
+    recipients: Vec<u64>,
+    size: usize,
+    received: usize,
+    next_seq: u64,
+}
+
+/// Blob transfers going on in a room, by ID
+#[derive(Default)]
+pub struct BlobTransfers {
+    transfers: HashMap<String, Transfer>,
+}
+
+impl BlobTransfers {
+    /// Register a transfer from the user's connection, returning its ID. Transfers without
+    /// recipients are counted like any other, their chunks just go nowhere
+    pub fn start(
+        &mut self,
+        from: &str,
+        connection_id: u64,
+        recipients: Vec<u64>,
+        size: usize,
+    ) -> Result<String, BlobError> {
+        let signaling = &CONFIG.signaling;
+        if size > signaling.max_blob_size {
+            return Err(BlobError::TooLarge(size));
+        }
+        let sending = self
+            .transfers
+            .values()
+            .filter(|transfer| transfer.from == from)
+            .count();
+        if sending >= signaling.max_blob_transfers {
+            return Err(BlobError::TooMany);
+        }
+
+        let id = ulid::generate();
+        let transfer = Transfer {
+            from: from.to_string(),
+            connection_id,
+            recipients,
+            size,
+            received: 0,
+            next_seq: 0,
+        };
+        self.transfers.insert(id.clone(), transfer);
+        Ok(id)
+    }
+
+    /// Count a chunk of `len` bytes sent by the connection, returning where it goes. A
+    /// chunk taking the blob past its size removes the transfer
+    pub fn chunk(
+        &mut self,
+        id: &str,
+        connection_id: u64,
+        seq: u64,
+        len: usize,
+    ) -> Result<Vec<u64>, BlobError> {
+        let transfer = self.sent_by(id, connection_id)?;
+        if seq != transfer.next_seq {
+            return Err(BlobError::OutOfOrder(transfer.next_seq));
+        }
+        if transfer.received + len > transfer.size {
+            self.transfers.remove(id);
+            return Err(BlobError::Overflow);
+        }
+        transfer.received += len;
+        transfer.next_seq += 1;
+        Ok(transfer.recipients.clone())
+    }
+
+    /// Remove the connection's transfer once all of the blob was sent, returning its
+    /// recipients. Ending it short removes it all the same
+    pub fn end(&mut self, id: &str, connection_id: u64) -> Result<Vec<u64>, BlobError> {
+        let transfer = self.sent_by(id, connection_id)?;
+        let (received, size) = (transfer.received, transfer.size);
+        let transfer = self
+            .transfers
+            .remove(id)
+            .ok_or_else(|| BlobError::NotFound(id.to_string()))?;
+        match received == size {
+            true => Ok(transfer.recipients),
+            false => Err(BlobError::Incomplete(received)),
+        }
+    }
+
+    /// Stop passing the transfer on to a recipient, returning whether it has any left. A
+    /// transfer left without recipients is removed
+    pub fn drop_recipient(&mut self, id: &str, recipient: u64) -> bool {
+        let transfer = match self.transfers.get_mut(id) {
+            Some(transfer) => transfer,
+            None => return false,
+        };
+        transfer
+            .recipients
+            .retain(|connection_id| *connection_id != recipient);
+        if transfer.recipients.is_empty() {
+            self.transfers.remove(id);
+            return false;
+        }
+        true
+    }
+
+    /// Remove the transfers the connection was sending, with their recipients
+    pub fn abort_connection(&mut self, connection_id: u64) -> Vec<(String, Vec<u64>)> {
+        let aborted: Vec<String> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.connection_id == connection_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        aborted
+            .into_iter()
+            .filter_map(|id| {
+                let transfer = self.transfers.remove(&id)?;
+                Some((id, transfer.recipients))
+            })
+            .collect()
+    }
+
+    /// Recipients of a transfer, for telling them it failed
+    pub fn recipients(&self, id: &str) -> Vec<u64> {
+        self.transfers
+            .get(id)
+            .map(|transfer| transfer.recipients.clone())
+            .unwrap_or_default()
+    }
+
+    fn sent_by(&mut self, id: &str, connection_id: u64) -> Result<&mut Transfer, BlobError> {
+        match self.transfers.get_mut(id) {
+            Some(transfer) if transfer.connection_id == connection_id => Ok(transfer),
+            _ => Err(BlobError::NotFound(id.to_string())),
+        }
+    }
+}
+
+impl Room {
+    /// Register a transfer from the user's connection and tell the recipients it started,
+    /// returning its ID
+    pub async fn start_blob(
+        &self,
+        from: &str,
+        connection_id: u64,
+        recipients: Vec<u64>,
+        size: usize,
+        mime: &str,
+    ) -> Result<String, BlobError> {
+        let id = self
+            .blobs
+            .lock()
+            .start(from, connection_id, recipients.clone(), size)?;
+        let signal = BlobSignal::Started {
+            transfer_id: id.clone(),
+            from: from.to_string(),
+            size,
+            mime: mime.to_string(),
+        };
+        let undelivered = self.deliver_blob(recipients, signal).await;
+        self.drop_blob_recipients(&id, undelivered)?;
+        Ok(id)
+    }
+
+    /// Pass a chunk of the connection's transfer on. Recipients without room for it in
+    /// time are dropped from the transfer and told it failed, it fails for the sender
+    /// too once none are left
+    pub async fn relay_blob_chunk(
+        &self,
+        id: &str,
+        connection_id: u64,
+        seq: u64,
+        data: &str,
+        len: usize,
+    ) -> Result<(), BlobError> {
+        let (recipients, result) = {
+            let mut blobs = self.blobs.lock();
+            (
+                blobs.recipients(id),
+                blobs.chunk(id, connection_id, seq, len),
+            )
+        };
+        if let Err(BlobError::Overflow) = result {
+            self.fail_blob(id, recipients);
+            return Err(BlobError::Overflow);
+        }
+        let signal = BlobSignal::Chunk {
+            transfer_id: id.to_string(),
+            seq,
+            data: data.to_string(),
+        };
+        let undelivered = self.deliver_blob(result?, signal).await;
+        self.drop_blob_recipients(id, undelivered)
+    }
+
+    /// Tell the recipients the blob was sent whole, or that it failed if it was ended short
+    pub async fn end_blob(&self, id: &str, connection_id: u64) -> Result<(), BlobError> {
+        let (recipients, result) = {
+            let mut blobs = self.blobs.lock();
+            (blobs.recipients(id), blobs.end(id, connection_id))
+        };
+        if let Err(BlobError::Incomplete(received)) = result {
+            self.fail_blob(id, recipients);
+            return Err(BlobError::Incomplete(received));
+        }
+        let signal = BlobSignal::Ended {
+            transfer_id: id.to_string(),
+        };
+        // Those missing the end were sent the whole blob still
+        self.deliver_blob(result?, signal).await;
+        Ok(())
+    }
+
+    /// Tell the recipients of the transfers the connection was sending that they failed,
+    /// as it ended
+    pub(super) fn abort_blobs(&self, connection_id: u64) {
+        let aborted = self.blobs.lock().abort_connection(connection_id);
+        for (id, recipients) in aborted {
+            self.fail_blob(&id, recipients);
+        }
+    }
+
+    /// Pass a part of a transfer on to its recipients, waiting for all of them at once to
+    /// have room for it. Returns those that didn't in time or left
+    async fn deliver_blob(&self, recipients: Vec<u64>, signal: BlobSignal) -> Vec<u64> {
+        let deliveries = recipients.into_iter().map(|recipient| {
+            let control = self
+                .subscribers
+                .lock()
+                .get(&recipient)
+                .map(|handle| handle.control.clone());
+            let event = RoomEvent::RelayBlob(signal.clone());
+            async move {
+                let delivered = match control {
+                    Some(control) => {
+                        let send = control.send(SubscriberSignal::Event(event));
+                        matches!(
+                            tokio::time::timeout(DELIVERY_TIMEOUT, send).await,
+                            Ok(Ok(()))
+                        )
+                    }
+                    None => false,
+                };
+                (recipient, delivered)
+            }
+        });
+        future::join_all(deliveries)
+            .await
+            .into_iter()
+            .filter(|(_, delivered)| !delivered)
+            .map(|(recipient, _)| recipient)
+            .collect()
+    }
+
+    /// Stop passing the transfer on to the recipients, telling them it failed
+    fn drop_blob_recipients(&self, id: &str, recipients: Vec<u64>) -> Result<(), BlobError> {
+        if recipients.is_empty() {
+            return Ok(());
+        }
+        let mut left = true;
+        {
+            let mut blobs = self.blobs.lock();
+            for recipient in &recipients {
+                left = blobs.drop_recipient(id, *recipient);
+            }
+        }
+        self.fail_blob(id, recipients);
+        match left {
+            true => Ok(()),
+            false => Err(BlobError::Undeliverable),
+        }
+    }
+
+    /// Tell the recipients the transfer failed, dropped for those whose control channel
+    /// is full
+    fn fail_blob(&self, id: &str, recipients: Vec<u64>) {
+        for recipient in recipients {
+            let signal = BlobSignal::Failed {
+                transfer_id: id.to_string(),
+            };
+            let event = RoomEvent::RelayBlob(signal);
+            self.signal_subscriber(recipient, SubscriberSignal::Event(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::subscriber::{SubscriberMessage, SubscriberOptions};
+    use super::super::RoomSettings;
+    use super::*;
+    use crate::util::testing;
+    use crate::ws::types::SignalingTransport;
+
+    #[test]
+    fn transfers_are_counted_and_checked() {
+        let mut blobs = BlobTransfers::default();
+        let too_large = CONFIG.signaling.max_blob_size + 1;
+        assert_eq!(
+            blobs.start("alice", 1, vec![2], too_large),
+            Err(BlobError::TooLarge(too_large))
+        );
+
+        let id = blobs.start("alice", 1, vec![2, 3], 10).unwrap();
+        assert_eq!(blobs.chunk(&id, 1, 1, 4), Err(BlobError::OutOfOrder(0)));
+        assert_eq!(
+            blobs.chunk(&id, 4, 0, 4),
+            Err(BlobError::NotFound(id.clone()))
+        );
+        assert_eq!(blobs.chunk(&id, 1, 0, 4), Ok(vec![2, 3]));
+        assert!(blobs.drop_recipient(&id, 2));
+        assert_eq!(blobs.chunk(&id, 1, 1, 6), Ok(vec![3]));
+        assert_eq!(blobs.end(&id, 1), Ok(vec![3]));
+        assert_eq!(blobs.end(&id, 1), Err(BlobError::NotFound(id)));
+
+        // Going past the size or ending short of it ends the transfer
+        let id = blobs.start("alice", 1, vec![2], 10).unwrap();
+        assert_eq!(blobs.chunk(&id, 1, 0, 11), Err(BlobError::Overflow));
+        assert!(blobs.recipients(&id).is_empty());
+        let id = blobs.start("alice", 1, vec![2], 10).unwrap();
+        blobs.chunk(&id, 1, 0, 4).unwrap();
+        assert_eq!(blobs.end(&id, 1), Err(BlobError::Incomplete(4)));
+
+        for _ in 0..CONFIG.signaling.max_blob_transfers {
+            blobs.start("alice", 1, vec![2], 10).unwrap();
+        }
+        assert_eq!(
+            blobs.start("alice", 1, vec![2], 10),
+            Err(BlobError::TooMany)
+        );
+        assert!(blobs.start("bob", 2, vec![1], 10).is_ok());
+        let aborted = blobs.abort_connection(1);
+        assert_eq!(aborted.len(), CONFIG.signaling.max_blob_transfers);
+        assert!(aborted.iter().all(|(_, recipients)| recipients == &[2]));
+        assert!(blobs.start("alice", 1, vec![2], 10).is_ok());
+    }
+
+    #[tokio::test]
+    async fn slow_recipients_dont_hold_up_the_others() {
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let _alice = room.subscribe(1, "alice", options).unwrap();
+        let mut bob = room.subscribe(2, "bob", options).unwrap();
+        let carol = room.subscribe(3, "carol", options).unwrap();
+
+        let id = room
+            .start_blob("alice", 1, vec![3, 2], 4, "text/plain")
+            .await
+            .unwrap();
+        assert!(matches!(
+            bob.recv().await,
+            SubscriberMessage::Event(RoomEvent::RelayBlob(BlobSignal::Started { .. }))
+        ));
+        // Carol never reads, so the control channel fills up
+        let filler = || {
+            let signal = BlobSignal::Ended {
+                transfer_id: "filler".to_string(),
+            };
+            SubscriberSignal::Event(RoomEvent::RelayBlob(signal))
+        };
+        while room.signal_subscriber(3, filler()) {}
+
+        let relaying = tokio::spawn({
+            let room = room.clone();
+            async move { room.relay_blob_chunk(&id, 1, 0, "aGkh", 3).await }
+        });
+        let received = tokio::time::timeout(DELIVERY_TIMEOUT / 5, bob.recv()).await;
+        assert!(matches!(
+            received,
+            Ok(SubscriberMessage::Event(RoomEvent::RelayBlob(
+                BlobSignal::Chunk { seq: 0, .. }
+            )))
+        ));
+        relaying.abort();
+        drop(carol);
+        room.delete().await;
+    }
+}
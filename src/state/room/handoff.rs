@@ -0,0 +1,124 @@
+//! Moving a room to another instance, so a node can be replaced without its calls
+//! dropping. The old instance exports the room's signaling state, the new one imports
+//! it and issues every user a join token of its own, and the old one tells each
+//! connected client to reconnect to the new instance with its token. Membership, roles,
+//! bans and metadata make the hop, media is negotiated again from scratch
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::subscriber::SubscriberSignal;
+use super::token::JoinToken;
+use super::{Ban, Room, RoomEvent, RoomSettings};
+use crate::api::ApiError;
+use crate::state::user::{ProducerInfo, UserInfoUpdate, UserOptions};
+
+/// Signaling state of a room, as `GET /room/{id}/export` serves it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomExport {
+    /// Settings in effect, owner included
+    pub settings: RoomSettings,
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+    #[serde(default)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub users: Vec<ExportedUser>,
+}
+
+/// User of the room, connected or still to redeem its join token
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedUser {
+    pub id: String,
+    pub options: UserOptions,
+    #[serde(default)]
+    pub profile: UserInfoUpdate,
+    /// Whether a client holds the user's session, and is to be moved
+    #[serde(default)]
+    pub connected: bool,
+    /// Producers running as the room was exported. Not started again on import, the
+    /// client produces anew once it reconnected
+    #[serde(default)]
+    pub producers: Vec<ProducerInfo>,
+}
+
+impl Room {
+    /// The room's signaling state, for `Room::import` to set up on another instance
+    pub async fn export(&self) -> RoomExport {
+        let metadata = self.metadata().await;
+        let mut users = Vec::new();
+        for user in self.users.read().await.values() {
+            let user = user.read().await;
+            users.push(ExportedUser {
+                id: user.id().to_string(),
+                options: user.options(),
+                profile: user.profile(),
+                connected: user.registered(),
+                producers: user.producers(),
+            });
+        }
+        users.sort_by(|a, b| a.id.cmp(&b.id));
+
+        RoomExport {
+            settings: metadata.settings,
+            bans: self.bans(),
+            metadata: metadata.values,
+            users,
+        }
+    }
+
+    /// Create a room exported from another instance, with a join token for each of its
+    /// users by ID. Tokens of the other instance aren't valid here, clients need these
+    pub async fn import(
+        tenant: &str,
+        id: String,
+        export: RoomExport,
+    ) -> Result<(Arc<Room>, Vec<(String, JoinToken)>), ApiError> {
+        let room = Room::new(tenant, id, export.settings).await?;
+        room.bans
+            .lock()
+            .extend(export.bans.into_iter().map(|ban| (ban.user_id, ban.reason)));
+        *room.metadata.lock() = export.metadata;
+
+        let users = room.users();
+        let mut tokens = Vec::with_capacity(export.users.len());
+        for exported in export.users {
+            let token = match users.create(exported.id.clone(), exported.options).await {
+                Ok(token) => token,
+                Err(err) => {
+                    drop(users);
+                    room.delete().await;
+                    return Err(err);
+                }
+            };
+            if let Some(user) = users.get(&exported.id).await {
+                user.write().await.set_info(exported.profile);
+            }
+            tokens.push((exported.id, token));
+        }
+        Ok((room, tokens))
+    }
+
+    /// Tell the connected users with a token to reconnect to `url` with it, returning
+    /// the IDs of those told. Their sessions here end as their clients move
+    pub fn hand_off(&self, url: &str, tokens: &HashMap<String, String>) -> Vec<String> {
+        let mut told = Vec::new();
+        for subscriber in self.subscribers() {
+            let token = match tokens.get(&subscriber.user_id) {
+                Some(token) => token.clone(),
+                None => continue,
+            };
+            let event = RoomEvent::MigrateTo(url.to_string(), token);
+            if self.signal_subscriber(subscriber.connection_id, SubscriberSignal::Event(event)) {
+                told.push(subscriber.user_id);
+            }
+        }
+        told.sort();
+        told.dedup();
+        told
+    }
+}
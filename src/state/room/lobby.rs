@@ -0,0 +1,105 @@
+use std::collections::HashSet;
+
+/// Users joining a locked room wait here until a moderator opens the room or lets them
+/// in, see `RoomSettings::lobby`
+pub struct Lobby {
+    locked: bool,
+    /// In the order they arrived
+    waiting: Vec<String>,
+    /// Let in ahead of the room opening, kept while they're in the room so resuming a
+    /// session doesn't send them back
+    admitted: HashSet<String>,
+}
+
+impl Lobby {
+    pub fn new(locked: bool) -> Self {
+        Lobby {
+            locked,
+            waiting: Vec::new(),
+            admitted: HashSet::new(),
+        }
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn waiting(&self) -> &[String] {
+        &self.waiting
+    }
+
+    pub fn is_waiting(&self, user_id: &str) -> bool {
+        self.waiting.iter().any(|id| id == user_id)
+    }
+
+    /// Queue a user joining the room, `false` if it may come in. A user already waiting
+    /// keeps its place
+    pub fn enter(&mut self, user_id: &str) -> bool {
+        if !self.locked || self.admitted.contains(user_id) {
+            return false;
+        }
+        if !self.is_waiting(user_id) {
+            self.waiting.push(user_id.to_string());
+        }
+        true
+    }
+
+    /// Let a waiting user in while the room stays locked, `false` if it wasn't waiting
+    pub fn admit(&mut self, user_id: &str) -> bool {
+        let waiting = self.waiting.len();
+        self.waiting.retain(|id| id != user_id);
+        if self.waiting.len() == waiting {
+            return false;
+        }
+        self.admitted.insert(user_id.to_string());
+        true
+    }
+
+    /// Unlock the room, letting everyone in. `false` if it wasn't locked
+    pub fn open(&mut self) -> bool {
+        if !self.locked {
+            return false;
+        }
+        self.locked = false;
+        self.waiting.clear();
+        self.admitted.clear();
+        true
+    }
+
+    /// Forget a user that left the room, it waits again if it comes back
+    pub fn leave(&mut self, user_id: &str) {
+        self.waiting.retain(|id| id != user_id);
+        self.admitted.remove(user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_wait_until_admitted_or_the_room_opens() {
+        let mut lobby = Lobby::new(true);
+        assert!(lobby.enter("alice"));
+        assert!(lobby.enter("bob"));
+        assert!(lobby.enter("carol"));
+        // Reconnecting keeps the place in line
+        assert!(lobby.enter("alice"));
+        assert_eq!(lobby.waiting(), ["alice", "bob", "carol"]);
+
+        // Admitted users come back in, until they leave
+        assert!(lobby.admit("bob"));
+        assert!(!lobby.admit("bob"));
+        assert!(!lobby.enter("bob"));
+        lobby.leave("bob");
+        assert!(lobby.enter("bob"));
+        lobby.leave("carol");
+        assert_eq!(lobby.waiting(), ["alice", "bob"]);
+
+        assert!(lobby.open());
+        assert!(!lobby.open());
+        assert!(!lobby.locked());
+        assert!(lobby.waiting().is_empty());
+        assert!(!lobby.enter("dave"));
+    }
+}
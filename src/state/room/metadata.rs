@@ -0,0 +1,165 @@
+use std::fmt::{self, Display};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use super::RoomSettings;
+use crate::util::config::QualityProfile;
+
+/// Longest key of the room's metadata, in bytes
+pub const MAX_METADATA_KEY: usize = 64;
+/// Most bytes the room's metadata may take serialized as JSON, keeping it a scratch space
+/// rather than a message bus
+pub const MAX_METADATA_SIZE: usize = 16 * 1024;
+
+/// How long a room has been running and what it's set up with, shown to clients
+/// and over the management API
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMetadata {
+    #[serde(serialize_with = "rfc3339")]
+    pub created_at: DateTime<Utc>,
+    /// Last time a user joined, left, or started or stopped producing
+    #[serde(serialize_with = "rfc3339")]
+    pub last_activity: DateTime<Utc>,
+    /// Settings the room was created with, updated with any changed since
+    pub settings: RoomSettings,
+    /// User owning the room, see `RoomSettings::owner`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Ceilings of the room's `qualityProfile`, for clients to configure their encoders with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityProfile>,
+    /// Values moderators set for everyone in the room, see `Room::set_metadata`
+    #[serde(rename = "metadata")]
+    pub values: Map<String, Value>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MetadataError {
+    /// Length of the key in bytes
+    KeyTooLong(usize),
+    /// Size the metadata would have grown to, in bytes
+    TooLarge(usize),
+}
+
+impl Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::KeyTooLong(length) => write!(
+                f,
+                "Metadata key of {} bytes is above the limit of {}",
+                length, MAX_METADATA_KEY
+            ),
+            MetadataError::TooLarge(size) => write!(
+                f,
+                "Room metadata of {} bytes would be above the limit of {}",
+                size, MAX_METADATA_SIZE
+            ),
+        }
+    }
+}
+
+/// Set `key` in the metadata, unless it would outgrow the limits
+pub fn set_value(
+    values: &mut Map<String, Value>,
+    key: String,
+    value: Value,
+) -> Result<(), MetadataError> {
+    if key.len() > MAX_METADATA_KEY {
+        return Err(MetadataError::KeyTooLong(key.len()));
+    }
+
+    let mut updated = values.clone();
+    updated.insert(key, value);
+    let size = serde_json::to_vec(&updated).map_or(usize::MAX, |bytes| bytes.len());
+    if size > MAX_METADATA_SIZE {
+        return Err(MetadataError::TooLarge(size));
+    }
+
+    *values = updated;
+    Ok(())
+}
+
+impl RoomMetadata {
+    pub fn video_allowed(&self) -> bool {
+        self.settings.producer_limits.video > 0
+    }
+}
+
+fn rfc3339<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn metadata_shape() {
+        let metadata = RoomMetadata {
+            created_at: Utc.ymd(2021, 6, 1).and_hms_milli(12, 0, 0, 250),
+            last_activity: Utc.ymd(2021, 6, 1).and_hms(12, 30, 5),
+            settings: RoomSettings {
+                max_users: Some(8),
+                persistent: true,
+                ..RoomSettings::default()
+            },
+            owner: None,
+            quality: None,
+            values: Map::new(),
+        };
+
+        assert!(metadata.video_allowed());
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            json!({
+                "createdAt": "2021-06-01T12:00:00.250Z",
+                "lastActivity": "2021-06-01T12:30:05.000Z",
+                "settings": {
+                    "maxUsers": 8,
+                    "owner": null,
+                    "ownerLeavePolicy": "none",
+                    "lobby": false,
+                    "fastJoin": null,
+                    "producerLimits": { "audio": 1, "video": 1, "screenshare": 1 },
+                    "maxIncomingBitrate": null,
+                    "bitrateBudget": null,
+                    "hls": null,
+                    "persistent": true,
+                    "echo": false,
+                    "codecs": null,
+                    "scalabilityModes": null,
+                    "opusLimits": { "stereo": true, "maxAverageBitrate": null },
+                    "bitrateLimits": { "audio": null, "video": null, "screenshare": null },
+                    "qualityProfile": null,
+                    "recordingAllowed": true,
+                    "requireRecordingConsent": false,
+                    "audioLevelThresholdDb": null,
+                    "audioLevelIntervalMs": null,
+                },
+                "metadata": {},
+            })
+        );
+    }
+
+    #[test]
+    fn metadata_stays_within_limits() {
+        let mut values = Map::new();
+        set_value(&mut values, "agenda".to_string(), json!({ "item": 2 })).unwrap();
+        assert_eq!(values["agenda"]["item"], 2);
+
+        let key = "k".repeat(MAX_METADATA_KEY + 1);
+        let result = set_value(&mut values, key, json!(true));
+        assert_eq!(result, Err(MetadataError::KeyTooLong(MAX_METADATA_KEY + 1)));
+
+        let layout = json!("x".repeat(MAX_METADATA_SIZE));
+        let result = set_value(&mut values, "layout".to_string(), layout);
+        assert!(matches!(result, Err(MetadataError::TooLarge(_))));
+        assert_eq!(values.len(), 1);
+    }
+}
@@ -0,0 +1,332 @@
+//! Moving a live room's router to another worker, to even out the load that builds up on
+//! long running nodes. The room's producers are piped onto the new router and its
+//! connections told to initialize their transports again, as after a media restart.
+//! Those that moved can consume the users that haven't yet through the pipes, and the old
+//! router keeps serving the rest until they moved too. A step failing before the switch
+//! leaves the room on its old router as if nothing happened
+
+use std::fmt::{self, Display};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+
+use mediasoup::prelude::*;
+use mediasoup::worker::WorkerId;
+use serde::Serialize;
+
+use super::{router_options, Room, ROOMS};
+use crate::rtc::get_worker_pool;
+use crate::rtc::pool::TransportPool;
+use crate::state::user::ProduceType;
+use crate::util::config::CONFIG;
+
+/// Longest the old router is kept for connections that didn't move yet
+pub const DRAIN: Duration = Duration::from_secs(30);
+/// How often the old router is checked for connections still on it
+const DRAIN_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Step {
+    CreateRouter,
+    PipeProducers,
+    SwitchRouter,
+    RestartTransports,
+    DrainOldRouter,
+}
+
+/// How a step of the migration went
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StepReport {
+    pub step: Step,
+    pub ok: bool,
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Outcome of `Room::migrate`, step by step. An aborted migration stopped at its failed
+/// step, with the room still on the worker it was on
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Migration {
+    pub from: WorkerId,
+    pub to: WorkerId,
+    pub aborted: bool,
+    pub steps: Vec<StepReport>,
+}
+
+impl Migration {
+    fn step(&mut self, step: Step, started: Instant, detail: Option<String>) {
+        self.steps.push(StepReport {
+            step,
+            ok: true,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            detail,
+        });
+    }
+
+    fn abort(mut self, step: Step, started: Instant, err: impl Display) -> Migration {
+        self.steps.push(StepReport {
+            step,
+            ok: false,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            detail: Some(err.to_string()),
+        });
+        self.aborted = true;
+        self
+    }
+}
+
+/// Why a migration wasn't started
+#[derive(Debug, PartialEq)]
+pub enum MigrationError {
+    Closed,
+    InProgress,
+    /// No other worker is alive to move to
+    NoTarget,
+    WorkerNotFound(String),
+    SameWorker,
+    /// Media leaving the router, which would be cut: recording, broadcast, HLS or playback
+    Egress(&'static str),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Closed => write!(f, "Room is closed"),
+            MigrationError::InProgress => write!(f, "Room is already being migrated"),
+            MigrationError::NoTarget => write!(f, "No other worker to migrate the room to"),
+            MigrationError::WorkerNotFound(id) => write!(f, "No live worker with ID {}", id),
+            MigrationError::SameWorker => write!(f, "Room is already on that worker"),
+            MigrationError::Egress(what) => {
+                write!(f, "Room can't be migrated while its {} is running", what)
+            }
+        }
+    }
+}
+
+/// Worker to move a room away from `from` to, the one asked for or the one with the
+/// fewest rooms
+async fn target(from: WorkerId, requested: Option<&str>) -> Result<Worker, MigrationError> {
+    let workers = get_worker_pool().workers();
+    if let Some(requested) = requested {
+        let worker = workers
+            .into_iter()
+            .find(|worker| worker.id().to_string() == requested)
+            .ok_or_else(|| MigrationError::WorkerNotFound(requested.to_string()))?;
+        return match worker.id() == from {
+            true => Err(MigrationError::SameWorker),
+            false => Ok(worker),
+        };
+    }
+
+    let rooms = ROOMS.read().await;
+    workers
+        .into_iter()
+        .filter(|worker| worker.id() != from)
+        .min_by_key(|worker| {
+            rooms
+                .values()
+                .filter(|room| room.worker_id() == worker.id())
+                .count()
+        })
+        .ok_or(MigrationError::NoTarget)
+}
+
+impl Room {
+    /// Move the room's router to another worker, see the module. Refused while media
+    /// leaves the router. Producers started on the old router after the switch aren't
+    /// piped, only the connections still on it receive them
+    pub async fn migrate(
+        self: &Arc<Self>,
+        worker_id: Option<&str>,
+    ) -> Result<Migration, MigrationError> {
+        let from = self.worker_id();
+        let worker = target(from, worker_id).await?;
+        self.check_egress().await?;
+        if self.migrating.swap(true, Ordering::AcqRel) {
+            return Err(MigrationError::InProgress);
+        }
+
+        let migration = self.migrate_to(worker).await;
+        self.migrating.store(false, Ordering::Release);
+        migration
+    }
+
+    async fn check_egress(&self) -> Result<(), MigrationError> {
+        if self.recording.lock().await.is_some() {
+            return Err(MigrationError::Egress("recording"));
+        }
+        if self.broadcast.lock().await.is_some() {
+            return Err(MigrationError::Egress("broadcast"));
+        }
+        if self.hls.lock().await.is_some() {
+            return Err(MigrationError::Egress("HLS packaging"));
+        }
+        if self.playback.lock().await.is_some() {
+            return Err(MigrationError::Egress("playback"));
+        }
+        Ok(())
+    }
+
+    async fn migrate_to(self: &Arc<Self>, worker: Worker) -> Result<Migration, MigrationError> {
+        let old_router = self.router().ok_or(MigrationError::Closed)?;
+        let mut migration = Migration {
+            from: self.worker_id(),
+            to: worker.id(),
+            aborted: false,
+            steps: Vec::new(),
+        };
+        info!(
+            "Migrating room {} from worker {} to {}",
+            self.id, migration.from, migration.to
+        );
+
+        let started = Instant::now();
+        let codecs = self
+            .settings
+            .codecs
+            .as_deref()
+            .unwrap_or(&CONFIG.rtc.codecs);
+        let router = match worker.create_router(router_options(codecs)).await {
+            Ok(router) => router,
+            Err(err) => return Ok(migration.abort(Step::CreateRouter, started, err)),
+        };
+        migration.step(Step::CreateRouter, started, Some(router.id().to_string()));
+
+        // Dropping the pipes and the new router on the way out of a failure closes them,
+        // the old router never stopped serving
+        let started = Instant::now();
+        let mut pipes = Vec::new();
+        for producer_id in self.producer_ids().await {
+            let options = PipeToRouterOptions::new(router.clone());
+            match old_router
+                .pipe_producer_to_router(producer_id, options)
+                .await
+            {
+                Ok(pipe) => pipes.push(pipe),
+                Err(err) => return Ok(migration.abort(Step::PipeProducers, started, err)),
+            }
+        }
+        let detail = format!("{} producers", pipes.len());
+        migration.step(Step::PipeProducers, started, Some(detail));
+
+        let started = Instant::now();
+        if self.closed() {
+            return Ok(migration.abort(Step::SwitchRouter, started, MigrationError::Closed));
+        }
+        *self.transport_pool.lock() = TransportPool::start(&router);
+        *self.router.lock() = router;
+        *self.worker_id.lock() = migration.to;
+        self.keyframe_requests.lock().clear();
+        self.stats_cache.lock().take();
+        // The speakers are observed on the new router, where every producer is piped
+        let (threshold_db, interval_ms) = self.audio_level_options().await;
+        if self
+            .observe_audio_levels(threshold_db, interval_ms)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Failed to observe the audio levels of room {} on its new router",
+                self.id
+            );
+        }
+        migration.step(Step::SwitchRouter, started, None);
+
+        let started = Instant::now();
+        let restarted = self.restart_transports().await;
+        let detail = format!("{} connections", restarted);
+        migration.step(Step::RestartTransports, started, Some(detail));
+
+        let started = Instant::now();
+        let id = self.id.clone();
+        tokio::spawn(async move {
+            let deadline = Instant::now() + DRAIN;
+            while Instant::now() < deadline && serving(&old_router, !pipes.is_empty()).await {
+                tokio::time::sleep(DRAIN_CHECK).await;
+            }
+            drop(pipes);
+            info!("Released the router room {} migrated away from", id);
+        });
+        let detail = format!("released in at most {} seconds", DRAIN.as_secs());
+        migration.step(Step::DrainOldRouter, started, Some(detail));
+        Ok(migration)
+    }
+
+    /// Producers of every user connected to this node
+    async fn producer_ids(&self) -> Vec<ProducerId> {
+        let mut ids = Vec::new();
+        for user in self.users.read().await.values() {
+            let user = user.read().await;
+            ids.extend(
+                ProduceType::ALL
+                    .iter()
+                    .filter_map(|produce_type| user.get_producer(*produce_type))
+                    .filter(|producer| !producer.closed())
+                    .map(|producer| producer.id()),
+            );
+        }
+        ids
+    }
+}
+
+/// Whether connections still have transports on the router, besides the one carrying
+/// its pipes
+async fn serving(router: &Router, piped: bool) -> bool {
+    let pipe_transports = if piped { 1 } else { 0 };
+    match router.dump().await {
+        Ok(dump) => dump.transport_ids.len() > pipe_transports,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn rooms_move_to_another_worker() {
+        let room = testing::room(RoomSettings::default()).await;
+        let from = room.worker_id();
+        let old_router = room.router().unwrap();
+
+        let same = from.to_string();
+        assert_eq!(
+            room.migrate(Some(&same)).await.unwrap_err(),
+            MigrationError::SameWorker
+        );
+        assert_eq!(
+            room.migrate(Some("nowhere")).await.unwrap_err(),
+            MigrationError::WorkerNotFound("nowhere".to_string())
+        );
+
+        let migration = room.migrate(None).await.unwrap();
+        assert!(!migration.aborted);
+        assert_eq!(migration.from, from);
+        assert_ne!(migration.to, from);
+        assert_eq!(room.worker_id(), migration.to);
+        assert_ne!(room.router().unwrap().id(), old_router.id());
+        let steps: Vec<Step> = migration.steps.iter().map(|step| step.step).collect();
+        assert_eq!(
+            steps,
+            [
+                Step::CreateRouter,
+                Step::PipeProducers,
+                Step::SwitchRouter,
+                Step::RestartTransports,
+                Step::DrainOldRouter,
+            ]
+        );
+        assert!(migration.steps.iter().all(|step| step.ok));
+
+        // And back again
+        let back = from.to_string();
+        let migration = room.migrate(Some(&back)).await.unwrap();
+        assert_eq!(migration.to, from);
+        room.delete().await;
+    }
+}
@@ -1,97 +1,652 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Weak,
 };
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
+use mediasoup::consumer::Consumer;
+use mediasoup::producer::{Producer, ProducerId};
 use mediasoup::router::{Router, RouterOptions};
+use mediasoup::worker::WorkerId;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use strum::IntoStaticStr;
 use tokio::sync::{
-    broadcast::{self, Receiver, Sender},
-    RwLock,
+    broadcast::{self, error::RecvError, Receiver, Sender},
+    mpsc, oneshot, Mutex as AsyncMutex, RwLock,
 };
+use tokio::task::JoinHandle;
 
-use super::user::{ProduceType, User};
+use super::store::get_store;
+use super::user::{ConnectionState, Permission, ProduceType, Role, User, UserInfo};
+use crate::integrations::{format::LifecycleEvent, redis::get_redis, webhook::get_webhook};
+use crate::rtc::audio_level::{AudioLevels, SpeakerVolume};
+use crate::rtc::broadcast::{
+    Broadcast, BroadcastError, BroadcastSources, BroadcastState, StartBroadcast,
+};
+use crate::rtc::hls::{HlsError, HlsPackager};
+use crate::rtc::playback::{Playback, PlaybackError, PlaybackSource, SYSTEM_USER_ID};
+use crate::rtc::pool::TransportPool;
+use crate::rtc::quality::QualityLevel;
+use crate::rtc::recording::{Recording, RecordingError, TrackSource};
+use crate::rtc::stats::ConnectionStats;
+use crate::rtc::Bitrates;
+use crate::util::config::{validate_codecs, CodecConfig, QualityProfile, CONFIG, DEFAULT_TENANT};
+use crate::util::metrics::{ROOM_NACKS, ROOM_PLIS, ROOM_RETRANSMITTED_BYTES};
+use crate::ws::types::MediaClosedReason;
 use crate::{api::ApiError, rtc::get_worker_pool};
 
+pub mod announcements;
+pub mod audience;
+pub mod blob;
+pub mod handoff;
+pub mod lobby;
+pub mod metadata;
+pub mod migration;
+pub mod occupancy;
+pub mod positions;
+pub mod recent;
+pub mod retransmissions;
+pub mod roster;
+pub mod settings;
+pub mod stats;
+pub mod subscriber;
+pub mod throttle;
+pub mod token;
+pub mod usage;
 pub mod users;
+pub use handoff::{ExportedUser, RoomExport};
+pub use metadata::{MetadataError, RoomMetadata};
+pub use migration::{Migration, MigrationError};
+pub use occupancy::{Occupancy, OccupancyCounts};
+pub use positions::{Position, UserPosition};
+pub use retransmissions::{QualityAlert, RetransmissionCounts};
+pub use settings::{
+    HlsSettings, IdleSettings, OwnerLeavePolicy, ProducerLimits, RetransmissionSettings,
+    RoomSettings, RoomSettingsUpdate,
+};
+pub use stats::{RoomStats, UserStats};
+pub use subscriber::RoomSubscriber;
+pub use throttle::ThrottleState;
+pub use usage::{CallSummary, RoomSummary, TalkStats, Usage};
 pub use users::RoomUsers;
 
-#[derive(Clone, Debug)]
+use announcements::{Announce, Announcements};
+use audience::{Audience, AudienceChange};
+use blob::{BlobSignal, BlobTransfers};
+use lobby::Lobby;
+use positions::{Positions, POSITION_TICK};
+use recent::RecentEvents;
+use retransmissions::RetransmissionWindow;
+use roster::Roster;
+use subscriber::{
+    BroadcastEvent, SubscriberHandle, SubscriberInfo, SubscriberOptions, SubscriberSignal,
+};
+use throttle::{Throttle, THROTTLE_INTERVAL};
+
+#[derive(Clone, Debug, Serialize, Deserialize, IntoStaticStr)]
 pub enum RoomEvent {
-    UserJoined(String),
-    UserLeft(String),
-    UserStartProduce(String, ProduceType),
+    /// The user and what it looked like as it registered
+    UserJoined(String, UserInfo),
+    /// The user was removed, kicked if the last field is set
+    UserLeft(String, bool),
+    /// The producer of the type was started, paused if the last field is set
+    UserStartProduce(String, ProduceType, bool),
     UserStopProduce(String, ProduceType),
-    RoomDelete,
+    /// The user's producer of the type was paused or resumed
+    UserProducerPauseChanged(String, ProduceType, bool),
+    /// The user's producer of the type was swapped for a new one
+    UserProducerReplaced(String, ProduceType),
+    /// Consumers of the user's producer of the type across the room, sent once the count
+    /// moved by `signaling.audience_delta` since the user was last told
+    ProducerAudience(String, ProduceType, usize),
+    UserInfoUpdated(String),
+    UserRoleChanged(String, Role),
+    /// The room changed hands, or was left without an owner
+    OwnerChanged(Option<String>),
+    /// The user joined the locked room and waits in its lobby
+    UserWaiting(String),
+    /// A moderator let the user in from the lobby, the room stays locked
+    UserAdmitted(String),
+    /// The locked room was opened, everyone waiting in the lobby comes in
+    RoomOpened,
+    /// A user reported another to the moderators, sent to their connections only
+    UserReported(UserReport),
+    /// The room's media crossed a threshold of its `RetransmissionSettings`, sent to the
+    /// connections of its moderators only
+    QualityAlert(QualityAlert),
+    /// The user's connection dropped and it's left for the client to resume the session,
+    /// or it did
+    UserConnectionStateChanged(String, ConnectionState),
+    /// Quality of the user's connection went up or down a level
+    UserQualityChanged(String, QualityLevel),
+    /// The user did nothing for the room's `IdleSettings::idle_after_secs`
+    UserIdle(String),
+    /// The user did something again after it went idle
+    UserActive(String),
+    /// Loudest users over the last audio level interval, none once everyone went quiet
+    ActiveSpeakers(Vec<SpeakerVolume>),
+    /// Everyone who updated their position lately, sent on a tick when any changed
+    Positions(Vec<UserPosition>),
+    /// Application message from a user, passed on to everyone else as is
+    Relay(String, serde_json::Value),
+    /// Part of a blob transfer, sent to its recipients' connections only
+    RelayBlob(BlobSignal),
+    /// Whether the room is being recorded
+    RecordingStateChanged(bool),
+    /// The users were asked whether they consent to being recorded, see
+    /// `RoomSettings::require_recording_consent`
+    RecordingConsentRequested(Vec<String>),
+    /// Settings in effect after a moderator or the management API changed them
+    RoomSettingsChanged(RoomSettings),
+    /// A key of the room's metadata was set to the value, or deleted
+    RoomMetadataChanged(String, Option<serde_json::Value>),
+    /// The room went over its bitrate budget and was throttled to the level, or eased off
+    /// to it, 0 being no longer throttled
+    RoomThrottled(u8),
+    BroadcastStateChanged(BroadcastState),
+    /// The room moved to another instance, whose URL and the user's join token there
+    /// are sent to its connection only. See `Room::hand_off`
+    MigrateTo(String, String),
+    /// The call ended as the room emptied or was deleted, sent to the connections still
+    /// there and never broadcast
+    CallSummary(CallSummary),
+    RoomDelete(RoomSummary),
+}
+
+/// Complaint about a user, see `Room::signal_moderators`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserReport {
+    pub id: String,
+    pub reporter: String,
+    pub target: String,
+    pub reason: String,
+}
+
+/// User refused when it authenticates, see `Room::ban`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+/// Move the rooms on a worker that died onto the one that replaced it, see
+/// `Room::restart_media`
+pub async fn restart_media(dead: WorkerId) {
+    let rooms: Vec<Arc<Room>> = ROOMS
+        .read()
+        .await
+        .values()
+        .filter(|room| room.worker_id() == dead)
+        .cloned()
+        .collect();
+    for room in rooms {
+        room.restart_media().await;
+    }
+}
+
+fn router_options(codecs: &[CodecConfig]) -> RouterOptions {
+    let mut options = RouterOptions::default();
+    options.media_codecs = crate::rtc::media_codecs(codecs);
+    options
 }
 
 lazy_static! {
-    pub static ref ROOMS: RwLock<HashMap<String, Arc<Room>>> = RwLock::new(HashMap::new());
+    /// Rooms by tenant and ID, see `Room::get`
+    pub static ref ROOMS: RwLock<HashMap<(String, String), Arc<Room>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Tenant and ID of a room from its scoped ID, see `Room::scoped_id`
+pub fn split_scoped(scoped_id: &str) -> (&str, &str) {
+    match scoped_id.split_once('/') {
+        Some((tenant, id)) if tenant != DEFAULT_TENANT && CONFIG.api.has_tenant(tenant) => {
+            (tenant, id)
+        }
+        _ => (DEFAULT_TENANT, scoped_id),
+    }
 }
 
-pub type RoomUserMap = HashMap<String, RwLock<User>>;
+/// How long sampled stats are served before the worker is asked again, so dashboards
+/// polling many rooms don't load it
+const STATS_TTL: Duration = Duration::from_secs(2);
+/// How long the management API waits for a connection to gather its stats
+const CONNECTION_STATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type RoomUserMap = HashMap<String, Arc<RwLock<User>>>;
 pub type RoomRegistrationMap = HashMap<String, String>;
 
+/// HLS packaging of a room, refreshed as its users start and stop producing
+struct RoomHls {
+    packager: HlsPackager,
+    video_user: Option<String>,
+    watcher: JoinHandle<()>,
+}
+
 pub struct Room {
     id: String,
+    tenant: String,
+    /// ID qualified by the tenant, see `Room::scoped_id`
+    scoped_id: String,
     closed: AtomicBool,
-    router: Router,
-    sender: Sender<RoomEvent>,
+    bridged: AtomicBool,
+    /// Replaced along with the worker it's on by `restart_media` if the worker dies
+    router: Mutex<Router>,
+    /// Transports kept ready on the router, replaced along with it
+    transport_pool: Mutex<Option<Arc<TransportPool>>>,
+    worker_id: Mutex<WorkerId>,
+    /// Set while `migrate` moves the router to another worker
+    migrating: AtomicBool,
+    sender: Sender<BroadcastEvent>,
+    settings: RoomSettings,
+    occupancy: Occupancy,
+    usage: Usage,
+    subscribers: Mutex<HashMap<u64, SubscriberHandle>>,
+    /// Last keyframe request made to each producer on behalf of a fast joining consumer
+    keyframe_requests: Mutex<HashMap<ProducerId, Instant>>,
+    /// Consumers of each producer, see `Room::watch_consumer`
+    audience: Mutex<Audience>,
+    /// Current cap on the bitrate each participant may send
+    max_incoming_bitrate: Mutex<Option<u32>>,
+    /// Holds the room to its bitrate budget, if it has one
+    throttle: Mutex<Option<Throttle>>,
+    /// Settings below can be changed while the room is live, the rest are fixed
+    /// at creation
+    producer_limits: Mutex<ProducerLimits>,
+    max_users: Mutex<Option<usize>>,
+    /// User moderating the room whatever its role, see `RoomSettings::owner`
+    owner: Mutex<Option<String>>,
+    /// Users held back until the room is opened, see `RoomSettings::lobby`
+    lobby: Mutex<Lobby>,
+    /// Name of the configured profile the room's media is held to
+    quality_profile: Mutex<Option<String>>,
+    recording_allowed: AtomicBool,
+    feature_flags: Mutex<HashMap<String, bool>>,
+    /// Last user listing computed for RoomInfo and the roster revision it reflects,
+    /// served while the node is under load
+    info_cache: Mutex<Option<(u64, HashMap<String, UserInfo>)>>,
+    roster: Mutex<Roster>,
+    /// Last stats sampled and when
+    stats_cache: Mutex<Option<(Instant, RoomStats)>>,
+    /// NACKs, PLIs and retransmissions over the last minute, see `retransmissions`
+    retransmissions: Mutex<RetransmissionWindow>,
+    /// Replayed to moderators joining late, `None` unless `event_replay` is configured
+    recent_events: Option<Mutex<RecentEvents>>,
+    recording: AsyncMutex<Option<Recording>>,
+    broadcast: AsyncMutex<Option<Broadcast>>,
+    hls: AsyncMutex<Option<RoomHls>>,
+    playback: AsyncMutex<Option<Playback>>,
+    /// Replaced when the threshold or interval changes, `None` once the room is deleted
+    audio_levels: AsyncMutex<Option<AudioLevels>>,
+    persistent: AtomicBool,
+    /// Users whose relayed messages are dropped, kept across leaving and rejoining
+    relay_muted: Mutex<HashSet<String>>,
+    /// Blobs being relayed between connections, see `blob`
+    blobs: Mutex<BlobTransfers>,
+    positions: Mutex<Positions>,
+    /// Producers last announced started or stopped, changes to them are coalesced
+    announcements: Mutex<Announcements>,
+    /// Shared by moderators with everyone in the room, see `RoomMetadata::values`
+    metadata: Mutex<serde_json::Map<String, serde_json::Value>>,
+    /// Banned users by ID along with why, kept whether or not they're in the room
+    bans: Mutex<HashMap<String, Option<String>>>,
+    created_at: DateTime<Utc>,
+    /// Last time a user joined, left, or started or stopped producing
+    last_activity: Mutex<DateTime<Utc>>,
+    /// Closes the room once it stayed empty for the idle timeout
+    idle_timer: Mutex<Option<JoinHandle<()>>>,
+    /// When the room is closed whoever is in it, see `RoomSettings::max_duration_secs`
+    expires_at: Option<Instant>,
+    expiry_timer: Mutex<Option<JoinHandle<()>>>,
 
     users: RwLock<RoomUserMap>,
     pub(super) registrations: RwLock<RoomRegistrationMap>,
 }
 
 impl Room {
-    pub async fn new(id: String /* video_allowed: bool */) -> Result<Arc<Self>, ApiError> {
-        if ROOMS.read().await.contains_key(&id) {
+    pub async fn new(
+        tenant: &str,
+        id: String,
+        settings: RoomSettings,
+    ) -> Result<Arc<Self>, ApiError> {
+        if Room::get(tenant, &id).await.is_some() {
             return Err(ApiError::RoomAlreadyExists(id));
         }
 
-        let worker = get_worker_pool().get_worker();
+        let scoped_id = match tenant {
+            DEFAULT_TENANT => id.clone(),
+            tenant => format!("{}/{}", tenant, id),
+        };
+        // A default tenant's room named like another tenant's would be mistaken for it
+        if split_scoped(&scoped_id) != (tenant, id.as_str()) {
+            return Err(ApiError::InvalidRoomId(id));
+        }
 
-        let mut options = RouterOptions::default();
-        options.media_codecs.push(crate::rtc::create_opus_codec(2));
+        if settings.hls.is_some() && CONFIG.hls.is_none() {
+            return Err(ApiError::HlsUnavailable);
+        }
+
+        let codecs = settings.codecs.as_deref().unwrap_or(&CONFIG.rtc.codecs);
+        validate_codecs(codecs).map_err(|err| ApiError::InvalidCodecs(err.to_string()))?;
+
+        let worker = get_worker_pool().get_worker();
         let router = worker
-            .create_router(options)
+            .create_router(router_options(codecs))
             .await
             .map_err(|_| ApiError::InternalServerError)?;
 
+        let max_incoming_bitrate = settings
+            .max_incoming_bitrate
+            .or(CONFIG.rtc.max_incoming_bitrate);
+        let bitrate_budget = settings
+            .bitrate_budget
+            .or(CONFIG.rtc.room_bitrate_budget)
+            .filter(|budget| *budget > 0);
+        let persistent = settings.persistent;
+        let producer_limits = settings.producer_limits;
+        let max_users = settings.max_users;
+        let owner = settings.owner.clone();
+        let locked = settings.lobby;
+        let quality_profile = settings.quality_profile.clone();
+        let recording_allowed = settings.recording_allowed;
+        let feature_flags = settings.feature_flags.clone();
+        let max_duration = settings.max_duration_secs.map(Duration::from_secs);
         let (sender, _) = broadcast::channel(32);
-        info!("Created new room {}", id);
+        info!("Created new room {}", scoped_id);
         let room = Arc::new(Room {
             id: id.clone(),
+            tenant: tenant.to_string(),
+            scoped_id,
             closed: AtomicBool::new(false),
-            router,
+            bridged: AtomicBool::new(false),
+            transport_pool: Mutex::new(TransportPool::start(&router)),
+            router: Mutex::new(router),
+            worker_id: Mutex::new(worker.id()),
+            migrating: AtomicBool::new(false),
             sender,
+            settings,
+            occupancy: Occupancy::new(tenant),
+            usage: Usage::new(),
+            subscribers: Mutex::new(HashMap::new()),
+            keyframe_requests: Mutex::new(HashMap::new()),
+            audience: Mutex::new(Audience::default()),
+            max_incoming_bitrate: Mutex::new(max_incoming_bitrate),
+            throttle: Mutex::new(bitrate_budget.map(Throttle::new)),
+            producer_limits: Mutex::new(producer_limits),
+            max_users: Mutex::new(max_users),
+            owner: Mutex::new(owner),
+            lobby: Mutex::new(Lobby::new(locked)),
+            quality_profile: Mutex::new(quality_profile),
+            recording_allowed: AtomicBool::new(recording_allowed),
+            feature_flags: Mutex::new(feature_flags),
+            info_cache: Mutex::new(None),
+            roster: Mutex::new(Roster::default()),
+            stats_cache: Mutex::new(None),
+            retransmissions: Mutex::new(RetransmissionWindow::default()),
+            recent_events: CONFIG
+                .event_replay
+                .as_ref()
+                .map(|config| Mutex::new(RecentEvents::new(config))),
+            recording: AsyncMutex::new(None),
+            broadcast: AsyncMutex::new(None),
+            hls: AsyncMutex::new(None),
+            playback: AsyncMutex::new(None),
+            audio_levels: AsyncMutex::new(None),
+            persistent: AtomicBool::new(persistent),
+            relay_muted: Mutex::new(HashSet::new()),
+            blobs: Mutex::new(BlobTransfers::default()),
+            positions: Mutex::new(Positions::default()),
+            announcements: Mutex::new(Announcements::default()),
+            metadata: Mutex::new(serde_json::Map::new()),
+            bans: Mutex::new(HashMap::new()),
+            created_at: Utc::now(),
+            last_activity: Mutex::new(Utc::now()),
+            idle_timer: Mutex::new(None),
+            expires_at: max_duration.map(|duration| Instant::now() + duration),
+            expiry_timer: Mutex::new(None),
 
             users: RwLock::new(HashMap::new()),
             registrations: RwLock::new(HashMap::new()),
         });
 
-        ROOMS.write().await.insert(id, room.clone());
+        let key = (room.tenant.clone(), id);
+        ROOMS.write().await.insert(key, room.clone());
+        if let Some(store) = get_store() {
+            store.save_room(&room.scoped_id, &room.settings);
+        }
+        room.notify(LifecycleEvent::RoomCreated);
+        // A room nobody joins is as idle as one everybody left
+        room.schedule_idle_close();
+        room.schedule_expiry();
+
+        let threshold_db = room
+            .settings
+            .audio_level_threshold_db
+            .unwrap_or(CONFIG.audio_levels.threshold_db);
+        let interval_ms = room
+            .settings
+            .audio_level_interval_ms
+            .unwrap_or(CONFIG.audio_levels.interval_ms);
+        if room
+            .observe_audio_levels(threshold_db, interval_ms)
+            .await
+            .is_err()
+        {
+            room.delete().await;
+            return Err(ApiError::InternalServerError);
+        }
 
+        if let Some(hls) = room.settings.hls.clone() {
+            if let Err(err) = room.set_hls(Some(hls)).await {
+                room.delete().await;
+                return Err(err.into());
+            }
+        }
+
+        if bitrate_budget.is_some() {
+            tokio::spawn(watch_bitrate(Arc::downgrade(&room)));
+        }
+        if let Some(interval) = room.settings.retransmissions.sample_interval() {
+            tokio::spawn(watch_retransmissions(Arc::downgrade(&room), interval));
+        }
         Ok(room)
     }
 
-    pub async fn get(id: &str) -> Option<Arc<Self>> {
-        ROOMS.read().await.get(id).map(|arc| arc.clone())
+    /// The tenant's room, rooms of other tenants aren't found whatever their ID
+    pub async fn get(tenant: &str, id: &str) -> Option<Arc<Self>> {
+        let key = (tenant.to_string(), id.to_string());
+        ROOMS.read().await.get(&key).cloned()
+    }
+
+    /// Room known by a scoped ID across instances and in the store
+    pub async fn get_scoped(scoped_id: &str) -> Option<Arc<Self>> {
+        let (tenant, id) = split_scoped(scoped_id);
+        Room::get(tenant, id).await
     }
 
     pub fn id(&self) -> &str {
         &self.id
     }
 
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// ID that tells the room apart from those of other tenants, the plain ID for the
+    /// default tenant's rooms and `{tenant}/{id}` for the others. Rooms are known by it in
+    /// Redis, the store and join tokens
+    pub fn scoped_id(&self) -> &str {
+        &self.scoped_id
+    }
+
     pub async fn delete(&self) {
         let result =
             self.closed
                 .compare_exchange(false, true, Ordering::Release, Ordering::Relaxed);
 
         if result.is_ok() {
-            info!("Deleting room {}", self.id);
-            ROOMS.write().await.remove(&self.id);
-            self.send_event(RoomEvent::RoomDelete);
+            info!("Deleting room {}", self.scoped_id);
+            self.cancel_idle_close();
+            if let Some(timer) = self.expiry_timer.lock().take() {
+                timer.abort();
+            }
+            self.transport_pool.lock().take();
+            let key = (self.tenant.clone(), self.id.clone());
+            ROOMS.write().await.remove(&key);
+            if let Some(redis) = get_redis() {
+                redis.delete_room(&self.scoped_id);
+            }
+            if let Some(store) = get_store() {
+                store.delete_room(&self.scoped_id);
+            }
+
+            // Files are complete by the time the summary says a recording is available
+            if let Some(recording) = self.recording.lock().await.take() {
+                recording.stop().await;
+            }
+
+            if let Some(broadcast) = self.broadcast.lock().await.take() {
+                broadcast.stop().await;
+            }
+
+            if let Some(hls) = self.hls.lock().await.take() {
+                hls.watcher.abort();
+                hls.packager.stop().await;
+            }
+
+            if let Some(playback) = self.playback.lock().await.take() {
+                playback.stop().await;
+            }
+
+            self.audio_levels.lock().await.take();
+
+            // Ahead of the room's own summary, queued before it's broadcast
+            self.end_call().await;
+            let summary = self.usage.summary();
+            self.send_event(RoomEvent::RoomDelete(summary.clone()));
+
+            // Delivered ahead of queued broadcast events, each connection sends
+            // the summary and then closes. Waits for room on full control channels
+            // rather than dropping the summary, without holding the lock
+            let controls: Vec<_> = self
+                .subscribers
+                .lock()
+                .values()
+                .map(|handle| handle.control.clone())
+                .collect();
+            for control in controls {
+                let event = RoomEvent::RoomDelete(summary.clone());
+                control.send(SubscriberSignal::Event(event)).await.ok();
+            }
+
+            // Users hold the room, so it is only dropped once they are all gone
+            let users: Vec<Arc<RwLock<User>>> = self
+                .users
+                .write()
+                .await
+                .drain()
+                .map(|(_, user)| user)
+                .collect();
+            self.registrations.write().await.clear();
+            self.occupancy.clear();
+            drop(users);
+        }
+    }
+
+    /// Move the room onto a new router on the worker that replaced the one it was on,
+    /// which died. Its media died along with the worker: the producers are closed as
+    /// such, recording, broadcasting and playback stop, and the connections are told to
+    /// initialize their transports again. Users stay in the room. A room that can't be
+    /// moved is deleted rather than left without media
+    pub async fn restart_media(self: &Arc<Self>) {
+        if self.closed() {
+            return;
+        }
+
+        let worker = get_worker_pool().get_worker();
+        let codecs = self
+            .settings
+            .codecs
+            .as_deref()
+            .unwrap_or(&CONFIG.rtc.codecs);
+        let router = match worker.create_router(router_options(codecs)).await {
+            Ok(router) => router,
+            Err(err) => {
+                error!("Failed to move room {} to a new worker: {}", self.id, err);
+                self.delete().await;
+                return;
+            }
+        };
+        info!("Moving room {} to worker {}", self.id, worker.id());
+        *self.transport_pool.lock() = TransportPool::start(&router);
+        *self.router.lock() = router;
+        *self.worker_id.lock() = worker.id();
+        self.keyframe_requests.lock().clear();
+        self.stats_cache.lock().take();
+
+        // Their producers are gone, they're started again once there are new ones
+        if let Some(recording) = self.recording.lock().await.take() {
+            recording.stop().await;
+            self.send_event(RoomEvent::RecordingStateChanged(false));
+        }
+        if let Some(broadcast) = self.broadcast.lock().await.take() {
+            broadcast.stop().await;
+        }
+        if let Some(playback) = self.playback.lock().await.take() {
+            playback.stop().await;
+        }
+        let hls = self.hls.lock().await.take().map(|hls| {
+            hls.watcher.abort();
+            (hls.packager, hls.video_user)
+        });
+        if let Some((packager, video_user)) = hls {
+            packager.stop().await;
+            let settings = HlsSettings { video_user };
+            if let Err(err) = self.set_hls(Some(settings)).await {
+                warn!("Failed to package room {} as HLS again: {}", self.id, err);
+            }
         }
+        let (threshold_db, interval_ms) = self.audio_level_options().await;
+        self.audio_levels.lock().await.take();
+        if self
+            .observe_audio_levels(threshold_db, interval_ms)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Failed to observe the audio levels of room {} again",
+                self.id
+            );
+        }
+
+        for user in self.users.read().await.values() {
+            user.write()
+                .await
+                .close_producers(&ProduceType::ALL, MediaClosedReason::MediaRestarted);
+        }
+        self.restart_transports().await;
+    }
+
+    /// Tell every connection to initialize its transports again on the room's current
+    /// router, returning how many were told
+    async fn restart_transports(&self) -> usize {
+        // Waits for room on full control channels, the connections can't do without it
+        let controls: Vec<_> = self
+            .subscribers
+            .lock()
+            .values()
+            .map(|handle| handle.control.clone())
+            .collect();
+        let count = controls.len();
+        for control in controls {
+            control.send(SubscriberSignal::MediaRestart).await.ok();
+        }
+        count
     }
 
     pub fn closed(&self) -> bool {
@@ -99,23 +654,1260 @@ impl Room {
     }
 
     pub fn send_event(&self, event: RoomEvent) {
-        self.sender.send(event).ok();
+        // Leaves are recorded as users are removed, hidden ones included
+        if let RoomEvent::UserJoined(..)
+        | RoomEvent::UserStartProduce(..)
+        | RoomEvent::UserStopProduce(..)
+        | RoomEvent::UserProducerReplaced(..) = event
+        {
+            self.touch();
+        }
+
+        match &event {
+            RoomEvent::UserJoined(id, _)
+            | RoomEvent::UserLeft(id, _)
+            | RoomEvent::UserStartProduce(id, ..)
+            | RoomEvent::UserStopProduce(id, _)
+            | RoomEvent::UserProducerPauseChanged(id, ..)
+            | RoomEvent::UserInfoUpdated(id)
+            | RoomEvent::UserRoleChanged(id, _)
+            | RoomEvent::UserConnectionStateChanged(id, _) => self.roster.lock().touch(id),
+            RoomEvent::Positions(positions) => {
+                let mut roster = self.roster.lock();
+                for position in positions {
+                    roster.touch(&position.user_id);
+                }
+            }
+            _ => {}
+        }
+        self.record_recent(&event);
+
+        if let Some(redis) = get_redis() {
+            redis.publish(&self.scoped_id, &event);
+        }
+
+        // Relayed messages are chatter between clients, not something to integrate with,
+        // and speakers, positions and audiences change far too often to post each change
+        let chatter = matches!(
+            event,
+            RoomEvent::Relay(..)
+                | RoomEvent::ActiveSpeakers(_)
+                | RoomEvent::Positions(_)
+                | RoomEvent::ProducerAudience(..)
+        );
+        if let (Some(webhook), false) = (get_webhook(), chatter) {
+            webhook.publish(&self.id, &event);
+        }
+
+        self.sender.send(event.into()).ok();
     }
 
-    pub fn subscribe(&self) -> Option<Receiver<RoomEvent>> {
-        match self.closed() {
-            false => Some(self.sender.subscribe()),
-            true => None,
+    fn record_recent(&self, event: &RoomEvent) {
+        if let Some(recent_events) = &self.recent_events {
+            recent_events.lock().record(event, Utc::now());
+        }
+    }
+
+    /// Membership and moderation events of the last `since_secs` with when they happened,
+    /// oldest first. `None` if rooms don't keep them, see `EventReplayConfig`
+    pub fn recent_events(&self, since_secs: u64) -> Option<Vec<(DateTime<Utc>, RoomEvent)>> {
+        let recent_events = self.recent_events.as_ref()?;
+        Some(recent_events.lock().since(since_secs, Utc::now()))
+    }
+
+    /// Tell the webhook about a change in the room's life, which participants aren't sent
+    /// Summarize the call going on in the room, if any, for the integrations and the
+    /// connections still there such as hidden users'
+    pub(super) async fn end_call(&self) {
+        let summary = match self.usage.end_call() {
+            Some(summary) => summary,
+            None => return,
+        };
+        self.notify(LifecycleEvent::CallEnded(summary.clone()));
+
+        let controls: Vec<_> = self
+            .subscribers
+            .lock()
+            .values()
+            .map(|handle| handle.control.clone())
+            .collect();
+        for control in controls {
+            let event = RoomEvent::CallSummary(summary.clone());
+            control.send(SubscriberSignal::Event(event)).await.ok();
         }
     }
 
-    pub fn router(&self) -> Option<&Router> {
+    pub(in crate::state) fn notify(&self, event: LifecycleEvent) {
+        if let Some(webhook) = get_webhook() {
+            webhook.publish_lifecycle(&self.id, &event);
+        }
+    }
+
+    /// Register a connection as a receiver of this room's events
+    pub fn subscribe(
+        self: &Arc<Self>,
+        connection_id: u64,
+        user_id: &str,
+        options: SubscriberOptions,
+    ) -> Option<RoomSubscriber> {
+        if self.closed() {
+            return None;
+        }
+
+        if let Some(redis) = get_redis() {
+            if !self.bridged.swap(true, Ordering::AcqRel) {
+                redis.bridge(self.scoped_id.clone(), self.sender.clone());
+            }
+        }
+
+        let info = SubscriberInfo {
+            connection_id,
+            user_id: user_id.to_string(),
+            options,
+        };
+
+        let (control, control_receiver) = mpsc::channel(8);
+        self.subscribers.lock().insert(
+            connection_id,
+            SubscriberHandle {
+                info: info.clone(),
+                control,
+            },
+        );
+
+        Some(RoomSubscriber::new(
+            self.clone(),
+            info,
+            self.sender.subscribe(),
+            control_receiver,
+        ))
+    }
+
+    fn unsubscribe(&self, connection_id: u64) {
+        self.subscribers.lock().remove(&connection_id);
+        self.abort_blobs(connection_id);
+    }
+
+    pub fn subscribers(&self) -> Vec<SubscriberInfo> {
+        self.subscribers
+            .lock()
+            .values()
+            .map(|handle| handle.info.clone())
+            .collect()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().len()
+    }
+
+    /// Queue a signal for a single subscriber, returning whether it was delivered
+    pub fn signal_subscriber(&self, connection_id: u64, signal: SubscriberSignal) -> bool {
+        match self.subscribers.lock().get(&connection_id) {
+            Some(handle) => handle.control.try_send(signal).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Deliver an event to the connections of the room's moderators only, rather than
+    /// broadcasting it. Dropped for connections whose control channel is full
+    pub async fn signal_moderators(&self, event: RoomEvent) {
+        self.record_recent(&event);
+        let subscribers: Vec<_> = self
+            .subscribers
+            .lock()
+            .values()
+            .map(|handle| (handle.info.user_id.clone(), handle.control.clone()))
+            .collect();
+        let users = self.users.read().await;
+        for (user_id, control) in subscribers {
+            let moderator = match users.get(&user_id) {
+                Some(user) => user.read().await.has_permission(Permission::Moderator),
+                None => false,
+            };
+            if moderator {
+                control
+                    .try_send(SubscriberSignal::Event(event.clone()))
+                    .ok();
+            }
+        }
+    }
+
+    /// Stats of a connection's transports, producers and consumers, gathered by the
+    /// connection. `None` if it has no transports or didn't answer in time
+    pub async fn connection_stats(&self, connection_id: u64) -> Option<ConnectionStats> {
+        let (sender, receiver) = oneshot::channel();
+        if !self.signal_subscriber(connection_id, SubscriberSignal::Stats(sender)) {
+            return None;
+        }
+        tokio::time::timeout(CONNECTION_STATS_TIMEOUT, receiver)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    pub fn router(&self) -> Option<Router> {
         match self.closed() {
-            false => Some(&self.router),
+            false => Some(self.router.lock().clone()),
             true => None,
         }
     }
 
+    /// Worker the room's router is on
+    pub fn worker_id(&self) -> WorkerId {
+        *self.worker_id.lock()
+    }
+
+    /// Producer of any user connected to this node, by its ID
+    pub async fn producer(&self, id: &str) -> Option<Producer> {
+        let users = self.users();
+        let guard = users.guard().await;
+        for user in guard.iter() {
+            let user = user.read().await;
+            let producer = ProduceType::ALL
+                .iter()
+                .filter_map(|produce_type| user.get_producer(*produce_type))
+                .find(|producer| producer.id().to_string() == id);
+            if let Some(producer) = producer {
+                return Some(producer.clone());
+            }
+        }
+        None
+    }
+
+    /// Settings the room was created with. Those that can change are read through
+    /// their own accessors, or all at once from `metadata`
+    pub fn settings(&self) -> &RoomSettings {
+        &self.settings
+    }
+
+    pub fn occupancy(&self) -> &Occupancy {
+        &self.occupancy
+    }
+
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Whether a keyframe may be requested from a producer, limiting requests to
+    /// one per `interval` so a burst of joining consumers doesn't flood the sender
+    pub fn allow_key_frame_request(&self, producer_id: ProducerId, interval: Duration) -> bool {
+        let mut requests = self.keyframe_requests.lock();
+        let now = Instant::now();
+        requests.retain(|_, requested_at| now.duration_since(*requested_at) < interval);
+        if requests.contains_key(&producer_id) {
+            return false;
+        }
+
+        requests.insert(producer_id, now);
+        true
+    }
+
+    /// Consumers of the producer across the room
+    pub fn audience(&self, producer_id: ProducerId) -> usize {
+        self.audience.lock().count(producer_id)
+    }
+
+    /// Count a consumer of the user's producer of the type until it closes, however it
+    /// comes to, telling the user as the count moves
+    pub fn watch_consumer(
+        self: &Arc<Self>,
+        consumer: &Consumer,
+        user_id: &str,
+        produce_type: ProduceType,
+    ) {
+        let producer_id = consumer.producer_id();
+        let delta = CONFIG.signaling.audience_delta;
+        let change = self
+            .audience
+            .lock()
+            .add(producer_id, user_id, produce_type, delta);
+        if let Some(change) = change {
+            self.announce_audience(change);
+        }
+
+        let room = Arc::downgrade(self);
+        consumer
+            .on_close(move || {
+                if let Some(room) = room.upgrade() {
+                    let change = room.audience.lock().remove(producer_id, delta);
+                    if let Some(change) = change {
+                        room.announce_audience(change);
+                    }
+                }
+            })
+            .detach();
+    }
+
+    fn announce_audience(&self, change: AudienceChange) {
+        let event = RoomEvent::ProducerAudience(change.user_id, change.produce_type, change.count);
+        self.send_event(event);
+    }
+
+    pub fn max_incoming_bitrate(&self) -> Option<u32> {
+        *self.max_incoming_bitrate.lock()
+    }
+
+    pub fn producer_limits(&self) -> ProducerLimits {
+        *self.producer_limits.lock()
+    }
+
+    pub fn max_users(&self) -> Option<usize> {
+        *self.max_users.lock()
+    }
+
+    pub fn owner(&self) -> Option<String> {
+        self.owner.lock().clone()
+    }
+
+    pub fn is_owner(&self, user_id: &str) -> bool {
+        self.owner.lock().as_deref() == Some(user_id)
+    }
+
+    /// Hand the room to another user, or leave it without an owner, announcing the change
+    pub fn set_owner(&self, owner: Option<String>) {
+        let mut current = self.owner.lock();
+        if *current == owner {
+            return;
+        }
+        *current = owner.clone();
+        drop(current);
+        info!("Room {} is now owned by {:?}", self.scoped_id, owner);
+        self.send_event(RoomEvent::OwnerChanged(owner));
+    }
+
+    /// Apply the room's `OwnerLeavePolicy` once its owner left
+    pub(super) async fn owner_left(self: &Arc<Self>) {
+        if self.closed() {
+            return;
+        }
+        match self.settings.owner_leave_policy {
+            OwnerLeavePolicy::None => {}
+            OwnerLeavePolicy::CloseOnOwnerLeave => {
+                info!("Owner left room {}, closing it", self.scoped_id);
+                // The owner's session may still be winding down, it isn't waited for
+                let room = self.clone();
+                tokio::spawn(async move { room.delete().await });
+            }
+            OwnerLeavePolicy::Transfer => {
+                let mut heir = None;
+                for (id, user) in self.users.read().await.iter() {
+                    let user = user.read().await;
+                    if !user.registered() || user.hidden() {
+                        continue;
+                    }
+                    let present = user.connected_for().unwrap_or_default();
+                    if heir
+                        .as_ref()
+                        .map_or(true, |(_, longest)| present > *longest)
+                    {
+                        heir = Some((id.clone(), present));
+                    }
+                }
+                self.set_owner(heir.map(|(id, _)| id));
+            }
+        }
+    }
+
+    /// Whether users joining wait in the lobby, until the room is opened
+    pub fn locked(&self) -> bool {
+        self.lobby.lock().locked()
+    }
+
+    /// Users waiting in the lobby, in the order they arrived
+    pub fn waiting(&self) -> Vec<String> {
+        self.lobby.lock().waiting().to_vec()
+    }
+
+    pub fn in_lobby(&self, user_id: &str) -> bool {
+        self.lobby.lock().is_waiting(user_id)
+    }
+
+    /// Hold a user joining the locked room in the lobby and tell the moderators, `false`
+    /// if it may come in
+    pub fn enter_lobby(&self, user_id: &str) -> bool {
+        let waiting = self.lobby.lock().enter(user_id);
+        if waiting {
+            self.send_event(RoomEvent::UserWaiting(user_id.to_string()));
+        }
+        waiting
+    }
+
+    /// Let a user waiting in the lobby in, `false` if it wasn't waiting
+    pub fn admit(&self, user_id: &str) -> bool {
+        let admitted = self.lobby.lock().admit(user_id);
+        if admitted {
+            self.send_event(RoomEvent::UserAdmitted(user_id.to_string()));
+        }
+        admitted
+    }
+
+    /// Unlock the room, letting everyone waiting in the lobby in. `false` if it wasn't
+    /// locked
+    pub fn open(&self) -> bool {
+        let opened = self.lobby.lock().open();
+        if opened {
+            info!("Opened room {}", self.scoped_id);
+            self.send_event(RoomEvent::RoomOpened);
+        }
+        opened
+    }
+
+    pub(super) fn leave_lobby(&self, user_id: &str) {
+        self.lobby.lock().leave(user_id);
+    }
+
+    pub fn recording_allowed(&self) -> bool {
+        self.recording_allowed.load(Ordering::Relaxed)
+    }
+
+    pub fn relay_muted(&self, user_id: &str) -> bool {
+        self.relay_muted.lock().contains(user_id)
+    }
+
+    /// Store where a user is, relayed with everyone else's position on the next tick
+    pub fn update_position(self: &Arc<Self>, user_id: &str, position: Position) {
+        let mut positions = self.positions.lock();
+        positions.update(user_id, position, Instant::now());
+        if !std::mem::replace(&mut positions.ticking, true) {
+            tokio::spawn(relay_positions(Arc::downgrade(self)));
+        }
+    }
+
+    /// Position of a user that updated it lately
+    pub fn position(&self, user_id: &str) -> Option<Position> {
+        self.positions.lock().get(user_id, Instant::now())
+    }
+
+    pub(super) fn remove_position(&self, user_id: &str) {
+        self.positions.lock().remove(user_id);
+    }
+
+    /// Announce a user's producer of the type started or stopped, `producer` being the
+    /// one it has now. Changes following it too closely are announced together later,
+    /// as the state the producer is in by then
+    pub fn announce_producer(
+        self: &Arc<Self>,
+        user_id: &str,
+        produce_type: ProduceType,
+        producer: Option<&Producer>,
+    ) {
+        let change = self.announcements.lock().change(
+            user_id,
+            produce_type,
+            producer.map(Producer::id),
+            Instant::now(),
+        );
+        match change {
+            Announce::Now => self.send_event(match producer {
+                Some(producer) => RoomEvent::UserStartProduce(
+                    user_id.to_string(),
+                    produce_type,
+                    producer.paused(),
+                ),
+                None => RoomEvent::UserStopProduce(user_id.to_string(), produce_type),
+            }),
+            Announce::After(delay) => {
+                let room = Arc::downgrade(self);
+                let user_id = user_id.to_string();
+                tokio::spawn(settle_producer(room, user_id, produce_type, delay));
+            }
+            Announce::Held => (),
+        }
+    }
+
+    pub(super) fn remove_announcements(&self, user_id: &str) {
+        self.announcements.lock().remove(user_id);
+    }
+
+    /// Drop, or stop dropping, the relayed messages of a user who abused them
+    pub fn set_relay_muted(&self, user_id: &str, muted: bool) {
+        let mut relay_muted = self.relay_muted.lock();
+        match muted {
+            true => relay_muted.insert(user_id.to_string()),
+            false => relay_muted.remove(user_id),
+        };
+    }
+
+    /// Set a key of the metadata everyone in the room sees, refused if it would outgrow
+    /// the limits of `metadata::set_value`
+    pub fn set_metadata(&self, key: String, value: serde_json::Value) -> Result<(), MetadataError> {
+        metadata::set_value(&mut self.metadata.lock(), key.clone(), value.clone())?;
+        self.send_event(RoomEvent::RoomMetadataChanged(key, Some(value)));
+        Ok(())
+    }
+
+    /// Delete a key of the room's metadata, `false` if it wasn't set
+    pub fn delete_metadata(&self, key: &str) -> bool {
+        let deleted = self.metadata.lock().remove(key).is_some();
+        if deleted {
+            self.send_event(RoomEvent::RoomMetadataChanged(key.to_string(), None));
+        }
+        deleted
+    }
+
+    pub fn banned(&self, user_id: &str) -> bool {
+        self.bans.lock().contains_key(user_id)
+    }
+
+    /// Refuse the user whenever it authenticates, whatever token it comes with. Users
+    /// already in the room are left to the caller to kick
+    pub fn ban(&self, user_id: &str, reason: Option<String>) {
+        self.bans.lock().insert(user_id.to_string(), reason);
+        self.usage.record_ban();
+    }
+
+    /// Lift a ban, returning whether the user was banned
+    pub fn unban(&self, user_id: &str) -> bool {
+        self.bans.lock().remove(user_id).is_some()
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans
+            .lock()
+            .iter()
+            .map(|(user_id, reason)| Ban {
+                user_id: user_id.clone(),
+                reason: reason.clone(),
+            })
+            .collect()
+    }
+
+    /// Change the cap on the bitrate each participant may send, 0 removes it.
+    /// Applied to the transports of every connected subscriber
+    pub fn set_max_incoming_bitrate(&self, bitrate: u32) {
+        *self.max_incoming_bitrate.lock() = Some(bitrate).filter(|bitrate| *bitrate > 0);
+        self.signal_incoming_bitrate();
+    }
+
+    /// Cap on the bitrate each participant may send now, the room's own or its share of
+    /// the budget while the room is throttled all the way, whichever is lower
+    pub fn incoming_bitrate_cap(&self) -> Option<u32> {
+        let connections = self.subscribers.lock().len();
+        let throttled = self
+            .throttle
+            .lock()
+            .as_ref()
+            .and_then(|throttle| throttle.max_incoming_bitrate(connections));
+        [self.max_incoming_bitrate(), throttled]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    fn signal_incoming_bitrate(&self) {
+        let bitrate = self.incoming_bitrate_cap().unwrap_or(0);
+        for handle in self.subscribers.lock().values() {
+            let signal = SubscriberSignal::MaxIncomingBitrate(bitrate);
+            handle.control.try_send(signal).ok();
+        }
+    }
+
+    /// Budget the room is held to and how far it's throttled, if it has one
+    pub fn throttle_state(&self) -> Option<ThrottleState> {
+        self.throttle.lock().as_ref().map(Throttle::state)
+    }
+
+    /// Spatial layer video consumers are held to while the room is throttled
+    pub fn throttled_layer(&self) -> Option<u8> {
+        let level = self.throttle_state()?.level;
+        Throttle::max_spatial_layer(level)
+    }
+
+    /// Tell every connection the room was throttled to `level`, they cap their
+    /// consumers' layers as they hear of it
+    fn throttled(&self, level: u8) {
+        info!("Throttled room {} to level {}", self.id, level);
+        self.signal_incoming_bitrate();
+        self.send_event(RoomEvent::RoomThrottled(level));
+    }
+
+    /// Apply the settings present in an update and tell every participant the
+    /// settings now in effect
+    pub async fn update_settings(
+        self: &Arc<Self>,
+        update: RoomSettingsUpdate,
+    ) -> Result<(), HlsError> {
+        if let Some(bitrate) = update.max_incoming_bitrate {
+            self.set_max_incoming_bitrate(bitrate);
+        }
+
+        if let Some(hls) = update.hls {
+            self.set_hls(hls).await?;
+        }
+
+        if let Some(persistent) = update.persistent {
+            self.set_persistent(persistent).await;
+        }
+
+        // Users already in the room stay, the cap is checked as users are created
+        if let Some(max_users) = update.max_users {
+            *self.max_users.lock() = max_users;
+        }
+
+        // Connections cap their consumers' layers as they're told the new settings
+        if let Some(quality_profile) = update.quality_profile {
+            *self.quality_profile.lock() = quality_profile;
+        }
+
+        if let Some(video_allowed) = update.video_allowed {
+            self.set_video_allowed(video_allowed).await;
+        }
+
+        if let Some(recording_allowed) = update.recording_allowed {
+            self.recording_allowed
+                .store(recording_allowed, Ordering::Relaxed);
+            if !recording_allowed {
+                self.stop_recording().await.ok();
+            }
+        }
+
+        if update.audio_level_threshold_db.is_some() || update.audio_level_interval_ms.is_some() {
+            let current = self
+                .audio_levels
+                .lock()
+                .await
+                .as_ref()
+                .map(|audio_levels| (audio_levels.threshold_db(), audio_levels.interval_ms()));
+            if let Some((threshold_db, interval_ms)) = current {
+                let threshold_db = update.audio_level_threshold_db.unwrap_or(threshold_db);
+                let interval_ms = update.audio_level_interval_ms.unwrap_or(interval_ms);
+                if self
+                    .observe_audio_levels(threshold_db, interval_ms)
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        "Failed to replace the audio level observer of room {}",
+                        self.id
+                    );
+                }
+            }
+        }
+
+        if let Some(feature_flags) = update.feature_flags {
+            *self.feature_flags.lock() = feature_flags;
+        }
+
+        let settings = self.metadata().await.settings;
+        if let Some(store) = get_store() {
+            store.save_room(&self.scoped_id, &settings);
+        }
+        self.send_event(RoomEvent::RoomSettingsChanged(settings));
+        Ok(())
+    }
+
+    /// Allow camera video or disallow it, closing the camera producers already open
+    async fn set_video_allowed(&self, video_allowed: bool) {
+        self.producer_limits.lock().video = usize::from(video_allowed);
+        if video_allowed {
+            return;
+        }
+
+        for user in self.users.read().await.values() {
+            user.write()
+                .await
+                .close_producers(&[ProduceType::Video], MediaClosedReason::NotAllowed);
+        }
+    }
+
+    /// Flags clients turn the room's features on or off with
+    pub fn feature_flags(&self) -> HashMap<String, bool> {
+        self.feature_flags.lock().clone()
+    }
+
+    /// Ceilings of the room's quality profile, if it has one
+    pub fn quality_profile(&self) -> Option<&'static QualityProfile> {
+        let name = self.quality_profile.lock();
+        CONFIG.rooms.profiles.get(name.as_deref()?)
+    }
+
+    pub fn persistent(&self) -> bool {
+        self.persistent.load(Ordering::Relaxed)
+    }
+
+    /// Opt the room out of being closed while empty, or back in
+    pub async fn set_persistent(self: &Arc<Self>, persistent: bool) {
+        self.persistent.store(persistent, Ordering::Relaxed);
+        if persistent {
+            self.cancel_idle_close();
+        } else if self.users.read().await.is_empty() {
+            self.schedule_idle_close();
+        }
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        *self.last_activity.lock()
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock() = Utc::now();
+    }
+
+    /// Lifecycle timestamps along with the settings currently in effect
+    pub async fn metadata(&self) -> RoomMetadata {
+        let mut settings = self.settings.clone();
+        settings.max_incoming_bitrate = self.max_incoming_bitrate();
+        settings.producer_limits = self.producer_limits();
+        settings.max_users = self.max_users();
+        settings.owner = self.owner();
+        settings.recording_allowed = self.recording_allowed();
+        settings.persistent = self.persistent();
+        settings.quality_profile = self.quality_profile.lock().clone();
+        settings.feature_flags = self.feature_flags();
+        settings.hls = self.hls.lock().await.as_ref().map(|hls| HlsSettings {
+            video_user: hls.video_user.clone(),
+        });
+        if let Some(audio_levels) = self.audio_levels.lock().await.as_ref() {
+            settings.audio_level_threshold_db = Some(audio_levels.threshold_db());
+            settings.audio_level_interval_ms = Some(audio_levels.interval_ms());
+        }
+
+        RoomMetadata {
+            created_at: self.created_at,
+            last_activity: self.last_activity(),
+            owner: self.owner(),
+            settings,
+            quality: self.quality_profile().cloned(),
+            values: self.metadata.lock().clone(),
+        }
+    }
+
+    /// Counts of the room's users and the bitrates of their transports, sampled from
+    /// the worker at most once per `STATS_TTL`
+    pub async fn stats(&self) -> RoomStats {
+        if let Some((sampled, stats)) = &*self.stats_cache.lock() {
+            if sampled.elapsed() < STATS_TTL {
+                return RoomStats {
+                    throttle: self.throttle_state(),
+                    ..stats.clone()
+                };
+            }
+        }
+
+        let mut users = HashMap::new();
+        let mut media = Vec::new();
+        let mut retransmissions = self.retransmissions.lock().users();
+        for (id, user) in self.users.read().await.iter() {
+            let user = user.read().await;
+            let consumers = user.media().map_or(0, |media| media.consumers());
+            let producers = user.producer_count();
+            users.insert(
+                id.clone(),
+                UserStats {
+                    producers,
+                    consumers,
+                    retransmissions: retransmissions.remove(id).unwrap_or_default(),
+                },
+            );
+            media.extend(user.media().cloned());
+        }
+
+        // Sampled once the users are released, each transport is a request to the worker
+        let mut bitrates = Bitrates::default();
+        for media in media {
+            match media.bitrates().await {
+                Ok(sampled) => bitrates += sampled,
+                Err(()) => warn!("Failed to sample transport stats in room {}", self.id),
+            }
+        }
+
+        let stats = RoomStats {
+            worker_id: self.worker_id(),
+            user_count: users.len(),
+            users,
+            bitrates,
+            retransmissions: self.retransmissions.lock().room(),
+            throttle: self.throttle_state(),
+        };
+        *self.stats_cache.lock() = Some((Instant::now(), stats.clone()));
+        stats
+    }
+
+    /// Record a user being added, which keeps the room open
+    pub(super) fn user_joined(&self) {
+        self.touch();
+        self.cancel_idle_close();
+    }
+
+    /// Record a user being removed, starting the idle timeout once nobody is left
+    pub(super) fn user_left(self: &Arc<Self>, empty: bool) {
+        self.touch();
+        if empty {
+            self.schedule_idle_close();
+        }
+    }
+
+    fn schedule_idle_close(self: &Arc<Self>) {
+        if let Some(timeout) = CONFIG.rooms.idle_timeout() {
+            self.schedule_idle_close_after(timeout);
+        }
+    }
+
+    /// Close the room if it's still empty once `timeout` elapsed, unless it's persistent
+    fn schedule_idle_close_after(self: &Arc<Self>, timeout: Duration) {
+        if self.persistent() || self.closed() {
+            return;
+        }
+
+        let room = Arc::downgrade(self);
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let room = match room.upgrade() {
+                Some(room) => room,
+                None => return,
+            };
+
+            if room.persistent() || !room.users.read().await.is_empty() {
+                return;
+            }
+
+            // Deleting cancels the timer, which mustn't abort this task midway
+            room.idle_timer.lock().take();
+            info!(
+                "Closing room {}, empty for {} seconds",
+                room.id,
+                (Utc::now() - room.last_activity()).num_seconds()
+            );
+            room.delete().await;
+        });
+
+        if let Some(previous) = self.idle_timer.lock().replace(timer) {
+            previous.abort();
+        }
+    }
+
+    /// When the room is closed whoever is in it, if it was created with a maximum duration
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    /// Delete the room once it reached its maximum duration, its users are sent
+    /// `RoomDelete` like when it's deleted over the API
+    fn schedule_expiry(self: &Arc<Self>) {
+        let expires_at = match self.expires_at {
+            Some(expires_at) => expires_at,
+            None => return,
+        };
+
+        let room = Arc::downgrade(self);
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep_until(expires_at.into()).await;
+            let room = match room.upgrade() {
+                Some(room) => room,
+                None => return,
+            };
+
+            // Deleting cancels the timer, which mustn't abort this task midway
+            room.expiry_timer.lock().take();
+            info!("Closing room {}, it reached its maximum duration", room.id);
+            room.delete().await;
+        });
+        *self.expiry_timer.lock() = Some(timer);
+    }
+
+    fn cancel_idle_close(&self) {
+        if let Some(timer) = self.idle_timer.lock().take() {
+            timer.abort();
+        }
+    }
+
+    /// Directory holding the HLS playlist, if the room is packaged
+    pub async fn hls_directory(&self) -> Option<PathBuf> {
+        let hls = self.hls.lock().await;
+        hls.as_ref()
+            .map(|hls| hls.packager.directory().to_path_buf())
+    }
+
+    /// Start, change or stop HLS packaging
+    pub async fn set_hls(self: &Arc<Self>, settings: Option<HlsSettings>) -> Result<(), HlsError> {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => {
+                if let Some(hls) = self.hls.lock().await.take() {
+                    hls.watcher.abort();
+                    hls.packager.stop().await;
+                }
+
+                return Ok(());
+            }
+        };
+
+        let config = CONFIG.hls.as_ref().ok_or(HlsError::Unavailable)?;
+        let mut hls = self.hls.lock().await;
+        match hls.as_mut() {
+            Some(hls) => hls.video_user = settings.video_user,
+            None => {
+                let packager =
+                    HlsPackager::create(self.router.lock().clone(), &self.scoped_id, config)
+                        .await?;
+                let watcher =
+                    tokio::spawn(watch_hls(Arc::downgrade(self), self.sender.subscribe()));
+                *hls = Some(RoomHls {
+                    packager,
+                    video_user: settings.video_user,
+                    watcher,
+                });
+            }
+        }
+
+        drop(hls);
+        self.refresh_hls().await;
+        Ok(())
+    }
+
+    /// Point the packager at the producers currently making up the playlist
+    async fn refresh_hls(&self) {
+        let mut hls = self.hls.lock().await;
+        let hls = match hls.as_mut() {
+            Some(hls) => hls,
+            None => return,
+        };
+
+        let mut sources = self.broadcast_sources(&[]).await;
+        if let Some(video_user) = &hls.video_user {
+            if let Some(user) = self.users.read().await.get(video_user) {
+                let user = user.read().await;
+                sources.video = user
+                    .get_producer(ProduceType::ScreenshareVideo)
+                    .or_else(|| user.get_producer(ProduceType::Video))
+                    .map(|producer| producer.id())
+                    .filter(|_| user.registered() && !user.hidden())
+                    .or(sources.video);
+            }
+        }
+
+        if let Err(err) = hls.packager.update(sources).await {
+            warn!("Failed to package room {} as HLS: {}", self.id, err);
+        }
+    }
+
+    pub async fn recording(&self) -> bool {
+        self.recording.lock().await.is_some()
+    }
+
+    /// Start recording every producer of the room's visible users, and those started
+    /// later. Where the room requires consent, they're asked for it first
+    pub async fn start_recording(&self) -> Result<(), RecordingError> {
+        let config = CONFIG
+            .recording
+            .as_ref()
+            .ok_or(RecordingError::Unavailable)?;
+        if !self.recording_allowed() {
+            return Err(RecordingError::NotAllowed);
+        }
+
+        let mut recording = self.recording.lock().await;
+        if recording.is_some() {
+            return Err(RecordingError::AlreadyRecording);
+        }
+
+        let router = self.router.lock().clone();
+        let require_consent = self.settings.require_recording_consent;
+        let mut started = Recording::start(router, &self.scoped_id, config, require_consent)?;
+        let mut asked = Vec::new();
+        for user in self.users.read().await.values() {
+            let user = user.read().await;
+            if !user.registered() || user.hidden() {
+                continue;
+            }
+
+            if started.ask(user.id()) {
+                asked.push(user.id().to_string());
+            }
+            for produce_type in ProduceType::ALL.iter() {
+                if let Some(producer) = user.get_producer(*produce_type) {
+                    started.add(TrackSource {
+                        user_id: user.id().to_string(),
+                        produce_type: *produce_type,
+                        producer_id: producer.id(),
+                    });
+                }
+            }
+        }
+
+        *recording = Some(started);
+        drop(recording);
+        self.usage.record_recording();
+        self.send_event(RoomEvent::RecordingStateChanged(true));
+        if !asked.is_empty() {
+            self.send_event(RoomEvent::RecordingConsentRequested(asked));
+        }
+        Ok(())
+    }
+
+    /// Ask a user connecting while the room is recorded for its consent, if the room
+    /// requires it and the user didn't answer yet
+    pub async fn ask_recording_consent(&self, user_id: &str) {
+        let asked = match self.recording.lock().await.as_mut() {
+            Some(recording) => recording.ask(user_id),
+            None => false,
+        };
+        if asked {
+            let event = RoomEvent::RecordingConsentRequested(vec![user_id.to_string()]);
+            self.send_event(event);
+        }
+    }
+
+    /// Record whether a user consents to being recorded, recording its producers from
+    /// now on if it does and no longer if it doesn't
+    pub async fn answer_recording_consent(
+        &self,
+        user_id: &str,
+        granted: bool,
+    ) -> Result<(), RecordingError> {
+        let mut recording = self.recording.lock().await;
+        let recording = recording.as_mut().ok_or(RecordingError::NotRecording)?;
+        recording.answer(user_id, granted)?;
+
+        let users = self.users.read().await;
+        let user = match users.get(user_id) {
+            Some(user) => user.read().await,
+            None => return Ok(()),
+        };
+        for produce_type in ProduceType::ALL.iter() {
+            if let Some(producer) = user.get_producer(*produce_type) {
+                match granted {
+                    true => recording.add(TrackSource {
+                        user_id: user_id.to_string(),
+                        produce_type: *produce_type,
+                        producer_id: producer.id(),
+                    }),
+                    false => recording.remove(producer.id()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop recording, returning once every file is flushed and closed
+    pub async fn stop_recording(&self) -> Result<(), RecordingError> {
+        let recording = self
+            .recording
+            .lock()
+            .await
+            .take()
+            .ok_or(RecordingError::NotRecording)?;
+        recording.stop().await;
+        self.send_event(RoomEvent::RecordingStateChanged(false));
+        Ok(())
+    }
+
+    /// Add a newly started producer to the recording, if the room is being recorded
+    pub async fn record_producer(&self, source: TrackSource) {
+        if let Some(recording) = self.recording.lock().await.as_mut() {
+            recording.add(source);
+        }
+    }
+
+    pub async fn broadcasting(&self) -> bool {
+        let broadcast = self.broadcast.lock().await;
+        matches!(broadcast.as_ref(), Some(broadcast) if !broadcast.finished())
+    }
+
+    /// Push the room to an ingest, mixing the audio of the requested users and showing
+    /// the first of them sharing their screen, or else the first with a camera. A
+    /// broadcast that failed is replaced
+    pub async fn start_broadcast(
+        self: &Arc<Self>,
+        request: StartBroadcast,
+    ) -> Result<(), BroadcastError> {
+        let config = CONFIG
+            .broadcast
+            .as_ref()
+            .ok_or(BroadcastError::Unavailable)?;
+        let mut broadcast = self.broadcast.lock().await;
+        if matches!(broadcast.as_ref(), Some(broadcast) if !broadcast.finished()) {
+            return Err(BroadcastError::AlreadyBroadcasting);
+        }
+
+        let sources = self.broadcast_sources(&request.users).await;
+        let room = Arc::downgrade(self);
+        let router = self.router.lock().clone();
+        let started = Broadcast::start(&router, &request.url, sources, config, move |state| {
+            // The pipeline doesn't keep the room alive, nor outlive it
+            if let Some(room) = Weak::upgrade(&room) {
+                room.send_event(RoomEvent::BroadcastStateChanged(state));
+            }
+        })
+        .await?;
+
+        info!("Broadcasting room {}", self.id);
+        *broadcast = Some(started);
+        Ok(())
+    }
+
+    /// Stop pushing to the ingest, returning once FFmpeg exited
+    pub async fn stop_broadcast(&self) -> Result<(), BroadcastError> {
+        let broadcast = self
+            .broadcast
+            .lock()
+            .await
+            .take()
+            .filter(|broadcast| !broadcast.finished())
+            .ok_or(BroadcastError::NotBroadcasting)?;
+        broadcast.stop().await;
+        Ok(())
+    }
+
+    /// Threshold and interval the audio levels are observed with, or will be once observed
+    async fn audio_level_options(&self) -> (i8, u16) {
+        match self.audio_levels.lock().await.as_ref() {
+            Some(audio_levels) => (audio_levels.threshold_db(), audio_levels.interval_ms()),
+            None => (
+                self.settings
+                    .audio_level_threshold_db
+                    .unwrap_or(CONFIG.audio_levels.threshold_db),
+                self.settings
+                    .audio_level_interval_ms
+                    .unwrap_or(CONFIG.audio_levels.interval_ms),
+            ),
+        }
+    }
+
+    /// Observe the audio levels of the room with a new threshold and interval. Observers
+    /// can't be reconfigured, so the current one is replaced and its producers carried over
+    async fn observe_audio_levels(
+        self: &Arc<Self>,
+        threshold_db: i8,
+        interval_ms: u16,
+    ) -> Result<(), ()> {
+        let room = Arc::downgrade(self);
+        let router = self.router.lock().clone();
+        let created = AudioLevels::create(&router, threshold_db, interval_ms, move |speakers| {
+            if let Some(room) = Weak::upgrade(&room) {
+                room.usage.record_speakers(&speakers, interval_ms);
+                room.send_event(RoomEvent::ActiveSpeakers(speakers));
+            }
+        })
+        .await?;
+
+        let mut audio_levels = self.audio_levels.lock().await;
+        if self.closed() {
+            return Ok(());
+        }
+        if let Some(previous) = audio_levels.as_ref() {
+            for (producer_id, user_id) in previous.producers() {
+                created.add(&user_id, producer_id).await;
+            }
+        }
+        *audio_levels = Some(created);
+        Ok(())
+    }
+
+    /// Announce a visible user speaking into the microphone producer it just started
+    pub async fn observe_audio(&self, user_id: &str, producer_id: ProducerId) {
+        if let Some(audio_levels) = self.audio_levels.lock().await.as_ref() {
+            audio_levels.add(user_id, producer_id).await;
+        }
+    }
+
+    /// Producer of the audio the room is playing, consumed as the system user's audio
+    pub async fn playback_producer(&self) -> Option<ProducerId> {
+        let playback = self.playback.lock().await;
+        playback
+            .as_ref()
+            .filter(|playback| !playback.finished())
+            .map(Playback::producer_id)
+    }
+
+    /// Play audio into the room, announced as the system user producing audio. Requests
+    /// made while audio is playing are refused rather than queued
+    pub async fn play(self: &Arc<Self>, source: PlaybackSource) -> Result<(), PlaybackError> {
+        let config = CONFIG.playback.as_ref().ok_or(PlaybackError::Unavailable)?;
+        let mut playback = self.playback.lock().await;
+        if matches!(playback.as_ref(), Some(playback) if !playback.finished()) {
+            return Err(PlaybackError::AlreadyPlaying);
+        }
+
+        let room = Arc::downgrade(self);
+        let router = self.router.lock().clone();
+        let started = Playback::start(&router, source, config, move || {
+            if let Some(room) = Weak::upgrade(&room) {
+                let event =
+                    RoomEvent::UserStopProduce(SYSTEM_USER_ID.to_string(), ProduceType::Audio);
+                room.send_event(event);
+            }
+        })
+        .await?;
+
+        info!("Playing audio into room {}", self.id);
+        *playback = Some(started);
+        drop(playback);
+        let event =
+            RoomEvent::UserStartProduce(SYSTEM_USER_ID.to_string(), ProduceType::Audio, false);
+        self.send_event(event);
+        Ok(())
+    }
+
+    async fn broadcast_sources(&self, selected: &[String]) -> BroadcastSources {
+        let users = self.users.read().await;
+        let ids: Vec<&String> = if selected.is_empty() {
+            users.keys().collect()
+        } else {
+            selected.iter().collect()
+        };
+
+        let mut sources = BroadcastSources {
+            audio: Vec::new(),
+            video: None,
+        };
+        let mut camera = None;
+        for id in ids {
+            let user = match users.get(id) {
+                Some(user) => user.read().await,
+                None => continue,
+            };
+            if !user.registered() || user.hidden() {
+                continue;
+            }
+
+            for produce_type in [ProduceType::Audio, ProduceType::ScreenshareAudio].iter() {
+                if let Some(producer) = user.get_producer(*produce_type) {
+                    sources.audio.push(producer.id());
+                }
+            }
+
+            if sources.video.is_none() {
+                sources.video = user
+                    .get_producer(ProduceType::ScreenshareVideo)
+                    .map(|producer| producer.id());
+            }
+
+            if camera.is_none() {
+                camera = user
+                    .get_producer(ProduceType::Video)
+                    .map(|producer| producer.id());
+            }
+        }
+
+        sources.video = sources.video.or(camera);
+        sources
+    }
+
+    pub fn cached_info(&self) -> Option<(u64, HashMap<String, UserInfo>)> {
+        self.info_cache.lock().clone()
+    }
+
+    pub fn cache_info(&self, revision: u64, users: HashMap<String, UserInfo>) {
+        *self.info_cache.lock() = Some((revision, users));
+    }
+
+    /// Revision of what `RoomInfo` lists, see `Roster`
+    pub fn roster_revision(&self) -> u64 {
+        self.roster.lock().revision()
+    }
+
+    /// Users of this node that joined, left or changed after the revision, `None` if
+    /// the changes aren't known that far back
+    pub fn roster_changes(&self, since: u64) -> Option<Vec<String>> {
+        self.roster.lock().changed_since(since)
+    }
+
     pub fn users(self: &Arc<Room>) -> RoomUsers {
         RoomUsers::from_room(self.clone())
     }
@@ -123,6 +1915,503 @@ impl Room {
 
 impl Drop for Room {
     fn drop(&mut self) {
+        self.occupancy.clear();
         debug!("Room {} dropped, mediasoup Router cleaned up", self.id);
     }
 }
+
+/// Relay the room's positions every tick until nobody is updating theirs, or the room
+/// is gone
+async fn relay_positions(room: Weak<Room>) {
+    let mut ticks = tokio::time::interval(POSITION_TICK);
+    loop {
+        ticks.tick().await;
+        let room = match room.upgrade() {
+            Some(room) if !room.closed() => room,
+            _ => return,
+        };
+
+        let mut positions = room.positions.lock();
+        let relayed = positions.tick(Instant::now());
+        positions.ticking = !positions.is_empty();
+        let ticking = positions.ticking;
+        drop(positions);
+        if let Some(relayed) = relayed {
+            room.send_event(RoomEvent::Positions(relayed));
+        }
+        if !ticking {
+            return;
+        }
+    }
+}
+
+/// Hold the room to its bitrate budget, sampling its transports every
+/// `THROTTLE_INTERVAL` until the room is gone
+async fn watch_bitrate(room: Weak<Room>) {
+    let mut samples = tokio::time::interval(THROTTLE_INTERVAL);
+    samples.tick().await;
+    loop {
+        samples.tick().await;
+        let room = match room.upgrade() {
+            Some(room) if !room.closed() => room,
+            _ => return,
+        };
+
+        let bitrates = room.stats().await.bitrates;
+        let level = room
+            .throttle
+            .lock()
+            .as_mut()
+            .and_then(|throttle| throttle.sample(bitrates.incoming + bitrates.outgoing));
+        if let Some(level) = level {
+            room.throttled(level);
+        }
+    }
+}
+
+/// Sample the NACKs, PLIs and retransmissions of the room's media every `interval` until
+/// the room is gone, publishing them for the room and alerting its moderators if asked
+async fn watch_retransmissions(room: Weak<Room>, interval: Duration) {
+    let (tenant, id) = match room.upgrade() {
+        Some(room) => (room.tenant.clone(), room.id.clone()),
+        None => return,
+    };
+    let labels = [tenant.as_str(), id.as_str()];
+    let mut samples = tokio::time::interval(interval);
+    samples.tick().await;
+    loop {
+        samples.tick().await;
+        let room = match room.upgrade() {
+            Some(room) if !room.closed() => room,
+            _ => break,
+        };
+
+        let mut media = Vec::new();
+        for (id, user) in room.users.read().await.iter() {
+            let user = user.read().await;
+            if let Some(user_media) = user.media() {
+                let producers = ProduceType::ALL
+                    .iter()
+                    .filter_map(|produce_type| user.get_producer(*produce_type).cloned())
+                    .collect::<Vec<_>>();
+                media.push((id.clone(), user_media.clone(), producers));
+            }
+        }
+        // Sampled once the users are released, like the room's stats
+        let mut users = HashMap::new();
+        for (id, user_media, producers) in media {
+            match user_media.retransmissions(producers).await {
+                Ok(streams) => {
+                    users.insert(id, streams);
+                }
+                Err(()) => warn!("Failed to sample retransmissions in room {}", room.id),
+            }
+        }
+
+        let settings = room.settings.retransmissions;
+        let (counts, alert) = {
+            let mut window = room.retransmissions.lock();
+            window.sample(users, Instant::now());
+            (window.room(), window.alert(&settings))
+        };
+        ROOM_NACKS
+            .with_label_values(&labels)
+            .set(counts.nacks as i64);
+        ROOM_PLIS.with_label_values(&labels).set(counts.plis as i64);
+        ROOM_RETRANSMITTED_BYTES
+            .with_label_values(&labels)
+            .set(counts.retransmitted_bytes as i64);
+        if let (true, Some(alert)) = (settings.alerts, alert) {
+            room.signal_moderators(RoomEvent::QualityAlert(alert)).await;
+        }
+    }
+
+    for gauge in &[&*ROOM_NACKS, &*ROOM_PLIS, &*ROOM_RETRANSMITTED_BYTES] {
+        gauge.remove_label_values(&labels).ok();
+    }
+}
+
+/// Announce the state a user's producer settled in after changes were held back. Users
+/// that left were announced gone along with their producers
+async fn settle_producer(
+    room: Weak<Room>,
+    user_id: String,
+    produce_type: ProduceType,
+    delay: Duration,
+) {
+    tokio::time::sleep(delay).await;
+    let room = match room.upgrade() {
+        Some(room) => room,
+        None => return,
+    };
+
+    // Held until announced, so that a change made meanwhile is announced after this
+    let users = room.users.read().await;
+    let user = match users.get(&user_id) {
+        Some(user) => user.read().await,
+        None => return,
+    };
+    let producer = user.get_producer(produce_type);
+    let settled = room.announcements.lock().settle(
+        &user_id,
+        produce_type,
+        producer.map(Producer::id),
+        Instant::now(),
+    );
+    if settled.stopped {
+        room.send_event(RoomEvent::UserStopProduce(user_id.clone(), produce_type));
+    }
+    if let (true, Some(producer)) = (settled.started, producer) {
+        let event = RoomEvent::UserStartProduce(user_id, produce_type, producer.paused());
+        room.send_event(event);
+    }
+}
+
+/// Refresh the room's HLS packaging whenever what it could carry changes, until the
+/// room is gone
+async fn watch_hls(room: Weak<Room>, mut events: Receiver<BroadcastEvent>) {
+    loop {
+        match events.recv().await.map(|broadcast| broadcast.event) {
+            Ok(RoomEvent::UserStartProduce(..))
+            | Ok(RoomEvent::UserStopProduce(..))
+            | Ok(RoomEvent::UserProducerReplaced(..))
+            | Ok(RoomEvent::UserLeft(..))
+            | Err(RecvError::Lagged(_)) => (),
+            Ok(_) => continue,
+            Err(RecvError::Closed) => return,
+        }
+
+        match room.upgrade() {
+            Some(room) => room.refresh_hls().await,
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::subscriber::{SubscriberMessage, SubscriberOptions};
+    use super::*;
+    use crate::state::user::UserOptions;
+    use crate::util::metrics::{ROOM_SUBSCRIBERS, ROOM_USERS};
+    use crate::util::testing;
+    use crate::ws::types::SignalingTransport;
+
+    fn options() -> SubscriberOptions {
+        SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_closes_every_subscriber() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let mut first = room.subscribe(1, "first", options()).unwrap();
+        let mut second = room.subscribe(2, "second", options()).unwrap();
+
+        room.delete().await;
+        for subscriber in [&mut first, &mut second] {
+            match subscriber.recv().await {
+                SubscriberMessage::Event(RoomEvent::RoomDelete(_)) => (),
+                _ => panic!("Expected the room deletion"),
+            }
+        }
+        assert!(room.subscribe(3, "late", options()).is_none());
+    }
+
+    #[tokio::test]
+    async fn summary_reaches_subscribers_with_full_queues() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let mut subscriber = room.subscribe(1, "busy", options()).unwrap();
+
+        // Fill the control channel, which holds 8 signals
+        for bitrate in 1..=8 {
+            room.set_max_incoming_bitrate(bitrate);
+        }
+
+        let deleting = {
+            let room = room.clone();
+            tokio::spawn(async move { room.delete().await })
+        };
+        for bitrate in 1..=8 {
+            match subscriber.recv().await {
+                SubscriberMessage::MaxIncomingBitrate(received) => assert_eq!(received, bitrate),
+                _ => panic!("Expected the queued signals first"),
+            }
+        }
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::RoomDelete(summary)) => {
+                assert_eq!(summary.total_unique_users, 0)
+            }
+            _ => panic!("Expected the room summary"),
+        }
+        deleting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_are_summarized_before_the_room_closes() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("host".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        drop(users);
+        let mut subscriber = room.subscribe(1, "host", options()).unwrap();
+        room.ban("mallory", None);
+
+        let deleting = {
+            let room = room.clone();
+            tokio::spawn(async move { room.delete().await })
+        };
+        let mut call = None;
+        loop {
+            match subscriber.recv().await {
+                SubscriberMessage::Event(RoomEvent::CallSummary(summary)) => call = Some(summary),
+                SubscriberMessage::Event(RoomEvent::RoomDelete(_)) => break,
+                _ => (),
+            }
+        }
+        let call = call.expect("Expected the call summary first");
+        assert_eq!((call.peak_users, call.bans), (1, 1));
+        deleting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn media_restarts_on_a_new_router() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("host".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        drop(users);
+        let mut subscriber = room.subscribe(1, "host", options()).unwrap();
+        let router_id = room.router().unwrap().id();
+
+        // Rooms on other workers are left alone
+        let other = "0a7cfe2c-6f1d-4f0e-9d62-3d5bd5b5a003";
+        restart_media(serde_json::from_value(other.into()).unwrap()).await;
+        assert_eq!(room.router().unwrap().id(), router_id);
+
+        // Moved on its own, other tests' rooms are on the same worker
+        room.restart_media().await;
+        assert_ne!(room.router().unwrap().id(), router_id);
+        assert_eq!(room.worker_id(), get_worker_pool().get_worker().id());
+        loop {
+            match subscriber.recv().await {
+                SubscriberMessage::MediaRestart => break,
+                SubscriberMessage::Close(_) => panic!("Expected the connection to stay"),
+                _ => (),
+            }
+        }
+        assert!(room.users().get("host").await.is_some());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn delete_releases_remaining_users() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let pending = ROOM_USERS.with_label_values(&["pending", DEFAULT_TENANT]);
+        let base = pending.get();
+
+        let users = room.users();
+        users
+            .create("never-connects".to_string(), UserOptions::default())
+            .await
+            .unwrap();
+        drop(users);
+        assert_eq!(pending.get(), base + 1);
+
+        room.delete().await;
+        assert_eq!(room.occupancy().counts().capacity_used(), 0);
+        assert_eq!(pending.get(), base);
+        assert_eq!(Arc::strong_count(&room), 1);
+    }
+
+    #[tokio::test]
+    async fn key_frame_requests_are_rate_limited() {
+        let room = testing::room(RoomSettings::default()).await;
+        let producer = |id: &str| serde_json::from_value::<ProducerId>(id.into()).unwrap();
+        let first = producer("0a7cfe2c-6f1d-4f0e-9d62-3d5bd5b5a001");
+        let second = producer("0a7cfe2c-6f1d-4f0e-9d62-3d5bd5b5a002");
+        let interval = Duration::from_millis(100);
+
+        assert!(room.allow_key_frame_request(first, interval));
+        assert!(!room.allow_key_frame_request(first, interval));
+        assert!(room.allow_key_frame_request(second, interval));
+        tokio::time::sleep(interval).await;
+        assert!(room.allow_key_frame_request(first, interval));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn subscriber_count_follows_churn() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let base = ROOM_SUBSCRIBERS.get();
+
+        let subscribe = |connection_id: u64| {
+            let user_id = format!("user-{}", connection_id);
+            room.subscribe(connection_id, &user_id, options()).unwrap()
+        };
+        let subscribers: Vec<RoomSubscriber> = (0..10).map(subscribe).collect();
+        assert_eq!(room.subscriber_count(), 10);
+        assert_eq!(ROOM_SUBSCRIBERS.get(), base + 10);
+
+        // Half of the connections drop, then a few others join
+        let mut subscribers: Vec<RoomSubscriber> = subscribers
+            .into_iter()
+            .filter(|subscriber| subscriber.info().connection_id % 2 == 0)
+            .collect();
+        assert_eq!(room.subscriber_count(), 5);
+        assert_eq!(ROOM_SUBSCRIBERS.get(), base + 5);
+
+        subscribers.extend((10..13).map(subscribe));
+        assert_eq!(room.subscriber_count(), 8);
+        assert_eq!(ROOM_SUBSCRIBERS.get(), base + 8);
+        assert_eq!(room.subscribers().len(), 8);
+
+        drop(subscribers);
+        assert_eq!(room.subscriber_count(), 0);
+        assert_eq!(ROOM_SUBSCRIBERS.get(), base);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn bitrate_update_reaches_every_participant() {
+        let _serial = testing::serial();
+        let settings = RoomSettings {
+            max_incoming_bitrate: Some(500_000),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        assert_eq!(room.max_incoming_bitrate(), Some(500_000));
+
+        let mut first = room.subscribe(1, "first", options()).unwrap();
+        let mut second = room.subscribe(2, "second", options()).unwrap();
+
+        room.update_settings(RoomSettingsUpdate {
+            max_incoming_bitrate: Some(250_000),
+            ..RoomSettingsUpdate::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(room.max_incoming_bitrate(), Some(250_000));
+        for subscriber in [&mut first, &mut second] {
+            match subscriber.recv().await {
+                SubscriberMessage::MaxIncomingBitrate(bitrate) => assert_eq!(bitrate, 250_000),
+                _ => panic!("Expected a bitrate update"),
+            }
+        }
+
+        room.update_settings(RoomSettingsUpdate {
+            max_incoming_bitrate: Some(0),
+            ..RoomSettingsUpdate::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(room.max_incoming_bitrate(), None);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn audio_level_updates_replace_the_observer() {
+        let settings = RoomSettings {
+            audio_level_threshold_db: Some(-50),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let metadata = room.metadata().await;
+        assert_eq!(metadata.settings.audio_level_threshold_db, Some(-50));
+        assert_eq!(
+            metadata.settings.audio_level_interval_ms,
+            Some(CONFIG.audio_levels.interval_ms)
+        );
+
+        room.update_settings(RoomSettingsUpdate {
+            audio_level_interval_ms: Some(200),
+            ..RoomSettingsUpdate::default()
+        })
+        .await
+        .unwrap();
+        let metadata = room.metadata().await;
+        assert_eq!(metadata.settings.audio_level_threshold_db, Some(-50));
+        assert_eq!(metadata.settings.audio_level_interval_ms, Some(200));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn empty_rooms_close_after_the_idle_timeout() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut events = room.sender.subscribe();
+        room.schedule_idle_close_after(Duration::from_millis(50));
+
+        match events.recv().await.map(|broadcast| broadcast.event) {
+            Ok(RoomEvent::RoomDelete(_)) => (),
+            _ => panic!("Expected the room to be deleted"),
+        }
+        assert!(room.closed());
+        assert!(Room::get(room.tenant(), room.id()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rooms_close_at_their_maximum_duration() {
+        let settings = RoomSettings {
+            max_duration_secs: Some(1),
+            persistent: true,
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let mut events = room.sender.subscribe();
+        assert!(room.expires_at().unwrap() > Instant::now());
+
+        // Whether or not it's persistent
+        match events.recv().await.map(|broadcast| broadcast.event) {
+            Ok(RoomEvent::RoomDelete(_)) => (),
+            _ => panic!("Expected the room to be deleted"),
+        }
+        assert!(room.closed());
+        assert!(room.expiry_timer.lock().is_none());
+    }
+
+    #[tokio::test]
+    async fn joining_cancels_the_idle_timeout() {
+        let room = testing::room(RoomSettings::default()).await;
+        room.schedule_idle_close_after(Duration::from_millis(50));
+        room.users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!room.closed());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn persistent_rooms_stay_open() {
+        let settings = RoomSettings {
+            persistent: true,
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        room.schedule_idle_close_after(Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!room.closed());
+
+        // Opting back in closes the room once it idled again
+        room.set_persistent(false).await;
+        room.schedule_idle_close_after(Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(room.closed());
+    }
+}
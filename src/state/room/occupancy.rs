@@ -0,0 +1,203 @@
+use parking_lot::Mutex;
+use prometheus::IntGauge;
+use serde::Serialize;
+
+use crate::util::config::DEFAULT_TENANT;
+use crate::util::metrics::ROOM_USERS;
+
+/// User counts of a room, by class
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OccupancyCounts {
+    /// Registered users shown to other participants
+    pub visible: usize,
+    /// Registered users hidden from other participants, such as bots and observers
+    pub hidden: usize,
+    /// Users created over the API that haven't connected yet
+    pub pending: usize,
+}
+
+impl OccupancyCounts {
+    /// Every user holding a slot in the room, this is what the room capacity is checked against
+    pub fn capacity_used(&self) -> usize {
+        self.visible + self.hidden + self.pending
+    }
+}
+
+/// Occupancy counters, updated together whenever a user is created, registered or removed
+pub struct Occupancy {
+    counts: Mutex<OccupancyCounts>,
+    /// Tenant of the room, the users metric is labelled with it
+    tenant: String,
+}
+
+impl Default for Occupancy {
+    fn default() -> Self {
+        Occupancy::new(DEFAULT_TENANT)
+    }
+}
+
+impl Occupancy {
+    pub(super) fn new(tenant: &str) -> Self {
+        Occupancy {
+            counts: Mutex::new(OccupancyCounts::default()),
+            tenant: tenant.to_string(),
+        }
+    }
+
+    fn gauge(&self, class: &str) -> IntGauge {
+        ROOM_USERS.with_label_values(&[class, &self.tenant])
+    }
+
+    pub fn counts(&self) -> OccupancyCounts {
+        *self.counts.lock()
+    }
+
+    /// Reserve a slot for a newly created user, `false` if the room is full
+    pub(super) fn reserve(&self, capacity: Option<usize>) -> bool {
+        let mut counts = self.counts.lock();
+        if let Some(capacity) = capacity {
+            if counts.capacity_used() >= capacity {
+                return false;
+            }
+        }
+
+        counts.pending += 1;
+        self.gauge("pending").inc();
+        true
+    }
+
+    /// Move a pending user into the visible or hidden class
+    pub(in crate::state) fn admit(&self, hidden: bool) {
+        let mut counts = self.counts.lock();
+        counts.pending -= 1;
+        self.gauge("pending").dec();
+        if hidden {
+            counts.hidden += 1;
+        } else {
+            counts.visible += 1;
+        }
+        self.gauge(class(hidden)).inc();
+    }
+
+    /// Release the slot of a removed user
+    pub(super) fn release(&self, registered: bool, hidden: bool) {
+        let mut counts = self.counts.lock();
+        let label = if !registered {
+            counts.pending -= 1;
+            "pending"
+        } else if hidden {
+            counts.hidden -= 1;
+            class(true)
+        } else {
+            counts.visible -= 1;
+            class(false)
+        };
+        self.gauge(label).dec();
+    }
+
+    /// Release every remaining slot when the room goes away
+    pub(super) fn clear(&self) {
+        let mut counts = self.counts.lock();
+        self.gauge("visible").sub(counts.visible as i64);
+        self.gauge("hidden").sub(counts.hidden as i64);
+        self.gauge("pending").sub(counts.pending as i64);
+        *counts = OccupancyCounts::default();
+    }
+}
+
+fn class(hidden: bool) -> &'static str {
+    match hidden {
+        true => "hidden",
+        false => "visible",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    fn gauge(class: &str) -> i64 {
+        ROOM_USERS.with_label_values(&[class, DEFAULT_TENANT]).get()
+    }
+
+    fn gauges() -> [i64; 3] {
+        [gauge("visible"), gauge("hidden"), gauge("pending")]
+    }
+
+    fn counts(occupancy: &Occupancy) -> [usize; 3] {
+        let counts = occupancy.counts();
+        [counts.visible, counts.hidden, counts.pending]
+    }
+
+    fn moved(base: [i64; 3], by: [i64; 3]) -> [i64; 3] {
+        [base[0] + by[0], base[1] + by[1], base[2] + by[2]]
+    }
+
+    #[test]
+    fn pending_user_is_admitted() {
+        let _serial = testing::serial();
+        let base = gauges();
+        let occupancy = Occupancy::default();
+
+        assert!(occupancy.reserve(None));
+        assert_eq!(counts(&occupancy), [0, 0, 1]);
+        assert_eq!(gauges(), moved(base, [0, 0, 1]));
+
+        occupancy.admit(false);
+        assert_eq!(counts(&occupancy), [1, 0, 0]);
+        assert_eq!(gauges(), moved(base, [1, 0, 0]));
+
+        occupancy.release(true, false);
+        assert_eq!(counts(&occupancy), [0, 0, 0]);
+        assert_eq!(gauges(), base);
+    }
+
+    #[test]
+    fn bot_joins_and_leaves_hidden() {
+        let _serial = testing::serial();
+        let base = gauges();
+        let occupancy = Occupancy::default();
+
+        assert!(occupancy.reserve(None));
+        occupancy.admit(true);
+        assert_eq!(counts(&occupancy), [0, 1, 0]);
+        assert_eq!(gauges(), moved(base, [0, 1, 0]));
+
+        occupancy.release(true, true);
+        assert_eq!(counts(&occupancy), [0, 0, 0]);
+        assert_eq!(gauges(), base);
+    }
+
+    #[test]
+    fn pending_user_is_released() {
+        let _serial = testing::serial();
+        let base = gauges();
+        let occupancy = Occupancy::default();
+
+        assert!(occupancy.reserve(None));
+        occupancy.release(false, false);
+        assert_eq!(counts(&occupancy), [0, 0, 0]);
+        assert_eq!(gauges(), base);
+    }
+
+    #[test]
+    fn capacity_counts_every_class() {
+        let _serial = testing::serial();
+        let base = gauges();
+        let occupancy = Occupancy::default();
+
+        assert!(occupancy.reserve(Some(3)));
+        occupancy.admit(false);
+        assert!(occupancy.reserve(Some(3)));
+        occupancy.admit(true);
+        assert!(occupancy.reserve(Some(3)));
+        assert!(!occupancy.reserve(Some(3)));
+        assert_eq!(counts(&occupancy), [1, 1, 1]);
+
+        occupancy.clear();
+        assert_eq!(counts(&occupancy), [0, 0, 0]);
+        assert_eq!(gauges(), base);
+    }
+}
@@ -0,0 +1,125 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often the positions that changed are relayed, however often users update them
+pub const POSITION_TICK: Duration = Duration::from_millis(100);
+/// How long a position is relayed after its user last updated it
+pub const POSITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a user is in a spatial audio room, in the application's units. `rotation` is
+/// around the vertical axis
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub rotation: f32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPosition {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub position: Position,
+}
+
+/// Latest positions of a room's users, coalesced between ticks
+#[derive(Default)]
+pub struct Positions {
+    latest: HashMap<String, (Position, Instant)>,
+    /// Whether the positions relayed differ from those sent on the last tick
+    changed: bool,
+    /// Whether a task is relaying them, it stops once nobody is updating
+    pub(super) ticking: bool,
+}
+
+impl Positions {
+    pub fn update(&mut self, user_id: &str, position: Position, now: Instant) {
+        let previous = self.latest.insert(user_id.to_string(), (position, now));
+        if previous.map(|(previous, _)| previous) != Some(position) {
+            self.changed = true;
+        }
+    }
+
+    pub fn remove(&mut self, user_id: &str) {
+        if self.latest.remove(user_id).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Position of a user that updated it lately
+    pub fn get(&self, user_id: &str, now: Instant) -> Option<Position> {
+        self.latest
+            .get(user_id)
+            .filter(|(_, updated)| now.duration_since(*updated) < POSITION_TIMEOUT)
+            .map(|(position, _)| *position)
+    }
+
+    /// Every position to relay, `None` when they're the same as on the last tick. Users
+    /// that stopped updating are dropped
+    pub fn tick(&mut self, now: Instant) -> Option<Vec<UserPosition>> {
+        let before = self.latest.len();
+        self.latest
+            .retain(|_, (_, updated)| now.duration_since(*updated) < POSITION_TIMEOUT);
+        if self.latest.len() != before {
+            self.changed = true;
+        }
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+
+        let positions = self
+            .latest
+            .iter()
+            .map(|(user_id, (position, _))| UserPosition {
+                user_id: user_id.clone(),
+                position: *position,
+            })
+            .collect();
+        Some(positions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Position {
+        Position {
+            x,
+            y: 0.0,
+            z: 0.0,
+            rotation: 0.0,
+        }
+    }
+
+    #[test]
+    fn updates_are_coalesced_until_stale() {
+        let mut positions = Positions::default();
+        let start = Instant::now();
+        assert!(positions.tick(start).is_none());
+
+        positions.update("alice", at(1.0), start);
+        positions.update("alice", at(2.0), start);
+        let relayed = positions.tick(start).unwrap();
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].position, at(2.0));
+
+        // Standing still sends nothing more
+        positions.update("alice", at(2.0), start + POSITION_TICK);
+        assert!(positions.tick(start + POSITION_TICK).is_none());
+
+        // Until the user stopped updating, which relays the room without it
+        let later = start + POSITION_TICK + POSITION_TIMEOUT;
+        assert!(positions.get("alice", later).is_none());
+        assert_eq!(positions.tick(later), Some(vec![]));
+        assert!(positions.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+//! Membership and moderation events of a room over the last few minutes, replayed to
+//! moderators joining a call in progress with `GetRecentEvents`. Only events sent on this
+//! node are kept, and never more than `event_replay.max_events` however busy the room is
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::RoomEvent;
+use crate::util::config::EventReplayConfig;
+
+pub struct RecentEvents {
+    config: &'static EventReplayConfig,
+    /// Oldest first
+    events: VecDeque<(DateTime<Utc>, RoomEvent)>,
+}
+
+impl RecentEvents {
+    pub fn new(config: &'static EventReplayConfig) -> Self {
+        RecentEvents {
+            config,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Keep the event if it's one moderators are replayed, dropping the oldest past the
+    /// limit and those kept for longer than the retention
+    pub fn record(&mut self, event: &RoomEvent, now: DateTime<Utc>) {
+        if !replayed(event) {
+            return;
+        }
+
+        self.expire(now);
+        if self.events.len() >= self.config.max_events {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, event.clone()));
+    }
+
+    /// Events kept from the last `since_secs`, oldest first
+    pub fn since(
+        &mut self,
+        since_secs: u64,
+        now: DateTime<Utc>,
+    ) -> Vec<(DateTime<Utc>, RoomEvent)> {
+        self.expire(now);
+        let since_secs = since_secs.min(self.config.retention_secs);
+        let since = now - Duration::seconds(since_secs as i64);
+        self.events
+            .iter()
+            .filter(|(at, _)| *at >= since)
+            .cloned()
+            .collect()
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let oldest = now - Duration::seconds(self.config.retention_secs as i64);
+        while let Some((at, _)) = self.events.front() {
+            if *at >= oldest {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+}
+
+/// Who came and went and what moderators did, the room's media and chatter aren't kept
+fn replayed(event: &RoomEvent) -> bool {
+    matches!(
+        event,
+        RoomEvent::UserJoined(..)
+            | RoomEvent::UserLeft(..)
+            | RoomEvent::UserRoleChanged(..)
+            | RoomEvent::OwnerChanged(_)
+            | RoomEvent::UserWaiting(_)
+            | RoomEvent::UserAdmitted(_)
+            | RoomEvent::UserReported(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::user::UserInfo;
+
+    fn recent(max_events: usize, retention_secs: u64) -> RecentEvents {
+        let config = Box::leak(Box::new(EventReplayConfig {
+            max_events,
+            retention_secs,
+        }));
+        RecentEvents::new(config)
+    }
+
+    fn ids(events: &[(DateTime<Utc>, RoomEvent)]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|(_, event)| match event {
+                RoomEvent::UserJoined(id, _) | RoomEvent::UserLeft(id, _) => id.as_str(),
+                _ => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_membership_and_moderation_is_kept() {
+        let now = Utc::now();
+        let mut recent = recent(10, 300);
+        recent.record(
+            &RoomEvent::UserJoined("alice".to_string(), UserInfo::default()),
+            now,
+        );
+        recent.record(&RoomEvent::ActiveSpeakers(Vec::new()), now);
+        recent.record(
+            &RoomEvent::Relay("alice".to_string(), serde_json::Value::Null),
+            now,
+        );
+        recent.record(&RoomEvent::UserLeft("alice".to_string(), true), now);
+        assert_eq!(ids(&recent.since(60, now)), vec!["alice", "alice"]);
+    }
+
+    #[test]
+    fn events_are_bounded_and_expire() {
+        let start = Utc::now();
+        let at = |secs| start + Duration::seconds(secs);
+        let mut recent = recent(2, 300);
+        for (secs, id) in [(0, "alice"), (10, "bob"), (20, "carol")].iter() {
+            recent.record(&RoomEvent::UserLeft(id.to_string(), false), at(*secs));
+        }
+        assert_eq!(ids(&recent.since(600, at(20))), vec!["bob", "carol"]);
+        assert_eq!(ids(&recent.since(5, at(20))), vec!["carol"]);
+
+        // Past the retention whatever was asked for
+        assert_eq!(ids(&recent.since(600, at(315))), vec!["carol"]);
+        assert!(recent.since(600, at(400)).is_empty());
+        assert!(recent.events.is_empty());
+    }
+}
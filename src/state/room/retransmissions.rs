@@ -0,0 +1,286 @@
+//! NACKs, PLIs and retransmissions of a room's media over the last minute, sampled from
+//! the producers and consumers of its users. Summed for the room in metrics and alerts so
+//! label cardinality stays bounded, broken down by user in the room's stats
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::settings::RetransmissionSettings;
+
+/// How far back samples are summed
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RetransmissionCounts {
+    /// NACKs sent to the user for its producers, and by it for its consumers
+    pub nacks: u64,
+    /// Keyframe requests, counted the same way
+    pub plis: u64,
+    /// Estimated from the packets retransmitted and the average packet size of each
+    /// stream, mediasoup doesn't count their bytes
+    pub retransmitted_bytes: u64,
+}
+
+impl RetransmissionCounts {
+    /// Counts of a stream from its mediasoup stats
+    pub fn from_stream(
+        nacks: u64,
+        plis: u64,
+        packets_retransmitted: u64,
+        packet_count: u64,
+        byte_count: u64,
+    ) -> Self {
+        let retransmitted_bytes = match packet_count {
+            0 => 0,
+            packets => packets_retransmitted.saturating_mul(byte_count / packets),
+        };
+        RetransmissionCounts {
+            nacks,
+            plis,
+            retransmitted_bytes,
+        }
+    }
+
+    /// What was added since the stream's counts were `earlier`
+    fn since(&self, earlier: &Self) -> Self {
+        RetransmissionCounts {
+            nacks: self.nacks.saturating_sub(earlier.nacks),
+            plis: self.plis.saturating_sub(earlier.plis),
+            retransmitted_bytes: self
+                .retransmitted_bytes
+                .saturating_sub(earlier.retransmitted_bytes),
+        }
+    }
+}
+
+impl AddAssign for RetransmissionCounts {
+    fn add_assign(&mut self, other: RetransmissionCounts) {
+        self.nacks += other.nacks;
+        self.plis += other.plis;
+        self.retransmitted_bytes += other.retransmitted_bytes;
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCounter {
+    Nacks,
+    Plis,
+    RetransmittedBytes,
+}
+
+/// The room's media crossed a threshold of its `RetransmissionSettings`, sent to its
+/// moderators
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityAlert {
+    /// Over the last minute, summed over the room's users
+    pub counts: RetransmissionCounts,
+    pub exceeded: Vec<AlertCounter>,
+}
+
+/// Counts of each user's streams over the last `WINDOW`
+#[derive(Default)]
+pub struct RetransmissionWindow {
+    /// Counts each stream had when last sampled, by producer or consumer ID
+    streams: HashMap<String, RetransmissionCounts>,
+    /// What each user's streams added at each sample
+    samples: VecDeque<(Instant, HashMap<String, RetransmissionCounts>)>,
+    /// Whether the last alert is still standing, another is only sent once the room
+    /// went back under every threshold
+    alerting: bool,
+}
+
+impl RetransmissionWindow {
+    /// Account for the counts of every stream of each user so far, streams left out
+    /// were closed
+    pub fn sample(
+        &mut self,
+        users: HashMap<String, Vec<(String, RetransmissionCounts)>>,
+        now: Instant,
+    ) {
+        let mut streams = HashMap::new();
+        let mut added = HashMap::new();
+        for (user_id, user_streams) in users {
+            let mut user_added = RetransmissionCounts::default();
+            for (id, counts) in user_streams {
+                user_added += match self.streams.get(&id) {
+                    Some(earlier) => counts.since(earlier),
+                    None => counts,
+                };
+                streams.insert(id, counts);
+            }
+            added.insert(user_id, user_added);
+        }
+        self.streams = streams;
+        self.samples.push_back((now, added));
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) < WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn room(&self) -> RetransmissionCounts {
+        let mut counts = RetransmissionCounts::default();
+        for (_, users) in &self.samples {
+            for added in users.values() {
+                counts += *added;
+            }
+        }
+        counts
+    }
+
+    pub fn users(&self) -> HashMap<String, RetransmissionCounts> {
+        let mut counts = HashMap::new();
+        for (_, users) in &self.samples {
+            for (user_id, added) in users {
+                *counts.entry(user_id.clone()).or_default() += *added;
+            }
+        }
+        counts
+    }
+
+    /// Alert for the thresholds the last minute crossed, unless the room was already
+    /// alerted about and hasn't gone back under them since
+    pub fn alert(&mut self, settings: &RetransmissionSettings) -> Option<QualityAlert> {
+        let counts = self.room();
+        let exceeded: Vec<_> = [
+            (
+                AlertCounter::Nacks,
+                counts.nacks,
+                settings.max_nacks_per_minute,
+            ),
+            (
+                AlertCounter::Plis,
+                counts.plis,
+                settings.max_plis_per_minute,
+            ),
+            (
+                AlertCounter::RetransmittedBytes,
+                counts.retransmitted_bytes,
+                settings.max_retransmitted_bytes_per_minute,
+            ),
+        ]
+        .iter()
+        .filter(|(_, count, max)| count > max)
+        .map(|(counter, ..)| *counter)
+        .collect();
+
+        let alerting = !exceeded.is_empty();
+        let alert = match (alerting, self.alerting) {
+            (true, false) => Some(QualityAlert { counts, exceeded }),
+            _ => None,
+        };
+        self.alerting = alerting;
+        alert
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(nacks: u64, plis: u64, retransmitted_bytes: u64) -> RetransmissionCounts {
+        RetransmissionCounts {
+            nacks,
+            plis,
+            retransmitted_bytes,
+        }
+    }
+
+    fn sample(
+        window: &mut RetransmissionWindow,
+        streams: &[(&str, &str, RetransmissionCounts)],
+        at: Instant,
+    ) {
+        let mut users: HashMap<String, Vec<_>> = HashMap::new();
+        for (user_id, id, counts) in streams {
+            users
+                .entry(user_id.to_string())
+                .or_default()
+                .push((id.to_string(), *counts));
+        }
+        window.sample(users, at);
+    }
+
+    #[test]
+    fn retransmitted_bytes_are_estimated_from_the_packet_size() {
+        assert_eq!(
+            RetransmissionCounts::from_stream(4, 1, 10, 1000, 1_200_000),
+            counts(4, 1, 12_000)
+        );
+        assert_eq!(
+            RetransmissionCounts::from_stream(0, 0, 10, 0, 0),
+            counts(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn samples_are_summed_over_the_last_minute() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut window = RetransmissionWindow::default();
+
+        sample(&mut window, &[("alice", "a1", counts(10, 1, 1000))], at(0));
+        sample(
+            &mut window,
+            &[
+                ("alice", "a1", counts(30, 1, 1500)),
+                ("bob", "b1", counts(5, 2, 0)),
+            ],
+            at(30),
+        );
+        assert_eq!(window.room(), counts(35, 3, 1500));
+        assert_eq!(window.users()["alice"], counts(30, 1, 1500));
+        assert_eq!(window.users()["bob"], counts(5, 2, 0));
+
+        // The first sample fell out of the window, and a stream closed and replaced by
+        // another doesn't take away from what was counted
+        sample(
+            &mut window,
+            &[
+                ("alice", "a2", counts(4, 0, 0)),
+                ("bob", "b1", counts(5, 2, 0)),
+            ],
+            at(65),
+        );
+        assert_eq!(window.room(), counts(29, 2, 500));
+        assert_eq!(window.users()["alice"], counts(24, 0, 500));
+    }
+
+    #[test]
+    fn alerts_wait_for_the_room_to_recover() {
+        let settings = RetransmissionSettings {
+            alerts: true,
+            max_nacks_per_minute: 100,
+            ..RetransmissionSettings::default()
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut window = RetransmissionWindow::default();
+
+        sample(&mut window, &[("alice", "a1", counts(50, 0, 0))], at(0));
+        assert_eq!(window.alert(&settings), None);
+
+        sample(&mut window, &[("alice", "a1", counts(200, 0, 0))], at(15));
+        let alert = window.alert(&settings).unwrap();
+        assert_eq!(alert.counts, counts(200, 0, 0));
+        assert_eq!(alert.exceeded, vec![AlertCounter::Nacks]);
+
+        sample(&mut window, &[("alice", "a1", counts(300, 0, 0))], at(30));
+        assert_eq!(window.alert(&settings), None);
+
+        // Under the threshold once the minute moved past the burst, then over it again
+        sample(&mut window, &[("alice", "a1", counts(300, 0, 0))], at(120));
+        assert_eq!(window.alert(&settings), None);
+        sample(&mut window, &[("alice", "a1", counts(500, 0, 0))], at(135));
+        assert!(window.alert(&settings).is_some());
+    }
+}
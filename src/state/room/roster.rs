@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+/// Most users a roster remembers changes of, departed ones included. Past it the older
+/// half is forgotten and deltas from before then list everyone again
+const MAX_ROSTER_ENTRIES: usize = 4096;
+
+/// Revisions of what `RoomInfo` lists, so clients that listed the room's users once can
+/// ask what changed since with `RoomInfoDelta` rather than list them all again. Only
+/// users on this node are tracked
+#[derive(Default)]
+pub struct Roster {
+    revision: u64,
+    /// Revision each user last joined, left or changed at
+    changed: HashMap<String, u64>,
+    /// Changes up to this revision were forgotten
+    floor: u64,
+}
+
+impl Roster {
+    /// Latest revision, 0 before the first change
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Note a change to what's listed of the user
+    pub fn touch(&mut self, user_id: &str) {
+        self.revision += 1;
+        self.changed.insert(user_id.to_string(), self.revision);
+        if self.changed.len() <= MAX_ROSTER_ENTRIES {
+            return;
+        }
+
+        let mut revisions: Vec<u64> = self.changed.values().copied().collect();
+        revisions.sort_unstable();
+        let floor = revisions[revisions.len() / 2];
+        self.changed.retain(|_, revision| *revision > floor);
+        self.floor = floor;
+    }
+
+    /// Users changed after the revision, `None` if changes from then were forgotten or
+    /// it's a revision yet to come
+    pub fn changed_since(&self, since: u64) -> Option<Vec<String>> {
+        if since < self.floor || since > self.revision {
+            return None;
+        }
+        let mut changed: Vec<String> = self
+            .changed
+            .iter()
+            .filter(|(_, revision)| **revision > since)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+        changed.sort();
+        Some(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_listed_until_forgotten() {
+        let mut roster = Roster::default();
+        assert_eq!(roster.changed_since(0), Some(Vec::new()));
+
+        roster.touch("alice");
+        roster.touch("bob");
+        let seen = roster.revision();
+        roster.touch("alice");
+        assert_eq!(roster.changed_since(seen), Some(vec!["alice".to_string()]));
+        assert_eq!(roster.changed_since(0).unwrap().len(), 2);
+        assert_eq!(roster.changed_since(roster.revision() + 1), None);
+
+        for i in 0..MAX_ROSTER_ENTRIES {
+            roster.touch(&format!("user{}", i));
+        }
+        assert_eq!(roster.changed_since(seen), None);
+        let latest = roster.revision();
+        roster.touch("bob");
+        assert_eq!(roster.changed_since(latest), Some(vec!["bob".to_string()]));
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use mediasoup::scalability_modes::ScalabilityMode;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::rtc::audio_level::{INTERVAL_MS, THRESHOLD_DB};
+use crate::rtc::bitrate::MIN_ENCODING_BITRATE;
+use crate::state::user::{present, ProduceType};
+use crate::util::config::{validate_codecs, CodecConfig, QualityProfile, CONFIG};
+
+/// Most feature flags a room may have
+pub const MAX_FEATURE_FLAGS: usize = 32;
+/// Longest name of a feature flag, in bytes
+pub const MAX_FEATURE_FLAG_LENGTH: usize = 64;
+/// Milliseconds a room may let microphones stay open without push to talk heartbeats
+pub const PTT_MAX_UNMUTE_MS: RangeInclusive<u64> = 1_000..=3_600_000;
+
+/// Per-room behaviour, provided when the room is created
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RoomSettings {
+    /// Maximum number of users, checked against the capacity used by visible,
+    /// hidden and pending users when a user is created
+    pub max_users: Option<usize>,
+    /// User the room was created for, such as the one starting an ad-hoc group call.
+    /// Owners may moderate the room whatever their role
+    pub owner: Option<String>,
+    /// What happens to the room when its owner leaves
+    pub owner_leave_policy: OwnerLeavePolicy,
+    /// Start the room locked, for calls scheduled ahead. Users joining wait in its lobby
+    /// without media until a moderator opens the room or lets them in, or its owner joins
+    pub lobby: bool,
+    /// Start the first video consumers of a connection unpaused at the lowest layer
+    pub fast_join: Option<FastJoinSettings>,
+    pub producer_limits: ProducerLimits,
+    /// Cap on the bitrate each participant may send in bits per second, overriding
+    /// the configured default
+    pub max_incoming_bitrate: Option<u32>,
+    /// Cap on the bitrate of the room's transports together in bits per second, in and
+    /// out, overriding the configured default
+    pub bitrate_budget: Option<u32>,
+    /// Package the room as HLS for viewers who don't join, requires HLS on the node
+    pub hls: Option<HlsSettings>,
+    /// Keep the room open while it's empty instead of closing it after the idle timeout
+    pub persistent: bool,
+    /// Close the room this many seconds after it was created, whoever is in it and even
+    /// if it's persistent. Counted again from a restart that restores the room
+    pub max_duration_secs: Option<u64>,
+    /// Echo test room, where users may consume their own producers and the audio they
+    /// produce is looped straight back to them, to check devices before a call
+    pub echo: bool,
+    /// Codecs the room's router offers, the configured `rtc.codecs` when absent
+    pub codecs: Option<Vec<CodecConfig>>,
+    /// Scalability modes producers may use, such as "L3T3" for three spatial and three
+    /// temporal SVC layers. Any mode mediasoup supports when absent
+    #[serde(deserialize_with = "scalability_modes")]
+    pub scalability_modes: Option<Vec<String>>,
+    pub opus_limits: OpusLimits,
+    pub bitrate_limits: BitrateLimits,
+    /// Name of a configured profile whose ceilings the room's media is held to, on top
+    /// of `bitrateLimits`
+    #[serde(deserialize_with = "quality_profile")]
+    pub quality_profile: Option<String>,
+    /// Whether the room may be recorded, turning it off stops a recording in progress
+    pub recording_allowed: bool,
+    /// Only record users once they consented, each is asked as recording starts or as
+    /// it joins while the room is being recorded
+    pub require_recording_consent: bool,
+    /// Volume in dBov from which a user counts as speaking, the configured
+    /// `audio_levels.threshold_db` when absent
+    #[serde(deserialize_with = "audio_level_threshold")]
+    pub audio_level_threshold_db: Option<i8>,
+    /// Milliseconds volumes are averaged over before speakers are announced, the
+    /// configured `audio_levels.interval_ms` when absent
+    #[serde(deserialize_with = "audio_level_interval")]
+    pub audio_level_interval_ms: Option<u16>,
+    /// Named switches for clients to turn features on or off in this room only, told to
+    /// every user as it authenticates and again whenever they change
+    #[serde(deserialize_with = "feature_flags")]
+    pub feature_flags: HashMap<String, bool>,
+    /// Tell the room about users doing nothing for a while, and optionally disconnect
+    /// them. Users are never idle when absent
+    pub idle: Option<IdleSettings>,
+    /// How the NACKs, PLIs and retransmissions of the room's media are sampled, and
+    /// when moderators are alerted about them
+    pub retransmissions: RetransmissionSettings,
+    /// Enforce push to talk, pausing a user's audio producer this many milliseconds after
+    /// it was resumed unless the client keeps sending `PttHeartbeat`, see `ws::ptt`
+    #[serde(deserialize_with = "ptt_max_unmute")]
+    pub ptt_max_unmute_ms: Option<u64>,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            max_users: None,
+            owner: None,
+            owner_leave_policy: OwnerLeavePolicy::None,
+            lobby: false,
+            fast_join: None,
+            producer_limits: ProducerLimits::default(),
+            max_incoming_bitrate: None,
+            bitrate_budget: None,
+            hls: None,
+            persistent: false,
+            max_duration_secs: None,
+            echo: false,
+            codecs: None,
+            scalability_modes: None,
+            opus_limits: OpusLimits::default(),
+            bitrate_limits: BitrateLimits::default(),
+            quality_profile: None,
+            recording_allowed: true,
+            require_recording_consent: false,
+            audio_level_threshold_db: None,
+            audio_level_interval_ms: None,
+            feature_flags: HashMap::new(),
+            idle: None,
+            retransmissions: RetransmissionSettings::default(),
+            ptt_max_unmute_ms: None,
+        }
+    }
+}
+
+impl RoomSettings {
+    /// Settings of a room created from the template, with the fields of `overrides`
+    /// replacing the template's whole
+    pub fn from_template(
+        template: &Map<String, Value>,
+        overrides: Map<String, Value>,
+    ) -> Result<Self, serde_json::Error> {
+        let mut fields = template.clone();
+        fields.extend(overrides);
+        serde_json::from_value(Value::Object(fields))
+    }
+
+    /// Check a configured template while the configuration is loaded, so its quality
+    /// profile is looked up in the `profiles` configured alongside it rather than
+    /// `CONFIG`. Unlike creation requests, unknown fields are refused to catch typos
+    pub fn check_template(
+        template: &Map<String, Value>,
+        profiles: &HashMap<String, QualityProfile>,
+    ) -> Result<(), String> {
+        let known = serde_json::to_value(RoomSettings::default()).map_err(|err| err.to_string())?;
+        if let Some(field) = template
+            .keys()
+            .find(|field| known.get(field.as_str()).is_none())
+        {
+            return Err(format!("unknown field {}", field));
+        }
+
+        let mut fields = template.clone();
+        match fields.remove("qualityProfile") {
+            None | Some(Value::Null) => {}
+            Some(Value::String(name)) if profiles.contains_key(&name) => {}
+            Some(profile) => {
+                return Err(format!(
+                    "qualityProfile {} is not a configured quality profile",
+                    profile
+                ))
+            }
+        }
+
+        let settings: RoomSettings =
+            serde_json::from_value(Value::Object(fields)).map_err(|err| err.to_string())?;
+        if let Some(codecs) = &settings.codecs {
+            validate_codecs(codecs).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// What happens to a room when its owner leaves
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerLeavePolicy {
+    /// The room carries on, the owner keeps it should it come back
+    None,
+    /// The room is deleted
+    CloseOnOwnerLeave,
+    /// The visible user present the longest becomes the owner
+    Transfer,
+}
+
+impl Default for OwnerLeavePolicy {
+    fn default() -> Self {
+        OwnerLeavePolicy::None
+    }
+}
+
+/// Settings that can be changed while the room is live, absent fields are left as they are
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RoomSettingsUpdate {
+    /// 0 removes the cap
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_incoming_bitrate: Option<u32>,
+    /// `null` stops HLS packaging, settings start it or change what is packaged
+    #[serde(deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub hls: Option<Option<HlsSettings>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persistent: Option<bool>,
+    /// `null` removes the cap. Lowering it below the users already in the room
+    /// only keeps new ones out
+    #[serde(
+        deserialize_with = "max_users",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_users: Option<Option<usize>>,
+    /// Disallowing video closes the camera producers open in the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_allowed: Option<bool>,
+    /// Changing either replaces the room's audio level observer
+    #[serde(
+        deserialize_with = "audio_level_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub audio_level_threshold_db: Option<i8>,
+    #[serde(
+        deserialize_with = "audio_level_interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub audio_level_interval_ms: Option<u16>,
+    /// `null` lifts the room's profile. Producers already open keep their encodings, the
+    /// clients are told the new ceilings to apply them
+    #[serde(
+        deserialize_with = "quality_profile_update",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub quality_profile: Option<Option<String>>,
+    /// Replaces the room's flags whole, `{}` clears them
+    #[serde(
+        deserialize_with = "feature_flags_update",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub feature_flags: Option<HashMap<String, bool>>,
+}
+
+fn max_users<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<usize>>, D::Error> {
+    let max_users = Option::<usize>::deserialize(deserializer)?;
+    if max_users == Some(0) {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(0),
+            &"a user cap of at least 1",
+        ));
+    }
+
+    Ok(Some(max_users))
+}
+
+fn scalability_modes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    let modes = Option::<Vec<String>>::deserialize(deserializer)?;
+    for mode in modes.iter().flatten() {
+        if mode.parse::<ScalabilityMode>().is_err() {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Str(mode),
+                &"a scalability mode such as L3T3 or S2T1",
+            ));
+        }
+    }
+
+    Ok(modes)
+}
+
+fn quality_profile<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let name = Option::<String>::deserialize(deserializer)?;
+    if let Some(name) = &name {
+        if !CONFIG.rooms.profiles.contains_key(name) {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Str(name),
+                &"the name of a configured quality profile",
+            ));
+        }
+    }
+
+    Ok(name)
+}
+
+fn quality_profile_update<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<String>>, D::Error> {
+    quality_profile(deserializer).map(Some)
+}
+
+fn audio_level_threshold<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i8>, D::Error> {
+    let threshold = Option::<i8>::deserialize(deserializer)?;
+    match threshold {
+        Some(threshold) if !THRESHOLD_DB.contains(&threshold) => Err(de::Error::invalid_value(
+            de::Unexpected::Signed(i64::from(threshold)),
+            &"a threshold from -127 to 0 dB",
+        )),
+        _ => Ok(threshold),
+    }
+}
+
+fn audio_level_interval<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u16>, D::Error> {
+    let interval = Option::<u16>::deserialize(deserializer)?;
+    match interval {
+        Some(interval) if !INTERVAL_MS.contains(&interval) => Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(u64::from(interval)),
+            &"an interval from 100 to 10000 milliseconds",
+        )),
+        _ => Ok(interval),
+    }
+}
+
+fn ptt_max_unmute<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let max_unmute = Option::<u64>::deserialize(deserializer)?;
+    match max_unmute {
+        Some(max_unmute) if !PTT_MAX_UNMUTE_MS.contains(&max_unmute) => {
+            Err(de::Error::invalid_value(
+                de::Unexpected::Unsigned(max_unmute),
+                &"a delay from 1000 to 3600000 milliseconds",
+            ))
+        }
+        _ => Ok(max_unmute),
+    }
+}
+
+fn feature_flags<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, bool>, D::Error> {
+    let flags = HashMap::<String, bool>::deserialize(deserializer)?;
+    if flags.len() > MAX_FEATURE_FLAGS {
+        return Err(de::Error::invalid_length(
+            flags.len(),
+            &"at most 32 feature flags",
+        ));
+    }
+    if let Some(name) = flags
+        .keys()
+        .find(|name| name.is_empty() || name.len() > MAX_FEATURE_FLAG_LENGTH)
+    {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Str(name),
+            &"a feature flag name of 1 to 64 bytes",
+        ));
+    }
+
+    Ok(flags)
+}
+
+fn feature_flags_update<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<HashMap<String, bool>>, D::Error> {
+    feature_flags(deserializer).map(Some)
+}
+
+/// What the HLS playlist carries. Audio of every visible user is mixed, so whoever
+/// speaks is heard, along with a single video
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HlsSettings {
+    /// User whose screenshare, or else camera, is shown. Without one the first
+    /// screenshare or camera found is
+    pub video_user: Option<String>,
+}
+
+/// Most producers a single connection may have open at once, by type. A user holds
+/// one producer of each type, so a limit is either 0 to disallow the type or 1
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProducerLimits {
+    #[serde(deserialize_with = "producer_limit")]
+    pub audio: usize,
+    #[serde(deserialize_with = "producer_limit")]
+    pub video: usize,
+    /// Applies to screenshare audio and screenshare video separately
+    #[serde(deserialize_with = "producer_limit")]
+    pub screenshare: usize,
+}
+
+fn producer_limit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let limit = usize::deserialize(deserializer)?;
+    if limit > 1 {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(limit as u64),
+            &"a producer limit of 0 or 1",
+        ));
+    }
+
+    Ok(limit)
+}
+
+impl ProducerLimits {
+    pub fn get(&self, produce_type: ProduceType) -> usize {
+        match produce_type {
+            ProduceType::Audio => self.audio,
+            ProduceType::Video => self.video,
+            ProduceType::ScreenshareAudio | ProduceType::ScreenshareVideo => self.screenshare,
+        }
+    }
+}
+
+impl Default for ProducerLimits {
+    fn default() -> Self {
+        ProducerLimits {
+            audio: 1,
+            video: 1,
+            screenshare: 1,
+        }
+    }
+}
+
+/// Caps on the Opus options audio producers may ask for, requests past them are
+/// clamped rather than refused. Large voice rooms can save bandwidth this way
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpusLimits {
+    pub stereo: bool,
+    /// Highest `maxaveragebitrate`, also applied to producers that don't ask for one
+    pub max_average_bitrate: Option<u32>,
+}
+
+impl Default for OpusLimits {
+    fn default() -> Self {
+        OpusLimits {
+            stereo: true,
+            max_average_bitrate: None,
+        }
+    }
+}
+
+/// Most a single producer may send in bits per second, by kind. Producers are created
+/// with their encodings clamped under the cap, or refused if they can't be
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BitrateLimits {
+    #[serde(deserialize_with = "bitrate_cap")]
+    pub audio: Option<u32>,
+    #[serde(deserialize_with = "bitrate_cap")]
+    pub video: Option<u32>,
+    /// Applies to screenshare audio and screenshare video separately
+    #[serde(deserialize_with = "bitrate_cap")]
+    pub screenshare: Option<u32>,
+}
+
+fn bitrate_cap<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let cap = Option::<u32>::deserialize(deserializer)?;
+    match cap {
+        Some(cap) if cap < MIN_ENCODING_BITRATE => Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(u64::from(cap)),
+            &"a bitrate cap of at least 30000 bits per second",
+        )),
+        _ => Ok(cap),
+    }
+}
+
+impl BitrateLimits {
+    pub fn get(&self, produce_type: ProduceType) -> Option<u32> {
+        match produce_type {
+            ProduceType::Audio => self.audio,
+            ProduceType::Video => self.video,
+            ProduceType::ScreenshareAudio | ProduceType::ScreenshareVideo => self.screenshare,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FastJoinSettings {
+    /// Number of video consumers per connection created in fast join mode
+    pub consumers: usize,
+    /// Minimum time between keyframe requests to the same producer, in milliseconds
+    pub keyframe_interval: u64,
+    /// Longest wait for the bandwidth estimate to settle before promoting to higher layers, in milliseconds
+    pub max_promote_delay: u64,
+}
+
+impl Default for FastJoinSettings {
+    fn default() -> Self {
+        FastJoinSettings {
+            consumers: 4,
+            keyframe_interval: 1000,
+            max_promote_delay: 5000,
+        }
+    }
+}
+
+/// When users count as idle, see `ws::idle`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct IdleSettings {
+    /// Seconds without activity before the room is told the user is idle, 0 never tells it
+    pub idle_after_secs: u64,
+    /// Seconds without activity before the user is disconnected with `IdleDisconnect`,
+    /// 0 never disconnects it
+    pub disconnect_after_secs: u64,
+    /// Seconds ahead of the disconnect the client is sent `IdleWarning`
+    pub warning_secs: u64,
+}
+
+impl Default for IdleSettings {
+    fn default() -> Self {
+        IdleSettings {
+            idle_after_secs: 300,
+            disconnect_after_secs: 0,
+            warning_secs: 60,
+        }
+    }
+}
+
+impl IdleSettings {
+    pub fn idle_after(&self) -> Option<Duration> {
+        match self.idle_after_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn disconnect_after(&self) -> Option<Duration> {
+        match self.disconnect_after_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn warning(&self) -> Duration {
+        Duration::from_secs(self.warning_secs)
+    }
+}
+
+/// Shortest interval the room's retransmissions are sampled at, each sample asks the
+/// worker for the stats of every producer and consumer in the room
+const MIN_RETRANSMISSION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When the room's retransmissions are sampled and alerted on, see `retransmissions`.
+/// Thresholds are summed over the room's users for the last minute
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetransmissionSettings {
+    /// Seconds between samples, at least 5. 0 never samples the room
+    pub sample_interval_secs: u64,
+    /// Send moderators `QualityAlert` when a threshold is crossed
+    pub alerts: bool,
+    pub max_nacks_per_minute: u64,
+    pub max_plis_per_minute: u64,
+    pub max_retransmitted_bytes_per_minute: u64,
+}
+
+impl Default for RetransmissionSettings {
+    fn default() -> Self {
+        RetransmissionSettings {
+            sample_interval_secs: 15,
+            alerts: false,
+            max_nacks_per_minute: 6000,
+            max_plis_per_minute: 120,
+            max_retransmitted_bytes_per_minute: 10_000_000,
+        }
+    }
+}
+
+impl RetransmissionSettings {
+    pub fn sample_interval(&self) -> Option<Duration> {
+        match self.sample_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs).max(MIN_RETRANSMISSION_INTERVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn producer_limits_above_one_are_rejected() {
+        let settings: RoomSettings =
+            serde_json::from_str(r#"{ "producerLimits": { "video": 0 } }"#).unwrap();
+        assert_eq!(settings.producer_limits.get(ProduceType::Audio), 1);
+        assert_eq!(settings.producer_limits.get(ProduceType::Video), 0);
+
+        let error = serde_json::from_str::<RoomSettings>(r#"{ "producerLimits": { "audio": 2 } }"#)
+            .unwrap_err();
+        assert!(error.to_string().contains("a producer limit of 0 or 1"));
+    }
+
+    #[test]
+    fn bitrate_caps_are_by_kind() {
+        let settings: RoomSettings =
+            serde_json::from_str(r#"{ "bitrateLimits": { "video": 1500000 } }"#).unwrap();
+        let limits = settings.bitrate_limits;
+        assert_eq!(limits.get(ProduceType::Video), Some(1_500_000));
+        assert_eq!(limits.get(ProduceType::ScreenshareVideo), None);
+
+        let error =
+            serde_json::from_str::<RoomSettings>(r#"{ "bitrateLimits": { "audio": 8000 } }"#)
+                .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("a bitrate cap of at least 30000"));
+    }
+
+    #[test]
+    fn scalability_modes_are_validated() {
+        let settings: RoomSettings =
+            serde_json::from_str(r#"{ "scalabilityModes": ["L1T3", "L3T3_KEY"] }"#).unwrap();
+        assert_eq!(settings.scalability_modes.unwrap().len(), 2);
+
+        let error = serde_json::from_str::<RoomSettings>(r#"{ "scalabilityModes": ["L9T9"] }"#)
+            .unwrap_err();
+        assert!(error.to_string().contains("a scalability mode"));
+    }
+
+    #[test]
+    fn quality_profiles_must_be_configured() {
+        crate::util::testing::init();
+        let settings: RoomSettings =
+            serde_json::from_str(r#"{ "qualityProfile": "low" }"#).unwrap();
+        assert_eq!(settings.quality_profile.as_deref(), Some("low"));
+
+        let error =
+            serde_json::from_str::<RoomSettings>(r#"{ "qualityProfile": "ultra" }"#).unwrap_err();
+        assert!(error.to_string().contains("a configured quality profile"));
+
+        let update: RoomSettingsUpdate =
+            serde_json::from_str(r#"{ "qualityProfile": null }"#).unwrap();
+        assert_eq!(update.quality_profile, Some(None));
+    }
+
+    #[test]
+    fn templates_are_checked_and_overridden() {
+        let template: Map<String, Value> =
+            serde_json::from_str(r#"{ "maxUsers": 8, "lobby": true, "qualityProfile": "low" }"#)
+                .unwrap();
+        let mut profiles = HashMap::new();
+        profiles.insert("low".to_string(), QualityProfile::default());
+        RoomSettings::check_template(&template, &profiles).unwrap();
+
+        let error = RoomSettings::check_template(&template, &HashMap::new()).unwrap_err();
+        assert!(error.contains("not a configured quality profile"));
+
+        let typo: Map<String, Value> = serde_json::from_str(r#"{ "maxUser": 8 }"#).unwrap();
+        let error = RoomSettings::check_template(&typo, &profiles).unwrap_err();
+        assert_eq!(error, "unknown field maxUser");
+
+        let invalid: Map<String, Value> =
+            serde_json::from_str(r#"{ "scalabilityModes": ["L9T9"] }"#).unwrap();
+        let error = RoomSettings::check_template(&invalid, &profiles).unwrap_err();
+        assert!(error.contains("a scalability mode"));
+
+        // Overrides replace the template's fields, the others are kept
+        crate::util::testing::init();
+        let overrides =
+            serde_json::from_str(r#"{ "maxUsers": 4, "qualityProfile": null }"#).unwrap();
+        let settings = RoomSettings::from_template(&template, overrides).unwrap();
+        assert_eq!(settings.max_users, Some(4));
+        assert!(settings.lobby);
+        assert_eq!(settings.quality_profile, None);
+    }
+
+    #[test]
+    fn hls_update_tells_null_from_missing() {
+        let update: RoomSettingsUpdate = serde_json::from_str("{}").unwrap();
+        assert!(update.hls.is_none());
+
+        let update: RoomSettingsUpdate = serde_json::from_str(r#"{ "hls": null }"#).unwrap();
+        assert!(matches!(update.hls, Some(None)));
+
+        let update: RoomSettingsUpdate =
+            serde_json::from_str(r#"{ "hls": { "videoUser": "host" } }"#).unwrap();
+        let settings = update.hls.flatten().unwrap();
+        assert_eq!(settings.video_user.as_deref(), Some("host"));
+    }
+
+    #[test]
+    fn max_users_update_is_validated() {
+        let update: RoomSettingsUpdate = serde_json::from_str(r#"{ "maxUsers": null }"#).unwrap();
+        assert_eq!(update.max_users, Some(None));
+
+        let update: RoomSettingsUpdate = serde_json::from_str(r#"{ "maxUsers": 2 }"#).unwrap();
+        assert_eq!(update.max_users, Some(Some(2)));
+
+        let error = serde_json::from_str::<RoomSettingsUpdate>(r#"{ "maxUsers": 0 }"#).unwrap_err();
+        assert!(error.to_string().contains("a user cap of at least 1"));
+    }
+
+    #[test]
+    fn audio_levels_out_of_range_are_rejected() {
+        let settings: RoomSettings = serde_json::from_str(
+            r#"{ "audioLevelThresholdDb": -40, "audioLevelIntervalMs": 300 }"#,
+        )
+        .unwrap();
+        assert_eq!(settings.audio_level_threshold_db, Some(-40));
+        assert_eq!(settings.audio_level_interval_ms, Some(300));
+
+        let error =
+            serde_json::from_str::<RoomSettings>(r#"{ "audioLevelThresholdDb": 10 }"#).unwrap_err();
+        assert!(error.to_string().contains("a threshold from -127 to 0 dB"));
+
+        let error = serde_json::from_str::<RoomSettingsUpdate>(r#"{ "audioLevelIntervalMs": 50 }"#)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("an interval from 100 to 10000 milliseconds"));
+    }
+
+    #[test]
+    fn push_to_talk_delay_is_bounded() {
+        let settings: RoomSettings = serde_json::from_str(r#"{ "pttMaxUnmuteMs": 5000 }"#).unwrap();
+        assert_eq!(settings.ptt_max_unmute_ms, Some(5000));
+
+        let error =
+            serde_json::from_str::<RoomSettings>(r#"{ "pttMaxUnmuteMs": 100 }"#).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("a delay from 1000 to 3600000 milliseconds"));
+    }
+
+    #[test]
+    fn feature_flags_are_capped() {
+        let update: RoomSettingsUpdate =
+            serde_json::from_str(r#"{ "featureFlags": { "reactions": true } }"#).unwrap();
+        let flags = update.feature_flags.unwrap();
+        assert_eq!(flags.get("reactions"), Some(&true));
+
+        let name = "x".repeat(MAX_FEATURE_FLAG_LENGTH + 1);
+        let error =
+            serde_json::from_value::<RoomSettings>(json!({ "featureFlags": { name: true } }))
+                .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("a feature flag name of 1 to 64 bytes"));
+
+        let flags: Map<String, Value> = (0..=MAX_FEATURE_FLAGS)
+            .map(|i| (format!("flag{}", i), Value::Bool(true)))
+            .collect();
+        let error = serde_json::from_value::<RoomSettingsUpdate>(json!({ "featureFlags": flags }))
+            .unwrap_err();
+        assert!(error.to_string().contains("at most 32 feature flags"));
+    }
+}
@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+use mediasoup::worker::WorkerId;
+use serde::Serialize;
+
+use super::{RetransmissionCounts, ThrottleState};
+use crate::rtc::Bitrates;
+
+/// Counts and bitrates of a room for operator dashboards
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomStats {
+    /// Worker hosting the room's router
+    pub worker_id: WorkerId,
+    pub user_count: usize,
+    /// Every user, hidden ones included
+    pub users: HashMap<String, UserStats>,
+    /// Summed over the transports of every connection
+    pub bitrates: Bitrates,
+    /// Over the last minute, summed over every user. Zero if the room isn't sampled, see
+    /// `RoomSettings::retransmissions`
+    pub retransmissions: RetransmissionCounts,
+    /// How far the room is throttled to keep `bitrates` within its budget, absent if
+    /// it has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleState>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UserStats {
+    pub producers: usize,
+    /// 0 until the user's connection initialized its transports
+    pub consumers: usize,
+    /// Of the user's producers and consumers over the last minute
+    pub retransmissions: RetransmissionCounts,
+}
@@ -0,0 +1,160 @@
+use mediasoup::producer::ProducerId;
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use super::{Room, RoomEvent};
+use crate::rtc::stats::ConnectionStats;
+use crate::state::user::ProduceType;
+use crate::util::metrics::ROOM_SUBSCRIBERS;
+use crate::ws::{
+    error::WSCloseType,
+    types::{MediaClosedReason, SignalingTransport},
+};
+
+/// Text a broadcast event is sent to connections as. The first connection sending it
+/// serializes it, the others reuse the text rather than serializing it again
+pub type SharedText = Arc<OnceCell<Arc<str>>>;
+
+/// Room event as broadcast to every subscriber
+#[derive(Clone, Debug)]
+pub struct BroadcastEvent {
+    pub event: RoomEvent,
+    pub text: SharedText,
+}
+
+impl From<RoomEvent> for BroadcastEvent {
+    fn from(event: RoomEvent) -> Self {
+        BroadcastEvent {
+            event,
+            text: SharedText::default(),
+        }
+    }
+}
+
+/// Signals queued on the control channel of a single subscriber
+#[derive(Debug)]
+pub enum SubscriberSignal {
+    /// Event delivered to this subscriber only
+    Event(RoomEvent),
+    /// Apply a new cap on the bitrate the connection may send, 0 for none
+    MaxIncomingBitrate(u32),
+    /// Producers of the subscriber's user were closed by someone else, e.g. when a
+    /// moderator demoted it to listener
+    ProducersClosed(Vec<(ProduceType, ProducerId)>, MediaClosedReason),
+    /// The room moved to a new router, the connection's transports went with the old one
+    MediaRestart,
+    /// Gather the connection's stats for the management API, dropped if it has no
+    /// transports to gather them from
+    Stats(oneshot::Sender<ConnectionStats>),
+    /// Disconnect the subscriber's connection
+    Close(WSCloseType),
+}
+
+/// What the connection negotiated when it joined the room
+#[derive(Clone, Copy)]
+pub struct SubscriberOptions {
+    pub signaling: SignalingTransport,
+}
+
+/// Room-side view of a live subscriber
+#[derive(Clone)]
+pub struct SubscriberInfo {
+    pub connection_id: u64,
+    pub user_id: String,
+    pub options: SubscriberOptions,
+}
+
+pub(super) struct SubscriberHandle {
+    pub(super) info: SubscriberInfo,
+    pub(super) control: mpsc::Sender<SubscriberSignal>,
+}
+
+pub enum SubscriberMessage {
+    Event(RoomEvent),
+    /// The subscriber fell behind and this many room events were dropped
+    Lagged(u64),
+    MaxIncomingBitrate(u32),
+    ProducersClosed(Vec<(ProduceType, ProducerId)>, MediaClosedReason),
+    MediaRestart,
+    Stats(oneshot::Sender<ConnectionStats>),
+    Close(WSCloseType),
+}
+
+/// Registered receiver of room events, deregistered from the room when dropped
+pub struct RoomSubscriber {
+    room: Arc<Room>,
+    info: SubscriberInfo,
+    events: broadcast::Receiver<BroadcastEvent>,
+    control: mpsc::Receiver<SubscriberSignal>,
+    /// Text of the broadcast event received last, see `shared_text`
+    shared: Option<SharedText>,
+}
+
+impl RoomSubscriber {
+    pub(super) fn new(
+        room: Arc<Room>,
+        info: SubscriberInfo,
+        events: broadcast::Receiver<BroadcastEvent>,
+        control: mpsc::Receiver<SubscriberSignal>,
+    ) -> Self {
+        ROOM_SUBSCRIBERS.inc();
+        RoomSubscriber {
+            room,
+            info,
+            events,
+            control,
+            shared: None,
+        }
+    }
+
+    pub fn info(&self) -> &SubscriberInfo {
+        &self.info
+    }
+
+    /// Text shared with the room's other subscribers for the event `recv` returned last,
+    /// `None` once taken or if the event was sent to this subscriber alone
+    pub fn shared_text(&mut self) -> Option<SharedText> {
+        self.shared.take()
+    }
+
+    /// Wait for the next room event or signal, signals take precedence
+    pub async fn recv(&mut self) -> SubscriberMessage {
+        self.shared = None;
+        tokio::select! {
+            biased;
+
+            signal = self.control.recv() => match signal {
+                Some(SubscriberSignal::Event(event)) => SubscriberMessage::Event(event),
+                Some(SubscriberSignal::MaxIncomingBitrate(bitrate)) => {
+                    SubscriberMessage::MaxIncomingBitrate(bitrate)
+                }
+                Some(SubscriberSignal::ProducersClosed(closed, reason)) => {
+                    SubscriberMessage::ProducersClosed(closed, reason)
+                }
+                Some(SubscriberSignal::MediaRestart) => SubscriberMessage::MediaRestart,
+                Some(SubscriberSignal::Stats(reply)) => SubscriberMessage::Stats(reply),
+                Some(SubscriberSignal::Close(reason)) => SubscriberMessage::Close(reason),
+                // The room holds the sender for as long as the subscriber is registered
+                None => SubscriberMessage::Close(WSCloseType::RoomClosed),
+            },
+            event = self.events.recv() => match event {
+                Ok(BroadcastEvent { event, text }) => {
+                    self.shared = Some(text);
+                    SubscriberMessage::Event(event)
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => SubscriberMessage::Lagged(count),
+                Err(broadcast::error::RecvError::Closed) => {
+                    SubscriberMessage::Close(WSCloseType::RoomClosed)
+                }
+            },
+        }
+    }
+}
+
+impl Drop for RoomSubscriber {
+    fn drop(&mut self) {
+        self.room.unsubscribe(self.info.connection_id);
+        ROOM_SUBSCRIBERS.dec();
+    }
+}
@@ -0,0 +1,142 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/// How often a room with a bitrate budget is checked against it
+pub const THROTTLE_INTERVAL: Duration = Duration::from_secs(2);
+/// Level at which video is held to its lowest layer and producers to a share of the
+/// budget, as far as a room is throttled
+pub const MAX_THROTTLE_LEVEL: u8 = 3;
+/// Share of the budget a throttled room has to stay under to ease off a level
+const EASE_BELOW: f64 = 0.7;
+/// Samples in a row under that share before it does, one level at a time, so that
+/// media coming back doesn't push the room straight over its budget again
+const EASE_SAMPLES: u32 = 3;
+
+/// How a room is held to its budget, for its stats
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleState {
+    /// Bits per second the room's transports may carry together, in and out
+    pub budget: u32,
+    /// 0 while the room is under budget, up to `MAX_THROTTLE_LEVEL`
+    pub level: u8,
+}
+
+/// Level a room is throttled to, raised a step each sample it's over its budget and
+/// lowered a step after it's been well under it for a while
+pub struct Throttle {
+    budget: u32,
+    level: u8,
+    /// Samples in a row under `EASE_BELOW` of the budget
+    calm: u32,
+}
+
+impl Throttle {
+    pub fn new(budget: u32) -> Self {
+        Throttle {
+            budget,
+            level: 0,
+            calm: 0,
+        }
+    }
+
+    pub fn state(&self) -> ThrottleState {
+        ThrottleState {
+            budget: self.budget,
+            level: self.level,
+        }
+    }
+
+    /// Account for the bitrate the room's transports carry, giving the new level if it
+    /// changed
+    pub fn sample(&mut self, bitrate: u64) -> Option<u8> {
+        if bitrate > u64::from(self.budget) {
+            self.calm = 0;
+            if self.level < MAX_THROTTLE_LEVEL {
+                self.level += 1;
+                return Some(self.level);
+            }
+            return None;
+        }
+
+        if (bitrate as f64) >= f64::from(self.budget) * EASE_BELOW {
+            self.calm = 0;
+            return None;
+        }
+        self.calm += 1;
+        if self.level > 0 && self.calm >= EASE_SAMPLES {
+            self.calm = 0;
+            self.level -= 1;
+            return Some(self.level);
+        }
+        None
+    }
+
+    /// Spatial layer video consumers are held to at the level, lower ones at each step
+    pub fn max_spatial_layer(level: u8) -> Option<u8> {
+        match level {
+            0 => None,
+            1 => Some(1),
+            _ => Some(0),
+        }
+    }
+
+    /// Cap on the bitrate each of `connections` may send at the current level, an even
+    /// share of the budget once the room is throttled all the way
+    pub fn max_incoming_bitrate(&self, connections: usize) -> Option<u32> {
+        if self.level < MAX_THROTTLE_LEVEL {
+            return None;
+        }
+        Some(self.budget / connections.max(1) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_step_up_over_budget_and_ease_off_gradually() {
+        let mut throttle = Throttle::new(1_000_000);
+        assert_eq!(throttle.sample(900_000), None);
+        assert_eq!(throttle.max_incoming_bitrate(4), None);
+
+        // One level a sample while over, no further than the last
+        assert_eq!(throttle.sample(1_500_000), Some(1));
+        assert_eq!(throttle.sample(1_200_000), Some(2));
+        assert_eq!(throttle.sample(1_100_000), Some(3));
+        assert_eq!(throttle.sample(1_100_000), None);
+        assert_eq!(throttle.max_incoming_bitrate(4), Some(250_000));
+
+        // Just under the budget isn't enough to ease off
+        for _ in 0..5 {
+            assert_eq!(throttle.sample(800_000), None);
+        }
+
+        // Well under it for a while is, one level at a time, and going over again
+        // starts the wait over
+        assert_eq!(throttle.sample(500_000), None);
+        assert_eq!(throttle.sample(500_000), None);
+        assert_eq!(throttle.sample(500_000), Some(2));
+        assert_eq!(throttle.sample(500_000), None);
+        assert_eq!(throttle.sample(1_100_000), Some(3));
+        for _ in 0..2 {
+            assert_eq!(throttle.sample(500_000), None);
+        }
+        assert_eq!(throttle.sample(500_000), Some(2));
+        assert_eq!(
+            throttle.state(),
+            ThrottleState {
+                budget: 1_000_000,
+                level: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn layers_drop_with_each_level() {
+        assert_eq!(Throttle::max_spatial_layer(0), None);
+        assert_eq!(Throttle::max_spatial_layer(1), Some(1));
+        assert_eq!(Throttle::max_spatial_layer(MAX_THROTTLE_LEVEL), Some(0));
+    }
+}
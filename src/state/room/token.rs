@@ -0,0 +1,199 @@
+//! Join tokens handed out over the management API. The token carries the room, user,
+//! expiry and session constraints in a payload signed by this node, its nonce is what the
+//! room keeps to make the token single use
+
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::fmt::{self, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tokens are only redeemed on the node that issued them, as that's where the room holds
+/// their nonce, so the key never has to leave the node. It's random unless the room store
+/// kept it from a previous run
+static SIGNING_KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+pub fn signing_key() -> &'static [u8; 32] {
+    SIGNING_KEY.get_or_init(|| {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    })
+}
+
+/// Sign with the key of a previous run, so its tokens stay valid. Only takes effect
+/// before the first token is issued or verified
+pub fn restore_signing_key(key: [u8; 32]) -> bool {
+    SIGNING_KEY.set(key).is_ok()
+}
+
+/// Limits a join token puts on the session it starts
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionConstraints {
+    /// Consume only, producing is refused
+    pub listen_only: bool,
+    /// Close the connection this many seconds after it authenticated
+    pub max_session_secs: Option<u64>,
+}
+
+/// Signed contents of a join token
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinClaims {
+    pub room: String,
+    pub sub: String,
+    /// UNIX time in seconds after which the token is refused
+    pub exp: u64,
+    /// Registration the token redeems, removed once it's used
+    pub nonce: String,
+    #[serde(flatten)]
+    pub constraints: SessionConstraints,
+}
+
+/// Join token along with the ID it's revoked by, which is its nonce
+#[derive(Debug)]
+pub struct JoinToken {
+    pub token: String,
+    pub id: String,
+}
+
+/// Why a token was refused, only logged as clients are told no more than `Unauthorized`
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    InvalidSignature,
+    /// ID of the room the token was issued for
+    WrongRoom(String),
+    Expired,
+    /// Already used, or its user was removed since
+    NotRegistered,
+    /// The token's user is banned from the room
+    Banned,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "Token is malformed"),
+            TokenError::InvalidSignature => write!(f, "Token signature doesn't match"),
+            TokenError::WrongRoom(room) => write!(f, "Token was issued for room {}", room),
+            TokenError::Expired => write!(f, "Token has expired"),
+            TokenError::NotRegistered => write!(f, "Token was already used or revoked"),
+            TokenError::Banned => write!(f, "Token's user is banned from the room"),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the UNIX epoch")
+        .as_secs()
+}
+
+fn sign(payload: &[u8]) -> Hmac<Sha1> {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(signing_key()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac
+}
+
+/// `payload.signature`, both base64url encoded
+pub fn issue(claims: &JoinClaims) -> String {
+    let payload = serde_json::to_vec(claims).expect("Join claims always serialize");
+    let signature = sign(&payload).finalize().into_bytes();
+    format!(
+        "{}.{}",
+        base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Claims of a token presented for `room_id`, if it was signed here and is still valid.
+/// Whether its nonce is still registered is up to the room
+pub fn verify(token: &str, room_id: &str, now: u64) -> Result<JoinClaims, TokenError> {
+    let (payload, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| TokenError::Malformed)?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| TokenError::Malformed)?;
+    sign(&payload)
+        .verify(&signature)
+        .map_err(|_| TokenError::InvalidSignature)?;
+
+    let claims: JoinClaims = serde_json::from_slice(&payload).map_err(|_| TokenError::Malformed)?;
+    if claims.room != room_id {
+        return Err(TokenError::WrongRoom(claims.room));
+    }
+
+    if claims.exp < now {
+        return Err(TokenError::Expired);
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp: u64) -> JoinClaims {
+        JoinClaims {
+            room: "room".to_string(),
+            sub: "alice".to_string(),
+            exp,
+            nonce: "nonce".to_string(),
+            constraints: SessionConstraints {
+                listen_only: true,
+                max_session_secs: Some(3600),
+            },
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let claims = claims(1_000);
+        let token = issue(&claims);
+        assert_eq!(verify(&token, "room", 1_000), Ok(claims));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_room_and_time() {
+        let token = issue(&claims(1_000));
+        assert_eq!(verify(&token, "room", 1_001), Err(TokenError::Expired));
+        assert_eq!(
+            verify(&token, "other", 1_000),
+            Err(TokenError::WrongRoom("room".to_string()))
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let token = issue(&claims(1_000));
+        let (_, signature) = token.split_once('.').unwrap();
+
+        // Same signature over a payload for another user
+        let forged = JoinClaims {
+            sub: "mallory".to_string(),
+            ..claims(1_000)
+        };
+        let forged = serde_json::to_vec(&forged).unwrap();
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config(&forged, base64::URL_SAFE_NO_PAD),
+            signature
+        );
+        assert_eq!(
+            verify(&forged, "room", 1_000),
+            Err(TokenError::InvalidSignature)
+        );
+
+        assert_eq!(
+            verify("opaque-token", "room", 1_000),
+            Err(TokenError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use crate::rtc::audio_level::SpeakerVolume;
+use crate::rtc::quality::QualityLevel;
+
+/// Compact summary of a room's lifetime, delivered when the room is deleted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSummary {
+    pub duration_secs: u64,
+    /// Most visible users connected at the same time
+    pub peak_users: usize,
+    /// Distinct visible users that connected over the room's lifetime
+    pub total_unique_users: usize,
+    pub recording_available: bool,
+}
+
+/// How a call went, from the first visible user joining until the room emptied or was
+/// deleted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallSummary {
+    pub duration_secs: u64,
+    /// Most visible users connected at the same time during the call
+    pub peak_users: usize,
+    /// Milliseconds each user was among the room's active speakers, by user ID
+    pub talk_time_ms: HashMap<String, u64>,
+    /// How each user spoke, by user ID
+    pub talk_stats: HashMap<String, TalkStats>,
+    pub kicks: u32,
+    pub bans: u32,
+    /// Mean of the quality sampled from the connections, from 0 when it was always bad
+    /// to 2 when it was always good. Absent when quality isn't sampled
+    pub average_quality: Option<f64>,
+}
+
+/// How much a user spoke during a call, counted in audio level intervals. Kept by user
+/// ID, so leaving and joining again adds to it
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TalkStats {
+    /// Milliseconds the user was among the room's active speakers
+    pub talk_time_ms: u64,
+    /// Longest stretch the user spoke without a pause, in milliseconds
+    pub longest_monologue_ms: u64,
+    /// Times the user started speaking while someone else was and kept on
+    pub interruptions: u32,
+}
+
+/// Counters of the call going on in a room, kept as it goes rather than pieced together
+/// at the end
+struct Call {
+    started_at: Instant,
+    peak_users: usize,
+    talk: HashMap<String, TalkStats>,
+    /// Users among the active speakers of the last interval, and for how long they've
+    /// been speaking. No more than the speakers reported per interval
+    speaking: Vec<(String, u64)>,
+    kicks: u32,
+    bans: u32,
+    /// Sum of the quality samples, 2 for each good one and 1 for each ok one
+    quality_total: u64,
+    quality_samples: u64,
+}
+
+impl Call {
+    fn new() -> Self {
+        Call {
+            started_at: Instant::now(),
+            peak_users: 0,
+            talk: HashMap::new(),
+            speaking: Vec::new(),
+            kicks: 0,
+            bans: 0,
+            quality_total: 0,
+            quality_samples: 0,
+        }
+    }
+
+    /// Count an interval of the speakers, ending the stretch of those who went quiet.
+    /// Costs as much whatever the number of users in the room
+    fn record_speakers(&mut self, speakers: &[SpeakerVolume], interval_ms: u64) {
+        let previous = std::mem::take(&mut self.speaking);
+        let was_speaking = |user_id: &str| {
+            previous
+                .iter()
+                .find(|(id, _)| id == user_id)
+                .map(|(_, stretch)| *stretch)
+        };
+        for speaker in speakers {
+            let user_id = &speaker.user_id;
+            let stretch = was_speaking(user_id);
+            let talk = self.talk.entry(user_id.clone()).or_default();
+            talk.talk_time_ms += interval_ms;
+            // Someone who spoke over the last interval still speaks
+            if stretch.is_none()
+                && speakers.iter().any(|other| {
+                    other.user_id != *user_id && was_speaking(&other.user_id).is_some()
+                })
+            {
+                talk.interruptions += 1;
+            }
+            let stretch = stretch.unwrap_or(0) + interval_ms;
+            talk.longest_monologue_ms = talk.longest_monologue_ms.max(stretch);
+            self.speaking.push((user_id.clone(), stretch));
+        }
+    }
+
+    fn summary(self) -> CallSummary {
+        let average_quality = match self.quality_samples {
+            0 => None,
+            samples => Some(self.quality_total as f64 / samples as f64),
+        };
+        CallSummary {
+            duration_secs: self.started_at.elapsed().as_secs(),
+            peak_users: self.peak_users,
+            talk_time_ms: self
+                .talk
+                .iter()
+                .map(|(user_id, talk)| (user_id.clone(), talk.talk_time_ms))
+                .collect(),
+            talk_stats: self.talk,
+            kicks: self.kicks,
+            bans: self.bans,
+            average_quality,
+        }
+    }
+}
+
+/// Usage counters of a room, hidden users are never counted
+pub struct Usage {
+    created_at: Instant,
+    counters: Mutex<UsageCounters>,
+}
+
+#[derive(Default)]
+struct UsageCounters {
+    peak_users: usize,
+    unique_users: HashSet<String>,
+    recorded: bool,
+    /// Started by the first visible user joining, ended as the room empties
+    call: Option<Call>,
+}
+
+impl Usage {
+    pub(super) fn new() -> Self {
+        Usage {
+            created_at: Instant::now(),
+            counters: Mutex::new(UsageCounters::default()),
+        }
+    }
+
+    /// Record a visible user connecting, `connected` being the visible user count including them
+    pub(in crate::state) fn record_join(&self, user_id: &str, connected: usize) {
+        let mut counters = self.counters.lock();
+        counters.peak_users = counters.peak_users.max(connected);
+        counters.unique_users.insert(user_id.to_string());
+        let call = counters.call.get_or_insert_with(Call::new);
+        call.peak_users = call.peak_users.max(connected);
+    }
+
+    /// Record the users speaking over an audio level interval of `interval_ms`
+    pub(super) fn record_speakers(&self, speakers: &[SpeakerVolume], interval_ms: u16) {
+        let mut counters = self.counters.lock();
+        if let Some(call) = counters.call.as_mut() {
+            call.record_speakers(speakers, u64::from(interval_ms));
+        }
+    }
+
+    /// How each user spoke so far in the call going on, by user ID
+    pub fn talk_stats(&self) -> HashMap<String, TalkStats> {
+        self.counters
+            .lock()
+            .call
+            .as_ref()
+            .map(|call| call.talk.clone())
+            .unwrap_or_default()
+    }
+
+    /// How the user spoke so far in the call going on, if it did
+    pub fn talk_stats_of(&self, user_id: &str) -> Option<TalkStats> {
+        let counters = self.counters.lock();
+        counters.call.as_ref()?.talk.get(user_id).cloned()
+    }
+
+    pub(super) fn record_kick(&self) {
+        if let Some(call) = self.counters.lock().call.as_mut() {
+            call.kicks += 1;
+        }
+    }
+
+    pub(super) fn record_ban(&self) {
+        if let Some(call) = self.counters.lock().call.as_mut() {
+            call.bans += 1;
+        }
+    }
+
+    /// Record the quality sampled from a visible user's connection
+    pub(in crate::state) fn record_quality(&self, level: QualityLevel) {
+        if let Some(call) = self.counters.lock().call.as_mut() {
+            call.quality_total += match level {
+                QualityLevel::Good => 2,
+                QualityLevel::Ok => 1,
+                QualityLevel::Bad => 0,
+            };
+            call.quality_samples += 1;
+        }
+    }
+
+    /// End the call going on, the next visible user to join starts another
+    pub(super) fn end_call(&self) -> Option<CallSummary> {
+        self.counters.lock().call.take().map(Call::summary)
+    }
+
+    /// Record that the room was recorded at some point
+    pub(super) fn record_recording(&self) {
+        self.counters.lock().recorded = true;
+    }
+
+    pub fn summary(&self) -> RoomSummary {
+        let counters = self.counters.lock();
+        RoomSummary {
+            duration_secs: self.created_at.elapsed().as_secs(),
+            peak_users: counters.peak_users,
+            total_unique_users: counters.unique_users.len(),
+            recording_available: counters.recorded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speaking(user_id: &str) -> SpeakerVolume {
+        SpeakerVolume {
+            user_id: user_id.to_string(),
+            volume: -20,
+        }
+    }
+
+    #[test]
+    fn calls_are_summarized_as_they_go() {
+        let usage = Usage::new();
+        // Nothing counts before anyone joined
+        usage.record_kick();
+        usage.record_speakers(&[speaking("alice")], 500);
+        assert_eq!(usage.end_call(), None);
+
+        usage.record_join("alice", 1);
+        usage.record_join("bob", 2);
+        usage.record_speakers(&[speaking("alice"), speaking("bob")], 500);
+        usage.record_speakers(&[speaking("alice")], 500);
+        usage.record_quality(QualityLevel::Good);
+        usage.record_quality(QualityLevel::Bad);
+        usage.record_quality(QualityLevel::Good);
+        usage.record_kick();
+        usage.record_ban();
+        let summary = usage.end_call().unwrap();
+        assert_eq!(summary.peak_users, 2);
+        assert_eq!(summary.talk_time_ms["alice"], 1000);
+        assert_eq!(summary.talk_time_ms["bob"], 500);
+        assert_eq!(summary.talk_stats["alice"].longest_monologue_ms, 1000);
+        assert_eq!((summary.kicks, summary.bans), (1, 1));
+        assert_eq!(summary.average_quality, Some(4.0 / 3.0));
+        assert_eq!(usage.end_call(), None);
+
+        // The next call starts over, the room's own counters don't
+        usage.record_join("carol", 1);
+        let summary = usage.end_call().unwrap();
+        assert_eq!(summary.peak_users, 1);
+        assert!(summary.talk_time_ms.is_empty());
+        assert_eq!(summary.average_quality, None);
+        assert_eq!(usage.summary().total_unique_users, 3);
+    }
+
+    #[test]
+    fn monologues_and_interruptions_are_counted() {
+        let usage = Usage::new();
+        usage.record_join("alice", 1);
+        usage.record_join("bob", 2);
+        usage.record_speakers(&[speaking("alice")], 500);
+        usage.record_speakers(&[speaking("alice")], 500);
+        // Bob cuts in while alice carries on, then she stops and starts over
+        usage.record_speakers(&[speaking("alice"), speaking("bob")], 500);
+        usage.record_speakers(&[speaking("bob")], 500);
+        usage.record_speakers(&[], 500);
+        usage.record_speakers(&[speaking("alice")], 500);
+
+        // Leaving and joining again doesn't start the user over
+        usage.record_join("bob", 2);
+        usage.record_speakers(&[speaking("bob")], 500);
+        let bob = usage.talk_stats_of("bob").unwrap();
+        assert_eq!(bob.talk_time_ms, 1500);
+        assert_eq!(bob.longest_monologue_ms, 1000);
+        assert_eq!(bob.interruptions, 1);
+
+        let summary = usage.end_call().unwrap();
+        let alice = &summary.talk_stats["alice"];
+        assert_eq!(alice.talk_time_ms, 2000);
+        assert_eq!(alice.longest_monologue_ms, 1500);
+        assert_eq!(alice.interruptions, 0);
+        assert!(usage.talk_stats().is_empty());
+    }
+}
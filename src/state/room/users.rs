@@ -1,17 +1,30 @@
 use rand::prelude::*;
 use std::collections::hash_map::Values;
+use std::time::Duration;
 use std::{ops::Deref, sync::Arc};
 use tokio::sync::{RwLock, RwLockReadGuard};
 
+use super::subscriber::SubscriberSignal;
+use super::token::{self, JoinClaims, JoinToken, SessionConstraints, TokenError};
 use super::{Room, RoomEvent, RoomUserMap};
 use crate::api::ApiError;
-use crate::state::user::User;
+use crate::integrations::audit::{self, AuditAction, AuditRecord};
+use crate::integrations::{format::LifecycleEvent, redis::get_redis};
+use crate::rtc::playback::SYSTEM_USER_ID;
+use crate::state::store::{get_store, StoredUser};
+use crate::state::user::{ConnectionState, User, UserOptions};
+use crate::util::config::CONFIG;
+use crate::ws::error::WSCloseType;
+
+/// Longest kick reason in bytes, it's sent as the close frame reason which can't
+/// be longer than 123 bytes
+pub const MAX_KICK_REASON: usize = 120;
 
 fn generate_token(rng: &mut dyn RngCore) -> Result<String, ApiError> {
     let mut token_bytes = [0; 24];
     rng.try_fill_bytes(&mut token_bytes)
         .map_err(|_| ApiError::InternalServerError)?;
-    Ok(base64::encode_config(&token_bytes, base64::URL_SAFE))
+    Ok(base64::encode_config(token_bytes, base64::URL_SAFE_NO_PAD))
 }
 
 pub struct RoomUsers {
@@ -23,32 +36,110 @@ impl<'r> RoomUsers {
         RoomUsers { room }
     }
 
-    pub async fn new(&'r self, id: String) -> Result<UserGuard<'r>, ApiError> {
-        let token = {
-            let registrations = self.room.registrations.read().await;
-            let mut rng = thread_rng();
-            let mut token = generate_token(&mut rng)?;
-            while registrations.contains_key(&token) {
-                token = generate_token(&mut rng)?;
-            }
-            token
-        };
+    /// Registration nonce not issued for any other user of the room
+    async fn unique_token(&'r self) -> Result<String, ApiError> {
+        let registrations = self.room.registrations.read().await;
+        let mut rng = thread_rng();
+        let mut token = generate_token(&mut rng)?;
+        while registrations.contains_key(&token) {
+            token = generate_token(&mut rng)?;
+        }
+        Ok(token)
+    }
+
+    /// Signed join token redeeming a registration, refused once `exp` passed
+    fn join_token(
+        &'r self,
+        id: &str,
+        nonce: String,
+        exp: u64,
+        constraints: SessionConstraints,
+    ) -> String {
+        token::issue(&JoinClaims {
+            room: self.room.scoped_id().to_string(),
+            sub: id.to_string(),
+            exp,
+            nonce,
+            constraints,
+        })
+    }
+
+    /// Create a pending user and return its join token, valid for the configured
+    /// lifetime. Hidden users are left out of what other participants can see
+    pub async fn create(&'r self, id: String, options: UserOptions) -> Result<JoinToken, ApiError> {
+        let nonce = self.unique_token().await?;
+        let exp = token::now() + CONFIG.api.join_token_ttl;
+        let token = self.join_token(&id, nonce.clone(), exp, options.constraints);
+        self.insert_pending(id, nonce.clone(), exp, options).await?;
+        Ok(JoinToken { token, id: nonce })
+    }
 
-        let user = User::new(self.room.clone(), id.clone(), token.clone());
+    /// Bring back a pending user kept by the room store, the token it was issued
+    /// before the restart stays valid
+    pub async fn restore(&'r self, id: String, stored: StoredUser) -> Result<(), ApiError> {
+        self.insert_pending(id, stored.nonce, stored.expires, stored.options)
+            .await
+    }
+
+    async fn insert_pending(
+        &'r self,
+        id: String,
+        nonce: String,
+        expires: u64,
+        options: UserOptions,
+    ) -> Result<(), ApiError> {
+        let stored = StoredUser {
+            nonce: nonce.clone(),
+            expires,
+            options: options.clone(),
+        };
+        let user = User::new(self.room.clone(), id.clone(), nonce.clone(), options);
         let mut users = self.room.users.write().await;
-        if users.contains_key(&id) {
+        // Taken by audio the server plays into the room
+        if users.contains_key(&id) || id == SYSTEM_USER_ID {
             return Err(ApiError::UserAlreadyExists(id));
         }
 
-        users.insert(id.clone(), RwLock::new(user));
+        if !self.room.occupancy.reserve(self.room.max_users()) {
+            return Err(ApiError::RoomFull(self.room.id().to_string()));
+        }
+
+        users.insert(id.clone(), Arc::new(RwLock::new(user)));
         drop(users);
+        self.room.user_joined();
 
         let mut registrations = self.room.registrations.write().await;
-        registrations.insert(token, id.clone());
+        registrations.insert(nonce, id.clone());
         drop(registrations);
 
+        if let Some(store) = get_store() {
+            store.add_user(self.room.scoped_id(), &id, stored);
+        }
+
         debug!("Created new user {} in room {}", &id, self.room.id());
-        Ok(self.get(&id).await.unwrap())
+        Ok(())
+    }
+
+    /// Issue a token that takes over the session of a connected user, `None` if the
+    /// user isn't connected
+    async fn takeover(&'r self, id: &str, constraints: SessionConstraints) -> Option<String> {
+        let nonce = self.unique_token().await.ok()?;
+        let exp = token::now() + CONFIG.api.join_token_ttl;
+        let token = self.join_token(id, nonce.clone(), exp, constraints);
+        let users = self.room.users.read().await;
+        if !users.get(id)?.read().await.registered() {
+            return None;
+        }
+
+        // Lock order matches `register`: users, then registrations
+        let mut registrations = self.room.registrations.write().await;
+        registrations.insert(nonce, id.to_string());
+        debug!(
+            "Issued session takeover token for user {} in room {}",
+            id,
+            self.room.id()
+        );
+        Some(token)
     }
 
     pub async fn get(&'r self, id: &str) -> Option<UserGuard<'r>> {
@@ -63,34 +154,255 @@ impl<'r> RoomUsers {
         })
     }
 
-    pub async fn register(&'r self, token: &str) -> Option<UserGuard<'r>> {
+    /// Create and immediately register a user whose identity was already verified,
+    /// replacing a pending user or taking over the session of a connected one
+    pub async fn join(
+        &'r self,
+        id: String,
+        options: UserOptions,
+        connection_id: u64,
+    ) -> Option<Registration<'r>> {
+        let token = match self.create(id.clone(), options.clone()).await {
+            Ok(join_token) => join_token.token,
+            Err(ApiError::UserAlreadyExists(_)) => {
+                match self.takeover(&id, options.constraints).await {
+                    Some(token) => token,
+                    None => {
+                        self.remove(&id).await.ok();
+                        self.create(id, options).await.ok()?.token
+                    }
+                }
+            }
+            Err(_) => return None,
+        };
+
+        self.register(&token, connection_id).await.ok()
+    }
+
+    /// Revoke a join token that wasn't used yet. A pending user has no other way in, so
+    /// it's removed along with the token
+    pub async fn revoke(&'r self, token_id: &str) -> Result<(), ApiError> {
+        let id = {
+            // Lock order matches `register`: users, then registrations
+            let _users = self.room.users.read().await;
+            let mut registrations = self.room.registrations.write().await;
+            registrations
+                .remove(token_id)
+                .ok_or_else(|| ApiError::JoinTokenNotFound(token_id.to_string()))?
+        };
+
+        let pending = match self.get(&id).await {
+            Some(user) => user.read().await.token() == Some(token_id),
+            None => false,
+        };
+        if pending {
+            self.remove(&id).await.ok();
+        }
+
+        debug!(
+            "Revoked join token of user {} in room {}",
+            id,
+            self.room.id()
+        );
+        Ok(())
+    }
+
+    /// Register the user a join token was issued for on a connection. The token must be
+    /// signed by this node for this room, unexpired and not used before, and its user
+    /// not banned
+    pub async fn register(
+        &'r self,
+        token: &str,
+        connection_id: u64,
+    ) -> Result<Registration<'r>, TokenError> {
+        let claims = token::verify(token, self.room.scoped_id(), token::now())?;
+        if self.room.banned(&claims.sub) {
+            return Err(TokenError::Banned);
+        }
+
+        let users = self.room.users.read().await;
         let mut registrations = self.room.registrations.write().await;
-        let registration = registrations.remove(token)?;
+        match registrations.get(&claims.nonce) {
+            Some(registration) if *registration == claims.sub => {
+                registrations.remove(&claims.nonce);
+            }
+            _ => return Err(TokenError::NotRegistered),
+        }
         drop(registrations);
+        if let Some(store) = get_store() {
+            store.remove_user(self.room.scoped_id(), &claims.sub);
+        }
 
-        let users = self.room.users.read().await;
-        let user = users.get(&registration)?;
-        let id = {
+        let user = users.get(&claims.sub).ok_or(TokenError::NotRegistered)?;
+        let (id, replaced) = {
             let mut user = user.write().await;
-            user.register().await;
-            user.id().to_string()
+            let replaced = user.register(connection_id, claims.constraints).await;
+            (user.id().to_string(), replaced)
         };
+        audit::record(AuditRecord::new(self.room.scoped_id(), AuditAction::Joined).target(&id));
 
-        Some(UserGuard { inner: users, id })
+        Ok(Registration {
+            user: UserGuard { inner: users, id },
+            replaced,
+            expires: claims.exp,
+        })
+    }
+
+    /// Remove a user when its connection ends, unless another connection took it over
+    pub async fn disconnect(&'r self, id: &str, connection_id: u64) {
+        let owned = match self.get(id).await {
+            Some(user) => user.read().await.connection_id() == Some(connection_id),
+            None => false,
+        };
+
+        if owned {
+            self.remove(id).await.ok();
+        }
+    }
+
+    /// Keep a user whose connection dropped for `grace`, marked as disconnected, so its
+    /// client can resume the session by joining again. It's removed if it doesn't come
+    /// back in time, and `held` is dropped along with it, such as the transports its
+    /// producers are on
+    pub async fn linger<T: Send + 'static>(
+        &'r self,
+        id: &str,
+        connection_id: u64,
+        grace: Duration,
+        held: T,
+    ) {
+        match self.get(id).await {
+            Some(user) => {
+                let mut user = user.write().await;
+                if user.connection_id() != Some(connection_id) {
+                    return;
+                }
+                user.set_connection_state(ConnectionState::Disconnected);
+            }
+            None => return,
+        }
+
+        let room = self.room.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            room.users().disconnect(&id, connection_id).await;
+            drop(held);
+        });
+    }
+
+    /// Remove a user and close its connection, telling it why. `by` is the moderator
+    /// who kicked the user, none when it's done over the management API
+    pub async fn kick(
+        &'r self,
+        id: &str,
+        reason: Option<String>,
+        by: Option<&str>,
+    ) -> Result<(), ()> {
+        let connection_id = match self.get(id).await {
+            Some(user) => user.read().await.connection_id(),
+            None => return Err(()),
+        };
+
+        info!(
+            "User {} kicked from room {} by {}: {}",
+            id,
+            self.room.id(),
+            by.unwrap_or("the management API"),
+            reason.as_deref().unwrap_or("no reason given")
+        );
+        audit::record(
+            AuditRecord::new(self.room.scoped_id(), AuditAction::Kicked)
+                .actor(by)
+                .target(id)
+                .reason(reason.as_deref()),
+        );
+        self.room.notify(LifecycleEvent::UserKicked {
+            id: id.to_string(),
+            reason: reason.clone(),
+            by: by.map(str::to_string),
+        });
+        self.room.usage().record_kick();
+        // Signaled before the user is removed, so the connection closes with the
+        // reason rather than on seeing its user leave
+        if let Some(connection_id) = connection_id {
+            let signal = SubscriberSignal::Close(WSCloseType::Kicked(reason));
+            self.room.signal_subscriber(connection_id, signal);
+        }
+        self.remove_user(id, true).await
     }
 
     pub async fn remove(&'r self, id: &str) -> Result<(), ()> {
+        self.remove_user(id, false).await
+    }
+
+    async fn remove_user(&'r self, id: &str, kicked: bool) -> Result<(), ()> {
         let mut users = self.room.users.write().await;
         match users.remove(id) {
-            Some(_) => {
+            Some(user) => {
+                self.room.user_left(users.is_empty());
+                let user = user.read().await;
+                self.room
+                    .registrations
+                    .write()
+                    .await
+                    .retain(|_, registration| registration != id);
                 debug!("Removed user {} from room {}", id, self.room.id());
-                self.room.send_event(RoomEvent::UserLeft(id.to_string()));
+                if let Some(store) = get_store() {
+                    store.remove_user(self.room.scoped_id(), id);
+                }
+                self.room
+                    .occupancy
+                    .release(user.registered(), user.hidden());
+                self.room.remove_position(id);
+                self.room.remove_announcements(id);
+                self.room.leave_lobby(id);
+
+                if user.registered() {
+                    audit::record(
+                        AuditRecord::new(self.room.scoped_id(), AuditAction::Left).target(id),
+                    );
+                }
+
+                // Hidden and pending users were never announced
+                let mut emptied = false;
+                if user.registered() && !user.hidden() {
+                    if let Some(redis) = get_redis() {
+                        redis.remove_user(self.room.scoped_id(), id);
+                    }
+                    self.room
+                        .send_event(RoomEvent::UserLeft(id.to_string(), kicked));
+                    if self.room.occupancy.counts().visible == 0 {
+                        self.room
+                            .notify(LifecycleEvent::LastUserLeft(id.to_string()));
+                        emptied = true;
+                    }
+                }
+
+                drop(users);
+                if emptied {
+                    self.room.end_call().await;
+                }
+                if user.registered() && self.room.is_owner(id) {
+                    self.room.owner_left().await;
+                }
                 Ok(())
             }
             None => Err(()),
         }
     }
 
+    /// Every user of the room, holding up the room's user map only while they're
+    /// gathered. Reading them one by one afterwards doesn't keep users from joining
+    /// or leaving meanwhile
+    pub async fn snapshot(&'r self) -> Vec<(String, Arc<RwLock<User>>)> {
+        let users = self.room.users.read().await;
+        users
+            .iter()
+            .map(|(id, user)| (id.clone(), user.clone()))
+            .collect()
+    }
+
     // This is dumb
     pub async fn guard(&'r self) -> UserMapGuard<'r> {
         let inner = self.room.users.read().await;
@@ -98,6 +410,14 @@ impl<'r> RoomUsers {
     }
 }
 
+/// A freshly registered user, along with the connection whose session it took over
+pub struct Registration<'r> {
+    pub user: UserGuard<'r>,
+    pub replaced: Option<u64>,
+    /// UNIX time in seconds the redeemed token expires
+    pub expires: u64,
+}
+
 pub struct UserGuard<'r> {
     inner: RwLockReadGuard<'r, RoomUserMap>,
     id: String,
@@ -109,6 +429,7 @@ impl Deref for UserGuard<'_> {
     fn deref(&self) -> &Self::Target {
         self.inner
             .get(&self.id)
+            .map(Arc::as_ref)
             .expect("UserGuard deref failed, this should never happen")
     }
 }
@@ -118,7 +439,157 @@ pub struct UserMapGuard<'r> {
 }
 
 impl<'r> UserMapGuard<'r> {
-    pub fn iter(&'r self) -> Values<'r, String, RwLock<User>> {
+    pub fn iter(&'r self) -> Values<'r, String, Arc<RwLock<User>>> {
         self.inner.values()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::state::room::RoomSettings;
+    use crate::state::user::UserOptions;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn simultaneous_joins_leave_one_owner() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+
+        for round in 0..20 {
+            let id = format!("user-{}", round);
+            let join = |connection_id: u64| {
+                let (users, id) = (&users, id.clone());
+                async move {
+                    let registration = users.join(id, UserOptions::default(), connection_id).await;
+                    registration.map(|registration| registration.replaced)
+                }
+            };
+            let (first, second) = tokio::join!(join(1), join(2));
+
+            // The last connection to register owns the user, an earlier one was either
+            // taken over or lost its pending registration
+            let owner = users.get(&id).await.unwrap().read().await.connection_id();
+            match (first, second) {
+                (Some(None), Some(Some(1))) => assert_eq!(owner, Some(2)),
+                (Some(Some(2)), Some(None)) => assert_eq!(owner, Some(1)),
+                (Some(None), None) => assert_eq!(owner, Some(1)),
+                (None, Some(None)) => assert_eq!(owner, Some(2)),
+                _ => panic!("Unexpected outcome {:?} {:?}", first, second),
+            }
+
+            let counts = room.occupancy().counts();
+            assert_eq!((counts.visible, counts.pending), (1, 0));
+
+            // Only the owner's disconnect removes the user
+            let loser = if owner == Some(1) { 2 } else { 1 };
+            users.disconnect(&id, loser).await;
+            assert!(users.get(&id).await.is_some());
+            users.disconnect(&id, owner.unwrap()).await;
+            assert!(users.get(&id).await.is_none());
+        }
+
+        assert_eq!(room.occupancy().counts().capacity_used(), 0);
+        drop(users);
+        room.delete().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    #[tokio::test]
+    async fn join_tokens_are_single_use_and_bound_to_their_room() {
+        let room = testing::room(RoomSettings::default()).await;
+        let other = testing::room(RoomSettings::default()).await;
+        let options = UserOptions {
+            constraints: SessionConstraints {
+                listen_only: true,
+                max_session_secs: None,
+            },
+            ..UserOptions::default()
+        };
+        let token = room
+            .users()
+            .create("alice".to_string(), options)
+            .await
+            .unwrap()
+            .token;
+
+        let refused = other.users().register(&token, 1).await.err();
+        assert_eq!(refused, Some(TokenError::WrongRoom(room.id().to_string())));
+
+        let users = room.users();
+        let registration = users.register(&token, 1).await.unwrap();
+        assert!(registration.user.read().await.constraints().listen_only);
+        drop(registration);
+
+        let reused = users.register(&token, 2).await.err();
+        assert_eq!(reused, Some(TokenError::NotRegistered));
+        drop(users);
+        room.delete().await;
+        other.delete().await;
+    }
+
+    #[tokio::test]
+    async fn only_one_connection_redeems_a_token() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+
+        let (first, second) =
+            tokio::join!(async { users.register(&token, 1).await.is_ok() }, async {
+                users.register(&token, 2).await.is_ok()
+            },);
+        assert!(first ^ second);
+        drop(users);
+        room.delete().await;
+    }
+
+    async fn state(users: &RoomUsers) -> Option<(Option<u64>, ConnectionState)> {
+        let user = users.get("alice").await?;
+        let user = user.read().await;
+        Some((user.connection_id(), user.connection_state()))
+    }
+
+    #[tokio::test]
+    async fn dropped_users_linger_until_they_resume() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let grace = Duration::from_millis(50);
+
+        let options = UserOptions::default();
+        assert!(users
+            .join("alice".to_string(), options.clone(), 1)
+            .await
+            .is_some());
+        users.linger("alice", 1, grace, ()).await;
+        assert_eq!(
+            state(&users).await,
+            Some((Some(1), ConnectionState::Disconnected))
+        );
+
+        // Joining again takes the session over, the first connection's grace period
+        // ending doesn't remove it
+        assert!(users.join("alice".to_string(), options, 2).await.is_some());
+        assert_eq!(
+            state(&users).await,
+            Some((Some(2), ConnectionState::Connected))
+        );
+        tokio::time::sleep(grace * 2).await;
+        assert!(state(&users).await.is_some());
+
+        users.linger("alice", 2, grace, ()).await;
+        tokio::time::sleep(grace * 2).await;
+        assert_eq!(state(&users).await, None);
+        drop(users);
+        room.delete().await;
+    }
+}
@@ -0,0 +1,337 @@
+//! Rooms of this node and the users created over the API that haven't joined yet, kept in
+//! a store so a restarted node can bring them back and clients can still redeem their
+//! join tokens. Media and connected users aren't kept, they start over
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+use super::room::{split_scoped, token, Room, RoomSettings};
+use super::user::UserOptions;
+
+pub static STORE: OnceCell<Persistence> = OnceCell::new();
+
+/// Persistence of rooms, only present when a store is configured
+pub fn get_store() -> Option<&'static Persistence> {
+    STORE.get()
+}
+
+/// Changes made within this long of each other are saved together
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// User created over the API that hasn't redeemed its join token yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredUser {
+    /// Registration the join token redeems
+    pub nonce: String,
+    /// UNIX time in seconds the join token expires, the user isn't restored after it
+    pub expires: u64,
+    pub options: UserOptions,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredRoom {
+    /// Settings in effect when the room last changed
+    pub settings: RoomSettings,
+    /// Pending users by ID
+    pub pending: HashMap<String, StoredUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Snapshot {
+    /// Key join tokens are signed with, base64 encoded, so they stay valid across restarts
+    pub signing_key: Option<String>,
+    /// Rooms by scoped ID, see `Room::scoped_id`
+    pub rooms: HashMap<String, StoredRoom>,
+}
+
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Room store failed: {}", self.0)
+    }
+}
+
+/// Where the snapshot of the node's rooms is kept. Register an implementation with
+/// `ServerBuilder::room_store` to keep it in a database rather than a file
+#[async_trait]
+pub trait RoomStore: Send + Sync {
+    /// Snapshot saved last, an empty one if there is none yet
+    async fn load(&self) -> Result<Snapshot, StoreError>;
+    async fn save(&self, snapshot: &Snapshot) -> Result<(), StoreError>;
+}
+
+/// Snapshot kept as a JSON file, replaced as a whole on every save
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileStore { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl RoomStore for FileStore {
+    async fn load(&self) -> Result<Snapshot, StoreError> {
+        match tokio::fs::read(&self.path).await {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).map_err(|err| StoreError(err.to_string()))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Snapshot::default()),
+            Err(err) => Err(StoreError(err.to_string())),
+        }
+    }
+
+    /// Written next to the file and renamed over it, a crash midway leaves the last snapshot
+    async fn save(&self, snapshot: &Snapshot) -> Result<(), StoreError> {
+        let contents = serde_json::to_vec(snapshot).map_err(|err| StoreError(err.to_string()))?;
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, contents)
+            .await
+            .map_err(|err| StoreError(err.to_string()))?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .map_err(|err| StoreError(err.to_string()))
+    }
+}
+
+enum Operation {
+    SaveRoom(String, RoomSettings),
+    DeleteRoom(String),
+    /// Room ID, user ID and the pending user
+    AddUser(String, String, StoredUser),
+    /// Room ID and user ID
+    RemoveUser(String, String),
+}
+
+impl Snapshot {
+    fn apply(&mut self, operation: Operation) {
+        match operation {
+            Operation::SaveRoom(id, settings) => {
+                self.rooms
+                    .entry(id)
+                    .and_modify(|room| room.settings = settings.clone())
+                    .or_insert_with(|| StoredRoom {
+                        settings,
+                        pending: HashMap::new(),
+                    });
+            }
+            Operation::DeleteRoom(id) => {
+                self.rooms.remove(&id);
+            }
+            Operation::AddUser(room_id, id, user) => {
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.pending.insert(id, user);
+                }
+            }
+            Operation::RemoveUser(room_id, id) => {
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.pending.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the snapshot in the store up to date as rooms change. Changes are queued and
+/// saved in the background, nothing waits on the store
+pub struct Persistence {
+    queue: UnboundedSender<Operation>,
+}
+
+impl Persistence {
+    /// Load the last snapshot, taking over its signing key, and start saving changes.
+    /// Returns the rooms it held, for `restore` to create again once the persistence
+    /// is in place to record them
+    pub async fn start(
+        store: Arc<dyn RoomStore>,
+    ) -> Result<(Self, HashMap<String, StoredRoom>), StoreError> {
+        let mut snapshot = store.load().await?;
+        let restored = snapshot
+            .signing_key
+            .as_deref()
+            .and_then(|key| base64::decode(key).ok())
+            .and_then(|key| key.try_into().ok())
+            .map_or(false, token::restore_signing_key);
+        if !restored {
+            snapshot.signing_key = Some(base64::encode(token::signing_key()));
+        }
+
+        let rooms = std::mem::take(&mut snapshot.rooms);
+        let (queue, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(save_changes(store, snapshot, receiver));
+        Ok((Persistence { queue }, rooms))
+    }
+
+    pub fn save_room(&self, id: &str, settings: &RoomSettings) {
+        self.write(Operation::SaveRoom(id.to_string(), settings.clone()));
+    }
+
+    pub fn delete_room(&self, id: &str) {
+        self.write(Operation::DeleteRoom(id.to_string()));
+    }
+
+    pub fn add_user(&self, room_id: &str, id: &str, user: StoredUser) {
+        self.write(Operation::AddUser(
+            room_id.to_string(),
+            id.to_string(),
+            user,
+        ));
+    }
+
+    /// The user joined or was removed, either way its token can't be redeemed anymore
+    pub fn remove_user(&self, room_id: &str, id: &str) {
+        self.write(Operation::RemoveUser(room_id.to_string(), id.to_string()));
+    }
+
+    fn write(&self, operation: Operation) {
+        self.queue.send(operation).ok();
+    }
+}
+
+/// Apply queued changes to the snapshot and save it, the changes made within `SAVE_DELAY`
+/// of the first at once
+async fn save_changes(
+    store: Arc<dyn RoomStore>,
+    mut snapshot: Snapshot,
+    mut receiver: UnboundedReceiver<Operation>,
+) {
+    while let Some(operation) = receiver.recv().await {
+        snapshot.apply(operation);
+        let deadline = Instant::now() + SAVE_DELAY;
+        while let Ok(Some(operation)) = tokio::time::timeout_at(deadline, receiver.recv()).await {
+            snapshot.apply(operation);
+        }
+
+        if let Err(err) = store.save(&snapshot).await {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Create the rooms of the last snapshot again along with their pending users, leaving
+/// out those whose join token expired in the meantime
+pub async fn restore(rooms: HashMap<String, StoredRoom>) {
+    let now = token::now();
+    for (id, stored) in rooms {
+        // Rooms are stored by their scoped ID
+        let (tenant, room_id) = split_scoped(&id);
+        let room = match Room::new(tenant, room_id.to_string(), stored.settings).await {
+            Ok(room) => room,
+            Err(err) => {
+                warn!("Failed to restore room {}: {}", id, err);
+                continue;
+            }
+        };
+
+        let users = room.users();
+        let mut count = 0;
+        for (user_id, user) in stored.pending {
+            if user.expires < now {
+                continue;
+            }
+
+            match users.restore(user_id.clone(), user).await {
+                Ok(()) => count += 1,
+                Err(err) => warn!("Failed to restore user {} in room {}: {}", user_id, id, err),
+            }
+        }
+        info!("Restored room {} with {} pending users", id, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::token::{JoinClaims, SessionConstraints};
+    use crate::util::testing;
+
+    fn stored_user(nonce: &str, expires: u64) -> StoredUser {
+        StoredUser {
+            nonce: nonce.to_string(),
+            expires,
+            options: UserOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn file_store_round_trips_snapshots() {
+        let path = std::env::temp_dir().join(format!("vortex-{}.json", rand::random::<u64>()));
+        let store = FileStore::new(&path);
+        assert!(store.load().await.unwrap().rooms.is_empty());
+
+        let mut snapshot = Snapshot::default();
+        snapshot.apply(Operation::SaveRoom(
+            "room".to_string(),
+            RoomSettings::default(),
+        ));
+        snapshot.apply(Operation::AddUser(
+            "room".to_string(),
+            "alice".to_string(),
+            stored_user("nonce", 1_000),
+        ));
+        // Users of rooms that aren't stored are dropped
+        snapshot.apply(Operation::AddUser(
+            "other".to_string(),
+            "bob".to_string(),
+            stored_user("nonce", 1_000),
+        ));
+        store.save(&snapshot).await.unwrap();
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.rooms.len(), 1);
+        assert_eq!(loaded.rooms["room"].pending["alice"].nonce, "nonce");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restored_rooms_keep_unexpired_tokens() {
+        testing::init();
+        let room_id = format!("test-{}", rand::random::<u64>());
+        let now = token::now();
+        let mut pending = HashMap::new();
+        pending.insert("alice".to_string(), stored_user("fresh", now + 60));
+        pending.insert("bob".to_string(), stored_user("stale", now - 60));
+        let mut rooms = HashMap::new();
+        rooms.insert(
+            room_id.clone(),
+            StoredRoom {
+                settings: RoomSettings::default(),
+                pending,
+            },
+        );
+
+        restore(rooms).await;
+        let room = Room::get_scoped(&room_id).await.unwrap();
+        let users = room.users();
+        assert!(users.get("bob").await.is_none());
+
+        let token = token::issue(&JoinClaims {
+            room: room_id.clone(),
+            sub: "alice".to_string(),
+            exp: now + 60,
+            nonce: "fresh".to_string(),
+            constraints: SessionConstraints::default(),
+        });
+        assert!(users.register(&token, 1).await.is_ok());
+        drop(users);
+        room.delete().await;
+    }
+}
@@ -1,27 +1,53 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
 use std::{str::FromStr, sync::Arc};
 
-use mediasoup::producer::Producer;
+use mediasoup::producer::{Producer, ProducerId};
 use mediasoup::rtp_parameters::MediaKind;
 
-use super::room::{Room, RoomEvent};
+use super::room::{
+    subscriber::SubscriberSignal, token::SessionConstraints, Position, Room, RoomEvent,
+};
+use crate::integrations::{format::LifecycleEvent, redis::get_redis};
+use crate::rtc::quality::QualityLevel;
+use crate::rtc::MediaStats;
+use crate::util::rate::TokenBucket;
+use crate::ws::types::MediaClosedReason;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+/// Media a user produces, at most one producer of each per user. Part of the protocol,
+/// clients take it from the schema rather than keeping a copy
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ProduceType {
+    /// Microphone
     #[serde(rename = "audio")]
     Audio,
+    /// Camera
     #[serde(rename = "video")]
     Video,
 
+    /// Audio of a shared screen or tab, also accepted as `screenshareaudio`
     #[serde(rename = "saudio")]
     #[serde(alias = "screenshareaudio")]
     ScreenshareAudio,
+    /// Shared screen or tab, also accepted as `screensharevideo`
     #[serde(rename = "svideo")]
     #[serde(alias = "screensharevideo")]
     ScreenshareVideo,
 }
 
 impl ProduceType {
+    pub const ALL: [ProduceType; 4] = [
+        ProduceType::Audio,
+        ProduceType::Video,
+        ProduceType::ScreenshareAudio,
+        ProduceType::ScreenshareVideo,
+    ];
+
     pub fn into_kind(self) -> MediaKind {
         match self {
             ProduceType::Audio | ProduceType::ScreenshareAudio => MediaKind::Audio,
@@ -40,28 +66,259 @@ impl FromStr for ProduceType {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            s if s == "audio" => Ok(Self::Audio),
+            "audio" => Ok(Self::Audio),
+            "video" => Ok(Self::Video),
+            "saudio" | "screenshareaudio" => Ok(Self::ScreenshareAudio),
+            "svideo" | "screensharevideo" => Ok(Self::ScreenshareVideo),
             _ => Err(()),
         }
     }
 }
 
+impl Display for ProduceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProduceType::Audio => "audio",
+            ProduceType::Video => "video",
+            ProduceType::ScreenshareAudio => "saudio",
+            ProduceType::ScreenshareVideo => "svideo",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Capabilities granted to a user beyond joining the room
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Moderator,
+    /// Left out of `MuteAll`, such as a co-presenter
+    BypassMute,
+}
+
+impl Permission {
+    /// Name used in the API and JWT claims
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Moderator => "moderator",
+            Permission::BypassMute => "bypass_mute",
+        }
+    }
+}
+
+/// What a user may do in the room, moderators change it at runtime
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Speaker,
+    /// Consumes only, as most of the audience of a town hall does
+    Listener,
+    /// Speaker who may also promote and demote others
+    Moderator,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::Speaker
+    }
+}
+
+impl Role {
+    pub fn can_produce(self) -> bool {
+        self != Role::Listener
+    }
+}
+
+/// Whether a registered user's connection is up, or dropped and left for the client to
+/// resume the session
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::Connected
+    }
+}
+
+/// How a user is set up when created, over the API or from a JWT
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UserOptions {
+    /// Keep the user out of RoomInfo, join/leave events and public occupancy
+    pub hidden: bool,
+    pub permissions: Vec<Permission>,
+    pub role: Role,
+    /// Initial `UserInfo::metadata`, at most `MAX_METADATA_SIZE` once serialized
+    pub metadata: Map<String, Value>,
+    /// Embedded in the join token, JWTs carry them as claims
+    #[serde(flatten)]
+    pub constraints: SessionConstraints,
+}
+
+/// Relayed messages a user may send in a burst
+const RELAY_BURST: u32 = 10;
+/// Relayed messages a user may send each second after a burst
+const RELAY_PER_SECOND: u32 = 5;
+/// Producers a user may start or stop in a burst
+const PRODUCE_BURST: u32 = 10;
+/// Producers a user may start or stop each second after a burst
+const PRODUCE_PER_SECOND: u32 = 2;
+/// Users a user may report to the moderators in a burst
+const REPORT_BURST: u32 = 3;
+/// Users a user may report each minute after a burst
+const REPORT_PER_MINUTE: u32 = 2;
+
+/// Longest display name a user may set, in characters
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+/// Longest avatar URL a user may set, in characters
+pub const MAX_AVATAR_LENGTH: usize = 512;
+/// Most bytes a user's metadata may take serialized as JSON
+pub const MAX_METADATA_SIZE: usize = 8 * 1024;
+
+/// Whether metadata fits in `MAX_METADATA_SIZE`
+pub fn metadata_fits(metadata: &Map<String, Value>) -> bool {
+    serde_json::to_vec(metadata).map_or(false, |bytes| bytes.len() <= MAX_METADATA_SIZE)
+}
+
+/// Profile fields a user can change while connected. A field left out is kept
+/// as it is, a field set to `null` is cleared
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfoUpdate {
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub display_name: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub avatar: Option<Option<String>>,
+    /// Replaces the metadata as a whole
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata: Option<Option<Map<String, Value>>>,
+}
+
+/// Tell a field set to `null` apart from one left out
+pub(crate) fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl UserInfoUpdate {
+    /// Check field lengths, returning the name of the first oversized field
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let too_long = |value: &Option<Option<String>>, max: usize| {
+            value
+                .as_ref()
+                .and_then(Option::as_ref)
+                .is_some_and(|value| value.chars().count() > max)
+        };
+
+        if too_long(&self.display_name, MAX_DISPLAY_NAME_LENGTH) {
+            return Err("displayName");
+        }
+
+        if too_long(&self.avatar, MAX_AVATAR_LENGTH) {
+            return Err("avatar");
+        }
+
+        if let Some(Some(metadata)) = &self.metadata {
+            if !metadata_fits(metadata) {
+                return Err("metadata");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Changes of a user's sampled quality kept for support to look back on
+pub const QUALITY_HISTORY: usize = 20;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct QualityChange {
+    pub at: DateTime<Utc>,
+    pub level: QualityLevel,
+}
+
 pub struct User {
     id: String,
     token: Option<String>,
     room: Arc<Room>,
+    hidden: bool,
+    permissions: Vec<Permission>,
+    role: Role,
+    /// Signaling connection currently holding the user's session
+    connection_id: Option<u64>,
+    /// When the connection holding the session registered it
+    connected_at: Option<Instant>,
+    connection_state: ConnectionState,
+    /// Last quality sampled from the connection, until it's first sampled
+    quality: Option<QualityLevel>,
+    /// Latest changes of `quality`, oldest first
+    quality_history: VecDeque<QualityChange>,
+    /// Limits of the join token the session was registered with
+    constraints: SessionConstraints,
+    display_name: Option<String>,
+    avatar: Option<String>,
+    metadata: Map<String, Value>,
 
     audio: Option<Producer>,
+    video: Option<Producer>,
+    screenshare_audio: Option<Producer>,
+    screenshare_video: Option<Producer>,
+    /// Transports and consumers of the connection, once it initialized them
+    media: Option<MediaStats>,
+    /// Relayed messages the user may still send, shared by its connections
+    relay_bucket: TokenBucket,
+    /// Producers the user may still start or stop, shared by its connections
+    produce_bucket: TokenBucket,
+    report_bucket: TokenBucket,
 }
 
 impl User {
-    pub(super) fn new(room: Arc<Room>, id: String, token: String) -> User {
+    pub(super) fn new(room: Arc<Room>, id: String, token: String, options: UserOptions) -> User {
         User {
-            id: id,
-            token: Some(token.clone()),
-            room: room,
+            id,
+            token: Some(token),
+            room,
+            hidden: options.hidden,
+            permissions: options.permissions,
+            role: options.role,
+            connection_id: None,
+            connected_at: None,
+            connection_state: ConnectionState::Connected,
+            quality: None,
+            quality_history: VecDeque::new(),
+            constraints: SessionConstraints::default(),
+            display_name: None,
+            avatar: None,
+            metadata: options.metadata,
 
             audio: None,
+            video: None,
+            screenshare_audio: None,
+            screenshare_video: None,
+            media: None,
+            relay_bucket: TokenBucket::new(RELAY_BURST, RELAY_PER_SECOND),
+            produce_bucket: TokenBucket::new(PRODUCE_BURST, PRODUCE_PER_SECOND),
+            report_bucket: TokenBucket::per_minute(REPORT_BURST, REPORT_PER_MINUTE),
         }
     }
 
@@ -70,31 +327,289 @@ impl User {
     }
 
     pub fn token(&self) -> Option<&str> {
-        self.token.as_ref().map(|string| string.as_str())
+        self.token.as_deref()
     }
 
     pub fn registered(&self) -> bool {
         self.token.is_none()
     }
 
-    pub async fn register(&mut self) {
+    /// Hidden users (bots, observers) are not announced to or listed for other participants
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Granted explicitly when the user was created, not those its role implies
+    pub fn permissions(&self) -> &[Permission] {
+        &self.permissions
+    }
+
+    /// Granted when the user was created, by its role, or to the room's owner
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        let granted = match permission {
+            Permission::Moderator => self.role == Role::Moderator || self.room.is_owner(&self.id),
+            Permission::BypassMute => false,
+        };
+        granted || self.permissions.contains(&permission)
+    }
+
+    /// Whether another relayed message fits in the user's rate limit
+    pub fn take_relay(&mut self) -> bool {
+        self.relay_bucket.take(Instant::now())
+    }
+
+    /// Whether starting or stopping another producer fits in the user's rate limit, or
+    /// how long until it does
+    pub fn take_produce(&mut self) -> Result<(), Duration> {
+        match self.produce_bucket.take(Instant::now()) {
+            true => Ok(()),
+            false => Err(self.produce_bucket.wait()),
+        }
+    }
+
+    /// Whether another report fits in the user's rate limit, or how long until one does
+    pub fn take_report(&mut self) -> Result<(), Duration> {
+        match self.report_bucket.take(Instant::now()) {
+            true => Ok(()),
+            false => Err(self.report_bucket.wait()),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Change the user's role and let the room know. Producers of a user demoted to
+    /// listener are closed as if it stopped them, and its connection is told to let go
+    pub fn set_role(&mut self, role: Role) {
+        if self.role == role {
+            return;
+        }
+
+        self.role = role;
+        if !role.can_produce() {
+            self.close_producers(&ProduceType::ALL, MediaClosedReason::RoleChanged);
+        }
+
+        let event = RoomEvent::UserRoleChanged(self.id.clone(), role);
+        if self.hidden {
+            // Nobody else knows of the user, but its own client still has to
+            if let Some(connection_id) = self.connection_id {
+                let signal = SubscriberSignal::Event(event);
+                self.room.signal_subscriber(connection_id, signal);
+            }
+            return;
+        }
+
+        if let Some(redis) = get_redis() {
+            redis.set_user(self.room.scoped_id(), &self.id, &self.into_info());
+        }
+        self.room.send_event(event);
+    }
+
+    /// Close the user's producers of the given types as if it stopped them, telling its
+    /// connection to let go of them and why
+    pub fn close_producers(&mut self, produce_types: &[ProduceType], reason: MediaClosedReason) {
+        let closed: Vec<(ProduceType, ProducerId)> = produce_types
+            .iter()
+            .filter_map(|produce_type| {
+                let producer = self.get_producer(*produce_type)?;
+                Some((*produce_type, producer.id()))
+            })
+            .collect();
+        for (produce_type, _) in closed.iter() {
+            self.set_producer(*produce_type, None).ok();
+            self.announce_producer(*produce_type);
+        }
+
+        if let (Some(connection_id), false) = (self.connection_id, closed.is_empty()) {
+            let signal = SubscriberSignal::ProducersClosed(closed, reason);
+            self.room.signal_subscriber(connection_id, signal);
+        }
+    }
+
+    /// Drop the user's producers and announce them stopped, without telling its
+    /// connection, which already let go of them along with their transports
+    pub fn withdraw_producers(&mut self) {
+        for produce_type in ProduceType::ALL.iter() {
+            if self.get_producer(*produce_type).is_some() {
+                self.set_producer(*produce_type, None).ok();
+                self.announce_producer(*produce_type);
+            }
+        }
+    }
+
+    pub fn connection_id(&self) -> Option<u64> {
+        self.connection_id
+    }
+
+    pub fn constraints(&self) -> SessionConstraints {
+        self.constraints
+    }
+
+    /// Options that create the user again as it is now, its role and metadata included
+    pub fn options(&self) -> UserOptions {
+        UserOptions {
+            hidden: self.hidden,
+            permissions: self.permissions.clone(),
+            role: self.role,
+            metadata: self.metadata.clone(),
+            constraints: self.constraints,
+        }
+    }
+
+    /// Display name and avatar as an update that sets both
+    pub fn profile(&self) -> UserInfoUpdate {
+        UserInfoUpdate {
+            display_name: Some(self.display_name.clone()),
+            avatar: Some(self.avatar.clone()),
+            metadata: None,
+        }
+    }
+
+    /// Producers the user has running and whether they're paused
+    pub fn producers(&self) -> Vec<ProducerInfo> {
+        ProduceType::ALL
+            .iter()
+            .filter_map(|produce_type| {
+                self.get_producer(*produce_type)
+                    .map(|producer| ProducerInfo {
+                        produce_type: *produce_type,
+                        paused: producer.paused(),
+                    })
+            })
+            .collect()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection_state
+    }
+
+    /// How long the connection holding the session has had it
+    pub fn connected_for(&self) -> Option<Duration> {
+        self.connected_at.map(|connected_at| connected_at.elapsed())
+    }
+
+    pub fn quality(&self) -> Option<QualityLevel> {
+        self.quality
+    }
+
+    pub fn quality_history(&self) -> impl Iterator<Item = &QualityChange> {
+        self.quality_history.iter()
+    }
+
+    /// Mark the user's connection as up or dropped and let the room know, unless the user
+    /// is hidden
+    pub fn set_connection_state(&mut self, state: ConnectionState) {
+        if self.connection_state == state {
+            return;
+        }
+
+        self.connection_state = state;
+        if self.hidden {
+            return;
+        }
+
+        if let Some(redis) = get_redis() {
+            redis.set_user(self.room.scoped_id(), &self.id, &self.into_info());
+        }
+        let event = RoomEvent::UserConnectionStateChanged(self.id.clone(), state);
+        self.room.send_event(event);
+    }
+
+    /// Record the quality sampled from the user's connection and let the room know if it
+    /// changed, unless the user is hidden
+    pub fn set_quality(&mut self, level: QualityLevel) {
+        if !self.hidden {
+            self.room.usage().record_quality(level);
+        }
+        if self.quality.replace(level) == Some(level) {
+            return;
+        }
+
+        if self.quality_history.len() == QUALITY_HISTORY {
+            self.quality_history.pop_front();
+        }
+        let at = Utc::now();
+        self.quality_history.push_back(QualityChange { at, level });
+        if self.hidden {
+            return;
+        }
+
+        let event = RoomEvent::UserQualityChanged(self.id.clone(), level);
+        self.room.send_event(event);
+    }
+
+    /// Attach the user to a connection, returning the connection whose session it took over.
+    /// A takeover isn't announced as a leave and join, but the producers of the old
+    /// connection go away along with its transports
+    pub async fn register(
+        &mut self,
+        connection_id: u64,
+        constraints: SessionConstraints,
+    ) -> Option<u64> {
+        let replaced = self.connection_id.replace(connection_id);
+        self.connected_at = Some(Instant::now());
+        self.constraints = constraints;
+        if replaced.is_some() {
+            debug!("User {} session taken over", &self.id);
+            self.withdraw_producers();
+            self.media = None;
+            // Back from a dropped connection
+            self.set_connection_state(ConnectionState::Connected);
+        }
+
         if let Some(token) = self.token.take() {
             let mut registrations = self.room.registrations.write().await;
             registrations.remove(&token);
             debug!("User {} registered", &self.id);
-            self.room.send_event(RoomEvent::UserJoined(self.id.clone()));
+            self.room.occupancy().admit(self.hidden);
+            if !self.hidden {
+                let connected = self.room.occupancy().counts().visible;
+                self.room.usage().record_join(&self.id, connected);
+                let info = self.into_info();
+                if let Some(redis) = get_redis() {
+                    redis.set_user(self.room.scoped_id(), &self.id, &info);
+                }
+                self.room
+                    .send_event(RoomEvent::UserJoined(self.id.clone(), info));
+                if connected == 1 {
+                    self.room
+                        .notify(LifecycleEvent::FirstUserJoined(self.id.clone()));
+                }
+            }
         }
+
+        replaced
+    }
+
+    pub fn producer_count(&self) -> usize {
+        ProduceType::ALL
+            .iter()
+            .filter(|produce_type| self.get_producer(**produce_type).is_some())
+            .count()
+    }
+
+    pub fn media(&self) -> Option<&MediaStats> {
+        self.media.as_ref()
+    }
+
+    pub fn set_media(&mut self, media: MediaStats) {
+        self.media = Some(media);
     }
 
     pub fn get_producer(&self, produce_type: ProduceType) -> Option<&Producer> {
         let producer = match produce_type {
             ProduceType::Audio => &self.audio,
-            _ => todo!(),
+            ProduceType::Video => &self.video,
+            ProduceType::ScreenshareAudio => &self.screenshare_audio,
+            ProduceType::ScreenshareVideo => &self.screenshare_video,
         };
 
         producer.as_ref()
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn set_producer(
         &mut self,
         produce_type: ProduceType,
@@ -105,28 +620,190 @@ impl User {
         }
         let producer = match produce_type {
             ProduceType::Audio => &mut self.audio,
-            _ => todo!(),
+            ProduceType::Video => &mut self.video,
+            ProduceType::ScreenshareAudio => &mut self.screenshare_audio,
+            ProduceType::ScreenshareVideo => &mut self.screenshare_video,
         };
 
         *producer = new_producer;
         Ok(())
     }
 
+    /// Let the room know a producer was started or stopped, unless the user is hidden.
+    /// Changes in quick succession are coalesced, see `Room::announce_producer`
+    pub fn announce_producer(&self, produce_type: ProduceType) {
+        if self.hidden {
+            return;
+        }
+
+        if let Some(redis) = get_redis() {
+            redis.set_user(self.room.scoped_id(), &self.id, &self.into_info());
+        }
+
+        let producer = self.get_producer(produce_type);
+        self.room
+            .announce_producer(&self.id, produce_type, producer);
+    }
+
+    /// Let the room know a producer was paused or resumed, unless the user is hidden
+    pub fn announce_producer_paused(&self, produce_type: ProduceType, paused: bool) {
+        if self.hidden {
+            return;
+        }
+
+        if let Some(redis) = get_redis() {
+            redis.set_user(self.room.scoped_id(), &self.id, &self.into_info());
+        }
+
+        let event = RoomEvent::UserProducerPauseChanged(self.id.clone(), produce_type, paused);
+        self.room.send_event(event);
+    }
+
+    /// Let the room know a producer was swapped for a new one, unless the user is hidden
+    pub fn announce_replaced_producer(&self, produce_type: ProduceType) {
+        if !self.hidden {
+            let event = RoomEvent::UserProducerReplaced(self.id.clone(), produce_type);
+            self.room.send_event(event);
+        }
+    }
+
+    /// Replace the user's profile and let the room know, unless the user is hidden
+    /// Apply a profile update, leaving out fields the update doesn't mention
+    pub fn set_info(&mut self, update: UserInfoUpdate) {
+        if let Some(display_name) = update.display_name {
+            self.display_name = display_name;
+        }
+        if let Some(avatar) = update.avatar {
+            self.avatar = avatar;
+        }
+        if let Some(metadata) = update.metadata {
+            self.metadata = metadata.unwrap_or_default();
+        }
+        if self.hidden {
+            return;
+        }
+
+        if let Some(redis) = get_redis() {
+            redis.set_user(self.room.scoped_id(), &self.id, &self.into_info());
+        }
+
+        self.room
+            .send_event(RoomEvent::UserInfoUpdated(self.id.clone()));
+    }
+
     pub fn into_info(&self) -> UserInfo {
         UserInfo::from(self)
     }
 }
 
 /// Structure passed to clients connected over WebSocket
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 pub struct UserInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>,
+    /// Set by the application when creating the user and by the user itself
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    metadata: Map<String, Value>,
     audio: bool,
+    /// Active producers, absent in entries written by older instances
+    #[serde(default)]
+    producers: Vec<ProducerInfo>,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    connection_state: ConnectionState,
+    /// Where the user is in a spatial audio room, while it keeps updating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<Position>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ProducerInfo {
+    #[serde(rename = "type")]
+    produce_type: ProduceType,
+    paused: bool,
 }
 
 impl From<&User> for UserInfo {
     fn from(user: &User) -> UserInfo {
+        let producers = user.producers();
         UserInfo {
+            display_name: user.display_name.clone(),
+            avatar: user.avatar.clone(),
+            metadata: user.metadata.clone(),
             audio: user.audio.is_some(),
+            producers,
+            role: user.role,
+            connection_state: user.connection_state,
+            position: user.room.position(&user.id),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::util::testing;
+
+    fn update(json: &str) -> UserInfoUpdate {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn update_tells_null_from_missing() {
+        let info = update(r#"{ "displayName": "Alice" }"#);
+        assert_eq!(info.display_name, Some(Some("Alice".to_string())));
+        assert_eq!(info.avatar, None);
+
+        let info = update(r#"{ "avatar": null }"#);
+        assert_eq!(info.display_name, None);
+        assert_eq!(info.avatar, Some(None));
+    }
+
+    #[test]
+    fn oversized_metadata_is_refused() {
+        let info = update(r#"{ "metadata": { "team": "red" } }"#);
+        assert_eq!(info.validate(), Ok(()));
+
+        let color = "f".repeat(MAX_METADATA_SIZE);
+        let info = update(&format!(r#"{{ "metadata": {{ "color": "{}" }} }}"#, color));
+        assert_eq!(info.validate(), Err("metadata"));
+    }
+
+    #[tokio::test]
+    async fn set_info_keeps_fields_left_out() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut user = User::new(
+            room.clone(),
+            "alice".to_string(),
+            "token".to_string(),
+            UserOptions {
+                hidden: true,
+                ..UserOptions::default()
+            },
+        );
+
+        user.set_info(update(
+            r#"{ "displayName": "Alice", "avatar": "https://example.com/a.png" }"#,
+        ));
+        user.set_info(update(r#"{ "displayName": "Alice B." }"#));
+        assert_eq!(user.display_name.as_deref(), Some("Alice B."));
+        assert_eq!(user.avatar.as_deref(), Some("https://example.com/a.png"));
+
+        user.set_info(update(r#"{ "avatar": null }"#));
+        assert_eq!(user.display_name.as_deref(), Some("Alice B."));
+        assert_eq!(user.avatar, None);
+
+        user.set_info(update(r#"{ "metadata": { "team": "red" } }"#));
+        user.set_info(update(r#"{ "displayName": "Alice" }"#));
+        assert_eq!(user.metadata["team"], "red");
+        user.set_info(update(r#"{ "metadata": null }"#));
+        assert!(user.metadata.is_empty());
+
+        drop(user);
+        room.delete().await;
+    }
+}
@@ -0,0 +1,232 @@
+//! Address of the client behind the reverse proxies in front of the node. The forwarding
+//! headers are only believed when the peer is a trusted proxy, anyone else could set them
+//! to pass for another address
+
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use warp::{Filter, Rejection};
+
+use super::config::CONFIG;
+
+lazy_static! {
+    static ref TRUSTED_PROXIES: Vec<IpNetwork> = CONFIG
+        .signaling
+        .trusted_proxies
+        .iter()
+        .map(|network| network.parse().expect("Trusted proxies were not validated"))
+        .collect();
+}
+
+/// Peer of a connection the server accepted itself rather than through warp, which can't
+/// tell its address. Set on the requests of connections accepted by `listener::serve_connection`
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Range of addresses in CIDR notation, a bare address stands for itself alone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidNetwork(String);
+
+impl Display for InvalidNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not an address or CIDR range", self.0)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidNetwork(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.parse().map_err(|_| invalid())?);
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// An address from a forwarding header, which may carry a port and IPv6 brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+        .map(canonical)
+}
+
+/// The `for` parameters of a `Forwarded` header, oldest hop first
+fn forwarded_for(header: &str) -> Vec<&str> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                Some(value).filter(|_| name.trim().eq_ignore_ascii_case("for"))
+            })
+        })
+        .collect()
+}
+
+/// Client address given the peer and its forwarding headers, `Forwarded` taking precedence
+/// over `X-Forwarded-For`. The hops are walked back from the peer for as long as they're
+/// trusted proxies, the first that isn't is the client. A hop that can't be read, like
+/// `unknown` or an obfuscated identifier, ends the walk at the proxy that reported it
+pub fn resolve(
+    peer: IpAddr,
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    trusted: &[IpNetwork],
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|network| network.contains(ip));
+    let mut client = canonical(peer);
+    if !is_trusted(client) {
+        return client;
+    }
+
+    let hops = match (forwarded, x_forwarded_for) {
+        (Some(forwarded), _) => forwarded_for(forwarded),
+        (None, Some(x_forwarded_for)) => x_forwarded_for.split(',').collect(),
+        (None, None) => Vec::new(),
+    };
+    for hop in hops.into_iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Client address of the request, `None` if the transport has no peer address
+pub fn filter() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("forwarded"))
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |remote: Option<SocketAddr>,
+             accepted: Option<PeerAddr>,
+             forwarded: Option<String>,
+             x_forwarded_for: Option<String>| {
+                let peer = accepted.map(|PeerAddr(peer)| peer).or(remote)?.ip();
+                Some(resolve(
+                    peer,
+                    forwarded.as_deref(),
+                    x_forwarded_for.as_deref(),
+                    &TRUSTED_PROXIES,
+                ))
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_are_parsed_and_matched() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains(ip("10.1.2.3")));
+        assert!(network.contains(ip("::ffff:10.1.2.3")));
+        assert!(!network.contains(ip("11.0.0.1")));
+
+        let single: IpNetwork = "fd00::1".parse().unwrap();
+        assert!(single.contains(ip("fd00::1")));
+        assert!(!single.contains(ip("fd00::2")));
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(ip("1.2.3.4")));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("proxy".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn headers_are_only_believed_from_trusted_proxies() {
+        let trusted = vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+
+        // Untrusted peers can't pass for another address
+        let spoofed = resolve(ip("203.0.113.9"), None, Some("198.51.100.1"), &trusted);
+        assert_eq!(spoofed, ip("203.0.113.9"));
+
+        // The client is the last hop that isn't a trusted proxy, whatever it prepended
+        let chain = "192.0.2.66, 198.51.100.1, 10.0.0.2";
+        let client = resolve(ip("127.0.0.1"), None, Some(chain), &trusted);
+        assert_eq!(client, ip("198.51.100.1"));
+
+        let forwarded = "for=192.0.2.60;proto=https, For=\"[2001:db8::1]:4711\"";
+        let client = resolve(
+            ip("127.0.0.1"),
+            Some(forwarded),
+            Some("192.0.2.1"),
+            &trusted,
+        );
+        assert_eq!(client, ip("2001:db8::1"));
+
+        // Unreadable hops stop at the proxy that reported them
+        let client = resolve(ip("127.0.0.1"), Some("for=unknown"), None, &trusted);
+        assert_eq!(client, ip("127.0.0.1"));
+        let client = resolve(ip("127.0.0.1"), None, None, &trusted);
+        assert_eq!(client, ip("127.0.0.1"));
+    }
+}
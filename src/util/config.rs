@@ -0,0 +1,2163 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use jsonwebtoken::{Algorithm, DecodingKey};
+use mediasoup::data_structures::TransportListenIp;
+use mediasoup::prelude::TransportListenIps;
+
+use super::client_ip::IpNetwork;
+use super::tls;
+use crate::poll::MAX_POLL_TIMEOUT;
+use crate::rtc::audio_level::{INTERVAL_MS, THRESHOLD_DB};
+use crate::rtc::bitrate::MIN_ENCODING_BITRATE;
+use crate::state::room::RoomSettings;
+use crate::state::user::ProduceType;
+
+const DEFAULT_CONFIG_FILE: &str = "vortex.toml";
+/// Token shown in `vortex.example.toml`, refused so a copied example isn't left open
+const PLACEHOLDER_MANAGE_TOKEN: &str = "change-me";
+/// Tenant of rooms created with `api.manage_tokens`, all of them in single-tenant
+/// deployments
+pub const DEFAULT_TENANT: &str = "default";
+
+lazy_static! {
+    pub static ref CONFIG: Config =
+        Config::load().unwrap_or_else(|err| panic!("Invalid configuration: {}", err));
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Default log filter, `RUST_LOG` takes precedence
+    pub log_level: String,
+    pub api: ApiConfig,
+    pub rtc: RtcConfig,
+    pub turn: Option<TurnConfig>,
+    pub load_shedding: LoadSheddingConfig,
+    pub rooms: RoomsConfig,
+    pub signaling: SignalingConfig,
+    pub quality: QualityConfig,
+    pub reaping: ReapingConfig,
+    pub audio_levels: AudioLevelsConfig,
+    pub shutdown: ShutdownConfig,
+    pub integrations: IntegrationsConfig,
+    pub cluster: Option<ClusterConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub auth: Option<AuthConfig>,
+    pub recording: Option<RecordingConfig>,
+    pub broadcast: Option<BroadcastConfig>,
+    pub hls: Option<HlsConfig>,
+    pub playback: Option<PlaybackConfig>,
+    pub probe: Option<ProbeConfig>,
+    pub event_replay: Option<EventReplayConfig>,
+    pub persistence: Option<PersistenceConfig>,
+    pub audit: Option<AuditConfig>,
+    pub tls: Option<TlsConfig>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    pub telemetry: Option<TelemetryConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Keys accepted by the management API and metrics endpoint, any of them grants access
+    /// to the rooms of the default tenant
+    pub manage_tokens: Vec<String>,
+    /// Seconds a join token from the user creation endpoint can be used for
+    pub join_token_ttl: u64,
+    /// Products sharing the server, each only seeing its own rooms and metrics
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub id: String,
+    /// Management API keys of the tenant, any of them grants access to its rooms
+    pub manage_tokens: Vec<String>,
+}
+
+impl ApiConfig {
+    /// Management tokens along with the tenant each grants access to
+    pub fn tenant_tokens(&self) -> impl Iterator<Item = (&str, &str)> {
+        let default = self
+            .manage_tokens
+            .iter()
+            .map(|token| (DEFAULT_TENANT, token.as_str()));
+        let tenants = self.tenants.iter().flat_map(|tenant| {
+            tenant
+                .manage_tokens
+                .iter()
+                .map(move |token| (tenant.id.as_str(), token.as_str()))
+        });
+        default.chain(tenants)
+    }
+
+    pub fn has_tenant(&self, id: &str) -> bool {
+        id == DEFAULT_TENANT || self.tenants.iter().any(|tenant| tenant.id == id)
+    }
+
+    /// Tenant IDs are part of the scoped room IDs, see `Room::scoped_id`, and a token
+    /// has to tell which tenant a request is for
+    fn validate_tenants(&self) -> Result<(), ConfigError> {
+        let mut ids = HashSet::new();
+        for tenant in &self.tenants {
+            let invalid = |reason: &str| {
+                Err(ConfigError::InvalidTenant(format!(
+                    "tenant \"{}\" {}",
+                    tenant.id, reason
+                )))
+            };
+            if tenant.id.is_empty() || tenant.id.contains('/') {
+                return invalid("must be named without slashes");
+            }
+            if tenant.id == DEFAULT_TENANT || !ids.insert(tenant.id.as_str()) {
+                return invalid("is configured more than once");
+            }
+            if tenant.manage_tokens.iter().all(String::is_empty) {
+                return invalid("has no management tokens");
+            }
+        }
+
+        let mut tokens = HashMap::new();
+        for (tenant, token) in self.tenant_tokens() {
+            if *tokens.entry(token).or_insert(tenant) != tenant {
+                return Err(ConfigError::InvalidTenant(
+                    "a management token is shared by more than one tenant".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtcConfig {
+    pub listen_ips: Vec<ListenIp>,
+    /// Ports the WebRTC transports listen on, one of its own for each. mediasoup 0.8 has
+    /// no WebRtcServer to share a single port between them
+    pub min_port: u16,
+    pub max_port: u16,
+    pub disable_rtp: bool,
+    /// mediasoup worker processes rooms are spread over, each on a core of its own
+    pub workers: usize,
+    pub enable_udp: bool,
+    pub enable_tcp: bool,
+    pub prefer_udp: bool,
+    pub codecs: Vec<CodecConfig>,
+    /// Default cap on the bitrate a participant may send, in bits per second
+    pub max_incoming_bitrate: Option<u32>,
+    /// Default cap on the bitrate of a room's transports together, in and out, in bits
+    /// per second. Rooms over it have their media held back until they're under again
+    pub room_bitrate_budget: Option<u32>,
+    /// Report only the network of the addresses clients connect from in transport stats,
+    /// see `rtc::stats::redact_ip`
+    pub redact_remote_addresses: bool,
+    pub transport_pool: Option<TransportPoolConfig>,
+    /// Seconds the node reports itself unready after a worker ran out of ports or
+    /// memory, 0 to stay ready
+    pub exhausted_unready_secs: u64,
+    /// Seconds consumers resumed before the receiving transport's DTLS connected wait for
+    /// it, before they're closed
+    pub resume_timeout_secs: u64,
+}
+
+/// WebRTC transports kept ready on each room's router, so initializing a connection's
+/// transports doesn't wait for the worker to create them
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportPoolConfig {
+    /// Idle transports kept per router
+    pub size: usize,
+    /// Seconds an idle transport is kept before it's replaced with a fresh one
+    pub max_idle_secs: u64,
+}
+
+impl Default for TransportPoolConfig {
+    fn default() -> Self {
+        TransportPoolConfig {
+            size: 4,
+            max_idle_secs: 300,
+        }
+    }
+}
+
+/// TURN relay using coturn's REST API shared secret authentication
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TurnConfig {
+    pub urls: Vec<String>,
+    pub secret: String,
+    /// Lifetime of generated credentials in seconds
+    #[serde(default = "default_turn_ttl")]
+    pub ttl: u64,
+}
+
+fn default_turn_ttl() -> u64 {
+    86400
+}
+
+/// Identity of this node in a multi-node deployment, enables signed node affinity
+/// tokens so reconnecting clients can be sent back to the node holding their session
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub node_id: String,
+    /// Address clients dial to reach this node directly
+    pub public_url: String,
+    pub affinity_secret: String,
+    /// Lifetime of affinity tokens in seconds
+    #[serde(default = "default_affinity_ttl")]
+    pub affinity_ttl: u64,
+}
+
+fn default_affinity_ttl() -> u64 {
+    60
+}
+
+/// Accept signed JWTs in `Authenticate`, creating the user from its claims
+/// instead of requiring a token registered over the API
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub algorithm: Algorithm,
+    /// Shared secret for HMAC algorithms, PEM encoded public key otherwise
+    pub key: String,
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+}
+
+/// Where tokens presented to `Authenticate` are checked, locally when this isn't set. See
+/// `ws::auth`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub backend: AuthBackend,
+    /// Endpoint tokens are posted to, for the `http` backend. Plain HTTP only
+    #[serde(default)]
+    pub url: String,
+    /// How long the endpoint has to answer before it counts as down
+    #[serde(default = "default_auth_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Seconds an answer is reused for the same token and room, 0 asks every time
+    #[serde(default = "default_auth_cache_secs")]
+    pub cache_secs: u64,
+}
+
+fn default_auth_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_auth_cache_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    /// JWTs and join tokens from the management API, as without `auth`
+    Local,
+    Http,
+}
+
+/// Server-side recording, rooms can only be recorded when this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Recordings are written to `<directory>/<room>/<start time>/`
+    pub directory: String,
+    /// FFmpeg binary writing the files
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+}
+
+fn default_ffmpeg() -> String {
+    "ffmpeg".to_string()
+}
+
+/// Live broadcasting to external ingests, rooms can only be broadcast when this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastConfig {
+    /// FFmpeg binary pushing media to the ingest
+    pub ffmpeg: String,
+    /// URL schemes broadcasts may target, out of `rtmp`, `rtmps` and `srt`
+    pub allowed_schemes: Vec<String>,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig {
+            ffmpeg: default_ffmpeg(),
+            allowed_schemes: vec!["rtmp".to_string(), "rtmps".to_string(), "srt".to_string()],
+        }
+    }
+}
+
+/// HLS packaging for passive audiences, rooms can only enable HLS when this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HlsConfig {
+    /// Playlists and segments are written to `<directory>/<room>/`
+    pub directory: String,
+    /// FFmpeg binary packaging the segments
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+    /// Target segment duration in seconds
+    #[serde(default = "default_hls_segment_secs")]
+    pub segment_secs: u32,
+    /// Segments kept in the playlist, older ones are deleted from disk
+    #[serde(default = "default_hls_playlist_size")]
+    pub playlist_size: u32,
+}
+
+/// Audio played into rooms over the API, rooms can only play audio when this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// Files that can be played by name, e.g. chimes
+    pub directory: String,
+    /// FFmpeg binary encoding the audio
+    #[serde(default = "default_ffmpeg")]
+    pub ffmpeg: String,
+    /// Largest audio that can be uploaded with a play request, in bytes
+    #[serde(default = "default_playback_max_upload_bytes")]
+    pub max_upload_bytes: usize,
+}
+
+/// Transports clients may connect to without joining a room, to check that WebRTC works
+/// where they are. Served without a management token when this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Probes running at once across the node, further ones are refused
+    pub max_probes: usize,
+    /// Probes a single address may have running at once, so one client can't take all
+    /// of them
+    pub max_probes_per_ip: usize,
+    /// Seconds a probe may wait for its client to connect before it's closed
+    pub timeout_secs: u64,
+}
+
+/// Recent membership and moderation events kept by each room, for moderators joining
+/// late to catch up on with `GetRecentEvents`. Rooms keep none unless this is set
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventReplayConfig {
+    /// Events kept per room, the oldest are dropped past it
+    pub max_events: usize,
+    /// Seconds an event is kept for, a day at most
+    pub retention_secs: u64,
+}
+
+/// Keep rooms and unredeemed join tokens across restarts, in a JSON file
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PersistenceConfig {
+    pub path: String,
+}
+
+/// Record who joined and left each room and every moderation action, one JSON object per line
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub sink: AuditSinkKind,
+    /// File the records are appended to, for the `file` sink
+    #[serde(default)]
+    pub path: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditSinkKind {
+    File,
+    Stdout,
+}
+
+/// Serve HTTPS and `wss://` directly, for deployments without a reverse proxy in front
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the server's own certificate first
+    pub cert_path: String,
+    /// PEM private key of the certificate, PKCS#8 or RSA
+    pub key_path: String,
+}
+
+/// Serve on a Unix domain socket, for a reverse proxy on the same host. Who may connect is
+/// up to the socket file's permissions
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    pub path: String,
+    /// Permissions of the socket file in octal, e.g. "660"
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Owner and group of the socket file, left as the server's when absent
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Keep serving on `HTTP_HOST` as well
+    #[serde(default)]
+    pub tcp: bool,
+}
+
+/// Export spans to an OpenTelemetry collector over OTLP, in builds with the `otel` feature
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// gRPC endpoint of the collector
+    pub endpoint: String,
+    pub service_name: String,
+    /// Share of the traces started by the server that are exported. Those continuing a
+    /// management API request's trace follow its sampling decision
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            endpoint: "http://localhost:4317".to_string(),
+            service_name: "vortex".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+fn default_playback_max_upload_bytes() -> usize {
+    5 * 1024 * 1024
+}
+
+fn default_hls_segment_secs() -> u32 {
+    4
+}
+
+fn default_hls_playlist_size() -> u32 {
+    6
+}
+
+/// Node CPU usage above which expensive read commands are served from cache
+/// (`soft_cpu`) or rejected (`hard_cpu`), reloaded on SIGHUP
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    pub soft_cpu: f64,
+    pub hard_cpu: f64,
+    pub retry_after_ms: u64,
+}
+
+/// Lifecycle of the rooms created over the API
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Seconds an empty room is kept before it's closed, 0 keeps empty rooms open.
+    /// Persistent rooms are never closed this way
+    pub idle_timeout: u64,
+    /// Ceilings rooms pick by name with their `qualityProfile` setting
+    pub profiles: HashMap<String, QualityProfile>,
+    /// Room settings in their API form that rooms are created from by name, the fields
+    /// of the creation request replacing the template's
+    pub templates: HashMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Ceilings on the media of a room, by kind. Nothing is capped where they're absent
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct QualityProfile {
+    pub audio: MediaCeiling,
+    pub video: MediaCeiling,
+    /// Applies to screenshare audio and screenshare video separately
+    pub screenshare: MediaCeiling,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct MediaCeiling {
+    /// Bits per second a single producer may send, its encodings are clamped under it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bitrate: Option<u32>,
+    /// RTP parameters don't carry resolutions or frame rates, these are only passed on
+    /// for clients to configure their encoders with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_framerate: Option<u32>,
+    /// Highest spatial layer forwarded to consumers, 0 being the lowest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_spatial_layer: Option<u8>,
+}
+
+impl QualityProfile {
+    pub fn get(&self, produce_type: ProduceType) -> &MediaCeiling {
+        match produce_type {
+            ProduceType::Audio => &self.audio,
+            ProduceType::Video => &self.video,
+            ProduceType::ScreenshareAudio | ProduceType::ScreenshareVideo => &self.screenshare,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        [&self.audio, &self.video, &self.screenshare]
+            .iter()
+            .all(|ceiling| {
+                ceiling
+                    .max_bitrate
+                    .map_or(true, |bitrate| bitrate >= MIN_ENCODING_BITRATE)
+                    && ceiling.max_height != Some(0)
+                    && ceiling.max_framerate != Some(0)
+            })
+    }
+}
+
+/// Limits on the commands of a single signaling connection
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalingConfig {
+    /// Commands a connection may have waiting to run, more are refused with
+    /// `TooManyRequests`. A batch only runs if all of its commands fit
+    pub max_pending_commands: usize,
+    /// Seconds between pings on WebSocket connections, 0 sends none
+    pub ping_interval: u64,
+    /// Pings in a row a client may leave unanswered before it's disconnected
+    pub max_missed_pongs: u32,
+    /// Seconds a new connection has to authenticate, then as many to initialize its
+    /// transports, before it's closed with `HandshakeTimeout`. 0 waits forever
+    pub handshake_timeout: u64,
+    /// Bytes an inbound WebSocket message or long poll body may take. Larger ones aren't
+    /// buffered, the connection is closed with `InvalidData` or the body refused with 413
+    pub max_message_size: usize,
+    /// Bytes the `data` of a single command may take once serialized, such as the RTP
+    /// parameters of `StartProduce`. Larger commands are refused with `CommandTooLarge`
+    pub max_command_size: usize,
+    /// Bytes the payload of `RelayBroadcast` and `RelayDirect` may take once serialized
+    pub max_relay_payload: usize,
+    /// Bytes a blob relayed with `RelayBlobStart` may take, decoded
+    pub max_blob_size: usize,
+    /// Blob transfers a user may be sending at once in a room, 0 turns blobs off
+    pub max_blob_transfers: usize,
+    /// Seconds a user whose connection dropped is kept for the client to resume the
+    /// session, before it leaves the room. 0 removes it right away
+    pub reconnect_grace: u64,
+    /// Seconds a long-poll session is kept without a request from its client, before its
+    /// connection is closed like a dropped WebSocket. Above the longest a poll may wait
+    pub poll_session_timeout: u64,
+    /// WebSocket connections a single address may have open at once, further upgrades
+    /// are refused with `TooManyConnections`. 0 doesn't limit them
+    pub max_connections_per_ip: usize,
+    /// Reverse proxies, as addresses or CIDR ranges, whose `Forwarded` and `X-Forwarded-For`
+    /// headers are believed to tell the client's address. Everyone else's are ignored
+    pub trusted_proxies: Vec<String>,
+    /// Origins of the pages browsers may open WebSocket connections from, such as
+    /// `https://app.example.com` or `https://*.example.com`. Upgrades from others are
+    /// refused with 403, any origin may connect if it's empty
+    pub allowed_origins: Vec<String>,
+    /// Close sessions with `TokenExpired` once the token they authenticated or last
+    /// refreshed with expires, rather than letting them outlive it
+    pub enforce_token_expiry: bool,
+    /// Seconds before its token expires that an enforced session is sent `tokenExpiring`,
+    /// for the client to refresh it
+    pub token_expiry_warning: u64,
+    /// Consumers a producer has to gain or lose before its user is sent `producerAudience`,
+    /// 0 sends none
+    pub audience_delta: usize,
+    /// Seconds before a session reaches its join token's `maxSessionSecs` or its room's
+    /// `maxDurationSecs` that it's sent `sessionExpiring`, once for each
+    pub session_expiry_warnings: Vec<u64>,
+    /// Membership events a connection is sent each `event_batch_ms` as they come. Past
+    /// them, the rest of the burst is sent in a single frame at the end of the tick to
+    /// clients that declared `eventBatches`. 0 batches none
+    pub event_batch_threshold: usize,
+    pub event_batch_ms: u64,
+}
+
+/// Coarse connection quality reported to the room, sampled from each connection's
+/// transport and producer stats
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityConfig {
+    /// Seconds between samples of a connection, 0 samples none
+    pub interval: u64,
+    /// Fraction of packets lost on the user's producers from which its quality is
+    /// `ok`, and `bad`
+    pub ok_loss: f64,
+    pub bad_loss: f64,
+    /// Round trip times in milliseconds from which the quality is `ok`, and `bad`
+    pub ok_rtt_ms: f64,
+    pub bad_rtt_ms: f64,
+    /// Bandwidth estimated towards the client as a fraction of what's being sent to it,
+    /// below which the quality is `ok`, and `bad`
+    pub ok_bitrate_ratio: f64,
+    pub bad_bitrate_ratio: f64,
+}
+
+/// Closing consumers that sent nothing for a while, which their client most likely
+/// forgot about
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReapingConfig {
+    /// Seconds between counts of the packets each consumer sent, 0 reaps none
+    pub interval: u64,
+    /// Seconds a consumer that isn't paused may send nothing before it's closed
+    pub inactive_after: u64,
+}
+
+/// Speaking detection of rooms that don't set their own, see `audioLevelThresholdDb` and
+/// `audioLevelIntervalMs` in the room settings
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioLevelsConfig {
+    /// Volume in dBov from which a user counts as speaking, from -127 to 0
+    pub threshold_db: i8,
+    /// Milliseconds over which volumes are averaged before speakers are announced,
+    /// from 100 to 10000
+    pub interval_ms: u16,
+}
+
+/// Graceful shutdown on SIGTERM or Ctrl-C
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds the node keeps serving while `/health` reports it isn't ready,
+    /// giving load balancers time to move traffic away before it exits
+    pub drain_secs: u64,
+}
+
+/// Room events mirrored to webhooks and Redis, whose endpoints come from `WEBHOOK_URL`
+/// and `REDIS_URI`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegrationsConfig {
+    /// Payload format of webhook requests, `json`, `cloudevents` or one registered with
+    /// `ServerBuilder::formatter`
+    pub webhook_format: String,
+    /// Payload format of the events published to Redis
+    pub redis_format: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenIp {
+    pub ip: IpAddr,
+    pub announced_ip: Option<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(tag = "codec", rename_all = "lowercase", deny_unknown_fields)]
+pub enum CodecConfig {
+    Opus {
+        channels: u8,
+    },
+    Vp8,
+    /// Carries SVC layers in a single stream
+    #[serde(rename_all = "camelCase")]
+    Vp9 {
+        /// 0, or 2 for 10 and 12 bit color
+        #[serde(default)]
+        profile_id: u8,
+    },
+    /// Carries SVC layers in a single stream
+    Av1,
+    /// For clients decoding video in hardware
+    #[serde(rename_all = "camelCase")]
+    H264 {
+        /// Six hex digits, constrained baseline level 3.1 by default
+        #[serde(default = "default_profile_level_id")]
+        profile_level_id: String,
+        /// 0 or 1
+        #[serde(default = "default_packetization_mode")]
+        packetization_mode: u8,
+    },
+}
+
+fn default_profile_level_id() -> String {
+    "42e01f".to_string()
+}
+
+fn default_packetization_mode() -> u8 {
+    1
+}
+
+/// Check a list of codecs offered by a router, from the configuration or a room's settings
+pub fn validate_codecs(codecs: &[CodecConfig]) -> Result<(), ConfigError> {
+    if !codecs
+        .iter()
+        .any(|codec| matches!(codec, CodecConfig::Opus { .. }))
+    {
+        return Err(ConfigError::NoAudioCodec);
+    }
+
+    for codec in codecs.iter() {
+        match codec {
+            CodecConfig::Opus { channels } if !(1..=2).contains(channels) => {
+                return Err(ConfigError::InvalidChannels(*channels));
+            }
+            CodecConfig::H264 {
+                profile_level_id, ..
+            } if profile_level_id.len() != 6
+                || !profile_level_id.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                return Err(ConfigError::InvalidProfileLevelId(profile_level_id.clone()));
+            }
+            CodecConfig::H264 {
+                packetization_mode, ..
+            } if *packetization_mode > 1 => {
+                return Err(ConfigError::InvalidPacketizationMode(*packetization_mode));
+            }
+            CodecConfig::Vp9 { profile_id } if ![0, 2].contains(profile_id) => {
+                return Err(ConfigError::InvalidVp9Profile(*profile_id));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, String),
+    Parse(String, String),
+    InvalidVariable(&'static str, String),
+    NoManageTokens,
+    PlaceholderManageToken,
+    NoListenIps,
+    DuplicateListenIp(IpAddr),
+    MismatchedAnnouncedIp(IpAddr, IpAddr),
+    UnbindableListenIp(IpAddr, String),
+    InvalidPortRange(u16, u16),
+    NoWorkers,
+    InvalidTransportPool,
+    NoAudioCodec,
+    InvalidChannels(u8),
+    InvalidProfileLevelId(String),
+    InvalidPacketizationMode(u8),
+    InvalidVp9Profile(u8),
+    InvalidQualityProfile(String),
+    /// Name of the template and what's wrong with it
+    InvalidRoomTemplate(String, String),
+    IncompleteTurn,
+    InvalidTurnTtl,
+    InvalidJoinTokenTtl,
+    InvalidTenant(String),
+    InvalidLoadThresholds(f64, f64),
+    InvalidQualityThresholds,
+    InvalidAudioLevels,
+    InvalidReaping,
+    NoPendingCommands,
+    NoMissedPongs,
+    InvalidPollSessionTimeout,
+    InvalidSizeLimits,
+    InvalidTrustedProxy(String),
+    InvalidAllowedOrigin(String),
+    IncompleteCluster,
+    InvalidJwtKey(String),
+    IncompleteRecording,
+    IncompleteBroadcast,
+    IncompleteHls,
+    IncompletePlayback,
+    InvalidProbe,
+    InvalidEventReplay,
+    IncompletePersistence,
+    IncompleteAuth,
+    IncompleteAudit,
+    IncompleteTls,
+    InvalidTls(String),
+    IncompleteUnixSocket,
+    InvalidSocketMode(String),
+    InvalidHlsRetention,
+    InvalidBroadcastScheme(String),
+    IncompleteTelemetry,
+    InvalidSampleRatio(f64),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "Failed to read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "Failed to parse {}: {}", path, err),
+            ConfigError::InvalidVariable(name, err) => {
+                write!(f, "{} environment variable is invalid: {}", name, err)
+            }
+            ConfigError::NoManageTokens => write!(
+                f,
+                "No management tokens configured, set api.manage_tokens or MANAGE_TOKEN"
+            ),
+            ConfigError::PlaceholderManageToken => write!(
+                f,
+                "Management token \"{}\" from the example configuration must be replaced",
+                PLACEHOLDER_MANAGE_TOKEN
+            ),
+            ConfigError::NoListenIps => write!(
+                f,
+                "No RTC listen IPs configured, set rtc.listen_ips or RTC_IPS"
+            ),
+            ConfigError::DuplicateListenIp(ip) => {
+                write!(f, "RTC listen IP {} is configured more than once", ip)
+            }
+            ConfigError::MismatchedAnnouncedIp(ip, announced_ip) => write!(
+                f,
+                "RTC listen IP {} cannot be announced as {}, both must be IPv4 or IPv6",
+                ip, announced_ip
+            ),
+            ConfigError::UnbindableListenIp(ip, err) => write!(
+                f,
+                "RTC listen IP {} cannot be bound, check that IPv6 is enabled on this host: {}",
+                ip, err
+            ),
+            ConfigError::InvalidPortRange(min, max) => write!(
+                f,
+                "RTC port range is empty, min port {} is above max port {}",
+                min, max
+            ),
+            ConfigError::NoWorkers => write!(f, "At least one RTC worker is required"),
+            ConfigError::InvalidTransportPool => write!(
+                f,
+                "Transport pool size and idle time must be above zero"
+            ),
+            ConfigError::NoAudioCodec => write!(f, "At least one audio codec must be enabled"),
+            ConfigError::InvalidChannels(channels) => {
+                write!(f, "Opus codec cannot have {} channels", channels)
+            }
+            ConfigError::InvalidProfileLevelId(id) => write!(
+                f,
+                "H264 profile level ID must be six hex digits, got {}",
+                id
+            ),
+            ConfigError::InvalidPacketizationMode(mode) => {
+                write!(f, "H264 packetization mode must be 0 or 1, got {}", mode)
+            }
+            ConfigError::InvalidVp9Profile(profile) => {
+                write!(f, "VP9 profile ID must be 0 or 2, got {}", profile)
+            }
+            ConfigError::InvalidQualityProfile(name) => write!(
+                f,
+                "Quality profile {} must cap bitrates at 30000 bits per second or more, heights and frame rates above zero",
+                name
+            ),
+            ConfigError::InvalidRoomTemplate(name, err) => {
+                write!(f, "Invalid room template {}: {}", name, err)
+            }
+            ConfigError::IncompleteTurn => write!(
+                f,
+                "TURN requires both URLs and a secret, set turn.urls and turn.secret or TURN_URLS and TURN_SECRET"
+            ),
+            ConfigError::InvalidTurnTtl => write!(f, "TURN credential TTL must be above zero"),
+            ConfigError::InvalidJoinTokenTtl => {
+                write!(f, "Join token TTL must be above zero, set api.join_token_ttl")
+            }
+            ConfigError::InvalidTenant(err) => write!(f, "Invalid api.tenants: {}", err),
+            ConfigError::InvalidLoadThresholds(soft, hard) => write!(
+                f,
+                "Load shedding thresholds must satisfy 0 < soft ({}) <= hard ({}) <= 1",
+                soft, hard
+            ),
+            ConfigError::InvalidQualityThresholds => write!(
+                f,
+                "Quality thresholds must not be negative, with each ok threshold below its bad one and quality.bad_bitrate_ratio at most quality.ok_bitrate_ratio"
+            ),
+            ConfigError::InvalidAudioLevels => write!(
+                f,
+                "audio_levels.threshold_db must be from -127 to 0 and audio_levels.interval_ms from 100 to 10000"
+            ),
+            ConfigError::InvalidReaping => write!(
+                f,
+                "reaping.inactive_after must be at least reaping.interval"
+            ),
+            ConfigError::NoPendingCommands => write!(
+                f,
+                "Connections must be allowed at least one pending command, raise signaling.max_pending_commands"
+            ),
+            ConfigError::NoMissedPongs => write!(
+                f,
+                "Clients must be allowed to miss at least one pong, raise signaling.max_missed_pongs"
+            ),
+            ConfigError::InvalidPollSessionTimeout => write!(
+                f,
+                "signaling.poll_session_timeout must be above {} seconds, the longest a poll waits",
+                MAX_POLL_TIMEOUT
+            ),
+            ConfigError::InvalidSizeLimits => write!(
+                f,
+                "Signaling size limits must be positive, with signaling.max_relay_payload at most signaling.max_command_size and that at most signaling.max_message_size"
+            ),
+            ConfigError::InvalidTrustedProxy(err) => {
+                write!(f, "Invalid signaling.trusted_proxies entry: {}", err)
+            }
+            ConfigError::InvalidAllowedOrigin(origin) => write!(
+                f,
+                "Invalid signaling.allowed_origins entry {}, expected an origin like https://app.example.com",
+                origin
+            ),
+            ConfigError::InvalidJwtKey(err) => write!(f, "Invalid JWT key: {}", err),
+            ConfigError::IncompleteCluster => write!(
+                f,
+                "Cluster requires a node ID, public URL and affinity secret"
+            ),
+            ConfigError::IncompleteRecording => write!(
+                f,
+                "Recording requires a directory and an FFmpeg binary, set recording.directory or RECORDING_DIR"
+            ),
+            ConfigError::IncompleteBroadcast => {
+                write!(f, "Broadcasting requires an FFmpeg binary, set broadcast.ffmpeg")
+            }
+            ConfigError::IncompleteHls => write!(
+                f,
+                "HLS requires a directory and an FFmpeg binary, set hls.directory or HLS_DIR"
+            ),
+            ConfigError::IncompletePlayback => write!(
+                f,
+                "Playback requires a directory and an FFmpeg binary, set playback.directory"
+            ),
+            ConfigError::InvalidProbe => write!(
+                f,
+                "probe.max_probes, probe.max_probes_per_ip and probe.timeout_secs must be above zero"
+            ),
+            ConfigError::InvalidEventReplay => write!(
+                f,
+                "event_replay.max_events must be above zero and event_replay.retention_secs between 1 and 86400"
+            ),
+            ConfigError::IncompletePersistence => {
+                write!(f, "Persistence requires a file, set persistence.path")
+            }
+            ConfigError::IncompleteAuth => write!(
+                f,
+                "The http authentication backend requires an http:// endpoint and a timeout, set auth.url and auth.timeout_ms"
+            ),
+            ConfigError::IncompleteAudit => {
+                write!(f, "The file audit sink requires a file, set audit.path")
+            }
+            ConfigError::IncompleteTls => write!(
+                f,
+                "TLS requires a certificate and a key, set tls.cert_path and tls.key_path"
+            ),
+            ConfigError::InvalidTls(err) => write!(f, "Invalid TLS certificate or key: {}", err),
+            ConfigError::IncompleteUnixSocket => {
+                write!(f, "The Unix socket listener requires a path, set unix_socket.path")
+            }
+            ConfigError::InvalidSocketMode(mode) => write!(
+                f,
+                "Socket mode \"{}\" is not octal permissions like \"660\"",
+                mode
+            ),
+            ConfigError::InvalidHlsRetention => write!(
+                f,
+                "HLS segment duration and playlist size must be above zero"
+            ),
+            ConfigError::InvalidBroadcastScheme(scheme) => write!(
+                f,
+                "Broadcast scheme \"{}\" is not supported, use rtmp, rtmps or srt",
+                scheme
+            ),
+            ConfigError::IncompleteTelemetry => {
+                write!(f, "Trace export requires a collector, set telemetry.endpoint")
+            }
+            ConfigError::InvalidSampleRatio(ratio) => write!(
+                f,
+                "Trace sample ratio {} is not between 0 and 1",
+                ratio
+            ),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: "info".to_string(),
+            api: ApiConfig::default(),
+            rtc: RtcConfig::default(),
+            turn: None,
+            load_shedding: LoadSheddingConfig::default(),
+            rooms: RoomsConfig::default(),
+            signaling: SignalingConfig::default(),
+            quality: QualityConfig::default(),
+            reaping: ReapingConfig::default(),
+            audio_levels: AudioLevelsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            integrations: IntegrationsConfig::default(),
+            cluster: None,
+            jwt: None,
+            auth: None,
+            recording: None,
+            broadcast: None,
+            hls: None,
+            playback: None,
+            probe: None,
+            event_replay: None,
+            persistence: None,
+            audit: None,
+            tls: None,
+            unix_socket: None,
+            telemetry: None,
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            manage_tokens: Vec::new(),
+            join_token_ttl: 600,
+            tenants: Vec::new(),
+        }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            max_probes: 20,
+            max_probes_per_ip: 2,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for EventReplayConfig {
+    fn default() -> Self {
+        EventReplayConfig {
+            max_events: 100,
+            retention_secs: 600,
+        }
+    }
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig {
+            soft_cpu: 0.75,
+            hard_cpu: 0.9,
+            retry_after_ms: 5000,
+        }
+    }
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        RoomsConfig {
+            idle_timeout: 300,
+            profiles: default_profiles(),
+            templates: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_secs: 10 }
+    }
+}
+
+impl Default for IntegrationsConfig {
+    fn default() -> Self {
+        IntegrationsConfig {
+            webhook_format: "json".to_string(),
+            redis_format: "json".to_string(),
+        }
+    }
+}
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        SignalingConfig {
+            max_pending_commands: 128,
+            ping_interval: 15,
+            max_missed_pongs: 2,
+            handshake_timeout: 10,
+            max_message_size: 64 * 1024,
+            max_command_size: 16 * 1024,
+            max_relay_payload: 4096,
+            max_blob_size: 1024 * 1024,
+            max_blob_transfers: 2,
+            reconnect_grace: 0,
+            poll_session_timeout: 60,
+            max_connections_per_ip: 100,
+            trusted_proxies: Vec::new(),
+            allowed_origins: Vec::new(),
+            enforce_token_expiry: false,
+            token_expiry_warning: 60,
+            audience_delta: 0,
+            session_expiry_warnings: vec![300, 60],
+            event_batch_threshold: 20,
+            event_batch_ms: 100,
+        }
+    }
+}
+
+impl Default for ReapingConfig {
+    fn default() -> Self {
+        ReapingConfig {
+            interval: 60,
+            inactive_after: 300,
+        }
+    }
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        QualityConfig {
+            interval: 5,
+            ok_loss: 0.02,
+            bad_loss: 0.1,
+            ok_rtt_ms: 250.0,
+            bad_rtt_ms: 500.0,
+            ok_bitrate_ratio: 0.9,
+            bad_bitrate_ratio: 0.6,
+        }
+    }
+}
+
+impl Default for AudioLevelsConfig {
+    fn default() -> Self {
+        AudioLevelsConfig {
+            threshold_db: -70,
+            interval_ms: 800,
+        }
+    }
+}
+
+impl QualityConfig {
+    pub fn interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.interval)).filter(|interval| !interval.is_zero())
+    }
+}
+
+impl ReapingConfig {
+    pub fn interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.interval)).filter(|interval| !interval.is_zero())
+    }
+
+    pub fn inactive_after(&self) -> Duration {
+        Duration::from_secs(self.inactive_after)
+    }
+}
+
+impl SignalingConfig {
+    pub fn poll_session_timeout(&self) -> Duration {
+        Duration::from_secs(self.poll_session_timeout)
+    }
+
+    pub fn ping_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.ping_interval)).filter(|interval| !interval.is_zero())
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.handshake_timeout)).filter(|timeout| !timeout.is_zero())
+    }
+
+    pub fn reconnect_grace(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.reconnect_grace)).filter(|grace| !grace.is_zero())
+    }
+}
+
+/// Profiles offered unless the configuration lists its own
+fn default_profiles() -> HashMap<String, QualityProfile> {
+    let profile =
+        |audio, video: (u32, u32, u32, Option<u8>), screenshare: (u32, u32)| QualityProfile {
+            audio: MediaCeiling {
+                max_bitrate: audio,
+                ..MediaCeiling::default()
+            },
+            video: MediaCeiling {
+                max_bitrate: Some(video.0),
+                max_height: Some(video.1),
+                max_framerate: Some(video.2),
+                max_spatial_layer: video.3,
+            },
+            screenshare: MediaCeiling {
+                max_bitrate: Some(screenshare.0),
+                max_framerate: Some(screenshare.1),
+                ..MediaCeiling::default()
+            },
+        };
+
+    let mut profiles = HashMap::new();
+    profiles.insert(
+        "low".to_string(),
+        profile(Some(32_000), (300_000, 360, 15, Some(0)), (500_000, 5)),
+    );
+    profiles.insert(
+        "standard".to_string(),
+        profile(None, (1_000_000, 720, 30, Some(1)), (1_500_000, 15)),
+    );
+    profiles.insert(
+        "high".to_string(),
+        profile(None, (2_500_000, 1080, 30, None), (3_000_000, 30)),
+    );
+    profiles
+}
+
+impl RoomsConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.idle_timeout)).filter(|timeout| !timeout.is_zero())
+    }
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        RtcConfig {
+            listen_ips: Vec::new(),
+            min_port: 10000,
+            max_port: 11000,
+            disable_rtp: false,
+            workers: 1,
+            enable_udp: true,
+            enable_tcp: true,
+            prefer_udp: true,
+            codecs: vec![CodecConfig::Opus { channels: 2 }],
+            max_incoming_bitrate: None,
+            room_bitrate_budget: None,
+            redact_remote_addresses: false,
+            transport_pool: None,
+            exhausted_unready_secs: 30,
+            resume_timeout_secs: 15,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration file named by `CONFIG_FILE` (or `vortex.toml` if present),
+    /// apply environment overrides and validate the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => Config::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_string(), err.to_string()))?;
+        toml::from_str(&contents)
+            .map_err(|err| ConfigError::Parse(path.to_string(), err.to_string()))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Ok(level) = env::var("LOG_LEVEL") {
+            self.log_level = level;
+        }
+
+        if let Ok(tokens) = env::var("MANAGE_TOKEN") {
+            self.api.manage_tokens.extend(
+                tokens
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|token| !token.is_empty())
+                    .map(str::to_string),
+            );
+        }
+
+        if let Ok(ttl) = env::var("JOIN_TOKEN_TTL") {
+            self.api.join_token_ttl = parse_variable("JOIN_TOKEN_TTL", &ttl)?;
+        }
+
+        if let Ok(timeout) = env::var("POLL_SESSION_TIMEOUT") {
+            self.signaling.poll_session_timeout = parse_variable("POLL_SESSION_TIMEOUT", &timeout)?;
+        }
+
+        if let Ok(ip_list) = env::var("RTC_IPS") {
+            self.rtc.listen_ips = parse_ip_list(&ip_list)?;
+        }
+
+        if let Ok(port) = env::var("RTC_MIN_PORT") {
+            self.rtc.min_port = parse_variable("RTC_MIN_PORT", &port)?;
+        }
+
+        if let Ok(port) = env::var("RTC_MAX_PORT") {
+            self.rtc.max_port = parse_variable("RTC_MAX_PORT", &port)?;
+        }
+
+        if let Ok(workers) = env::var("RTC_WORKERS") {
+            self.rtc.workers = parse_variable("RTC_WORKERS", &workers)?;
+        }
+
+        if let Ok(bitrate) = env::var("RTC_MAX_INCOMING_BITRATE") {
+            self.rtc.max_incoming_bitrate =
+                Some(parse_variable("RTC_MAX_INCOMING_BITRATE", &bitrate)?);
+        }
+
+        if let Ok(budget) = env::var("RTC_ROOM_BITRATE_BUDGET") {
+            self.rtc.room_bitrate_budget =
+                Some(parse_variable("RTC_ROOM_BITRATE_BUDGET", &budget)?);
+        }
+
+        if let Ok(timeout) = env::var("ROOM_IDLE_TIMEOUT") {
+            self.rooms.idle_timeout = parse_variable("ROOM_IDLE_TIMEOUT", &timeout)?;
+        }
+
+        if let Ok(drain) = env::var("SHUTDOWN_DRAIN_SECS") {
+            self.shutdown.drain_secs = parse_variable("SHUTDOWN_DRAIN_SECS", &drain)?;
+        }
+
+        if let Ok(format) = env::var("WEBHOOK_FORMAT") {
+            self.integrations.webhook_format = format;
+        }
+
+        if let Ok(format) = env::var("REDIS_FORMAT") {
+            self.integrations.redis_format = format;
+        }
+
+        if let Ok(disable_rtp) = env::var("DISABLE_RTP") {
+            self.rtc.disable_rtp = disable_rtp == "1";
+        }
+
+        if let Ok(redact) = env::var("RTC_REDACT_REMOTE_ADDRESSES") {
+            self.rtc.redact_remote_addresses = redact == "1";
+        }
+
+        let turn_urls = env::var("TURN_URLS").ok();
+        let turn_secret = env::var("TURN_SECRET").ok();
+        if turn_urls.is_some() || turn_secret.is_some() {
+            let turn = self.turn.get_or_insert_with(|| TurnConfig {
+                urls: Vec::new(),
+                secret: String::new(),
+                ttl: default_turn_ttl(),
+            });
+
+            if let Some(urls) = turn_urls {
+                turn.urls = urls
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+
+            if let Some(secret) = turn_secret {
+                turn.secret = secret;
+            }
+        }
+
+        if let Ok(directory) = env::var("RECORDING_DIR") {
+            let recording = self.recording.get_or_insert_with(|| RecordingConfig {
+                directory: String::new(),
+                ffmpeg: default_ffmpeg(),
+            });
+            recording.directory = directory;
+        }
+
+        if let Ok(directory) = env::var("HLS_DIR") {
+            let hls = self.hls.get_or_insert_with(|| HlsConfig {
+                directory: String::new(),
+                ffmpeg: default_ffmpeg(),
+                segment_secs: default_hls_segment_secs(),
+                playlist_size: default_hls_playlist_size(),
+            });
+            hls.directory = directory;
+        }
+
+        if let Ok(ttl) = env::var("TURN_TTL") {
+            if let Some(turn) = &mut self.turn {
+                turn.ttl = parse_variable("TURN_TTL", &ttl)?;
+            }
+        }
+
+        // The standard OpenTelemetry variables, the endpoint alone enables the export
+        if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry
+                .get_or_insert_with(TelemetryConfig::default)
+                .endpoint = endpoint;
+        }
+
+        if let Some(telemetry) = &mut self.telemetry {
+            if let Ok(name) = env::var("OTEL_SERVICE_NAME") {
+                telemetry.service_name = name;
+            }
+            if let Ok(ratio) = env::var("OTEL_TRACES_SAMPLER_ARG") {
+                telemetry.sample_ratio = parse_variable("OTEL_TRACES_SAMPLER_ARG", &ratio)?;
+            }
+        }
+
+        if env::var("OTEL_SDK_DISABLED").map_or(false, |disabled| disabled == "true") {
+            self.telemetry = None;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.api.manage_tokens.iter().all(String::is_empty) {
+            return Err(ConfigError::NoManageTokens);
+        }
+
+        if self
+            .api
+            .tenant_tokens()
+            .any(|(_, token)| token == PLACEHOLDER_MANAGE_TOKEN)
+        {
+            return Err(ConfigError::PlaceholderManageToken);
+        }
+
+        if self.api.join_token_ttl == 0 {
+            return Err(ConfigError::InvalidJoinTokenTtl);
+        }
+
+        self.api.validate_tenants()?;
+
+        let rtc = &self.rtc;
+        if rtc.listen_ips.is_empty() {
+            return Err(ConfigError::NoListenIps);
+        }
+
+        rtc.validate_listen_ips()?;
+
+        if rtc.min_port > rtc.max_port {
+            return Err(ConfigError::InvalidPortRange(rtc.min_port, rtc.max_port));
+        }
+
+        if rtc.workers == 0 {
+            return Err(ConfigError::NoWorkers);
+        }
+
+        if let Some(pool) = &rtc.transport_pool {
+            if pool.size == 0 || pool.max_idle_secs == 0 {
+                return Err(ConfigError::InvalidTransportPool);
+            }
+        }
+
+        validate_codecs(&rtc.codecs)?;
+
+        if let Some(turn) = &self.turn {
+            if turn.urls.is_empty() || turn.secret.is_empty() {
+                return Err(ConfigError::IncompleteTurn);
+            }
+
+            if turn.ttl == 0 {
+                return Err(ConfigError::InvalidTurnTtl);
+            }
+        }
+
+        let load = &self.load_shedding;
+        if !(load.soft_cpu > 0.0 && load.soft_cpu <= load.hard_cpu && load.hard_cpu <= 1.0) {
+            return Err(ConfigError::InvalidLoadThresholds(
+                load.soft_cpu,
+                load.hard_cpu,
+            ));
+        }
+
+        let quality = &self.quality;
+        if !(quality.ok_loss >= 0.0
+            && quality.ok_loss <= quality.bad_loss
+            && quality.ok_rtt_ms >= 0.0
+            && quality.ok_rtt_ms <= quality.bad_rtt_ms
+            && quality.bad_bitrate_ratio >= 0.0
+            && quality.bad_bitrate_ratio <= quality.ok_bitrate_ratio)
+        {
+            return Err(ConfigError::InvalidQualityThresholds);
+        }
+
+        if let Some((name, _)) = self
+            .rooms
+            .profiles
+            .iter()
+            .find(|(_, profile)| !profile.is_valid())
+        {
+            return Err(ConfigError::InvalidQualityProfile(name.clone()));
+        }
+
+        for (name, template) in &self.rooms.templates {
+            RoomSettings::check_template(template, &self.rooms.profiles)
+                .map_err(|err| ConfigError::InvalidRoomTemplate(name.clone(), err))?;
+        }
+
+        let audio_levels = &self.audio_levels;
+        if !(THRESHOLD_DB.contains(&audio_levels.threshold_db)
+            && INTERVAL_MS.contains(&audio_levels.interval_ms))
+        {
+            return Err(ConfigError::InvalidAudioLevels);
+        }
+
+        if self.reaping.interval > 0 && self.reaping.inactive_after < self.reaping.interval {
+            return Err(ConfigError::InvalidReaping);
+        }
+
+        if self.signaling.max_pending_commands == 0 {
+            return Err(ConfigError::NoPendingCommands);
+        }
+        if self.signaling.max_missed_pongs == 0 {
+            return Err(ConfigError::NoMissedPongs);
+        }
+        if self.signaling.poll_session_timeout <= MAX_POLL_TIMEOUT {
+            return Err(ConfigError::InvalidPollSessionTimeout);
+        }
+        let signaling = &self.signaling;
+        if !(signaling.max_relay_payload > 0
+            && signaling.max_relay_payload <= signaling.max_command_size
+            && signaling.max_command_size <= signaling.max_message_size)
+        {
+            return Err(ConfigError::InvalidSizeLimits);
+        }
+
+        for proxy in &self.signaling.trusted_proxies {
+            if let Err(err) = proxy.parse::<IpNetwork>() {
+                return Err(ConfigError::InvalidTrustedProxy(err.to_string()));
+            }
+        }
+
+        for origin in &self.signaling.allowed_origins {
+            let valid = match origin.split_once("://") {
+                Some((scheme, host)) => {
+                    !scheme.is_empty()
+                        && !host.is_empty()
+                        && !host.trim_end_matches('/').contains('/')
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(ConfigError::InvalidAllowedOrigin(origin.clone()));
+            }
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if cluster.node_id.is_empty()
+                || cluster.public_url.is_empty()
+                || cluster.affinity_secret.is_empty()
+            {
+                return Err(ConfigError::IncompleteCluster);
+            }
+        }
+
+        if let Some(jwt) = &self.jwt {
+            jwt.decoding_key()?;
+        }
+
+        if let Some(recording) = &self.recording {
+            if recording.directory.is_empty() || recording.ffmpeg.is_empty() {
+                return Err(ConfigError::IncompleteRecording);
+            }
+        }
+
+        if let Some(broadcast) = &self.broadcast {
+            if broadcast.ffmpeg.is_empty() {
+                return Err(ConfigError::IncompleteBroadcast);
+            }
+
+            if let Some(scheme) = broadcast
+                .allowed_schemes
+                .iter()
+                .find(|scheme| !matches!(scheme.as_str(), "rtmp" | "rtmps" | "srt"))
+            {
+                return Err(ConfigError::InvalidBroadcastScheme(scheme.clone()));
+            }
+        }
+
+        if let Some(hls) = &self.hls {
+            if hls.directory.is_empty() || hls.ffmpeg.is_empty() {
+                return Err(ConfigError::IncompleteHls);
+            }
+
+            if hls.segment_secs == 0 || hls.playlist_size == 0 {
+                return Err(ConfigError::InvalidHlsRetention);
+            }
+        }
+
+        if let Some(playback) = &self.playback {
+            if playback.directory.is_empty() || playback.ffmpeg.is_empty() {
+                return Err(ConfigError::IncompletePlayback);
+            }
+        }
+
+        if let Some(probe) = &self.probe {
+            if probe.max_probes == 0 || probe.max_probes_per_ip == 0 || probe.timeout_secs == 0 {
+                return Err(ConfigError::InvalidProbe);
+            }
+        }
+
+        if let Some(event_replay) = &self.event_replay {
+            if event_replay.max_events == 0 || !(1..=86400).contains(&event_replay.retention_secs) {
+                return Err(ConfigError::InvalidEventReplay);
+            }
+        }
+
+        if let Some(persistence) = &self.persistence {
+            if persistence.path.is_empty() {
+                return Err(ConfigError::IncompletePersistence);
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            if auth.backend == AuthBackend::Http
+                && (!auth.url.starts_with("http://") || auth.timeout_ms == 0)
+            {
+                return Err(ConfigError::IncompleteAuth);
+            }
+        }
+
+        if let Some(audit) = &self.audit {
+            if audit.sink == AuditSinkKind::File && audit.path.is_empty() {
+                return Err(ConfigError::IncompleteAudit);
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.cert_path.is_empty() || tls.key_path.is_empty() {
+                return Err(ConfigError::IncompleteTls);
+            }
+            tls::load(tls).map_err(ConfigError::InvalidTls)?;
+        }
+
+        if let Some(unix_socket) = &self.unix_socket {
+            if unix_socket.path.is_empty() {
+                return Err(ConfigError::IncompleteUnixSocket);
+            }
+            if let Some(mode) = &unix_socket.mode {
+                match u32::from_str_radix(mode, 8) {
+                    Ok(bits) if bits <= 0o777 => (),
+                    _ => return Err(ConfigError::InvalidSocketMode(mode.clone())),
+                }
+            }
+        }
+
+        if let Some(telemetry) = &self.telemetry {
+            if telemetry.endpoint.is_empty() {
+                return Err(ConfigError::IncompleteTelemetry);
+            }
+            if !(0.0..=1.0).contains(&telemetry.sample_ratio) {
+                return Err(ConfigError::InvalidSampleRatio(telemetry.sample_ratio));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl JwtConfig {
+    pub fn decoding_key(&self) -> Result<DecodingKey, ConfigError> {
+        let key = self.key.as_bytes();
+        let result = match self.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                Ok(DecodingKey::from_secret(key))
+            }
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(key),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(key),
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(key),
+        };
+
+        result.map_err(|err| ConfigError::InvalidJwtKey(err.to_string()))
+    }
+}
+
+impl RtcConfig {
+    /// Each listen IP once, announced with an address of its own family. IPv6 ones must be
+    /// bindable, hosts with IPv6 disabled would otherwise only fail once a transport is created
+    fn validate_listen_ips(&self) -> Result<(), ConfigError> {
+        for (index, listen_ip) in self.listen_ips.iter().enumerate() {
+            if self.listen_ips[..index]
+                .iter()
+                .any(|other| other.ip == listen_ip.ip)
+            {
+                return Err(ConfigError::DuplicateListenIp(listen_ip.ip));
+            }
+
+            if let Some(announced_ip) = listen_ip.announced_ip {
+                if announced_ip.is_ipv4() != listen_ip.ip.is_ipv4() {
+                    return Err(ConfigError::MismatchedAnnouncedIp(
+                        listen_ip.ip,
+                        announced_ip,
+                    ));
+                }
+            }
+
+            if listen_ip.ip.is_ipv6() {
+                UdpSocket::bind(SocketAddr::new(listen_ip.ip, 0)).map_err(|err| {
+                    ConfigError::UnbindableListenIp(listen_ip.ip, err.to_string())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn transport_listen_ips(&self) -> TransportListenIps {
+        let ips: Vec<TransportListenIp> = self
+            .listen_ips
+            .iter()
+            .map(|listen_ip| TransportListenIp {
+                ip: listen_ip.ip,
+                announced_ip: listen_ip.announced_ip,
+            })
+            .collect();
+
+        TransportListenIps::try_from(ips).expect("RTC listen IPs were not validated")
+    }
+
+    /// Address handed out to clients for plain RTP transports
+    pub fn announced_ip(&self) -> IpAddr {
+        let listen_ip = self.listen_ips[0];
+        listen_ip.announced_ip.unwrap_or(listen_ip.ip)
+    }
+}
+
+/// Parse the `ip[,announced_ip];...` format of `RTC_IPS`
+fn parse_ip_list(ip_list: &str) -> Result<Vec<ListenIp>, ConfigError> {
+    let mut listen_ips = Vec::new();
+    for ip_pair in ip_list.split(';').filter(|pair| !pair.is_empty()) {
+        let mut iter = ip_pair.split(',');
+        if let Some(ip) = iter.next() {
+            let ip = parse_variable("RTC_IPS", ip)?;
+            let announced_ip = iter
+                .next()
+                .map(|ip| parse_variable("RTC_IPS", ip))
+                .transpose()?;
+            listen_ips.push(ListenIp { ip, announced_ip });
+        }
+    }
+
+    Ok(listen_ips)
+}
+
+fn parse_variable<T>(name: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    T::from_str(value.trim()).map_err(|err| ConfigError::InvalidVariable(name, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_lists() {
+        let codecs = |toml: &str| -> Vec<CodecConfig> {
+            #[derive(Deserialize)]
+            struct Codecs {
+                codecs: Vec<CodecConfig>,
+            }
+            toml::from_str::<Codecs>(toml).unwrap().codecs
+        };
+
+        let valid = codecs(
+            r#"
+            [[codecs]]
+            codec = "opus"
+            channels = 1
+            [[codecs]]
+            codec = "h264"
+            "#,
+        );
+        assert!(validate_codecs(&valid).is_ok());
+        match &valid[1] {
+            CodecConfig::H264 {
+                profile_level_id,
+                packetization_mode,
+            } => assert_eq!(
+                (profile_level_id.as_str(), *packetization_mode),
+                ("42e01f", 1)
+            ),
+            _ => panic!("Expected H264 defaults"),
+        }
+
+        let video_only = codecs("[[codecs]]\ncodec = \"vp8\"");
+        assert!(matches!(
+            validate_codecs(&video_only),
+            Err(ConfigError::NoAudioCodec)
+        ));
+
+        let mut invalid = valid.clone();
+        invalid[1] = CodecConfig::H264 {
+            profile_level_id: "42e0".to_string(),
+            packetization_mode: 1,
+        };
+        assert!(matches!(
+            validate_codecs(&invalid),
+            Err(ConfigError::InvalidProfileLevelId(_))
+        ));
+        invalid[1] = CodecConfig::H264 {
+            profile_level_id: "42e01f".to_string(),
+            packetization_mode: 2,
+        };
+        assert!(matches!(
+            validate_codecs(&invalid),
+            Err(ConfigError::InvalidPacketizationMode(2))
+        ));
+
+        let svc = codecs(
+            r#"
+            [[codecs]]
+            codec = "opus"
+            channels = 2
+            [[codecs]]
+            codec = "vp9"
+            [[codecs]]
+            codec = "av1"
+            "#,
+        );
+        assert!(validate_codecs(&svc).is_ok());
+        assert!(matches!(svc[1], CodecConfig::Vp9 { profile_id: 0 }));
+        invalid[1] = CodecConfig::Vp9 { profile_id: 1 };
+        assert!(matches!(
+            validate_codecs(&invalid),
+            Err(ConfigError::InvalidVp9Profile(1))
+        ));
+    }
+
+    #[test]
+    fn defaults_match_hardcoded_settings() {
+        // The same values as the shared test setup, tests run in parallel
+        env::set_var("MANAGE_TOKEN", "test-token");
+        env::set_var("RTC_IPS", "127.0.0.1");
+
+        let mut config = Config::default();
+        config.apply_env().unwrap();
+        config.validate().unwrap();
+
+        let rtc = &config.rtc;
+        assert!(rtc.enable_udp);
+        assert!(rtc.enable_tcp);
+        assert!(rtc.prefer_udp);
+        assert!(!rtc.disable_rtp);
+        assert_eq!(rtc.workers, 1);
+        assert_eq!(rtc.min_port, 10000);
+        assert_eq!(rtc.max_port, 11000);
+        assert!(matches!(
+            rtc.codecs.as_slice(),
+            [CodecConfig::Opus { channels: 2 }]
+        ));
+        assert_eq!(rtc.listen_ips.len(), 1);
+        assert_eq!(rtc.listen_ips[0].ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(rtc.listen_ips[0].announced_ip, None);
+        assert_eq!(config.api.manage_tokens, vec!["test-token".to_string()]);
+        assert_eq!(config.rooms.idle_timeout(), Some(Duration::from_secs(300)));
+        assert_eq!(
+            config.signaling.ping_interval(),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(config.integrations.webhook_format, "json");
+        assert_eq!(config.integrations.redis_format, "json");
+    }
+
+    #[test]
+    fn placeholder_token_is_rejected() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["change-me".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::PlaceholderManageToken)
+        ));
+
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn tenants_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        let tenant = |id: &str, token: &str| TenantConfig {
+            id: id.to_string(),
+            manage_tokens: vec![token.to_string()],
+        };
+
+        for tenants in [
+            vec![tenant("acme/eu", "acme-secret")],
+            vec![tenant(DEFAULT_TENANT, "acme-secret")],
+            vec![
+                tenant("acme", "acme-secret"),
+                tenant("acme", "other-secret"),
+            ],
+            vec![tenant("acme", "a-real-secret")],
+            vec![tenant("acme", "")],
+        ] {
+            config.api.tenants = tenants;
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::InvalidTenant(_))
+            ));
+        }
+
+        config.api.tenants = vec![tenant("acme", "acme-secret")];
+        config.validate().unwrap();
+        let tokens: Vec<_> = config.api.tenant_tokens().collect();
+        assert_eq!(
+            tokens,
+            vec![(DEFAULT_TENANT, "a-real-secret"), ("acme", "acme-secret")]
+        );
+    }
+
+    #[test]
+    fn listen_ips_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        let listen_ip = ListenIp {
+            ip: "0.0.0.0".parse().unwrap(),
+            announced_ip: Some("2001:db8::1".parse().unwrap()),
+        };
+        config.rtc.listen_ips = vec![listen_ip];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MismatchedAnnouncedIp(_, _))
+        ));
+
+        let listen_ip = ListenIp {
+            ip: "0.0.0.0".parse().unwrap(),
+            announced_ip: Some("203.0.113.1".parse().unwrap()),
+        };
+        config.rtc.listen_ips = vec![listen_ip, listen_ip];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicateListenIp(_))
+        ));
+
+        config.rtc.listen_ips = vec![listen_ip];
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn broadcast_schemes_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.broadcast = Some(BroadcastConfig::default());
+        config.validate().unwrap();
+
+        config.broadcast = Some(BroadcastConfig {
+            allowed_schemes: vec!["rtmps".to_string(), "file".to_string()],
+            ..BroadcastConfig::default()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidBroadcastScheme(scheme)) if scheme == "file"
+        ));
+    }
+
+    #[test]
+    fn quality_profiles_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.validate().unwrap();
+        let low = &config.rooms.profiles["low"];
+        assert_eq!(low.get(ProduceType::Video).max_spatial_layer, Some(0));
+        assert_eq!(low.get(ProduceType::ScreenshareAudio).max_bitrate, None);
+
+        let rooms: RoomsConfig = toml::from_str(
+            r#"
+            [profiles.tiny.video]
+            maxBitrate = 8000
+            "#,
+        )
+        .unwrap();
+        config.rooms = rooms;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidQualityProfile(name)) if name == "tiny"
+        ));
+    }
+
+    #[test]
+    fn room_templates_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.rooms = toml::from_str(
+            r#"
+            [templates.webinar]
+            maxUsers = 500
+            qualityProfile = "standard"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.rooms.templates["webinar"]["maxUsers"], 500);
+
+        config.rooms.templates.get_mut("webinar").unwrap()["qualityProfile"] = "ultra".into();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRoomTemplate(name, _)) if name == "webinar"
+        ));
+    }
+
+    #[test]
+    fn telemetry_is_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.telemetry = Some(toml::from_str("sample_ratio = 0.25").unwrap());
+        config.validate().unwrap();
+
+        config.telemetry = Some(TelemetryConfig {
+            sample_ratio: 1.5,
+            ..TelemetryConfig::default()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSampleRatio(_))
+        ));
+        config.telemetry = Some(TelemetryConfig {
+            endpoint: String::new(),
+            ..TelemetryConfig::default()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::IncompleteTelemetry)
+        ));
+    }
+
+    #[test]
+    fn auth_is_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.auth = Some(toml::from_str(r#"backend = "local""#).unwrap());
+        config.validate().unwrap();
+
+        config.auth = Some(toml::from_str(r#"backend = "http""#).unwrap());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::IncompleteAuth)
+        ));
+        let auth: AuthConfig =
+            toml::from_str("backend = \"http\"\nurl = \"http://auth.internal/validate\"").unwrap();
+        assert_eq!((auth.timeout_ms, auth.cache_secs), (2000, 30));
+        config.auth = Some(auth);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn allowed_origins_are_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.signaling.allowed_origins = vec![
+            "https://app.example.com".to_string(),
+            "https://*.example.com".to_string(),
+            "http://localhost:3000/".to_string(),
+        ];
+        config.validate().unwrap();
+
+        for origin in [
+            "app.example.com",
+            "https://",
+            "https://app.example.com/join",
+        ]
+        .iter()
+        {
+            config.signaling.allowed_origins = vec![origin.to_string()];
+            assert!(matches!(
+                config.validate(),
+                Err(ConfigError::InvalidAllowedOrigin(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn poll_session_timeout_is_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        config.validate().unwrap();
+        assert_eq!(
+            config.signaling.poll_session_timeout(),
+            Duration::from_secs(60)
+        );
+
+        config.signaling.poll_session_timeout = MAX_POLL_TIMEOUT;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidPollSessionTimeout)
+        ));
+    }
+
+    #[test]
+    fn turn_is_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        let turn: TurnConfig =
+            toml::from_str("urls = [\"turn:turn.example.com\"]\nsecret = \"s\"").unwrap();
+        assert_eq!(turn.ttl, 86400);
+        config.turn = Some(turn.clone());
+        config.validate().unwrap();
+
+        config.turn = Some(TurnConfig {
+            urls: Vec::new(),
+            ..turn.clone()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::IncompleteTurn)
+        ));
+        config.turn = Some(TurnConfig { ttl: 0, ..turn });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidTurnTtl)
+        ));
+    }
+
+    #[test]
+    fn event_replay_is_checked() {
+        let mut config = Config::default();
+        config.api.manage_tokens = vec!["a-real-secret".to_string()];
+        config.rtc.listen_ips = vec![ListenIp {
+            ip: "127.0.0.1".parse().unwrap(),
+            announced_ip: None,
+        }];
+        let event_replay: EventReplayConfig = toml::from_str("max_events = 50").unwrap();
+        assert_eq!(event_replay.retention_secs, 600);
+        config.event_replay = Some(event_replay);
+        config.validate().unwrap();
+
+        config.event_replay = Some(toml::from_str("retention_secs = 0").unwrap());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidEventReplay)
+        ));
+    }
+}
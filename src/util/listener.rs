@@ -0,0 +1,146 @@
+//! Listeners the server accepts connections on itself rather than through warp: TLS ones
+//! and the Unix domain socket a reverse proxy on the same host connects through
+
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixListener;
+use warp::filters::BoxedFilter;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::reply::Response;
+
+use super::client_ip::PeerAddr;
+use super::config::UnixSocketConfig;
+
+/// Pause after failing to accept a connection, such as when out of file descriptors
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve `route` on a connection accepted outside warp, upgrades included. Its requests
+/// come from `peer` as far as the routes can tell
+pub async fn serve_connection<I>(route: BoxedFilter<(Response,)>, io: I, peer: SocketAddr)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut service = warp::service(route);
+    let service = service_fn(move |mut request: Request<Body>| {
+        request.extensions_mut().insert(PeerAddr(peer));
+        service.call(request)
+    });
+    // WebSockets are upgraded from HTTP/1.1 only
+    let served = Http::new()
+        .http1_only(true)
+        .serve_connection(io, service)
+        .with_upgrades()
+        .await;
+    if let Err(err) = served {
+        debug!("Connection from {} failed: {}", peer, err);
+    }
+}
+
+/// Bind the socket, removing the file of one left behind by a server that didn't shut
+/// down cleanly. A socket still accepting connections belongs to another server and is
+/// left alone
+fn bind(config: &UnixSocketConfig) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(&config.path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists and isn't a socket",
+            ));
+        }
+        Ok(_) if UnixStream::connect(&config.path).is_ok() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on it",
+            ));
+        }
+        Ok(_) => {
+            info!("Removing stale socket {}", config.path);
+            fs::remove_file(&config.path)?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+
+    let listener = UnixListener::bind(&config.path)?;
+    if let Some(mode) = &config.mode {
+        let mode = u32::from_str_radix(mode, 8).expect("Socket mode was not validated");
+        fs::set_permissions(&config.path, Permissions::from_mode(mode))?;
+    }
+    if config.uid.is_some() || config.gid.is_some() {
+        chown(&config.path, config.uid, config.gid)?;
+    }
+    Ok(listener)
+}
+
+/// Serve `route` on the Unix domain socket until the process exits. Its requests come
+/// from 127.0.0.1, which decides whether their forwarding headers are believed
+pub async fn serve_unix(route: BoxedFilter<(Response,)>, config: &'static UnixSocketConfig) {
+    let listener =
+        bind(config).unwrap_or_else(|err| panic!("Failed to listen on {}: {}", config.path, err));
+    info!("Listening on {}", config.path);
+    let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        tokio::spawn(serve_connection(route.clone(), stream, peer));
+    }
+}
+
+/// Remove the socket file on shutdown, so the next server doesn't find it stale
+pub fn remove_unix(config: &UnixSocketConfig) {
+    if let Err(err) = fs::remove_file(&config.path) {
+        warn!("Failed to remove socket {}: {}", config.path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path: &std::path::Path) -> UnixSocketConfig {
+        UnixSocketConfig {
+            path: path.to_string_lossy().into_owned(),
+            mode: Some("600".to_string()),
+            uid: None,
+            gid: None,
+            tcp: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!("vortex-{}.sock", rand::random::<u64>()));
+        let config = config(&path);
+
+        let listener = bind(&config).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(bind(&config).is_err());
+
+        // Dropping the listener leaves the file behind, like a crash would
+        drop(listener);
+        assert!(path.exists());
+        let _listener = bind(&config).unwrap();
+        remove_unix(&config);
+        assert!(!path.exists());
+
+        fs::write(&path, "not a socket").unwrap();
+        assert!(bind(&config).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
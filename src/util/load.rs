@@ -0,0 +1,172 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use super::config::{Config, LoadSheddingConfig, CONFIG};
+use super::metrics::{LOAD_SHED_DECISIONS, NODE_CPU_USAGE};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref THRESHOLDS: RwLock<LoadSheddingConfig> = RwLock::new(CONFIG.load_shedding.clone());
+}
+
+/// Node CPU usage in the last sample, as the bits of an `f64` between 0 and 1
+static CPU_USAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Set while the load signal is simulated, so samples don't overwrite it
+static OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// How expensive read commands should be served under the current load
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadLevel {
+    /// Execute normally
+    Normal,
+    /// Serve from caches where possible
+    Elevated,
+    /// Reject, asking the client to retry later
+    Overloaded,
+}
+
+pub fn cpu_usage() -> f64 {
+    f64::from_bits(CPU_USAGE.load(Ordering::Relaxed))
+}
+
+/// Override the measured load signal, used when simulating overload
+pub fn set_cpu_usage(usage: f64) {
+    OVERRIDDEN.store(true, Ordering::Relaxed);
+    store_cpu_usage(usage);
+}
+
+/// Go back to the measured load signal after `set_cpu_usage`
+pub fn clear_cpu_usage() {
+    OVERRIDDEN.store(false, Ordering::Relaxed);
+    store_cpu_usage(0.0);
+}
+
+fn store_cpu_usage(usage: f64) {
+    CPU_USAGE.store(usage.to_bits(), Ordering::Relaxed);
+    NODE_CPU_USAGE.set(usage);
+}
+
+pub fn level() -> LoadLevel {
+    let thresholds = THRESHOLDS.read().unwrap();
+    let usage = cpu_usage();
+    if usage >= thresholds.hard_cpu {
+        LoadLevel::Overloaded
+    } else if usage >= thresholds.soft_cpu {
+        LoadLevel::Elevated
+    } else {
+        LoadLevel::Normal
+    }
+}
+
+/// Delay clients are asked to wait before retrying a rejected command
+pub fn retry_after_ms() -> u64 {
+    THRESHOLDS.read().unwrap().retry_after_ms
+}
+
+/// Count a command served from cache or rejected because of load
+pub fn record_shed(command: &str, level: LoadLevel) {
+    let decision = match level {
+        LoadLevel::Normal => return,
+        LoadLevel::Elevated => "cached",
+        LoadLevel::Overloaded => "rejected",
+    };
+
+    LOAD_SHED_DECISIONS
+        .with_label_values(&[command, decision])
+        .inc();
+}
+
+/// Busy and total jiffies across all CPUs, from the first line of `/proc/stat`
+fn read_cpu_times() -> Option<(u64, u64)> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let line = stat.lines().next()?;
+    let times: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|value| value.parse().ok())
+        .collect();
+
+    // user nice system idle iowait ...
+    let idle = times.get(3)? + times.get(4).unwrap_or(&0);
+    let total: u64 = times.iter().sum();
+    Some((total - idle, total))
+}
+
+/// Reload the load shedding thresholds from the configuration file
+pub fn reload() {
+    match Config::load() {
+        Ok(config) => {
+            info!("Reloaded load shedding thresholds");
+            *THRESHOLDS.write().unwrap() = config.load_shedding;
+        }
+        Err(err) => error!("Keeping previous load shedding thresholds: {}", err),
+    }
+}
+
+/// Sample node CPU usage every second, and reload thresholds on SIGHUP
+pub fn start_monitor() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut previous = read_cpu_times();
+        loop {
+            interval.tick().await;
+            let current = read_cpu_times();
+            if let (Some((busy, total)), Some((previous_busy, previous_total))) =
+                (current, previous)
+            {
+                if total > previous_total && !OVERRIDDEN.load(Ordering::Relaxed) {
+                    let usage =
+                        busy.saturating_sub(previous_busy) as f64 / (total - previous_total) as f64;
+                    store_cpu_usage(usage.clamp(0.0, 1.0));
+                }
+            }
+
+            previous = current;
+        }
+    });
+
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangup) => {
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    reload();
+                }
+            });
+        }
+        Err(err) => warn!(
+            "Failed to listen for SIGHUP, thresholds won't reload: {}",
+            err
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[test]
+    fn level_follows_thresholds() {
+        let _serial = testing::serial();
+        let (soft, hard) = {
+            let thresholds = THRESHOLDS.read().unwrap();
+            (thresholds.soft_cpu, thresholds.hard_cpu)
+        };
+
+        set_cpu_usage(soft / 2.0);
+        assert_eq!(level(), LoadLevel::Normal);
+        set_cpu_usage(soft);
+        assert_eq!(level(), LoadLevel::Elevated);
+        set_cpu_usage(hard);
+        assert_eq!(level(), LoadLevel::Overloaded);
+
+        clear_cpu_usage();
+        assert_eq!(level(), LoadLevel::Normal);
+    }
+}
@@ -0,0 +1,172 @@
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+
+use super::config::DEFAULT_TENANT;
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new_custom(Some("vortex".to_string()), None)
+        .expect("Failed to create metrics registry");
+    pub static ref COMMAND_PANICS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "command_panics_total",
+            "Panics caught while handling a command or room event"
+        ),
+        &["command"],
+    ));
+    pub static ref COMMAND_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "command_seconds",
+            "Time from reading the frame a command came in to writing its reply or error"
+        )
+        .buckets(vec![
+            0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0
+        ]),
+        &["command"],
+    ));
+    pub static ref COMMAND_ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "command_errors_total",
+            "Errors replied to commands, by command and error type"
+        ),
+        &["command", "error"],
+    ));
+    pub static ref ROOM_SUBSCRIBERS: IntGauge = register(IntGauge::new(
+        "room_subscribers",
+        "Connections currently subscribed to room events"
+    ));
+    pub static ref ROOM_USERS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "room_users",
+            "Users across all rooms, by visibility class and tenant"
+        ),
+        &["class", "tenant"],
+    ));
+    pub static ref FIRST_VIDEO_FRAME_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "first_video_frame_seconds",
+            "Time from transport connect to the first video keyframe forwarded to a connection"
+        )
+        .buckets(vec![0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0]),
+        &["mode"],
+    ));
+    pub static ref NODE_CPU_USAGE: Gauge = register(Gauge::new(
+        "node_cpu_usage",
+        "Node CPU usage between 0 and 1, the load signal used for load shedding"
+    ));
+    pub static ref LOAD_SHED_DECISIONS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "load_shed_decisions_total",
+            "Commands served from cache or rejected because the node is overloaded"
+        ),
+        &["command", "decision"],
+    ));
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "webhook_deliveries_total",
+            "Webhook deliveries by outcome, failed ones gave up after their retries"
+        ),
+        &["outcome"],
+    ));
+    pub static ref AUDIT_RECORDS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "audit_records_total",
+            "Audit records by outcome, dropped ones found the queue to the sink full"
+        ),
+        &["outcome"],
+    ));
+    pub static ref TRANSPORT_POOL_CLAIMS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "transport_pool_claims_total",
+            "Transports asked of router pools, by whether an idle one was ready"
+        ),
+        &["outcome"],
+    ));
+    pub static ref TRANSPORT_POOL_IDLE: IntGauge = register(IntGauge::new(
+        "transport_pool_idle",
+        "Transports idle in the pools of every router"
+    ));
+    pub static ref TRANSPORT_POOL_EXPIRED: IntCounter = register(IntCounter::new(
+        "transport_pool_expired_total",
+        "Pooled transports closed after staying idle too long"
+    ));
+    pub static ref REAPED_CONSUMERS: IntCounter = register(IntCounter::new(
+        "reaped_consumers_total",
+        "Consumers closed after sending nothing for reaping.inactive_after seconds"
+    ));
+    pub static ref DROPPED_EVENTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "dropped_events_total",
+            "Low priority events not sent to connections falling behind on reading"
+        ),
+        &["event"],
+    ));
+    pub static ref TRANSPORT_INIT_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "transport_init_failures_total",
+            "Failed attempts at creating a connection's transports, by kind of failure"
+        ),
+        &["kind"],
+    ));
+    pub static ref ROOM_NACKS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "room_nacks",
+            "NACKs of a room's producers and consumers over the last minute"
+        ),
+        &["tenant", "room"],
+    ));
+    pub static ref ROOM_PLIS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "room_plis",
+            "Keyframe requests of a room's producers and consumers over the last minute"
+        ),
+        &["tenant", "room"],
+    ));
+    pub static ref ROOM_RETRANSMITTED_BYTES: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "room_retransmitted_bytes",
+            "Estimated bytes a room's media retransmitted over the last minute"
+        ),
+        &["tenant", "room"],
+    ));
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    collector: Result<T, prometheus::Error>,
+) -> T {
+    let collector = collector.expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Failed to register metric");
+    collector
+}
+
+/// Render the metrics `tenant` may see in the Prometheus text exposition format. The
+/// default tenant sees them all, the others only those labelled with their ID
+pub fn gather(tenant: &str) -> String {
+    let mut families = REGISTRY.gather();
+    if tenant != DEFAULT_TENANT {
+        for family in &mut families {
+            let metrics = family
+                .take_metric()
+                .into_iter()
+                .filter(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_name() == "tenant" && label.get_value() == tenant)
+                })
+                .collect();
+            family.set_metric(metrics);
+        }
+        // Families without metrics can't be encoded
+        families.retain(|family| !family.get_metric().is_empty());
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .expect("Failed to encode metrics");
+    String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+}
@@ -1 +1,13 @@
+pub mod client_ip;
+pub mod config;
+pub mod listener;
+pub mod load;
+pub mod metrics;
+pub mod rate;
+pub mod telemetry;
+pub mod tls;
+pub mod ulid;
 pub mod variables;
+
+#[cfg(test)]
+pub mod testing;
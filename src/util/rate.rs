@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing bursts of up to `capacity` actions, refilled at `per_second`
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so a fresh user isn't throttled on its first burst
+    pub fn new(capacity: u32, per_second: u32) -> Self {
+        TokenBucket {
+            capacity: f64::from(capacity),
+            per_second: f64::from(per_second),
+            tokens: f64::from(capacity),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Refilled `per_minute` tokens a minute, for what may only be done now and then
+    pub fn per_minute(capacity: u32, per_minute: u32) -> Self {
+        TokenBucket {
+            per_second: f64::from(per_minute) / 60.0,
+            ..TokenBucket::new(capacity, 0)
+        }
+    }
+
+    /// Take a token if one is left at `now`
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// How long after the last take until a token is left again
+    pub fn wait(&self) -> Duration {
+        let missing = (1.0 - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.per_second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_refills() {
+        let mut bucket = TokenBucket::new(3, 2);
+        let start = Instant::now();
+        assert_eq!((0..5).filter(|_| bucket.take(start)).count(), 3);
+
+        // Half a second at 2 per second refills one
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+        assert_eq!(bucket.wait(), Duration::from_millis(500));
+
+        // Never beyond the burst, however long it's been
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..5).filter(|_| bucket.take(much_later)).count(), 3);
+    }
+}
@@ -0,0 +1,211 @@
+//! Span export to an OpenTelemetry collector, and the W3C trace context management API
+//! requests carry into the room operations they trigger. Without the `otel` feature, or
+//! a `telemetry` section, spans only go to the logs
+
+use tracing::{field, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use warp::trace::Info;
+
+use super::config::CONFIG;
+
+/// Header of the W3C trace context
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Trace a request belongs to and the span that sent it, from its `traceparent` header
+#[derive(Debug, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    /// Parse a `version-traceid-parentid-flags` header. Versions after 00 may append
+    /// fields, which are ignored
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+        if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceParent {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+/// Lowercase hex only, as the spec has it
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Install the global subscriber, logging what `filter` lets through and exporting it as
+/// spans when trace export is configured
+pub fn init(filter: EnvFilter) {
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+    // Without an exporter the layer is `None`, leaving spans to the logs alone
+    #[cfg(feature = "otel")]
+    let (registry, error) = match CONFIG.telemetry.as_ref().map(otel::layer).transpose() {
+        Ok(layer) => (registry.with(layer), None),
+        Err(err) => (registry.with(None), Some(err)),
+    };
+    registry.init();
+
+    #[cfg(feature = "otel")]
+    if let Some(err) = error {
+        warn!("Failed to start the trace exporter: {}", err);
+    }
+    if CONFIG.telemetry.is_some() && !cfg!(feature = "otel") {
+        warn!("Trace export is configured, but this build lacks the otel feature");
+    }
+}
+
+/// Flush the spans not exported yet, on shutdown
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+/// Span of a management API request, continuing the trace of its `traceparent` header
+pub fn api_span(info: Info) -> Span {
+    let span = tracing::info_span!(
+        "api",
+        method = %info.method(),
+        path = %info.path(),
+        trace_id = field::Empty,
+    );
+    let parent = info
+        .request_headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(TraceParent::parse);
+    if let Some(parent) = parent {
+        span.record("trace_id", &parent.trace_id.as_str());
+        #[cfg(feature = "otel")]
+        otel::set_parent(&span, info.request_headers());
+    }
+    span
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::propagation::{Extractor, TextMapPropagator};
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::trace::{self, Sampler};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::trace::TraceError;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing::Span;
+    use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use warp::http::HeaderMap;
+
+    use crate::util::config::{TelemetryConfig, CONFIG};
+
+    pub fn layer<S>(
+        config: &TelemetryConfig,
+    ) -> Result<OpenTelemetryLayer<S, trace::Tracer>, TraceError>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(&config.endpoint);
+        let sampler = Sampler::TraceIdRatioBased(config.sample_ratio);
+        let resource = Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]);
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(
+                trace::config()
+                    .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+                    .with_resource(resource),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    struct Headers<'a>(&'a HeaderMap);
+
+    impl Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+
+    /// Parent the span to the request's remote span, for the exporter to link them
+    pub fn set_parent(span: &Span, headers: &HeaderMap) {
+        if CONFIG.telemetry.is_none() {
+            return;
+        }
+        let context = TraceContextPropagator::new().extract(&Headers(headers));
+        span.set_parent(context);
+    }
+
+    pub fn shutdown() {
+        if CONFIG.telemetry.is_some() {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_headers() {
+        let parent =
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(parent.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.parent_id, "00f067aa0ba902b7");
+        assert!(parent.sampled);
+
+        // Later versions may carry more fields
+        let parent =
+            TraceParent::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra")
+                .unwrap();
+        assert!(!parent.sampled);
+
+        for invalid in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceParent::parse(invalid), None, "{}", invalid);
+        }
+    }
+}
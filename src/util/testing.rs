@@ -0,0 +1,43 @@
+//! Shared setup for tests that need rooms backed by a real mediasoup worker
+
+use parking_lot::{Mutex, MutexGuard};
+use std::env;
+use std::sync::{Arc, Once};
+use warp::test::RequestBuilder;
+
+use crate::rtc::worker::{WorkerPool, WORKER_POOL};
+use crate::state::room::{Room, RoomSettings};
+use crate::util::config::DEFAULT_TENANT;
+
+static INIT: Once = Once::new();
+static SERIAL: Mutex<()> = parking_lot::const_mutex(());
+
+/// Provide the minimal configuration and start the worker pool, once per test binary
+pub fn init() {
+    INIT.call_once(|| {
+        env::set_var("MANAGE_TOKEN", "test-token");
+        env::set_var("RTC_IPS", "127.0.0.1");
+        // Two workers, for rooms to be moved between
+        WORKER_POOL.get_or_init(|| futures::executor::block_on(WorkerPool::with_workers(2)));
+    });
+}
+
+/// Create a room with a unique ID, tests run in parallel against the global room map
+pub async fn room(settings: RoomSettings) -> Arc<Room> {
+    init();
+    let id = format!("test-{}", rand::random::<u64>());
+    Room::new(DEFAULT_TENANT, id, settings)
+        .await
+        .expect("Failed to create test room")
+}
+
+/// Hold while a test asserts on global metrics, which tests running in parallel would
+/// otherwise move under it
+pub fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock()
+}
+
+/// Request to the management API carrying the default tenant's token
+pub fn request() -> RequestBuilder {
+    warp::test::request().header("Authorization", "test-token")
+}
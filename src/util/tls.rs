@@ -0,0 +1,188 @@
+//! TLS for deployments without a reverse proxy in front, serving `wss://`, the management
+//! API and health checks on the one listener. The certificate is read again on SIGHUP and
+//! when its files change, connections made from then on get the new one
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::RwLock;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey, SigningKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+
+use super::config::TlsConfig;
+use super::listener::{serve_connection, ACCEPT_BACKOFF};
+
+/// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Read the certificate chain and key, checking the key is the certificate's
+pub fn load(config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let certs = read_pem(&config.cert_path, pemfile::certs)?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| format!("no certificate in {}", config.cert_path))?;
+
+    let mut keys = read_pem(&config.key_path, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(&config.key_path, pemfile::rsa_private_keys)?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("no PKCS#8 or RSA private key in {}", config.key_path))?;
+    let key = sign::any_supported_type(key)
+        .map_err(|()| format!("unsupported private key in {}", config.key_path))?;
+    check_pair(cert, key.as_ref())?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn read_pem<T>(
+    path: &str,
+    parse: fn(&mut dyn BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>, String> {
+    let file = File::open(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    parse(&mut BufReader::new(file)).map_err(|()| format!("{} is not valid PEM", path))
+}
+
+/// Sign with the key and verify the signature with the certificate's public key, which
+/// only succeeds if they're a pair
+fn check_pair(cert: &Certificate, key: &dyn SigningKey) -> Result<(), String> {
+    let signer = key
+        .choose_scheme(&[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .ok_or_else(|| "unsupported private key".to_string())?;
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+
+    let message = b"vortex certificate check";
+    let signature = signer.sign(message).map_err(|err| err.to_string())?;
+    let cert = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|err| format!("unparseable certificate: {:?}", err))?;
+    cert.verify_signature(algorithm, message, &signature)
+        .map_err(|_| "the private key doesn't match the certificate".to_string())
+}
+
+/// Hands out the latest certificate loaded
+struct ReloadingCert {
+    key: RwLock<CertifiedKey>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().clone())
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &str| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    Some((modified(&config.cert_path)?, modified(&config.key_path)?))
+}
+
+/// Reload the certificate on SIGHUP and when its files change. A certificate that fails
+/// to load is logged and the current one kept
+async fn watch(cert: Arc<ReloadingCert>, config: &'static TlsConfig) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    let mut modified = modified_times(config);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("Reloading the TLS certificate on SIGHUP"),
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                let now = modified_times(config);
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                info!("TLS certificate files changed, reloading them");
+            }
+        }
+
+        match load(config) {
+            Ok(key) => {
+                *cert.key.write() = key;
+                info!("Reloaded the TLS certificate");
+            }
+            Err(err) => error!("Keeping the current TLS certificate: {}", err),
+        }
+    }
+}
+
+/// Serve `route` over TLS on `addr`. The certificate was checked along with the rest of
+/// the configuration
+pub async fn serve(route: BoxedFilter<(Response,)>, addr: SocketAddr, config: &'static TlsConfig) {
+    let key = load(config).expect("TLS certificate was not validated");
+    let cert = Arc::new(ReloadingCert {
+        key: RwLock::new(key),
+    });
+    tokio::spawn(watch(cert.clone(), config));
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.cert_resolver = cert;
+    // Connections are served as HTTP/1.1, see `serve_connection`
+    server_config.set_protocols(&[b"http/1.1".to_vec()]);
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to listen on {}: {}", addr, err));
+    info!("Serving TLS on {}", addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let route = route.clone();
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => serve_connection(route, stream, peer).await,
+                Err(err) => debug!("TLS handshake with {} failed: {}", peer, err),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unusable_files_are_refused() {
+        let dir = std::env::temp_dir();
+        let garbage = dir.join(format!("vortex-{}.pem", rand::random::<u64>()));
+        fs::write(&garbage, "not a certificate").unwrap();
+        let config = |cert_path: &str| TlsConfig {
+            cert_path: cert_path.to_string(),
+            key_path: garbage.to_string_lossy().into_owned(),
+        };
+
+        let missing = load(&config("/nonexistent/cert.pem")).unwrap_err();
+        assert!(missing.starts_with("failed to read /nonexistent/cert.pem"));
+        let empty = load(&config(&garbage.to_string_lossy())).unwrap_err();
+        assert!(empty.starts_with("no certificate in"));
+        fs::remove_file(&garbage).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+/// Crockford's base 32, which leaves out I, L, O and U
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// New ULID, 26 characters sorting by the millisecond it was made in
+pub fn generate() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0);
+    encode(millis, rand::thread_rng().gen())
+}
+
+/// 48 bits of time followed by 80 bits of randomness
+fn encode(millis: u64, random: u128) -> String {
+    let value = (u128::from(millis & 0xFFFF_FFFF_FFFF) << 80) | (random & ((1 << 80) - 1));
+    (0..26)
+        .rev()
+        .map(|digit| ALPHABET[((value >> (digit * 5)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulids_sort_by_time() {
+        assert_eq!(encode(0, 0), "00000000000000000000000000");
+        assert_eq!(encode(1, 0), "00000000010000000000000000");
+        assert_eq!(
+            encode(0xFFFF_FFFF_FFFF, u128::MAX),
+            "7ZZZZZZZZZZZZZZZZZZZZZZZZZ"
+        );
+
+        let id = generate();
+        assert_eq!(id.len(), 26);
+        assert!(encode(0, u128::MAX) < id);
+    }
+}
@@ -1,10 +1,8 @@
-use std::convert::TryFrom;
 use std::env;
-use std::net::{IpAddr, SocketAddr};
-use std::str::FromStr;
+use std::net::SocketAddr;
 
-use mediasoup::data_structures::TransportListenIp;
-use mediasoup::prelude::TransportListenIps;
+use super::config::CONFIG;
+use crate::integrations::format;
 
 lazy_static! {
     // HTTP API
@@ -14,42 +12,33 @@ lazy_static! {
         .expect("HTTP_HOST environment variable is not a valid IP:port");
     pub static ref WS_URL: String =
         env::var("WS_URL").expect("Missing WS_URL environment variable.");
-    pub static ref MANAGE_TOKEN: String =
-        env::var("MANAGE_TOKEN").expect("Missing MANAGE_TOKEN environment variable.");
 
-    // RTC
-    pub static ref RTC_IPS: TransportListenIps = {
-        let ip_list = env::var("RTC_IPS").expect("Missing RTC_IPS environment variable.");
-        let ip_list = ip_list.split(';');
-        let mut ip_vec = Vec::new();
-        for ip_pair in ip_list {
-            let mut iter = ip_pair.split(',');
-            if let Some(ip) = iter.next() {
-                let ip = IpAddr::from_str(&ip).expect("Not a valid listen IP");
-                let announced_ip = iter.next().map(|ip| IpAddr::from_str(&ip).expect("Not a valid announcement IP"));
-                ip_vec.push(TransportListenIp {
-                    ip, announced_ip,
-                });
-            }
-        }
-
-        TransportListenIps::try_from(ip_vec).unwrap()
-    };
-
-    pub static ref RTC_MIN_PORT: u16 = env::var("RTC_MIN_PORT")
-        .unwrap_or_else(|_| "10000".to_string())
-        .parse()
-        .expect("RTC_MIN_PORT is not a valid 16-bit number");
-    pub static ref RTC_MAX_PORT: u16 = env::var("RTC_MAX_PORT")
-        .unwrap_or_else(|_| "11000".to_string())
-        .parse()
-        .expect("RTC_MAX_PORT is not a valid 16-bit number");
-    pub static ref DISABLE_RTP: bool = env::var("DISABLE_RTP").map_or(false, |v| v == "1");
+    // Integrations
+    pub static ref REDIS_URI: Option<String> = env::var("REDIS_URI").ok();
+    /// Comma separated, every event is delivered to each of them
+    pub static ref WEBHOOK_URLS: Vec<String> = env::var("WEBHOOK_URL")
+        .map(|urls| {
+            urls.split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    /// Key the bodies of webhook requests are signed with, unsigned without it
+    pub static ref WEBHOOK_SECRET: Option<String> = env::var("WEBHOOK_SECRET").ok();
 }
 
 pub fn preflight_checks() {
-    format!("{}", *WS_URL);
-    format!("{}", *MANAGE_TOKEN);
+    lazy_static::initialize(&WS_URL);
+
+    lazy_static::initialize(&CONFIG);
 
-    format!("{}", RTC_IPS.len());
+    // Formats registered by embedders are only known by now
+    let integrations = &CONFIG.integrations;
+    for name in [&integrations.webhook_format, &integrations.redis_format] {
+        if format::get(name).is_none() {
+            panic!("Unknown integration payload format {}", name);
+        }
+    }
 }
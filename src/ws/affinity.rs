@@ -0,0 +1,151 @@
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::{Filter, Rejection};
+
+use crate::util::config::{ClusterConfig, CONFIG};
+
+pub const AFFINITY_HEADER: &str = "x-vortex-affinity";
+
+#[derive(Deserialize)]
+struct AffinityQuery {
+    affinity: Option<String>,
+}
+
+/// Reply to a client that reconnected to a node other than the one holding its session
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Redirect {
+    error: &'static str,
+    node_id: String,
+    url: String,
+}
+
+impl Reply for Redirect {
+    fn into_response(self) -> Response {
+        warp::reply::with_status(warp::reply::json(&self), StatusCode::MISDIRECTED_REQUEST)
+            .into_response()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the UNIX epoch")
+        .as_secs()
+}
+
+fn sign(cluster: &ClusterConfig, payload: &str) -> Hmac<Sha1> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(cluster.affinity_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Signed token pointing reconnecting clients at this node, `None` for single node deployments
+pub fn issue() -> Option<String> {
+    Some(issue_at(CONFIG.cluster.as_ref()?, now()))
+}
+
+fn issue_at(cluster: &ClusterConfig, now: u64) -> String {
+    let expiry = now + cluster.affinity_ttl;
+
+    // `expiry|node_id|url`, the URL goes last as it may contain the separator
+    let payload = format!("{}|{}|{}", expiry, cluster.node_id, cluster.public_url);
+    let signature = sign(cluster, &payload).finalize().into_bytes();
+    format!(
+        "{}.{}",
+        base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Where a token sends the client, if it is authentic, unexpired and names another node
+fn verify(token: &str) -> Option<Redirect> {
+    verify_at(CONFIG.cluster.as_ref()?, token, now())
+}
+
+fn verify_at(cluster: &ClusterConfig, token: &str, now: u64) -> Option<Redirect> {
+    let mut parts = token.splitn(2, '.');
+    let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let payload = String::from_utf8(payload).ok()?;
+    sign(cluster, &payload).verify(&signature).ok()?;
+
+    let mut fields = payload.splitn(3, '|');
+    let expiry: u64 = fields.next()?.parse().ok()?;
+    let node_id = fields.next()?;
+    let url = fields.next()?;
+    if expiry < now || node_id == cluster.node_id {
+        return None;
+    }
+
+    Some(Redirect {
+        error: "WrongNode",
+        node_id: node_id.to_string(),
+        url: url.to_string(),
+    })
+}
+
+/// Extract the affinity token from the query or header, resolving it to a redirect
+/// when the client belongs on another node. Invalid tokens are ignored
+pub fn filter() -> impl Filter<Extract = (Option<Redirect>,), Error = Rejection> + Clone {
+    warp::query::<AffinityQuery>()
+        .and(warp::header::optional::<String>(AFFINITY_HEADER))
+        .map(|query: AffinityQuery, header: Option<String>| {
+            query.affinity.or(header).as_deref().and_then(verify)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_id: &str, secret: &str) -> ClusterConfig {
+        ClusterConfig {
+            node_id: node_id.to_string(),
+            public_url: format!("wss://{}.example.com/ws?region=eu|west", node_id),
+            affinity_secret: secret.to_string(),
+            affinity_ttl: 60,
+        }
+    }
+
+    #[test]
+    fn other_nodes_redirect_to_the_issuer() {
+        let (a, b) = (node("a", "secret"), node("b", "secret"));
+        let token = issue_at(&a, 1000);
+
+        // The issuing node keeps the client
+        assert!(verify_at(&a, &token, 1000).is_none());
+        let redirect = verify_at(&b, &token, 1060).unwrap();
+        assert_eq!(redirect.error, "WrongNode");
+        assert_eq!(redirect.node_id, "a");
+        assert_eq!(redirect.url, a.public_url);
+
+        assert!(verify_at(&b, &token, 1061).is_none());
+    }
+
+    #[test]
+    fn forged_tokens_are_ignored() {
+        let (a, b) = (node("a", "secret"), node("b", "secret"));
+        let token = issue_at(&a, 1000);
+        let (_, signature) = token.split_once('.').unwrap();
+
+        // Pointed at another URL while keeping the signature
+        let payload = format!("1060|a|{}", "wss://evil.example.com/ws");
+        let changed = format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            signature
+        );
+        assert!(verify_at(&b, &changed, 1000).is_none());
+
+        // Signed with a secret the cluster doesn't share
+        let forged = issue_at(&node("a", "other"), 1000);
+        assert!(verify_at(&b, &forged, 1000).is_none());
+        assert!(verify_at(&b, "not-a-token", 1000).is_none());
+    }
+}
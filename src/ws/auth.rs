@@ -0,0 +1,332 @@
+//! Where the tokens clients authenticate with are checked. By default that's done locally,
+//! taking JWTs when `jwt` is configured and join tokens from the management API otherwise.
+//! Deployments that keep identities elsewhere set `auth.backend = "http"` to have an
+//! endpoint of theirs asked instead, or plug in an `Authenticator` of their own with
+//! `ServerBuilder::authenticator`
+
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::hyper::{self, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request};
+
+use super::error::WSCloseType;
+use super::jwt::JwtVerifier;
+use crate::state::room::{token, Room};
+use crate::state::user::{metadata_fits, UserOptions};
+use crate::util::config::{AuthConfig, CONFIG};
+
+pub static AUTHENTICATOR: OnceCell<Arc<dyn Authenticator>> = OnceCell::new();
+
+lazy_static! {
+    static ref LOCAL_AUTHENTICATOR: LocalAuthenticator = LocalAuthenticator::new(
+        CONFIG
+            .jwt
+            .as_ref()
+            .map(|jwt| JwtVerifier::new(jwt).expect("JWT key was not validated")),
+    );
+}
+
+/// Answers of the `http` backend kept at most, expired ones are dropped to make room
+const MAX_CACHED_ANSWERS: usize = 10_000;
+
+/// The authenticator set up for the server, checking tokens locally unless another was
+pub fn authenticator() -> &'static dyn Authenticator {
+    match AUTHENTICATOR.get() {
+        Some(authenticator) => authenticator.as_ref(),
+        None => &*LOCAL_AUTHENTICATOR,
+    }
+}
+
+/// Who a token authenticates in a room
+#[derive(Clone, Debug)]
+pub struct UserClaims {
+    pub user_id: String,
+    /// How the user is set up, it's created or takes over the session of the user of the
+    /// same ID already in the room. `None` when the token redeems a user created over
+    /// the management API
+    pub options: Option<UserOptions>,
+    /// UNIX time in seconds the token expires
+    pub expires: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthError {
+    /// The token isn't valid for the room
+    Refused,
+    /// The backend couldn't tell, such as while it's down
+    Unavailable,
+}
+
+impl From<AuthError> for WSCloseType {
+    fn from(err: AuthError) -> WSCloseType {
+        match err {
+            AuthError::Refused => WSCloseType::Unauthorized,
+            AuthError::Unavailable => WSCloseType::ServerError,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Who `token` authenticates in the room. Nothing is redeemed by checking it, join
+    /// tokens are once the user registers, so tokens are checked again to refresh a session
+    async fn validate(&self, room: &Room, token: &str) -> Result<UserClaims, AuthError>;
+}
+
+/// JWTs when configured, join tokens signed by this node otherwise
+pub struct LocalAuthenticator {
+    jwt: Option<JwtVerifier>,
+}
+
+impl LocalAuthenticator {
+    pub fn new(jwt: Option<JwtVerifier>) -> Self {
+        LocalAuthenticator { jwt }
+    }
+}
+
+#[async_trait]
+impl Authenticator for LocalAuthenticator {
+    async fn validate(&self, room: &Room, token: &str) -> Result<UserClaims, AuthError> {
+        let jwt = self
+            .jwt
+            .as_ref()
+            .and_then(|jwt| jwt.verify(token, room.tenant(), room.id()));
+        match jwt {
+            Some(Ok(claims)) => Ok(UserClaims {
+                user_id: claims.sub,
+                options: Some(claims.options),
+                expires: claims.exp,
+            }),
+            Some(Err(())) => Err(AuthError::Refused),
+            None => match token::verify(token, room.scoped_id(), token::now()) {
+                Ok(claims) => Ok(UserClaims {
+                    user_id: claims.sub,
+                    options: None,
+                    expires: claims.exp,
+                }),
+                Err(err) => {
+                    tracing::debug!(error = %err, "Rejected join token");
+                    Err(AuthError::Refused)
+                }
+            },
+        }
+    }
+}
+
+/// Sent to the endpoint of the `http` backend
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidationRequest<'a> {
+    tenant: &'a str,
+    room_id: &'a str,
+    token: &'a str,
+}
+
+/// Answer of the endpoint to a token it accepts, along with the fields of `UserOptions`
+/// such as `permissions` and `role`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidationResponse {
+    user_id: String,
+    /// UNIX time in seconds
+    expires_at: u64,
+    #[serde(flatten)]
+    options: UserOptions,
+}
+
+/// Posts tokens to an endpoint, which answers a success status with the user for tokens
+/// it accepts and a client error status for those it refuses. Anything else, including
+/// no answer within the timeout, is an outage. Answers are reused for the same token
+/// and room for `auth.cache_secs`
+pub struct HttpAuthenticator {
+    client: Client<HttpConnector>,
+    url: String,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<(String, String), (Result<UserClaims, AuthError>, Instant)>>,
+}
+
+impl HttpAuthenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        HttpAuthenticator {
+            client: Client::new(),
+            url: config.url.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            cache_ttl: Duration::from_secs(config.cache_secs),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn ask(&self, room: &Room, token: &str) -> Result<UserClaims, AuthError> {
+        let body = serde_json::to_vec(&ValidationRequest {
+            tenant: room.tenant(),
+            room_id: room.id(),
+            token,
+        })
+        .map_err(|_| AuthError::Unavailable)?;
+        let request = Request::post(self.url.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|_| AuthError::Unavailable)?;
+
+        let answer = tokio::time::timeout(self.timeout, async {
+            let response = self.client.request(request).await?;
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            Ok::<_, hyper::Error>((status, body))
+        })
+        .await;
+        let (status, body) = match answer {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(err)) => {
+                warn!("Authentication endpoint {} failed: {}", self.url, err);
+                return Err(AuthError::Unavailable);
+            }
+            Err(_) => {
+                warn!("Authentication endpoint {} timed out", self.url);
+                return Err(AuthError::Unavailable);
+            }
+        };
+
+        if status.is_client_error() {
+            tracing::debug!(status = %status, "Authentication endpoint refused the token");
+            return Err(AuthError::Refused);
+        }
+        if !status.is_success() {
+            warn!("Authentication endpoint {} answered {}", self.url, status);
+            return Err(AuthError::Unavailable);
+        }
+
+        let response: ValidationResponse = serde_json::from_slice(&body).map_err(|err| {
+            warn!(
+                "Authentication endpoint {} answered badly: {}",
+                self.url, err
+            );
+            AuthError::Unavailable
+        })?;
+        if !metadata_fits(&response.options.metadata) {
+            tracing::debug!("Authentication endpoint gave oversized metadata");
+            return Err(AuthError::Refused);
+        }
+        Ok(UserClaims {
+            user_id: response.user_id,
+            options: Some(response.options),
+            expires: response.expires_at,
+        })
+    }
+
+    fn cached(&self, key: &(String, String)) -> Option<Result<UserClaims, AuthError>> {
+        let cache = self.cache.lock();
+        let (answer, at) = cache.get(key)?;
+        Some(answer.clone()).filter(|_| at.elapsed() < self.cache_ttl)
+    }
+
+    /// Outages aren't kept, the next attempt asks again
+    fn cache(&self, key: (String, String), answer: &Result<UserClaims, AuthError>) {
+        if self.cache_ttl.is_zero() || matches!(answer, Err(AuthError::Unavailable)) {
+            return;
+        }
+        let mut cache = self.cache.lock();
+        if cache.len() >= MAX_CACHED_ANSWERS {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (_, at)| at.elapsed() < ttl);
+            if cache.len() >= MAX_CACHED_ANSWERS {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (answer.clone(), Instant::now()));
+    }
+}
+
+#[async_trait]
+impl Authenticator for HttpAuthenticator {
+    async fn validate(&self, room: &Room, token: &str) -> Result<UserClaims, AuthError> {
+        let key = (room.scoped_id().to_string(), token.to_string());
+        if let Some(answer) = self.cached(&key) {
+            return answer;
+        }
+        let answer = self.ask(room, token).await;
+        self.cache(key, &answer);
+        answer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::RoomSettings;
+    use crate::state::user::{Permission, Role};
+    use crate::util::config::AuthBackend;
+    use crate::util::testing;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    fn config(url: String) -> AuthConfig {
+        AuthConfig {
+            backend: AuthBackend::Http,
+            url,
+            timeout_ms: 1000,
+            cache_secs: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_answers_are_cached() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let endpoint = warp::post()
+            .and(warp::body::json())
+            .map(move |request: Value| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let answer = json!({
+                    "userId": "alice",
+                    "expiresAt": 2_000_000_000u64,
+                    "role": "moderator",
+                    "permissions": ["moderator"],
+                });
+                match request["token"] == "good" {
+                    true => warp::reply::with_status(warp::reply::json(&answer), StatusCode::OK),
+                    false => warp::reply::with_status(
+                        warp::reply::json(&json!({})),
+                        StatusCode::FORBIDDEN,
+                    ),
+                }
+            });
+        let (addr, server) = warp::serve(endpoint).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let room = testing::room(RoomSettings::default()).await;
+        let authenticator = HttpAuthenticator::new(&config(format!("http://{}/", addr)));
+        let claims = authenticator.validate(&room, "good").await.unwrap();
+        assert_eq!(claims.user_id, "alice");
+        assert_eq!(claims.expires, 2_000_000_000);
+        let options = claims.options.unwrap();
+        assert_eq!(options.role, Role::Moderator);
+        assert_eq!(options.permissions, vec![Permission::Moderator]);
+        assert_eq!(
+            authenticator.validate(&room, "bad").await.err(),
+            Some(AuthError::Refused)
+        );
+
+        authenticator.validate(&room, "good").await.unwrap();
+        authenticator.validate(&room, "bad").await.ok();
+        assert_eq!(asked.load(Ordering::Relaxed), 2);
+
+        // Nothing listens on the discard port
+        let down = HttpAuthenticator::new(&config("http://127.0.0.1:9/".to_string()));
+        assert_eq!(
+            down.validate(&room, "good").await.err(),
+            Some(AuthError::Unavailable)
+        );
+        assert_eq!(
+            WSCloseType::from(AuthError::Unavailable).code(),
+            WSCloseType::ServerError.code()
+        );
+    }
+}
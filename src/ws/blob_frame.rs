@@ -0,0 +1,98 @@
+//! Blob chunks sent as raw bytes in binary frames, sparing clients the base64 of
+//! `RelayBlobChunk`. Such a frame starts with a NUL byte, which no JSON does, then has
+//! the `RelayBlobChunk` command without the chunk's `data`, another NUL byte and the
+//! bytes of the chunk:
+//!
+//! `\0{"id":"7","type":"RelayBlobChunk","data":{"transferId":"…","seq":0}}\0<bytes>`
+//!
+//! The command then goes through like one sent as text, its recipients get the chunk
+//! base64 encoded in a `RelayBlobChunk` event either way
+
+use serde::de::Error;
+use serde_json::Value;
+use warp::ws::Message;
+
+use super::types::WSCommand;
+
+/// Byte starting the frame and ending the command before the chunk's bytes
+const SEPARATOR: u8 = 0;
+
+/// Stands for the frame's text where the event loop keeps it for error reports
+pub const FRAME_TEXT: &str = "binary RelayBlobChunk";
+
+/// Command of a binary frame carrying a blob chunk, `None` for any other frame
+pub fn chunk_command(message: &Message) -> Option<serde_json::Result<WSCommand>> {
+    if !message.is_binary() {
+        return None;
+    }
+    match message.as_bytes() {
+        [SEPARATOR, rest @ ..] => Some(parse(rest)),
+        _ => None,
+    }
+}
+
+fn parse(frame: &[u8]) -> serde_json::Result<WSCommand> {
+    let end = frame
+        .iter()
+        .position(|byte| *byte == SEPARATOR)
+        .ok_or_else(|| serde_json::Error::custom("Blob chunk frame has no separator"))?;
+    let mut command: Value = serde_json::from_slice(&frame[..end])?;
+    if command["type"] != "RelayBlobChunk" {
+        return Err(serde_json::Error::custom(
+            "Binary frames starting with NUL only carry RelayBlobChunk",
+        ));
+    }
+    match command.get_mut("data").and_then(Value::as_object_mut) {
+        Some(data) => {
+            let chunk = base64::encode(&frame[end + 1..]);
+            data.insert("data".to_string(), Value::String(chunk));
+        }
+        None => return Err(serde_json::Error::custom("Blob chunk frame has no data")),
+    }
+    serde_json::from_value(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::types::WSCommandType;
+
+    fn frame(command: &str, chunk: &[u8]) -> Message {
+        let mut bytes = vec![SEPARATOR];
+        bytes.extend_from_slice(command.as_bytes());
+        bytes.push(SEPARATOR);
+        bytes.extend_from_slice(chunk);
+        Message::binary(bytes)
+    }
+
+    #[test]
+    fn binary_chunks_become_commands() {
+        let command = r#"{"id":"7","type":"RelayBlobChunk","data":{"transferId":"t","seq":2}}"#;
+        let out = chunk_command(&frame(command, &[0, 0xff, 0x10]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(out.id.as_deref(), Some("7"));
+        match out.command_type {
+            WSCommandType::RelayBlobChunk {
+                transfer_id,
+                seq,
+                data,
+            } => {
+                assert_eq!((transfer_id.as_str(), seq), ("t", 2));
+                assert_eq!(base64::decode(data).unwrap(), vec![0, 0xff, 0x10]);
+            }
+            _ => panic!("Expected a blob chunk"),
+        }
+
+        // JSON in binary frames is left to the usual parsing
+        assert!(chunk_command(&Message::binary(command.as_bytes())).is_none());
+        assert!(chunk_command(&Message::text(command)).is_none());
+
+        let other = r#"{"type":"RelayBlobEnd","data":{"transferId":"t"}}"#;
+        assert!(chunk_command(&frame(other, b"x")).unwrap().is_err());
+        let unterminated = [&[SEPARATOR][..], command.as_bytes()].concat();
+        assert!(chunk_command(&Message::binary(unterminated))
+            .unwrap()
+            .is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Concurrent WebSocket connections by remote address, so a single host can't open
+//! connections until the node runs out of memory before any of them authenticates
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use parking_lot::Mutex;
+
+use crate::util::config::CONFIG;
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+}
+
+/// A connection counted against its address, released when dropped however the
+/// connection ends
+pub struct ConnectionSlot {
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    /// Count another connection from the address, `None` if it already has as many as
+    /// `signaling.max_connections_per_ip` allows
+    pub fn acquire(ip: IpAddr) -> Option<ConnectionSlot> {
+        let limit = CONFIG.signaling.max_connections_per_ip;
+        let mut connections = CONNECTIONS.lock();
+        let count = connections.entry(ip).or_insert(0);
+        if limit > 0 && *count >= limit {
+            return None;
+        }
+
+        *count += 1;
+        Some(ConnectionSlot { ip })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Connections currently counted against the address
+pub fn count(ip: IpAddr) -> usize {
+    CONNECTIONS.lock().get(&ip).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn slots_are_released_when_dropped() {
+        testing::init();
+        let limit = CONFIG.signaling.max_connections_per_ip;
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let slots: Vec<ConnectionSlot> = (0..limit)
+            .map(|_| ConnectionSlot::acquire(ip).unwrap())
+            .collect();
+        assert_eq!(count(ip), limit);
+        assert!(ConnectionSlot::acquire(ip).is_none());
+
+        // Other hosts aren't affected
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(ConnectionSlot::acquire(other).is_some());
+
+        drop(slots);
+        assert_eq!(count(ip), 0);
+        assert!(ConnectionSlot::acquire(ip).is_some());
+    }
+}
@@ -1,26 +1,279 @@
+use schemars::JsonSchema;
 use serde::Serialize;
+use serde_json::Value;
 use std::fmt::{self, Display};
 use strum::IntoStaticStr;
 
-use super::types::WSCommand;
+use super::phase::Phase;
+use super::types::{Capability, WSCommand, MAX_BATCH_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::rtc::hls::HlsError;
+use crate::rtc::opus::OpusError;
+use crate::rtc::recording::RecordingError;
+use crate::rtc::ProduceError;
+use crate::state::room::blob::BlobError;
+use crate::state::room::users::MAX_KICK_REASON;
+use crate::state::room::MetadataError;
+use crate::state::user::Permission;
+use crate::util::config::CONFIG;
 
 #[derive(IntoStaticStr)]
 pub enum WSErrorType {
     UserNotFound(String),
+    /// Name of the oversized user info field
+    InvalidUserInfo(&'static str),
+    MissingPermission(Permission),
+    /// Length of the kick, ban or report reason in bytes
+    InvalidKickReason(usize),
+    /// `RefreshToken` was given a token that isn't valid for the session's user and room
+    TokenRefused,
+    /// The user waits in the lobby of the locked room, see `RoomSettings::lobby`
+    RoomLocked,
+    /// ID of a user a moderator tried to admit who isn't in the lobby
+    NotWaiting(String),
+    /// The authentication backend couldn't check the token, the session is kept until
+    /// it expires and the refresh may be tried again
+    AuthUnavailable,
 
     TransportConnectionFailure,
 
     ProducerFailure,
     ProducerNotFound(String),
+    /// Produce type whose limit was reached
+    TooManyProducers(String),
+    /// Name of the Opus option out of range, or `codec` when the producer isn't Opus
+    InvalidOpusOptions(&'static str),
+    /// The user is a listener, or its join token doesn't allow producing
+    ListenOnly,
+    /// Session initialized with `RecvWebRtc`, it has nothing to produce on
+    NoSendTransport,
+    /// The room's cap on the kind's bitrate, which the encodings can't be clamped under
+    BitrateUnattainable(u32),
+    /// Scalability mode of an encoding the room doesn't allow
+    ScalabilityModeNotAllowed(String),
 
     ConsumerFailure,
     ConsumerNotFound(String),
+    /// Requested priority, outside of 1 to 255
+    InvalidConsumerPriority(u32),
+    /// Own producers can only be consumed in echo test rooms
+    OwnProducer,
+    /// ID of the audio consumer a keyframe or layers were requested for
+    NotVideoConsumer(String),
+    /// ID of the consumer whose producer doesn't send the requested layers
+    InvalidConsumerLayers(String),
+
+    Overloaded {
+        retry_after_ms: u64,
+    },
+    /// The connection has too many commands waiting to run, or the user relayed
+    /// too many messages
+    TooManyRequests,
+    /// The user started and stopped producers too often
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    /// The user reported others too often
+    TooManyReports {
+        retry_after_ms: u64,
+    },
+
+    RecordingFailure,
+    RecordingUnavailable,
+    AlreadyRecording,
+    NotRecording,
+    RecordingNotAllowed,
+    /// The user wasn't asked whether it consents to being recorded
+    ConsentNotRequested,
+
+    SettingsFailure,
+    HlsUnavailable,
+    /// mediasoup failed to dump what was asked for
+    DumpFailure,
+    /// Why the room's metadata refused the value
+    InvalidRoomMetadata(MetadataError),
+    /// Rooms on this server don't keep recent events, see `EventReplayConfig`
+    RecentEventsUnavailable,
+
+    /// Number of commands in the batch
+    BatchTooLarge(usize),
+    NestedBatch,
+    /// Why the frame couldn't be parsed, sent right before the connection is closed
+    InvalidCommand(String),
+    /// Size of the command's data in bytes
+    CommandTooLarge(usize),
+    /// Type of a command this server doesn't know, as sent by newer clients
+    UnknownCommand(String),
+    /// Capability the command belongs to, which the client didn't declare when
+    /// authenticating
+    CapabilityNotDeclared(Capability),
+    /// Phase the connection was in when the command came, sent right before the
+    /// connection is closed with `InvalidState`
+    CommandOutOfOrder(Phase),
+
+    /// Size of the payload in bytes
+    RelayTooLarge(usize),
+    /// Declared size of the blob in bytes
+    BlobTooLarge(usize),
+    /// ID of a blob transfer the connection isn't sending
+    BlobTransferNotFound(String),
+    /// The user is sending `signaling.max_blob_transfers` blobs already
+    TooManyBlobTransfers,
+    /// Name of the chunk field refused, `data` when it isn't base64 or runs past the
+    /// blob's size
+    InvalidBlobChunk(&'static str),
+    /// Sequence number the chunk should have had
+    BlobChunkOutOfOrder(u64),
+    /// Bytes sent of the blob when it was ended short of its size
+    BlobIncomplete(usize),
+    /// Every recipient of the blob left or stopped keeping up
+    BlobUndeliverable,
+
+    /// ID of a room the connection didn't join
+    NotInRoom(String),
+    /// ID of a room the connection is already in
+    AlreadyInRoom(String),
+    /// ID of the room whose token was refused
+    JoinRefused(String),
+    /// Rooms joined with `JoinRoom` have no transports, media commands can't run in them
+    SignalOnly,
+    /// Why `Authenticate` on an established connection couldn't switch rooms, the
+    /// connection was left in none and waits for another `Authenticate`
+    SwitchRefused(String),
+}
+
+impl WSErrorType {
+    /// Stable numeric code sent to clients, these are part of the protocol
+    /// and must never be changed or reused
+    pub fn code(&self) -> u16 {
+        match self {
+            WSErrorType::UserNotFound(_) => 1000,
+            WSErrorType::InvalidUserInfo(_) => 1001,
+            WSErrorType::MissingPermission(_) => 1002,
+            WSErrorType::InvalidKickReason(_) => 1003,
+            WSErrorType::TokenRefused => 1004,
+            WSErrorType::RoomLocked => 1005,
+            WSErrorType::NotWaiting(_) => 1006,
+            WSErrorType::AuthUnavailable => 1007,
+
+            WSErrorType::TransportConnectionFailure => 2000,
+
+            WSErrorType::ProducerFailure => 3000,
+            WSErrorType::ProducerNotFound(_) => 3001,
+            WSErrorType::TooManyProducers(_) => 3002,
+            WSErrorType::InvalidOpusOptions(_) => 3003,
+            WSErrorType::ListenOnly => 3004,
+            WSErrorType::NoSendTransport => 3005,
+            WSErrorType::BitrateUnattainable(_) => 3006,
+            WSErrorType::ScalabilityModeNotAllowed(_) => 3007,
+
+            WSErrorType::ConsumerFailure => 4000,
+            WSErrorType::ConsumerNotFound(_) => 4001,
+            WSErrorType::InvalidConsumerPriority(_) => 4002,
+            WSErrorType::OwnProducer => 4003,
+            WSErrorType::NotVideoConsumer(_) => 4004,
+            WSErrorType::InvalidConsumerLayers(_) => 4005,
+
+            WSErrorType::Overloaded { .. } => 5000,
+            WSErrorType::TooManyRequests => 5001,
+            WSErrorType::RateLimited { .. } => 5002,
+            WSErrorType::TooManyReports { .. } => 5003,
+
+            WSErrorType::RecordingFailure => 6000,
+            WSErrorType::RecordingUnavailable => 6001,
+            WSErrorType::AlreadyRecording => 6002,
+            WSErrorType::NotRecording => 6003,
+            WSErrorType::RecordingNotAllowed => 6004,
+            WSErrorType::ConsentNotRequested => 6005,
+
+            WSErrorType::BatchTooLarge(_) => 7000,
+            WSErrorType::NestedBatch => 7001,
+            WSErrorType::InvalidCommand(_) => 7002,
+            WSErrorType::CommandTooLarge(_) => 7003,
+            WSErrorType::UnknownCommand(_) => 7004,
+            WSErrorType::CapabilityNotDeclared(_) => 7005,
+            WSErrorType::CommandOutOfOrder(_) => 7006,
+
+            WSErrorType::RelayTooLarge(_) => 8000,
+            WSErrorType::BlobTooLarge(_) => 8001,
+            WSErrorType::BlobTransferNotFound(_) => 8002,
+            WSErrorType::TooManyBlobTransfers => 8003,
+            WSErrorType::InvalidBlobChunk(_) => 8004,
+            WSErrorType::BlobChunkOutOfOrder(_) => 8005,
+            WSErrorType::BlobIncomplete(_) => 8006,
+            WSErrorType::BlobUndeliverable => 8007,
+
+            WSErrorType::SettingsFailure => 9000,
+            WSErrorType::HlsUnavailable => 9001,
+            WSErrorType::DumpFailure => 9002,
+            WSErrorType::InvalidRoomMetadata(_) => 9003,
+            WSErrorType::RecentEventsUnavailable => 9004,
+
+            WSErrorType::NotInRoom(_) => 10000,
+            WSErrorType::AlreadyInRoom(_) => 10001,
+            WSErrorType::JoinRefused(_) => 10002,
+            WSErrorType::SignalOnly => 10003,
+            WSErrorType::SwitchRefused(_) => 10004,
+        }
+    }
+
+    /// Identifier of the entity the error refers to
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            WSErrorType::UserNotFound(id)
+            | WSErrorType::NotWaiting(id)
+            | WSErrorType::ProducerNotFound(id)
+            | WSErrorType::ConsumerNotFound(id)
+            | WSErrorType::NotVideoConsumer(id)
+            | WSErrorType::InvalidConsumerLayers(id)
+            | WSErrorType::TooManyProducers(id)
+            | WSErrorType::NotInRoom(id)
+            | WSErrorType::AlreadyInRoom(id)
+            | WSErrorType::JoinRefused(id)
+            | WSErrorType::UnknownCommand(id)
+            | WSErrorType::ScalabilityModeNotAllowed(id)
+            | WSErrorType::BlobTransferNotFound(id) => Some(id),
+            WSErrorType::InvalidUserInfo(field)
+            | WSErrorType::InvalidOpusOptions(field)
+            | WSErrorType::InvalidBlobChunk(field) => Some(field),
+            WSErrorType::MissingPermission(permission) => Some(permission.name()),
+            WSErrorType::CapabilityNotDeclared(capability) => Some(capability.name()),
+            WSErrorType::CommandOutOfOrder(phase) => Some(phase.name()),
+            _ => None,
+        }
+    }
+
+    /// How long the client should wait before retrying the command
+    pub fn retry_after_ms(&self) -> Option<u64> {
+        match self {
+            WSErrorType::Overloaded { retry_after_ms }
+            | WSErrorType::RateLimited { retry_after_ms }
+            | WSErrorType::TooManyReports { retry_after_ms } => Some(*retry_after_ms),
+            _ => None,
+        }
+    }
 }
 
 impl Display for WSErrorType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WSErrorType::UserNotFound(id) => write!(f, "User with ID {} doesn't exist", id),
+            WSErrorType::UserNotFound(_) => write!(f, "User doesn't exist"),
+            WSErrorType::InvalidUserInfo(_) => write!(f, "User info field is too large"),
+            WSErrorType::MissingPermission(_) => {
+                write!(f, "User lacks the permission required for this command")
+            }
+            WSErrorType::InvalidKickReason(length) => write!(
+                f,
+                "Reason of {} bytes is above the limit of {}",
+                length, MAX_KICK_REASON
+            ),
+            WSErrorType::TokenRefused => {
+                write!(f, "Token is invalid, expired or for another user or room")
+            }
+            WSErrorType::RoomLocked => {
+                write!(f, "Room is locked, wait in the lobby until it's opened")
+            }
+            WSErrorType::NotWaiting(_) => write!(f, "User isn't waiting in the lobby"),
+            WSErrorType::AuthUnavailable => write!(f, "Tokens can't be checked right now"),
             WSErrorType::TransportConnectionFailure => {
                 write!(f, "An error occured while trying to connect transport")
             }
@@ -29,46 +282,368 @@ impl Display for WSErrorType {
                 f,
                 "An unknown error occured while setting up an RTC producer"
             ),
-            WSErrorType::ProducerNotFound(id) => write!(f, "Producer with ID {} doesn't exist", id),
+            WSErrorType::ProducerNotFound(_) => write!(f, "Producer doesn't exist"),
+            WSErrorType::TooManyProducers(produce_type) => {
+                write!(f, "Producer limit reached for {}", produce_type)
+            }
+            WSErrorType::InvalidOpusOptions(_) => {
+                write!(
+                    f,
+                    "Opus options are out of range or don't apply to this producer"
+                )
+            }
+            WSErrorType::ListenOnly => write!(f, "User isn't allowed to produce"),
+            WSErrorType::NoSendTransport => {
+                write!(f, "Session was initialized without a send transport")
+            }
+            WSErrorType::BitrateUnattainable(cap) => write!(
+                f,
+                "Encodings can't fit under the room's cap of {} bits per second",
+                cap
+            ),
+            WSErrorType::ScalabilityModeNotAllowed(_) => {
+                write!(f, "Scalability mode isn't allowed in this room")
+            }
 
             WSErrorType::ConsumerFailure => write!(
                 f,
                 "An unknown error occured while setting up an RTC consumer"
             ),
-            WSErrorType::ConsumerNotFound(id) => write!(f, "Consumer with ID {} doesn't exist", id),
+            WSErrorType::ConsumerNotFound(_) => write!(f, "Consumer doesn't exist"),
+            WSErrorType::InvalidConsumerPriority(priority) => write!(
+                f,
+                "Consumer priority must be between 1 and 255, got {}",
+                priority
+            ),
+            WSErrorType::OwnProducer => {
+                write!(f, "Own media can only be consumed in echo test rooms")
+            }
+            WSErrorType::NotVideoConsumer(_) => {
+                write!(f, "Keyframes and layers only exist for video consumers")
+            }
+            WSErrorType::InvalidConsumerLayers(_) => {
+                write!(f, "Producer doesn't send the requested layers")
+            }
+
+            WSErrorType::Overloaded { .. } => {
+                write!(f, "Server is overloaded, retry the command later")
+            }
+            WSErrorType::TooManyRequests => write!(
+                f,
+                "Too many commands waiting to run, wait for replies before sending more"
+            ),
+            WSErrorType::RateLimited { .. } => write!(
+                f,
+                "Producers are started and stopped too often, retry the command later"
+            ),
+            WSErrorType::TooManyReports { .. } => {
+                write!(f, "Users are reported too often, retry the command later")
+            }
+
+            WSErrorType::RecordingFailure => {
+                write!(f, "An error occured while starting the recording")
+            }
+            WSErrorType::RecordingUnavailable => {
+                write!(f, "Recording is not enabled on this server")
+            }
+            WSErrorType::AlreadyRecording => write!(f, "Room is already being recorded"),
+            WSErrorType::NotRecording => write!(f, "Room is not being recorded"),
+            WSErrorType::RecordingNotAllowed => {
+                write!(f, "Recording is not allowed in this room")
+            }
+            WSErrorType::ConsentNotRequested => {
+                write!(f, "Consent to being recorded wasn't requested")
+            }
+
+            WSErrorType::SettingsFailure => {
+                write!(f, "An error occured while applying the room settings")
+            }
+            WSErrorType::HlsUnavailable => write!(f, "HLS is not enabled on this server"),
+            WSErrorType::DumpFailure => write!(f, "Failed to dump media state"),
+            WSErrorType::InvalidRoomMetadata(err) => write!(f, "{}", err),
+            WSErrorType::RecentEventsUnavailable => {
+                write!(f, "Recent events are not kept on this server")
+            }
+
+            WSErrorType::BatchTooLarge(size) => write!(
+                f,
+                "Batch of {} commands is above the limit of {}",
+                size, MAX_BATCH_SIZE
+            ),
+            WSErrorType::NestedBatch => write!(f, "Batches can't contain other batches"),
+            WSErrorType::InvalidCommand(err) => write!(f, "Unable to parse command: {}", err),
+            WSErrorType::CommandTooLarge(size) => write!(
+                f,
+                "Command data of {} bytes is above the limit of {}",
+                size, CONFIG.signaling.max_command_size
+            ),
+            WSErrorType::UnknownCommand(_) => write!(f, "Command type isn't known to this server"),
+            WSErrorType::CapabilityNotDeclared(_) => write!(
+                f,
+                "Command belongs to a capability the client didn't declare"
+            ),
+            WSErrorType::CommandOutOfOrder(phase) => {
+                write!(f, "Command can't be sent while the connection is {}", phase)
+            }
+
+            WSErrorType::RelayTooLarge(size) => write!(
+                f,
+                "Relayed payload of {} bytes is above the limit of {}",
+                size, CONFIG.signaling.max_relay_payload
+            ),
+            WSErrorType::BlobTooLarge(size) => write!(
+                f,
+                "Blob of {} bytes is above the limit of {}",
+                size, CONFIG.signaling.max_blob_size
+            ),
+            WSErrorType::BlobTransferNotFound(_) => {
+                write!(f, "Blob transfer doesn't exist or isn't this connection's")
+            }
+            WSErrorType::TooManyBlobTransfers => write!(
+                f,
+                "Too many blobs being sent at once, end one before starting another"
+            ),
+            WSErrorType::InvalidBlobChunk(_) => {
+                write!(f, "Chunk isn't base64 or runs past the blob's size")
+            }
+            WSErrorType::BlobChunkOutOfOrder(expected) => {
+                write!(f, "Chunk is out of order, {} was expected", expected)
+            }
+            WSErrorType::BlobIncomplete(received) => write!(
+                f,
+                "Blob was ended after {} bytes, short of its size",
+                received
+            ),
+            WSErrorType::BlobUndeliverable => {
+                write!(f, "None of the blob's recipients are left to receive it")
+            }
+
+            WSErrorType::NotInRoom(_) => write!(f, "Connection hasn't joined this room"),
+            WSErrorType::AlreadyInRoom(_) => write!(f, "Connection is already in this room"),
+            WSErrorType::JoinRefused(_) => write!(f, "Token was refused for this room"),
+            WSErrorType::SignalOnly => {
+                write!(
+                    f,
+                    "Rooms joined with JoinRoom have no transports for this command"
+                )
+            }
+            WSErrorType::SwitchRefused(reason) => write!(
+                f,
+                "Couldn't switch rooms ({}), authenticate again to join one",
+                reason
+            ),
+        }
+    }
+}
+
+impl From<RecordingError> for WSErrorType {
+    fn from(err: RecordingError) -> Self {
+        match err {
+            RecordingError::Unavailable => WSErrorType::RecordingUnavailable,
+            RecordingError::AlreadyRecording => WSErrorType::AlreadyRecording,
+            RecordingError::NotRecording => WSErrorType::NotRecording,
+            RecordingError::NotAllowed => WSErrorType::RecordingNotAllowed,
+            RecordingError::ConsentNotRequested => WSErrorType::ConsentNotRequested,
+            RecordingError::Egress(_) => WSErrorType::RecordingFailure,
+        }
+    }
+}
+
+impl From<HlsError> for WSErrorType {
+    fn from(err: HlsError) -> Self {
+        match err {
+            HlsError::Unavailable => WSErrorType::HlsUnavailable,
+            HlsError::Egress(_) => WSErrorType::SettingsFailure,
+        }
+    }
+}
+
+impl From<BlobError> for WSErrorType {
+    fn from(err: BlobError) -> Self {
+        match err {
+            BlobError::TooLarge(size) => WSErrorType::BlobTooLarge(size),
+            BlobError::NotFound(id) => WSErrorType::BlobTransferNotFound(id),
+            BlobError::TooMany => WSErrorType::TooManyBlobTransfers,
+            BlobError::OutOfOrder(expected) => WSErrorType::BlobChunkOutOfOrder(expected),
+            BlobError::Overflow => WSErrorType::InvalidBlobChunk("data"),
+            BlobError::Incomplete(received) => WSErrorType::BlobIncomplete(received),
+            BlobError::Undeliverable => WSErrorType::BlobUndeliverable,
+        }
+    }
+}
+
+impl From<ProduceError> for WSErrorType {
+    fn from(err: ProduceError) -> Self {
+        match err {
+            ProduceError::Opus(OpusError::OutOfRange(option)) => {
+                WSErrorType::InvalidOpusOptions(option)
+            }
+            ProduceError::Opus(OpusError::NotOpus) => WSErrorType::InvalidOpusOptions("codec"),
+            ProduceError::NoSendTransport => WSErrorType::NoSendTransport,
+            ProduceError::Failed => WSErrorType::ProducerFailure,
         }
     }
 }
 
-#[repr(u16)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug)]
 pub enum WSCloseType {
-    /// Sent when the received data is unparseable
-    InvalidData = 1003,
-    /// Sent when a client tries to send a command in the wrong state
-    InvalidState = 1002,
-    Unauthorized = 4001,
-    Kicked = 4003,
-    RoomClosed = 4004,
-    ServerError = 1011,
+    /// Sent when the received data is unparseable, with what serde made of it
+    InvalidData(String),
+    /// Sent when a client sends a command its connection doesn't take in the phase it's
+    /// in, with the command's type
+    InvalidState(&'static str, Phase),
+    Unauthorized,
+    /// Reason the moderator gave, if any, sent as the close reason
+    Kicked(Option<String>),
+    RoomClosed,
+    /// Sent when another connection authenticated as the same user
+    SessionReplaced,
+    /// Sent once a session lasted as long as its join token allows
+    SessionExpired,
+    /// Sent when a banned user authenticates
+    Banned,
+    /// Sent when the client stopped answering pings
+    PingTimeout,
+    /// Sent when the client reads too slowly for even replies to be written
+    SlowConsumer,
+    /// Sent when the client took too long to authenticate or initialize its transports
+    HandshakeTimeout,
+    /// Sent when the client authenticates with a protocol version that isn't spoken
+    UnsupportedVersion(u32),
+    /// Refused when the client's address has too many connections open already
+    TooManyConnections,
+    /// Sent once the session's token expired without being refreshed, when token expiry
+    /// is enforced
+    TokenExpired,
+    /// Sent when the server ran out of ports or memory for the connection's transports,
+    /// the client should try another instance
+    ServerOverloaded,
+    /// Sent when the user did nothing for the room's `IdleSettings::disconnect_after_secs`
+    IdleDisconnect,
+    /// Sent once the client left with `Leave`
+    Left,
+    /// Refused when a browser opens the connection from a page outside
+    /// `signaling.allowed_origins`
+    OriginNotAllowed,
+    ServerError,
+}
+
+/// Most bytes a close frame's reason can hold, what's left of the 125 byte control
+/// frame payload after the close code
+pub const MAX_CLOSE_REASON: usize = 123;
+
+impl WSCloseType {
+    /// Reason sent in the close frame, cut short to fit
+    pub fn reason(&self) -> String {
+        let mut reason = self.to_string();
+        if reason.len() > MAX_CLOSE_REASON {
+            let mut end = MAX_CLOSE_REASON;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        reason
+    }
+
+    /// Close code sent to the client, part of the protocol like error codes
+    pub fn code(&self) -> u16 {
+        match self {
+            WSCloseType::InvalidData(_) => 1003,
+            WSCloseType::InvalidState(..) => 1002,
+            WSCloseType::Unauthorized => 4001,
+            WSCloseType::Kicked(_) => 4003,
+            WSCloseType::RoomClosed => 4004,
+            WSCloseType::SessionReplaced => 4006,
+            WSCloseType::SessionExpired => 4007,
+            WSCloseType::Banned => 4008,
+            WSCloseType::PingTimeout => 4009,
+            WSCloseType::SlowConsumer => 4010,
+            WSCloseType::HandshakeTimeout => 4011,
+            WSCloseType::UnsupportedVersion(_) => 4012,
+            WSCloseType::TooManyConnections => 4013,
+            WSCloseType::TokenExpired => 4014,
+            WSCloseType::ServerOverloaded => 1013,
+            WSCloseType::IdleDisconnect => 4015,
+            WSCloseType::Left => 1000,
+            WSCloseType::OriginNotAllowed => 4016,
+            WSCloseType::ServerError => 1011,
+        }
+    }
+
+    /// Whether the connection was lost rather than closed on purpose, by the client or for
+    /// a reason that ends the session. Its user may come back on a new connection
+    pub fn dropped(&self) -> bool {
+        matches!(
+            self,
+            WSCloseType::InvalidData(_)
+                | WSCloseType::PingTimeout
+                | WSCloseType::SlowConsumer
+                | WSCloseType::ServerOverloaded
+                | WSCloseType::ServerError
+        )
+    }
+
+    /// Whether it only ends the user's session in one room, rooms the connection joined
+    /// with `JoinRoom` are left on these instead of closing the connection
+    pub fn room_scoped(&self) -> bool {
+        matches!(
+            self,
+            WSCloseType::Kicked(_)
+                | WSCloseType::RoomClosed
+                | WSCloseType::SessionReplaced
+                | WSCloseType::Banned
+        )
+    }
 }
 
 impl Display for WSCloseType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WSCloseType::InvalidData => write!(f, "Unable to parse data"),
-            WSCloseType::InvalidState => write!(f, "Command executed in invalid state"),
+            WSCloseType::InvalidData(err) => write!(f, "Unable to parse data: {}", err),
+            WSCloseType::InvalidState(command, phase) => {
+                write!(
+                    f,
+                    "{} can't be sent while the connection is {}",
+                    command, phase
+                )
+            }
             WSCloseType::Unauthorized => write!(f, "Invalid token"),
-            WSCloseType::Kicked => write!(f, "You have been kicked!"),
+            WSCloseType::Kicked(Some(reason)) => write!(f, "{}", reason),
+            WSCloseType::Kicked(None) => write!(f, "You have been kicked!"),
             WSCloseType::RoomClosed => write!(f, "Room has been closed"),
+            WSCloseType::SessionReplaced => {
+                write!(f, "Session was taken over by another connection")
+            }
+            WSCloseType::SessionExpired => write!(f, "Session has expired"),
+            WSCloseType::Banned => write!(f, "You are banned from this room"),
+            WSCloseType::PingTimeout => write!(f, "Pings went unanswered"),
+            WSCloseType::SlowConsumer => write!(f, "Client is reading too slowly"),
+            WSCloseType::HandshakeTimeout => write!(f, "Session setup took too long"),
+            WSCloseType::UnsupportedVersion(version) => write!(
+                f,
+                "Protocol version {} is not supported, use {} to {}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            WSCloseType::TooManyConnections => {
+                write!(f, "Too many connections from this address")
+            }
+            WSCloseType::TokenExpired => write!(f, "Token expired without being refreshed"),
+            WSCloseType::ServerOverloaded => {
+                write!(f, "Server is out of capacity, try again later")
+            }
+            WSCloseType::IdleDisconnect => write!(f, "Disconnected for being idle too long"),
+            WSCloseType::Left => write!(f, "Left the room"),
+            WSCloseType::OriginNotAllowed => write!(f, "Origin is not allowed to connect"),
             WSCloseType::ServerError => write!(f, "Internal Server Error"),
         }
     }
 }
 
 impl From<serde_json::Error> for WSCloseType {
-    fn from(_: serde_json::Error) -> WSCloseType {
-        WSCloseType::InvalidData
+    /// serde's message names the field or expected type along with the line and column
+    fn from(err: serde_json::Error) -> WSCloseType {
+        WSCloseType::InvalidData(err.to_string())
     }
 }
 
@@ -78,33 +653,308 @@ impl From<warp::Error> for WSCloseType {
     }
 }
 
-#[derive(Serialize)]
+impl From<futures::channel::mpsc::SendError> for WSCloseType {
+    fn from(_: futures::channel::mpsc::SendError) -> WSCloseType {
+        WSCloseType::ServerError
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
 pub struct WSError<'a> {
     id: Option<String>,
+    #[serde(rename = "roomId", skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+    /// Trace ID of the command, which its logs carry too
+    #[serde(rename = "traceId", skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
     #[serde(rename = "type")]
     command_type: &'a str,
+    code: u16,
     error: &'static str,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+    /// Data of the failed command, for connections that authenticated with `debug`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<Value>,
 }
 
 impl<'a> WSError<'a> {
     pub fn new(id: Option<String>, command_type: &'a str, error: WSErrorType) -> Self {
         WSError {
             id,
+            room_id: None,
+            trace_id: None,
             command_type,
+            code: error.code(),
             message: error.to_string(),
+            detail: error.detail().map(str::to_string),
+            retry_after_ms: error.retry_after_ms(),
             error: error.into(),
+            request: None,
         }
     }
 
-    pub fn from_command(command: WSCommand, error: WSErrorType) -> Self {
-        let id = command.id;
+    /// Error for a command, echoing its data if `echo_request` is set
+    pub fn from_command(command: WSCommand, error: WSErrorType, echo_request: bool) -> Self {
         let command_type: &'static str = command.command_type.into();
+        let request = command.data.filter(|_| echo_request).map(redact);
         WSError {
-            id,
-            command_type,
+            trace_id: Some(command.trace_id),
+            room_id: command.room_id,
+            request,
+            ..WSError::new(command.id, command_type, error)
+        }
+    }
+}
+
+/// Error of one part of a command that went ahead with the rest, reported within its
+/// reply rather than as a `WSError`
+#[derive(Serialize, JsonSchema)]
+pub struct InlineError {
+    code: u16,
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl From<WSErrorType> for InlineError {
+    fn from(error: WSErrorType) -> Self {
+        InlineError {
+            code: error.code(),
             message: error.to_string(),
+            detail: error.detail().map(str::to_string),
             error: error.into(),
         }
     }
 }
+
+/// Blank out tokens anywhere in a command's data before it's sent back
+fn redact(mut value: Value) -> Value {
+    match &mut value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                if key == "token" || key.ends_with("Token") {
+                    *field = Value::String("[redacted]".to_string());
+                } else {
+                    *field = redact(field.take());
+                }
+            }
+        }
+        Value::Array(values) => {
+            for field in values.iter_mut() {
+                *field = redact(field.take());
+            }
+        }
+        _ => (),
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn serialize(error: WSErrorType) -> Value {
+        serde_json::to_value(WSError::new(Some("1".to_string()), "test", error)).unwrap()
+    }
+
+    #[test]
+    fn codes_are_stable() {
+        let cases = vec![
+            (WSErrorType::UserNotFound("user".to_string()), 1000),
+            (WSErrorType::InvalidUserInfo("name"), 1001),
+            (WSErrorType::MissingPermission(Permission::Moderator), 1002),
+            (WSErrorType::InvalidKickReason(200), 1003),
+            (WSErrorType::RoomLocked, 1005),
+            (WSErrorType::NotWaiting("user".to_string()), 1006),
+            (WSErrorType::AuthUnavailable, 1007),
+            (WSErrorType::TransportConnectionFailure, 2000),
+            (WSErrorType::ProducerFailure, 3000),
+            (WSErrorType::ProducerNotFound("producer".to_string()), 3001),
+            (WSErrorType::TooManyProducers("audio".to_string()), 3002),
+            (WSErrorType::InvalidOpusOptions("stereo"), 3003),
+            (WSErrorType::ListenOnly, 3004),
+            (WSErrorType::NoSendTransport, 3005),
+            (
+                WSErrorType::ScalabilityModeNotAllowed("L3T3".to_string()),
+                3007,
+            ),
+            (WSErrorType::ConsumerFailure, 4000),
+            (WSErrorType::ConsumerNotFound("consumer".to_string()), 4001),
+            (WSErrorType::InvalidConsumerPriority(0), 4002),
+            (WSErrorType::OwnProducer, 4003),
+            (WSErrorType::NotVideoConsumer("consumer".to_string()), 4004),
+            (
+                WSErrorType::InvalidConsumerLayers("consumer".to_string()),
+                4005,
+            ),
+            (
+                WSErrorType::Overloaded {
+                    retry_after_ms: 500,
+                },
+                5000,
+            ),
+            (WSErrorType::TooManyRequests, 5001),
+            (
+                WSErrorType::RateLimited {
+                    retry_after_ms: 500,
+                },
+                5002,
+            ),
+            (
+                WSErrorType::TooManyReports {
+                    retry_after_ms: 500,
+                },
+                5003,
+            ),
+            (WSErrorType::RecordingFailure, 6000),
+            (WSErrorType::RecordingUnavailable, 6001),
+            (WSErrorType::AlreadyRecording, 6002),
+            (WSErrorType::NotRecording, 6003),
+            (WSErrorType::RecordingNotAllowed, 6004),
+            (WSErrorType::ConsentNotRequested, 6005),
+            (WSErrorType::BatchTooLarge(65), 7000),
+            (WSErrorType::NestedBatch, 7001),
+            (
+                WSErrorType::InvalidCommand("expected value".to_string()),
+                7002,
+            ),
+            (WSErrorType::CommandTooLarge(20000), 7003),
+            (WSErrorType::UnknownCommand("Nope".to_string()), 7004),
+            (WSErrorType::CapabilityNotDeclared(Capability::Relay), 7005),
+            (WSErrorType::CommandOutOfOrder(Phase::AwaitingAuth), 7006),
+            (WSErrorType::RelayTooLarge(5000), 8000),
+            (WSErrorType::TooManyBlobTransfers, 8003),
+            (WSErrorType::BlobChunkOutOfOrder(2), 8005),
+            (WSErrorType::SettingsFailure, 9000),
+            (WSErrorType::HlsUnavailable, 9001),
+            (WSErrorType::DumpFailure, 9002),
+            (WSErrorType::RecentEventsUnavailable, 9004),
+        ];
+
+        for (error, code) in cases {
+            let name: &'static str = (&error).into();
+            let value = serialize(error);
+            assert_eq!(value["code"], code, "{}", name);
+            assert_eq!(value["error"], name);
+        }
+    }
+
+    #[test]
+    fn detail_names_the_entity() {
+        let cases = vec![
+            (WSErrorType::UserNotFound("user".to_string()), json!("user")),
+            (WSErrorType::InvalidUserInfo("name"), json!("name")),
+            (
+                WSErrorType::MissingPermission(Permission::Moderator),
+                json!("moderator"),
+            ),
+            (
+                WSErrorType::ProducerNotFound("producer".to_string()),
+                json!("producer"),
+            ),
+            (
+                WSErrorType::TooManyProducers("audio".to_string()),
+                json!("audio"),
+            ),
+            (
+                WSErrorType::ConsumerNotFound("consumer".to_string()),
+                json!("consumer"),
+            ),
+            (WSErrorType::TransportConnectionFailure, Value::Null),
+            (WSErrorType::ProducerFailure, Value::Null),
+            (WSErrorType::ConsumerFailure, Value::Null),
+            (WSErrorType::InvalidConsumerPriority(0), Value::Null),
+            (
+                WSErrorType::Overloaded {
+                    retry_after_ms: 500,
+                },
+                Value::Null,
+            ),
+        ];
+
+        for (error, detail) in cases {
+            let value = serialize(error);
+            assert_eq!(value["detail"], detail, "{}", value);
+            if detail.is_null() {
+                assert!(value.get("detail").is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn overloaded_carries_retry_hint() {
+        let value = serialize(WSErrorType::Overloaded {
+            retry_after_ms: 500,
+        });
+        assert_eq!(value["retry_after_ms"], 500);
+        assert!(serialize(WSErrorType::ProducerFailure)
+            .get("retry_after_ms")
+            .is_none());
+    }
+
+    #[test]
+    fn parse_failures_explain_themselves() {
+        let err = serde_json::from_str::<WSCommand>(r#"{ "id": "1", "type": "StartProduce" }"#)
+            .unwrap_err();
+        let close = WSCloseType::from(err);
+        assert_eq!(close.code(), 1003);
+        assert!(close.reason().contains("missing field `data`"), "{}", close);
+
+        let long = WSCloseType::InvalidData("é".repeat(100));
+        let reason = long.reason();
+        assert!(reason.len() <= MAX_CLOSE_REASON);
+        assert!(reason.starts_with("Unable to parse data: é"));
+    }
+
+    #[test]
+    fn out_of_order_commands_are_named() {
+        let close = WSCloseType::InvalidState("StartProduce", Phase::AwaitingTransports);
+        assert_eq!(close.code(), 1002);
+        assert_eq!(
+            close.reason(),
+            "StartProduce can't be sent while the connection is awaiting-transports"
+        );
+
+        let value = serialize(WSErrorType::CommandOutOfOrder(Phase::AwaitingAuth));
+        assert_eq!(value["detail"], "awaiting-auth");
+    }
+
+    #[test]
+    fn requests_are_echoed_on_demand() {
+        let command = || -> WSCommand {
+            serde_json::from_value(json!({
+                "id": "1",
+                "type": "Authenticate",
+                "data": { "roomId": "room", "token": "secret" },
+            }))
+            .unwrap()
+        };
+
+        let quiet = serde_json::to_value(WSError::from_command(
+            command(),
+            WSErrorType::UserNotFound("user".to_string()),
+            false,
+        ))
+        .unwrap();
+        assert!(quiet.get("request").is_none());
+        // Commands without a trace ID are traced by their own ID
+        assert_eq!(quiet["traceId"], "1");
+
+        let echoed = serde_json::to_value(WSError::from_command(
+            command(),
+            WSErrorType::UserNotFound("user".to_string()),
+            true,
+        ))
+        .unwrap();
+        assert_eq!(
+            echoed["request"],
+            json!({ "roomId": "room", "token": "[redacted]" })
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::{Duration, Instant};
+
+use crate::state::room::token;
+use crate::util::config::CONFIG;
+
+/// What a session's token calls for once its deadline passed
+#[derive(Debug, PartialEq)]
+pub enum ExpiryAction {
+    /// Warn the client that the token expires at this UNIX time in seconds
+    Warn(u64),
+    Close,
+}
+
+/// Expiry of the token a session authenticated or last refreshed with. When it's enforced
+/// the client is warned ahead of it, and the session closed unless the token is refreshed
+pub struct TokenExpiry {
+    /// UNIX time in seconds
+    expires: u64,
+    /// Seconds ahead of expiry the client is warned, `None` when expiry isn't enforced
+    warning: Option<u64>,
+    warned: bool,
+}
+
+impl TokenExpiry {
+    pub fn new(expires: u64, warning: Option<u64>) -> Self {
+        TokenExpiry {
+            expires,
+            warning,
+            warned: false,
+        }
+    }
+
+    /// Enforced if the configuration says so
+    pub fn from_config(expires: u64) -> Self {
+        let signaling = &CONFIG.signaling;
+        let warning =
+            Some(signaling.token_expiry_warning).filter(|_| signaling.enforce_token_expiry);
+        TokenExpiry::new(expires, warning)
+    }
+
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+
+    /// Take over the expiry of a new token, warning about it again
+    pub fn refresh(&mut self, expires: u64) {
+        self.expires = expires;
+        self.warned = false;
+    }
+
+    /// When the warning is due, then the expiry, `None` if expiry isn't enforced
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let warning = self.warning?;
+        let at = match self.warned {
+            true => self.expires,
+            false => self.expires.saturating_sub(warning),
+        };
+        Some(Instant::now() + Duration::from_secs(at.saturating_sub(token::now())))
+    }
+
+    /// What's due by `now`, a UNIX time in seconds
+    pub fn due(&mut self, now: u64) -> Option<ExpiryAction> {
+        let warning = self.warning?;
+        if now >= self.expires {
+            return Some(ExpiryAction::Close);
+        }
+        if !self.warned && now >= self.expires.saturating_sub(warning) {
+            self.warned = true;
+            return Some(ExpiryAction::Warn(self.expires));
+        }
+        None
+    }
+}
+
+/// End of a session capped by its join token or its room, and the warnings left to send
+/// ahead of it
+pub struct SessionExpiry {
+    ends_at: Instant,
+    /// Seconds before the end, the soonest last
+    warnings: Vec<u64>,
+}
+
+impl SessionExpiry {
+    pub fn new(ends_at: Instant, warnings: &[u64]) -> Self {
+        let mut warnings = warnings.to_vec();
+        warnings.sort_unstable_by(|a, b| b.cmp(a));
+        warnings.dedup();
+        SessionExpiry { ends_at, warnings }
+    }
+
+    /// Warned as configured, `None` when the session isn't capped
+    pub fn from_config(ends_at: Option<Instant>) -> Option<Self> {
+        Some(SessionExpiry::new(
+            ends_at?,
+            &CONFIG.signaling.session_expiry_warnings,
+        ))
+    }
+
+    /// When the next warning is due, `None` once they were all sent
+    pub fn next_warning(&self) -> Option<Instant> {
+        let secs = *self.warnings.first()?;
+        Some(
+            self.ends_at
+                .checked_sub(Duration::from_secs(secs))
+                .unwrap_or_else(Instant::now),
+        )
+    }
+
+    /// Seconds left if a warning is due by `now`. Warnings that passed together, such as
+    /// for a session starting with less time left than they're sent ahead, are sent once
+    pub fn due(&mut self, now: Instant) -> Option<u64> {
+        let remaining = self.ends_at.saturating_duration_since(now);
+        let due = self
+            .warnings
+            .iter()
+            .take_while(|secs| remaining <= Duration::from_secs(**secs))
+            .count();
+        if due == 0 {
+            return None;
+        }
+        self.warnings.drain(..due);
+        Some(remaining.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_warned_once_per_interval() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut expiry = SessionExpiry::new(at(600), &[60, 300]);
+        assert_eq!(expiry.next_warning(), Some(at(300)));
+        assert_eq!(expiry.due(at(200)), None);
+        assert_eq!(expiry.due(at(300)), Some(300));
+        assert_eq!(expiry.due(at(320)), None);
+        assert_eq!(expiry.next_warning(), Some(at(540)));
+        assert_eq!(expiry.due(at(550)), Some(50));
+        assert_eq!(expiry.next_warning(), None);
+        assert_eq!(expiry.due(at(599)), None);
+
+        // Starting past both warnings sends a single one
+        let mut expiry = SessionExpiry::new(at(30), &[300, 60]);
+        assert_eq!(expiry.due(start), Some(30));
+        assert_eq!(expiry.next_warning(), None);
+    }
+
+    #[test]
+    fn unrefreshed_tokens_are_warned_then_closed() {
+        let mut expiry = TokenExpiry::new(1_000, Some(60));
+        assert_eq!(expiry.due(900), None);
+        assert_eq!(expiry.due(940), Some(ExpiryAction::Warn(1_000)));
+        assert_eq!(expiry.due(950), None);
+
+        // Refreshing pushes both back
+        expiry.refresh(2_000);
+        assert_eq!(expiry.due(1_000), None);
+        assert_eq!(expiry.due(1_950), Some(ExpiryAction::Warn(2_000)));
+        assert_eq!(expiry.due(2_000), Some(ExpiryAction::Close));
+
+        // Unless enforced, nothing is ever due
+        let mut expiry = TokenExpiry::new(1_000, None);
+        assert!(expiry.next_deadline().is_none());
+        assert_eq!(expiry.due(2_000), None);
+    }
+}
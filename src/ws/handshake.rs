@@ -0,0 +1,153 @@
+//! Where an upgrade request comes from and which subprotocol it speaks. Browsers send the
+//! origin of the page opening the connection, upgrades from pages outside
+//! `signaling.allowed_origins` are refused with 403. Clients may offer subprotocols in
+//! `Sec-WebSocket-Protocol`, the first this server speaks is echoed back and picks the
+//! connection's wire format. Clients offering none get JSON without the header
+
+use warp::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use super::error::WSCloseType;
+
+/// Subprotocols spoken, in the order they're preferred when a client offers several.
+/// JSON is the only wire format so far
+pub const SUBPROTOCOLS: &[&str] = &["vortex-v1.json"];
+
+/// What the upgrade request settled on
+#[derive(Debug, PartialEq)]
+pub struct Negotiated {
+    /// Sent back in `Sec-WebSocket-Protocol`, none if the client offered none spoken here
+    pub subprotocol: Option<&'static str>,
+}
+
+impl Negotiated {
+    /// Answer the upgrade with the subprotocol
+    pub fn apply(&self, response: &mut Response) {
+        if let Some(subprotocol) = self.subprotocol {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(subprotocol),
+            );
+        }
+    }
+}
+
+/// Check the request's origin against the allowed ones and pick its subprotocol,
+/// `OriginNotAllowed` if the origin isn't allowed. Requests without an origin come from
+/// clients other than browsers and are let through
+pub fn filter(
+    allowed: &'static [String],
+) -> impl Filter<Extract = (Result<Negotiated, WSCloseType>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(move |origin: Option<String>, offered: Option<String>| {
+            if let Some(origin) = origin.filter(|origin| !origin_allowed(origin, allowed)) {
+                debug!("Refused an upgrade from origin {}", origin);
+                return Err(WSCloseType::OriginNotAllowed);
+            }
+            Ok(Negotiated {
+                subprotocol: offered.as_deref().and_then(select_subprotocol),
+            })
+        })
+}
+
+/// Whether a page of the origin may open connections, any may if none are listed. Entries
+/// are origins such as `https://app.example.com`, `https://*.example.com` matching the
+/// subdomains of `example.com`
+pub fn origin_allowed(origin: &str, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+
+    let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+    allowed.iter().any(|entry| {
+        let entry = entry.trim_end_matches('/').to_ascii_lowercase();
+        match entry.split_once("://*.") {
+            Some((scheme, domain)) => origin
+                .strip_prefix(scheme)
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain))
+                .map_or(false, |subdomain| {
+                    subdomain.len() > 1 && subdomain.ends_with('.')
+                }),
+            None => origin == entry,
+        }
+    })
+}
+
+/// First subprotocol of a comma separated offer that's spoken here
+fn select_subprotocol(offered: &str) -> Option<&'static str> {
+    let offered: Vec<_> = offered.split(',').map(str::trim).collect();
+    SUBPROTOCOLS
+        .iter()
+        .find(|subprotocol| offered.contains(*subprotocol))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_are_matched_exactly_or_by_subdomain() {
+        let allowed = vec![
+            "https://app.example.com".to_string(),
+            "https://*.example.org".to_string(),
+            "http://localhost:3000/".to_string(),
+        ];
+        assert!(origin_allowed("https://app.example.com", &allowed));
+        assert!(origin_allowed("HTTPS://App.Example.com", &allowed));
+        assert!(origin_allowed("http://localhost:3000", &allowed));
+        assert!(origin_allowed("https://meet.example.org", &allowed));
+        assert!(origin_allowed("https://a.b.example.org", &allowed));
+
+        assert!(!origin_allowed("https://evil.example.com", &allowed));
+        assert!(!origin_allowed("http://app.example.com", &allowed));
+        assert!(!origin_allowed("https://example.org", &allowed));
+        assert!(!origin_allowed("https://notexample.org", &allowed));
+        assert!(!origin_allowed("https://example.org.evil.com", &allowed));
+        assert!(!origin_allowed("http://localhost:3001", &allowed));
+        assert!(!origin_allowed("null", &allowed));
+
+        assert!(origin_allowed("https://anywhere.test", &[]));
+    }
+
+    #[test]
+    fn subprotocols_are_picked_from_the_offer() {
+        assert_eq!(select_subprotocol("vortex-v1.json"), Some("vortex-v1.json"));
+        assert_eq!(
+            select_subprotocol("vortex-v1.msgpack, vortex-v1.json"),
+            Some("vortex-v1.json")
+        );
+        assert_eq!(select_subprotocol("vortex-v1.msgpack"), None);
+        assert_eq!(select_subprotocol(""), None);
+    }
+
+    #[tokio::test]
+    async fn upgrades_from_other_origins_are_refused() {
+        let allowed: &'static [String] =
+            Box::leak(vec!["https://app.example.com".to_string()].into_boxed_slice());
+        let negotiate = |origin: Option<&'static str>| async move {
+            let mut request =
+                warp::test::request().header("sec-websocket-protocol", "vortex-v1.json");
+            if let Some(origin) = origin {
+                request = request.header("origin", origin);
+            }
+            request.filter(&filter(allowed)).await.unwrap()
+        };
+
+        let negotiated = Negotiated {
+            subprotocol: Some("vortex-v1.json"),
+        };
+        assert_eq!(
+            negotiate(Some("https://app.example.com")).await.unwrap(),
+            negotiated
+        );
+        assert_eq!(negotiate(None).await.unwrap(), negotiated);
+        let refused = negotiate(Some("https://elsewhere.test")).await;
+        assert!(matches!(refused, Err(WSCloseType::OriginNotAllowed)));
+        let response = super::super::upgrade::refuse(WSCloseType::OriginNotAllowed);
+        assert_eq!(response.status(), 403);
+    }
+}
@@ -0,0 +1,146 @@
+//! Users who joined and walked away, see `RoomSettings::idle`. A user whose client sends
+//! no command, has no unpaused producer and sends no pong of its own for a while is idle,
+//! and the room is told so clients can dim it. Pongs answering the server's pings don't
+//! count, browsers send those whether anyone is there or not. Rooms may also disconnect
+//! idle users after a longer while, warning their client first so someone still
+//! listening can stay by sending any command
+
+use std::time::Instant;
+
+use crate::state::room::IdleSettings;
+
+#[derive(Debug, PartialEq)]
+pub enum IdleAction {
+    /// Tell the room the user went idle
+    Idle,
+    /// Warn the client of the disconnect, this many seconds ahead
+    Warn(u64),
+    Disconnect,
+}
+
+/// Activity of a connection's user in its room
+pub struct IdleMonitor {
+    settings: IdleSettings,
+    last_active: Instant,
+    /// Whether the room was told the user is idle
+    idle: bool,
+    /// Whether the client was warned of the disconnect
+    warned: bool,
+}
+
+impl IdleMonitor {
+    pub fn new(settings: IdleSettings, now: Instant) -> Self {
+        IdleMonitor {
+            settings,
+            last_active: now,
+            idle: false,
+            warned: false,
+        }
+    }
+
+    /// As the room is set up, `None` if it has users never go idle
+    pub fn from_settings(settings: Option<IdleSettings>) -> Option<Self> {
+        let settings = settings?;
+        if settings.idle_after().is_none() && settings.disconnect_after().is_none() {
+            return None;
+        }
+        Some(IdleMonitor::new(settings, Instant::now()))
+    }
+
+    /// Note the user did something, `true` if the room was told it's idle and should be
+    /// told it's back
+    pub fn active(&mut self, now: Instant) -> bool {
+        self.last_active = now;
+        self.warned = false;
+        std::mem::replace(&mut self.idle, false)
+    }
+
+    /// When something's due next, `None` while nothing is left to do until the user is
+    /// active again
+    pub fn next_check(&self) -> Option<Instant> {
+        let idle_at = match (self.idle, self.settings.idle_after()) {
+            (false, Some(idle_after)) => Some(self.last_active + idle_after),
+            _ => None,
+        };
+        let disconnect_at =
+            self.settings
+                .disconnect_after()
+                .map(|disconnect_after| match self.warned {
+                    true => self.last_active + disconnect_after,
+                    false => {
+                        self.last_active + disconnect_after.saturating_sub(self.settings.warning())
+                    }
+                });
+        match (idle_at, disconnect_at) {
+            (Some(idle_at), Some(disconnect_at)) => Some(idle_at.min(disconnect_at)),
+            (idle_at, disconnect_at) => idle_at.or(disconnect_at),
+        }
+    }
+
+    /// What's due by `now`, one thing at a time
+    pub fn due(&mut self, now: Instant) -> Option<IdleAction> {
+        let idle_for = now.saturating_duration_since(self.last_active);
+        if let Some(disconnect_after) = self.settings.disconnect_after() {
+            if idle_for >= disconnect_after {
+                return Some(IdleAction::Disconnect);
+            }
+            let remaining = disconnect_after - idle_for;
+            if !self.warned && remaining <= self.settings.warning() {
+                self.warned = true;
+                return Some(IdleAction::Warn(remaining.as_secs()));
+            }
+        }
+        match self.settings.idle_after() {
+            Some(idle_after) if !self.idle && idle_for >= idle_after => {
+                self.idle = true;
+                Some(IdleAction::Idle)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn idle_users_are_warned_then_disconnected() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let settings = IdleSettings {
+            idle_after_secs: 60,
+            disconnect_after_secs: 300,
+            warning_secs: 30,
+        };
+        let mut monitor = IdleMonitor::new(settings, start);
+        assert_eq!(monitor.next_check(), Some(at(60)));
+        assert_eq!(monitor.due(at(30)), None);
+        assert_eq!(monitor.due(at(60)), Some(IdleAction::Idle));
+        assert_eq!(monitor.next_check(), Some(at(270)));
+
+        // Coming back starts the count over, the room is told once
+        assert!(monitor.active(at(100)));
+        assert!(!monitor.active(at(100)));
+        assert_eq!(monitor.due(at(200)), Some(IdleAction::Idle));
+        assert_eq!(monitor.due(at(380)), Some(IdleAction::Warn(20)));
+        assert_eq!(monitor.due(at(390)), None);
+        assert_eq!(monitor.next_check(), Some(at(400)));
+        assert_eq!(monitor.due(at(400)), Some(IdleAction::Disconnect));
+
+        let settings = IdleSettings {
+            disconnect_after_secs: 0,
+            ..settings
+        };
+        let mut monitor = IdleMonitor::new(settings, start);
+        assert_eq!(monitor.due(at(1000)), Some(IdleAction::Idle));
+        assert_eq!(monitor.next_check(), None);
+        assert!(IdleMonitor::from_settings(None).is_none());
+        let never = IdleSettings {
+            idle_after_secs: 0,
+            ..settings
+        };
+        assert!(IdleMonitor::from_settings(Some(never)).is_none());
+    }
+}
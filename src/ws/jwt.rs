@@ -0,0 +1,165 @@
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::state::user::{metadata_fits, UserOptions};
+use crate::util::config::{ConfigError, JwtConfig, DEFAULT_TENANT};
+
+#[derive(Deserialize)]
+pub struct Claims {
+    /// User ID
+    pub sub: String,
+    /// Room ID
+    pub room: String,
+    /// Tenant of the room, the default tenant if absent
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// UNIX time in seconds the token expires, required and checked by the validation
+    pub exp: u64,
+    #[serde(flatten)]
+    pub options: UserOptions,
+}
+
+/// Checks JWTs against the key, algorithm, audience and issuer of a `jwt` section
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    pub fn new(jwt: &JwtConfig) -> Result<Self, ConfigError> {
+        let key = jwt.decoding_key()?;
+        let mut validation = Validation::new(jwt.algorithm);
+        if let Some(audience) = &jwt.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &jwt.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        Ok(JwtVerifier { key, validation })
+    }
+
+    /// Verify a JWT presented for `room_id` of `tenant`. Returns `None` if the token isn't
+    /// shaped like a JWT, so it is treated as a registration token
+    pub fn verify(&self, token: &str, tenant: &str, room_id: &str) -> Option<Result<Claims, ()>> {
+        if token.matches('.').count() != 2 {
+            return None;
+        }
+
+        let result = match jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation) {
+            Ok(data) if data.claims.room != room_id => {
+                tracing::debug!("Rejected JWT issued for another room");
+                Err(())
+            }
+            Ok(data) if data.claims.tenant.as_deref().unwrap_or(DEFAULT_TENANT) != tenant => {
+                tracing::debug!("Rejected JWT issued for another tenant");
+                Err(())
+            }
+            Ok(data) if !metadata_fits(&data.claims.options.metadata) => {
+                tracing::debug!("Rejected JWT with oversized metadata");
+                Err(())
+            }
+            Ok(data) => Ok(data.claims),
+            Err(err) => {
+                tracing::debug!(error = %err, "Rejected JWT");
+                Err(())
+            }
+        };
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::room::token;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    const SECRET: &str = "jwt-test-secret";
+
+    fn verifier() -> JwtVerifier {
+        JwtVerifier::new(&JwtConfig {
+            algorithm: Algorithm::HS256,
+            key: SECRET.to_string(),
+            audience: Some("vortex".to_string()),
+            issuer: None,
+        })
+        .unwrap()
+    }
+
+    fn sign(algorithm: Algorithm, secret: &str, claims: &Value) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&Header::new(algorithm), claims, &key).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "alice",
+            "room": "room",
+            "aud": "vortex",
+            "exp": token::now() + 3600,
+            "role": "moderator",
+        })
+    }
+
+    #[test]
+    fn valid_tokens_carry_the_user() {
+        let token = sign(Algorithm::HS256, SECRET, &claims());
+        let claims = verifier()
+            .verify(&token, DEFAULT_TENANT, "room")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (claims.sub.as_str(), claims.room.as_str()),
+            ("alice", "room")
+        );
+
+        // Join tokens have a single dot and are left to the room
+        assert!(verifier()
+            .verify("payload.signature", DEFAULT_TENANT, "room")
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        let with = |field: &str, value: Value| {
+            let mut claims = claims();
+            claims[field] = value;
+            claims
+        };
+        let cases = vec![
+            (
+                "expired",
+                sign(
+                    Algorithm::HS256,
+                    SECRET,
+                    &with("exp", json!(token::now() - 3600)),
+                ),
+            ),
+            (
+                "wrong audience",
+                sign(Algorithm::HS256, SECRET, &with("aud", json!("other"))),
+            ),
+            (
+                "wrong room",
+                sign(Algorithm::HS256, SECRET, &with("room", json!("other"))),
+            ),
+            (
+                "wrong tenant",
+                sign(Algorithm::HS256, SECRET, &with("tenant", json!("other"))),
+            ),
+            ("wrong algorithm", sign(Algorithm::HS384, SECRET, &claims())),
+            (
+                "wrong signature",
+                sign(Algorithm::HS256, "another-secret", &claims()),
+            ),
+        ];
+
+        for (case, token) in cases {
+            let result = verifier().verify(&token, DEFAULT_TENANT, "room");
+            assert!(matches!(result, Some(Err(()))), "{} was accepted", case);
+        }
+    }
+}
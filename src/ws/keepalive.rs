@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+use crate::util::config::CONFIG;
+
+/// Pings of a WebSocket connection and the pongs still owed for them, so half-open
+/// connections are reaped instead of holding their user and transports
+pub struct Keepalive {
+    interval: Duration,
+    max_missed: u32,
+    /// Pings sent since the last pong
+    unanswered: u32,
+    next_ping: Instant,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, max_missed: u32) -> Self {
+        Keepalive {
+            interval,
+            max_missed,
+            unanswered: 0,
+            next_ping: Instant::now() + interval,
+        }
+    }
+
+    /// As configured, `None` if pings are turned off
+    pub fn from_config() -> Option<Self> {
+        let signaling = &CONFIG.signaling;
+        let interval = signaling.ping_interval()?;
+        Some(Keepalive::new(interval, signaling.max_missed_pongs))
+    }
+
+    pub fn next_ping(&self) -> Instant {
+        self.next_ping
+    }
+
+    /// Count a ping as sent and schedule the next, `false` if the client already left
+    /// `max_missed` in a row unanswered and should be disconnected instead
+    pub fn ping(&mut self) -> bool {
+        if self.unanswered >= self.max_missed {
+            return false;
+        }
+
+        self.unanswered += 1;
+        self.next_ping = Instant::now() + self.interval;
+        true
+    }
+
+    pub fn pong(&mut self) {
+        self.unanswered = 0;
+    }
+
+    pub fn unanswered(&self) -> u32 {
+        self.unanswered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missed_pongs_disconnect() {
+        let mut keepalive = Keepalive::new(Duration::from_secs(15), 2);
+        assert!(keepalive.next_ping() > Instant::now());
+
+        assert!(keepalive.ping());
+        keepalive.pong();
+        assert!(keepalive.ping());
+        assert!(keepalive.ping());
+        assert_eq!(keepalive.unanswered(), 2);
+        assert!(!keepalive.ping());
+
+        // A late pong still counts
+        keepalive.pong();
+        assert!(keepalive.ping());
+    }
+}
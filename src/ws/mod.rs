@@ -1,246 +1,7007 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-
-use futures::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU64, AtomicU8, Ordering},
+    Arc,
 };
+use std::time::{Duration, Instant};
+
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
 
+use mediasoup::consumer::{Consumer, ConsumerLayers};
+use mediasoup::data_structures::DtlsState;
+use mediasoup::producer::{Producer, ProducerId};
+use mediasoup::router::Router;
+use mediasoup::rtp_parameters::{MediaKind, RtpParameters};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug_span, field, info_span, Instrument, Span};
+use warp::reply::Response;
 use warp::ws::{Message, WebSocket, Ws};
 use warp::{Filter, Rejection, Reply};
 
 use crate::{
-    rtc::RtcState,
+    health, info,
+    integrations::{
+        audit::{self, AuditAction, AuditRecord},
+        redis::get_redis,
+    },
+    rtc::{
+        bitrate,
+        downlink::DownlinkThrottle,
+        dump::{self, DumpTarget},
+        layers,
+        mock::MockSession,
+        opus::OpusOptions,
+        playback::SYSTEM_USER_ID,
+        quality::QualityMonitor,
+        reaper::{self, ConsumerReaper},
+        recording::TrackSource,
+        score::{ScoreReceiver, ScoreSource, ScoreThrottle},
+        stats, turn,
+        types::InitializationInput,
+        InitError, KeyFrameError, LayersError, RtcSession, RtcState,
+    },
     state::{
-        room::{Room, RoomEvent},
-        user::UserInfo,
+        room::{
+            blob::BlobSignal,
+            subscriber::{SharedText, SubscriberMessage, SubscriberOptions, SubscriberSignal},
+            token::{self, SessionConstraints, TokenError},
+            users::MAX_KICK_REASON,
+            QualityAlert, Room, RoomEvent, RoomSettingsUpdate, RoomSubscriber, RoomSummary,
+            UserReport,
+        },
+        user::{Permission, ProduceType, Role, UserInfo, UserInfoUpdate},
+    },
+    util::{
+        client_ip,
+        config::{CONFIG, DEFAULT_TENANT},
+        load::{self, LoadLevel},
+        metrics::{
+            COMMAND_ERRORS, COMMAND_PANICS, DROPPED_EVENTS, REAPED_CONSUMERS,
+            TRANSPORT_INIT_FAILURES,
+        },
+        ulid,
     },
 };
 
-mod error;
-mod types;
+pub mod affinity;
+pub mod auth;
+pub mod blob_frame;
+pub mod connections;
+pub mod error;
+pub mod expiry;
+pub mod handshake;
+pub mod idle;
+pub mod jwt;
+pub mod keepalive;
+pub mod outbox;
+pub mod phase;
+pub mod ptt;
+pub mod queue;
+pub mod rooms;
+pub mod schema;
+pub mod types;
+pub mod upgrade;
 
+use auth::{authenticator, AuthError};
+use connections::ConnectionSlot;
 use error::{WSCloseType, WSError, WSErrorType};
-use types::{WSCommand, WSCommandType, WSEvent, WSReply, WSReplyType};
+use expiry::{ExpiryAction, SessionExpiry, TokenExpiry};
+use handshake::Negotiated;
+use idle::{IdleAction, IdleMonitor};
+use keepalive::Keepalive;
+use outbox::{Outbox, Outgoing, ReplyTiming, FLUSH_TIMEOUT};
+use phase::Phase;
+use ptt::PushToTalk;
+use queue::{CommandQueue, QueuedCommand};
+use rooms::{JoinedReceiver, JoinedRooms};
+use types::{
+    sequenced_text, Capabilities, Capability, EchoConsumer, MediaClosedReason, NewConsumer,
+    ProducerSummary, RecentEvent, ReplacedConsumer, RoomInfoPage, RoomSnapshot, SequencedEvent,
+    SignalingTransport, Subscriptions, UserConsumer, WSCommand, WSCommandType, WSEvent, WSReply,
+    WSReplyType, MAX_BATCH_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use upgrade::Preauthorized;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How long a replaced producer stays open, for the other connections to move their
+/// consumers to its successor before their old ones close
+const REPLACED_PRODUCER_GRACE: Duration = Duration::from_secs(2);
+
+/// Outgoing half of a signaling connection, independent of the transport carrying it
+pub type WSSink = Pin<Box<dyn Sink<Message, Error = WSCloseType> + Send>>;
+/// Incoming half of a signaling connection, independent of the transport carrying it
+pub type WSStream = Pin<Box<dyn Stream<Item = Result<Message, WSCloseType>> + Send>>;
+
+pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    session_route::<RtcState>()
+}
+
+/// The signaling route with media going through `MockSession`, so clients go through
+/// the handshake and commands without negotiating ICE and DTLS. For load testing the
+/// signaling path, see `ServerBuilder::mock_media`
+pub fn mock_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    session_route::<MockSession>()
+}
+
+fn session_route<R: RtcSession>() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+{
+    warp::ws::ws()
+        .and(handshake::filter(&CONFIG.signaling.allowed_origins))
+        .and(affinity::filter())
+        .and(upgrade::filter())
+        .and(client_ip::filter())
+        .and_then(
+            |ws: Ws,
+             negotiated: Result<Negotiated, WSCloseType>,
+             redirect: Option<affinity::Redirect>,
+             credentials,
+             ip| async move {
+                let negotiated = match negotiated {
+                    Ok(negotiated) => negotiated,
+                    Err(close) => return Ok(upgrade::refuse(close)),
+                };
+                match redirect {
+                    Some(redirect) => Ok(redirect.into_response()),
+                    None => accept::<R>(ws, negotiated, credentials, ip).await,
+                }
+            },
+        )
+}
+
+/// Complete the upgrade, unless its address has too many connections open already or the
+/// credentials it came with are refused
+async fn accept<R: RtcSession>(
+    ws: Ws,
+    negotiated: Negotiated,
+    credentials: Option<upgrade::Credentials>,
+    ip: Option<IpAddr>,
+) -> Result<Response, Rejection> {
+    // Held until the connection ends, whichever way it does
+    let slot = match ip.map(ConnectionSlot::acquire) {
+        Some(None) => return Ok(upgrade::refuse(WSCloseType::TooManyConnections)),
+        Some(slot) => slot,
+        None => None,
+    };
+
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let preauthorized = match credentials {
+        Some(credentials) => match Preauthorized::register(credentials, connection_id).await {
+            Ok(preauthorized) => Some(preauthorized),
+            Err(close) => return Ok(upgrade::refuse(close)),
+        },
+        None => None,
+    };
+
+    let reply = limited(ws).on_upgrade(move |ws| async move {
+        on_connection::<R>(ws, connection_id, ip, preauthorized).await;
+        drop(slot);
+    });
+    let mut response = reply.into_response();
+    negotiated.apply(&mut response);
+    Ok(response)
+}
+
+/// Refuse inbound messages above the configured size before they're buffered
+fn limited(ws: Ws) -> Ws {
+    let limit = CONFIG.signaling.max_message_size;
+    ws.max_message_size(limit).max_frame_size(limit)
+}
+
+async fn on_connection<R: RtcSession>(
+    ws: WebSocket,
+    connection_id: u64,
+    ip: Option<IpAddr>,
+    preauthorized: Option<Preauthorized>,
+) {
+    let (ws_sink, ws_stream) = ws.split();
+    let ws_sink: WSSink = Box::pin(ws_sink.sink_map_err(WSCloseType::from));
+    // Reading only fails on frames above the size limit or breaking the protocol
+    let ws_stream: WSStream = Box::pin(
+        ws_stream.map(|message| message.map_err(|err| WSCloseType::InvalidData(err.to_string()))),
+    );
+
+    serve_authenticated::<R>(
+        ws_sink,
+        ws_stream,
+        SignalingTransport::WebSocket,
+        connection_id,
+        ip,
+        preauthorized,
+    )
+    .await;
+}
 
-pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Copy {
-    warp::ws::ws().map(|ws: Ws| ws.on_upgrade(on_connection))
+/// Run the signaling state machine over an established connection until it closes
+pub async fn serve<R: RtcSession>(
+    ws_sink: WSSink,
+    ws_stream: WSStream,
+    signaling: SignalingTransport,
+) {
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    serve_authenticated::<R>(ws_sink, ws_stream, signaling, connection_id, None, None).await;
 }
 
-async fn on_connection(ws: WebSocket) {
-    let (mut ws_sink, mut ws_stream) = ws.split();
-    let result = handle(&mut ws_sink, &mut ws_stream).await;
+/// Like `serve`, skipping `Authenticate` if the user was registered with the upgrade
+async fn serve_authenticated<R: RtcSession>(
+    ws_sink: WSSink,
+    mut ws_stream: WSStream,
+    signaling: SignalingTransport,
+    connection_id: u64,
+    ip: Option<IpAddr>,
+    preauthorized: Option<Preauthorized>,
+) {
+    let span = info_span!(
+        "connection",
+        id = connection_id,
+        transport = ?signaling,
+        ip = field::Empty,
+        user_id = field::Empty,
+        room_id = field::Empty,
+    );
+    if let Some(ip) = ip {
+        span.record("ip", &field::display(ip));
+    }
+
+    let started = Instant::now();
+    let (outbox, mut writer) = Outbox::start(ws_sink);
+    let result = handle::<R>(
+        &outbox,
+        &mut ws_stream,
+        connection_id,
+        signaling,
+        preauthorized,
+    )
+    .instrument(span.clone())
+    .await;
+    span.in_scope(|| {
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(()) => tracing::info!(duration_ms, "Connection closed"),
+            Err(close) => tracing::info!(duration_ms, close = %close, code = close.code(), "Connection closed"),
+        }
+    });
+
+    // What's waiting goes out before the close frame, unless the client is too slow for it
+    if let Err(WSCloseType::SlowConsumer) = result {
+        outbox.abandon();
+    }
+    drop(outbox);
+    let mut ws_sink = match tokio::time::timeout(FLUSH_TIMEOUT, &mut writer).await {
+        Ok(Ok(Some(ws_sink))) => ws_sink,
+        Ok(_) => return,
+        Err(_) => {
+            writer.abort();
+            return;
+        }
+    };
+
     if let Err(close) = result {
-        let code = close as u16;
-        let reason = close.to_string();
-        ws_sink.send(Message::close_with(code, reason)).await.ok();
+        // The close reason may be cut short, the error frame carries all of it
+        let error = match &close {
+            WSCloseType::InvalidData(err) => Some(WSError::new(
+                None,
+                "Unknown",
+                WSErrorType::InvalidCommand(err.clone()),
+            )),
+            WSCloseType::InvalidState(command, phase) => Some(WSError::new(
+                None,
+                command,
+                WSErrorType::CommandOutOfOrder(*phase),
+            )),
+            _ => None,
+        };
+        if let Some(Ok(text)) = error.map(|error| serde_json::to_string(&error)) {
+            ws_sink.send(Message::text(text)).await.ok();
+        }
+
+        let code = close.code();
+        ws_sink
+            .send(Message::close_with(code, close.reason()))
+            .await
+            .ok();
     } else {
         ws_sink.send(Message::close()).await.ok();
     }
 }
 
-async fn handle(
-    ws_sink: &mut SplitSink<WebSocket, Message>,
-    ws_stream: &mut SplitStream<WebSocket>,
+async fn handle<R: RtcSession>(
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+    connection_id: u64,
+    signaling: SignalingTransport,
+    preauthorized: Option<Preauthorized>,
 ) -> Result<(), WSCloseType> {
-    // Authentication
-    let (room, user_id) = loop {
-        match ws_stream.next().await {
-            Some(message) => {
-                let message = message.map_err(|_| WSCloseType::ServerError)?;
-                // Try to get the text message, ignore otherwise (might be ping, binary)
-                if let Ok(text) = message.to_str() {
-                    let out: WSCommand = serde_json::from_str(text)?;
-                    if let WSCommandType::Authenticate { room_id, token } = out.command_type {
-                        let room = Room::get(&room_id).await.ok_or(WSCloseType::Unauthorized)?;
-                        let users = room.users();
-                        // Attempt to register user
-                        let user = users
-                            .register(&token)
-                            .await
-                            .ok_or(WSCloseType::Unauthorized)?;
-                        let id = user.read().await.id().to_string();
-
-                        let reply = WSReply {
-                            id: out.id,
-                            reply_type: WSReplyType::Authenticate {
-                                user_id: id.clone(),
-                                room_id: room.id().to_string(),
-                                rtp_capabilities: room
-                                    .router()
-                                    .ok_or(WSCloseType::RoomClosed)?
-                                    .rtp_capabilities()
-                                    .clone(),
-                            },
-                        };
+    let mut authenticated = match preauthorized {
+        Some(mut preauthorized) => Authenticated {
+            reply_id: None,
+            received: None,
+            registered: preauthorized.take(),
+            scores: preauthorized.scores,
+            debug: preauthorized.debug,
+            version: preauthorized.version,
+            capabilities: preauthorized.capabilities,
+            subscriptions: Subscriptions::all(),
+        },
+        None => match wait_authenticate(outbox, ws_stream, connection_id).await? {
+            Some(authenticated) => authenticated,
+            // Client disconnected before they authenticated, return
+            None => return Ok(()),
+        },
+    };
 
-                        ws_sink
-                            .send(Message::text(serde_json::to_string(&reply)?))
-                            .await?;
-                        break (room, id);
-                    } else {
-                        return Err(WSCloseType::InvalidState);
-                    }
+    // Authenticating again on an established connection switches rooms. The user has left
+    // the room it was in by then, whether or not it gets into the new one
+    loop {
+        let out =
+            match session::<R>(outbox, ws_stream, connection_id, signaling, authenticated).await? {
+                Some(out) => out,
+                None => return Ok(()),
+            };
+        authenticated = match authenticate(&out, connection_id).await {
+            Ok(authenticated) => authenticated,
+            Err(close) => {
+                tracing::debug!(close = %close, "Refused room switch");
+                let error = WSErrorType::SwitchRefused(close.reason());
+                count_error("Authenticate", &error);
+                // The token isn't echoed, even to debugging clients
+                let error = WSError::from_command(out, error, false);
+                outbox
+                    .send(Message::text(serde_json::to_string(&error)?))
+                    .await?;
+                match wait_authenticate(outbox, ws_stream, connection_id).await? {
+                    Some(authenticated) => authenticated,
+                    None => return Ok(()),
                 }
             }
-            // Client disconnected before they authenticated, return
-            None => return Ok(()),
+        };
+    }
+}
+
+/// The user registered with `Authenticate`, and what the client asked for with it
+struct Authenticated {
+    /// ID of the command, `None` when the credentials came with the upgrade request
+    reply_id: Option<String>,
+    received: Option<Instant>,
+    registered: Registered,
+    scores: bool,
+    debug: bool,
+    version: u32,
+    capabilities: Capabilities,
+    subscriptions: Subscriptions,
+}
+
+/// Wait for the client to authenticate, `None` if it disconnected first. `ServerInfo` is
+/// answered in the meantime, for clients to check what they're talking to
+async fn wait_authenticate(
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+    connection_id: u64,
+) -> Result<Option<Authenticated>, WSCloseType> {
+    let deadline = CONFIG
+        .signaling
+        .handshake_timeout()
+        .map(|timeout| Instant::now() + timeout);
+    loop {
+        let message = match handshake_frame(ws_stream, deadline).await? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        // Commands come as text or binary JSON, control frames are skipped
+        if let Some(text) = frame_text(&message)? {
+            let received = Instant::now();
+            let mut out: WSCommand = serde_json::from_str(text)?;
+            out.received = Some(received);
+            if Phase::AwaitingAuth.answers(&out.command_type) {
+                answer_early(None, Phase::AwaitingAuth, out, outbox).await?;
+                continue;
+            }
+            return authenticate(&out, connection_id).await.map(Some);
+        }
+    }
+}
+
+/// Register the user of an `Authenticate` command, any other command is out of order
+async fn authenticate(out: &WSCommand, connection_id: u64) -> Result<Authenticated, WSCloseType> {
+    match &out.command_type {
+        WSCommandType::Authenticate {
+            room_id,
+            tenant,
+            token,
+            scores,
+            debug,
+            version,
+            capabilities,
+            subscriptions,
+        } => {
+            check_version(*version)?;
+            let tenant = tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+            Ok(Authenticated {
+                reply_id: out.id.clone(),
+                received: out.received,
+                registered: register(tenant, room_id, token, connection_id).await?,
+                scores: *scores,
+                debug: *debug,
+                version: *version,
+                capabilities: Capabilities::negotiate(capabilities.as_deref()),
+                subscriptions: Subscriptions::from_names(subscriptions.as_deref()),
+            })
+        }
+        _ => Err(out_of_order(Phase::AwaitingAuth, out)),
+    }
+}
+
+/// Answer a command sent while the session is set up, which `phase` takes without moving
+/// the session along. Commands about the room wait for the room
+async fn answer_early(
+    room: Option<&Arc<Room>>,
+    phase: Phase,
+    out: WSCommand,
+    outbox: &Outbox,
+) -> Result<(), WSCloseType> {
+    let result = match (&out.command_type, room) {
+        (WSCommandType::ServerInfo, _) => Ok(WSReplyType::ServerInfo(info::server_info())),
+        (WSCommandType::RoomInfo(page), Some(room)) => room_info(room, *page).await,
+        (WSCommandType::RoomInfoDelta { since }, Some(room)) => {
+            Ok(room_info_delta(room, *since).await)
         }
+        _ => return Err(out_of_order(phase, &out)),
     };
+    send_result(outbox, out, result, false).await
+}
 
-    // Transport initialization
-    let rtc_state = loop {
-        match ws_stream.next().await {
-            Some(message) => {
-                let message = message.map_err(|_| WSCloseType::ServerError)?;
-                // Try to get the text message, ignore otherwise (might be ping, binary)
-                if let Ok(text) = message.to_str() {
-                    let out: WSCommand = serde_json::from_str(text)?;
-                    if let WSCommandType::InitializeTransports { init_data } = out.command_type {
-                        let router = room.router().ok_or(WSCloseType::RoomClosed)?;
-                        let rtc_state = RtcState::initialize(router, init_data)
-                            .await
-                            .map_err(|_| WSCloseType::ServerError)?;
-                        let reply_data = rtc_state.get_init_data();
+/// Close for a command the connection doesn't take in the phase it's in
+fn out_of_order(phase: Phase, out: &WSCommand) -> WSCloseType {
+    let command: &'static str = (&out.command_type).into();
+    tracing::debug!(command, phase = %phase, "Command sent out of order");
+    WSCloseType::InvalidState(command, phase)
+}
 
-                        let reply = WSReply {
-                            id: out.id,
-                            reply_type: WSReplyType::InitializeTransports { reply_data },
-                        };
+/// Session of the user in the room it authenticated into, from subscribing to it until
+/// the user leaves. Returns the `Authenticate` command the client sent to switch rooms,
+/// if that's why it left
+async fn session<R: RtcSession>(
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+    connection_id: u64,
+    signaling: SignalingTransport,
+    authenticated: Authenticated,
+) -> Result<Option<WSCommand>, WSCloseType> {
+    let Authenticated {
+        reply_id,
+        received,
+        registered,
+        scores,
+        debug,
+        version,
+        capabilities,
+        subscriptions,
+    } = authenticated;
+    let Registered {
+        room,
+        user_id,
+        constraints,
+        replaced,
+        token_expires,
+    } = registered;
+    let users = room.users();
+    let span = Span::current();
+    span.record("user_id", &user_id.as_str());
+    span.record("room_id", &room.id());
+    // However the session ends from here on, panics included, the user leaves the room
+    let guard = SessionGuard {
+        room: room.clone(),
+        user_id: user_id.clone(),
+        connection_id,
+        armed: true,
+    };
 
-                        ws_sink
-                            .send(Message::text(serde_json::to_string(&reply)?))
-                            .await?;
-                        break rtc_state;
-                    } else {
-                        return Err(WSCloseType::InvalidState);
-                    }
-                }
-            }
-            // Client disconnected before they authenticated, clean up
+    // Subscribe before taking the snapshot of the room, so no event between the two is lost
+    let mut subscriber =
+        match room.subscribe(connection_id, &user_id, SubscriberOptions { signaling }) {
+            Some(subscriber) => subscriber,
             None => {
-                room.users().remove(&user_id).await.ok();
-                return Ok(());
+                guard.disconnect().await;
+                return Err(WSCloseType::RoomClosed);
             }
+        };
+
+    if let Some(replaced) = replaced {
+        let signal = SubscriberSignal::Close(WSCloseType::SessionReplaced);
+        room.signal_subscriber(replaced, signal);
+    }
+
+    // Another connection may have taken the session over before this one subscribed, in
+    // which case it couldn't be signaled
+    let (owned, hidden, moderator) = match users.get(&user_id).await {
+        Some(user) => {
+            let user = user.read().await;
+            (
+                user.connection_id() == Some(connection_id),
+                user.hidden(),
+                user.has_permission(Permission::Moderator),
+            )
         }
+        None => (false, false, false),
+    };
+    if !owned {
+        return Err(WSCloseType::SessionReplaced);
+    }
+    // Hidden users aren't recorded, the others are asked as they connect if the room is
+    // being recorded, in time for their first producer
+    if !hidden {
+        room.ask_recording_consent(&user_id).await;
+    }
+    // The owner joining opens a locked room, other moderators come in to open it when
+    // they see fit. Hidden users aren't part of the call to wait for it
+    if room.is_owner(&user_id) && room.open() {
+        audit::record(
+            AuditRecord::new(room.scoped_id(), AuditAction::RoomOpened).actor(Some(&user_id)),
+        );
+    }
+    let waiting = !moderator && !hidden && room.enter_lobby(&user_id);
+
+    // Unsolicited, without an ID, when the credentials came with the upgrade request
+    let reply = WSReply {
+        id: reply_id,
+        room_id: None,
+        reply_type: WSReplyType::Authenticate {
+            user_id: user_id.clone(),
+            room_id: room.id().to_string(),
+            rtp_capabilities: room
+                .router()
+                .ok_or(WSCloseType::RoomClosed)?
+                .rtp_capabilities()
+                .clone(),
+            signaling,
+            version,
+            capabilities: Capability::ALL.to_vec(),
+            subscriptions: subscriptions.categories(),
+            ice_servers: turn::ice_servers(&user_id),
+            users: room_users(&room).await,
+            waiting,
+            feature_flags: room.feature_flags(),
+            node_affinity: affinity::issue(),
+        },
     };
 
-    // TODO: implement some sort of way to automatically remove a user from a room if the thread panics
-    // the Room user remove function is async but the Drop trait is not
+    let timing = received.map(|received| ReplyTiming {
+        command: "Authenticate",
+        received,
+    });
+    outbox
+        .send_reply(Message::text(serde_json::to_string(&reply)?), timing)
+        .await?;
+    // Counted from authentication, whatever the client does next
+    let expires_at = constraints
+        .max_session_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
 
-    let result = event_loop(&room, &user_id, rtc_state, ws_sink, ws_stream).await;
-    room.users().remove(&user_id).await.ok();
+    // Numbered from the lobby on, where the client is told it was let in
+    let mut events = EventSequence::new(capabilities, subscriptions);
+    if waiting {
+        let admitted = wait_in_lobby(
+            &room,
+            &user_id,
+            outbox,
+            ws_stream,
+            &mut subscriber,
+            &mut events,
+        );
+        match admitted.await {
+            Ok(true) => (),
+            result => {
+                guard.disconnect().await;
+                return result.map(|_| None);
+            }
+        }
+    }
+
+    // Transport initialization. The user is registered by now, so it's disconnected
+    // whichever way this ends without transports
+    let deadline = CONFIG
+        .signaling
+        .handshake_timeout()
+        .map(|timeout| Instant::now() + timeout);
+    let mut rtc_state = match initialize_transports::<R>(
+        &room,
+        &user_id,
+        connection_id,
+        outbox,
+        ws_stream,
+        deadline,
+    )
+    .await
+    {
+        Ok(Some(rtc_state)) => rtc_state,
+        result => {
+            guard.disconnect().await;
+            return result.map(|_| None);
+        }
+    };
+
+    let session = Session {
+        scores: match scores {
+            true => Some(rtc_state.enable_scores()),
+            false => None,
+        },
+        debug,
+        version,
+        capabilities,
+        events,
+        expires_at,
+        // Warned about the room closing as well, which ends the session just the same
+        session_expiry: SessionExpiry::from_config(match (expires_at, room.expires_at()) {
+            (Some(session), Some(room)) => Some(session.min(room)),
+            (session, room) => session.or(room),
+        }),
+        token_expiry: TokenExpiry::from_config(token_expires),
+        // Long polling clients can't answer pings, their sessions time out on their own
+        keepalive: match signaling {
+            SignalingTransport::WebSocket => Keepalive::from_config(),
+            _ => None,
+        },
+    };
+    // Shared with the commands being handled, and kept past the connection if it dropped
+    let rtc_state = Arc::new(Mutex::new(rtc_state));
+    let (joined, joined_messages) = JoinedRooms::new(connection_id, signaling);
+    let event_loop = event_loop(
+        &room,
+        subscriber,
+        rtc_state.clone(),
+        (joined.clone(), joined_messages),
+        session,
+        outbox,
+        ws_stream,
+    );
+    // Outside the handlers of commands and events, a panic still only ends this session
+    let payload = || format!("Event loop of {}", user_id);
+    let result = isolate("EventLoop", payload, event_loop).await;
+    joined.leave_all().await;
+    let grace = CONFIG.signaling.reconnect_grace();
+    match (&result, grace) {
+        // Producers stay up for the others until the user resumes the session or leaves
+        (Err(close), Some(grace)) if close.dropped() => {
+            guard.defuse();
+            let users = room.users();
+            users
+                .linger(&user_id, connection_id, grace, rtc_state)
+                .await;
+        }
+        // Switching rooms leaves this one like closing the connection does, the transports
+        // are closed as the media is dropped
+        _ => guard.disconnect().await,
+    }
     result
 }
 
-async fn event_loop(
+/// Removes the user from its room if the session ends without doing so, such as when it
+/// panics or its connection's task is aborted. Removal is async and `Drop` isn't, so it's
+/// left to a task of its own
+struct SessionGuard {
+    room: Arc<Room>,
+    user_id: String,
+    connection_id: u64,
+    armed: bool,
+}
+
+impl SessionGuard {
+    /// Remove the user now, rather than once the guard is dropped
+    async fn disconnect(mut self) {
+        self.armed = false;
+        let users = self.room.users();
+        users.disconnect(&self.user_id, self.connection_id).await;
+    }
+
+    /// Leave the user in the room, for whoever took over its removal
+    fn defuse(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        if std::thread::panicking() {
+            tracing::error!(user_id = %self.user_id, "Session panicked, removing the user");
+        }
+        // Nothing is left to remove the user from once the runtime shuts down
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let room = self.room.clone();
+            let user_id = std::mem::take(&mut self.user_id);
+            let connection_id = self.connection_id;
+            runtime.spawn(async move {
+                room.users().disconnect(&user_id, connection_id).await;
+            });
+        }
+    }
+}
+
+/// A user registered with the credentials of `Authenticate`, not yet subscribed to its room
+pub struct Registered {
+    room: Arc<Room>,
+    user_id: String,
+    constraints: SessionConstraints,
+    /// Connection whose session the user took over
+    replaced: Option<u64>,
+    /// UNIX time in seconds the token the user authenticated with expires
+    token_expires: u64,
+}
+
+fn check_version(version: u32) -> Result<(), WSCloseType> {
+    match (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        true => Ok(()),
+        false => Err(WSCloseType::UnsupportedVersion(version)),
+    }
+}
+
+/// Register the user `token` belongs to as connected through `connection_id`. Rooms of
+/// other tenants are refused like rooms that don't exist
+async fn register(
+    tenant: &str,
+    room_id: &str,
+    token: &str,
+    connection_id: u64,
+) -> Result<Registered, WSCloseType> {
+    let room = Room::get(tenant, room_id)
+        .await
+        .ok_or(WSCloseType::Unauthorized)?;
+    let users = room.users();
+    // Attempt to register user, or create it from the claims the authenticator gives
+    // A user joining with a JWT is registered with a join token of its own, it's the JWT
+    // whose expiry counts
+    let claims = authenticator().validate(&room, token).await?;
+    if room.banned(&claims.user_id) {
+        return Err(WSCloseType::Banned);
+    }
+    let mut token_expires = None;
+    let registration = match claims.options {
+        Some(options) => {
+            token_expires = Some(claims.expires);
+            users.join(claims.user_id, options, connection_id).await
+        }
+        None => match users.register(token, connection_id).await {
+            Ok(registration) => Some(registration),
+            Err(TokenError::Banned) => return Err(WSCloseType::Banned),
+            Err(err) => {
+                tracing::debug!(error = %err, "Rejected join token");
+                None
+            }
+        },
+    }
+    .ok_or(WSCloseType::Unauthorized)?;
+    let (user_id, constraints) = {
+        let user = registration.user.read().await;
+        (user.id().to_string(), user.constraints())
+    };
+    let replaced = registration.replaced;
+    let token_expires = token_expires.unwrap_or(registration.expires);
+    drop(registration);
+
+    Ok(Registered {
+        room,
+        user_id,
+        constraints,
+        replaced,
+        token_expires,
+    })
+}
+
+/// Hold the client of a user waiting in the lobby until it's let in, `false` if it
+/// disconnected first. Its commands are refused meanwhile, and the room events it missed
+/// are made up for by the users sent along with `RoomOpened`
+async fn wait_in_lobby(
     room: &Arc<Room>,
     user_id: &str,
-    rtc_state: RtcState,
-    ws_sink: &mut SplitSink<WebSocket, Message>,
-    ws_stream: &mut SplitStream<WebSocket>,
-) -> Result<(), WSCloseType> {
-    let mut room_stream = room.subscribe().ok_or(WSCloseType::RoomClosed)?;
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+    subscriber: &mut RoomSubscriber,
+    events: &mut EventSequence,
+) -> Result<bool, WSCloseType> {
+    // Checked again on every room event, those letting the user in included, so one
+    // dropped while lagging doesn't keep it waiting
+    while room.in_lobby(user_id) {
+        tokio::select! {
+            message = handshake_frame(ws_stream, None) => {
+                let message = match message? {
+                    Some(message) => message,
+                    None => return Ok(false),
+                };
+                let text = match frame_text(&message)? {
+                    Some(text) => text,
+                    None => continue,
+                };
+                let mut out: WSCommand = serde_json::from_str(text)?;
+                out.received = Some(Instant::now());
+                if let WSCommandType::Leave = out.command_type {
+                    let connection_id = subscriber.info().connection_id;
+                    return Err(leave(room, user_id, connection_id, outbox, out, false).await);
+                }
+                send_result(outbox, out, Err(WSErrorType::RoomLocked), false).await?;
+            }
+            message = subscriber.recv() => match message {
+                SubscriberMessage::Event(RoomEvent::UserLeft(id, _)) if id == user_id => {
+                    return Err(WSCloseType::Kicked(None));
+                }
+                SubscriberMessage::Event(RoomEvent::RoomDelete(_)) => {
+                    return Err(WSCloseType::RoomClosed);
+                }
+                SubscriberMessage::Close(reason) => return Err(reason),
+                _ => (),
+            },
+        }
+    }
+
+    let event = WSEvent::RoomOpened {
+        users: room_users(room).await,
+    };
+    events.send(outbox, event).await?;
+    Ok(true)
+}
+
+/// Wait for the client to initialize its transports, `None` if it disconnected first
+async fn initialize_transports<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    connection_id: u64,
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+    deadline: Option<Instant>,
+) -> Result<Option<R>, WSCloseType> {
+    loop {
+        let message = match handshake_frame(ws_stream, deadline).await? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        // Commands come as text or binary JSON, control frames are skipped
+        let text = match frame_text(&message)? {
+            Some(text) => text,
+            None => continue,
+        };
+        let received = Instant::now();
+        let out: WSCommand = serde_json::from_str(text)?;
+        let init_data = match out.command_type {
+            WSCommandType::InitializeTransports { init_data } => init_data,
+            WSCommandType::Leave => {
+                return Err(leave(room, user_id, connection_id, outbox, out, false).await)
+            }
+            _ if Phase::AwaitingTransports.answers(&out.command_type) => {
+                answer_early(Some(room), Phase::AwaitingTransports, out, outbox).await?;
+                continue;
+            }
+            _ => return Err(out_of_order(Phase::AwaitingTransports, &out)),
+        };
+
+        let rtc_state = start_transports::<R>(room, user_id, init_data).await?;
+        let reply_data = rtc_state.get_init_data();
+        let reply = WSReply {
+            id: out.id,
+            room_id: None,
+            reply_type: WSReplyType::InitializeTransports { reply_data },
+        };
+
+        let timing = ReplyTiming {
+            command: "InitializeTransports",
+            received,
+        };
+        outbox
+            .send_reply(Message::text(serde_json::to_string(&reply)?), Some(timing))
+            .await?;
+        return Ok(Some(rtc_state));
+    }
+}
+
+/// Transports of the connection on the room's current router, capped like the rest of
+/// the room
+async fn start_transports<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    init_data: InitializationInput,
+) -> Result<R, WSCloseType> {
+    let router = room.router().ok_or(WSCloseType::RoomClosed)?;
+    let mut rtc_state = create_transports::<R>(&router, init_data).await?;
+    cap_layers(room, &mut rtc_state).await;
+    if let Some(bitrate) = room.incoming_bitrate_cap() {
+        rtc_state
+            .set_max_incoming_bitrate(bitrate)
+            .await
+            .map_err(|_| WSCloseType::ServerError)?;
+    }
+    let users = room.users();
+    if let (Some(user), Some(media)) = (users.get(user_id).await, rtc_state.media_stats()) {
+        user.write().await.set_media(media);
+    }
+    Ok(rtc_state)
+}
+
+/// Create the transports, once more on the spot should the worker fail in a way that
+/// may not last. Running out of ports or memory closes the connection for the client
+/// to try another instance, and has the node report itself unready for a while
+async fn create_transports<R: RtcSession>(
+    router: &Router,
+    init_data: InitializationInput,
+) -> Result<R, WSCloseType> {
+    let err = match R::initialize(router, init_data.clone()).await {
+        Ok(rtc_state) => return Ok(rtc_state),
+        Err(err) => err,
+    };
+    TRANSPORT_INIT_FAILURES
+        .with_label_values(&[err.kind()])
+        .inc();
+    let err = match err {
+        InitError::Transient => match R::initialize(router, init_data).await {
+            Ok(rtc_state) => return Ok(rtc_state),
+            Err(err) => {
+                TRANSPORT_INIT_FAILURES
+                    .with_label_values(&[err.kind()])
+                    .inc();
+                err
+            }
+        },
+        err => err,
+    };
+
+    match err {
+        InitError::Exhausted => {
+            tracing::warn!("Worker ran out of ports or memory creating transports");
+            health::mark_exhausted();
+            Err(WSCloseType::ServerOverloaded)
+        }
+        InitError::Transient | InitError::Failed => Err(WSCloseType::ServerError),
+    }
+}
+
+/// Hold the connection's video consumers to the spatial layers of the room's quality
+/// profile, and lower ones while the room is throttled
+async fn cap_layers<R: RtcSession>(room: &Room, rtc_state: &mut R) {
+    let profile = room.quality_profile();
+    let throttled = room.throttled_layer();
+    for produce_type in [ProduceType::Video, ProduceType::ScreenshareVideo] {
+        let profile_cap = profile.and_then(|profile| profile.get(produce_type).max_spatial_layer);
+        let cap = [profile_cap, throttled].iter().flatten().min().copied();
+        rtc_state.cap_consumer_layers(produce_type, cap).await;
+    }
+}
+
+/// Next frame of a client still setting up its session, which has until `deadline` to
+/// send it
+async fn handshake_frame(
+    ws_stream: &mut WSStream,
+    deadline: Option<Instant>,
+) -> Result<Option<Message>, WSCloseType> {
+    let next = ws_stream.next();
+    let message = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), next)
+            .await
+            .map_err(|_| WSCloseType::HandshakeTimeout)?,
+        None => next.await,
+    };
+    message.transpose()
+}
+
+/// What the client asked for when authenticating
+struct Session {
+    scores: Option<ScoreReceiver>,
+    /// Echo the data of failing commands in their errors
+    debug: bool,
+    /// Protocol version the events are shaped for
+    version: u32,
+    /// Optional events and commands the client handles
+    capabilities: Capabilities,
+    /// Numbers the events sent, the first may have been sent in the lobby
+    events: EventSequence,
+    expires_at: Option<Instant>,
+    session_expiry: Option<SessionExpiry>,
+    token_expiry: TokenExpiry,
+    keepalive: Option<Keepalive>,
+}
+
+/// Command handled on a task of its own, aborted if the connection closes first
+struct InFlight(JoinHandle<Result<(), WSCloseType>>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn event_loop<R: RtcSession>(
+    room: &Arc<Room>,
+    mut subscriber: RoomSubscriber,
+    rtc_state: Arc<Mutex<R>>,
+    (joined, mut joined_messages): (JoinedRooms, JoinedReceiver),
+    session: Session,
+    outbox: &Outbox,
+    ws_stream: &mut WSStream,
+) -> Result<Option<WSCommand>, WSCloseType> {
+    let Session {
+        mut scores,
+        debug,
+        version,
+        capabilities,
+        mut events,
+        expires_at,
+        mut session_expiry,
+        mut token_expiry,
+        mut keepalive,
+    } = session;
+    let user_id = subscriber.info().user_id.clone();
+    let connection_id = subscriber.info().connection_id;
     let mut ws_stream = ws_stream.fuse();
+    // Frames are read as they arrive so events aren't held up behind them
+    let mut queue = CommandQueue::new(CONFIG.signaling.max_pending_commands);
+    let (
+        mut transport_states,
+        mut downlink_estimates,
+        mut layer_changes,
+        mut closed_consumers,
+        mut gathered_stats,
+    ) = {
+        let mut rtc_state = rtc_state.lock().await;
+        (
+            rtc_state.take_transport_states(),
+            rtc_state.take_downlink_estimates(),
+            rtc_state.take_layer_changes(),
+            rtc_state.take_closed_consumers(),
+            rtc_state.stats_subscription().take_receiver(),
+        )
+    };
+    let (mut quality, mut quality_samples) = match QualityMonitor::from_config() {
+        Some((monitor, samples)) => (Some(monitor), Some(samples)),
+        None => (None, None),
+    };
+    let (mut reaper, mut consumer_activity) = match R::consumer_reaper() {
+        Some((reaper, activity)) => (Some(reaper), Some(activity)),
+        None => (None, None),
+    };
+    let mut idle = IdleMonitor::from_settings(room.settings().idle);
+    let mut ptt = PushToTalk::from_settings(room.settings().ptt_max_unmute_ms);
+    let mut score_throttle = ScoreThrottle::default();
+    let mut downlink_throttle = DownlinkThrottle::default();
+    // The command being handled may hold the media while awaiting the worker
+    let mut in_flight: Option<InFlight> = None;
+    // Set once the room moved to a new worker, until the client initialized its transports
+    // on the new router
+    let mut media_restart = false;
 
     loop {
+        // Stats wait while a command has the media, nothing else is held up for them
+        let stats_tick = rtc_state
+            .try_lock()
+            .ok()
+            .and_then(|mut rtc_state| rtc_state.stats_subscription().next_tick());
+        let ping_at = keepalive.as_ref().map(Keepalive::next_ping);
+        let quality_at = quality.as_ref().map(QualityMonitor::next_tick);
+        let reap_at = reaper.as_ref().map(ConsumerReaper::next_tick);
+        let idle_at = idle.as_ref().and_then(IdleMonitor::next_check);
+        // Checked again once a command that may have queued a resume is done
+        let resumes_expire_at = rtc_state
+            .try_lock()
+            .ok()
+            .and_then(|rtc_state| rtc_state.resume_deadline());
+        let token_at = token_expiry.next_deadline();
+        let session_warning_at = session_expiry
+            .as_ref()
+            .and_then(SessionExpiry::next_warning);
+        let downlink_at = downlink_throttle.next_flush();
+        let mute_at = ptt.as_ref().and_then(PushToTalk::next_deadline);
         tokio::select! {
             message = ws_stream.next() => {
                 if let Some(message) = message {
-                    let message = message.map_err(|_| WSCloseType::ServerError)?;
-                    // Try to get the text message, ignore otherwise (might be ping, binary)
-                    if let Ok(text) = message.to_str() {
-                        let out: WSCommand = serde_json::from_str(text)?;
-                        match &out.command_type {
-                            WSCommandType::ConnectTransport { connect_data } => {
-                                let result = rtc_state.connect_transport(connect_data).await;
-                                if let Ok(_) = result {
-                                    let reply = WSReply {
-                                        id: out.id,
-                                        reply_type: WSReplyType::ConnectTransport,
-                                    };
-
-                                    ws_sink
-                                        .send(Message::text(serde_json::to_string(&reply)?))
-                                        .await?;
-                                } else {
-                                    let error = WSError::from_command(out, WSErrorType::TransportConnectionFailure);
-                                    ws_sink
-                                        .send(Message::text(serde_json::to_string(&error)?))
-                                        .await?;
-                                }
-                            },
-                            WSCommandType::RoomInfo => {
-                                let users = room.users();
-                                let guard = users.guard().await;
-                                let mut user_info: HashMap<String, UserInfo> = HashMap::new();
-                                for user in guard.iter() {
-                                    let user = user.read().await;
-                                    user_info.insert(user.id().to_string(), user.into_info());
-                                }
+                    let message = message?;
+                    if message.is_pong() {
+                        // Only pongs the client sent on its own show someone's there
+                        let unprompted = keepalive.as_ref().map_or(true, |keepalive| keepalive.unanswered() == 0);
+                        if unprompted {
+                            user_active(room, &user_id, &mut idle);
+                        }
+                        if let Some(keepalive) = keepalive.as_mut() {
+                            keepalive.pong();
+                        }
+                    }
+                    // Commands come as text or binary JSON, or as a blob chunk in a binary
+                    // frame. Control frames are skipped
+                    let frame = match blob_frame::chunk_command(&message) {
+                        Some(command) => {
+                            let mut command = command?;
+                            command.received = Some(Instant::now());
+                            Some((blob_frame::FRAME_TEXT, (None, vec![command])))
+                        }
+                        None => match frame_text(&message)? {
+                            Some(text) => Some((text, frame_commands(text, Instant::now())?)),
+                            None => None,
+                        },
+                    };
+                    if let Some((text, (batch_id, commands))) = frame {
+                        user_active(room, &user_id, &mut idle);
+                        if commands.len() > MAX_BATCH_SIZE {
+                            let error = WSErrorType::BatchTooLarge(commands.len());
+                            count_error("Batch", &error);
+                            let error = WSError::new(batch_id, "Batch", error);
+                            outbox.send(Message::text(serde_json::to_string(&error)?))
+                                .await?;
+                            continue;
+                        }
+
+                        // Refused on their own, the rest of their batch still runs. Their
+                        // data isn't echoed, even to debugging clients
+                        let mut admitted = Vec::with_capacity(commands.len());
+                        for out in commands {
+                            let size = out.data_size();
+                            if size <= CONFIG.signaling.max_command_size {
+                                admitted.push(out);
+                                continue;
+                            }
+                            tracing::debug!(trace_id = %out.trace_id, size, "Refused command above the size limit");
+                            let error = WSErrorType::CommandTooLarge(size);
+                            count_error((&out.command_type).into(), &error);
+                            let error = WSError::from_command(out, error, false);
+                            outbox.send(Message::text(serde_json::to_string(&error)?))
+                                .await?;
+                        }
 
-                                let reply = WSReply {
-                                    id: out.id,
-                                    reply_type: WSReplyType::RoomInfo {
-                                        id: room.id().to_string(),
-                                        video_allowed: false,
-                                        users: user_info,
-                                    }
-                                };
-
-                                ws_sink
-                                    .send(Message::text(serde_json::to_string(&reply)?))
+                        if let Err(refused) = queue.admit(admitted, text) {
+                            tracing::debug!(count = refused.len(), "Command queue full, refusing");
+                            for out in refused {
+                                tracing::debug!(trace_id = %out.trace_id, "Refused command");
+                                let error = WSErrorType::TooManyRequests;
+                                count_error((&out.command_type).into(), &error);
+                                let error = WSError::from_command(out, error, debug);
+                                outbox.send(Message::text(serde_json::to_string(&error)?))
                                     .await?;
                             }
-                            _ => return Err(WSCloseType::InvalidState),
-                        };
+                        }
                     }
                 } else {
-                    return Ok(());
+                    return Ok(None);
                 }
             },
-            event = room_stream.recv() => {
-                let event = event.map_err(|_| WSCloseType::ServerError)?;
-                match event {
-                    RoomEvent::UserJoined(id) => {
-                        if id != user_id {
-                            let event = WSEvent::UserJoined { id };
-                            ws_sink
-                                .send(Message::text(serde_json::to_string(&event)?))
-                                .await?;
-                        }
-                    },
-                    RoomEvent::UserLeft(id) => {
-                        if id == user_id {
-                            return Err(WSCloseType::Kicked);
-                        }
+            // One at a time so they apply in order, while events are still delivered
+            // as the command awaits the worker. A failing command is replied to with
+            // its error and the rest of its batch still runs
+            Some(queued) = async { queue.pop() }, if in_flight.is_none() && !queue.is_empty() => {
+                let QueuedCommand { command: out, frame } = queued;
+                // Authenticating again switches rooms once the commands before it are done.
+                // Those after it can't run before the transports in the new room are up
+                if let WSCommandType::Authenticate { .. } = out.command_type {
+                    tracing::debug!(dropped = queue.len(), "Switching rooms");
+                    return Ok(Some(out));
+                }
+                // Left on purpose, so the others hear of it now rather than after the grace
+                // period. The commands after it are dropped
+                if let WSCommandType::Leave = out.command_type {
+                    tracing::debug!(dropped = queue.len(), "Leaving");
+                    return Err(leave(room, &user_id, connection_id, outbox, out, debug).await);
+                }
+                // Refused before any handler sees it, whichever room it's for
+                if let Some(capability) = out.command_type.capability() {
+                    if !capabilities.contains(capability) {
+                        let error = WSErrorType::CapabilityNotDeclared(capability);
+                        send_result(outbox, out, Err(error), debug).await?;
+                        continue;
+                    }
+                }
+                // Refreshed here, as the token's expiry is the event loop's to keep
+                if let WSCommandType::RefreshToken { token } = &out.command_type {
+                    let result = refresh_token(room, &user_id, token).await.map(|expires_at| {
+                        token_expiry.refresh(expires_at);
+                        WSReplyType::RefreshToken { expires_at }
+                    });
+                    // The token isn't echoed, even to debugging clients
+                    send_result(outbox, out, result, false).await?;
+                    continue;
+                }
+                // Answered here, as the push to talk timer is the event loop's to keep
+                if let WSCommandType::PttHeartbeat = out.command_type {
+                    if let Some(ptt) = ptt.as_mut() {
+                        ptt.heartbeat(Instant::now());
+                    }
+                    send_result(outbox, out, Ok(WSReplyType::PttHeartbeat), debug).await?;
+                    continue;
+                }
+                // Handled here after the media restarted, the loop holds what the
+                // transports being replaced report
+                if let (true, WSCommandType::InitializeTransports { init_data }) =
+                    (media_restart, &out.command_type)
+                {
+                    let restarted = start_transports::<R>(room, &user_id, init_data.clone()).await?;
+                    // Producers left on the router the room migrated away from go along
+                    // with the transports being replaced
+                    if let Some(user) = room.users().get(&user_id).await {
+                        user.write().await.withdraw_producers();
+                    }
+                    let mut rtc_state = rtc_state.lock().await;
+                    *rtc_state = restarted;
+                    transport_states = rtc_state.take_transport_states();
+                    downlink_estimates = rtc_state.take_downlink_estimates();
+                    layer_changes = rtc_state.take_layer_changes();
+                    closed_consumers = rtc_state.take_closed_consumers();
+                    gathered_stats = rtc_state.stats_subscription().take_receiver();
+                    if scores.is_some() {
+                        scores = Some(rtc_state.enable_scores());
+                    }
+                    let reply_data = rtc_state.get_init_data();
+                    drop(rtc_state);
+                    media_restart = false;
+                    let result = Ok(WSReplyType::InitializeTransports { reply_data });
+                    send_result(outbox, out, result, debug).await?;
+                    continue;
+                }
+                let command_type: &'static str = (&out.command_type).into();
+                let span = info_span!(
+                    "command",
+                    command = command_type,
+                    id = ?out.id,
+                    trace_id = %out.trace_id,
+                    room_id = %room.id(),
+                    user_id = %user_id,
+                );
+                let room = room.clone();
+                let user_id = user_id.clone();
+                let rtc_state = rtc_state.clone();
+                let joined = joined.clone();
+                let outbox = outbox.clone();
+                let events = events.clone();
+                let task = async move {
+                    // Joined rooms have no media, their commands don't wait for it
+                    if joined_command(&room, &out) {
+                        let future =
+                            handle_joined_command(&room, &joined, &outbox, &events, out, debug);
+                        return isolate(command_type, move || frame.to_string(), future).await;
+                    }
 
-                        let event = WSEvent::UserLeft { id };
-                        ws_sink
-                            .send(Message::text(serde_json::to_string(&event)?))
-                            .await?;
-                    },
-                    RoomEvent::UserStartProduce(id, produce_type) => {
-                        if id != user_id {
-                            let event = WSEvent::UserStartProduce { id, produce_type };
-                            ws_sink
-                                .send(Message::text(serde_json::to_string(&event)?))
-                                .await?;
+                    let mut rtc_state = rtc_state.lock().await;
+                    let future = handle_command(
+                        &room,
+                        &user_id,
+                        &mut *rtc_state,
+                        &outbox,
+                        &events,
+                        out,
+                        debug,
+                    );
+                    isolate(command_type, move || frame.to_string(), future).await
+                };
+                in_flight = Some(InFlight(tokio::spawn(task.instrument(span))));
+            },
+            Some(result) = async { Some((&mut in_flight.as_mut()?.0).await) },
+                if in_flight.is_some() => {
+                in_flight = None;
+                result.map_err(|_| WSCloseType::ServerError)??;
+                // The command may have unmuted the microphone, or muted it
+                if let Some(ptt) = ptt.as_mut() {
+                    ptt.sync(microphone_open(room, &user_id).await, Instant::now());
+                }
+            },
+            Some(change) = async { transport_states.as_mut()?.recv().await },
+                if transport_states.is_some() => {
+                tracing::debug!(
+                    transport_id = %change.transport_id,
+                    ice_state = ?change.ice_state,
+                    dtls_state = ?change.dtls_state,
+                    "Transport state changed"
+                );
+                let event = WSEvent::TransportStateChanged {
+                    transport_id: change.transport_id.to_string(),
+                    ice_state: change.ice_state,
+                    dtls_state: change.dtls_state,
+                };
+                events.send(outbox, event).await?;
+                if change.dtls_state == DtlsState::Connected {
+                    let consumer_ids = rtc_state
+                        .lock()
+                        .await
+                        .transport_connected(change.transport_id)
+                        .await;
+                    if !consumer_ids.is_empty() {
+                        tracing::debug!(count = consumer_ids.len(), "Resumed queued consumers");
+                        events.send(outbox, WSEvent::ConsumersActivated { consumer_ids }).await?;
+                    }
+                }
+            },
+            // Closed along with their producer, the client is told unless the room already
+            // had them closed or the client stopped them itself
+            Some(consumer_id) = async { closed_consumers.as_mut()?.recv().await },
+                if closed_consumers.is_some() => {
+                let mut rtc_state = rtc_state.lock().await;
+                let consumer = rtc_state.get_consumer(&consumer_id).cloned();
+                if let Some(consumer) = consumer {
+                    rtc_state.stop_consume(&consumer_id);
+                    drop(rtc_state);
+                    let closed = vec![consumer];
+                    close_consumers(outbox, &mut events, closed, MediaClosedReason::ProducerClosed)
+                        .await?;
+                }
+            },
+            Some(change) = async { layer_changes.as_mut()?.recv().await },
+                if layer_changes.is_some() => {
+                let event = WSEvent::ConsumerLayersChanged {
+                    consumer_id: change.consumer_id,
+                    spatial_layer: change.layers.map(|layers| layers.spatial_layer),
+                    temporal_layer: change.layers.and_then(|layers| layers.temporal_layer),
+                    auto_layers: change.auto,
+                };
+                events.send(outbox, event).await?;
+            },
+            _ = tokio::time::sleep_until(stats_tick.unwrap_or_else(Instant::now).into()),
+                if stats_tick.is_some() => {
+                let mut rtc_state = match rtc_state.try_lock() {
+                    Ok(rtc_state) => rtc_state,
+                    Err(_) => continue,
+                };
+                let source = rtc_state.stats_source();
+                let room = room.clone();
+                let user_id = user_id.clone();
+                rtc_state.stats_subscription().tick(async move {
+                    let source = source?;
+                    let producers = own_producers(&room, &user_id).await;
+                    match source.gather(producers).await {
+                        Ok(stats) => Some(stats),
+                        Err(()) => {
+                            tracing::debug!("Failed to gather connection stats");
+                            None
                         }
-                    },
-                    RoomEvent::UserStopProduce(id, produce_type) => {
-                        if id != user_id {
-                            let event = WSEvent::UserStopProduce { id, produce_type };
-                            ws_sink
-                                .send(Message::text(serde_json::to_string(&event)?))
-                                .await?;
+                    }
+                });
+            },
+            Some(gathered) = async { gathered_stats.as_mut()?.recv().await },
+                if gathered_stats.is_some() => {
+                // Gathered before the client unsubscribed, or while a command that may
+                // unsubscribe has the media
+                let subscribed = rtc_state
+                    .try_lock()
+                    .map_or(true, |mut rtc_state| rtc_state.stats_subscription().subscribed());
+                if subscribed {
+                    events.send(outbox, WSEvent::Stats(gathered)).await?;
+                }
+            },
+            _ = tokio::time::sleep_until(quality_at.unwrap_or_else(Instant::now).into()),
+                if quality_at.is_some() => {
+                // Skipped while a command has the media, or before the transports exist
+                let source = rtc_state
+                    .try_lock()
+                    .ok()
+                    .and_then(|rtc_state| rtc_state.stats_source());
+                let room = room.clone();
+                let user_id = user_id.clone();
+                if let Some(quality) = quality.as_mut() {
+                    quality.tick(async move {
+                        let source = source?;
+                        let producers = own_producers(&room, &user_id)
+                            .await
+                            .into_iter()
+                            .map(|(_, producer)| producer)
+                            .collect();
+                        match source.sample_quality(producers).await {
+                            Ok(sample) => Some(sample),
+                            Err(()) => {
+                                tracing::debug!("Failed to sample connection quality");
+                                None
+                            }
                         }
+                    });
+                }
+            },
+            Some(sample) = async { quality_samples.as_mut()?.recv().await },
+                if quality_samples.is_some() => {
+                if let Some(user) = room.users().get(&user_id).await {
+                    user.write().await.set_quality(sample.level(&CONFIG.quality));
+                }
+            },
+            _ = tokio::time::sleep_until(reap_at.unwrap_or_else(Instant::now).into()),
+                if reap_at.is_some() => {
+                // Skipped while a command has the media
+                let consumers = rtc_state
+                    .try_lock()
+                    .ok()
+                    .map(|rtc_state| rtc_state.counted_consumers());
+                if let Some(reaper) = reaper.as_mut() {
+                    reaper.tick(async move { reaper::packet_counts(consumers?).await });
+                }
+            },
+            Some(counts) = async { consumer_activity.as_mut()?.recv().await },
+                if consumer_activity.is_some() => {
+                let inactive_after = match reaper.as_ref() {
+                    Some(reaper) => reaper.inactive_after(),
+                    None => continue,
+                };
+                let reaped = rtc_state.lock().await.reap_inactive(counts, inactive_after);
+                if !reaped.is_empty() {
+                    tracing::debug!(count = reaped.len(), "Reaped inactive consumers");
+                    REAPED_CONSUMERS.inc_by(reaped.len() as u64);
+                    close_consumers(outbox, &mut events, reaped, MediaClosedReason::Inactive)
+                        .await?;
+                }
+            },
+            _ = tokio::time::sleep_until(resumes_expire_at.unwrap_or_else(Instant::now).into()),
+                if resumes_expire_at.is_some() => {
+                let expired = rtc_state.lock().await.expire_resumes();
+                if !expired.is_empty() {
+                    tracing::debug!(count = expired.len(), "Receiving transport didn't connect, closing queued consumers");
+                    close_consumers(outbox, &mut events, expired, MediaClosedReason::TransportTimeout)
+                        .await?;
+                }
+            },
+            Some(update) = async { scores.as_mut()?.recv().await }, if scores.is_some() => {
+                score_throttle.offer(update);
+            },
+            _ = tokio::time::sleep_until(score_throttle.next_flush().into()),
+                if !score_throttle.is_empty() => {
+                for update in score_throttle.flush() {
+                    let event = match update.source {
+                        ScoreSource::Producer(produce_type) => WSEvent::ProducerScore {
+                            produce_type,
+                            score: update.score,
+                        },
+                        ScoreSource::Consumer(consumer_id) => WSEvent::ConsumerScore {
+                            consumer_id,
+                            score: update.score,
+                        },
+                    };
+                    events.send(outbox, event).await?;
+                }
+            },
+            Some(available_bitrate) = async { downlink_estimates.as_mut()?.recv().await },
+                if downlink_estimates.is_some() => {
+                downlink_throttle.offer(available_bitrate);
+            },
+            _ = tokio::time::sleep_until(downlink_at.unwrap_or_else(Instant::now).into()),
+                if downlink_at.is_some() => {
+                if let Some(available_bitrate) = downlink_throttle.flush() {
+                    events.send(outbox, WSEvent::DownlinkEstimate { available_bitrate }).await?;
+                }
+            },
+            _ = tokio::time::sleep_until(ping_at.unwrap_or_else(Instant::now).into()),
+                if ping_at.is_some() => {
+                if let Some(keepalive) = keepalive.as_mut() {
+                    if !keepalive.ping() {
+                        tracing::debug!(missed = keepalive.unanswered(), "Client stopped answering pings");
+                        return Err(WSCloseType::PingTimeout);
                     }
-                    RoomEvent::RoomDelete => {
-                        return Err(WSCloseType::RoomClosed);
-                    },
+                    outbox.send(Message::ping(Vec::new())).await?;
                 }
-            }
-        }
+            },
+            _ = tokio::time::sleep_until(expires_at.unwrap_or_else(Instant::now).into()),
+                if expires_at.is_some() => {
+                tracing::debug!("Session reached the duration its join token allows");
+                return Err(WSCloseType::SessionExpired);
+            },
+            _ = tokio::time::sleep_until(session_warning_at.unwrap_or_else(Instant::now).into()),
+                if session_warning_at.is_some() => {
+                let due = session_expiry
+                    .as_mut()
+                    .and_then(|expiry| expiry.due(Instant::now()));
+                if let Some(seconds_remaining) = due {
+                    events.send(outbox, WSEvent::SessionExpiring { seconds_remaining }).await?;
+                }
+            },
+            _ = tokio::time::sleep_until(idle_at.unwrap_or_else(Instant::now).into()),
+                if idle_at.is_some() => {
+                // Speaking or presenting counts, whether or not the client sends commands
+                let producing = own_producers(room, &user_id)
+                    .await
+                    .iter()
+                    .any(|(_, producer, _)| !producer.paused());
+                if producing {
+                    user_active(room, &user_id, &mut idle);
+                    continue;
+                }
+                match idle.as_mut().and_then(|idle| idle.due(Instant::now())) {
+                    Some(IdleAction::Idle) => room.send_event(RoomEvent::UserIdle(user_id.clone())),
+                    Some(IdleAction::Warn(seconds_remaining)) => {
+                        events.send(outbox, WSEvent::IdleWarning { seconds_remaining }).await?;
+                    }
+                    Some(IdleAction::Disconnect) => {
+                        tracing::debug!("Disconnecting idle user");
+                        return Err(WSCloseType::IdleDisconnect);
+                    }
+                    None => {}
+                }
+            },
+            _ = tokio::time::sleep_until(mute_at.unwrap_or_else(Instant::now).into()),
+                if mute_at.is_some() => {
+                if ptt.as_mut().map_or(false, |ptt| ptt.due(Instant::now())) {
+                    tracing::debug!("Push to talk heartbeats stopped, muting the microphone");
+                    // Muted as the client would, the room and the client itself hear of it
+                    // from the usual event
+                    let result = set_producer_pause(room, &user_id, ProduceType::Audio, true).await;
+                    if let Err(error) = result {
+                        tracing::debug!(%error, "Failed to mute the microphone");
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(token_at.unwrap_or_else(Instant::now).into()),
+                if token_at.is_some() => {
+                match token_expiry.due(token::now()) {
+                    Some(ExpiryAction::Warn(expires_at)) => {
+                        events.send(outbox, WSEvent::TokenExpiring { expires_at }).await?;
+                    }
+                    Some(ExpiryAction::Close) => {
+                        tracing::debug!(expires = token_expiry.expires(), "Token expired without being refreshed");
+                        return Err(WSCloseType::TokenExpired);
+                    }
+                    None => {}
+                }
+            },
+            // Rooms joined with `JoinRoom` that end the user's session there are left,
+            // the connection carries on in the others
+            Some((room_id, message)) = joined_messages.recv() => {
+                let result = joined_room_message::<R>(
+                    &joined,
+                    &room_id,
+                    version,
+                    outbox,
+                    &events,
+                    message,
+                )
+                .await;
+                match result {
+                    Err(close) if close.room_scoped() => {
+                        tracing::debug!(room_id = %room_id, close = %close, "Left joined room");
+                        joined.leave(&room_id).await;
+                        let event = WSEvent::RoomLeft {
+                            code: close.code(),
+                            reason: close.reason(),
+                        };
+                        events.in_room(&room_id).send(outbox, event).await?;
+                    }
+                    result => result?,
+                }
+            },
+            message = subscriber.recv() => {
+                let event = match message {
+                    SubscriberMessage::Event(event) => event,
+                    SubscriberMessage::Lagged(missed) => {
+                        resync(room, outbox, &mut events, missed).await?;
+                        continue;
+                    }
+                    SubscriberMessage::MaxIncomingBitrate(bitrate) => {
+                        let rtc_state = rtc_state.lock().await;
+                        if rtc_state.set_max_incoming_bitrate(bitrate).await.is_err() {
+                            tracing::warn!(bitrate, "Failed to apply incoming bitrate cap");
+                        }
+                        continue;
+                    }
+                    SubscriberMessage::ProducersClosed(closed, reason) => {
+                        let mut rtc_state = rtc_state.lock().await;
+                        for (produce_type, _) in closed.iter() {
+                            rtc_state.remove_consumers_of(&user_id, Some(*produce_type));
+                            rtc_state.release_producer(*produce_type);
+                        }
+                        drop(rtc_state);
+                        for (produce_type, producer_id) in closed {
+                            let event = WSEvent::ProducerClosed {
+                                id: producer_id.to_string(),
+                                produce_type,
+                                reason,
+                            };
+                            events.send(outbox, event).await?;
+                        }
+                        continue;
+                    }
+                    SubscriberMessage::Stats(reply) => {
+                        // Gathered in a task of its own like pushed stats, so a worker
+                        // slow to dump them doesn't hold up the connection
+                        if let Some(source) = rtc_state.lock().await.stats_source() {
+                            let room = room.clone();
+                            let user_id = user_id.clone();
+                            tokio::spawn(async move {
+                                let producers = own_producers(&room, &user_id).await;
+                                if let Ok(stats) = source.gather(producers).await {
+                                    reply.send(stats).ok();
+                                }
+                            });
+                        }
+                        continue;
+                    }
+                    SubscriberMessage::MediaRestart => {
+                        tracing::info!("Media restarted, waiting for the transports again");
+                        media_restart = true;
+                        events.send(outbox, WSEvent::MediaRestartRequired).await?;
+                        continue;
+                    }
+                    SubscriberMessage::Close(WSCloseType::Kicked(reason)) => {
+                        return kicked(outbox, &mut events, reason).await.map(|()| None);
+                    }
+                    SubscriberMessage::Close(reason) => return Err(reason),
+                };
+
+                // Muted by a moderator as well, unmuting again starts the timer over
+                if let RoomEvent::UserProducerPauseChanged(id, ProduceType::Audio, true) = &event {
+                    if let Some(ptt) = ptt.as_mut().filter(|_| *id == user_id) {
+                        ptt.sync(false, Instant::now());
+                    }
+                }
+                let event_type: &'static str = (&event).into();
+                let payload = event.clone();
+                events.share(subscriber.shared_text());
+                let span = debug_span!("room_event", event = event_type);
+                let future = handle_room_event(
+                    room,
+                    &user_id,
+                    version,
+                    Some(&*rtc_state),
+                    outbox,
+                    &mut events,
+                    event,
+                );
+                isolate(event_type, || format!("{:?}", payload), future)
+                    .instrument(span)
+                    .await?;
+            }
+        }
+    }
+}
+
+/// JSON carried by a data frame, `None` for pings, pongs and close frames. Some client
+/// stacks send it in binary frames, which have to be UTF-8 since no other binary format
+/// is spoken on a connection
+fn frame_text(message: &Message) -> Result<Option<&str>, WSCloseType> {
+    if message.is_binary() {
+        return std::str::from_utf8(message.as_bytes())
+            .map(Some)
+            .map_err(|err| {
+                WSCloseType::InvalidData(format!("Binary frame is not UTF-8: {}", err))
+            });
+    }
+    Ok(message.to_str().ok())
+}
+
+/// Commands carried by a data frame, either a single command, an array of them or a
+/// `Batch` command. The ID of a `Batch` command is returned to tag errors about the batch.
+/// The commands are stamped with `received`, when the frame was read
+fn frame_commands(
+    text: &str,
+    received: Instant,
+) -> serde_json::Result<(Option<String>, Vec<WSCommand>)> {
+    let (batch_id, mut commands) = if text.trim_start().starts_with('[') {
+        (None, serde_json::from_str(text)?)
+    } else {
+        let command: WSCommand = serde_json::from_str(text)?;
+        match command.command_type {
+            WSCommandType::Batch { commands } => (command.id, commands),
+            command_type => (
+                None,
+                vec![WSCommand {
+                    id: command.id,
+                    trace_id: command.trace_id,
+                    command_type,
+                    room_id: command.room_id,
+                    data: command.data,
+                    received: None,
+                }],
+            ),
+        }
+    };
+    for command in &mut commands {
+        command.received = Some(received);
+    }
+    Ok((batch_id, commands))
+}
+
+/// Numbers the events sent on a connection, a client seeing a gap or going
+/// backwards can reconverge with `SyncState`. Clones share the sequence, so commands
+/// handled on their own task can read it while the connection sends
+#[derive(Clone)]
+struct EventSequence {
+    last: Arc<AtomicU64>,
+    /// Room joined with `JoinRoom` the events are tagged with
+    room_id: Option<String>,
+    /// Events of capabilities the client didn't declare aren't sent
+    capabilities: Capabilities,
+    /// Bits of the `Subscriptions` the client asked for, shared like the sequence as
+    /// `SetSubscriptions` changes them for every room the connection is in
+    subscriptions: Arc<AtomicU8>,
+    /// Text of the room event being handled shared with the room's other connections,
+    /// see `send_shared`
+    shared: Option<SharedText>,
+}
+
+impl Default for EventSequence {
+    fn default() -> Self {
+        EventSequence::new(Capabilities::all(), Subscriptions::all())
+    }
+}
+
+impl EventSequence {
+    fn new(capabilities: Capabilities, subscriptions: Subscriptions) -> EventSequence {
+        EventSequence {
+            last: Arc::default(),
+            room_id: None,
+            capabilities,
+            subscriptions: Arc::new(AtomicU8::new(subscriptions.bits())),
+            shared: None,
+        }
+    }
+
+    fn subscriptions(&self) -> Subscriptions {
+        Subscriptions::from_bits(self.subscriptions.load(Ordering::SeqCst))
+    }
+
+    fn set_subscriptions(&self, subscriptions: Subscriptions) {
+        self.subscriptions
+            .store(subscriptions.bits(), Ordering::SeqCst);
+    }
+
+    /// Sequence number of the last event sent, 0 before the first
+    fn last(&self) -> u64 {
+        self.last.load(Ordering::SeqCst)
+    }
+
+    /// Same sequence, for the events of a room joined with `JoinRoom`
+    fn in_room(&self, room_id: &str) -> EventSequence {
+        EventSequence {
+            last: self.last.clone(),
+            room_id: Some(room_id.to_string()),
+            capabilities: self.capabilities,
+            subscriptions: self.subscriptions.clone(),
+            shared: None,
+        }
+    }
+
+    /// Text of the room event about to be handled, `None` if it isn't shared
+    fn share(&mut self, text: Option<SharedText>) {
+        self.shared = text;
+    }
+
+    /// Whether the event is sent at all, leaving out those of capabilities the client
+    /// didn't declare and of categories it isn't subscribed to
+    fn wanted(&self, event: &WSEvent) -> bool {
+        if let Some(capability) = event.capability() {
+            if !self.capabilities.contains(capability) {
+                return false;
+            }
+        }
+        match event.category() {
+            Some(category) => self.subscriptions().contains(category),
+            None => true,
+        }
+    }
+
+    /// Membership events may be held back while a burst lasts, if the client takes them
+    /// in batches
+    fn batchable(&self, event: &WSEvent) -> bool {
+        event.membership() && self.capabilities.contains(Capability::EventBatches)
+    }
+
+    /// Low priority events are dropped while the client is falling behind, without
+    /// taking a sequence number, as are events of capabilities the client didn't declare
+    /// and of categories it isn't subscribed to
+    async fn send(&mut self, outbox: &Outbox, event: WSEvent) -> Result<(), WSCloseType> {
+        if !self.wanted(&event) {
+            return Ok(());
+        }
+        let event_type: &'static str = (&event).into();
+        let low_priority = event.low_priority();
+        let batchable = self.batchable(&event);
+        let seq = self.last.fetch_add(1, Ordering::SeqCst) + 1;
+        let event = SequencedEvent {
+            seq,
+            room_id: self.room_id.clone(),
+            event,
+        };
+        let message = Message::text(serde_json::to_string(&event)?);
+        if !low_priority {
+            return outbox.send_event(message, batchable).await;
+        }
+
+        if !outbox.send_low_priority(message)? {
+            self.last.fetch_sub(1, Ordering::SeqCst);
+            DROPPED_EVENTS.with_label_values(&[event_type]).inc();
+        }
+        Ok(())
+    }
+
+    /// Send an event of the room being handled like `send`, serializing it only if no
+    /// other connection did yet. For events every connection is sent the same, as in a
+    /// large room each join would otherwise be serialized once for everyone in it
+    async fn send_shared(&mut self, outbox: &Outbox, event: WSEvent) -> Result<(), WSCloseType> {
+        let text = match self.shared.take() {
+            Some(text) if !event.low_priority() => text,
+            _ => return self.send(outbox, event).await,
+        };
+        if !self.wanted(&event) {
+            return Ok(());
+        }
+
+        let batchable = self.batchable(&event);
+        let body = text.get_or_try_init(|| serde_json::to_string(&event).map(Arc::from))?;
+        let seq = self.last.fetch_add(1, Ordering::SeqCst) + 1;
+        let message = Message::text(sequenced_text(seq, self.room_id.as_deref(), body));
+        outbox.send_event(message, batchable).await
+    }
+}
+
+/// Send the summary of a deleted room, nothing is sent after it but the close frame
+async fn room_closed(
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    summary: RoomSummary,
+) -> Result<(), WSCloseType> {
+    events.send(outbox, WSEvent::RoomSummary(summary)).await?;
+    Err(WSCloseType::RoomClosed)
+}
+
+/// Tell the client why it was kicked, then close the connection
+async fn kicked(
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    reason: Option<String>,
+) -> Result<(), WSCloseType> {
+    let event = WSEvent::Kicked {
+        reason: reason.clone(),
+    };
+    events.send(outbox, event).await?;
+    Err(WSCloseType::Kicked(reason))
+}
+
+/// Replace what a lagging client derived from the dropped room events,
+/// the connection carries on afterwards
+async fn resync(
+    room: &Arc<Room>,
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    missed: u64,
+) -> Result<(), WSCloseType> {
+    tracing::debug!(missed, "Subscriber lagged behind, resyncing");
+    let event = WSEvent::Resync {
+        missed,
+        users: room_users(room).await,
+    };
+    events.send(outbox, event).await
+}
+
+/// Relay what the subscriber of a room joined with `JoinRoom` received, tagged with the
+/// room. An error the room ends the session with leaves it, see `WSCloseType::room_scoped`
+async fn joined_room_message<R: RtcSession>(
+    joined: &JoinedRooms,
+    room_id: &str,
+    version: u32,
+    outbox: &Outbox,
+    events: &EventSequence,
+    message: SubscriberMessage,
+) -> Result<(), WSCloseType> {
+    // Left since the message was forwarded
+    let (room, user_id) = match joined.get(room_id).await {
+        Some(joined) => joined,
+        None => return Ok(()),
+    };
+    let mut events = events.in_room(room_id);
+    match message {
+        SubscriberMessage::Event(event) => {
+            handle_room_event::<R>(&room, &user_id, version, None, outbox, &mut events, event).await
+        }
+        SubscriberMessage::Lagged(missed) => resync(&room, outbox, &mut events, missed).await,
+        SubscriberMessage::Close(WSCloseType::Kicked(reason)) => {
+            kicked(outbox, &mut events, reason).await
+        }
+        SubscriberMessage::Close(reason) => Err(reason),
+        // The connection has no transports in the room
+        SubscriberMessage::MaxIncomingBitrate(_)
+        | SubscriberMessage::ProducersClosed(..)
+        | SubscriberMessage::MediaRestart
+        | SubscriberMessage::Stats(_) => Ok(()),
+    }
+}
+
+/// Run a command or room event handler, or the event loop they're called from, turning
+/// a panic into a `ServerError` close for this connection alone
+async fn isolate<F, P, T>(label: &'static str, payload: P, future: F) -> Result<T, WSCloseType>
+where
+    F: Future<Output = Result<T, WSCloseType>>,
+    P: FnOnce() -> String,
+{
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(result) => result,
+        Err(_) => {
+            COMMAND_PANICS.with_label_values(&[label]).inc();
+            tracing::error!(
+                handler = label,
+                payload = %payload(),
+                "Handler panicked, closing connection"
+            );
+            Err(WSCloseType::ServerError)
+        }
+    }
+}
+
+async fn handle_command<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    outbox: &Outbox,
+    events: &EventSequence,
+    out: WSCommand,
+    debug: bool,
+) -> Result<(), WSCloseType> {
+    let result = match &out.command_type {
+        WSCommandType::ConnectTransport { connect_data } => rtc_state
+            .connect_transport(connect_data)
+            .await
+            .map(|_| WSReplyType::ConnectTransport)
+            .map_err(|_| WSErrorType::TransportConnectionFailure),
+        WSCommandType::RoomInfo(page) => room_info(room, *page).await,
+        WSCommandType::RoomInfoDelta { since } => Ok(room_info_delta(room, *since).await),
+        WSCommandType::SyncState => Ok(sync_state(room, events).await),
+        WSCommandType::ServerInfo => Ok(WSReplyType::ServerInfo(info::server_info())),
+        WSCommandType::StartProduce {
+            produce_type,
+            rtp_parameters,
+            opus,
+            paused,
+        } => {
+            start_produce(
+                room,
+                user_id,
+                rtc_state,
+                *produce_type,
+                rtp_parameters,
+                *opus,
+                *paused,
+            )
+            .await
+        }
+        WSCommandType::StopProduce { produce_type } => {
+            stop_produce(room, user_id, rtc_state, *produce_type).await
+        }
+        WSCommandType::SetProducerPause {
+            produce_type,
+            paused,
+        } => set_producer_pause(room, user_id, *produce_type, *paused).await,
+        WSCommandType::MuteAll { produce_type } => mute_all(room, user_id, *produce_type).await,
+        WSCommandType::ReplaceProduce {
+            produce_type,
+            rtp_parameters,
+            opus,
+        } => {
+            replace_produce(
+                room,
+                user_id,
+                rtc_state,
+                *produce_type,
+                rtp_parameters,
+                *opus,
+            )
+            .await
+        }
+        WSCommandType::StartConsume {
+            produce_type,
+            user_id: producer_user_id,
+        } => start_consume(room, user_id, rtc_state, *produce_type, producer_user_id)
+            .await
+            .map(WSReplyType::StartConsume),
+        WSCommandType::ConsumeUser {
+            user_id: producer_user_id,
+        } => consume_user(room, user_id, rtc_state, producer_user_id).await,
+        WSCommandType::StopConsume { id } => match rtc_state.stop_consume(id) {
+            true => Ok(WSReplyType::StopConsume),
+            false => Err(WSErrorType::ConsumerNotFound(id.clone())),
+        },
+        WSCommandType::SetConsumerPause { id, paused } => {
+            set_consumer_pause(rtc_state, id, *paused).await
+        }
+        WSCommandType::ResumeConsumer { consumer_id } => {
+            resume_consumer(rtc_state, consumer_id).await
+        }
+        WSCommandType::SetConsumerPriority {
+            consumer_id,
+            priority,
+        } => set_consumer_priority(rtc_state, consumer_id, *priority).await,
+        WSCommandType::SetConsumerLayers {
+            consumer_id,
+            spatial_layer,
+            temporal_layer,
+        } => {
+            let layers = ConsumerLayers {
+                spatial_layer: *spatial_layer,
+                temporal_layer: *temporal_layer,
+            };
+            let result = rtc_state.set_consumer_layers(consumer_id, layers).await;
+            layers_reply(result, consumer_id, WSReplyType::SetConsumerLayers)
+        }
+        WSCommandType::SetConsumerAutoLayers {
+            consumer_id,
+            enabled,
+        } => {
+            let result = rtc_state
+                .set_consumer_auto_layers(consumer_id, *enabled)
+                .await;
+            layers_reply(result, consumer_id, WSReplyType::SetConsumerAutoLayers)
+        }
+        WSCommandType::SubscribeStats { interval_ms } => {
+            let interval = stats::clamp_interval(*interval_ms);
+            rtc_state.stats_subscription().subscribe(interval);
+            Ok(WSReplyType::SubscribeStats {
+                interval_ms: interval.as_millis() as u64,
+            })
+        }
+        WSCommandType::UnsubscribeStats => {
+            rtc_state.stats_subscription().unsubscribe();
+            Ok(WSReplyType::UnsubscribeStats)
+        }
+        WSCommandType::ListProducers => {
+            let producers = own_producers(room, user_id)
+                .await
+                .into_iter()
+                .map(|(produce_type, producer)| ProducerSummary {
+                    id: producer.id().to_string(),
+                    produce_type,
+                    kind: producer.kind(),
+                    paused: producer.paused(),
+                })
+                .collect();
+            Ok(WSReplyType::ListProducers { producers })
+        }
+        WSCommandType::ListConsumers => Ok(WSReplyType::ListConsumers {
+            consumers: rtc_state.list_consumers(),
+        }),
+        WSCommandType::RequestKeyFrame { consumer_id } => {
+            match rtc_state.request_key_frame(consumer_id).await {
+                Ok(()) => Ok(WSReplyType::RequestKeyFrame),
+                Err(KeyFrameError::ConsumerNotFound) => {
+                    Err(WSErrorType::ConsumerNotFound(consumer_id.clone()))
+                }
+                Err(KeyFrameError::NotVideo) => {
+                    Err(WSErrorType::NotVideoConsumer(consumer_id.clone()))
+                }
+                Err(KeyFrameError::Failed) => Err(WSErrorType::ConsumerFailure),
+            }
+        }
+        WSCommandType::SetUserInfo { info } => set_user_info(room, user_id, info).await,
+        WSCommandType::UpdatePosition { position } => {
+            room.update_position(user_id, *position);
+            Ok(WSReplyType::UpdatePosition)
+        }
+        WSCommandType::RelayBroadcast { payload } => relay_broadcast(room, user_id, payload).await,
+        WSCommandType::RelayDirect {
+            user_id: target,
+            payload,
+        } => relay_direct(room, user_id, target, payload).await,
+        WSCommandType::RelayBlobStart {
+            user_id: target,
+            size,
+            mime,
+        } => relay_blob_start(room, user_id, target.as_deref(), *size, mime).await,
+        WSCommandType::RelayBlobChunk {
+            transfer_id,
+            seq,
+            data,
+        } => relay_blob_chunk(room, user_id, transfer_id, *seq, data).await,
+        WSCommandType::RelayBlobEnd { transfer_id } => {
+            relay_blob_end(room, user_id, transfer_id).await
+        }
+        WSCommandType::PromoteUser { user_id: target } => {
+            set_role(room, user_id, target, Role::Speaker).await
+        }
+        WSCommandType::DemoteUser { user_id: target } => {
+            set_role(room, user_id, target, Role::Listener).await
+        }
+        WSCommandType::SetOwner { user_id: target } => set_owner(room, user_id, target).await,
+        WSCommandType::OpenRoom => open_room(room, user_id).await,
+        WSCommandType::AdmitUser { user_id: target } => admit_user(room, user_id, target).await,
+        WSCommandType::Kick {
+            user_id: target,
+            reason,
+        } => kick(room, user_id, target, reason.clone()).await,
+        WSCommandType::Ban {
+            user_id: target,
+            reason,
+        } => ban(room, user_id, target, reason.clone()).await,
+        WSCommandType::ReportUser {
+            user_id: target,
+            reason,
+        } => report_user(room, user_id, target, reason).await,
+        WSCommandType::SetMaxIncomingBitrate { bitrate } => {
+            set_max_incoming_bitrate(room, user_id, *bitrate).await
+        }
+        WSCommandType::UpdateRoomSettings { settings } => {
+            update_room_settings(room, user_id, settings.clone()).await
+        }
+        WSCommandType::GetTalkStats => talk_stats(room, user_id).await,
+        WSCommandType::GetRecentEvents { since_seconds } => {
+            recent_events(room, user_id, *since_seconds).await
+        }
+        WSCommandType::SetRoomMetadata { key, value } => {
+            set_room_metadata(room, user_id, key, Some(value)).await
+        }
+        WSCommandType::DeleteRoomMetadata { key } => {
+            set_room_metadata(room, user_id, key, None).await
+        }
+        WSCommandType::Dump { target } => dump_media(room, user_id, rtc_state, target).await,
+        WSCommandType::StartRecording => set_recording(room, user_id, true).await,
+        WSCommandType::StopRecording => set_recording(room, user_id, false).await,
+        WSCommandType::RecordingConsent { granted } => {
+            recording_consent(room, user_id, *granted).await
+        }
+        WSCommandType::SetSubscriptions { subscriptions } => {
+            events.set_subscriptions(Subscriptions::from_names(Some(subscriptions)));
+            Ok(WSReplyType::SetSubscriptions {
+                subscriptions: events.subscriptions().categories(),
+            })
+        }
+        // Top level batches are unpacked before commands are handled
+        WSCommandType::Batch { .. } => Err(WSErrorType::NestedBatch),
+        WSCommandType::Unknown(command_type) => {
+            Err(WSErrorType::UnknownCommand(command_type.clone()))
+        }
+        // `Authenticate` is taken by the event loop as a room switch, `Leave` as it ends the
+        // session, `RefreshToken` and `PttHeartbeat` as the loop keeps the token's expiry
+        // and the push to talk timer, and `InitializeTransports` once the media restarted
+        WSCommandType::Authenticate { .. }
+        | WSCommandType::InitializeTransports { .. }
+        | WSCommandType::RefreshToken { .. }
+        | WSCommandType::PttHeartbeat
+        | WSCommandType::Leave => return Err(out_of_order(Phase::Established, &out)),
+        // Taken by `handle_joined_command` before the media is
+        WSCommandType::JoinRoom { .. } | WSCommandType::LeaveRoom { .. } => {
+            return Err(out_of_order(Phase::Established, &out))
+        }
+    };
+
+    send_result(outbox, out, result, debug).await
+}
+
+/// Whether a command is for the connection's rooms joined with `JoinRoom` rather than the
+/// room it authenticated into, and doesn't need the media
+fn joined_command(room: &Room, out: &WSCommand) -> bool {
+    let joining = matches!(
+        out.command_type,
+        WSCommandType::JoinRoom { .. } | WSCommandType::LeaveRoom { .. }
+    );
+    joining
+        || out
+            .room_id
+            .as_deref()
+            .map_or(false, |room_id| room_id != room.id())
+}
+
+/// Expiry of a token refreshing the session of `user_id`, which it has to be valid for
+/// along with the room. Join tokens aren't redeemed again, their signature is enough
+async fn refresh_token(room: &Room, user_id: &str, token: &str) -> Result<u64, WSErrorType> {
+    match authenticator().validate(room, token).await {
+        Ok(claims) if claims.user_id == user_id => Ok(claims.expires),
+        Ok(_) | Err(AuthError::Refused) => Err(WSErrorType::TokenRefused),
+        Err(AuthError::Unavailable) => Err(WSErrorType::AuthUnavailable),
+    }
+}
+
+/// Remove the user for `Leave` and reply to it, ending the session. The reply goes out once
+/// the room was told the user left, the client may have closed its end by then
+async fn leave(
+    room: &Room,
+    user_id: &str,
+    connection_id: u64,
+    outbox: &Outbox,
+    out: WSCommand,
+    debug: bool,
+) -> WSCloseType {
+    room.users().disconnect(user_id, connection_id).await;
+    send_result(outbox, out, Ok(WSReplyType::Leave), debug)
+        .await
+        .ok();
+    WSCloseType::Left
+}
+
+/// Join or leave a room, or run a command tagged with one joined with `JoinRoom`
+async fn handle_joined_command(
+    room: &Arc<Room>,
+    joined: &JoinedRooms,
+    outbox: &Outbox,
+    events: &EventSequence,
+    out: WSCommand,
+    debug: bool,
+) -> Result<(), WSCloseType> {
+    let result = match &out.command_type {
+        WSCommandType::JoinRoom { room_id, token } => join_room(room, joined, room_id, token).await,
+        WSCommandType::LeaveRoom { room_id } => match joined.leave(room_id).await {
+            true => Ok(WSReplyType::LeaveRoom),
+            false => Err(WSErrorType::NotInRoom(room_id.clone())),
+        },
+        WSCommandType::Unknown(command_type) => {
+            Err(WSErrorType::UnknownCommand(command_type.clone()))
+        }
+        _ => {
+            let room_id = out.room_id.clone().unwrap_or_default();
+            joined_room_command(joined, &room_id, events, &out).await
+        }
+    };
+    send_result(outbox, out, result, debug).await
+}
+
+/// Reply to a command, or send its error
+async fn send_result(
+    outbox: &Outbox,
+    out: WSCommand,
+    result: Result<WSReplyType, WSErrorType>,
+    debug: bool,
+) -> Result<(), WSCloseType> {
+    let command: &'static str = (&out.command_type).into();
+    let timing = out
+        .received
+        .map(|received| ReplyTiming { command, received });
+    let message = match result {
+        Ok(reply_type) => {
+            let reply = WSReply {
+                id: out.id,
+                room_id: out.room_id,
+                reply_type,
+            };
+            Message::text(serde_json::to_string(&reply)?)
+        }
+        Err(error) => {
+            tracing::debug!(code = error.code(), "Command failed: {}", error);
+            count_error(command, &error);
+            let error = WSError::from_command(out, error, debug);
+            Message::text(serde_json::to_string(&error)?)
+        }
+    };
+
+    outbox.send_reply(message, timing).await
+}
+
+/// Count an error replied to a command, by the command and the error's type
+fn count_error(command: &str, error: &WSErrorType) {
+    let error_type: &'static str = error.into();
+    COMMAND_ERRORS
+        .with_label_values(&[command, error_type])
+        .inc();
+}
+
+/// Commands tagged with a room joined with `JoinRoom`, which only reach its signaling
+async fn joined_room_command(
+    joined: &JoinedRooms,
+    room_id: &str,
+    events: &EventSequence,
+    out: &WSCommand,
+) -> Result<WSReplyType, WSErrorType> {
+    let (room, user_id) = joined
+        .get(room_id)
+        .await
+        .ok_or_else(|| WSErrorType::NotInRoom(room_id.to_string()))?;
+    match &out.command_type {
+        WSCommandType::RoomInfo(page) => room_info(&room, *page).await,
+        WSCommandType::RoomInfoDelta { since } => Ok(room_info_delta(&room, *since).await),
+        WSCommandType::SyncState => Ok(sync_state(&room, events).await),
+        WSCommandType::SetUserInfo { info } => set_user_info(&room, &user_id, info).await,
+        WSCommandType::RelayBroadcast { payload } => {
+            relay_broadcast(&room, &user_id, payload).await
+        }
+        WSCommandType::RelayDirect {
+            user_id: target,
+            payload,
+        } => relay_direct(&room, &user_id, target, payload).await,
+        WSCommandType::RelayBlobStart {
+            user_id: target,
+            size,
+            mime,
+        } => relay_blob_start(&room, &user_id, target.as_deref(), *size, mime).await,
+        WSCommandType::RelayBlobChunk {
+            transfer_id,
+            seq,
+            data,
+        } => relay_blob_chunk(&room, &user_id, transfer_id, *seq, data).await,
+        WSCommandType::RelayBlobEnd { transfer_id } => {
+            relay_blob_end(&room, &user_id, transfer_id).await
+        }
+        _ => Err(WSErrorType::SignalOnly),
+    }
+}
+
+/// Join another room over the connection, see `WSCommandType::JoinRoom`
+async fn join_room(
+    room: &Arc<Room>,
+    joined: &JoinedRooms,
+    room_id: &str,
+    token: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    if room_id == room.id() || joined.contains(room_id).await {
+        return Err(WSErrorType::AlreadyInRoom(room_id.to_string()));
+    }
+
+    let (room, user_id) = joined
+        .join(room.tenant(), room_id, token)
+        .await
+        .ok_or_else(|| WSErrorType::JoinRefused(room_id.to_string()))?;
+    tracing::debug!(room_id, user_id = %user_id, "Joined another room");
+    Ok(WSReplyType::JoinRoom {
+        room_id: room_id.to_string(),
+        user_id,
+        users: room_users(&room).await,
+    })
+}
+
+/// List the room's users, or a page of them, shedding the work when the node is under
+/// load. The roster revision is read first, like the sequence number of `sync_state`
+async fn room_info(
+    room: &Arc<Room>,
+    page: Option<RoomInfoPage>,
+) -> Result<WSReplyType, WSErrorType> {
+    let level = load::level();
+    let cached = match level {
+        LoadLevel::Normal => None,
+        LoadLevel::Elevated => room.cached_info(),
+        LoadLevel::Overloaded => {
+            load::record_shed("RoomInfo", level);
+            return Err(WSErrorType::Overloaded {
+                retry_after_ms: load::retry_after_ms(),
+            });
+        }
+    };
+
+    let stale = cached.is_some();
+    let (revision, users) = match cached {
+        Some(cached) => {
+            load::record_shed("RoomInfo", level);
+            cached
+        }
+        None => {
+            let revision = room.roster_revision();
+            let users = room_users(room).await;
+            room.cache_info(revision, users.clone());
+            (revision, users)
+        }
+    };
+
+    let (users, next_offset) = match page {
+        Some(page) => page.apply(users),
+        None => (users, None),
+    };
+    Ok(WSReplyType::RoomInfo {
+        snapshot: room_snapshot(room, users).await,
+        stale,
+        revision,
+        next_offset,
+    })
+}
+
+/// Users that joined, left or changed since the revision, everyone if that's too far
+/// back. Changes on other nodes aren't tracked, so everyone is listed when the room
+/// spans several
+async fn room_info_delta(room: &Arc<Room>, since: u64) -> WSReplyType {
+    let revision = room.roster_revision();
+    let changed = match get_redis() {
+        Some(_) => None,
+        None => room.roster_changes(since),
+    };
+    let changed = match changed {
+        Some(changed) => changed,
+        None => {
+            return WSReplyType::RoomInfoDelta {
+                revision,
+                reset: true,
+                users: room_users(room).await,
+                removed: Vec::new(),
+            }
+        }
+    };
+
+    let mut present = HashMap::new();
+    for (id, user) in room.users().snapshot().await {
+        if changed.binary_search(&id).is_ok() {
+            present.insert(id, user);
+        }
+    }
+    let mut users = HashMap::new();
+    let mut removed = Vec::new();
+    for id in changed {
+        let info = match present.get(&id) {
+            Some(user) => {
+                let user = user.read().await;
+                if user.registered() && !user.hidden() {
+                    Some(user.into_info())
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        match info {
+            Some(info) => {
+                users.insert(id, info);
+            }
+            None => removed.push(id),
+        }
+    }
+    WSReplyType::RoomInfoDelta {
+        revision,
+        reset: false,
+        users,
+        removed,
+    }
+}
+
+/// Fresh snapshot of the room, never served from the cache since clients rely on it
+/// to correct their state. The sequence number is read first, so every event up to
+/// `seq` is reflected in it, and events sent meanwhile may be as well
+async fn sync_state(room: &Arc<Room>, events: &EventSequence) -> WSReplyType {
+    let seq = events.last();
+    let users = room_users(room).await;
+    WSReplyType::SyncState {
+        seq,
+        snapshot: room_snapshot(room, users).await,
+    }
+}
+
+async fn room_snapshot(room: &Arc<Room>, users: HashMap<String, UserInfo>) -> RoomSnapshot {
+    let metadata = room.metadata().await;
+    RoomSnapshot {
+        id: room.id().to_string(),
+        video_allowed: metadata.video_allowed(),
+        users,
+        recording: room.recording().await,
+        locked: room.locked(),
+        waiting: room.waiting(),
+        feature_flags: metadata.settings.feature_flags.clone(),
+        metadata,
+    }
+}
+
+/// Every user in the room visible to other participants, including those on other instances.
+/// Each user is read on its own, without holding up the room's user map
+async fn room_users(room: &Arc<Room>) -> HashMap<String, UserInfo> {
+    let users = room.users().snapshot().await;
+    let mut user_info: HashMap<String, UserInfo> = HashMap::with_capacity(users.len());
+    for (id, user) in users {
+        let user = user.read().await;
+        if !user.registered() || user.hidden() {
+            continue;
+        }
+
+        user_info.insert(id, user.into_info());
+    }
+
+    // Users connected to other instances
+    if let Some(redis) = get_redis() {
+        for (id, info) in redis.users(room.scoped_id()).await {
+            user_info.entry(id).or_insert(info);
+        }
+    }
+
+    user_info
+}
+
+async fn set_user_info(
+    room: &Arc<Room>,
+    user_id: &str,
+    info: &UserInfoUpdate,
+) -> Result<WSReplyType, WSErrorType> {
+    info.validate().map_err(WSErrorType::InvalidUserInfo)?;
+
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    user.write().await.set_info(info.clone());
+    Ok(WSReplyType::SetUserInfo)
+}
+
+/// Change the room's cap on participant bitrate, moderators only
+async fn set_max_incoming_bitrate(
+    room: &Arc<Room>,
+    user_id: &str,
+    bitrate: u32,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    room.set_max_incoming_bitrate(bitrate);
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::MaxIncomingBitrateSet)
+            .actor(Some(user_id))
+            .details(json!({ "bitrate": bitrate })),
+    );
+    Ok(WSReplyType::SetMaxIncomingBitrate)
+}
+
+/// How everyone spoke so far in the call, moderators only
+async fn talk_stats(room: &Arc<Room>, user_id: &str) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    Ok(WSReplyType::GetTalkStats {
+        users: room.usage().talk_stats(),
+    })
+}
+
+/// Membership and moderation events the room kept, moderators only
+async fn recent_events(
+    room: &Arc<Room>,
+    user_id: &str,
+    since_seconds: u64,
+) -> Result<WSReplyType, WSErrorType> {
+    if !is_moderator(room, user_id).await? {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+
+    let events = room
+        .recent_events(since_seconds)
+        .ok_or(WSErrorType::RecentEventsUnavailable)?
+        .into_iter()
+        .filter_map(|(at, event)| {
+            let event = replayed_event(event)?;
+            Some(RecentEvent {
+                at: at.timestamp_millis(),
+                event,
+            })
+        })
+        .collect();
+    Ok(WSReplyType::GetRecentEvents { events })
+}
+
+/// Event a kept room event was sent as
+fn replayed_event(event: RoomEvent) -> Option<WSEvent> {
+    Some(match event {
+        RoomEvent::UserJoined(id, info) => WSEvent::UserJoined { id, info },
+        RoomEvent::UserLeft(id, kicked) => WSEvent::UserLeft { id, kicked },
+        RoomEvent::UserRoleChanged(id, role) => WSEvent::UserRoleChanged { id, role },
+        RoomEvent::OwnerChanged(owner) => WSEvent::OwnerChanged { owner },
+        RoomEvent::UserWaiting(id) => WSEvent::UserWaiting { id },
+        RoomEvent::UserAdmitted(id) => WSEvent::UserAdmitted { id },
+        RoomEvent::UserReported(report) => WSEvent::UserReported {
+            id: report.id,
+            reporter: report.reporter,
+            target: report.target,
+            reason: report.reason,
+        },
+        _ => return None,
+    })
+}
+
+/// mediasoup's view of part of the room, moderators only
+async fn dump_media<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &R,
+    target: &DumpTarget,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    let dump = match target {
+        DumpTarget::Router => {
+            let router = room.router().ok_or(WSErrorType::DumpFailure)?;
+            dump::router(&router).await
+        }
+        DumpTarget::Transports => rtc_state.dump_transports().await,
+        DumpTarget::Producer { id } => {
+            let producer = room
+                .producer(id)
+                .await
+                .ok_or_else(|| WSErrorType::ProducerNotFound(id.clone()))?;
+            dump::producer(&producer).await
+        }
+        DumpTarget::Consumer { id } => {
+            let consumer = rtc_state
+                .get_consumer(id)
+                .ok_or_else(|| WSErrorType::ConsumerNotFound(id.clone()))?;
+            dump::consumer(consumer).await
+        }
+    };
+
+    tracing::info!(?target, "Dumped media state for a moderator");
+    let dump = dump.map_err(|_| WSErrorType::DumpFailure)?;
+    Ok(WSReplyType::Dump { dump })
+}
+
+/// Change the room's live settings, moderators only
+async fn update_room_settings(
+    room: &Arc<Room>,
+    user_id: &str,
+    settings: RoomSettingsUpdate,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    room.update_settings(settings).await?;
+    let settings = room.metadata().await.settings;
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::SettingsUpdated)
+            .actor(Some(user_id))
+            .details(json!({ "settings": settings })),
+    );
+    Ok(WSReplyType::UpdateRoomSettings)
+}
+
+/// Set a key of the room's metadata, or delete it without a value. Moderators only
+async fn set_room_metadata(
+    room: &Arc<Room>,
+    user_id: &str,
+    key: &str,
+    value: Option<&serde_json::Value>,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    let reply = match value {
+        Some(value) => {
+            room.set_metadata(key.to_string(), value.clone())
+                .map_err(WSErrorType::InvalidRoomMetadata)?;
+            WSReplyType::SetRoomMetadata
+        }
+        None => {
+            room.delete_metadata(key);
+            WSReplyType::DeleteRoomMetadata
+        }
+    };
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::MetadataUpdated)
+            .actor(Some(user_id))
+            .details(json!({ "key": key, "value": value })),
+    );
+    Ok(reply)
+}
+
+/// Check a relayed message against the size and rate limits, returning whether it's
+/// delivered. Messages of muted users are acknowledged like any other but go nowhere
+async fn admit_relay(
+    room: &Arc<Room>,
+    user_id: &str,
+    payload: &serde_json::Value,
+) -> Result<bool, WSErrorType> {
+    let size = serde_json::to_vec(payload).map_or(usize::MAX, |bytes| bytes.len());
+    if size > CONFIG.signaling.max_relay_payload {
+        return Err(WSErrorType::RelayTooLarge(size));
+    }
+    admit_relay_sender(room, user_id).await
+}
+
+/// Check the user against the relay rate limit, returning whether what it relays is
+/// delivered
+async fn admit_relay_sender(room: &Arc<Room>, user_id: &str) -> Result<bool, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.write().await.take_relay() {
+        return Err(WSErrorType::TooManyRequests);
+    }
+
+    Ok(!room.relay_muted(user_id))
+}
+
+/// Pass a message on to the rest of the room
+async fn relay_broadcast(
+    room: &Arc<Room>,
+    user_id: &str,
+    payload: &serde_json::Value,
+) -> Result<WSReplyType, WSErrorType> {
+    if admit_relay(room, user_id, payload).await? {
+        room.send_event(RoomEvent::Relay(user_id.to_string(), payload.clone()));
+    }
+    Ok(WSReplyType::RelayBroadcast)
+}
+
+/// Pass a message on to the connection of a single user on this node
+async fn relay_direct(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+    payload: &serde_json::Value,
+) -> Result<WSReplyType, WSErrorType> {
+    let connection_id = user_connection(room, target).await?;
+    if admit_relay(room, user_id, payload).await? {
+        let event = RoomEvent::Relay(user_id.to_string(), payload.clone());
+        if !room.signal_subscriber(connection_id, SubscriberSignal::Event(event)) {
+            return Err(WSErrorType::UserNotFound(target.to_string()));
+        }
+    }
+    Ok(WSReplyType::RelayDirect)
+}
+
+/// Connection of a user connected to this node
+async fn user_connection(room: &Room, user_id: &str) -> Result<u64, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    let connection_id = user.read().await.connection_id();
+    connection_id.ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))
+}
+
+/// Start relaying a blob to the connection of a single user on this node, or to those of
+/// everyone else on it. Blobs of muted users go nowhere, like their messages
+async fn relay_blob_start(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: Option<&str>,
+    size: usize,
+    mime: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    let connection_id = user_connection(room, user_id).await?;
+    let recipients = match target {
+        Some(target) => vec![user_connection(room, target).await?],
+        None => room
+            .subscribers()
+            .into_iter()
+            .filter(|subscriber| subscriber.user_id != user_id)
+            .map(|subscriber| subscriber.connection_id)
+            .collect(),
+    };
+    let recipients = match admit_relay_sender(room, user_id).await? {
+        true => recipients,
+        false => Vec::new(),
+    };
+
+    let transfer_id = room
+        .start_blob(user_id, connection_id, recipients, size, mime)
+        .await?;
+    Ok(WSReplyType::RelayBlobStart { transfer_id })
+}
+
+/// Pass the next chunk of a blob on, once it's known to be base64
+async fn relay_blob_chunk(
+    room: &Arc<Room>,
+    user_id: &str,
+    transfer_id: &str,
+    seq: u64,
+    data: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    let connection_id = user_connection(room, user_id).await?;
+    let len = base64::decode(data)
+        .map_err(|_| WSErrorType::InvalidBlobChunk("data"))?
+        .len();
+    room.relay_blob_chunk(transfer_id, connection_id, seq, data, len)
+        .await?;
+    Ok(WSReplyType::RelayBlobChunk)
+}
+
+async fn relay_blob_end(
+    room: &Arc<Room>,
+    user_id: &str,
+    transfer_id: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    let connection_id = user_connection(room, user_id).await?;
+    room.end_blob(transfer_id, connection_id).await?;
+    Ok(WSReplyType::RelayBlobEnd)
+}
+
+/// Promote a listener to speaker or demote a speaker to listener, moderators only
+async fn set_role(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+    role: Role,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    {
+        let user = users
+            .get(user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+        if !user.read().await.has_permission(Permission::Moderator) {
+            return Err(WSErrorType::MissingPermission(Permission::Moderator));
+        }
+    }
+
+    let target_user = users
+        .get(target)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(target.to_string()))?;
+    let mut target_user = target_user.write().await;
+    // Promoting a moderator would take its moderation away
+    if role == Role::Listener || target_user.role() == Role::Listener {
+        target_user.set_role(role);
+    }
+
+    let (action, reply) = match role {
+        Role::Listener => (AuditAction::Demoted, WSReplyType::DemoteUser),
+        _ => (AuditAction::Promoted, WSReplyType::PromoteUser),
+    };
+    audit::record(
+        AuditRecord::new(room.scoped_id(), action)
+            .actor(Some(user_id))
+            .target(target),
+    );
+    Ok(reply)
+}
+
+/// Hand the room to another registered user, moderators only
+async fn set_owner(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    // Pending and hidden users aren't part of the call to own it
+    let target_user = users
+        .get(target)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(target.to_string()))?;
+    let target_user = target_user.read().await;
+    if !target_user.registered() || target_user.hidden() {
+        return Err(WSErrorType::UserNotFound(target.to_string()));
+    }
+    drop(target_user);
+
+    room.set_owner(Some(target.to_string()));
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::OwnerChanged)
+            .actor(Some(user_id))
+            .target(target),
+    );
+    Ok(WSReplyType::SetOwner)
+}
+
+/// Let everyone waiting in the lobby in, moderators only. Opening a room that isn't
+/// locked succeeds
+async fn open_room(room: &Arc<Room>, user_id: &str) -> Result<WSReplyType, WSErrorType> {
+    if !is_moderator(room, user_id).await? {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+
+    if room.open() {
+        audit::record(
+            AuditRecord::new(room.scoped_id(), AuditAction::RoomOpened).actor(Some(user_id)),
+        );
+    }
+    Ok(WSReplyType::OpenRoom)
+}
+
+/// Let a user in from the lobby while the room stays locked, moderators only
+async fn admit_user(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    if !is_moderator(room, user_id).await? {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+
+    if !room.admit(target) {
+        return Err(WSErrorType::NotWaiting(target.to_string()));
+    }
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::Admitted)
+            .actor(Some(user_id))
+            .target(target),
+    );
+    Ok(WSReplyType::AdmitUser)
+}
+
+async fn is_moderator(room: &Arc<Room>, user_id: &str) -> Result<bool, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    let moderator = user.read().await.has_permission(Permission::Moderator);
+    Ok(moderator)
+}
+
+/// Remove a user from the room, moderators only
+async fn kick(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+    reason: Option<String>,
+) -> Result<WSReplyType, WSErrorType> {
+    if let Some(reason) = &reason {
+        if reason.len() > MAX_KICK_REASON {
+            return Err(WSErrorType::InvalidKickReason(reason.len()));
+        }
+    }
+
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    users
+        .kick(target, reason, Some(user_id))
+        .await
+        .map_err(|_| WSErrorType::UserNotFound(target.to_string()))?;
+    Ok(WSReplyType::Kick)
+}
+
+/// Ban a user from the room and kick it if it's in, moderators only
+async fn ban(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+    reason: Option<String>,
+) -> Result<WSReplyType, WSErrorType> {
+    if let Some(reason) = &reason {
+        if reason.len() > MAX_KICK_REASON {
+            return Err(WSErrorType::InvalidKickReason(reason.len()));
+        }
+    }
+
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    tracing::info!(banned = target, reason = ?reason, "Banned user from the room");
+    room.ban(target, reason.clone());
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::Banned)
+            .actor(Some(user_id))
+            .target(target)
+            .reason(reason.as_deref()),
+    );
+    // Bans hold whether or not the user is in the room
+    users.kick(target, reason, Some(user_id)).await.ok();
+    Ok(WSReplyType::Ban)
+}
+
+/// Pass a complaint about a user on to the room's moderators, with the ID it's recorded
+/// under in the audit log
+async fn report_user(
+    room: &Arc<Room>,
+    user_id: &str,
+    target: &str,
+    reason: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    if reason.len() > MAX_KICK_REASON {
+        return Err(WSErrorType::InvalidKickReason(reason.len()));
+    }
+
+    let users = room.users();
+    if users.get(target).await.is_none() {
+        return Err(WSErrorType::UserNotFound(target.to_string()));
+    }
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if let Err(wait) = user.write().await.take_report() {
+        return Err(WSErrorType::TooManyReports {
+            retry_after_ms: wait.as_millis() as u64,
+        });
+    }
+    drop(user);
+    drop(users);
+
+    let report = UserReport {
+        id: ulid::generate(),
+        reporter: user_id.to_string(),
+        target: target.to_string(),
+        reason: reason.to_string(),
+    };
+    tracing::info!(report_id = %report.id, reported = target, "Reported user to the moderators");
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::Reported)
+            .actor(Some(user_id))
+            .target(target)
+            .reason(Some(reason))
+            .details(json!({ "reportId": report.id })),
+    );
+    let report_id = report.id.clone();
+    room.signal_moderators(RoomEvent::UserReported(report))
+        .await;
+    Ok(WSReplyType::ReportUser { report_id })
+}
+
+/// Start or stop recording the room, moderators only
+async fn set_recording(
+    room: &Arc<Room>,
+    user_id: &str,
+    recording: bool,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    if !user.read().await.has_permission(Permission::Moderator) {
+        return Err(WSErrorType::MissingPermission(Permission::Moderator));
+    }
+    drop(user);
+
+    let (action, reply) = match recording {
+        true => room
+            .start_recording()
+            .await
+            .map(|_| (AuditAction::RecordingStarted, WSReplyType::StartRecording)),
+        false => room
+            .stop_recording()
+            .await
+            .map(|_| (AuditAction::RecordingStopped, WSReplyType::StopRecording)),
+    }
+    .map_err(WSErrorType::from)?;
+    audit::record(AuditRecord::new(room.scoped_id(), action).actor(Some(user_id)));
+    Ok(reply)
+}
+
+async fn recording_consent(
+    room: &Arc<Room>,
+    user_id: &str,
+    granted: bool,
+) -> Result<WSReplyType, WSErrorType> {
+    room.answer_recording_consent(user_id, granted)
+        .await
+        .map_err(WSErrorType::from)?;
+    let action = match granted {
+        true => AuditAction::RecordingConsentGranted,
+        false => AuditAction::RecordingConsentDeclined,
+    };
+    audit::record(AuditRecord::new(room.scoped_id(), action).target(user_id));
+    Ok(WSReplyType::RecordingConsent)
+}
+
+/// Current info of a user, looked up on other instances if they aren't connected here
+async fn user_info(room: &Arc<Room>, id: &str) -> Option<UserInfo> {
+    if let Some(user) = room.users().get(id).await {
+        return Some(user.read().await.into_info());
+    }
+
+    match get_redis() {
+        Some(redis) => redis.users(room.scoped_id()).await.remove(id),
+        None => None,
+    }
+}
+
+/// Refuse starting or stopping a producer once the user did so too often, flaky devices
+/// toggling them would keep the room busy with it
+async fn throttle_produce(room: &Arc<Room>, user_id: &str) -> Result<(), WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    let taken = user.write().await.take_produce();
+    taken.map_err(|wait| WSErrorType::RateLimited {
+        retry_after_ms: wait.as_millis() as u64,
+    })
+}
+
+async fn start_produce<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    produce_type: ProduceType,
+    rtp_parameters: &RtpParameters,
+    opus: Option<OpusOptions>,
+    paused: bool,
+) -> Result<WSReplyType, WSErrorType> {
+    throttle_produce(room, user_id).await?;
+    if !rtc_state.can_produce(produce_type, &room.producer_limits()) {
+        return Err(WSErrorType::TooManyProducers(produce_type.to_string()));
+    }
+
+    let users = room.users();
+    {
+        let user = users
+            .get(user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+        let user = user.read().await;
+        if user.constraints().listen_only || !user.role().can_produce() {
+            return Err(WSErrorType::ListenOnly);
+        }
+        if user.get_producer(produce_type).is_some() {
+            return Err(WSErrorType::ProducerFailure);
+        }
+    }
+
+    check_scalability_modes(room, rtp_parameters)?;
+    let mut rtp_parameters = rtp_parameters.clone();
+    let max_bitrates = cap_bitrate(room, produce_type, &mut rtp_parameters)?;
+    let (producer, opus) = rtc_state
+        .start_produce(
+            produce_type,
+            rtp_parameters,
+            opus,
+            &room.settings().opus_limits,
+        )
+        .await?;
+    let producer_id = producer.id();
+    // Paused before anyone hears of it, consumers start out muted along with it
+    if paused && producer.pause().await.is_err() {
+        rtc_state.release_producer(produce_type);
+        return Err(WSErrorType::ProducerFailure);
+    }
+
+    let hidden = {
+        let user = match users.get(user_id).await {
+            Some(user) => user,
+            None => {
+                rtc_state.release_producer(produce_type);
+                return Err(WSErrorType::UserNotFound(user_id.to_string()));
+            }
+        };
+        let mut user = user.write().await;
+        if user.set_producer(produce_type, Some(producer)).is_err() {
+            rtc_state.release_producer(produce_type);
+            return Err(WSErrorType::ProducerFailure);
+        }
+        user.announce_producer(produce_type);
+        user.hidden()
+    };
+
+    // Hidden users are kept out of recordings, as they are out of everything else
+    if !hidden {
+        let source = TrackSource {
+            user_id: user_id.to_string(),
+            produce_type,
+            producer_id,
+        };
+        room.record_producer(source).await;
+        if produce_type == ProduceType::Audio {
+            room.observe_audio(user_id, producer_id).await;
+        }
+    }
+
+    let echo = match produce_type {
+        ProduceType::Audio if room.settings().echo => {
+            start_echo(room, user_id, rtc_state, producer_id).await
+        }
+        _ => None,
+    };
+
+    Ok(WSReplyType::StartProduce {
+        producer_id: producer_id.to_string(),
+        echo,
+        opus,
+        max_bitrates,
+    })
+}
+
+/// Refuse a new producer whose encodings use a scalability mode the room doesn't allow.
+/// Simulcast and SVC producers may share a room, consumers are created the same way
+fn check_scalability_modes(room: &Room, rtp_parameters: &RtpParameters) -> Result<(), WSErrorType> {
+    let allowed = match &room.settings().scalability_modes {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+    let modes = rtp_parameters
+        .encodings
+        .iter()
+        .filter_map(layers::scalability_mode);
+    for mode in modes {
+        if !allowed.contains(&mode) {
+            return Err(WSErrorType::ScalabilityModeNotAllowed(mode));
+        }
+    }
+    Ok(())
+}
+
+/// Clamp the encodings of a new producer to the room's cap on its kind and that of its
+/// quality profile, giving the bitrates they were left with if there is one
+fn cap_bitrate(
+    room: &Room,
+    produce_type: ProduceType,
+    rtp_parameters: &mut RtpParameters,
+) -> Result<Option<Vec<u32>>, WSErrorType> {
+    let profile_cap = room
+        .quality_profile()
+        .and_then(|profile| profile.get(produce_type).max_bitrate);
+    let caps = [
+        room.settings().bitrate_limits.get(produce_type),
+        profile_cap,
+    ];
+    let cap = match caps.iter().flatten().min() {
+        Some(cap) => *cap,
+        None => return Ok(None),
+    };
+    bitrate::clamp_encodings(rtp_parameters, cap)
+        .map(Some)
+        .map_err(|_| WSErrorType::BitrateUnattainable(cap))
+}
+
+/// Loop the client's audio back on its receiving transport, through the same path media
+/// from other users takes. The producer still works if this fails
+async fn start_echo<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    producer_id: ProducerId,
+) -> Option<EchoConsumer> {
+    let router = room.router()?;
+    let consumer = rtc_state
+        .start_consume(&router, producer_id, user_id, ProduceType::Audio, None)
+        .await;
+    match consumer {
+        Ok(consumer) => Some(EchoConsumer {
+            id: consumer.id().to_string(),
+            kind: consumer.kind(),
+            rtp_parameters: consumer.rtp_parameters().clone(),
+            paused: consumer.paused(),
+        }),
+        Err(_) => {
+            tracing::warn!("Failed to loop audio back in echo test room");
+            None
+        }
+    }
+}
+
+async fn stop_produce<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    produce_type: ProduceType,
+) -> Result<WSReplyType, WSErrorType> {
+    throttle_produce(room, user_id).await?;
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    let mut user = user.write().await;
+    if user.get_producer(produce_type).is_none() {
+        return Err(WSErrorType::ProducerNotFound(produce_type.to_string()));
+    }
+
+    // Dropping the producer closes it along with every consumer of it
+    user.set_producer(produce_type, None)
+        .map_err(|_| WSErrorType::ProducerFailure)?;
+    user.announce_producer(produce_type);
+    drop(user);
+
+    // Echo consumers are this connection's own, forget them along with the producer
+    // rather than waiting on the room event, which hidden users don't send
+    rtc_state.remove_consumers_of(user_id, Some(produce_type));
+    rtc_state.release_producer(produce_type);
+    Ok(WSReplyType::StopProduce)
+}
+
+/// Mute or unmute the user's producer of a type and let the room know
+async fn set_producer_pause(
+    room: &Arc<Room>,
+    user_id: &str,
+    produce_type: ProduceType,
+    paused: bool,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    let user = users
+        .get(user_id)
+        .await
+        .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+    let producer = user
+        .read()
+        .await
+        .get_producer(produce_type)
+        .cloned()
+        .ok_or_else(|| WSErrorType::ProducerNotFound(produce_type.to_string()))?;
+    if producer.paused() == paused {
+        return Ok(WSReplyType::SetProducerPause);
+    }
+
+    let result = match paused {
+        true => producer.pause().await,
+        false => producer.resume().await,
+    };
+    result.map_err(|_| WSErrorType::ProducerFailure)?;
+    user.read()
+        .await
+        .announce_producer_paused(produce_type, paused);
+    Ok(WSReplyType::SetProducerPause)
+}
+
+/// Pause everyone else's producer of a type, moderators only. The producers are collected
+/// first so users joining or leaving meanwhile aren't held up, and those gone by the
+/// time theirs is paused are left out
+async fn mute_all(
+    room: &Arc<Room>,
+    user_id: &str,
+    produce_type: ProduceType,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    {
+        let user = users
+            .get(user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+        if !user.read().await.has_permission(Permission::Moderator) {
+            return Err(WSErrorType::MissingPermission(Permission::Moderator));
+        }
+    }
+
+    let mut producers = Vec::new();
+    {
+        let guard = users.guard().await;
+        for user in guard.iter() {
+            let user = user.read().await;
+            if user.id() == user_id || user.has_permission(Permission::BypassMute) {
+                continue;
+            }
+            if let Some(producer) = user.get_producer(produce_type) {
+                if !producer.paused() {
+                    producers.push((user.id().to_string(), producer.clone()));
+                }
+            }
+        }
+    }
+
+    let mut muted = 0;
+    let mut failed = Vec::new();
+    for (id, producer) in producers {
+        let user = match users.get(&id).await {
+            Some(user) => user,
+            None => continue,
+        };
+        // Replaced or stopped since it was collected
+        let current = user
+            .read()
+            .await
+            .get_producer(produce_type)
+            .map(Producer::id);
+        if current != Some(producer.id()) {
+            continue;
+        }
+
+        match producer.pause().await {
+            Ok(()) => {
+                user.read()
+                    .await
+                    .announce_producer_paused(produce_type, true);
+                muted += 1;
+            }
+            Err(_) => failed.push(id),
+        }
+    }
+
+    tracing::info!(
+        muted,
+        failed = failed.len(),
+        ?produce_type,
+        "Muted the room"
+    );
+    audit::record(
+        AuditRecord::new(room.scoped_id(), AuditAction::MutedAll)
+            .actor(Some(user_id))
+            .details(json!({ "type": produce_type, "muted": muted, "failed": failed })),
+    );
+    Ok(WSReplyType::MuteAll { muted, failed })
+}
+
+/// Whether the user's producer of a type is paused, `false` if it has none
+async fn producer_paused(room: &Arc<Room>, user_id: &str, produce_type: ProduceType) -> bool {
+    match room.users().get(user_id).await {
+        Some(user) => user
+            .read()
+            .await
+            .get_producer(produce_type)
+            .map_or(false, Producer::paused),
+        None => false,
+    }
+}
+
+/// Swap the user's producer of a type for a new one. Other connections move their
+/// consumers over when told, the old producer is kept open for a while so they can
+/// do it before its consumers close. Nothing changes if the new producer fails
+async fn replace_produce<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    produce_type: ProduceType,
+    rtp_parameters: &RtpParameters,
+    opus: Option<OpusOptions>,
+) -> Result<WSReplyType, WSErrorType> {
+    let users = room.users();
+    {
+        let user = users
+            .get(user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(user_id.to_string()))?;
+        let user = user.read().await;
+        if user.constraints().listen_only || !user.role().can_produce() {
+            return Err(WSErrorType::ListenOnly);
+        }
+        if user.get_producer(produce_type).is_none() {
+            return Err(WSErrorType::ProducerNotFound(produce_type.to_string()));
+        }
+    }
+
+    check_scalability_modes(room, rtp_parameters)?;
+    let mut rtp_parameters = rtp_parameters.clone();
+    let max_bitrates = cap_bitrate(room, produce_type, &mut rtp_parameters)?;
+    let (producer, opus) = rtc_state
+        .start_produce(
+            produce_type,
+            rtp_parameters,
+            opus,
+            &room.settings().opus_limits,
+        )
+        .await?;
+    let producer_id = producer.id();
+
+    let (replaced, hidden) = {
+        let user = match users.get(user_id).await {
+            Some(user) => user,
+            None => {
+                rtc_state.release_producer(produce_type);
+                return Err(WSErrorType::UserNotFound(user_id.to_string()));
+            }
+        };
+        let mut user = user.write().await;
+        // Closed by the room in the meantime, along with its consumers
+        let replaced = match user.get_producer(produce_type) {
+            Some(replaced) => replaced.clone(),
+            None => {
+                rtc_state.release_producer(produce_type);
+                return Err(WSErrorType::ProducerNotFound(produce_type.to_string()));
+            }
+        };
+        if user.set_producer(produce_type, Some(producer)).is_err() {
+            rtc_state.release_producer(produce_type);
+            return Err(WSErrorType::ProducerFailure);
+        }
+        user.announce_replaced_producer(produce_type);
+        (replaced, user.hidden())
+    };
+    // Counted once for both producers from here on
+    rtc_state.release_producer(produce_type);
+    tokio::spawn(async move {
+        tokio::time::sleep(REPLACED_PRODUCER_GRACE).await;
+        drop(replaced);
+    });
+
+    if !hidden {
+        let source = TrackSource {
+            user_id: user_id.to_string(),
+            produce_type,
+            producer_id,
+        };
+        room.record_producer(source).await;
+        if produce_type == ProduceType::Audio {
+            room.observe_audio(user_id, producer_id).await;
+        }
+    }
+
+    // The echo consumer follows the new producer like any other consumer would
+    rtc_state.remove_consumers_of(user_id, Some(produce_type));
+    let echo = match produce_type {
+        ProduceType::Audio if room.settings().echo => {
+            start_echo(room, user_id, rtc_state, producer_id).await
+        }
+        _ => None,
+    };
+
+    Ok(WSReplyType::ReplaceProduce {
+        producer_id: producer_id.to_string(),
+        echo,
+        opus,
+        max_bitrates,
+    })
+}
+
+async fn start_consume<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    produce_type: ProduceType,
+    producer_user_id: &str,
+) -> Result<NewConsumer, WSErrorType> {
+    if producer_user_id == user_id && !room.settings().echo {
+        return Err(WSErrorType::OwnProducer);
+    }
+
+    let producer_id = if producer_user_id == SYSTEM_USER_ID {
+        // Audio played by the server, which isn't a user of the room
+        room.playback_producer()
+            .await
+            .filter(|_| produce_type == ProduceType::Audio)
+            .ok_or_else(|| WSErrorType::ProducerNotFound(produce_type.to_string()))?
+    } else {
+        let users = room.users();
+        let user = users
+            .get(producer_user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(producer_user_id.to_string()))?;
+        let user = user.read().await;
+        let producer = user
+            .get_producer(produce_type)
+            .ok_or_else(|| WSErrorType::ProducerNotFound(produce_type.to_string()))?;
+        producer.id()
+    };
+
+    let router = room.router().ok_or(WSErrorType::ConsumerFailure)?;
+    let kind = produce_type.into_kind();
+    let fast_join = room
+        .settings()
+        .fast_join
+        .filter(|settings| rtc_state.take_fast_join_slot(settings, kind));
+    let consumer = rtc_state
+        .start_consume(
+            &router,
+            producer_id,
+            producer_user_id,
+            produce_type,
+            fast_join.as_ref(),
+        )
+        .await
+        .map_err(|_| WSErrorType::ConsumerFailure)?;
+    // Audio played by the server has nobody to tell how many hear it, and a user hearing
+    // itself isn't an audience
+    if producer_user_id != SYSTEM_USER_ID && producer_user_id != user_id {
+        room.watch_consumer(&consumer, producer_user_id, produce_type);
+    }
+
+    if let Some(settings) = fast_join {
+        let interval = Duration::from_millis(settings.keyframe_interval);
+        if room.allow_key_frame_request(producer_id, interval) {
+            consumer.request_key_frame().await.ok();
+        }
+    }
+
+    let id = consumer.id().to_string();
+    let auto_layers = rtc_state.consumer_auto_layers(&id).unwrap_or(true);
+    let rtp_parameters = consumer.rtp_parameters().clone();
+    let scalability_mode = rtp_parameters
+        .encodings
+        .first()
+        .and_then(layers::scalability_mode);
+    Ok(NewConsumer {
+        id,
+        producer_id: producer_id.to_string(),
+        kind,
+        rtp_parameters,
+        scalability_mode,
+        paused: consumer.paused(),
+        priority: consumer.priority(),
+        auto_layers,
+    })
+}
+
+/// Consume each producer the user has, carrying on past those that fail
+async fn consume_user<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    producer_user_id: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    if producer_user_id == user_id && !room.settings().echo {
+        return Err(WSErrorType::OwnProducer);
+    }
+    let produce_types: Vec<ProduceType> = {
+        let users = room.users();
+        let user = users
+            .get(producer_user_id)
+            .await
+            .ok_or_else(|| WSErrorType::UserNotFound(producer_user_id.to_string()))?;
+        let user = user.read().await;
+        ProduceType::ALL
+            .iter()
+            .copied()
+            .filter(|produce_type| user.get_producer(*produce_type).is_some())
+            .collect()
+    };
+
+    let mut consumers = Vec::with_capacity(produce_types.len());
+    for produce_type in produce_types {
+        let result = start_consume(room, user_id, rtc_state, produce_type, producer_user_id).await;
+        let (consumer, error) = match result {
+            Ok(consumer) => (Some(consumer), None),
+            Err(error) => (None, Some(error.into())),
+        };
+        consumers.push(UserConsumer {
+            produce_type,
+            consumer,
+            error,
+        });
+    }
+    Ok(WSReplyType::ConsumeUser {
+        user_id: producer_user_id.to_string(),
+        consumers,
+    })
+}
+
+async fn set_consumer_pause<R: RtcSession>(
+    rtc_state: &R,
+    id: &str,
+    paused: bool,
+) -> Result<WSReplyType, WSErrorType> {
+    let consumer = rtc_state
+        .get_consumer(id)
+        .ok_or_else(|| WSErrorType::ConsumerNotFound(id.to_string()))?;
+    let result = match paused {
+        true => consumer.pause().await,
+        false => consumer.resume().await,
+    };
+
+    result
+        .map(|_| WSReplyType::SetConsumerPause)
+        .map_err(|_| WSErrorType::ConsumerFailure)
+}
+
+async fn resume_consumer<R: RtcSession>(
+    rtc_state: &mut R,
+    id: &str,
+) -> Result<WSReplyType, WSErrorType> {
+    let consumer = rtc_state
+        .get_consumer(id)
+        .cloned()
+        .ok_or_else(|| WSErrorType::ConsumerNotFound(id.to_string()))?;
+    let kind = consumer.kind();
+    // Resumed along with the others queued once the receiving transport connected
+    if rtc_state.defer_resume(id) {
+        return Ok(WSReplyType::ResumeConsumer);
+    }
+    consumer
+        .resume()
+        .await
+        .map_err(|_| WSErrorType::ConsumerFailure)?;
+
+    // The consumer plays either way, the keyframe only spares waiting for the next one
+    if kind == MediaKind::Video {
+        rtc_state.request_key_frame(id).await.ok();
+    }
+    Ok(WSReplyType::ResumeConsumer)
+}
+
+/// Priorities mediasoup accepts, 0 is reserved
+fn consumer_priority(priority: u32) -> Result<u8, WSErrorType> {
+    match u8::try_from(priority) {
+        Ok(valid @ 1..=255) => Ok(valid),
+        _ => Err(WSErrorType::InvalidConsumerPriority(priority)),
+    }
+}
+
+async fn set_consumer_priority<R: RtcSession>(
+    rtc_state: &R,
+    id: &str,
+    priority: u32,
+) -> Result<WSReplyType, WSErrorType> {
+    let priority = consumer_priority(priority)?;
+    match rtc_state.set_consumer_priority(id, priority).await {
+        Some(Ok(())) => Ok(WSReplyType::SetConsumerPriority),
+        Some(Err(())) => Err(WSErrorType::ConsumerFailure),
+        None => Err(WSErrorType::ConsumerNotFound(id.to_string())),
+    }
+}
+
+/// Note the user did something, telling the room if it was idle until then
+fn user_active(room: &Room, user_id: &str, idle: &mut Option<IdleMonitor>) {
+    if let Some(idle) = idle.as_mut() {
+        if idle.active(Instant::now()) {
+            room.send_event(RoomEvent::UserActive(user_id.to_string()));
+        }
+    }
+}
+
+/// Whether the connection's user has an unpaused audio producer
+async fn microphone_open(room: &Room, user_id: &str) -> bool {
+    own_producers(room, user_id)
+        .await
+        .iter()
+        .any(|(produce_type, producer, _)| {
+            *produce_type == ProduceType::Audio && !producer.paused()
+        })
+}
+
+/// Producers of the connection's user, for its stats and quality
+async fn own_producers(room: &Room, user_id: &str) -> Vec<(ProduceType, Producer, usize)> {
+    let users = room.users();
+    let user = match users.get(user_id).await {
+        Some(user) => user,
+        None => return Vec::new(),
+    };
+    let user = user.read().await;
+    ProduceType::ALL
+        .iter()
+        .filter_map(|produce_type| {
+            let producer = user.get_producer(*produce_type)?;
+            Some((
+                *produce_type,
+                producer.clone(),
+                room.audience(producer.id()),
+            ))
+        })
+        .collect()
+}
+
+fn layers_reply(
+    result: Result<(), LayersError>,
+    id: &str,
+    reply: WSReplyType,
+) -> Result<WSReplyType, WSErrorType> {
+    match result {
+        Ok(()) => Ok(reply),
+        Err(LayersError::ConsumerNotFound) => Err(WSErrorType::ConsumerNotFound(id.to_string())),
+        Err(LayersError::NotVideo) => Err(WSErrorType::NotVideoConsumer(id.to_string())),
+        Err(LayersError::Unavailable) => Err(WSErrorType::InvalidConsumerLayers(id.to_string())),
+        Err(LayersError::Failed) => Err(WSErrorType::ConsumerFailure),
+    }
+}
+
+/// Only events closing consumers wait for a command that has the media
+async fn handle_room_event<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    version: u32,
+    rtc_state: Option<&Mutex<R>>,
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    event: RoomEvent,
+) -> Result<(), WSCloseType> {
+    match (&event, rtc_state) {
+        (RoomEvent::UserLeft(id, _), Some(rtc_state)) if id != user_id => {
+            let consumers = rtc_state.lock().await.remove_consumers_of(id, None);
+            close_consumers(outbox, events, consumers, MediaClosedReason::UserLeft).await?;
+        }
+        (RoomEvent::RoomSettingsChanged(_), Some(rtc_state))
+        | (RoomEvent::RoomThrottled(_), Some(rtc_state)) => {
+            cap_layers(room, &mut *rtc_state.lock().await).await;
+        }
+        (RoomEvent::UserStopProduce(id, produce_type), Some(rtc_state)) => {
+            let consumers = rtc_state
+                .lock()
+                .await
+                .remove_consumers_of(id, Some(*produce_type));
+            close_consumers(
+                outbox,
+                events,
+                consumers,
+                MediaClosedReason::ProducerStopped,
+            )
+            .await?;
+        }
+        _ => (),
+    }
+
+    match event {
+        RoomEvent::UserJoined(id, info) => {
+            if id != user_id {
+                let event = WSEvent::UserJoined { id, info };
+                events.send_shared(outbox, event).await?;
+            }
+        }
+        RoomEvent::UserLeft(id, kicked_out) => {
+            if id == user_id {
+                return kicked(outbox, events, None).await;
+            }
+
+            let event = WSEvent::UserLeft {
+                id,
+                kicked: kicked_out,
+            };
+            events.send_shared(outbox, event).await?;
+        }
+        RoomEvent::UserStartProduce(id, produce_type, paused) => {
+            if id != user_id {
+                let event = WSEvent::UserStartProduce {
+                    id,
+                    produce_type,
+                    paused,
+                };
+                events.send_shared(outbox, event).await?;
+            }
+        }
+        // Sent to the user itself too, a moderator may have muted it
+        RoomEvent::UserProducerPauseChanged(id, produce_type, paused) => {
+            let event = WSEvent::UserProducerPauseChanged {
+                id,
+                produce_type,
+                paused,
+            };
+            events.send_shared(outbox, event).await?;
+        }
+        RoomEvent::UserStopProduce(id, produce_type) => {
+            if id != user_id {
+                let event = WSEvent::UserStopProduce { id, produce_type };
+                events.send_shared(outbox, event).await?;
+            }
+        }
+        RoomEvent::UserProducerReplaced(id, produce_type) => {
+            if id == user_id {
+                return Ok(());
+            }
+
+            // Clients before version 2 consume the new producer themselves, as if it
+            // was stopped and started again
+            if version < 2 {
+                if let Some(rtc_state) = rtc_state {
+                    let consumers = rtc_state
+                        .lock()
+                        .await
+                        .remove_consumers_of(&id, Some(produce_type));
+                    close_consumers(
+                        outbox,
+                        events,
+                        consumers,
+                        MediaClosedReason::ProducerStopped,
+                    )
+                    .await?;
+                }
+                let event = WSEvent::UserStopProduce {
+                    id: id.clone(),
+                    produce_type,
+                };
+                events.send(outbox, event).await?;
+                let paused = producer_paused(room, &id, produce_type).await;
+                let event = WSEvent::UserStartProduce {
+                    id,
+                    produce_type,
+                    paused,
+                };
+                events.send(outbox, event).await?;
+            } else {
+                let consumers = match rtc_state {
+                    Some(rtc_state) => {
+                        let mut rtc_state = rtc_state.lock().await;
+                        replace_consumers(
+                            room,
+                            user_id,
+                            &mut *rtc_state,
+                            outbox,
+                            events,
+                            &id,
+                            produce_type,
+                        )
+                        .await?
+                    }
+                    None => Vec::new(),
+                };
+                let event = WSEvent::UserProducerReplaced {
+                    id,
+                    produce_type,
+                    consumers,
+                };
+                events.send(outbox, event).await?;
+            }
+        }
+        // Only the producing user hears how many consume it
+        RoomEvent::ProducerAudience(id, produce_type, consumer_count) => {
+            if id == user_id {
+                let event = WSEvent::ProducerAudience {
+                    produce_type,
+                    consumer_count,
+                };
+                events.send(outbox, event).await?;
+            }
+        }
+        RoomEvent::UserInfoUpdated(id) => {
+            if id != user_id {
+                if let Some(info) = user_info(room, &id).await {
+                    let event = WSEvent::UserInfoUpdated { id, info };
+                    events.send(outbox, event).await?;
+                }
+            }
+        }
+        RoomEvent::Relay(from, payload) => {
+            if from != user_id {
+                let event = WSEvent::Relay { from, payload };
+                events.send(outbox, event).await?;
+            }
+        }
+        RoomEvent::RelayBlob(signal) => {
+            let event = match signal {
+                BlobSignal::Started {
+                    transfer_id,
+                    from,
+                    size,
+                    mime,
+                } => WSEvent::RelayBlobStarted {
+                    transfer_id,
+                    from,
+                    size,
+                    mime,
+                },
+                BlobSignal::Chunk {
+                    transfer_id,
+                    seq,
+                    data,
+                } => WSEvent::RelayBlobChunk {
+                    transfer_id,
+                    seq,
+                    data,
+                },
+                BlobSignal::Ended { transfer_id } => WSEvent::RelayBlobEnded { transfer_id },
+                BlobSignal::Failed { transfer_id } => WSEvent::RelayBlobFailed { transfer_id },
+            };
+            events.send(outbox, event).await?;
+        }
+        // Sent to the user itself too, its client has to know when it may speak
+        RoomEvent::UserRoleChanged(id, role) => {
+            let event = WSEvent::UserRoleChanged { id, role };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::OwnerChanged(owner) => {
+            let event = WSEvent::OwnerChanged { owner };
+            events.send(outbox, event).await?;
+        }
+        // Only moderators let users in
+        RoomEvent::UserWaiting(id) => {
+            if is_moderator(room, user_id).await.unwrap_or(false) {
+                events.send(outbox, WSEvent::UserWaiting { id }).await?;
+            }
+        }
+        RoomEvent::UserAdmitted(id) => {
+            if is_moderator(room, user_id).await.unwrap_or(false) {
+                events.send(outbox, WSEvent::UserAdmitted { id }).await?;
+            }
+        }
+        RoomEvent::UserReported(report) => {
+            let UserReport {
+                id,
+                reporter,
+                target,
+                reason,
+            } = report;
+            let event = WSEvent::UserReported {
+                id,
+                reporter,
+                target,
+                reason,
+            };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::QualityAlert(QualityAlert { counts, exceeded }) => {
+            let event = WSEvent::QualityAlert { counts, exceeded };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::RoomOpened => {
+            let event = WSEvent::RoomOpened {
+                users: room_users(room).await,
+            };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::UserConnectionStateChanged(id, state) => {
+            if id != user_id {
+                let event = WSEvent::UserConnectionStateChanged { id, state };
+                events.send(outbox, event).await?;
+            }
+        }
+        RoomEvent::UserQualityChanged(id, level) => {
+            let event = WSEvent::UserQualityChanged { id, level };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::UserIdle(id) => {
+            if id != user_id {
+                events.send(outbox, WSEvent::UserIdle { id }).await?;
+            }
+        }
+        RoomEvent::UserActive(id) => {
+            if id != user_id {
+                events.send(outbox, WSEvent::UserActive { id }).await?;
+            }
+        }
+        RoomEvent::ActiveSpeakers(speakers) => {
+            let event = WSEvent::ActiveSpeakers { speakers };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::Positions(users) => {
+            let event = WSEvent::Positions { users };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::RecordingStateChanged(recording) => {
+            let event = WSEvent::RecordingStateChanged { recording };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::RecordingConsentRequested(users) => {
+            if users.iter().any(|id| id == user_id) {
+                events
+                    .send(outbox, WSEvent::RecordingConsentRequested)
+                    .await?;
+            }
+        }
+        RoomEvent::RoomSettingsChanged(settings) => {
+            let quality = settings
+                .quality_profile
+                .as_ref()
+                .and_then(|name| CONFIG.rooms.profiles.get(name))
+                .cloned();
+            let event = WSEvent::RoomSettingsChanged {
+                video_allowed: settings.producer_limits.video > 0,
+                quality,
+                settings,
+            };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::RoomMetadataChanged(key, value) => {
+            let event = WSEvent::RoomMetadataChanged { key, value };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::RoomThrottled(level) => {
+            let event = WSEvent::RoomThrottled { level };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::BroadcastStateChanged(state) => {
+            let event = WSEvent::BroadcastStateChanged { state };
+            events.send(outbox, event).await?;
+        }
+        RoomEvent::MigrateTo(url, token) => {
+            events
+                .send(outbox, WSEvent::MigrateTo { url, token })
+                .await?;
+        }
+        RoomEvent::CallSummary(summary) => {
+            events.send(outbox, WSEvent::CallSummary(summary)).await?;
+        }
+        RoomEvent::RoomDelete(summary) => return room_closed(outbox, events, summary).await,
+    }
+
+    Ok(())
+}
+
+/// Move the connection's consumers of another user's producer to its replacement,
+/// keeping whether they were paused and their priority. Those that can't be moved are
+/// closed and the client told so
+async fn replace_consumers<R: RtcSession>(
+    room: &Arc<Room>,
+    user_id: &str,
+    rtc_state: &mut R,
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    producer_user_id: &str,
+    produce_type: ProduceType,
+) -> Result<Vec<ReplacedConsumer>, WSCloseType> {
+    let mut replaced = Vec::new();
+    let mut closed = Vec::new();
+    for old in rtc_state.remove_consumers_of(producer_user_id, Some(produce_type)) {
+        let reply = start_consume(room, user_id, rtc_state, produce_type, producer_user_id).await;
+        let NewConsumer {
+            id,
+            producer_id,
+            kind,
+            rtp_parameters,
+            scalability_mode,
+            ..
+        } = match reply {
+            Ok(consumer) => consumer,
+            Err(_) => {
+                closed.push(old);
+                continue;
+            }
+        };
+
+        let consumer = match rtc_state.get_consumer(&id) {
+            Some(consumer) => consumer,
+            None => {
+                closed.push(old);
+                continue;
+            }
+        };
+        if consumer.paused() != old.paused() {
+            let result = match old.paused() {
+                true => consumer.pause().await,
+                false => consumer.resume().await,
+            };
+            if result.is_err() {
+                tracing::debug!(consumer_id = %id, "Failed to carry the pause over");
+            }
+        }
+        if consumer.set_priority(old.priority()).await.is_err() {
+            tracing::debug!(consumer_id = %id, "Failed to carry the priority over");
+        }
+
+        replaced.push(ReplacedConsumer {
+            replaces: old.id().to_string(),
+            id,
+            producer_id,
+            kind,
+            rtp_parameters,
+            scalability_mode,
+            paused: consumer.paused(),
+            priority: consumer.priority(),
+        });
+    }
+
+    close_consumers(outbox, events, closed, MediaClosedReason::ProducerStopped).await?;
+    Ok(replaced)
+}
+
+/// Tell the client about consumers the server closed on its behalf
+async fn close_consumers(
+    outbox: &Outbox,
+    events: &mut EventSequence,
+    consumers: Vec<Consumer>,
+    reason: MediaClosedReason,
+) -> Result<(), WSCloseType> {
+    for consumer in consumers {
+        let event = WSEvent::ConsumerClosed {
+            id: consumer.id().to_string(),
+            producer_id: consumer.producer_id().to_string(),
+            reason,
+        };
+        events.send(outbox, event).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiError;
+    use crate::rtc::mock::{self, MockSession};
+    use crate::state::room::settings::BitrateLimits;
+    use crate::state::room::token::JoinClaims;
+    use crate::state::room::{OwnerLeavePolicy, RoomSettings};
+    use crate::state::user::UserOptions;
+    use crate::util::config::{JwtConfig, CONFIG};
+    use crate::util::metrics::LOAD_SHED_DECISIONS;
+    use crate::util::testing;
+    use auth::{LocalAuthenticator, AUTHENTICATOR};
+    use chrono::DateTime;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use jwt::JwtVerifier;
+    use warp::test::WsClient;
+
+    #[test]
+    fn consumer_priority_range() {
+        assert_eq!(consumer_priority(1).unwrap(), 1);
+        assert_eq!(consumer_priority(255).unwrap(), 255);
+        for invalid in [0, 256, u32::MAX].iter() {
+            match consumer_priority(*invalid) {
+                Err(WSErrorType::InvalidConsumerPriority(priority)) => {
+                    assert_eq!(priority, *invalid)
+                }
+                _ => panic!("Expected {} to be rejected", invalid),
+            }
+        }
+    }
+
+    #[test]
+    fn frames_carry_one_or_more_commands() {
+        let ids = |commands: Vec<WSCommand>| -> Vec<Option<String>> {
+            commands.into_iter().map(|command| command.id).collect()
+        };
+
+        let (batch_id, commands) =
+            frame_commands(r#"{ "id": "1", "type": "RoomInfo" }"#, Instant::now()).unwrap();
+        assert_eq!(
+            (batch_id, ids(commands)),
+            (None, vec![Some("1".to_string())])
+        );
+
+        let frame =
+            r#"[{ "id": "1", "type": "RoomInfo" }, { "id": "2", "type": "StopRecording" }]"#;
+        let (batch_id, commands) = frame_commands(frame, Instant::now()).unwrap();
+        assert_eq!(batch_id, None);
+        assert_eq!(
+            ids(commands),
+            vec![Some("1".to_string()), Some("2".to_string())]
+        );
+
+        let frame = r#"{
+            "id": "batch",
+            "type": "Batch",
+            "data": { "commands": [{ "id": "1", "type": "RoomInfo" }] }
+        }"#;
+        let (batch_id, commands) = frame_commands(frame, Instant::now()).unwrap();
+        assert_eq!(batch_id.as_deref(), Some("batch"));
+        assert_eq!(ids(commands), vec![Some("1".to_string())]);
+
+        assert!(frame_commands(r#"[{ "type": "Unknown" }]"#, Instant::now()).is_err());
+    }
+
+    #[test]
+    fn binary_frames_carry_json() {
+        let command = r#"{ "id": "1", "type": "RoomInfo" }"#;
+        let text = Message::text(command);
+        assert_eq!(frame_text(&text).unwrap(), Some(command));
+        let binary = Message::binary(command.as_bytes());
+        assert_eq!(frame_text(&binary).unwrap(), Some(command));
+
+        assert_eq!(frame_text(&Message::ping(vec![1])).unwrap(), None);
+        assert_eq!(frame_text(&Message::close()).unwrap(), None);
+        match frame_text(&Message::binary(vec![0xff, 0xfe])) {
+            Err(close @ WSCloseType::InvalidData(_)) => assert_eq!(close.code(), 1003),
+            _ => panic!("Expected binary that isn't UTF-8 to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_clients_miss_the_handshake() {
+        let mut ws_stream: WSStream = Box::pin(futures::stream::pending());
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(matches!(
+            handshake_frame(&mut ws_stream, Some(deadline)).await,
+            Err(WSCloseType::HandshakeTimeout)
+        ));
+
+        // Frames in time go through
+        let frames = vec![Ok::<_, WSCloseType>(Message::text("{}"))];
+        let mut ws_stream: WSStream = Box::pin(futures::stream::iter(frames));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let message = handshake_frame(&mut ws_stream, Some(deadline))
+            .await
+            .unwrap();
+        assert_eq!(message.unwrap().to_str(), Ok("{}"));
+        assert!(handshake_frame(&mut ws_stream, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn batches_continue_past_failures() {
+        let (outbox, mut sent) = Outbox::channel();
+        let room = testing::room(RoomSettings::default()).await;
+        let init_data = serde_json::from_value(json!({
+            "rtpCapabilities": { "codecs": [], "headerExtensions": [] },
+            "mode": "SplitWebRtc",
+        }))
+        .unwrap();
+        let mut rtc_state = RtcState::initialize(&room.router().unwrap(), init_data)
+            .await
+            .unwrap();
+
+        let frame = r#"[
+            { "id": "1", "type": "StopConsume", "data": { "id": "missing" } },
+            { "id": "2", "type": "Batch", "data": { "commands": [] } },
+            { "id": "3", "type": "StopProduce", "data": { "produceType": "audio" } },
+            { "id": "4", "type": "RequestKeyFrame", "data": { "consumerId": "missing" } },
+            { "id": "5", "type": "FutureFeature", "data": { "enabled": true } }
+        ]"#;
+        let (_, commands) = frame_commands(frame, Instant::now()).unwrap();
+        let events = EventSequence::default();
+        for out in commands {
+            handle_command(&room, "user", &mut rtc_state, &outbox, &events, out, true)
+                .await
+                .unwrap();
+        }
+
+        drop(outbox);
+        let mut replies = Vec::new();
+        let mut requests = Vec::new();
+        while let Some(Outgoing { message, .. }) = sent.recv().await {
+            let reply: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+            replies.push((reply["id"].clone(), reply["code"].clone()));
+            requests.push(reply["request"].clone());
+        }
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0], (json!("1"), json!(4001)));
+        // Errors echo the data of their command, the connection asked for it
+        assert_eq!(requests[0], json!({ "id": "missing" }));
+        assert_eq!(replies[1], (json!("2"), json!(7001)));
+        assert_eq!(replies[2].0, json!("3"));
+        assert_eq!(replies[3], (json!("4"), json!(4001)));
+        // Commands of newer clients are refused without closing the connection
+        assert_eq!(replies[4], (json!("5"), json!(7004)));
+        // Failures are counted by command and error
+        let errors = COMMAND_ERRORS.with_label_values(&["StopConsume", "ConsumerNotFound"]);
+        assert!(errors.get() >= 1);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn own_media_is_consumed_in_echo_rooms_only() {
+        for echo in [false, true].iter() {
+            let settings = RoomSettings {
+                echo: *echo,
+                ..RoomSettings::default()
+            };
+            let room = testing::room(settings).await;
+            let init_data = serde_json::from_value(json!({
+                "rtpCapabilities": { "codecs": [], "headerExtensions": [] },
+            }))
+            .unwrap();
+            let mut rtc_state = RtcState::initialize(&room.router().unwrap(), init_data)
+                .await
+                .unwrap();
+
+            let result =
+                start_consume(&room, "user", &mut rtc_state, ProduceType::Audio, "user").await;
+            match (echo, result) {
+                (false, Err(error @ WSErrorType::OwnProducer)) => assert_eq!(error.code(), 4003),
+                // Allowed through, the user just doesn't exist
+                (true, Err(WSErrorType::UserNotFound(_))) => (),
+                _ => panic!("Unexpected result with echo {}", echo),
+            }
+            room.delete().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn moderators_let_listeners_speak() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        for (id, role) in [("host", Role::Moderator), ("guest", Role::Listener)].iter() {
+            let options = UserOptions {
+                role: *role,
+                ..UserOptions::default()
+            };
+            let token = users.create(id.to_string(), options).await.unwrap().token;
+            users.register(&token, rand::random()).await.unwrap();
+        }
+        drop(users);
+
+        let init_data = serde_json::from_value(json!({
+            "rtpCapabilities": { "codecs": [], "headerExtensions": [] },
+        }))
+        .unwrap();
+        let mut rtc_state = RtcState::initialize(&room.router().unwrap(), init_data)
+            .await
+            .unwrap();
+        let parameters = RtpParameters::default();
+        let result = start_produce(
+            &room,
+            "guest",
+            &mut rtc_state,
+            ProduceType::Audio,
+            &parameters,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(WSErrorType::ListenOnly)));
+
+        let result = set_role(&room, "guest", "host", Role::Listener).await;
+        assert!(matches!(result, Err(WSErrorType::MissingPermission(_))));
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "observer", options).unwrap();
+        let result = set_role(&room, "host", "guest", Role::Speaker).await;
+        assert!(matches!(result, Ok(WSReplyType::PromoteUser)));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserRoleChanged(id, Role::Speaker)) => {
+                assert_eq!(id, "guest")
+            }
+            _ => panic!("Expected the role change"),
+        }
+
+        let guest = room.users().get("guest").await.unwrap();
+        assert_eq!(guest.read().await.role(), Role::Speaker);
+        drop((guest, subscriber));
+        room.delete().await;
+    }
+
+    async fn registered(room: &Arc<Room>, ids: &[&str]) {
+        let users = room.users();
+        for id in ids {
+            let token = users
+                .create(id.to_string(), UserOptions::default())
+                .await
+                .unwrap()
+                .token;
+            users.register(&token, rand::random()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn owners_moderate_and_hand_the_room_over() {
+        let settings = RoomSettings {
+            owner: Some("host".to_string()),
+            owner_leave_policy: OwnerLeavePolicy::Transfer,
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        registered(&room, &["host", "guest", "late"]).await;
+
+        // Speakers all, but the owner may moderate
+        let result = set_role(&room, "host", "guest", Role::Listener).await;
+        assert!(matches!(result, Ok(WSReplyType::DemoteUser)));
+        let result = set_owner(&room, "guest", "guest").await;
+        assert!(matches!(result, Err(WSErrorType::MissingPermission(_))));
+        assert_eq!(room.metadata().await.owner.as_deref(), Some("host"));
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "observer", options).unwrap();
+        let result = set_owner(&room, "host", "late").await;
+        assert!(matches!(result, Ok(WSReplyType::SetOwner)));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::OwnerChanged(owner)) => {
+                assert_eq!(owner.as_deref(), Some("late"))
+            }
+            _ => panic!("Expected the owner change"),
+        }
+        let host = room.users().get("host").await.unwrap();
+        assert!(!host.read().await.has_permission(Permission::Moderator));
+        drop(host);
+
+        // The user there the longest takes over from an owner that left
+        room.users().remove("late").await.unwrap();
+        loop {
+            match subscriber.recv().await {
+                SubscriberMessage::Event(RoomEvent::OwnerChanged(owner)) => {
+                    assert_eq!(owner.as_deref(), Some("host"));
+                    break;
+                }
+                SubscriberMessage::Event(RoomEvent::UserLeft(..)) => continue,
+                _ => panic!("Expected the owner change"),
+            }
+        }
+        drop(subscriber);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn rooms_close_when_their_owner_leaves() {
+        let settings = RoomSettings {
+            owner: Some("host".to_string()),
+            owner_leave_policy: OwnerLeavePolicy::CloseOnOwnerLeave,
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        registered(&room, &["host", "guest"]).await;
+
+        room.users().remove("guest").await.unwrap();
+        assert!(!room.closed());
+        room.users().remove("host").await.unwrap();
+        for _ in 0..50 {
+            if room.closed() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(room.closed());
+    }
+
+    #[tokio::test]
+    async fn talk_stats_are_for_moderators() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        for (id, role) in [("host", Role::Moderator), ("guest", Role::Speaker)].iter() {
+            let options = UserOptions {
+                role: *role,
+                ..UserOptions::default()
+            };
+            users.create(id.to_string(), options).await.unwrap();
+        }
+
+        let result = talk_stats(&room, "guest").await;
+        assert!(matches!(result, Err(WSErrorType::MissingPermission(_))));
+        match talk_stats(&room, "host").await {
+            Ok(WSReplyType::GetTalkStats { users }) => assert!(users.is_empty()),
+            _ => panic!("Expected the talk stats"),
+        }
+        drop(users);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn recent_events_are_for_moderators_when_kept() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        for (id, role) in [("host", Role::Moderator), ("guest", Role::Speaker)].iter() {
+            let options = UserOptions {
+                role: *role,
+                ..UserOptions::default()
+            };
+            users.create(id.to_string(), options).await.unwrap();
+        }
+
+        let result = recent_events(&room, "guest", 60).await;
+        assert!(matches!(result, Err(WSErrorType::MissingPermission(_))));
+        // Tests run without `event_replay`, rooms keep nothing
+        let result = recent_events(&room, "host", 60).await;
+        assert!(matches!(result, Err(WSErrorType::RecentEventsUnavailable)));
+        drop(users);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn moderators_update_room_settings() {
+        let settings = RoomSettings {
+            max_users: Some(4),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let users = room.users();
+        for (id, role) in [("host", Role::Moderator), ("guest", Role::Speaker)].iter() {
+            let options = UserOptions {
+                role: *role,
+                ..UserOptions::default()
+            };
+            users.create(id.to_string(), options).await.unwrap();
+        }
+
+        let update: RoomSettingsUpdate = serde_json::from_value(json!({
+            "maxUsers": 1,
+            "videoAllowed": false,
+            "recordingAllowed": false,
+        }))
+        .unwrap();
+        let result = update_room_settings(&room, "guest", update.clone()).await;
+        assert!(matches!(result, Err(WSErrorType::MissingPermission(_))));
+        assert_eq!(room.max_users(), Some(4));
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "observer", options).unwrap();
+        let result = update_room_settings(&room, "host", update).await;
+        assert!(matches!(result, Ok(WSReplyType::UpdateRoomSettings)));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::RoomSettingsChanged(settings)) => {
+                assert_eq!(settings.max_users, Some(1));
+                assert_eq!(settings.producer_limits.video, 0);
+                assert!(!settings.recording_allowed);
+            }
+            _ => panic!("Expected the settings change"),
+        }
+
+        // Nobody is kicked, but nobody else gets in
+        for id in ["host", "guest"].iter() {
+            assert!(users.get(id).await.is_some());
+        }
+        let result = users
+            .create("late".to_string(), UserOptions::default())
+            .await;
+        assert!(matches!(result, Err(ApiError::RoomFull(_))));
+        assert!(!room.metadata().await.video_allowed());
+        drop((users, subscriber));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn feature_flag_changes_reach_connected_clients() {
+        let settings: RoomSettings =
+            serde_json::from_value(json!({ "featureFlags": { "reactions": true } })).unwrap();
+        let room = testing::room(settings).await;
+        let options = UserOptions {
+            role: Role::Moderator,
+            ..UserOptions::default()
+        };
+        room.users()
+            .create("host".to_string(), options)
+            .await
+            .unwrap();
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "observer", options).unwrap();
+        let update: RoomSettingsUpdate =
+            serde_json::from_value(json!({ "featureFlags": { "whiteboard": true } })).unwrap();
+        let result = update_room_settings(&room, "host", update).await;
+        assert!(matches!(result, Ok(WSReplyType::UpdateRoomSettings)));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::RoomSettingsChanged(settings)) => {
+                assert_eq!(settings.feature_flags.len(), 1);
+                assert_eq!(settings.feature_flags.get("whiteboard"), Some(&true));
+            }
+            _ => panic!("Expected the settings change"),
+        }
+
+        // Clients fetching the room later see the same flags
+        match room_info(&room, None).await {
+            Ok(WSReplyType::RoomInfo { snapshot, .. }) => {
+                assert_eq!(snapshot.feature_flags, room.feature_flags());
+                assert!(!snapshot.feature_flags.contains_key("reactions"));
+            }
+            _ => panic!("Expected the room's info"),
+        }
+        drop(subscriber);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn relayed_messages_are_limited() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        let token = users
+            .create("alice".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        users.register(&token, 1).await.unwrap();
+        drop(users);
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(2, "bob", options).unwrap();
+        let payload = json!({ "reaction": "wave" });
+        let reply = relay_broadcast(&room, "alice", &payload).await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayBroadcast)));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::Relay(from, relayed)) => {
+                assert_eq!((from.as_str(), relayed), ("alice", payload.clone()))
+            }
+            _ => panic!("Expected the relayed message"),
+        }
+
+        let large = json!({ "text": "a".repeat(CONFIG.signaling.max_relay_payload) });
+        let reply = relay_broadcast(&room, "alice", &large).await;
+        assert!(matches!(reply, Err(WSErrorType::RelayTooLarge(_))));
+
+        // Acknowledged, but the next thing the room sees is the join
+        room.set_relay_muted("alice", true);
+        let reply = relay_broadcast(&room, "alice", &payload).await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayBroadcast)));
+        room.send_event(RoomEvent::UserJoined(
+            "carol".to_string(),
+            UserInfo::default(),
+        ));
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserJoined(id, _)) => assert_eq!(id, "carol"),
+            _ => panic!("Expected the muted message to be dropped"),
+        }
+
+        let mut limited = false;
+        for _ in 0..20 {
+            if let Err(WSErrorType::TooManyRequests) =
+                relay_broadcast(&room, "alice", &payload).await
+            {
+                limited = true;
+                break;
+            }
+        }
+        assert!(limited);
+        drop(subscriber);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn direct_relay_reaches_one_connection() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        for (id, connection_id) in [("alice", 1), ("bob", 2)].iter() {
+            let token = users
+                .create(id.to_string(), UserOptions::default())
+                .await
+                .unwrap()
+                .token;
+            users.register(&token, *connection_id).await.unwrap();
+        }
+        drop(users);
+
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut bob = room.subscribe(2, "bob", options).unwrap();
+        let payload = json!({ "key": "material" });
+        let reply = relay_direct(&room, "alice", "bob", &payload).await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayDirect)));
+        match bob.recv().await {
+            SubscriberMessage::Event(RoomEvent::Relay(from, relayed)) => {
+                assert_eq!((from.as_str(), relayed), ("alice", payload.clone()))
+            }
+            _ => panic!("Expected the relayed message"),
+        }
+
+        let reply = relay_direct(&room, "alice", "nobody", &payload).await;
+        assert!(matches!(reply, Err(WSErrorType::UserNotFound(id)) if id == "nobody"));
+        drop(bob);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn blobs_stream_to_their_recipient() {
+        let room = testing::room(RoomSettings::default()).await;
+        let users = room.users();
+        for (id, connection_id) in [("alice", 1), ("bob", 2)].iter() {
+            let token = users
+                .create(id.to_string(), UserOptions::default())
+                .await
+                .unwrap()
+                .token;
+            users.register(&token, *connection_id).await.unwrap();
+        }
+        drop(users);
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let alice = room.subscribe(1, "alice", options).unwrap();
+        let mut bob = room.subscribe(2, "bob", options).unwrap();
+        async fn next_blob(subscriber: &mut RoomSubscriber) -> BlobSignal {
+            match subscriber.recv().await {
+                SubscriberMessage::Event(RoomEvent::RelayBlob(signal)) => signal,
+                _ => panic!("Expected part of the blob"),
+            }
+        }
+
+        let reply = relay_blob_start(&room, "alice", Some("bob"), 6, "text/plain").await;
+        let transfer_id = match reply {
+            Ok(WSReplyType::RelayBlobStart { transfer_id }) => transfer_id,
+            _ => panic!("Expected the transfer to start"),
+        };
+        assert!(matches!(
+            next_blob(&mut bob).await,
+            BlobSignal::Started { size: 6, .. }
+        ));
+
+        let reply = relay_blob_chunk(&room, "alice", &transfer_id, 0, "not base64!").await;
+        assert!(matches!(reply, Err(WSErrorType::InvalidBlobChunk("data"))));
+        let reply = relay_blob_chunk(&room, "bob", &transfer_id, 0, "aGVsbG8h").await;
+        assert!(matches!(reply, Err(WSErrorType::BlobTransferNotFound(_))));
+        let reply = relay_blob_chunk(&room, "alice", &transfer_id, 0, "aGVsbG8h").await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayBlobChunk)));
+        match next_blob(&mut bob).await {
+            BlobSignal::Chunk { seq, data, .. } => {
+                assert_eq!((seq, data.as_str()), (0, "aGVsbG8h"))
+            }
+            _ => panic!("Expected the chunk"),
+        }
+        let reply = relay_blob_end(&room, "alice", &transfer_id).await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayBlobEnd)));
+        assert!(matches!(
+            next_blob(&mut bob).await,
+            BlobSignal::Ended { .. }
+        ));
+
+        // Dropped along with the sender's connection
+        let reply = relay_blob_start(&room, "alice", None, 6, "text/plain").await;
+        assert!(matches!(reply, Ok(WSReplyType::RelayBlobStart { .. })));
+        assert!(matches!(
+            next_blob(&mut bob).await,
+            BlobSignal::Started { .. }
+        ));
+        drop(alice);
+        assert!(matches!(
+            next_blob(&mut bob).await,
+            BlobSignal::Failed { .. }
+        ));
+        drop(bob);
+        room.delete().await;
+    }
+
+    fn room_info_reply(reply: Result<WSReplyType, WSErrorType>) -> (usize, bool) {
+        match reply {
+            Ok(WSReplyType::RoomInfo {
+                snapshot, stale, ..
+            }) => (snapshot.users.len(), stale),
+            _ => panic!("Expected room info"),
+        }
+    }
+
+    #[tokio::test]
+    async fn room_info_deltas_list_changes_since() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        registered(&room, &["alice", "bob"]).await;
+        let page = RoomInfoPage {
+            offset: 0,
+            limit: 1,
+        };
+        let revision = match room_info(&room, Some(page)).await {
+            Ok(WSReplyType::RoomInfo {
+                snapshot,
+                revision,
+                next_offset,
+                ..
+            }) => {
+                assert!(snapshot.users.contains_key("alice"));
+                assert_eq!(next_offset, Some(1));
+                revision
+            }
+            _ => panic!("Expected room info"),
+        };
+
+        registered(&room, &["carol"]).await;
+        room.users().remove("bob").await.unwrap();
+        let latest = match room_info_delta(&room, revision).await {
+            WSReplyType::RoomInfoDelta {
+                revision: latest,
+                reset,
+                users,
+                removed,
+            } => {
+                assert!(!reset);
+                assert_eq!(users.keys().collect::<Vec<_>>(), ["carol"]);
+                assert_eq!(removed, ["bob"]);
+                latest
+            }
+            _ => panic!("Expected a room info delta"),
+        };
+
+        match room_info_delta(&room, latest).await {
+            WSReplyType::RoomInfoDelta {
+                reset,
+                users,
+                removed,
+                ..
+            } => assert!(!reset && users.is_empty() && removed.is_empty()),
+            _ => panic!("Expected a room info delta"),
+        }
+
+        // A revision the room never had lists everyone
+        match room_info_delta(&room, latest + 100).await {
+            WSReplyType::RoomInfoDelta { reset, users, .. } => {
+                assert!(reset);
+                assert_eq!(users.len(), 2);
+            }
+            _ => panic!("Expected a room info delta"),
+        }
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn room_info_carries_metadata() {
+        // Another test may be holding the node under simulated load
+        let _serial = testing::serial();
+        let settings = RoomSettings {
+            max_users: Some(4),
+            producer_limits: serde_json::from_str(r#"{ "video": 0 }"#).unwrap(),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let reply = serde_json::to_value(room_info(&room, None).await.unwrap()).unwrap();
+
+        assert_eq!(reply["videoAllowed"], false);
+        assert_eq!(reply["settings"]["maxUsers"], 4);
+        assert_eq!(reply["settings"]["persistent"], false);
+        for field in ["createdAt", "lastActivity"].iter() {
+            let time = reply[field].as_str().unwrap();
+            assert!(DateTime::parse_from_rfc3339(time).is_ok());
+        }
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn room_info_sheds_load() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let (soft, hard) = {
+            let thresholds = CONFIG.load_shedding.clone();
+            (thresholds.soft_cpu, thresholds.hard_cpu)
+        };
+        let shed = |decision: &str| {
+            LOAD_SHED_DECISIONS
+                .with_label_values(&["RoomInfo", decision])
+                .get()
+        };
+        let (cached, rejected) = (shed("cached"), shed("rejected"));
+
+        // Fresh listings are cached for later
+        load::set_cpu_usage(0.0);
+        assert_eq!(room_info_reply(room_info(&room, None).await), (0, false));
+
+        load::set_cpu_usage(soft);
+        assert_eq!(room_info_reply(room_info(&room, None).await), (0, true));
+        assert_eq!(shed("cached"), cached + 1);
+
+        load::set_cpu_usage(hard);
+        match room_info(&room, None).await {
+            Err(error @ WSErrorType::Overloaded { .. }) => assert_eq!(error.code(), 5000),
+            _ => panic!("Expected the command to be rejected"),
+        }
+        assert_eq!(shed("rejected"), rejected + 1);
+
+        load::clear_cpu_usage();
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber_is_resynced() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let mut subscriber = room.subscribe(1, "slow", options).unwrap();
+
+        // Overflow the room's broadcast channel, which holds 32 events
+        for i in 0..40 {
+            room.send_event(RoomEvent::UserJoined(
+                format!("user-{}", i),
+                UserInfo::default(),
+            ));
+        }
+
+        let missed = match subscriber.recv().await {
+            SubscriberMessage::Lagged(missed) => missed,
+            _ => panic!("Expected the subscriber to lag"),
+        };
+        assert_eq!(missed, 8);
+
+        let (outbox, mut receiver) = Outbox::channel();
+        let mut events = EventSequence::default();
+        assert!(resync(&room, &outbox, &mut events, missed).await.is_ok());
+        let message = receiver.recv().await.unwrap().message;
+        let event: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        assert_eq!(event["type"], "resync");
+        assert_eq!(event["seq"], 1);
+        assert_eq!(event["data"]["missed"], 8);
+
+        // The subscriber keeps receiving the events still buffered
+        match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserJoined(id, _)) => assert_eq!(id, "user-8"),
+            _ => panic!("Expected the oldest buffered event"),
+        }
+        assert_eq!(room.subscriber_count(), 1);
+        drop(subscriber);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn sync_state_reports_the_last_event() {
+        let room = testing::room(RoomSettings::default()).await;
+        let (outbox, mut receiver) = Outbox::channel();
+        let mut events = EventSequence::default();
+
+        let reply = serde_json::to_value(sync_state(&room, &events).await).unwrap();
+        assert_eq!(reply["type"], "syncState");
+        assert_eq!(reply["data"]["seq"], 0);
+
+        for recording in [true, false].iter() {
+            let event = WSEvent::RecordingStateChanged {
+                recording: *recording,
+            };
+            events.send(&outbox, event).await.unwrap();
+        }
+        drop(outbox);
+        let mut sent: Vec<serde_json::Value> = Vec::new();
+        while let Some(Outgoing { message, .. }) = receiver.recv().await {
+            sent.push(serde_json::from_str(message.to_str().unwrap()).unwrap());
+        }
+        let seqs: Vec<_> = sent.iter().map(|event| event["seq"].clone()).collect();
+        assert_eq!(seqs, [json!(1), json!(2)]);
+
+        // Same snapshot as RoomInfo, along with the sequence number
+        let reply = serde_json::to_value(sync_state(&room, &events).await).unwrap();
+        assert_eq!(reply["data"]["seq"], 2);
+        assert_eq!(reply["data"]["id"], room.id());
+        assert!(reply["data"]["users"].is_object());
+        assert!(reply["data"]["settings"].is_object());
+        room.delete().await;
+    }
+
+    /// Deliver the room deletion to a connection, returning what it sent
+    async fn close_connection(subscriber: &mut RoomSubscriber) -> (Vec<String>, WSCloseType) {
+        let summary = match subscriber.recv().await {
+            SubscriberMessage::Event(RoomEvent::RoomDelete(summary)) => summary,
+            _ => panic!("Expected the room deletion first"),
+        };
+
+        let (outbox, mut receiver) = Outbox::channel();
+        let mut events = EventSequence::default();
+        let close = room_closed(&outbox, &mut events, summary)
+            .await
+            .unwrap_err();
+        drop(outbox);
+        let mut sent = Vec::new();
+        while let Some(Outgoing { message, .. }) = receiver.recv().await {
+            sent.push(message.to_str().unwrap().to_string());
+        }
+        (sent, close)
+    }
+
+    #[tokio::test]
+    async fn summary_is_the_last_message() {
+        let _serial = testing::serial();
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+
+        // Deleted directly, then over the API
+        let room = testing::room(RoomSettings::default()).await;
+        let mut subscriber = room.subscribe(1, "direct", options).unwrap();
+        room.delete().await;
+        let direct = close_connection(&mut subscriber).await;
+
+        let room = testing::room(RoomSettings::default()).await;
+        let mut subscriber = room.subscribe(2, "api", options).unwrap();
+        let response = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/{}", room.id()))
+            .reply(&crate::api::room::route())
+            .await;
+        assert_eq!(response.status(), 204);
+        let api = close_connection(&mut subscriber).await;
+
+        for (sent, close) in vec![direct, api] {
+            assert_eq!(sent.len(), 1);
+            let event: serde_json::Value = serde_json::from_str(&sent[0]).unwrap();
+            assert_eq!(event["type"], "roomSummary");
+            assert!(event["data"]["durationSecs"].is_u64());
+            assert!(matches!(close, WSCloseType::RoomClosed));
+        }
+    }
+
+    /// Stands in for an RTC call choking on unexpected mediasoup output
+    async fn panicking_rtc_call() -> Result<(), WSCloseType> {
+        panic!("Unexpected mediasoup output");
+    }
+
+    #[tokio::test]
+    async fn panic_only_closes_its_own_connection() {
+        let _serial = testing::serial();
+        let room = testing::room(RoomSettings::default()).await;
+        let options = SubscriberOptions {
+            signaling: SignalingTransport::WebSocket,
+        };
+        let failing = room.subscribe(1, "failing", options).unwrap();
+        let mut other = room.subscribe(2, "other", options).unwrap();
+
+        let panics = COMMAND_PANICS.with_label_values(&["TestPanic"]);
+        let before = panics.get();
+        let result = isolate("TestPanic", || "{}".to_string(), panicking_rtc_call()).await;
+        assert!(matches!(result, Err(WSCloseType::ServerError)));
+        assert_eq!(panics.get(), before + 1);
+
+        // The failing connection closes, the rest of the room carries on
+        drop(failing);
+        room.send_event(RoomEvent::UserJoined(
+            "late".to_string(),
+            UserInfo::default(),
+        ));
+        match other.recv().await {
+            SubscriberMessage::Event(RoomEvent::UserJoined(id, _)) => assert_eq!(id, "late"),
+            _ => panic!("Expected the join event"),
+        }
+        assert_eq!(room.subscriber_count(), 1);
+        room.delete().await;
+    }
+
+    /// Transports on the room's router, of every session in it
+    async fn transport_count(room: &Room) -> usize {
+        let dump = dump::router(&room.router().unwrap()).await.unwrap();
+        dump.dump["transportIds"].as_array().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn panicking_event_loop_removes_the_user() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut observer = join(&room, "observer", Role::Speaker).await;
+        let mut failing = join(&room, "failing", Role::Speaker).await;
+        assert_eq!(transport_count(&room).await, 2);
+
+        // Applied by the event loop itself, not a command handler
+        mock::set_bitrate_panics(true);
+        room.set_max_incoming_bitrate(500_000);
+        let (code, _) = recv_close(&mut failing).await;
+        mock::set_bitrate_panics(false);
+        assert_eq!(code, 1011);
+
+        let event = recv_type(&mut observer, "userLeft").await;
+        assert_eq!(event["data"]["id"], "failing");
+        assert!(room.users().get("failing").await.is_none());
+        // Its transport went along with the session's media
+        for _ in 0..50 {
+            if transport_count(&room).await == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(transport_count(&room).await, 1);
+        drop(observer);
+        room.delete().await;
+    }
+
+    /// The signaling route, with media going through `MockSession`
+    fn mock_route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        warp::ws()
+            .and(handshake::filter(&CONFIG.signaling.allowed_origins))
+            .and(upgrade::filter())
+            .and(client_ip::filter())
+            .and_then(
+                |ws, negotiated: Result<Negotiated, WSCloseType>, credentials, ip| {
+                    accept::<MockSession>(ws, negotiated.unwrap(), credentials, ip)
+                },
+            )
+    }
+
+    async fn recv(client: &mut WsClient) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("Timed out waiting for the server")
+            .expect("Connection failed")
+    }
+
+    /// Next message of the given type, skipping what the room sent in the meantime
+    async fn recv_type(client: &mut WsClient, message_type: &str) -> serde_json::Value {
+        loop {
+            let message = recv(client).await;
+            let text = message.to_str().expect("Expected a text frame");
+            let message: serde_json::Value = serde_json::from_str(text).unwrap();
+            if message["type"] == message_type {
+                return message;
+            }
+        }
+    }
+
+    async fn recv_close(client: &mut WsClient) -> (u16, String) {
+        loop {
+            let message = recv(client).await;
+            if let Some((code, reason)) = message.close_frame() {
+                return (code, reason.to_string());
+            }
+        }
+    }
+
+    async fn send(client: &mut WsClient, command: serde_json::Value) {
+        client.send_text(command.to_string()).await;
+    }
+
+    fn audio_parameters(ssrc: u32) -> serde_json::Value {
+        json!({
+            "codecs": [{
+                "mimeType": "audio/opus",
+                "payloadType": 100,
+                "clockRate": 48000,
+                "channels": 2,
+                "parameters": {},
+                "rtcpFeedback": [],
+            }],
+            "headerExtensions": [],
+            "encodings": [{ "ssrc": ssrc }],
+            "rtcp": { "cname": "host", "reducedSize": true },
+        })
+    }
+
+    fn rtp_capabilities() -> serde_json::Value {
+        json!({
+            "codecs": [{
+                "kind": "audio",
+                "mimeType": "audio/opus",
+                "preferredPayloadType": 100,
+                "clockRate": 48000,
+                "channels": 2,
+                "parameters": {},
+                "rtcpFeedback": [],
+            }],
+            "headerExtensions": [],
+        })
+    }
+
+    /// Authenticate as a new user of the room and initialize its transports
+    async fn join(room: &Arc<Room>, id: &str, role: Role) -> WsClient {
+        join_versioned(room, id, role, None).await
+    }
+
+    async fn join_versioned(
+        room: &Arc<Room>,
+        id: &str,
+        role: Role,
+        version: Option<u32>,
+    ) -> WsClient {
+        let options = UserOptions {
+            role,
+            ..UserOptions::default()
+        };
+        let mut auth = json!({});
+        if let Some(version) = version {
+            auth["version"] = json!(version);
+        }
+        join_with(room, id, options, auth).await
+    }
+
+    /// Join with more fields in the data of `Authenticate`
+    async fn join_with(
+        room: &Arc<Room>,
+        id: &str,
+        options: UserOptions,
+        auth: serde_json::Value,
+    ) -> WsClient {
+        let (mut client, _) = authenticate(room, id, options, auth).await;
+        initialize(&mut client).await;
+        client
+    }
+
+    /// Authenticate as a new user of the room, leaving its transports uninitialized
+    async fn authenticate(
+        room: &Arc<Room>,
+        id: &str,
+        options: UserOptions,
+        mut auth: serde_json::Value,
+    ) -> (WsClient, serde_json::Value) {
+        let token = room
+            .users()
+            .create(id.to_string(), options)
+            .await
+            .unwrap()
+            .token;
+        let mut client = warp::test::ws().handshake(mock_route()).await.unwrap();
+
+        auth["roomId"] = json!(room.id());
+        auth["token"] = json!(token);
+        send(
+            &mut client,
+            json!({ "id": "auth", "type": "Authenticate", "data": auth }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "authenticate").await;
+        assert_eq!(reply["data"]["userId"], id);
+        let version = match auth.get("version") {
+            Some(version) => version.clone(),
+            None => json!(MIN_PROTOCOL_VERSION),
+        };
+        assert_eq!(reply["data"]["version"], version);
+        (client, reply)
+    }
+
+    /// Initialize and connect the client's transports
+    async fn initialize(client: &mut WsClient) {
+        let data = json!({ "rtpCapabilities": rtp_capabilities() });
+        send(
+            client,
+            json!({ "id": "init", "type": "InitializeTransports", "data": data }),
+        )
+        .await;
+        let reply = recv_type(client, "initializeTransports").await;
+        let transport_id = reply["data"]["id"].clone();
+
+        let fingerprint = vec!["AB"; 32].join(":");
+        let data = json!({
+            "id": transport_id,
+            "dtlsParameters": {
+                "role": "client",
+                "fingerprints": [{ "algorithm": "sha-256", "value": fingerprint }],
+            },
+        });
+        send(
+            client,
+            json!({ "id": "connect", "type": "ConnectTransport", "data": data }),
+        )
+        .await;
+        let reply = recv_type(client, "connectTransport").await;
+        assert_eq!(reply["id"], "connect");
+    }
+
+    #[tokio::test]
+    async fn users_join_produce_and_consume() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let event = recv_type(&mut host, "userJoined").await;
+        assert_eq!(event["data"]["id"], "guest");
+        // Enough to show the user without asking for the room's info
+        assert_eq!(event["data"]["info"]["role"], "speaker");
+
+        let rtp_parameters = json!({
+            "codecs": [{
+                "mimeType": "audio/opus",
+                "payloadType": 100,
+                "clockRate": 48000,
+                "channels": 2,
+                "parameters": {},
+                "rtcpFeedback": [],
+            }],
+            "headerExtensions": [],
+            "encodings": [{ "ssrc": 1111 }],
+            "rtcp": { "cname": "host", "reducedSize": true },
+        });
+        let data = json!({ "produceType": "audio", "rtpParameters": rtp_parameters });
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "startProduce").await;
+        let producer_id = reply["data"]["producerId"].clone();
+        assert!(producer_id.is_string());
+
+        let event = recv_type(&mut guest, "userStartProduce").await;
+        assert_eq!(event["data"]["id"], "host");
+        let data = json!({ "produceType": "audio", "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "startConsume").await;
+        assert_eq!(reply["data"]["producerId"], producer_id);
+
+        // Both users are in the room the server describes
+        send(&mut guest, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut guest, "roomInfo").await;
+        let users = reply["data"]["users"].as_object().unwrap();
+        assert!(users.contains_key("host") && users.contains_key("guest"));
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn producers_and_consumers_are_listed() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "startProduce").await;
+        let producer_id = reply["data"]["producerId"].clone();
+        send(&mut host, json!({ "id": "list", "type": "ListProducers" })).await;
+        let reply = recv_type(&mut host, "listProducers").await;
+        assert_eq!(
+            reply["data"]["producers"],
+            json!([{ "id": producer_id, "type": "audio", "kind": "audio", "paused": false }])
+        );
+
+        let data = json!({ "produceType": "audio", "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "startConsume").await;
+        let consumer_id = reply["data"]["id"].clone();
+        send(&mut guest, json!({ "id": "list", "type": "ListConsumers" })).await;
+        let reply = recv_type(&mut guest, "listConsumers").await;
+        let consumers = reply["data"]["consumers"].as_array().unwrap();
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0]["id"], consumer_id);
+        assert_eq!(consumers[0]["producerId"], producer_id);
+        assert_eq!(consumers[0]["userId"], "host");
+        assert_eq!(consumers[0]["type"], "audio");
+
+        // Nothing is held once the consumer is stopped
+        let data = json!({ "id": consumer_id });
+        send(
+            &mut guest,
+            json!({ "id": "stop", "type": "StopConsume", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "stopConsume").await;
+        send(&mut guest, json!({ "id": "list", "type": "ListConsumers" })).await;
+        let reply = recv_type(&mut guest, "listConsumers").await;
+        assert_eq!(reply["data"]["consumers"], json!([]));
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn audiences_follow_consumers_as_they_close() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(2222) });
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "startProduce").await;
+        let producer_id: ProducerId =
+            serde_json::from_value(reply["data"]["producerId"].clone()).unwrap();
+
+        let consume = json!({ "produceType": "audio", "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": consume.clone() }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "startConsume").await;
+        assert_eq!(room.audience(producer_id), 1);
+
+        let data = json!({ "id": reply["data"]["id"] });
+        send(
+            &mut guest,
+            json!({ "id": "stop", "type": "StopConsume", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "stopConsume").await;
+        assert_eq!(room.audience(producer_id), 0);
+
+        // Consumers of a user that's kicked go with it
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": consume }),
+        )
+        .await;
+        recv_type(&mut guest, "startConsume").await;
+        assert_eq!(room.audience(producer_id), 1);
+        room.users()
+            .kick("guest", None, Some("host"))
+            .await
+            .unwrap();
+        recv_type(&mut guest, "kicked").await;
+        drop(guest);
+        for _ in 0..50 {
+            if room.audience(producer_id) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(room.audience(producer_id), 0);
+        drop(host);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn users_are_consumed_at_once() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "ConsumeUser", "data": data.clone() }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "consumeUser").await;
+        assert_eq!(reply["data"]["consumers"], json!([]));
+
+        let produce = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": produce }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "startProduce").await;
+        let producer_id = reply["data"]["producerId"].clone();
+
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "ConsumeUser", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "consumeUser").await;
+        assert_eq!(reply["data"]["userId"], "host");
+        let consumers = reply["data"]["consumers"].as_array().unwrap();
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0]["type"], "audio");
+        assert_eq!(consumers[0]["producerId"], producer_id);
+        assert_eq!(consumers[0]["kind"], "audio");
+        assert!(consumers[0]["id"].is_string());
+        assert!(consumers[0]["rtpParameters"].is_object());
+        assert!(consumers[0].get("error").is_none());
+
+        // A user that isn't there fails the whole command
+        let data = json!({ "userId": "nobody" });
+        send(
+            &mut guest,
+            json!({ "id": "missing", "type": "ConsumeUser", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "ConsumeUser").await;
+        assert_eq!(error["code"], 1000);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn migrated_rooms_keep_their_producers_until_users_move() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let produce = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": produce }),
+        )
+        .await;
+        let producer_id = recv_type(&mut host, "startProduce").await["data"]["producerId"].clone();
+
+        let migration = room.migrate(None).await.unwrap();
+        assert!(!migration.aborted);
+        recv_type(&mut host, "mediaRestartRequired").await;
+        recv_type(&mut guest, "mediaRestartRequired").await;
+
+        // The host's producer is piped to the new router the guest moved to
+        initialize(&mut guest).await;
+        let data = json!({ "produceType": "audio", "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "startConsume").await;
+        assert_eq!(reply["data"]["producerId"], producer_id);
+
+        // Until the host moves too, leaving it behind
+        initialize(&mut host).await;
+        let event = recv_type(&mut guest, "userStopProduce").await;
+        assert_eq!(event["data"]["id"], "host");
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn flapping_producers_are_coalesced_and_throttled() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Speaker).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let produce = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        let stop = json!({ "produceType": "audio" });
+
+        // Started, then stopped and started again twice in quick succession
+        for round in 0..3 {
+            if round > 0 {
+                send(
+                    &mut host,
+                    json!({ "id": "stop", "type": "StopProduce", "data": stop }),
+                )
+                .await;
+                recv_type(&mut host, "stopProduce").await;
+            }
+            send(
+                &mut host,
+                json!({ "id": "produce", "type": "StartProduce", "data": produce }),
+            )
+            .await;
+            recv_type(&mut host, "startProduce").await;
+        }
+
+        // The guest hears of the first start right away, then once of the new producer
+        let mut announced = Vec::new();
+        while announced.len() < 3 {
+            let message = recv(&mut guest).await;
+            let message: serde_json::Value =
+                serde_json::from_str(message.to_str().unwrap()).unwrap();
+            if let Some("userStartProduce") | Some("userStopProduce") = message["type"].as_str() {
+                announced.push(message["type"].clone());
+            }
+        }
+        assert_eq!(
+            announced,
+            ["userStartProduce", "userStopProduce", "userStartProduce"]
+        );
+        let more = tokio::time::timeout(
+            Duration::from_millis(500),
+            recv_type(&mut guest, "userStopProduce"),
+        );
+        assert!(more.await.is_err());
+
+        // Flapping on is refused until the client slows down
+        let mut refused = None;
+        for attempt in 0..20 {
+            let id = attempt.to_string();
+            let command = match attempt % 2 {
+                0 => json!({ "id": id, "type": "StopProduce", "data": stop }),
+                _ => json!({ "id": id, "type": "StartProduce", "data": produce }),
+            };
+            send(&mut host, command).await;
+            let reply = loop {
+                let message = recv(&mut host).await;
+                let message: serde_json::Value =
+                    serde_json::from_str(message.to_str().unwrap()).unwrap();
+                if message["id"] == id {
+                    break message;
+                }
+            };
+            if reply["code"] == 5002 {
+                refused = Some(reply);
+                break;
+            }
+        }
+        let refused = refused.expect("Producers were never rate limited");
+        assert!(refused["retry_after_ms"].as_u64().unwrap() > 0);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn producers_are_clamped_to_the_room_bitrate_caps() {
+        let settings = RoomSettings {
+            bitrate_limits: BitrateLimits {
+                audio: Some(64_000),
+                ..BitrateLimits::default()
+            },
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+
+        let mut rtp_parameters = audio_parameters(1111);
+        rtp_parameters["encodings"][0]["maxBitrate"] = json!(128_000);
+        let data = json!({ "produceType": "audio", "rtpParameters": rtp_parameters });
+        send(
+            &mut client,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "startProduce").await;
+        assert_eq!(reply["data"]["maxBitrates"], json!([64_000]));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn quality_profiles_cap_producers_and_can_be_lifted() {
+        let settings = RoomSettings {
+            bitrate_limits: BitrateLimits {
+                audio: Some(64_000),
+                ..BitrateLimits::default()
+            },
+            quality_profile: Some("low".to_string()),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+
+        // The lower of the room's cap and the profile's wins
+        let mut rtp_parameters = audio_parameters(1111);
+        rtp_parameters["encodings"][0]["maxBitrate"] = json!(128_000);
+        let data = json!({ "produceType": "audio", "rtpParameters": rtp_parameters });
+        send(
+            &mut client,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "startProduce").await;
+        assert_eq!(reply["data"]["maxBitrates"], json!([32_000]));
+
+        send(&mut client, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut client, "roomInfo").await;
+        assert_eq!(reply["data"]["settings"]["qualityProfile"], "low");
+        assert_eq!(reply["data"]["quality"]["video"]["maxHeight"], 360);
+
+        let update: RoomSettingsUpdate =
+            serde_json::from_value(json!({ "qualityProfile": null })).unwrap();
+        room.update_settings(update).await.unwrap();
+        let event = recv_type(&mut client, "roomSettingsChanged").await;
+        assert_eq!(event["data"]["settings"]["qualityProfile"], json!(null));
+        assert!(event["data"].get("quality").is_none());
+        assert!(room.quality_profile().is_none());
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn scalability_modes_are_restricted_by_the_room() {
+        let settings = RoomSettings {
+            scalability_modes: Some(vec!["L1T3".to_string()]),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+
+        let mut rtp_parameters = audio_parameters(1111);
+        rtp_parameters["encodings"][0]["scalabilityMode"] = json!("L3T3");
+        let data = json!({ "produceType": "audio", "rtpParameters": rtp_parameters });
+        send(
+            &mut client,
+            json!({ "id": "svc", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut client, "StartProduce").await;
+        assert_eq!(
+            (error["code"].clone(), error["detail"].clone()),
+            (json!(3007), json!("L3T3"))
+        );
+
+        // A single layer has no mode to restrict
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut client,
+            json!({ "id": "plain", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut client, "startProduce").await;
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn replaced_producers_keep_their_consumers() {
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            replace_producer(version).await;
+        }
+    }
+
+    async fn replace_producer(version: u32) {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join_versioned(&room, "host", Role::Moderator, Some(version)).await;
+        let mut guest = join_versioned(&room, "guest", Role::Speaker, Some(version)).await;
+
+        // Nothing to replace yet
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut host,
+            json!({ "id": "early", "type": "ReplaceProduce", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut host, "ReplaceProduce").await;
+        assert_eq!(error["id"], "early");
+
+        send(
+            &mut host,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "startProduce").await;
+        let old_producer = reply["data"]["producerId"].clone();
+        recv_type(&mut guest, "userStartProduce").await;
+        let data = json!({ "produceType": "audio", "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut guest, "startConsume").await;
+        let old_consumer = reply["data"]["id"].clone();
+
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(2222) });
+        send(
+            &mut host,
+            json!({ "id": "replace", "type": "ReplaceProduce", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "replaceProduce").await;
+        let new_producer = reply["data"]["producerId"].clone();
+        assert_ne!(new_producer, old_producer);
+
+        if version < 2 {
+            // Told to consume again, the way a stop and start would
+            let event = recv_type(&mut guest, "consumerClosed").await;
+            assert_eq!(event["data"]["id"], old_consumer);
+            let event = recv(&mut guest).await;
+            let event: serde_json::Value = serde_json::from_str(event.to_str().unwrap()).unwrap();
+            assert_eq!(event["type"], "userStopProduce");
+            let event = recv(&mut guest).await;
+            let event: serde_json::Value = serde_json::from_str(event.to_str().unwrap()).unwrap();
+            assert_eq!(event["type"], "userStartProduce");
+            assert_eq!(event["data"]["id"], "host");
+            drop((host, guest));
+            room.delete().await;
+            return;
+        }
+
+        // Moved over in a single event, without a stop and start
+        let event = recv(&mut guest).await;
+        let event: serde_json::Value = serde_json::from_str(event.to_str().unwrap()).unwrap();
+        assert_eq!(event["type"], "userProducerReplaced");
+        assert_eq!(event["data"]["id"], "host");
+        assert_eq!(event["data"]["type"], "audio");
+        let consumers = event["data"]["consumers"].as_array().unwrap();
+        assert_eq!(consumers.len(), 1);
+        assert_eq!(consumers[0]["replaces"], old_consumer);
+        assert_eq!(consumers[0]["producerId"], new_producer);
+        assert_ne!(consumers[0]["id"], old_consumer);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn paused_producers_show_as_paused() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({
+            "produceType": "audio",
+            "rtpParameters": audio_parameters(1111),
+            "paused": true,
+        });
+        send(
+            &mut guest,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "startProduce").await;
+        let event = recv_type(&mut host, "userStartProduce").await;
+        assert_eq!(event["data"]["paused"], true);
+
+        // Late joiners see it muted in the snapshot
+        send(&mut host, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut host, "roomInfo").await;
+        let producers = &reply["data"]["users"]["guest"]["producers"];
+        assert_eq!(producers, &json!([{ "type": "audio", "paused": true }]));
+
+        let data = json!({ "produceType": "audio", "paused": false });
+        send(
+            &mut guest,
+            json!({ "id": "unmute", "type": "SetProducerPause", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "setProducerPause").await;
+        let event = recv_type(&mut host, "userProducerPauseChanged").await;
+        assert_eq!(
+            event["data"],
+            json!({ "id": "guest", "type": "audio", "paused": false })
+        );
+
+        let data = json!({ "produceType": "video", "paused": true });
+        send(
+            &mut guest,
+            json!({ "id": "missing", "type": "SetProducerPause", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "SetProducerPause").await;
+        assert_eq!(error["id"], "missing");
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn push_to_talk_mutes_without_heartbeats() {
+        let settings = RoomSettings {
+            ptt_max_unmute_ms: Some(1000),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut guest,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "startProduce").await;
+        let unmuted = Instant::now();
+
+        // Held past the delay while the client keeps sending heartbeats
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            send(&mut guest, json!({ "id": "ptt", "type": "PttHeartbeat" })).await;
+            recv_type(&mut guest, "pttHeartbeat").await;
+        }
+        let event = recv_type(&mut host, "userProducerPauseChanged").await;
+        assert_eq!(
+            event["data"],
+            json!({ "id": "guest", "type": "audio", "paused": true })
+        );
+        assert!(unmuted.elapsed() >= Duration::from_millis(2000));
+        let event = recv_type(&mut guest, "userProducerPauseChanged").await;
+        assert_eq!(event["data"]["paused"], true);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn positions_are_relayed_on_a_tick() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        for x in [1.0, 2.0, 3.0] {
+            let data = json!({ "x": x, "y": 0.0, "z": -1.5, "rotation": 0.5 });
+            send(
+                &mut guest,
+                json!({ "id": "move", "type": "UpdatePosition", "data": data }),
+            )
+            .await;
+            recv_type(&mut guest, "updatePosition").await;
+        }
+        // Updates in between ticks come as one event with the latest
+        let event = recv_type(&mut host, "positions").await;
+        let users = event["data"]["users"].as_array().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0]["userId"], "guest");
+        assert_eq!(users[0]["z"], -1.5);
+
+        // Those joining later find it in the room's users
+        send(&mut host, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut host, "roomInfo").await;
+        assert_eq!(reply["data"]["users"]["guest"]["position"]["rotation"], 0.5);
+        assert!(reply["data"]["users"]["host"].get("position").is_none());
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn undeclared_capabilities_are_left_out() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        // Declares a capability from a newer server and leaves relaying out
+        let options = UserOptions::default();
+        let auth = json!({ "capabilities": ["stats", "holograms"] });
+        let mut guest = join_with(&room, "guest", options, auth).await;
+        recv_type(&mut host, "userJoined").await;
+
+        let relay = json!({
+            "id": "relay",
+            "type": "RelayBroadcast",
+            "data": { "payload": { "hand": "raised" } },
+        });
+        send(&mut guest, relay.clone()).await;
+        let error = recv_type(&mut guest, "RelayBroadcast").await;
+        assert_eq!(
+            (error["code"].clone(), error["detail"].clone()),
+            (json!(7005), json!("relay"))
+        );
+
+        // The host declared nothing, so it relays as before negotiation
+        send(&mut host, relay).await;
+        recv_type(&mut host, "relayBroadcast").await;
+        send(&mut guest, json!({ "id": "info", "type": "RoomInfo" })).await;
+        loop {
+            let message = recv(&mut guest).await;
+            let message: serde_json::Value =
+                serde_json::from_str(message.to_str().unwrap()).unwrap();
+            assert_ne!(message["type"], "relay");
+            if message["type"] == "roomInfo" {
+                break;
+            }
+        }
+
+        let data = json!({ "intervalMs": 1000 });
+        send(
+            &mut guest,
+            json!({ "id": "stats", "type": "SubscribeStats", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "subscribeStats").await;
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn events_are_sent_by_subscription() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let auth = json!({ "subscriptions": [] });
+        let (mut bot, reply) = authenticate(&room, "bot", UserOptions::default(), auth).await;
+        assert_eq!(reply["data"]["subscriptions"], json!([]));
+        initialize(&mut bot).await;
+        recv_type(&mut host, "userJoined").await;
+
+        let relay = json!({
+            "id": "relay",
+            "type": "RelayBroadcast",
+            "data": { "payload": { "hand": "raised" } },
+        });
+        send(&mut host, relay.clone()).await;
+        recv_type(&mut host, "relayBroadcast").await;
+        // Membership is sent whatever the connection subscribed to
+        let guest = join(&room, "guest", Role::Speaker).await;
+        let event = recv(&mut bot).await;
+        let event: serde_json::Value = serde_json::from_str(event.to_str().unwrap()).unwrap();
+        assert_eq!(event["type"], "userJoined");
+        assert_eq!(event["seq"], 1);
+
+        let data = json!({ "subscriptions": ["relay", "telepathy"] });
+        send(
+            &mut bot,
+            json!({ "id": "subscribe", "type": "SetSubscriptions", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut bot, "setSubscriptions").await;
+        assert_eq!(reply["data"]["subscriptions"], json!(["relay"]));
+        send(&mut host, relay).await;
+        let event = recv_type(&mut bot, "relay").await;
+        assert_eq!(event["data"]["payload"]["hand"], "raised");
+        drop((host, bot, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn rooms_are_joined_over_one_connection() {
+        let lobby = testing::room(RoomSettings::default()).await;
+        let other = testing::room(RoomSettings::default()).await;
+        let mut client = join(&lobby, "alice", Role::Speaker).await;
+        let token = other
+            .users()
+            .create("alice-preview".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+
+        let data = json!({ "roomId": other.id(), "token": token });
+        send(
+            &mut client,
+            json!({ "id": "join", "type": "JoinRoom", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "joinRoom").await;
+        assert_eq!(reply["data"]["userId"], "alice-preview");
+
+        // Events of the joined room come tagged with it
+        let mut bob = join(&other, "bob", Role::Speaker).await;
+        let event = recv_type(&mut client, "userJoined").await;
+        assert_eq!(event["roomId"], other.id());
+        assert_eq!(event["data"]["id"], "bob");
+
+        let relay = json!({
+            "id": "relay",
+            "roomId": other.id(),
+            "type": "RelayBroadcast",
+            "data": { "payload": { "speaking": true } },
+        });
+        send(&mut client, relay).await;
+        let reply = recv_type(&mut client, "relayBroadcast").await;
+        assert_eq!(reply["roomId"], other.id());
+        let event = recv_type(&mut bob, "relay").await;
+        assert_eq!(event["data"]["from"], "alice-preview");
+
+        // Signal-only, there are no transports to produce on
+        let produce = json!({
+            "id": "produce",
+            "roomId": other.id(),
+            "type": "StartProduce",
+            "data": { "produceType": "audio", "rtpParameters": audio_parameters(1111) },
+        });
+        send(&mut client, produce).await;
+        let error = recv_type(&mut client, "StartProduce").await;
+        assert_eq!(
+            (error["code"].clone(), error["roomId"].clone()),
+            (json!(10003), json!(other.id()))
+        );
+
+        // Leaving it keeps the connection in the lobby
+        let data = json!({ "roomId": other.id() });
+        send(
+            &mut client,
+            json!({ "id": "leave", "type": "LeaveRoom", "data": data }),
+        )
+        .await;
+        recv_type(&mut client, "leaveRoom").await;
+        let event = recv_type(&mut bob, "userLeft").await;
+        assert_eq!(event["data"]["id"], "alice-preview");
+        send(&mut client, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut client, "roomInfo").await;
+        assert_eq!(reply["data"]["id"], lobby.id());
+
+        drop((client, bob));
+        lobby.delete().await;
+        other.delete().await;
+    }
+
+    #[tokio::test]
+    async fn moderators_share_room_metadata() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "key": "agenda", "value": { "item": 2 } });
+        send(
+            &mut guest,
+            json!({ "id": "set", "type": "SetRoomMetadata", "data": data.clone() }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "SetRoomMetadata").await;
+        assert_eq!(error["code"], 1002);
+
+        send(
+            &mut host,
+            json!({ "id": "set", "type": "SetRoomMetadata", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "setRoomMetadata").await;
+        let event = recv_type(&mut guest, "roomMetadataChanged").await;
+        assert_eq!(
+            event["data"],
+            json!({ "key": "agenda", "value": { "item": 2 } })
+        );
+
+        send(&mut guest, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut guest, "roomInfo").await;
+        assert_eq!(reply["data"]["metadata"]["agenda"]["item"], 2);
+
+        let data = json!({ "key": "agenda" });
+        send(
+            &mut host,
+            json!({ "id": "delete", "type": "DeleteRoomMetadata", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "deleteRoomMetadata").await;
+        let event = recv_type(&mut guest, "roomMetadataChanged").await;
+        assert_eq!(event["data"], json!({ "key": "agenda", "value": null }));
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn mute_all_spares_the_moderator_and_bypassing_users() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let options = UserOptions {
+            permissions: vec![Permission::BypassMute],
+            ..UserOptions::default()
+        };
+        let mut presenter = join_with(&room, "presenter", options, json!({})).await;
+
+        for (client, ssrc) in [
+            (&mut host, 1111),
+            (&mut guest, 2222),
+            (&mut presenter, 3333),
+        ] {
+            let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(ssrc) });
+            send(
+                client,
+                json!({ "id": "produce", "type": "StartProduce", "data": data }),
+            )
+            .await;
+            recv_type(client, "startProduce").await;
+        }
+
+        let data = json!({ "produceType": "audio" });
+        send(
+            &mut guest,
+            json!({ "id": "mute", "type": "MuteAll", "data": data.clone() }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "MuteAll").await;
+        assert_eq!(error["error"], "MissingPermission");
+
+        send(
+            &mut host,
+            json!({ "id": "mute", "type": "MuteAll", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut host, "muteAll").await;
+        assert_eq!(reply["data"], json!({ "muted": 1, "failed": [] }));
+
+        // The muted user hears about it too
+        let event = recv_type(&mut guest, "userProducerPauseChanged").await;
+        assert_eq!(
+            event["data"],
+            json!({ "id": "guest", "type": "audio", "paused": true })
+        );
+        assert!(producer_paused(&room, "guest", ProduceType::Audio).await);
+        assert!(!producer_paused(&room, "host", ProduceType::Audio).await);
+        assert!(!producer_paused(&room, "presenter", ProduceType::Audio).await);
+        drop((host, guest, presenter));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn locked_rooms_hold_users_in_the_lobby() {
+        let settings = RoomSettings {
+            lobby: true,
+            owner: Some("host".to_string()),
+            ..RoomSettings::default()
+        };
+        let room = testing::room(settings).await;
+        let speaker = || UserOptions::default();
+        let (mut early, reply) = authenticate(&room, "early", speaker(), json!({})).await;
+        assert_eq!(reply["data"]["waiting"], true);
+
+        // Nothing runs in the lobby, transports included
+        let data = json!({ "rtpCapabilities": rtp_capabilities() });
+        send(
+            &mut early,
+            json!({ "id": "init", "type": "InitializeTransports", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut early, "InitializeTransports").await;
+        assert_eq!(error["id"], "init");
+        assert_eq!(error["code"], 1005);
+
+        // Moderators come in and see who's waiting
+        let mut moderator = join(&room, "moderator", Role::Moderator).await;
+        send(&mut moderator, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut moderator, "roomInfo").await;
+        assert_eq!(reply["data"]["locked"], true);
+        assert_eq!(reply["data"]["waiting"], json!(["early"]));
+        let (mut late, _) = authenticate(&room, "late", speaker(), json!({})).await;
+        let event = recv_type(&mut moderator, "userWaiting").await;
+        assert_eq!(event["data"]["id"], "late");
+
+        // and let them in one at a time
+        send(
+            &mut moderator,
+            json!({ "id": "admit", "type": "AdmitUser", "data": { "userId": "late" } }),
+        )
+        .await;
+        recv_type(&mut moderator, "admitUser").await;
+        let event = recv_type(&mut late, "roomOpened").await;
+        assert_eq!(event["seq"], 1);
+        assert!(event["data"]["users"].get("moderator").is_some());
+        initialize(&mut late).await;
+        send(
+            &mut moderator,
+            json!({ "id": "again", "type": "AdmitUser", "data": { "userId": "late" } }),
+        )
+        .await;
+        let error = recv_type(&mut moderator, "AdmitUser").await;
+        assert_eq!(error["code"], 1006);
+        assert!(room.locked());
+
+        // The host joining lets everyone else in
+        let host = join(&room, "host", Role::Speaker).await;
+        recv_type(&mut early, "roomOpened").await;
+        initialize(&mut early).await;
+        assert!(!room.locked());
+        assert!(room.waiting().is_empty());
+
+        drop((early, late, moderator, host));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn consumers_are_resumed_by_the_client() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut guest,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "startProduce").await;
+        recv_type(&mut host, "userStartProduce").await;
+
+        let data = json!({ "produceType": "audio", "userId": "guest" });
+        send(
+            &mut host,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let consumer = recv_type(&mut host, "startConsume").await["data"]["id"].clone();
+        let data = json!({ "consumerId": consumer });
+        send(
+            &mut host,
+            json!({ "id": "resume", "type": "ResumeConsumer", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "resumeConsumer").await;
+
+        let data = json!({ "consumerId": "missing" });
+        send(
+            &mut host,
+            json!({ "id": "missing", "type": "ResumeConsumer", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut host, "ResumeConsumer").await;
+        assert_eq!(error["id"], "missing");
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn closed_media_is_reported() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let produce = |ssrc: u32| {
+            let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(ssrc) });
+            json!({ "id": "produce", "type": "StartProduce", "data": data })
+        };
+        let consume = json!({
+            "id": "consume",
+            "type": "StartConsume",
+            "data": { "produceType": "audio", "userId": "guest" },
+        });
+
+        send(&mut guest, produce(1111)).await;
+        recv_type(&mut guest, "startProduce").await;
+        recv_type(&mut host, "userStartProduce").await;
+        send(&mut host, consume.clone()).await;
+        let stopped = recv_type(&mut host, "startConsume").await["data"]["id"].clone();
+        let data = json!({ "id": stopped });
+        send(
+            &mut host,
+            json!({ "id": "stop", "type": "StopConsume", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "stopConsume").await;
+        send(&mut host, consume).await;
+        let consumer = recv_type(&mut host, "startConsume").await["data"]["id"].clone();
+
+        // Closed without the room hearing of it, only the consumer still open is reported
+        let guest_user = room.users().get("guest").await.unwrap();
+        guest_user
+            .write()
+            .await
+            .set_producer(ProduceType::Audio, None)
+            .unwrap();
+        drop(guest_user);
+        let event = recv_type(&mut host, "consumerClosed").await;
+        assert_eq!(event["data"]["id"], consumer);
+        assert_eq!(event["data"]["reason"]["code"], "producer_closed");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(&mut host, json!({ "id": "info", "type": "RoomInfo" })).await;
+        loop {
+            let message = recv(&mut host).await;
+            let message: serde_json::Value =
+                serde_json::from_str(message.to_str().unwrap()).unwrap();
+            assert_ne!(message["type"], "consumerClosed");
+            if message["type"] == "roomInfo" {
+                break;
+            }
+        }
+
+        // Demoted users are told their producers are gone
+        send(&mut guest, produce(2222)).await;
+        let producer = recv_type(&mut guest, "startProduce").await["data"]["producerId"].clone();
+        let guest_user = room.users().get("guest").await.unwrap();
+        guest_user.write().await.set_role(Role::Listener);
+        drop(guest_user);
+        let event = recv_type(&mut guest, "producerClosed").await;
+        assert_eq!(event["data"]["id"], producer);
+        assert_eq!(event["data"]["type"], "audio");
+        assert_eq!(event["data"]["reason"]["code"], "role_changed");
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn idle_consumers_are_reaped() {
+        mock::set_reaping(Some((
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+        )));
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut guest,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "startProduce").await;
+        recv_type(&mut host, "userStartProduce").await;
+
+        // Nothing is sent to the direct transport, so the resumed consumer stays idle
+        let data = json!({ "produceType": "audio", "userId": "guest" });
+        send(
+            &mut host,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let consumer = recv_type(&mut host, "startConsume").await["data"]["id"].clone();
+        let resumed = Instant::now();
+        let data = json!({ "consumerId": consumer });
+        send(
+            &mut host,
+            json!({ "id": "resume", "type": "ResumeConsumer", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "resumeConsumer").await;
+
+        let event = recv_type(&mut host, "consumerClosed").await;
+        assert_eq!(event["data"]["id"], consumer);
+        assert_eq!(
+            event["data"]["reason"],
+            json!({ "code": "inactive", "message": "Consumer sent nothing for too long" })
+        );
+        assert!(resumed.elapsed() >= Duration::from_millis(300));
+        mock::set_reaping(None);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn resumes_time_out_without_a_receiving_transport() {
+        mock::set_resume_timeout(Some(Duration::from_millis(300)));
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut guest,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        recv_type(&mut guest, "startProduce").await;
+        recv_type(&mut host, "userStartProduce").await;
+
+        let data = json!({ "produceType": "audio", "userId": "guest" });
+        send(
+            &mut host,
+            json!({ "id": "consume", "type": "StartConsume", "data": data }),
+        )
+        .await;
+        let consumer = recv_type(&mut host, "startConsume").await["data"]["id"].clone();
+        let queued = Instant::now();
+        let data = json!({ "consumerId": consumer });
+        send(
+            &mut host,
+            json!({ "id": "resume", "type": "ResumeConsumer", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "resumeConsumer").await;
+
+        let event = recv_type(&mut host, "consumerClosed").await;
+        assert_eq!(event["data"]["id"], consumer);
+        assert_eq!(
+            event["data"]["reason"],
+            json!({
+                "code": "transport_timeout",
+                "message": "Receiving transport didn't connect in time"
+            })
+        );
+        assert!(queued.elapsed() >= Duration::from_millis(300));
+        mock::set_resume_timeout(None);
+        drop((host, guest));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn room_events_flow_during_slow_commands() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        mock::set_latency(Duration::from_millis(500));
+
+        let frame = json!([
+            { "id": "slow", "type": "RequestKeyFrame", "data": { "consumerId": "missing" } },
+            { "id": "after", "type": "SyncState" },
+        ]);
+        host.send_text(frame.to_string()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        room.send_event(RoomEvent::UserJoined(
+            "guest".to_string(),
+            UserInfo::default(),
+        ));
+
+        // Delivered while the worker is still busy with the first command
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let message = recv(&mut host).await;
+            let message: serde_json::Value =
+                serde_json::from_str(message.to_str().unwrap()).unwrap();
+            sent.push(message);
+        }
+        mock::set_latency(Duration::from_secs(0));
+        assert_eq!(sent[0]["type"], "userJoined");
+        assert_eq!(sent[0]["data"]["id"], "guest");
+        // Replies still follow the order of their commands
+        assert_eq!(sent[1]["id"], "slow");
+        assert_eq!(sent[1]["code"], 4001);
+        assert_eq!(sent[2]["id"], "after");
+        assert_eq!(sent[2]["data"]["seq"], sent[0]["seq"]);
+        drop(host);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let signaling = &CONFIG.signaling;
+
+        let payload = json!({ "text": "a".repeat(signaling.max_command_size) });
+        let data = json!({ "payload": payload });
+        send(
+            &mut host,
+            json!({ "id": "large", "type": "RelayBroadcast", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut host, "RelayBroadcast").await;
+        assert_eq!(error["id"], "large");
+        assert_eq!(error["code"], 7003);
+
+        // Only the command was refused
+        send(&mut host, json!({ "id": "info", "type": "RoomInfo" })).await;
+        recv_type(&mut host, "roomInfo").await;
+
+        host.send_text("a".repeat(signaling.max_message_size + 1))
+            .await;
+        let (code, _) = recv_close(&mut host).await;
+        assert_eq!(code, 1003);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn credentials_come_with_the_upgrade() {
+        let room = testing::room(RoomSettings::default()).await;
+        let token = room
+            .users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        let upgrade = |path: String| {
+            warp::test::request()
+                .path(&path)
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        };
+
+        // Refused before upgrading
+        let path = format!("/?room_id={}&token=invalid", room.id());
+        let response = upgrade(path).reply(&mock_route()).await;
+        assert_eq!(response.status(), 401);
+        let path = format!("/?room_id={}", room.id());
+        let response = upgrade(path)
+            .header("authorization", "Bearer invalid")
+            .reply(&mock_route())
+            .await;
+        assert_eq!(response.status(), 401);
+
+        // The reply comes unasked, the next step is initializing transports
+        let path = format!("/?room_id={}&token={}&version=2", room.id(), token);
+        let mut client = warp::test::ws()
+            .path(&path)
+            .handshake(mock_route())
+            .await
+            .unwrap();
+        let reply = recv_type(&mut client, "authenticate").await;
+        assert_eq!(reply["id"], serde_json::Value::Null);
+        assert_eq!(reply["data"]["userId"], "user");
+        assert_eq!(reply["data"]["version"], 2);
+
+        let data = json!({ "rtpCapabilities": rtp_capabilities() });
+        send(
+            &mut client,
+            json!({ "id": "init", "type": "InitializeTransports", "data": data }),
+        )
+        .await;
+        recv_type(&mut client, "initializeTransports").await;
+        drop(client);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn subprotocols_are_negotiated_on_the_upgrade() {
+        testing::init();
+        let upgrade = |offered: Option<&str>| {
+            let request = warp::test::request()
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                // Any origin may connect while none are listed
+                .header("origin", "https://app.example.com");
+            match offered {
+                Some(offered) => request.header("sec-websocket-protocol", offered),
+                None => request,
+            }
+        };
+
+        for offered in ["vortex-v1.json", "vortex-v1.msgpack, vortex-v1.json"].iter() {
+            let response = upgrade(Some(offered)).reply(&mock_route()).await;
+            assert_eq!(response.status(), 101);
+            assert_eq!(
+                response.headers()["sec-websocket-protocol"],
+                "vortex-v1.json"
+            );
+        }
+        // JSON all the same, without the header
+        for offered in [None, Some("vortex-v1.msgpack")].iter() {
+            let response = upgrade(*offered).reply(&mock_route()).await;
+            assert_eq!(response.status(), 101);
+            assert!(!response.headers().contains_key("sec-websocket-protocol"));
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_refused() {
+        let room = testing::room(RoomSettings::default()).await;
+        let token = room
+            .users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+
+        let mut client = warp::test::ws().handshake(mock_route()).await.unwrap();
+        let data = json!({ "roomId": room.id(), "token": token, "version": PROTOCOL_VERSION + 1 });
+        send(
+            &mut client,
+            json!({ "id": "auth", "type": "Authenticate", "data": data }),
+        )
+        .await;
+        let (code, _) = recv_close(&mut client).await;
+        assert_eq!(code, 4012);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn server_info_is_answered_before_authenticating() {
+        let room = testing::room(RoomSettings::default()).await;
+        let token = room
+            .users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+
+        let mut client = warp::test::ws().handshake(mock_route()).await.unwrap();
+        send(&mut client, json!({ "id": "info", "type": "ServerInfo" })).await;
+        let reply = recv_type(&mut client, "serverInfo").await;
+        assert_eq!(reply["id"], "info");
+        assert_eq!(reply["data"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            reply["data"]["protocolVersions"],
+            json!({ "min": MIN_PROTOCOL_VERSION, "max": PROTOCOL_VERSION })
+        );
+        assert_eq!(reply["data"]["features"]["simulcast"], true);
+        assert_eq!(
+            reply["data"]["limits"]["maxMessageSize"],
+            CONFIG.signaling.max_message_size
+        );
+
+        // The connection still authenticates afterwards
+        let data = json!({ "roomId": room.id(), "token": token });
+        send(
+            &mut client,
+            json!({ "id": "auth", "type": "Authenticate", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "authenticate").await;
+        assert_eq!(reply["data"]["userId"], "user");
+        send(&mut client, json!({ "id": "again", "type": "ServerInfo" })).await;
+        recv_type(&mut client, "serverInfo").await;
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn failing_commands_keep_the_connection() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+
+        let data = json!({
+            "id": "00000000-0000-4000-8000-000000000000",
+            "dtlsParameters": { "role": "client", "fingerprints": [] },
+        });
+        send(
+            &mut client,
+            json!({ "id": "1", "type": "ConnectTransport", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut client, "ConnectTransport").await;
+        assert_eq!(
+            (error["id"].clone(), error["code"].clone()),
+            (json!("1"), json!(2000))
+        );
+
+        let data = json!({ "produceType": "audio", "userId": "nobody" });
+        let command =
+            json!({ "id": "2", "traceId": "trace", "type": "StartConsume", "data": data });
+        send(&mut client, command).await;
+        let error = recv_type(&mut client, "StartConsume").await;
+        assert_eq!(error["code"], 1000);
+        assert_eq!(error["traceId"], "trace");
+
+        send(&mut client, json!({ "id": "3", "type": "RoomInfo" })).await;
+        recv_type(&mut client, "roomInfo").await;
+
+        // A refused room switch still leaves the room, the connection waits for another
+        // Authenticate and anything else is out of order
+        let data = json!({ "roomId": room.id(), "token": "token" });
+        send(
+            &mut client,
+            json!({ "id": "4", "type": "Authenticate", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut client, "Authenticate").await;
+        assert_eq!(error["code"], 10004);
+        assert!(error.get("request").is_none());
+        assert!(room.users().get("user").await.is_none());
+        send(&mut client, json!({ "id": "5", "type": "RoomInfo" })).await;
+        let (code, reason) = recv_close(&mut client).await;
+        assert_eq!(code, 1002);
+        assert_eq!(
+            reason,
+            "RoomInfo can't be sent while the connection is awaiting-auth"
+        );
+        room.delete().await;
+    }
+
+    /// Check JWTs signed with `secret` for the audience "vortex", join tokens still work
+    /// as they do without the `jwt` section
+    fn accept_jwts(secret: &str) {
+        let verifier = JwtVerifier::new(&JwtConfig {
+            algorithm: Algorithm::HS256,
+            key: secret.to_string(),
+            audience: Some("vortex".to_string()),
+            issuer: None,
+        })
+        .unwrap();
+        AUTHENTICATOR.get_or_init(|| Arc::new(LocalAuthenticator::new(Some(verifier))));
+    }
+
+    #[tokio::test]
+    async fn jwts_authenticate_in_their_room_only() {
+        const SECRET: &str = "ws-jwt-secret";
+        accept_jwts(SECRET);
+        let room = testing::room(RoomSettings::default()).await;
+        let sign = |algorithm, secret: &str, changes: serde_json::Value| {
+            let mut claims = json!({
+                "sub": "alice",
+                "room": room.id(),
+                "aud": "vortex",
+                "exp": token::now() + 3600,
+                "role": "speaker",
+            });
+            for (field, value) in changes.as_object().unwrap() {
+                claims[field] = value.clone();
+            }
+            let key = EncodingKey::from_secret(secret.as_bytes());
+            jsonwebtoken::encode(&Header::new(algorithm), &claims, &key).unwrap()
+        };
+        let connect = |token: String| {
+            let data = json!({ "roomId": room.id(), "token": token });
+            async move {
+                let mut client = warp::test::ws().handshake(mock_route()).await.unwrap();
+                send(
+                    &mut client,
+                    json!({ "id": "auth", "type": "Authenticate", "data": data }),
+                )
+                .await;
+                client
+            }
+        };
+
+        let refused = vec![
+            (
+                "expired",
+                sign(
+                    Algorithm::HS256,
+                    SECRET,
+                    json!({ "exp": token::now() - 3600 }),
+                ),
+            ),
+            (
+                "wrong audience",
+                sign(Algorithm::HS256, SECRET, json!({ "aud": "other" })),
+            ),
+            (
+                "wrong room",
+                sign(Algorithm::HS256, SECRET, json!({ "room": "other" })),
+            ),
+            (
+                "wrong tenant",
+                sign(Algorithm::HS256, SECRET, json!({ "tenant": "other" })),
+            ),
+            ("wrong algorithm", sign(Algorithm::HS384, SECRET, json!({}))),
+            (
+                "wrong signature",
+                sign(Algorithm::HS256, "other-secret", json!({})),
+            ),
+        ];
+        for (case, token) in refused {
+            let mut client = connect(token).await;
+            let (code, reason) = recv_close(&mut client).await;
+            assert_eq!((code, reason.as_str()), (4001, "Invalid token"), "{}", case);
+        }
+        assert!(room.users().get("alice").await.is_none());
+
+        let mut client = connect(sign(Algorithm::HS256, SECRET, json!({}))).await;
+        let reply = recv_type(&mut client, "authenticate").await;
+        assert_eq!(reply["data"]["userId"], "alice");
+        let user = room.users().get("alice").await.unwrap();
+        assert_eq!(user.read().await.role(), Role::Speaker);
+        drop((user, client));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn tokens_are_refreshed_for_the_same_user_only() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+        let issue = |room_id: &str, user_id: &str| {
+            token::issue(&JoinClaims {
+                room: room_id.to_string(),
+                sub: user_id.to_string(),
+                exp: token::now() + 3600,
+                nonce: "refresh".to_string(),
+                constraints: SessionConstraints::default(),
+            })
+        };
+
+        let data = json!({ "token": issue(room.id(), "user") });
+        send(
+            &mut client,
+            json!({ "id": "1", "type": "RefreshToken", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "refreshToken").await;
+        assert!(reply["data"]["expiresAt"].as_u64().unwrap() > token::now());
+
+        // Tokens of other users or rooms are refused, the session carries on
+        for token in [issue(room.id(), "other"), issue("other", "user")].iter() {
+            let data = json!({ "token": token });
+            send(
+                &mut client,
+                json!({ "id": "2", "type": "RefreshToken", "data": data }),
+            )
+            .await;
+            let error = recv_type(&mut client, "RefreshToken").await;
+            assert_eq!(error["code"], 1004);
+        }
+        send(&mut client, json!({ "id": "3", "type": "RoomInfo" })).await;
+        recv_type(&mut client, "roomInfo").await;
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn leaving_removes_the_user_without_a_grace_period() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut observer = join(&room, "observer", Role::Speaker).await;
+        let mut client = join(&room, "user", Role::Speaker).await;
+
+        // Commands after it in the batch are dropped with the session
+        let batch = json!([
+            { "id": "1", "type": "Leave" },
+            { "id": "2", "type": "RoomInfo" },
+        ]);
+        send(&mut client, batch).await;
+        let reply = recv_type(&mut client, "leave").await;
+        assert_eq!(reply["id"], "1");
+        assert!(room.users().get("user").await.is_none());
+        assert_eq!(
+            recv_close(&mut client).await,
+            (1000, "Left the room".to_string())
+        );
+
+        let event = recv_type(&mut observer, "userLeft").await;
+        assert_eq!(event["data"]["id"], "user");
+        drop(observer);
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn authenticating_again_switches_rooms() {
+        let first = testing::room(RoomSettings::default()).await;
+        let second = testing::room(RoomSettings::default()).await;
+        let mut observer = join(&first, "observer", Role::Speaker).await;
+        let mut client = join(&first, "user", Role::Speaker).await;
+
+        let token = second
+            .users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        let data = json!({ "roomId": second.id(), "token": token });
+        send(
+            &mut client,
+            json!({ "id": "switch", "type": "Authenticate", "data": data }),
+        )
+        .await;
+        let reply = recv_type(&mut client, "authenticate").await;
+        assert_eq!(reply["id"], "switch");
+        assert_eq!(reply["data"]["roomId"], second.id());
+        let event = recv_type(&mut observer, "userLeft").await;
+        assert_eq!(event["data"]["id"], "user");
+        assert!(first.users().get("user").await.is_none());
+
+        // The usual transports flow follows, on the same connection
+        let data = json!({ "rtpCapabilities": rtp_capabilities() });
+        send(
+            &mut client,
+            json!({ "id": "init", "type": "InitializeTransports", "data": data }),
+        )
+        .await;
+        recv_type(&mut client, "initializeTransports").await;
+        send(&mut client, json!({ "id": "info", "type": "RoomInfo" })).await;
+        recv_type(&mut client, "roomInfo").await;
+        first.delete().await;
+        second.delete().await;
+    }
+
+    #[tokio::test]
+    async fn commands_wait_for_transports() {
+        let room = testing::room(RoomSettings::default()).await;
+        let token = room
+            .users()
+            .create("user".to_string(), UserOptions::default())
+            .await
+            .unwrap()
+            .token;
+        let mut client = warp::test::ws().handshake(mock_route()).await.unwrap();
+
+        let data = json!({ "roomId": room.id(), "token": token });
+        send(
+            &mut client,
+            json!({ "id": "auth", "type": "Authenticate", "data": data }),
+        )
+        .await;
+        recv_type(&mut client, "authenticate").await;
+        // Reading the room is harmless, anything else closes the connection naming it
+        send(&mut client, json!({ "id": "info", "type": "RoomInfo" })).await;
+        let reply = recv_type(&mut client, "roomInfo").await;
+        assert_eq!(reply["id"], "info");
+        assert!(reply["data"]["users"].get("user").is_some());
+
+        let data = json!({ "produceType": "audio", "rtpParameters": audio_parameters(1111) });
+        send(
+            &mut client,
+            json!({ "id": "produce", "type": "StartProduce", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut client, "StartProduce").await;
+        assert_eq!(error["code"], 7006);
+        assert_eq!(error["detail"], "awaiting-transports");
+        let (code, reason) = recv_close(&mut client).await;
+        assert_eq!(code, 1002);
+        assert_eq!(
+            reason,
+            "StartProduce can't be sent while the connection is awaiting-transports"
+        );
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn reports_reach_moderators_only() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+        let mut troll = join(&room, "troll", Role::Speaker).await;
+
+        let data = json!({ "userId": "nobody", "reason": "spam" });
+        send(
+            &mut guest,
+            json!({ "id": "report", "type": "ReportUser", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "ReportUser").await;
+        assert_eq!(error["code"], 1000);
+
+        let data = json!({ "userId": "troll", "reason": "spam" });
+        let report = json!({ "id": "report", "type": "ReportUser", "data": data });
+        send(&mut guest, report.clone()).await;
+        let reply = recv_type(&mut guest, "reportUser").await;
+        let report_id = reply["data"]["reportId"].clone();
+        assert!(report_id.is_string());
+        let event = recv_type(&mut host, "userReported").await;
+        assert_eq!(
+            event["data"],
+            json!({ "id": report_id, "reporter": "guest", "target": "troll", "reason": "spam" })
+        );
+
+        // Nobody else hears of it, the reported user least of all
+        send(&mut troll, json!({ "id": "info", "type": "RoomInfo" })).await;
+        loop {
+            let message = recv(&mut troll).await;
+            let message: serde_json::Value =
+                serde_json::from_str(message.to_str().unwrap()).unwrap();
+            assert_ne!(message["type"], "userReported");
+            if message["type"] == "roomInfo" {
+                break;
+            }
+        }
+
+        // A few in a row, then the reporter has to wait
+        for _ in 0..2 {
+            send(&mut guest, report.clone()).await;
+            recv_type(&mut guest, "reportUser").await;
+        }
+        send(&mut guest, report).await;
+        let error = recv_type(&mut guest, "ReportUser").await;
+        assert_eq!(error["code"], 5003);
+        assert!(error["retry_after_ms"].as_u64().unwrap() > 0);
+        drop((host, guest, troll));
+        room.delete().await;
+    }
+
+    #[tokio::test]
+    async fn kicked_users_are_told_why() {
+        let room = testing::room(RoomSettings::default()).await;
+        let mut host = join(&room, "host", Role::Moderator).await;
+        let mut guest = join(&room, "guest", Role::Speaker).await;
+
+        let data = json!({ "userId": "host" });
+        send(
+            &mut guest,
+            json!({ "id": "kick", "type": "Kick", "data": data }),
+        )
+        .await;
+        let error = recv_type(&mut guest, "Kick").await;
+        assert_eq!(error["code"], 1002);
+
+        let data = json!({ "userId": "guest", "reason": "spam" });
+        send(
+            &mut host,
+            json!({ "id": "kick", "type": "Kick", "data": data }),
+        )
+        .await;
+        recv_type(&mut host, "kick").await;
+
+        let event = recv_type(&mut guest, "kicked").await;
+        assert_eq!(event["data"]["reason"], "spam");
+        assert_eq!(recv_close(&mut guest).await, (4003, "spam".to_string()));
+        let event = recv_type(&mut host, "userLeft").await;
+        assert_eq!(event["data"]["id"], "guest");
+        drop(host);
+        room.delete().await;
     }
 }
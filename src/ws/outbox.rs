@@ -0,0 +1,362 @@
+//! Messages waiting to be written to a connection. A task of its own writes them, so a
+//! client slow to read holds up neither its commands nor the room events it's sent.
+//! Bursts of membership events, such as a large room filling up, are written together
+//! in a frame carrying an array of them to clients that declared `eventBatches`
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use futures::SinkExt;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::task::JoinHandle;
+use warp::ws::Message;
+
+use super::error::WSCloseType;
+use super::WSSink;
+use crate::util::config::CONFIG;
+use crate::util::metrics::COMMAND_SECONDS;
+
+/// Messages a connection may have waiting to be written
+pub const OUTBOX_SIZE: usize = 256;
+/// Waiting messages past which low priority events are dropped, leaving the rest of the
+/// outbox to replies and the events clients rely on
+pub const LOW_PRIORITY_LIMIT: usize = OUTBOX_SIZE / 2;
+/// How long a message may wait for room before the client is disconnected as too slow
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Command a reply answers and when the frame carrying it was read, the reply is timed
+/// until it's written
+#[derive(Clone, Copy, Debug)]
+pub struct ReplyTiming {
+    pub command: &'static str,
+    pub received: Instant,
+}
+
+/// Message waiting in the outbox
+pub struct Outgoing {
+    pub message: Message,
+    timing: Option<ReplyTiming>,
+    /// Event that may be held back and written along with the rest of its burst
+    batchable: bool,
+}
+
+/// Events of a burst held back past the first `threshold` of a tick, written as a single
+/// frame once the tick is over or before anything that isn't part of the burst
+struct Burst {
+    threshold: usize,
+    tick: Duration,
+    /// Start of the current tick and the events counted in it
+    started: Instant,
+    counted: usize,
+    held: Vec<String>,
+}
+
+impl Burst {
+    fn new(threshold: usize, tick: Duration) -> Self {
+        Burst {
+            threshold,
+            tick,
+            started: Instant::now(),
+            counted: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Count an event of the burst, handing it back to be written unless it's held. A
+    /// threshold of 0 holds none
+    fn hold(&mut self, message: Message, now: Instant) -> Option<Message> {
+        if self.threshold == 0 {
+            return Some(message);
+        }
+        if now >= self.started + self.tick {
+            self.started = now;
+            self.counted = 0;
+        }
+
+        self.counted += 1;
+        if self.counted <= self.threshold {
+            return Some(message);
+        }
+        match message.to_str() {
+            Ok(text) => {
+                self.held.push(text.to_string());
+                None
+            }
+            Err(_) => Some(message),
+        }
+    }
+
+    /// When the held events are to be written, at the end of the tick
+    fn due(&self) -> Option<Instant> {
+        match self.held.is_empty() {
+            true => None,
+            false => Some(self.started + self.tick),
+        }
+    }
+
+    /// The held events as an array in a single frame
+    fn flush(&mut self) -> Option<Message> {
+        if self.held.is_empty() {
+            return None;
+        }
+        let frame = format!("[{}]", self.held.join(","));
+        self.held.clear();
+        Some(Message::text(frame))
+    }
+}
+
+/// Sending half, cheap to clone into anything that sends to the connection
+#[derive(Clone)]
+pub struct Outbox {
+    sender: Sender<Outgoing>,
+    /// Messages in the channel, which it doesn't report itself
+    waiting: Arc<AtomicUsize>,
+    /// Set once the connection is closed as too slow, what's still waiting is dropped
+    abandoned: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    /// An outbox and the receiving end of its messages
+    pub fn channel() -> (Outbox, Receiver<Outgoing>) {
+        let (sender, receiver) = mpsc::channel(OUTBOX_SIZE);
+        let outbox = Outbox {
+            sender,
+            waiting: Arc::new(AtomicUsize::new(0)),
+            abandoned: Arc::new(AtomicBool::new(false)),
+        };
+        (outbox, receiver)
+    }
+
+    /// Write the messages of a new outbox to `sink`. Once every clone of the outbox is
+    /// dropped and its messages written, the task hands the sink back for the close frame,
+    /// unless writing failed
+    pub fn start(mut sink: WSSink) -> (Outbox, JoinHandle<Option<WSSink>>) {
+        let (outbox, mut receiver) = Outbox::channel();
+        let waiting = outbox.waiting.clone();
+        let abandoned = outbox.abandoned.clone();
+        let signaling = &CONFIG.signaling;
+        let mut burst = Burst::new(
+            signaling.event_batch_threshold,
+            Duration::from_millis(signaling.event_batch_ms),
+        );
+        let writer = tokio::spawn(async move {
+            loop {
+                let outgoing = match burst.due() {
+                    Some(due) => tokio::select! {
+                        _ = tokio::time::sleep_until(due.into()) => None,
+                        outgoing = receiver.recv() => Some(outgoing),
+                    },
+                    None => Some(receiver.recv().await),
+                };
+                let Outgoing {
+                    message,
+                    timing,
+                    batchable,
+                } = match outgoing {
+                    // The tick is over, the events held since are written
+                    None => {
+                        if let Some(frame) = burst.flush() {
+                            if sink.send(frame).await.is_err() {
+                                return None;
+                            }
+                        }
+                        continue;
+                    }
+                    Some(Some(outgoing)) => outgoing,
+                    Some(None) => break,
+                };
+                waiting.fetch_sub(1, Ordering::Relaxed);
+                if abandoned.load(Ordering::Relaxed) {
+                    return Some(sink);
+                }
+
+                // Held events go first, whatever comes after them
+                let now = Instant::now();
+                if !batchable || burst.due().map_or(false, |due| due <= now) {
+                    if let Some(frame) = burst.flush() {
+                        if sink.send(frame).await.is_err() {
+                            return None;
+                        }
+                    }
+                }
+                let message = match batchable {
+                    true => match burst.hold(message, now) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    false => message,
+                };
+                if sink.send(message).await.is_err() {
+                    return None;
+                }
+                if let Some(ReplyTiming { command, received }) = timing {
+                    COMMAND_SECONDS
+                        .with_label_values(&[command])
+                        .observe(received.elapsed().as_secs_f64());
+                }
+            }
+
+            if let Some(frame) = burst.flush() {
+                if sink.send(frame).await.is_err() {
+                    return None;
+                }
+            }
+            Some(sink)
+        });
+        (outbox, writer)
+    }
+
+    /// Queue a reply or an event the client can't do without, waiting for room up to
+    /// `FLUSH_TIMEOUT`
+    pub async fn send(&self, message: Message) -> Result<(), WSCloseType> {
+        self.send_reply(message, None).await
+    }
+
+    /// Queue the reply to a command like `send`, timing it from the frame the command came
+    /// in until it's written
+    pub async fn send_reply(
+        &self,
+        message: Message,
+        timing: Option<ReplyTiming>,
+    ) -> Result<(), WSCloseType> {
+        self.queue(Outgoing {
+            message,
+            timing,
+            batchable: false,
+        })
+        .await
+    }
+
+    /// Queue an event the client can't do without like `send`. A batchable one may be
+    /// held back while a burst of them lasts
+    pub async fn send_event(&self, message: Message, batchable: bool) -> Result<(), WSCloseType> {
+        self.queue(Outgoing {
+            message,
+            timing: None,
+            batchable,
+        })
+        .await
+    }
+
+    async fn queue(&self, outgoing: Outgoing) -> Result<(), WSCloseType> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let result = match tokio::time::timeout(FLUSH_TIMEOUT, self.sender.send(outgoing)).await {
+            Ok(Ok(())) => return Ok(()),
+            // The writer stopped, the connection is gone
+            Ok(Err(_)) => Err(WSCloseType::ServerError),
+            Err(_) => Err(WSCloseType::SlowConsumer),
+        };
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Whether a low priority event would be queued rather than dropped
+    pub fn accepts_low_priority(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) < LOW_PRIORITY_LIMIT
+    }
+
+    /// Queue an event the client can do without, returning whether it was queued
+    pub fn send_low_priority(&self, message: Message) -> Result<bool, WSCloseType> {
+        if !self.accepts_low_priority() {
+            return Ok(false);
+        }
+
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(Outgoing {
+            message,
+            timing: None,
+            batchable: false,
+        }) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => {
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+                Err(WSCloseType::ServerError)
+            }
+        }
+    }
+
+    /// Drop what's still waiting instead of writing it, for a connection closed as too slow
+    pub fn abandon(&self) {
+        self.abandoned.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn low_priority_gives_way() {
+        let (outbox, mut receiver) = Outbox::channel();
+        for _ in 0..LOW_PRIORITY_LIMIT {
+            assert!(outbox.send_low_priority(Message::text("stats")).unwrap());
+        }
+        assert!(!outbox.accepts_low_priority());
+        assert!(!outbox.send_low_priority(Message::text("stats")).unwrap());
+
+        // Replies still fit in the rest of the outbox
+        for _ in LOW_PRIORITY_LIMIT..OUTBOX_SIZE {
+            outbox.send(Message::text("reply")).await.unwrap();
+        }
+
+        drop(receiver);
+        assert!(matches!(
+            outbox.send(Message::text("reply")).await,
+            Err(WSCloseType::ServerError)
+        ));
+    }
+
+    #[test]
+    fn bursts_past_the_threshold_are_held() {
+        let tick = Duration::from_millis(100);
+        let mut burst = Burst::new(2, tick);
+        let start = Instant::now();
+        let event = |n: u32| Message::text(format!("{{\"seq\":{}}}", n));
+
+        assert!(burst.hold(event(1), start).is_some());
+        assert!(burst.hold(event(2), start).is_some());
+        assert!(burst.hold(event(3), start).is_none());
+        assert!(burst.hold(event(4), start).is_none());
+        assert_eq!(burst.due(), Some(burst.started + tick));
+
+        let frame = burst.flush().unwrap();
+        assert_eq!(frame.to_str().unwrap(), r#"[{"seq":3},{"seq":4}]"#);
+        assert!(burst.flush().is_none());
+
+        // The next tick starts over
+        assert!(burst.hold(event(5), start + tick).is_some());
+        assert_eq!(burst.due(), None);
+
+        let mut off = Burst::new(0, tick);
+        assert!((0..10).all(|n| off.hold(event(n), start).is_some()));
+    }
+
+    #[tokio::test]
+    async fn replies_are_timed_once_written() {
+        let sink: WSSink = Box::pin(futures::sink::drain().sink_map_err(|never| match never {}));
+        let timed = COMMAND_SECONDS.with_label_values(&["OutboxTest"]);
+        let before = timed.get_sample_count();
+        let (outbox, writer) = Outbox::start(sink);
+        let timing = ReplyTiming {
+            command: "OutboxTest",
+            received: Instant::now(),
+        };
+        outbox
+            .send_reply(Message::text("reply"), Some(timing))
+            .await
+            .unwrap();
+        // Events aren't timed
+        outbox.send(Message::text("event")).await.unwrap();
+
+        drop(outbox);
+        assert!(writer.await.unwrap().is_some());
+        assert_eq!(timed.get_sample_count(), before + 1);
+    }
+}
@@ -0,0 +1,65 @@
+//! Where a connection is in setting up its session, which decides the commands it takes.
+//! A command sent out of order closes the connection with `InvalidState`, naming the
+//! command and the phase so client developers needn't guess which step they skipped.
+//! Commands that only read what the server or room already has are answered instead
+
+use std::fmt::{self, Display};
+
+use super::types::WSCommandType;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Until the client sent `Authenticate`, or after a room switch was refused
+    AwaitingAuth,
+    /// Authenticated, until the client sent `InitializeTransports`
+    AwaitingTransports,
+    /// Commands go through the event loop
+    Established,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::AwaitingAuth => "awaiting-auth",
+            Phase::AwaitingTransports => "awaiting-transports",
+            Phase::Established => "established",
+        }
+    }
+
+    /// Whether a command that doesn't move the session along is answered during the
+    /// setup, rather than closing the connection. Established connections answer what
+    /// they take in the event loop
+    pub fn answers(self, command: &WSCommandType) -> bool {
+        match self {
+            Phase::AwaitingAuth => matches!(command, WSCommandType::ServerInfo),
+            Phase::AwaitingTransports => matches!(
+                command,
+                WSCommandType::ServerInfo
+                    | WSCommandType::RoomInfo(_)
+                    | WSCommandType::RoomInfoDelta { .. }
+            ),
+            Phase::Established => false,
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_answered_during_setup() {
+        let room_info = WSCommandType::RoomInfo(None);
+        assert!(Phase::AwaitingAuth.answers(&WSCommandType::ServerInfo));
+        assert!(!Phase::AwaitingAuth.answers(&room_info));
+        assert!(Phase::AwaitingTransports.answers(&room_info));
+        assert!(!Phase::AwaitingTransports.answers(&WSCommandType::Leave));
+        assert!(!Phase::Established.answers(&room_info));
+    }
+}
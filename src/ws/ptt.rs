@@ -0,0 +1,92 @@
+//! Push to talk enforced by the server, see `RoomSettings::ptt_max_unmute_ms`. Once a
+//! user's microphone is unmuted, its client has to keep sending `PttHeartbeat` while the
+//! button is held. Should they stop, as a client that crashed or lost focus with the
+//! button down would, the microphone is paused after the room's delay and everyone is
+//! told as if the user had muted itself
+
+use std::time::{Duration, Instant};
+
+/// Deadline of a connection's open microphone, only running while it's unmuted
+pub struct PushToTalk {
+    max_unmute: Duration,
+    mute_at: Option<Instant>,
+}
+
+impl PushToTalk {
+    pub fn new(max_unmute: Duration) -> Self {
+        PushToTalk {
+            max_unmute,
+            mute_at: None,
+        }
+    }
+
+    /// As the room is set up, `None` if it doesn't enforce push to talk
+    pub fn from_settings(max_unmute_ms: Option<u64>) -> Option<Self> {
+        Some(PushToTalk::new(Duration::from_millis(max_unmute_ms?)))
+    }
+
+    /// Catch up with whether the microphone is open, starting the timer as it was
+    /// unmuted and stopping it as it was muted. A microphone that stayed open keeps its
+    /// deadline
+    pub fn sync(&mut self, open: bool, now: Instant) {
+        match (open, self.mute_at) {
+            (true, None) => self.mute_at = Some(now + self.max_unmute),
+            (false, _) => self.mute_at = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// The button is still held, ignored while the microphone is muted
+    pub fn heartbeat(&mut self, now: Instant) {
+        if self.mute_at.is_some() {
+            self.mute_at = Some(now + self.max_unmute);
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.mute_at
+    }
+
+    /// Whether the microphone should be muted by `now`, the timer stops until it's
+    /// unmuted again
+    pub fn due(&mut self, now: Instant) -> bool {
+        match self.mute_at {
+            Some(mute_at) if mute_at <= now => {
+                self.mute_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_microphones_are_muted_without_heartbeats() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut ptt = PushToTalk::new(Duration::from_millis(1000));
+        ptt.heartbeat(at(0));
+        assert_eq!(ptt.next_deadline(), None);
+
+        ptt.sync(true, at(100));
+        ptt.sync(true, at(500));
+        assert_eq!(ptt.next_deadline(), Some(at(1100)));
+        ptt.heartbeat(at(900));
+        assert!(!ptt.due(at(1100)));
+        assert!(ptt.due(at(1900)));
+        assert_eq!(ptt.next_deadline(), None);
+
+        // Muting on its own stops the timer, unmuting starts it over
+        ptt.sync(true, at(2000));
+        ptt.sync(false, at(2500));
+        assert!(!ptt.due(at(5000)));
+        ptt.sync(true, at(5000));
+        assert_eq!(ptt.next_deadline(), Some(at(6000)));
+
+        assert!(PushToTalk::from_settings(None).is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use super::types::WSCommand;
+
+/// Command read off a connection, waiting for the ones before it to finish
+pub struct QueuedCommand {
+    pub command: WSCommand,
+    /// Frame the command arrived in, logged if its handler panics
+    pub frame: Arc<str>,
+}
+
+/// Commands of a connection that were read but not handled yet. Bounded, so a
+/// client sending faster than the server replies is refused rather than buffered
+pub struct CommandQueue {
+    pending: VecDeque<QueuedCommand>,
+    limit: usize,
+}
+
+impl CommandQueue {
+    pub fn new(limit: usize) -> Self {
+        CommandQueue {
+            pending: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Queue every command of a frame, or none of them if they don't all fit, in
+    /// which case they're handed back to be refused
+    pub fn admit(&mut self, commands: Vec<WSCommand>, frame: &str) -> Result<(), Vec<WSCommand>> {
+        if self.pending.len() + commands.len() > self.limit {
+            return Err(commands);
+        }
+
+        let frame: Arc<str> = Arc::from(frame);
+        self.pending
+            .extend(commands.into_iter().map(|command| QueuedCommand {
+                command,
+                frame: frame.clone(),
+            }));
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<QueuedCommand> {
+        self.pending.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consume(id: usize) -> WSCommand {
+        let frame = format!(
+            r#"{{ "id": "{}", "type": "StartConsume", "data": {{ "produceType": "video", "userId": "user" }} }}"#,
+            id
+        );
+        serde_json::from_str(&frame).unwrap()
+    }
+
+    #[test]
+    fn flood_is_refused_past_the_limit() {
+        let mut queue = CommandQueue::new(32);
+        let mut refused = 0;
+        for id in 0..10_000 {
+            if queue.admit(vec![consume(id)], "frame").is_err() {
+                refused += 1;
+            }
+
+            // Handled slower than they arrive
+            if id % 100 == 0 {
+                queue.pop();
+            }
+            assert!(queue.len() <= 32);
+        }
+
+        // Once full, a command only fits in for each one handled
+        assert_eq!(refused, 10_000 - 32 - 100);
+        assert_eq!(queue.len(), 32);
+    }
+
+    #[test]
+    fn batches_are_admitted_whole() {
+        let mut queue = CommandQueue::new(4);
+        queue
+            .admit(vec![consume(0), consume(1), consume(2)], "batch")
+            .unwrap();
+
+        let refused = queue
+            .admit(vec![consume(3), consume(4)], "batch")
+            .unwrap_err();
+        assert_eq!(refused.len(), 2);
+        assert_eq!(queue.len(), 3);
+
+        queue.admit(vec![consume(3)], "single").unwrap();
+        assert_eq!(queue.len(), 4);
+    }
+}
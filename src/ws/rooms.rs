@@ -0,0 +1,153 @@
+//! Rooms a connection joined with `JoinRoom` besides the one it authenticated into, for
+//! clients previewing several rooms at once. They're signal-only: the connection gets
+//! their events and can read and relay in them, but it has no transports there
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use super::error::WSCloseType;
+use super::register;
+use super::types::SignalingTransport;
+use crate::state::room::subscriber::{SubscriberMessage, SubscriberOptions, SubscriberSignal};
+use crate::state::room::{Room, RoomSubscriber};
+
+/// What the subscribers of the joined rooms received, along with the room's ID
+pub type JoinedReceiver = UnboundedReceiver<(String, SubscriberMessage)>;
+
+struct JoinedRoom {
+    room: Arc<Room>,
+    user_id: String,
+    /// Passes what the room's subscriber receives on to the connection
+    forward: JoinHandle<()>,
+}
+
+impl Drop for JoinedRoom {
+    fn drop(&mut self) {
+        self.forward.abort();
+    }
+}
+
+/// Cheap to clone into the commands of the connection, clones share the rooms
+#[derive(Clone)]
+pub struct JoinedRooms {
+    connection_id: u64,
+    signaling: SignalingTransport,
+    rooms: Arc<Mutex<HashMap<String, JoinedRoom>>>,
+    sender: UnboundedSender<(String, SubscriberMessage)>,
+}
+
+impl JoinedRooms {
+    pub fn new(connection_id: u64, signaling: SignalingTransport) -> (Self, JoinedReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let rooms = JoinedRooms {
+            connection_id,
+            signaling,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            sender,
+        };
+        (rooms, receiver)
+    }
+
+    /// Register the user `token` belongs to in the tenant's room and subscribe to it,
+    /// returning the room and the user's ID in it. `None` if the token was refused or the
+    /// room closed
+    pub async fn join(
+        &self,
+        tenant: &str,
+        room_id: &str,
+        token: &str,
+    ) -> Option<(Arc<Room>, String)> {
+        let registered = register(tenant, room_id, token, self.connection_id)
+            .await
+            .ok()?;
+        let room = registered.room;
+        let user_id = registered.user_id;
+        let options = SubscriberOptions {
+            signaling: self.signaling,
+        };
+        let subscriber = match room.subscribe(self.connection_id, &user_id, options) {
+            Some(subscriber) => subscriber,
+            None => {
+                room.users().disconnect(&user_id, self.connection_id).await;
+                return None;
+            }
+        };
+        if let Some(replaced) = registered.replaced {
+            let signal = SubscriberSignal::Close(WSCloseType::SessionReplaced);
+            room.signal_subscriber(replaced, signal);
+        }
+
+        let forward = forward(room_id.to_string(), subscriber, self.sender.clone());
+        let joined = JoinedRoom {
+            room: room.clone(),
+            user_id: user_id.clone(),
+            forward,
+        };
+        self.rooms.lock().await.insert(room_id.to_string(), joined);
+        Some((room, user_id))
+    }
+
+    pub async fn contains(&self, room_id: &str) -> bool {
+        self.rooms.lock().await.contains_key(room_id)
+    }
+
+    /// The joined room and the user's ID in it
+    pub async fn get(&self, room_id: &str) -> Option<(Arc<Room>, String)> {
+        let rooms = self.rooms.lock().await;
+        let joined = rooms.get(room_id)?;
+        Some((joined.room.clone(), joined.user_id.clone()))
+    }
+
+    /// Unsubscribe from the room and disconnect the user from it, `false` if it wasn't joined
+    pub async fn leave(&self, room_id: &str) -> bool {
+        let joined = self.rooms.lock().await.remove(room_id);
+        match joined {
+            Some(joined) => {
+                self.disconnect(joined).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Leave every joined room, once the connection is closed
+    pub async fn leave_all(&self) {
+        let joined: Vec<JoinedRoom> = {
+            let mut rooms = self.rooms.lock().await;
+            rooms.drain().map(|(_, joined)| joined).collect()
+        };
+        for joined in joined {
+            self.disconnect(joined).await;
+        }
+    }
+
+    async fn disconnect(&self, joined: JoinedRoom) {
+        joined.forward.abort();
+        joined
+            .room
+            .users()
+            .disconnect(&joined.user_id, self.connection_id)
+            .await;
+    }
+}
+
+/// Hand what the subscriber receives to the connection until it's closed
+fn forward(
+    room_id: String,
+    mut subscriber: RoomSubscriber,
+    sender: UnboundedSender<(String, SubscriberMessage)>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let message = subscriber.recv().await;
+            let closed = matches!(message, SubscriberMessage::Close(_));
+            if sender.send((room_id.clone(), message)).is_err() || closed {
+                break;
+            }
+        }
+    })
+}
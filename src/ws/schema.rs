@@ -0,0 +1,110 @@
+//! JSON Schema of the signaling protocol, for client SDKs to generate their models from
+//! rather than reading the server's source. Served on `GET /schema` and written to a file
+//! with `vortex --write-schema`, `schema.json` at the root of the repository is kept up to
+//! date with it. mediasoup's own types, such as RTP parameters, are left open, clients
+//! take them from mediasoup-client
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use schemars::gen::SchemaSettings;
+use schemars::schema::{Metadata, RootSchema, SchemaObject, SubschemaValidation};
+
+use super::error::WSError;
+use super::types::{SequencedEvent, WSCommand, WSEvent, WSReply, PROTOCOL_VERSION};
+use crate::state::room::RoomSettings;
+use crate::state::user::ProduceType;
+
+/// Where `--write-schema` writes the schema when not given a path
+pub const SCHEMA_PATH: &str = "schema.json";
+
+lazy_static! {
+    pub static ref SCHEMA: String = text();
+}
+
+/// The schema of every frame sent on a connection, with the types they're made of as its
+/// definitions
+pub fn generate() -> RootSchema {
+    let mut generator = SchemaSettings::draft07().into_generator();
+    let frames = vec![
+        generator.subschema_for::<WSCommand>(),
+        generator.subschema_for::<WSReply>(),
+        generator.subschema_for::<SequencedEvent>(),
+        generator.subschema_for::<WSError<'static>>(),
+    ];
+    // Also found within the frames, named so SDKs can look them up
+    generator.subschema_for::<WSEvent>();
+    generator.subschema_for::<ProduceType>();
+    generator.subschema_for::<RoomSettings>();
+
+    let schema = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            title: Some("Vortex signaling protocol".to_string()),
+            description: Some(format!("Protocol version {}", PROTOCOL_VERSION)),
+            ..Default::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            one_of: Some(frames),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    RootSchema {
+        meta_schema: generator.settings().meta_schema.clone(),
+        schema,
+        definitions: generator.take_definitions(),
+    }
+}
+
+/// The schema as it's served and written
+pub fn text() -> String {
+    let mut text =
+        serde_json::to_string_pretty(&generate()).expect("Failed to serialize the schema");
+    text.push('\n');
+    text
+}
+
+pub fn write(path: impl AsRef<Path>) -> io::Result<()> {
+    fs::write(path, SCHEMA.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_is_up_to_date() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_PATH);
+        let written = fs::read_to_string(&path).unwrap_or_else(|err| {
+            panic!(
+                "Failed to read {}: {}, write it with `cargo run -- --write-schema` and commit it",
+                path.display(),
+                err
+            )
+        });
+        // Compared whole rather than with `assert_eq!`, printing both would bury the fix
+        assert!(
+            written == *SCHEMA,
+            "{} is out of date with the protocol types, regenerate it with \
+             `cargo run -- --write-schema` and commit it",
+            path.display()
+        );
+
+        let schema = generate();
+        for name in &[
+            "WSCommand",
+            "WSReply",
+            "WSEvent",
+            "WSError",
+            "ProduceType",
+            "RoomSettings",
+        ] {
+            assert!(
+                schema.definitions.contains_key(*name),
+                "{} is missing",
+                name
+            );
+        }
+    }
+}
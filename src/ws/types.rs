@@ -1,19 +1,80 @@
-use serde::{Deserialize, Serialize};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
-use strum::IntoStaticStr;
+use std::fmt::{self, Display};
+use std::time::Instant;
+use strum::{EnumVariantNames, IntoStaticStr, VariantNames};
 
+use mediasoup::data_structures::{DtlsState, IceState};
 use mediasoup::rtp_parameters::{MediaKind, RtpCapabilitiesFinalized, RtpParameters};
 
-use crate::rtc::types::{ConnectTransportData, InitializationInput, TransportInitData};
-use crate::state::user::{ProduceType, UserInfo};
+use super::error::InlineError;
+use crate::info::ServerInfo;
+use crate::rtc::audio_level::SpeakerVolume;
+use crate::rtc::broadcast::BroadcastState;
+use crate::rtc::dump::{Dump, DumpTarget};
+use crate::rtc::opus::OpusOptions;
+use crate::rtc::quality::QualityLevel;
+use crate::rtc::stats::ConnectionStats;
+use crate::rtc::types::{
+    ConnectTransportData, ConsumerSummary, IceServer, InitializationInput, TransportInitData,
+};
+use crate::state::room::retransmissions::AlertCounter;
+use crate::state::room::{
+    CallSummary, Position, RetransmissionCounts, RoomMetadata, RoomSettings, RoomSettingsUpdate,
+    RoomSummary, TalkStats, UserPosition,
+};
+use crate::state::user::{ConnectionState, ProduceType, Role, UserInfo, UserInfoUpdate};
+use crate::util::config::QualityProfile;
+use crate::util::ulid;
 
-#[derive(Deserialize, IntoStaticStr)]
+/// Most commands a single text frame may carry
+pub const MAX_BATCH_SIZE: usize = 64;
+/// Longest trace ID a client may pick, longer ones are replaced with a generated one
+pub const MAX_TRACE_ID: usize = 64;
+/// Most users a page of `RoomInfo` lists
+pub const MAX_ROOM_INFO_PAGE: usize = 500;
+/// Oldest protocol version still spoken, assumed for clients that don't pick one
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Latest protocol version. 2 moves consumers of replaced producers server side, see
+/// `WSEvent::UserProducerReplaced`
+pub const PROTOCOL_VERSION: u32 = 2;
+
+fn default_protocol_version() -> u32 {
+    MIN_PROTOCOL_VERSION
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, IntoStaticStr, EnumVariantNames)]
 #[serde(tag = "type", content = "data")]
 pub enum WSCommandType {
     #[serde(rename_all = "camelCase")]
     Authenticate {
         room_id: String,
+        /// Tenant the room belongs to, the default tenant if absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
         token: String,
+        /// Receive `ProducerScore` and `ConsumerScore` events
+        #[serde(default)]
+        scores: bool,
+        /// Echo the data of failing commands in their errors, for matching them up
+        /// while developing a client
+        #[serde(default)]
+        debug: bool,
+        /// Protocol version the client speaks, events it doesn't know are sent the way
+        /// older versions expect them
+        #[serde(default = "default_protocol_version")]
+        version: u32,
+        /// Optional parts of the protocol the client handles, see `Capability`. Clients
+        /// that don't send the list get all of them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Vec<String>>,
+        /// Categories of events the connection is sent, see `EventCategory`. Clients that
+        /// don't send the list get all of them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscriptions: Option<Vec<String>>,
     },
 
     InitializeTransports {
@@ -25,23 +86,70 @@ pub enum WSCommandType {
         connect_data: ConnectTransportData,
     },
 
-    RoomInfo,
+    /// Users of the room along with its state. Every user at once without a page, as
+    /// older clients send it
+    RoomInfo(Option<RoomInfoPage>),
+    /// Users that joined, left or changed since the `revision` of an earlier `RoomInfo`
+    /// or `RoomInfoDelta`, for large rooms where listing everyone again is costly
+    RoomInfoDelta {
+        since: u64,
+    },
+    /// Everything needed to rebuild the client's view of the room, sent when it
+    /// notices a gap in event sequence numbers
+    SyncState,
+    /// Version, features and limits of the server. Answered before `Authenticate` too
+    ServerInfo,
 
     #[serde(rename_all = "camelCase")]
     StartProduce {
         produce_type: ProduceType,
+        #[schemars(with = "serde_json::Value")]
         rtp_parameters: RtpParameters,
+        /// Only for audio, clamped to the room's limits
+        #[serde(default)]
+        opus: Option<OpusOptions>,
+        /// Start muted, until resumed with `SetProducerPause`
+        #[serde(default)]
+        paused: bool,
     },
     #[serde(rename_all = "camelCase")]
     StopProduce {
         produce_type: ProduceType,
     },
+    /// Mute or unmute a producer, consumers of it stay open
+    #[serde(rename_all = "camelCase")]
+    SetProducerPause {
+        produce_type: ProduceType,
+        paused: bool,
+    },
+    /// Pause the producers of a type of everyone else in the room, except users with the
+    /// `bypass_mute` permission. Moderators only
+    #[serde(rename_all = "camelCase")]
+    MuteAll {
+        produce_type: ProduceType,
+    },
+    /// Swap the producer of a type for a new one, such as when switching cameras,
+    /// without consumers of it going through a stop and start
+    #[serde(rename_all = "camelCase")]
+    ReplaceProduce {
+        produce_type: ProduceType,
+        #[schemars(with = "serde_json::Value")]
+        rtp_parameters: RtpParameters,
+        #[serde(default)]
+        opus: Option<OpusOptions>,
+    },
 
     #[serde(rename_all = "camelCase")]
     StartConsume {
         produce_type: ProduceType,
         user_id: String,
     },
+    /// Consume every producer of a user at once, such as when they come into view. A
+    /// producer that fails is reported in its entry without failing the others
+    #[serde(rename_all = "camelCase")]
+    ConsumeUser {
+        user_id: String,
+    },
     StopConsume {
         /// Consumer ID
         id: String,
@@ -51,16 +159,399 @@ pub enum WSCommandType {
         id: String,
         paused: bool,
     },
+    /// Start a paused consumer once the client's receiver is wired up, asking for a keyframe
+    /// for video so it doesn't wait for the next one. Before the receiving transport's DTLS
+    /// connected it's only queued, see `WSEvent::ConsumersActivated`
+    #[serde(rename_all = "camelCase")]
+    ResumeConsumer {
+        consumer_id: String,
+    },
+    /// How strongly a consumer is protected when downstream bandwidth runs short, 1 to 255
+    #[serde(rename_all = "camelCase")]
+    SetConsumerPriority {
+        consumer_id: String,
+        priority: u32,
+    },
+
+    /// Most layers forwarded to a video consumer, the temporal layer defaults to the
+    /// highest of the spatial layer
+    #[serde(rename_all = "camelCase")]
+    SetConsumerLayers {
+        consumer_id: String,
+        spatial_layer: u8,
+        #[serde(default)]
+        temporal_layer: Option<u8>,
+    },
+    /// Let the server adapt a video consumer's layers to the available bandwidth, on by
+    /// default. Turned off, the layers chosen with `SetConsumerLayers` are kept
+    #[serde(rename_all = "camelCase")]
+    SetConsumerAutoLayers {
+        consumer_id: String,
+        enabled: bool,
+    },
+
+    /// Have the stats of the connection's transports, producers and consumers pushed
+    /// as `Stats` events, at least a second apart
+    #[serde(rename_all = "camelCase")]
+    SubscribeStats {
+        interval_ms: u64,
+    },
+    UnsubscribeStats,
+
+    /// What the server holds for the connection, to reconcile the client's own bookkeeping
+    /// with after a `Resync` or resuming the session
+    ListProducers,
+    ListConsumers,
+
+    /// Ask for a keyframe after the decoder lost sync, instead of waiting for the next
+    /// one. Repeated requests for a consumer within a second are acknowledged but dropped
+    #[serde(rename_all = "camelCase")]
+    RequestKeyFrame {
+        consumer_id: String,
+    },
+
+    SetUserInfo {
+        info: UserInfoUpdate,
+    },
+
+    /// Where the user is in a spatial audio room, sent a few times a second while it
+    /// moves. Everyone's positions are relayed together as `Positions` events
+    UpdatePosition {
+        #[serde(flatten)]
+        position: Position,
+    },
+
+    /// Pass an application message (reactions, raised hands) on to every other
+    /// participant, the server doesn't look into it
+    RelayBroadcast {
+        payload: serde_json::Value,
+    },
+    /// Pass an application message on to a single participant connected to this node,
+    /// e.g. key exchange material
+    #[serde(rename_all = "camelCase")]
+    RelayDirect {
+        user_id: String,
+        payload: serde_json::Value,
+    },
+    /// Start relaying a blob of `size` bytes, such as a small image, to a single
+    /// participant connected to this node, or to everyone else on it. Its chunks follow
+    /// with `RelayBlobChunk` and `RelayBlobEnd` completes it, recipients are sent each
+    /// part as it comes. Blobs aren't kept, participants joining later don't get them
+    #[serde(rename_all = "camelCase")]
+    RelayBlobStart {
+        #[serde(default)]
+        user_id: Option<String>,
+        size: usize,
+        mime: String,
+    },
+    /// Next chunk of a blob, base64 encoded, counting from 0. Sent in binary frames or
+    /// text like any other command, or as raw bytes in a binary frame, see
+    /// `ws::blob_frame`
+    #[serde(rename_all = "camelCase")]
+    RelayBlobChunk {
+        transfer_id: String,
+        seq: u64,
+        data: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    RelayBlobEnd {
+        transfer_id: String,
+    },
+
+    /// Let a listener speak, moderators only
+    #[serde(rename_all = "camelCase")]
+    PromoteUser {
+        user_id: String,
+    },
+    /// Make a speaker a listener, closing its producers, moderators only
+    #[serde(rename_all = "camelCase")]
+    DemoteUser {
+        user_id: String,
+    },
+    /// Hand the room to another user in it, which may then moderate it, moderators only
+    #[serde(rename_all = "camelCase")]
+    SetOwner {
+        user_id: String,
+    },
+    /// Unlock a room that started locked, letting everyone waiting in its lobby in,
+    /// moderators only
+    OpenRoom,
+    /// Let a user waiting in the lobby in while the room stays locked, moderators only
+    #[serde(rename_all = "camelCase")]
+    AdmitUser {
+        user_id: String,
+    },
+
+    /// Remove a user from the room, closing its connection with the reason given,
+    /// moderators only
+    #[serde(rename_all = "camelCase")]
+    Kick {
+        user_id: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+
+    /// Kick a user and refuse it from then on, moderators only. Lifted over the
+    /// management API
+    #[serde(rename_all = "camelCase")]
+    Ban {
+        user_id: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Tell the room's moderators about a user misbehaving, anyone may
+    #[serde(rename_all = "camelCase")]
+    ReportUser {
+        user_id: String,
+        reason: String,
+    },
+
+    /// Cap on the bitrate each participant may send, 0 removes it
+    SetMaxIncomingBitrate {
+        bitrate: u32,
+    },
+    /// Change the room's live settings, moderators only. Absent fields are left as they are
+    UpdateRoomSettings {
+        settings: RoomSettingsUpdate,
+    },
+    /// Set a key of the metadata everyone in the room sees, moderators only
+    SetRoomMetadata {
+        key: String,
+        value: serde_json::Value,
+    },
+    /// Delete a key of the room's metadata, moderators only. Deleting one that isn't set
+    /// succeeds
+    DeleteRoomMetadata {
+        key: String,
+    },
+    /// How everyone spoke so far in the call, moderators only
+    GetTalkStats,
+    /// Who joined, left or was kicked and what moderators did in the room lately, for
+    /// catching up on a call in progress. Moderators only, and only if the server keeps
+    /// recent events
+    #[serde(rename_all = "camelCase")]
+    GetRecentEvents {
+        since_seconds: u64,
+    },
+
+    StartRecording,
+    StopRecording,
+    /// Answer to `RecordingConsentRequested`. The user's producers are only recorded
+    /// once it granted consent, and no longer once it declined
+    RecordingConsent {
+        granted: bool,
+    },
+
+    /// mediasoup's internal state of the room's router, the connection's transports or a
+    /// producer or consumer, for debugging. Moderators only
+    Dump {
+        target: DumpTarget,
+    },
+
+    /// Also join another room over this connection, signal-only: its events come tagged
+    /// with its `roomId` and commands tagged with it are run in it, but the connection has
+    /// no transports there
+    #[serde(rename_all = "camelCase")]
+    JoinRoom {
+        room_id: String,
+        token: String,
+    },
+    /// Leave a room joined with `JoinRoom`, the others are left as they are
+    #[serde(rename_all = "camelCase")]
+    LeaveRoom {
+        room_id: String,
+    },
+
+    /// Prove the session is still authorized with a new token for the same user and room,
+    /// which pushes its expiry back to the new token's
+    RefreshToken {
+        token: String,
+    },
+    /// Leave the room for good, as a client hanging up does. The user is removed right
+    /// away rather than kept for the reconnect grace period, then the connection is closed
+    /// with a normal close frame once this is replied to
+    Leave,
+    /// The push to talk button is still held, keeping the microphone open in rooms that
+    /// enforce push to talk for another `pttMaxUnmuteMs`. Ignored elsewhere
+    PttHeartbeat,
+    /// Replace the categories of events the connection is sent, for every room it's in
+    SetSubscriptions {
+        subscriptions: Vec<String>,
+    },
+
+    /// Commands run in order, each replied to on its own
+    Batch {
+        commands: Vec<WSCommand>,
+    },
+
+    /// A type this server doesn't know, replied to with `WSErrorType::UnknownCommand`
+    /// so newer clients can probe for features. Never sent by clients under this name
+    #[serde(skip)]
+    Unknown(String),
+}
+
+impl WSCommandType {
+    /// Capability the client must have declared to send the command
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            WSCommandType::RelayBroadcast { .. }
+            | WSCommandType::RelayDirect { .. }
+            | WSCommandType::RelayBlobStart { .. }
+            | WSCommandType::RelayBlobChunk { .. }
+            | WSCommandType::RelayBlobEnd { .. } => Some(Capability::Relay),
+            WSCommandType::SubscribeStats { .. } | WSCommandType::UnsubscribeStats => {
+                Some(Capability::Stats)
+            }
+            WSCommandType::SetConsumerLayers { .. }
+            | WSCommandType::SetConsumerAutoLayers { .. } => Some(Capability::SimulcastLayers),
+            WSCommandType::JoinRoom { .. } | WSCommandType::LeaveRoom { .. } => {
+                Some(Capability::MultiRoom)
+            }
+            WSCommandType::UpdatePosition { .. } => Some(Capability::Positions),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Deserialize)]
 pub struct WSCommand {
     pub id: Option<String>,
-    #[serde(flatten)]
+    /// Ties the command's logs and errors to a client report. The client's own, its
+    /// command ID, or a ULID when it sent neither
+    pub trace_id: String,
     pub command_type: WSCommandType,
+    /// Room joined with `JoinRoom` the command is for, the one the connection
+    /// authenticated into when absent
+    pub room_id: Option<String>,
+    /// `data` as the client sent it, echoed in the command's error for debugging clients
+    pub data: Option<serde_json::Value>,
+    /// When the frame carrying the command was read, its reply is timed from then
+    pub received: Option<Instant>,
 }
 
-#[derive(Serialize)]
+/// Command as a client sends it, also what the schema of `WSCommand` describes
+#[derive(Deserialize, JsonSchema)]
+struct RawCommand {
+    id: Option<String>,
+    #[serde(rename = "traceId")]
+    trace_id: Option<String>,
+    #[serde(rename = "roomId")]
+    room_id: Option<String>,
+    #[serde(flatten)]
+    command_type: WSCommandType,
+}
+
+/// What's read of a command whose type isn't known, to reply to it
+#[derive(Deserialize)]
+struct CommandEnvelope {
+    id: Option<String>,
+    #[serde(rename = "traceId")]
+    trace_id: Option<String>,
+    #[serde(rename = "roomId")]
+    room_id: Option<String>,
+    #[serde(rename = "type")]
+    command_type: String,
+}
+
+impl WSCommand {
+    /// Command as a client sends it, traced by its ID
+    pub fn new(id: String, command_type: WSCommandType) -> Self {
+        WSCommand {
+            trace_id: id.clone(),
+            id: Some(id),
+            command_type,
+            room_id: None,
+            data: None,
+            received: None,
+        }
+    }
+
+    /// Bytes `data` takes once serialized, 0 without any
+    pub fn data_size(&self) -> usize {
+        self.data.as_ref().map_or(0, |data| {
+            serde_json::to_vec(data).map_or(usize::MAX, |bytes| bytes.len())
+        })
+    }
+}
+
+/// The command as a client sends it, for clients built on these types like `vortex-bench`.
+/// `data` and `received` are only known to the server
+impl Serialize for WSCommand {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct SentCommand<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            id: Option<&'a String>,
+            trace_id: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            room_id: Option<&'a String>,
+            #[serde(flatten)]
+            command_type: &'a WSCommandType,
+        }
+
+        SentCommand {
+            id: self.id.as_ref(),
+            trace_id: &self.trace_id,
+            room_id: self.room_id.as_ref(),
+            command_type: &self.command_type,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl JsonSchema for WSCommand {
+    fn schema_name() -> String {
+        "WSCommand".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RawCommand::json_schema(gen)
+    }
+}
+
+impl<'de> Deserialize<'de> for WSCommand {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let data = value.get("data").cloned();
+        let RawCommand {
+            id,
+            trace_id,
+            room_id,
+            command_type,
+        } = match RawCommand::deserialize(&value) {
+            Ok(raw) => raw,
+            // A type that isn't known is replied to, one that is but doesn't parse is
+            // malformed
+            Err(err) => match CommandEnvelope::deserialize(&value) {
+                Ok(unknown)
+                    if !WSCommandType::VARIANTS.contains(&unknown.command_type.as_str()) =>
+                {
+                    RawCommand {
+                        id: unknown.id,
+                        trace_id: unknown.trace_id,
+                        room_id: unknown.room_id,
+                        command_type: WSCommandType::Unknown(unknown.command_type),
+                    }
+                }
+                _ => return Err(de::Error::custom(err)),
+            },
+        };
+        let trace_id = trace_id
+            .or_else(|| id.clone())
+            .filter(|trace_id| trace_id.len() <= MAX_TRACE_ID)
+            .unwrap_or_else(ulid::generate);
+        Ok(WSCommand {
+            id,
+            trace_id,
+            command_type,
+            room_id,
+            data,
+            received: None,
+        })
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "camelCase")]
 pub enum WSReplyType {
@@ -68,7 +559,30 @@ pub enum WSReplyType {
     Authenticate {
         user_id: String,
         room_id: String,
+        #[schemars(with = "serde_json::Value")]
         rtp_capabilities: RtpCapabilitiesFinalized,
+        /// Transport carrying the signaling connection, media always goes through RTC transports
+        signaling: SignalingTransport,
+        /// Protocol version of the connection, as the client asked for it
+        version: u32,
+        /// Every optional part of the protocol this server supports, those the client
+        /// didn't declare stay off for the connection
+        capabilities: Vec<Capability>,
+        /// Categories of events the connection is sent
+        subscriptions: Vec<EventCategory>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ice_servers: Option<Vec<IceServer>>,
+        /// Users already in the room and what they are producing
+        users: HashMap<String, UserInfo>,
+        /// The room is locked and the user waits in its lobby, where every command is
+        /// refused with `RoomLocked` until `RoomOpened` is sent
+        waiting: bool,
+        /// Feature flags of the room, `RoomSettingsChanged` carries them as they change
+        feature_flags: HashMap<String, bool>,
+        /// Opaque token to pass back as the `affinity` query parameter or
+        /// `X-Vortex-Affinity` header when reconnecting, multi-node deployments only
+        #[serde(skip_serializing_if = "Option::is_none")]
+        node_affinity: Option<String>,
     },
 
     InitializeTransports {
@@ -79,54 +593,1426 @@ pub enum WSReplyType {
 
     #[serde(rename_all = "camelCase")]
     RoomInfo {
-        id: String,
-        video_allowed: bool,
+        #[serde(flatten)]
+        snapshot: RoomSnapshot,
+        /// Served from a cached listing because the server is under load
+        stale: bool,
+        /// Revision of the listing, to ask for changes since with `RoomInfoDelta`
+        revision: u64,
+        /// Offset of the next page of a paged request, absent on the last one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_offset: Option<usize>,
+    },
+    #[serde(rename_all = "camelCase")]
+    RoomInfoDelta {
+        revision: u64,
+        /// The changes since weren't known, `users` lists everyone as `RoomInfo` would
+        reset: bool,
+        /// Users that joined or changed since
         users: HashMap<String, UserInfo>,
+        /// Users that left since
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<String>,
+    },
+    SyncState {
+        /// Sequence number of the last event sent before the snapshot was taken
+        seq: u64,
+        #[serde(flatten)]
+        snapshot: RoomSnapshot,
     },
+    ServerInfo(ServerInfo),
 
     #[serde(rename_all = "camelCase")]
     StartProduce {
         producer_id: String,
+        /// Consumer looping the audio back, in echo test rooms
+        #[serde(skip_serializing_if = "Option::is_none")]
+        echo: Option<EchoConsumer>,
+        /// Opus options the producer was created with, after the room's limits
+        #[serde(skip_serializing_if = "Option::is_none")]
+        opus: Option<OpusOptions>,
+        /// `maxBitrate` of each encoding once clamped, when the room caps the kind
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_bitrates: Option<Vec<u32>>,
     },
     StopProduce,
-
+    SetProducerPause,
+    /// Users whose producer was paused, and those whose producer failed to pause
+    MuteAll {
+        muted: usize,
+        failed: Vec<String>,
+    },
     #[serde(rename_all = "camelCase")]
-    StartConsume {
-        id: String,
+    ReplaceProduce {
         producer_id: String,
-        kind: MediaKind,
-        rtp_parameters: RtpParameters,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        echo: Option<EchoConsumer>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        opus: Option<OpusOptions>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_bitrates: Option<Vec<u32>>,
+    },
+
+    StartConsume(NewConsumer),
+    /// One entry per producer of the user, in the order of `ProduceType`
+    #[serde(rename_all = "camelCase")]
+    ConsumeUser {
+        user_id: String,
+        consumers: Vec<UserConsumer>,
     },
     StopConsume,
     SetConsumerPause,
+    ResumeConsumer,
+    SetConsumerPriority,
+    SetConsumerLayers,
+    SetConsumerAutoLayers,
+    /// Interval stats are pushed at, after clamping
+    #[serde(rename_all = "camelCase")]
+    SubscribeStats {
+        interval_ms: u64,
+    },
+    UnsubscribeStats,
+    ListProducers {
+        producers: Vec<ProducerSummary>,
+    },
+    ListConsumers {
+        consumers: Vec<ConsumerSummary>,
+    },
+    RequestKeyFrame,
+    SetUserInfo,
+    UpdatePosition,
+    RelayBroadcast,
+    RelayDirect,
+    #[serde(rename_all = "camelCase")]
+    RelayBlobStart {
+        transfer_id: String,
+    },
+    RelayBlobChunk,
+    RelayBlobEnd,
+    PromoteUser,
+    DemoteUser,
+    SetOwner,
+    OpenRoom,
+    AdmitUser,
+    Kick,
+    Ban,
+    /// ID of the report, for the client to refer to it
+    #[serde(rename_all = "camelCase")]
+    ReportUser {
+        report_id: String,
+    },
+    SetMaxIncomingBitrate,
+    UpdateRoomSettings,
+    SetRoomMetadata,
+    DeleteRoomMetadata,
+    GetTalkStats {
+        /// By user ID, users who didn't speak yet are absent
+        users: HashMap<String, TalkStats>,
+    },
+    GetRecentEvents {
+        /// Oldest first, as they were sent then
+        events: Vec<RecentEvent>,
+    },
+    StartRecording,
+    StopRecording,
+    RecordingConsent,
+    Dump {
+        #[serde(flatten)]
+        dump: Dump,
+    },
+    #[serde(rename_all = "camelCase")]
+    JoinRoom {
+        room_id: String,
+        user_id: String,
+        /// Users already in the room and what they are producing
+        users: HashMap<String, UserInfo>,
+    },
+    LeaveRoom,
+    /// The user was removed from the room, the connection closes next
+    Leave,
+    PttHeartbeat,
+    /// UNIX time in seconds the session's token expires now
+    #[serde(rename_all = "camelCase")]
+    RefreshToken {
+        expires_at: u64,
+    },
+    /// Categories of events the connection is sent now, those the server didn't know left out
+    SetSubscriptions {
+        subscriptions: Vec<EventCategory>,
+    },
 }
 
-#[derive(Serialize)]
+/// Consumer on the receiving transport of the client's own audio, closed along
+/// with the producer
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EchoConsumer {
+    pub id: String,
+    #[schemars(with = "String")]
+    pub kind: MediaKind,
+    #[schemars(with = "serde_json::Value")]
+    pub rtp_parameters: RtpParameters,
+    /// Resumed with `ResumeConsumer` like any other consumer
+    pub paused: bool,
+}
+
+/// Consumer created for the client by `StartConsume` or `ConsumeUser`
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NewConsumer {
+    pub id: String,
+    pub producer_id: String,
+    #[schemars(with = "String")]
+    pub kind: MediaKind,
+    #[schemars(with = "serde_json::Value")]
+    pub rtp_parameters: RtpParameters,
+    /// Layers the producer sends, such as "L3T3" for SVC or "S3T3" for simulcast.
+    /// Picked from with `SetConsumerLayers`, absent with a single layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scalability_mode: Option<String>,
+    /// Consumers start paused until resumed with `ResumeConsumer`, except fast joining
+    /// video and audio of clients initialized with `pauseConsumers` off
+    pub paused: bool,
+    pub priority: u8,
+    /// Whether the layers follow the bandwidth estimate, see `SetConsumerAutoLayers`
+    pub auto_layers: bool,
+}
+
+/// Outcome of consuming one producer for `ConsumeUser`: the consumer's fields, or the
+/// error that producer failed with while the others went ahead
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserConsumer {
+    #[serde(rename = "type")]
+    pub produce_type: ProduceType,
+    #[serde(flatten)]
+    pub consumer: Option<NewConsumer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<InlineError>,
+}
+
+/// Producer of the client's own user, as listed by `ListProducers`
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProducerSummary {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub produce_type: ProduceType,
+    #[schemars(with = "String")]
+    pub kind: MediaKind,
+    pub paused: bool,
+}
+
+/// Consumer of a replaced producer's successor, taking over from the one the client had
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplacedConsumer {
+    /// ID of the consumer it replaces, closed along with the old producer
+    pub replaces: String,
+    pub id: String,
+    pub producer_id: String,
+    #[schemars(with = "String")]
+    pub kind: MediaKind,
+    #[schemars(with = "serde_json::Value")]
+    pub rtp_parameters: RtpParameters,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scalability_mode: Option<String>,
+    pub paused: bool,
+    pub priority: u8,
+}
+
+/// Part of the room's users for `RoomInfo` to list, in the order of their IDs
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RoomInfoPage {
+    /// Users skipped
+    pub offset: usize,
+    /// Most users listed, up to `MAX_ROOM_INFO_PAGE`
+    pub limit: usize,
+}
+
+impl Default for RoomInfoPage {
+    fn default() -> Self {
+        RoomInfoPage {
+            offset: 0,
+            limit: MAX_ROOM_INFO_PAGE,
+        }
+    }
+}
+
+impl RoomInfoPage {
+    /// The users of the page, and the offset of the next one unless it's the last
+    pub fn apply(
+        &self,
+        users: HashMap<String, UserInfo>,
+    ) -> (HashMap<String, UserInfo>, Option<usize>) {
+        let limit = self.limit.clamp(1, MAX_ROOM_INFO_PAGE);
+        let total = users.len();
+        let mut users: Vec<(String, UserInfo)> = users.into_iter().collect();
+        users.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let page = users.into_iter().skip(self.offset).take(limit).collect();
+        let next = self.offset.saturating_add(limit);
+        (page, Some(next).filter(|next| *next < total))
+    }
+}
+
+/// State of the room as seen by its participants
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSnapshot {
+    pub id: String,
+    pub video_allowed: bool,
+    /// Users and the media they produce
+    pub users: HashMap<String, UserInfo>,
+    pub recording: bool,
+    /// Users joining wait in the lobby until the room is opened
+    pub locked: bool,
+    /// Users in the lobby in the order they arrived
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub waiting: Vec<String>,
+    /// Feature flags of the room, also found in its settings
+    pub feature_flags: HashMap<String, bool>,
+    #[serde(flatten)]
+    pub metadata: RoomMetadata,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug)]
+pub enum SignalingTransport {
+    #[serde(rename = "websocket")]
+    WebSocket,
+    #[serde(rename = "longpoll")]
+    LongPoll,
+}
+
+/// Optional part of the protocol, only used on connections whose client declared it
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    /// `ActiveSpeakers` events
+    ActiveSpeakers,
+    /// `RelayBroadcast`, `RelayDirect`, the blob relay commands and the `Relay` events
+    /// they send
+    Relay,
+    /// `SubscribeStats`, `UnsubscribeStats` and the `Stats` events
+    Stats,
+    /// `SetConsumerLayers`, `SetConsumerAutoLayers` and `ConsumerLayersChanged` events
+    SimulcastLayers,
+    /// `JoinRoom` and `LeaveRoom`
+    MultiRoom,
+    /// `UpdatePosition` and the `Positions` events
+    Positions,
+    /// Frames carrying an array of events, sent for bursts of membership events. Only
+    /// for clients that declare it
+    EventBatches,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::ActiveSpeakers,
+        Capability::Relay,
+        Capability::Stats,
+        Capability::SimulcastLayers,
+        Capability::MultiRoom,
+        Capability::Positions,
+        Capability::EventBatches,
+    ];
+
+    /// Name clients declare the capability with
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::ActiveSpeakers => "activeSpeakers",
+            Capability::Relay => "relay",
+            Capability::Stats => "stats",
+            Capability::SimulcastLayers => "simulcastLayers",
+            Capability::MultiRoom => "multiRoom",
+            Capability::Positions => "positions",
+            Capability::EventBatches => "eventBatches",
+        }
+    }
+
+    /// Changes how events are framed, so clients that predate negotiation don't get it
+    fn opt_in(self) -> bool {
+        self == Capability::EventBatches
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Capabilities negotiated for a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// Every capability but those clients have to opt in to
+    pub fn all() -> Capabilities {
+        let bits = Capability::ALL
+            .iter()
+            .filter(|c| !c.opt_in())
+            .fold(0, |bits, c| bits | c.bit());
+        Capabilities(bits)
+    }
+
+    /// Capabilities of a client that declared `declared`. Names this server doesn't know
+    /// are ignored, they come from newer clients. Clients that declare nothing predate
+    /// negotiation and keep everything they always had
+    pub fn negotiate(declared: Option<&[String]>) -> Capabilities {
+        let declared = match declared {
+            Some(declared) => declared,
+            None => return Capabilities::all(),
+        };
+        let bits = Capability::ALL
+            .iter()
+            .filter(|c| declared.iter().any(|name| name == c.name()))
+            .fold(0, |bits, c| bits | c.bit());
+        Capabilities(bits)
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::all()
+    }
+}
+
+/// Events a connection may do without, for clients such as bots and dashboards that only
+/// follow who's in the room. Membership and the room's lifecycle are always sent, clients
+/// can't keep track of the room without them
+#[derive(Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EventCategory {
+    /// Other users' producers starting, pausing, resuming and stopping
+    Media,
+    /// `ActiveSpeakers` events
+    Speaking,
+    /// `Positions` events
+    Positions,
+    /// `Stats`, scores, downlink estimates, connection quality and audiences
+    Stats,
+    /// `Relay` events
+    Relay,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 5] = [
+        EventCategory::Media,
+        EventCategory::Speaking,
+        EventCategory::Positions,
+        EventCategory::Stats,
+        EventCategory::Relay,
+    ];
+
+    /// Name clients subscribe to the category with
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventCategory::Media => "media",
+            EventCategory::Speaking => "speaking",
+            EventCategory::Positions => "positions",
+            EventCategory::Stats => "stats",
+            EventCategory::Relay => "relay",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Event categories a connection is sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subscriptions(u8);
+
+impl Subscriptions {
+    pub fn all() -> Subscriptions {
+        Subscriptions(EventCategory::ALL.iter().fold(0, |bits, c| bits | c.bit()))
+    }
+
+    /// Categories named in `names`, those this server doesn't know are ignored. Clients
+    /// that don't name any get everything, as they did before subscriptions
+    pub fn from_names(names: Option<&[String]>) -> Subscriptions {
+        let names = match names {
+            Some(names) => names,
+            None => return Subscriptions::all(),
+        };
+        let bits = EventCategory::ALL
+            .iter()
+            .filter(|c| names.iter().any(|name| name == c.name()))
+            .fold(0, |bits, c| bits | c.bit());
+        Subscriptions(bits)
+    }
+
+    pub fn from_bits(bits: u8) -> Subscriptions {
+        Subscriptions(bits & Subscriptions::all().0)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, category: EventCategory) -> bool {
+        self.0 & category.bit() != 0
+    }
+
+    pub fn categories(self) -> Vec<EventCategory> {
+        EventCategory::ALL
+            .iter()
+            .copied()
+            .filter(|c| self.contains(*c))
+            .collect()
+    }
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::all()
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
 pub struct WSReply {
     pub id: Option<String>,
+    /// Room of the command replied to, when it was tagged with one
+    #[serde(rename = "roomId", skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
     #[serde(flatten)]
     pub reply_type: WSReplyType,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema, IntoStaticStr)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "camelCase")]
 pub enum WSEvent {
     UserJoined {
         id: String,
+        /// What a `RoomInfo` would list for the user, as it joined
+        info: UserInfo,
     },
     UserLeft {
         id: String,
+        /// Removed by a moderator or the management API
+        kicked: bool,
     },
 
     UserStartProduce {
         id: String,
         #[serde(rename = "type")]
         produce_type: ProduceType,
+        /// Whether the producer started muted
+        paused: bool,
+    },
+    /// Sent for the connection's own user too, when a moderator muted it
+    UserProducerPauseChanged {
+        id: String,
+        #[serde(rename = "type")]
+        produce_type: ProduceType,
+        paused: bool,
     },
     UserStopProduce {
         id: String,
         #[serde(rename = "type")]
         produce_type: ProduceType,
     },
+    /// The user swapped its producer for a new one, `consumers` are what this connection
+    /// consumes of it now, in place of the consumers it had of the old one
+    UserProducerReplaced {
+        id: String,
+        #[serde(rename = "type")]
+        produce_type: ProduceType,
+        consumers: Vec<ReplacedConsumer>,
+    },
+
+    UserInfoUpdated {
+        id: String,
+        info: UserInfo,
+    },
+    UserRoleChanged {
+        id: String,
+        role: Role,
+    },
+    /// The room changed hands, `null` once its owner left without anyone to take over
+    OwnerChanged {
+        owner: Option<String>,
+    },
+    /// The user joined the locked room and waits in its lobby, sent to moderators only
+    UserWaiting {
+        id: String,
+    },
+    /// A moderator let the user in from the lobby, sent to moderators only
+    UserAdmitted {
+        id: String,
+    },
+    /// `reporter` reported `target` for `reason`, sent to moderators only
+    UserReported {
+        id: String,
+        reporter: String,
+        target: String,
+        reason: String,
+    },
+    /// NACKs, PLIs or retransmissions of the room's media over the last minute crossed a
+    /// threshold of `RoomSettings::retransmissions`, sent to moderators only. Not sent
+    /// again until the room went back under every threshold
+    QualityAlert {
+        counts: RetransmissionCounts,
+        exceeded: Vec<AlertCounter>,
+    },
+    /// The room was opened, or this user let in from its lobby. Events sent while it
+    /// waited were dropped, `users` replaces what the client knew of the room
+    RoomOpened {
+        users: HashMap<String, UserInfo>,
+    },
+    /// The user's connection dropped, its producers stay until it resumes the session or
+    /// leaves once the grace period is over
+    UserConnectionStateChanged {
+        id: String,
+        state: ConnectionState,
+    },
+    /// Quality sampled from the user's connection went up or down a level, sent for the
+    /// connection's own user too
+    UserQualityChanged {
+        id: String,
+        level: QualityLevel,
+    },
+    /// The user did nothing for a while in a room that tracks it, clients may dim it
+    UserIdle {
+        id: String,
+    },
+    /// The user did something again after it went idle
+    UserActive {
+        id: String,
+    },
+    /// Loudest users over the last audio level interval, empty once everyone went quiet.
+    /// Sent for the connection's own user too
+    ActiveSpeakers {
+        speakers: Vec<SpeakerVolume>,
+    },
+    /// Users who updated their position lately, at most every `POSITION_TICK`. Users
+    /// missing from it stopped updating or left
+    Positions {
+        users: Vec<UserPosition>,
+    },
+    /// Message another participant relayed to the room, or to this user alone
+    Relay {
+        from: String,
+        payload: serde_json::Value,
+    },
+    /// A participant started relaying a blob to this user, or to the room. Its chunks
+    /// follow in order as `RelayBlobChunk` events, then `RelayBlobEnded`, or
+    /// `RelayBlobFailed` should it not make it whole
+    #[serde(rename_all = "camelCase")]
+    RelayBlobStarted {
+        transfer_id: String,
+        from: String,
+        size: usize,
+        mime: String,
+    },
+    /// Base64 data as the sender sent it
+    #[serde(rename_all = "camelCase")]
+    RelayBlobChunk {
+        transfer_id: String,
+        seq: u64,
+        data: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    RelayBlobEnded {
+        transfer_id: String,
+    },
+    /// The blob won't be completed, what was received of it is to be discarded
+    #[serde(rename_all = "camelCase")]
+    RelayBlobFailed {
+        transfer_id: String,
+    },
+
+    /// Recording of the room started or stopped
+    RecordingStateChanged {
+        recording: bool,
+    },
+
+    /// The room requires consent to record the user, answered with `RecordingConsent`.
+    /// Asked as recording starts, or as the user connects while it goes on and it
+    /// didn't answer yet
+    RecordingConsentRequested,
+
+    /// A moderator or the management API changed the room's settings, `settings` are
+    /// those now in effect
+    #[serde(rename_all = "camelCase")]
+    RoomSettingsChanged {
+        video_allowed: bool,
+        settings: RoomSettings,
+        /// Ceilings of the room's quality profile. Producers already open keep their
+        /// encodings, clients bring them under the new ceilings themselves
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<QualityProfile>,
+    },
+
+    /// A moderator set a key of the room's metadata, `value` is null if it was deleted
+    RoomMetadataChanged {
+        key: String,
+        value: Option<serde_json::Value>,
+    },
+
+    /// The room went over its bitrate budget and its video was held to lower layers, or
+    /// its producers to a share of the budget, as far as `level` goes. Eases off a level
+    /// at a time once it's back under, down to 0
+    RoomThrottled {
+        level: u8,
+    },
+
+    /// Progress of the room's broadcast to an external ingest
+    BroadcastStateChanged {
+        state: BroadcastState,
+    },
+
+    /// Sent right before the connection is closed because the user was kicked
+    Kicked {
+        reason: Option<String>,
+    },
+
+    /// How the call went, once the last visible user left or right before `RoomSummary`
+    /// if the room is deleted during it
+    CallSummary(CallSummary),
+    /// Sent right before the connection is closed because the room was deleted
+    RoomSummary(RoomSummary),
+
+    /// The session's token expires at this UNIX time in seconds, and the connection is
+    /// closed then unless the client sends `RefreshToken`
+    #[serde(rename_all = "camelCase")]
+    TokenExpiring {
+        expires_at: u64,
+    },
+
+    /// The room moved to another instance. Connect to `url` and authenticate with
+    /// `token` to carry on there, media has to be set up again
+    MigrateTo {
+        url: String,
+        token: String,
+    },
+
+    /// The session ends in this many seconds, closed with `SessionExpired` once it lasted
+    /// its join token's `maxSessionSecs`, or along with the room at its `maxDurationSecs`
+    #[serde(rename_all = "camelCase")]
+    SessionExpiring {
+        seconds_remaining: u64,
+    },
+    /// The user did nothing for so long the connection is closed with `IdleDisconnect` in
+    /// this many seconds, unless the client sends a command before
+    #[serde(rename_all = "camelCase")]
+    IdleWarning {
+        seconds_remaining: u64,
+    },
+
+    /// A room joined with `JoinRoom` was left by the server, with the close code and
+    /// reason the connection would have been closed with if it was the only room
+    RoomLeft {
+        code: u16,
+        reason: String,
+    },
+
+    /// Room events were dropped because the client fell behind, `users` replaces
+    /// whatever the client derived from them
+    Resync {
+        missed: u64,
+        users: HashMap<String, UserInfo>,
+    },
+
+    /// ICE or DTLS state of one of the connection's transports changed, clients restart
+    /// ICE or warn the user when it disconnects or fails
+    #[serde(rename_all = "camelCase")]
+    TransportStateChanged {
+        transport_id: String,
+        #[schemars(with = "String")]
+        ice_state: IceState,
+        #[schemars(with = "String")]
+        dtls_state: DtlsState,
+    },
+    /// Consumers resumed before the receiving transport connected were resumed as it did
+    #[serde(rename_all = "camelCase")]
+    ConsumersActivated {
+        consumer_ids: Vec<String>,
+    },
+
+    /// Health of one of the client's producers from 0 to 10, for clients that asked
+    /// for scores. Sent at most every few seconds per producer
+    ProducerScore {
+        #[serde(rename = "type")]
+        produce_type: ProduceType,
+        score: u8,
+    },
+    /// Consumers of one of the client's producers across the room, once the count moved
+    /// by `signaling.audience_delta` or dropped to none
+    #[serde(rename_all = "camelCase")]
+    ProducerAudience {
+        #[serde(rename = "type")]
+        produce_type: ProduceType,
+        consumer_count: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    ConsumerScore {
+        consumer_id: String,
+        score: u8,
+    },
+
+    /// Layers forwarded to a video consumer changed, both are absent while nothing is
+    /// forwarded
+    #[serde(rename_all = "camelCase")]
+    ConsumerLayersChanged {
+        consumer_id: String,
+        spatial_layer: Option<u8>,
+        temporal_layer: Option<u8>,
+        auto_layers: bool,
+    },
+
+    /// The media server process carrying the room's media died and the room moved to a
+    /// new one. Producers and consumers are gone, the client sends `InitializeTransports`
+    /// again and starts over with its media. It stays in the room meanwhile. Also sent
+    /// when the room is migrated to another process, the old transports then keep
+    /// working until the client replaced them
+    MediaRestartRequired,
+
+    /// Bitrate in bits per second estimated to be available towards the client, sent
+    /// when it changes but at most every couple of seconds to clients that asked for it
+    /// with `downlinkEstimates` when initializing their transports
+    #[serde(rename_all = "camelCase")]
+    DownlinkEstimate {
+        available_bitrate: u32,
+    },
+
+    /// Stats of the connection, pushed on the interval it subscribed with
+    Stats(ConnectionStats),
+
+    /// A consumer was closed by the server rather than by the client
+    #[serde(rename_all = "camelCase")]
+    ConsumerClosed {
+        id: String,
+        producer_id: String,
+        reason: MediaClosedReason,
+    },
+    /// A producer of the connection was closed by the server rather than by the client
+    ProducerClosed {
+        id: String,
+        #[serde(rename = "type")]
+        produce_type: ProduceType,
+        reason: MediaClosedReason,
+    },
+}
+
+impl WSEvent {
+    /// Capability the client must have declared to be sent the event
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            WSEvent::ActiveSpeakers { .. } => Some(Capability::ActiveSpeakers),
+            WSEvent::Relay { .. }
+            | WSEvent::RelayBlobStarted { .. }
+            | WSEvent::RelayBlobChunk { .. }
+            | WSEvent::RelayBlobEnded { .. }
+            | WSEvent::RelayBlobFailed { .. } => Some(Capability::Relay),
+            WSEvent::Stats(_) => Some(Capability::Stats),
+            WSEvent::Positions { .. } => Some(Capability::Positions),
+            WSEvent::ConsumerLayersChanged { .. } => Some(Capability::SimulcastLayers),
+            _ => None,
+        }
+    }
+
+    /// Category the connection must be subscribed to to be sent the event, none for
+    /// those every client is sent
+    pub fn category(&self) -> Option<EventCategory> {
+        match self {
+            WSEvent::UserStartProduce { .. }
+            | WSEvent::UserProducerPauseChanged { .. }
+            | WSEvent::UserStopProduce { .. } => Some(EventCategory::Media),
+            WSEvent::ActiveSpeakers { .. } => Some(EventCategory::Speaking),
+            WSEvent::Positions { .. } => Some(EventCategory::Positions),
+            WSEvent::Stats(_)
+            | WSEvent::ProducerScore { .. }
+            | WSEvent::ProducerAudience { .. }
+            | WSEvent::ConsumerScore { .. }
+            | WSEvent::DownlinkEstimate { .. }
+            | WSEvent::UserQualityChanged { .. } => Some(EventCategory::Stats),
+            WSEvent::Relay { .. }
+            | WSEvent::RelayBlobStarted { .. }
+            | WSEvent::RelayBlobChunk { .. }
+            | WSEvent::RelayBlobEnded { .. }
+            | WSEvent::RelayBlobFailed { .. } => Some(EventCategory::Relay),
+            _ => None,
+        }
+    }
+
+    /// Users joining and leaving, which come in bursts as a large room fills or empties
+    pub fn membership(&self) -> bool {
+        matches!(self, WSEvent::UserJoined { .. } | WSEvent::UserLeft { .. })
+    }
+
+    /// Events a client falling behind can do without, the next one replaces them
+    pub fn low_priority(&self) -> bool {
+        matches!(
+            self,
+            WSEvent::ProducerScore { .. }
+                | WSEvent::ConsumerScore { .. }
+                | WSEvent::Stats(_)
+                | WSEvent::DownlinkEstimate { .. }
+                | WSEvent::ActiveSpeakers { .. }
+                | WSEvent::Positions { .. }
+        )
+    }
+}
+
+/// Event numbered in the order it was sent on its connection, starting at 1
+#[derive(Serialize, JsonSchema)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    /// Room joined with `JoinRoom` the event comes from, absent for the room the
+    /// connection authenticated into
+    #[serde(rename = "roomId", skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(flatten)]
+    pub event: WSEvent,
+}
+
+/// Event a room kept for `GetRecentEvents`, see `state::room::recent`
+#[derive(Serialize, JsonSchema)]
+pub struct RecentEvent {
+    /// UNIX time in milliseconds the event was sent
+    pub at: i64,
+    #[serde(flatten)]
+    pub event: WSEvent,
+}
+
+/// `SequencedEvent` as text, put together from the text of its event serialized alone.
+/// Connections sent the same event share that text, only their sequence differs
+pub fn sequenced_text(seq: u64, room_id: Option<&str>, event: &str) -> String {
+    // Events serialize to an object, tagged with their type
+    let fields = event.get(1..).unwrap_or_default();
+    let mut text = String::with_capacity(event.len() + 48);
+    text.push_str("{\"seq\":");
+    text.push_str(&seq.to_string());
+    if let Some(room_id) = room_id {
+        text.push_str(",\"roomId\":");
+        text.push_str(&serde_json::Value::from(room_id).to_string());
+    }
+    text.push(',');
+    text.push_str(fields);
+    text
+}
+
+/// Why the server closed a producer or consumer
+#[derive(Clone, Copy, Debug)]
+pub enum MediaClosedReason {
+    /// The user producing the media stopped it
+    ProducerStopped,
+    /// The user producing the media left the room
+    UserLeft,
+    /// The producer went away without being stopped, such as along with its transport
+    ProducerClosed,
+    /// The user's role no longer allows producing
+    RoleChanged,
+    /// The room's settings no longer allow the media, such as when video is turned off
+    NotAllowed,
+    /// The media server process carrying the room's media died, see `MediaRestartRequired`
+    MediaRestarted,
+    /// The consumer sent nothing for `reaping.inactive_after` while not paused, its client
+    /// most likely lost track of it
+    Inactive,
+    /// Resumed before the receiving transport connected, which it didn't do in
+    /// `rtc.resume_timeout_secs`
+    TransportTimeout,
+}
+
+impl MediaClosedReason {
+    pub const ALL: [MediaClosedReason; 8] = [
+        MediaClosedReason::ProducerStopped,
+        MediaClosedReason::UserLeft,
+        MediaClosedReason::ProducerClosed,
+        MediaClosedReason::RoleChanged,
+        MediaClosedReason::NotAllowed,
+        MediaClosedReason::MediaRestarted,
+        MediaClosedReason::Inactive,
+        MediaClosedReason::TransportTimeout,
+    ];
+
+    /// Stable code sent to clients, these are part of the protocol
+    /// and must never be changed or reused
+    pub fn code(&self) -> &'static str {
+        match self {
+            MediaClosedReason::ProducerStopped => "producer_stopped",
+            MediaClosedReason::UserLeft => "user_left",
+            MediaClosedReason::ProducerClosed => "producer_closed",
+            MediaClosedReason::RoleChanged => "role_changed",
+            MediaClosedReason::NotAllowed => "not_allowed",
+            MediaClosedReason::MediaRestarted => "media_restarted",
+            MediaClosedReason::Inactive => "inactive",
+            MediaClosedReason::TransportTimeout => "transport_timeout",
+        }
+    }
+}
+
+impl Display for MediaClosedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaClosedReason::ProducerStopped => write!(f, "Producer has been stopped"),
+            MediaClosedReason::UserLeft => write!(f, "Producing user left the room"),
+            MediaClosedReason::ProducerClosed => write!(f, "Producer has been closed"),
+            MediaClosedReason::RoleChanged => write!(f, "Role no longer allows producing"),
+            MediaClosedReason::NotAllowed => write!(f, "Room no longer allows this media"),
+            MediaClosedReason::MediaRestarted => write!(f, "Media server restarted"),
+            MediaClosedReason::Inactive => write!(f, "Consumer sent nothing for too long"),
+            MediaClosedReason::TransportTimeout => {
+                write!(f, "Receiving transport didn't connect in time")
+            }
+        }
+    }
+}
+
+impl Serialize for MediaClosedReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("MediaClosedReason", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+impl JsonSchema for MediaClosedReason {
+    fn schema_name() -> String {
+        "MediaClosedReason".to_string()
+    }
+
+    /// Serialized with its code and message rather than by name
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let code = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(
+                MediaClosedReason::ALL
+                    .iter()
+                    .map(|reason| reason.code().into())
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let mut schema = SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            ..Default::default()
+        };
+        let object = schema.object();
+        object.properties.insert("code".to_string(), code.into());
+        object
+            .properties
+            .insert("message".to_string(), gen.subschema_for::<String>());
+        object.required.insert("code".to_string());
+        object.required.insert("message".to_string());
+        schema.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn set_consumer_priority_command() {
+        let command: WSCommand = serde_json::from_value(json!({
+            "id": "1",
+            "type": "SetConsumerPriority",
+            "data": { "consumerId": "consumer", "priority": 200 },
+        }))
+        .unwrap();
+
+        match command.command_type {
+            WSCommandType::SetConsumerPriority {
+                consumer_id,
+                priority,
+            } => assert_eq!((consumer_id.as_str(), priority), ("consumer", 200)),
+            _ => panic!("Expected SetConsumerPriority"),
+        }
+    }
+
+    #[test]
+    fn consumer_layer_commands() {
+        let command: WSCommand = serde_json::from_value(json!({
+            "id": "1",
+            "type": "SetConsumerLayers",
+            "data": { "consumerId": "consumer", "spatialLayer": 1 },
+        }))
+        .unwrap();
+        match command.command_type {
+            WSCommandType::SetConsumerLayers {
+                spatial_layer,
+                temporal_layer,
+                ..
+            } => assert_eq!((spatial_layer, temporal_layer), (1, None)),
+            _ => panic!("Expected SetConsumerLayers"),
+        }
+
+        let command: WSCommand = serde_json::from_value(json!({
+            "id": "2",
+            "type": "SetConsumerAutoLayers",
+            "data": { "consumerId": "consumer", "enabled": false },
+        }))
+        .unwrap();
+        match command.command_type {
+            WSCommandType::SetConsumerAutoLayers {
+                consumer_id,
+                enabled,
+            } => assert_eq!((consumer_id.as_str(), enabled), ("consumer", false)),
+            _ => panic!("Expected SetConsumerAutoLayers"),
+        }
+    }
+
+    #[test]
+    fn media_closed_reasons_are_stable() {
+        let cases = vec![
+            (
+                MediaClosedReason::ProducerStopped,
+                json!({ "code": "producer_stopped", "message": "Producer has been stopped" }),
+            ),
+            (
+                MediaClosedReason::UserLeft,
+                json!({ "code": "user_left", "message": "Producing user left the room" }),
+            ),
+            (
+                MediaClosedReason::ProducerClosed,
+                json!({ "code": "producer_closed", "message": "Producer has been closed" }),
+            ),
+            (
+                MediaClosedReason::RoleChanged,
+                json!({ "code": "role_changed", "message": "Role no longer allows producing" }),
+            ),
+            (
+                MediaClosedReason::NotAllowed,
+                json!({ "code": "not_allowed", "message": "Room no longer allows this media" }),
+            ),
+            (
+                MediaClosedReason::MediaRestarted,
+                json!({ "code": "media_restarted", "message": "Media server restarted" }),
+            ),
+            (
+                MediaClosedReason::Inactive,
+                json!({ "code": "inactive", "message": "Consumer sent nothing for too long" }),
+            ),
+            (
+                MediaClosedReason::TransportTimeout,
+                json!({
+                    "code": "transport_timeout",
+                    "message": "Receiving transport didn't connect in time"
+                }),
+            ),
+        ];
+
+        assert_eq!(cases.len(), MediaClosedReason::ALL.len());
+        for (reason, expected) in cases {
+            assert_eq!(serde_json::to_value(&reason).unwrap(), expected);
+        }
+    }
+
+    /// Where each reason sits in `MediaClosedReason::ALL`. Without a wildcard arm, a new
+    /// variant doesn't build until it's given a place here, and then in `ALL`
+    fn position(reason: MediaClosedReason) -> usize {
+        match reason {
+            MediaClosedReason::ProducerStopped => 0,
+            MediaClosedReason::UserLeft => 1,
+            MediaClosedReason::ProducerClosed => 2,
+            MediaClosedReason::RoleChanged => 3,
+            MediaClosedReason::NotAllowed => 4,
+            MediaClosedReason::MediaRestarted => 5,
+            MediaClosedReason::Inactive => 6,
+            MediaClosedReason::TransportTimeout => 7,
+        }
+    }
+
+    #[test]
+    fn media_closed_reasons_are_all_listed() {
+        let positions: Vec<usize> = MediaClosedReason::ALL
+            .iter()
+            .map(|r| position(*r))
+            .collect();
+        assert_eq!(
+            positions,
+            (0..MediaClosedReason::ALL.len()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn user_start_produce_event_shape() {
+        let event = WSEvent::UserStartProduce {
+            id: "alice".to_string(),
+            produce_type: ProduceType::Audio,
+            paused: true,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "type": "userStartProduce",
+                "data": { "id": "alice", "type": "audio", "paused": true },
+            })
+        );
+    }
+
+    #[test]
+    fn consumer_closed_event_shape() {
+        let event = WSEvent::ConsumerClosed {
+            id: "consumer".to_string(),
+            producer_id: "producer".to_string(),
+            reason: MediaClosedReason::UserLeft,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "type": "consumerClosed",
+                "data": {
+                    "id": "consumer",
+                    "producerId": "producer",
+                    "reason": { "code": "user_left", "message": "Producing user left the room" },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn broadcast_state_event_shape() {
+        let event = WSEvent::BroadcastStateChanged {
+            state: BroadcastState::Connecting,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({ "type": "broadcastStateChanged", "data": { "state": "connecting" } })
+        );
+    }
+
+    #[test]
+    fn scores_are_opt_in() {
+        let scores = |data: serde_json::Value| {
+            let command: WSCommand =
+                serde_json::from_value(json!({ "type": "Authenticate", "data": data })).unwrap();
+            match command.command_type {
+                WSCommandType::Authenticate { scores, .. } => scores,
+                _ => panic!("Expected Authenticate"),
+            }
+        };
+
+        assert!(!scores(json!({ "roomId": "room", "token": "token" })));
+        assert!(scores(
+            json!({ "roomId": "room", "token": "token", "scores": true })
+        ));
+    }
+
+    #[test]
+    fn transport_state_event_shape() {
+        let event = WSEvent::TransportStateChanged {
+            transport_id: "transport".to_string(),
+            ice_state: IceState::Disconnected,
+            dtls_state: DtlsState::Connected,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "type": "transportStateChanged",
+                "data": {
+                    "transportId": "transport",
+                    "iceState": "disconnected",
+                    "dtlsState": "connected",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn events_carry_their_sequence_number() {
+        let event = SequencedEvent {
+            seq: 7,
+            room_id: None,
+            event: WSEvent::UserLeft {
+                id: "user".to_string(),
+                kicked: false,
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({ "seq": 7, "type": "userLeft", "data": { "id": "user", "kicked": false } })
+        );
+    }
+
+    #[test]
+    fn commands_are_always_traced() {
+        let command =
+            |frame: serde_json::Value| -> WSCommand { serde_json::from_value(frame).unwrap() };
+
+        let traced = command(json!({ "id": "1", "traceId": "trace", "type": "RoomInfo" }));
+        assert_eq!(traced.trace_id, "trace");
+
+        let untraced = command(json!({ "id": "1", "type": "RoomInfo" }));
+        assert_eq!(untraced.trace_id, "1");
+
+        let anonymous =
+            command(json!({ "traceId": "x".repeat(MAX_TRACE_ID + 1), "type": "RoomInfo" }));
+        assert_eq!(anonymous.trace_id.len(), 26);
+    }
+
+    #[test]
+    fn room_info_pages_are_optional() {
+        let command: WSCommand = serde_json::from_value(json!({ "type": "RoomInfo" })).unwrap();
+        assert!(matches!(
+            command.command_type,
+            WSCommandType::RoomInfo(None)
+        ));
+
+        let command: WSCommand =
+            serde_json::from_value(json!({ "type": "RoomInfo", "data": { "offset": 2 } })).unwrap();
+        let page = match command.command_type {
+            WSCommandType::RoomInfo(Some(page)) => page,
+            _ => panic!("Expected a paged RoomInfo"),
+        };
+        assert_eq!(page.limit, MAX_ROOM_INFO_PAGE);
+
+        let users: HashMap<String, UserInfo> = ["d", "a", "c", "b"]
+            .iter()
+            .map(|id| (id.to_string(), UserInfo::default()))
+            .collect();
+        let page = RoomInfoPage {
+            offset: 1,
+            limit: 2,
+        };
+        let (listed, next) = page.apply(users.clone());
+        let mut ids: Vec<&String> = listed.keys().collect();
+        ids.sort();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(next, Some(3));
+
+        let page = RoomInfoPage {
+            offset: 3,
+            limit: 2,
+        };
+        let (listed, next) = page.apply(users);
+        assert!(listed.contains_key("d"));
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn sent_commands_read_back() {
+        let update = UserInfoUpdate {
+            display_name: Some(None),
+            ..UserInfoUpdate::default()
+        };
+        let command = WSCommand::new("7".to_string(), WSCommandType::SetUserInfo { info: update });
+        let frame = serde_json::to_value(&command).unwrap();
+        assert_eq!(
+            frame,
+            json!({
+                "id": "7",
+                "traceId": "7",
+                "type": "SetUserInfo",
+                "data": { "info": { "displayName": null } },
+            })
+        );
+
+        let command: WSCommand = serde_json::from_value(frame).unwrap();
+        match command.command_type {
+            WSCommandType::SetUserInfo { info } => {
+                assert_eq!(info.display_name, Some(None));
+                assert!(info.avatar.is_none());
+            }
+            _ => panic!("Expected SetUserInfo"),
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_told_from_malformed_commands() {
+        let command: WSCommand = serde_json::from_value(json!({
+            "id": "1",
+            "roomId": "other",
+            "type": "FutureFeature",
+            "data": { "enabled": true },
+        }))
+        .unwrap();
+        assert_eq!(command.id.as_deref(), Some("1"));
+        assert_eq!(command.room_id.as_deref(), Some("other"));
+        match command.command_type {
+            WSCommandType::Unknown(command_type) => assert_eq!(command_type, "FutureFeature"),
+            _ => panic!("Expected Unknown"),
+        }
+
+        // Known types still have to parse, and commands need a type
+        for frame in [
+            json!({ "id": "2", "type": "StartProduce", "data": {} }),
+            json!({ "id": "3", "type": 7 }),
+            json!({ "id": "4" }),
+        ] {
+            assert!(serde_json::from_value::<WSCommand>(frame).is_err());
+        }
+    }
+
+    #[test]
+    fn shared_event_text_matches_serialized_events() {
+        let event = || WSEvent::UserLeft {
+            id: "alice".to_string(),
+            kicked: false,
+        };
+        let text = serde_json::to_string(&event()).unwrap();
+        for room_id in [None, Some("room \"1\"")].iter() {
+            let sequenced = SequencedEvent {
+                seq: 42,
+                room_id: room_id.map(str::to_string),
+                event: event(),
+            };
+            assert_eq!(
+                sequenced_text(42, *room_id, &text),
+                serde_json::to_string(&sequenced).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn capabilities_are_negotiated() {
+        // Clients from before negotiation get everything they always had
+        assert_eq!(Capabilities::negotiate(None), Capabilities::all());
+        assert!(!Capabilities::all().contains(Capability::EventBatches));
+
+        // Names from newer clients are skipped, the ones known still count
+        let declared = vec!["stats".to_string(), "holograms".to_string()];
+        let negotiated = Capabilities::negotiate(Some(&declared));
+        assert!(negotiated.contains(Capability::Stats));
+        assert!(!negotiated.contains(Capability::Relay));
+
+        let negotiated = Capabilities::negotiate(Some(&[]));
+        for capability in &Capability::ALL {
+            assert!(!negotiated.contains(*capability));
+            let name = serde_json::to_value(capability).unwrap();
+            assert_eq!(name, capability.name());
+        }
+    }
+
+    #[test]
+    fn membership_events_cant_be_unsubscribed_from() {
+        assert_eq!(Subscriptions::from_names(None), Subscriptions::all());
+        let names = vec!["relay".to_string(), "telepathy".to_string()];
+        let subscriptions = Subscriptions::from_names(Some(&names));
+        assert_eq!(subscriptions.categories(), [EventCategory::Relay]);
+        assert_eq!(
+            Subscriptions::from_bits(subscriptions.bits()),
+            subscriptions
+        );
+        for category in &EventCategory::ALL {
+            let name = serde_json::to_value(category).unwrap();
+            assert_eq!(name, category.name());
+        }
+
+        let left = WSEvent::UserLeft {
+            id: "alice".to_string(),
+            kicked: false,
+        };
+        assert_eq!(left.category(), None);
+        assert_eq!(WSEvent::MediaRestartRequired.category(), None);
+        let speakers = WSEvent::ActiveSpeakers {
+            speakers: Vec::new(),
+        };
+        assert_eq!(speakers.category(), Some(EventCategory::Speaking));
+    }
 }
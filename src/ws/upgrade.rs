@@ -0,0 +1,135 @@
+//! Credentials passed with the upgrade request, for clients that would rather not send an
+//! `Authenticate` command. They're checked before the upgrade completes, so refused
+//! credentials get an HTTP error instead of a connection that closes right away
+
+use serde::Deserialize;
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::{Filter, Rejection};
+
+use super::error::WSCloseType;
+use super::types::{Capabilities, MIN_PROTOCOL_VERSION};
+use super::{check_version, register, Registered};
+use crate::util::config::DEFAULT_TENANT;
+
+#[derive(Deserialize)]
+struct CredentialsQuery {
+    room_id: Option<String>,
+    tenant: Option<String>,
+    token: Option<String>,
+    #[serde(default)]
+    scores: bool,
+    #[serde(default)]
+    debug: bool,
+    version: Option<u32>,
+    /// Comma separated, like the list `Authenticate` takes
+    capabilities: Option<String>,
+}
+
+/// What `Authenticate` would carry, see `WSCommandType::Authenticate`
+pub struct Credentials {
+    room_id: String,
+    tenant: Option<String>,
+    token: String,
+    scores: bool,
+    debug: bool,
+    version: u32,
+    capabilities: Capabilities,
+}
+
+/// Extract the credentials from the query, the token may come as a bearer token in the
+/// `Authorization` header instead. `None` unless both the room and token are given
+pub fn filter() -> impl Filter<Extract = (Option<Credentials>,), Error = Rejection> + Clone {
+    warp::query::<CredentialsQuery>()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|query: CredentialsQuery, authorization: Option<String>| {
+            let bearer = authorization
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .map(str::to_string);
+            let capabilities = query
+                .capabilities
+                .map(|list| list.split(',').map(str::to_string).collect::<Vec<_>>());
+            Some(Credentials {
+                room_id: query.room_id?,
+                tenant: query.tenant,
+                token: query.token.or(bearer)?,
+                scores: query.scores,
+                debug: query.debug,
+                version: query.version.unwrap_or(MIN_PROTOCOL_VERSION),
+                capabilities: Capabilities::negotiate(capabilities.as_deref()),
+            })
+        })
+}
+
+/// A user registered while upgrading the connection. Disconnected again if dropped before
+/// the connection takes it over, as when the upgrade doesn't go through
+pub struct Preauthorized {
+    connection_id: u64,
+    registered: Option<Registered>,
+    pub scores: bool,
+    pub debug: bool,
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Preauthorized {
+    pub async fn register(
+        credentials: Credentials,
+        connection_id: u64,
+    ) -> Result<Preauthorized, WSCloseType> {
+        check_version(credentials.version)?;
+        let tenant = credentials.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+        let registered = register(
+            tenant,
+            &credentials.room_id,
+            &credentials.token,
+            connection_id,
+        )
+        .await?;
+        Ok(Preauthorized {
+            connection_id,
+            registered: Some(registered),
+            scores: credentials.scores,
+            debug: credentials.debug,
+            version: credentials.version,
+            capabilities: credentials.capabilities,
+        })
+    }
+
+    /// Hand the user over to the connection
+    pub fn take(&mut self) -> Registered {
+        self.registered
+            .take()
+            .expect("Preauthorized user taken twice")
+    }
+}
+
+impl Drop for Preauthorized {
+    fn drop(&mut self) {
+        if let Some(registered) = self.registered.take() {
+            let connection_id = self.connection_id;
+            tokio::spawn(async move {
+                registered
+                    .room
+                    .users()
+                    .disconnect(&registered.user_id, connection_id)
+                    .await;
+            });
+        }
+    }
+}
+
+/// Response to an upgrade request that was refused, carrying the close code
+/// the connection would have been closed with
+pub fn refuse(close: WSCloseType) -> Response {
+    let status = match close {
+        WSCloseType::Banned | WSCloseType::OriginNotAllowed => StatusCode::FORBIDDEN,
+        WSCloseType::UnsupportedVersion(_) => StatusCode::BAD_REQUEST,
+        WSCloseType::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::UNAUTHORIZED,
+    };
+    let body = json!({ "code": close.code(), "reason": close.reason() });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}